@@ -39,6 +39,10 @@ pub trait Arena: Sized {
     fn alloc_handle<F: FnOnce(&mut Self::Data)>(&self, f: F) -> Option<Ref<Self::Data>>;
 
     fn alloc<F: FnOnce(&mut Self::Data)>(&self, f: F) -> Option<Rc<Self>> {
+        #[cfg(feature = "fault-inject")]
+        if crate::fault::should_fail(crate::fault::Site::Arena) {
+            return None;
+        }
         let inner = self.alloc_handle(f)?;
         // SAFETY: `inner` was allocated from `self`.
         Some(unsafe { Rc::from_unchecked(self, inner) })
@@ -81,11 +85,27 @@ pub trait ArenaObject {
     fn finalize<'s, A: Arena>(&'s mut self, guard: &'s mut A::Guard<'_>);
 }
 
+/// Number of recently freed entries that an `ArrayArena` keeps in quarantine.
+#[cfg(feature = "arena-sanitize")]
+const QUARANTINE: usize = 4;
+
+/// With the `arena-sanitize` feature, the most recently freed entries of an
+/// `ArrayArena`. They are reused only when no other entry is free, so that a
+/// `Ref` that outlives its object still points to a free entry, which
+/// `RcCell` then catches. Without the feature, it is empty.
+struct Quarantine {
+    #[cfg(feature = "arena-sanitize")]
+    entries: [usize; QUARANTINE],
+    #[cfg(feature = "arena-sanitize")]
+    next: usize,
+}
+
 /// A homogeneous memory allocator equipped with reference counts.
 #[pin_project]
 pub struct ArrayArena<T, const CAPACITY: usize> {
     #[pin]
     entries: [RcCell<T>; CAPACITY],
+    quarantine: Quarantine,
 }
 
 #[pin_project]
@@ -124,6 +144,35 @@ pub struct Rc<A: Arena> {
 // Also, `Rc` does not point to thread-local data.
 unsafe impl<T: Sync, A: Arena<Data = T>> Send for Rc<A> {}
 
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            #[cfg(feature = "arena-sanitize")]
+            entries: [usize::MAX; QUARANTINE],
+            #[cfg(feature = "arena-sanitize")]
+            next: 0,
+        }
+    }
+
+    /// Is the `i`th entry in quarantine?
+    fn contains(&self, _i: usize) -> bool {
+        #[cfg(feature = "arena-sanitize")]
+        return self.entries.contains(&_i);
+        #[cfg(not(feature = "arena-sanitize"))]
+        false
+    }
+
+    /// Put the `i`th entry, which has just been freed, in quarantine,
+    /// releasing the one that has been there longest.
+    fn push(&mut self, _i: usize) {
+        #[cfg(feature = "arena-sanitize")]
+        {
+            self.entries[self.next] = _i;
+            self.next = (self.next + 1) % QUARANTINE;
+        }
+    }
+}
+
 impl<T, const CAPACITY: usize> ArrayArena<T, CAPACITY> {
     /// Returns an `ArrayArena` of size `CAPACITY` that is filled with `D`'s const default value.
     /// Note that `D` must `impl const Default`.
@@ -139,6 +188,7 @@ impl<T, const CAPACITY: usize> ArrayArena<T, CAPACITY> {
     pub const fn new<D: Default>() -> ArrayArena<D, CAPACITY> {
         ArrayArena {
             entries: array![_ => RcCell::new(Default::default()); CAPACITY],
+            quarantine: Quarantine::new(),
         }
     }
 }
@@ -158,10 +208,16 @@ impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Arena
         let this = guard.get_pin_mut().project();
 
         let mut empty: Option<*mut RcCell<T>> = None;
-        for entry in IterPinMut::from(this.entries) {
+        let mut quarantined: Option<*mut RcCell<T>> = None;
+        for (i, entry) in IterPinMut::from(this.entries).enumerate() {
             if !entry.is_borrowed() {
-                if empty.is_none() {
-                    empty = Some(entry.as_ref().get_ref() as *const _ as *mut _)
+                let slot = if this.quarantine.contains(i) {
+                    &mut quarantined
+                } else {
+                    &mut empty
+                };
+                if slot.is_none() {
+                    *slot = Some(entry.as_ref().get_ref() as *const _ as *mut _)
                 }
                 // Note: Do not use `break` here.
                 // We must first search through all entries, and then alloc at empty
@@ -174,7 +230,7 @@ impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Arena
             }
         }
 
-        empty.map(|cell_raw| {
+        empty.or(quarantined).map(|cell_raw| {
             // SAFETY: `cell` is not referenced or borrowed. Also, it is already pinned.
             let mut cell = unsafe { Pin::new_unchecked(&mut *cell_raw) };
             n(cell.as_mut().get_pin_mut().unwrap().get_mut());
@@ -186,13 +242,26 @@ impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Arena
         let mut guard = self.lock();
         let this = guard.get_pin_mut().project();
 
-        for mut entry in IterPinMut::from(this.entries) {
+        let mut quarantined: Option<*mut RcCell<T>> = None;
+        for (i, mut entry) in IterPinMut::from(this.entries).enumerate() {
             if !entry.is_borrowed() {
+                if this.quarantine.contains(i) {
+                    if quarantined.is_none() {
+                        quarantined = Some(entry.as_ref().get_ref() as *const _ as *mut _);
+                    }
+                    continue;
+                }
                 f(entry.as_mut().get_pin_mut().unwrap().get_mut());
                 return Some(entry.borrow());
             }
         }
-        None
+
+        quarantined.map(|cell_raw| {
+            // SAFETY: `cell` is not referenced or borrowed. Also, it is already pinned.
+            let mut cell = unsafe { Pin::new_unchecked(&mut *cell_raw) };
+            f(cell.as_mut().get_pin_mut().unwrap().get_mut());
+            cell.borrow()
+        })
     }
 
     unsafe fn dup(&self, handle: &Ref<Self::Data>) -> Ref<Self::Data> {
@@ -205,6 +274,9 @@ impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Arena
 
         if let Ok(mut rm) = RefMut::<T>::try_from(handle) {
             rm.finalize::<Self>(&mut this);
+            let i = (rm.get_cell() as usize - this.entries.as_ptr() as usize)
+                / mem::size_of::<RcCell<T>>();
+            this.get_pin_mut().project().quarantine.push(i);
         }
     }
 
@@ -367,6 +439,47 @@ impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Arena
     }
 }
 
+impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Spinlock<ArrayArena<T, CAPACITY>> {
+    /// Calls `f` on each object that is referenced.
+    pub fn for_each_used<F: FnMut(&T)>(&self, mut f: F) {
+        let mut guard = self.lock();
+        let this = guard.get_pin_mut().project();
+
+        for entry in IterPinMut::from(this.entries) {
+            if entry.is_borrowed() {
+                // The entry is referenced. Check that it is not under finalization.
+                if let Some(r) = entry.try_borrow() {
+                    f(&r);
+                }
+            }
+        }
+    }
+}
+
+impl<T: 'static + ArenaObject + Unpin, const CAPACITY: usize> Spinlock<MruArena<T, CAPACITY>> {
+    /// Calls `f` on each object that is not referenced, least recently used
+    /// first, as long as `f` returns true.
+    pub fn for_each_unused<F: FnMut(&mut T) -> bool>(&self, mut f: F) {
+        let mut guard = self.lock();
+        let this = guard.get_pin_mut().project();
+
+        // SAFETY: the whole `MruArena` is protected by a lock.
+        for mut entry in unsafe { this.list.iter_pin_mut_unchecked().rev() } {
+            if !entry.data.is_borrowed()
+                && !f(entry
+                    .as_mut()
+                    .project()
+                    .data
+                    .get_pin_mut()
+                    .unwrap()
+                    .get_mut())
+            {
+                break;
+            }
+        }
+    }
+}
+
 impl<T, A: Arena<Data = T>> Rc<A> {
     /// # Safety
     ///
@@ -0,0 +1,116 @@
+//! Stack backtraces, for panics.
+//!
+//! The kernel is built with frame pointers: on entry, a function saves ra at
+//! fp - 8 and the caller's fp at fp - 16, where fp is its own frame pointer
+//! (s0). The walk follows these until it leaves the stack it started on.
+//!
+//! With `make KSYMS=yes`, the kernel is linked twice, and the second time
+//! gets the function symbols of the first in the .ksymtab section, so
+//! return addresses are printed with function names. Otherwise the table is
+//! empty, and kernel/kernel.sym can be used by hand.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{slice, str};
+
+use crate::{
+    memlayout::{kstack, TRAMPOLINE},
+    param::NPROC,
+    println,
+    riscv::{pgroundup, r_fp, PGSIZE},
+};
+
+/// Frames printed at most.
+const MAXDEPTH: usize = 32;
+
+/// An entry of the symbol table that kernel/ksyms.pl generates.
+// It needs repr(C) because the table is generated in assembly.
+#[repr(C)]
+struct KSym {
+    addr: usize,
+
+    /// NUL-terminated.
+    name: *const u8,
+}
+
+extern "C" {
+    // Sorted by address, and ends with an entry whose addr is 0.
+    // Defined by kernel.ld.
+    static ksymtab: [KSym; 0];
+}
+
+/// Serializes dumps of harts that stop after a panic.
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+/// The name of the function that contains `pc`, and the offset of `pc` in it.
+fn symbolize(pc: usize) -> Option<(&'static str, usize)> {
+    let mut found = None;
+    // SAFETY: ksymtab ends with an entry whose addr is 0.
+    let mut sym = unsafe { ksymtab.as_ptr() };
+    loop {
+        // SAFETY: sym is not past the last entry.
+        let KSym { addr, name } = unsafe { &*sym };
+        if *addr == 0 || *addr > pc {
+            break;
+        }
+        found = Some((*addr, *name));
+        sym = sym.wrapping_add(1);
+    }
+    let (addr, name) = found?;
+    // SAFETY: names in ksymtab are NUL-terminated strings.
+    let len = (0..).find(|&i| unsafe { *name.add(i) } == 0)?;
+    // SAFETY: name has len bytes before the NUL.
+    let name = unsafe { slice::from_raw_parts(name, len) };
+    Some((str::from_utf8(name).ok()?, pc - addr))
+}
+
+/// The highest address of the stack that holds the frame at `fp`.
+fn stack_top(fp: usize) -> usize {
+    if (kstack(NPROC - 1)..TRAMPOLINE).contains(&fp) {
+        // A kernel stack of a process is a page, between guard pages.
+        pgroundup(fp)
+    } else {
+        // stack0, where each hart boots, is at most a page per hart.
+        fp + PGSIZE
+    }
+}
+
+/// Prints the return addresses of the frames on the current stack.
+pub fn backtrace() {
+    let mut fp = r_fp();
+    let top = stack_top(fp);
+    println!("backtrace:");
+    for _ in 0..MAXDEPTH {
+        if fp % 8 != 0 || fp < 16 || fp > top {
+            break;
+        }
+        // SAFETY: fp is within the current stack, as are the saved registers below it.
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        // Attribute the return address to the call instruction before it.
+        match symbolize(ra.wrapping_sub(1)) {
+            Some((name, off)) => println!("  {:018p} {}+{:#x}", ra as *const u8, name, off + 1),
+            None => println!("  {:018p}", ra as *const u8),
+        }
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+}
+
+/// Prints the state of this hart, which stops because another one panicked.
+pub fn dump_hart(hartid: usize, sepc: usize, pid: Option<i32>) {
+    while DUMPING
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    println!(
+        "hart {} stopped: sepc={:018p} pid={}",
+        hartid,
+        sepc as *const u8,
+        pid.unwrap_or(-1)
+    );
+    backtrace();
+    DUMPING.store(false, Ordering::Release);
+}
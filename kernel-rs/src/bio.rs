@@ -10,15 +10,38 @@
 //! * When done with the buffer, call release.
 //! * Do not use the buffer after calling release.
 //! * Only one process at a time can use a buffer, so do not keep them longer than necessary.
+//! * To keep a buffer in the cache, as the log does until it installs the block, pin it and keep
+//!   a handle to it until unpinning it. Evicting a pinned buffer is a bug, and panics.
+//!
+//! The data of each buffer is in a page of its own. When free pages run low, the page allocator
+//! calls `shrink`, which takes back the pages of buffers that nobody uses, least recently used
+//! first. Such buffers are clean, so locking one again just gets it a new page and rereads the
+//! block. Up to NBUFRESERVE pages that buffers hold come out of a reserve, so that user
+//! processes cannot take them. The shrinker gives such a page back to the reserve, and stops
+//! once buffers hold no other pages. If no page is free, locking a buffer without one fails.
+//!
+//! The page cache holds the data of files a page at a time, indexed by (dev, inum, page index),
+//! so that user memory can map it some day. Reading a file goes through it, and fills a page from
+//! the blocks it covers. Writing a file still goes through the buffers, for the log, and updates
+//! the pages that are cached. Freeing the blocks of a file invalidates its pages.
 
 use core::mem::{self, ManuallyDrop};
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use static_assertions::const_assert;
 
 use crate::{
     arena::{Arena, ArenaObject, MruArena, Rc},
+    kalloc::Kmem,
+    kernel::kernel_builder,
+    klog,
+    kstat::Counter,
     lock::{Sleeplock, Spinlock},
-    param::{BSIZE, NBUF},
+    page::Page,
+    param::{BSIZE, NBUF, NBUFRESERVE, NPAGECACHE},
     proc::WaitChannel,
+    riscv::PGSIZE,
 };
 
 pub struct BufEntry {
@@ -29,15 +52,39 @@ pub struct BufEntry {
     pub vdisk_request_waitchannel: WaitChannel,
 
     pub inner: Sleeplock<BufInner>,
+
+    /// How many times it is pinned.
+    pins: AtomicU32,
 }
 
 impl BufEntry {
+    /// The device number of the block.
+    pub fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    /// Pins the buffer in the cache until a matching `unpin`. The caller
+    /// must keep a handle to it meanwhile.
+    pub fn pin(&self) {
+        let _ = self.pins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unpin(&self) {
+        let pins = self.pins.fetch_sub(1, Ordering::Relaxed);
+        assert!(pins > 0, "unpin: not pinned");
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Relaxed) > 0
+    }
+
     pub const fn zero() -> Self {
         Self {
             dev: 0,
             blockno: 0,
             vdisk_request_waitchannel: WaitChannel::new(),
             inner: Sleeplock::new("buffer", BufInner::zero()),
+            pins: AtomicU32::new(0),
         }
     }
 }
@@ -51,7 +98,9 @@ impl const Default for BufEntry {
 
 impl ArenaObject for BufEntry {
     fn finalize<'s, A: Arena>(&'s mut self, _guard: &'s mut A::Guard<'_>) {
-        // The buffer contents should have been written. Does nothing.
+        // The buffer may be evicted from now on.
+        assert!(!self.is_pinned(), "bcache: dropped a pinned buffer");
+        // The buffer contents should have been written. Does nothing else.
     }
 }
 
@@ -61,7 +110,36 @@ pub struct BufInner {
 
     /// Does disk "own" buf?
     pub disk: bool,
-    pub data: BufData,
+    pub data: BufPage,
+}
+
+/// The page that holds the data of a buffer. It has no page only while the
+/// buffer is unlocked.
+pub struct BufPage {
+    page: Option<Page>,
+
+    /// Did the page come out of the reserve?
+    reserved: bool,
+}
+
+const_assert!(mem::size_of::<BufData>() <= PGSIZE);
+
+impl Deref for BufPage {
+    type Target = BufData;
+
+    fn deref(&self) -> &Self::Target {
+        let page = self.page.as_ref().expect("BufPage: no page");
+        // SAFETY: a page is large enough for BufData, and aligned more strictly.
+        unsafe { &*(page.as_ptr() as *const BufData) }
+    }
+}
+
+impl DerefMut for BufPage {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let page = self.page.as_mut().expect("BufPage: no page");
+        // SAFETY: a page is large enough for BufData, and aligned more strictly.
+        unsafe { &mut *(page.as_mut_ptr() as *mut BufData) }
+    }
 }
 
 // Data in Buf may be assumed to be u32, so the data field in Buf must have
@@ -91,7 +169,10 @@ impl BufInner {
         Self {
             valid: false,
             disk: false,
-            data: BufData { inner: [0; BSIZE] },
+            data: BufPage {
+                page: None,
+                reserved: false,
+            },
         }
     }
 }
@@ -158,24 +239,307 @@ impl Bcache {
     }
 
     /// Return a unlocked buf with the contents of the indicated block.
-    pub fn get_buf(&self, dev: u32, blockno: u32) -> BufUnlocked {
-        self.find_or_alloc(
-            |buf| buf.dev == dev && buf.blockno == blockno,
-            |buf| {
-                buf.dev = dev;
-                buf.blockno = blockno;
-                buf.inner.get_mut().valid = false;
-            },
-        )
-        .expect("[BufGuard::new] no buffers")
+    /// Returns Ok(the buf) on success, Err(()) if every buffer is in use.
+    pub fn get_buf(&self, dev: u32, blockno: u32) -> Result<BufUnlocked, ()> {
+        let mut counter = Counter::BcacheHit;
+        let buf = self
+            .find_or_alloc(
+                |buf| buf.dev == dev && buf.blockno == blockno,
+                |buf| {
+                    assert!(!buf.is_pinned(), "bcache: evicted a pinned buffer");
+                    counter = Counter::BcacheMiss;
+                    buf.dev = dev;
+                    buf.blockno = blockno;
+                    buf.inner.get_mut().valid = false;
+                },
+            )
+            .ok_or(())?;
+        // TODO: remove kernel_builder()
+        kernel_builder().kstat.inc(counter);
+        Ok(buf)
+    }
+
+    /// Frees the pages of unused buffers, least recently used first, until
+    /// `pages` of them are free for others, or the buffers hold only pages
+    /// out of the reserve. Those go back to the reserve.
+    /// Returns the number of pages freed for others.
+    pub fn shrink(&self, kmem: &Spinlock<Kmem>, pages: usize) -> usize {
+        let mut freed = 0;
+        self.for_each_unused(|buf| {
+            if freed == pages
+                || BUF_PAGES.load(Ordering::Relaxed) <= RESERVE_USED.load(Ordering::Relaxed)
+            {
+                return false;
+            }
+            if !buf.is_pinned() {
+                let inner = buf.inner.get_mut();
+                if let Some(page) = inner.data.page.take() {
+                    inner.valid = false;
+                    if free_page(kmem, page, inner.data.reserved) {
+                        freed += 1;
+                    }
+                }
+            }
+            true
+        });
+        freed
+    }
+}
+
+/// The pages that buffers hold.
+static BUF_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The pages of the reserve: NBUFRESERVE, or 0 if they could not be
+/// reserved.
+static RESERVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The pages that buffers hold out of the reserve.
+static RESERVE_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserves the pages of the buffer cache. Called once at boot. If there are
+/// not as many free pages, buffers take pages as the other caches do.
+pub fn reserve(kmem: &Spinlock<Kmem>) {
+    if kmem.reserve(NBUFRESERVE).is_ok() {
+        RESERVE.store(NBUFRESERVE, Ordering::Relaxed);
+    } else {
+        klog!(Warn, "bcache: cannot reserve {} pages", NBUFRESERVE);
+    }
+}
+
+/// Returns Some(a page for the data of a buffer, and whether it is out of
+/// the reserve), out of the reserve while the buffers have not taken all of
+/// it. Returns None if no page is free.
+fn alloc_page() -> Option<(Page, bool)> {
+    // TODO: remove kernel_builder()
+    let kernel = kernel_builder();
+    let reserved = RESERVE_USED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            if used < RESERVE.load(Ordering::Relaxed) {
+                Some(used + 1)
+            } else {
+                None
+            }
+        })
+        .is_ok();
+    let page = if reserved {
+        kernel.kmem.alloc_reserved()
+    } else {
+        kernel.kmem.alloc_nofault()
+    };
+    if page.is_none() && reserved {
+        let _ = RESERVE_USED.fetch_sub(1, Ordering::Relaxed);
     }
+    let page = page?;
+    let _ = BUF_PAGES.fetch_add(1, Ordering::Relaxed);
+    Some((page, reserved))
+}
+
+/// Frees the page of a buffer, back to the reserve if it came out of it.
+/// Returns whether others may take the page.
+fn free_page(kmem: &Spinlock<Kmem>, page: Page, reserved: bool) -> bool {
+    let _ = BUF_PAGES.fetch_sub(1, Ordering::Relaxed);
+    if reserved {
+        let _ = RESERVE_USED.fetch_sub(1, Ordering::Relaxed);
+        kmem.free_reserved(page);
+        false
+    } else {
+        kmem.free(page);
+        true
+    }
+}
+
+/// The shrinker of the buffer cache. See `Bcache::shrink`.
+pub fn shrink(kmem: &Spinlock<Kmem>, pages: usize) -> usize {
+    // TODO: remove kernel_builder()
+    // SAFETY: pages run low only after the kernel is initialized.
+    unsafe { kernel_builder().get_bcache() }.shrink(kmem, pages)
 }
 
 impl BufUnlocked {
-    pub fn lock(self) -> Buf {
+    /// Locks the buffer, and gets it a page if it has none, which leaves it
+    /// invalid.
+    /// Returns Ok(the locked buffer) on success, Err(()) if no page is free.
+    pub fn lock(self) -> Result<Buf, ()> {
         mem::forget(self.inner.lock());
-        Buf {
+        let mut buf = Buf {
             inner: ManuallyDrop::new(self),
+        };
+        let inner = buf.deref_inner_mut();
+        if inner.data.page.is_none() {
+            let (page, reserved) = alloc_page().ok_or(())?;
+            inner.data = BufPage {
+                page: Some(page),
+                reserved,
+            };
+            inner.valid = false;
         }
+        Ok(buf)
     }
 }
+
+/// A cached page of the data of a file.
+pub struct PageEntry {
+    dev: u32,
+    inum: u32,
+    index: u32,
+
+    inner: Sleeplock<PageInner>,
+}
+
+struct PageInner {
+    /// Has data been read from the blocks of the file?
+    valid: bool,
+
+    /// The data. It has no page only while invalid.
+    page: Option<Page>,
+}
+
+impl PageEntry {
+    pub const fn zero() -> Self {
+        Self {
+            dev: 0,
+            inum: 0,
+            index: 0,
+            inner: Sleeplock::new(
+                "page",
+                PageInner {
+                    valid: false,
+                    page: None,
+                },
+            ),
+        }
+    }
+
+    /// Drops the data, so that the page is read again.
+    fn invalidate(&mut self, kmem: &Spinlock<Kmem>) -> bool {
+        let inner = self.inner.get_mut();
+        inner.valid = false;
+        inner.page.take().map(|page| kmem.free(page)).is_some()
+    }
+}
+
+#[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
+impl const Default for PageEntry {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl ArenaObject for PageEntry {
+    fn finalize<'s, A: Arena>(&'s mut self, _guard: &'s mut A::Guard<'_>) {
+        // The data stays until the page is evicted or invalidated.
+    }
+}
+
+pub type PageCache = Spinlock<MruArena<PageEntry, NPAGECACHE>>;
+
+impl PageCache {
+    /// # Safety
+    ///
+    /// The caller should make sure that `PageCache` never gets moved.
+    pub const unsafe fn zero() -> Self {
+        Spinlock::new("PAGECACHE", MruArena::<PageEntry, NPAGECACHE>::new())
+    }
+
+    /// Calls `f` on the page at `index` of the file (dev, inum). If the page
+    /// is not cached, first calls `fill` on a new page to read it. The caller
+    /// must hold the lock of the inode.
+    /// Returns Ok(what `f` returns) on success, Err(()) if out of memory or
+    /// if `fill` fails.
+    pub fn with_page<R, F, G>(
+        &self,
+        dev: u32,
+        inum: u32,
+        index: u32,
+        fill: F,
+        f: G,
+    ) -> Result<R, ()>
+    where
+        F: FnOnce(&mut [u8]) -> Result<(), ()>,
+        G: FnOnce(&[u8]) -> R,
+    {
+        let mut counter = Counter::PageCacheHit;
+        let entry = self
+            .find_or_alloc(
+                |page| page.dev == dev && page.inum == inum && page.index == index,
+                |page| {
+                    counter = Counter::PageCacheMiss;
+                    page.dev = dev;
+                    page.inum = inum;
+                    page.index = index;
+                    page.inner.get_mut().valid = false;
+                },
+            )
+            .ok_or(())?;
+        // TODO: remove kernel_builder()
+        let kernel = kernel_builder();
+        kernel.kstat.inc(counter);
+        let mut inner = entry.inner.lock();
+        if !inner.valid {
+            let mut page = match inner.page.take() {
+                Some(page) => page,
+                None => kernel.kmem.alloc_nofault().ok_or(())?,
+            };
+            if let Err(()) = fill(&mut page[..]) {
+                kernel.kmem.free(page);
+                return Err(());
+            }
+            inner.page = Some(page);
+            inner.valid = true;
+        }
+        let page = inner.page.as_ref().expect("with_page: no page");
+        Ok(f(&page[..]))
+    }
+
+    /// Copies `src` to the page that holds `off` of the file (dev, inum), if
+    /// it is cached. `src` must not cross the end of the page. The caller
+    /// must hold the lock of the inode.
+    pub fn update(&self, dev: u32, inum: u32, off: u32, src: &[u8]) {
+        let index = off / PGSIZE as u32;
+        let begin = off as usize % PGSIZE;
+        self.for_each_unused(|page| {
+            if page.dev != dev || page.inum != inum || page.index != index {
+                return true;
+            }
+            let inner = page.inner.get_mut();
+            if let (true, Some(data)) = (inner.valid, &mut inner.page) {
+                data[begin..begin + src.len()].copy_from_slice(src);
+            }
+            false
+        });
+    }
+
+    /// Invalidates the cached pages of the file (dev, inum), as its blocks
+    /// are freed. The caller must hold the lock of the inode.
+    pub fn invalidate(&self, dev: u32, inum: u32, kmem: &Spinlock<Kmem>) {
+        self.for_each_unused(|page| {
+            if page.dev == dev && page.inum == inum {
+                let _ = page.invalidate(kmem);
+            }
+            true
+        });
+    }
+
+    /// Frees the pages of up to `pages` unused entries, least recently used
+    /// first. Returns the number of pages freed.
+    pub fn shrink(&self, kmem: &Spinlock<Kmem>, pages: usize) -> usize {
+        let mut freed = 0;
+        self.for_each_unused(|page| {
+            if freed == pages {
+                return false;
+            }
+            if page.invalidate(kmem) {
+                freed += 1;
+            }
+            true
+        });
+        freed
+    }
+}
+
+/// The shrinker of the page cache. See `PageCache::shrink`.
+pub fn shrink_pages(kmem: &Spinlock<Kmem>, pages: usize) -> usize {
+    // TODO: remove kernel_builder()
+    // SAFETY: pages run low only after the kernel is initialized.
+    unsafe { kernel_builder().get_page_cache() }.shrink(kmem, pages)
+}
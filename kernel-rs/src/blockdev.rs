@@ -0,0 +1,149 @@
+//! Block devices, which the buffer cache reads and writes blocks of.
+//!
+//! Each has a device number, which tells its blocks apart in the buffer
+//! cache: the root disk is ROOTDEV, the second virtio disk FATDEV, and the
+//! md device MDDEV.
+//!
+//! The reads and writes of each are counted, with their bytes and how long
+//! they took, and printed by /proc/diskstats.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use array_macro::array;
+use arrayvec::ArrayString;
+
+use crate::{
+    bio::Buf,
+    kernel::kernel_builder,
+    param::{BSIZE, FATDEV, MDDEV, ROOTDEV},
+    some_or,
+};
+
+/// Block devices are numbered below this.
+const NBLKDEV: usize = 4;
+
+/// Upper bounds of the latency buckets, in microseconds. The last bucket
+/// has the rest.
+const LATENCY_US: [u64; 5] = [10, 100, 1000, 10_000, 100_000];
+
+const NBUCKET: usize = LATENCY_US.len() + 1;
+
+/// Size of the text of /proc/diskstats, at most.
+const DISKSTATSSIZE: usize = 512;
+
+/// Statistics of the requests to a block device.
+struct IoStat {
+    reads: AtomicU64,
+    writes: AtomicU64,
+
+    /// Requests that have been made and have not finished, including those
+    /// waiting for the disk.
+    inflight: AtomicU32,
+
+    /// Requests by latency.
+    latency: [AtomicU64; NBUCKET],
+}
+
+static STATS: [IoStat; NBLKDEV] = array![_ => IoStat::new(); NBLKDEV];
+
+impl IoStat {
+    const fn new() -> Self {
+        Self {
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            inflight: AtomicU32::new(0),
+            latency: array![_ => AtomicU64::new(0); NBUCKET],
+        }
+    }
+}
+
+pub trait BlockDevice {
+    /// Returns Ok(a locked Buf with the latest contents of block blockno of
+    /// the device, whose number is dev) on success, Err(()) if no buffer is
+    /// free. On an I/O error, its data are zeros, and it stays invalid.
+    fn read(&self, dev: u32, blockno: u32) -> Result<Buf, ()>;
+
+    /// Like `read`, but fails on an I/O error.
+    /// Returns Ok(the locked Buf) on success, Err(()) on an I/O error or if
+    /// no buffer is free.
+    fn try_read(&self, dev: u32, blockno: u32) -> Result<Buf, ()>;
+
+    /// Writes the contents of b to the device.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn write(&self, b: &mut Buf) -> Result<(), ()>;
+
+    /// Waits until what was written to the device reaches stable storage.
+    /// Writes may reach it out of order before then.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn flush(&self) -> Result<(), ()>;
+
+    /// Tells the device that the n blocks from blockno are no longer used,
+    /// if it cares.
+    fn discard(&self, blockno: u32, n: u32);
+}
+
+/// Runs f, which reads or writes a block of the device whose number is
+/// dev, and counts it.
+pub fn account<R, F: FnOnce() -> R>(dev: u32, write: bool, f: F) -> R {
+    let stat = some_or!(STATS.get(dev as usize), return f());
+    let _ = stat.inflight.fetch_add(1, Ordering::Relaxed);
+    let clock = &kernel_builder().clock;
+    let start = clock.monotonic();
+    let result = f();
+    let us = (clock.monotonic() - start) / 1000;
+    let bucket = LATENCY_US
+        .iter()
+        .position(|bound| us < *bound)
+        .unwrap_or(NBUCKET - 1);
+    let _ = stat.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    let count = if write { &stat.writes } else { &stat.reads };
+    let _ = count.fetch_add(1, Ordering::Relaxed);
+    let _ = stat.inflight.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// The text of /proc/diskstats: a line for each attached device, of its
+/// number, reads, bytes read, writes, bytes written, requests in flight,
+/// and requests by latency, under a header.
+pub fn diskstats() -> ArrayString<[u8; DISKSTATSSIZE]> {
+    let mut text = ArrayString::new();
+    let _ = write!(text, "dev reads rbytes writes wbytes inflight");
+    for bound in LATENCY_US.iter() {
+        let _ = write!(text, " <{}us", bound);
+    }
+    let _ = writeln!(text, " more");
+    for (dev, stat) in STATS.iter().enumerate() {
+        if get(dev as u32).is_none() {
+            continue;
+        }
+        let reads = stat.reads.load(Ordering::Relaxed);
+        let writes = stat.writes.load(Ordering::Relaxed);
+        let _ = write!(
+            text,
+            "{} {} {} {} {} {}",
+            dev,
+            reads,
+            reads * BSIZE as u64,
+            writes,
+            writes * BSIZE as u64,
+            stat.inflight.load(Ordering::Relaxed)
+        );
+        for count in stat.latency.iter() {
+            let _ = write!(text, " {}", count.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(text);
+    }
+    text
+}
+
+/// Returns the block device whose number is dev, if it is attached.
+pub fn get(dev: u32) -> Option<&'static dyn BlockDevice> {
+    let kernel = kernel_builder();
+    match dev {
+        ROOTDEV => Some(&kernel.file_system.log.disk),
+        FATDEV if kernel.fat32.is_attached() => Some(&kernel.fat32.disk),
+        MDDEV if kernel.md.is_attached() => Some(&kernel.md),
+        _ => None,
+    }
+}
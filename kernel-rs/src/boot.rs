@@ -0,0 +1,74 @@
+//! Stages of the boot of the first hart.
+//!
+//! kernel_main() runs the stages in the order of `Stage`, and each may use
+//! what the stages before it set up. A subsystem starts in the first stage
+//! that sets up everything it needs: modules in module.rs name their stage,
+//! and run after the work of kernel_main() in it. The other harts wait
+//! until all stages end, and then set up only what is per hart.
+
+use crate::{
+    klog,
+    module::init_modules,
+    riscv::r_time,
+    time::{NSEC_PER_SEC, TIMEBASE_FREQ},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The UART and the console, so that the later stages can print.
+    EarlyConsole,
+
+    /// The page allocator, and the kernel page table with paging on.
+    Memory,
+
+    /// The clock, the process table, and the traps and the interrupts of
+    /// this hart.
+    PerCpu,
+
+    /// The disks and the keyboard, with their interrupt handlers.
+    Devices,
+
+    /// The caches of blocks and pages that the file systems use.
+    Fs,
+
+    /// The first user process.
+    Userspace,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::EarlyConsole => "early-console",
+            Stage::Memory => "memory",
+            Stage::PerCpu => "per-cpu",
+            Stage::Devices => "devices",
+            Stage::Fs => "fs",
+            Stage::Userspace => "userspace",
+        }
+    }
+
+    /// Starts the stage, whose work kernel_main() does next.
+    pub fn begin(self) -> StageRun {
+        StageRun {
+            stage: self,
+            start: r_time(),
+        }
+    }
+}
+
+/// A stage that kernel_main() is in.
+#[must_use]
+pub struct StageRun {
+    stage: Stage,
+    start: u64,
+}
+
+impl StageRun {
+    /// Ends the stage after the work of kernel_main(): runs the modules of
+    /// the stage, and logs how long it took.
+    pub fn end(self) {
+        init_modules(self.stage);
+        let us = (r_time() - self.start) * (NSEC_PER_SEC / TIMEBASE_FREQ) / 1000;
+        klog!(Info, "stage {}: {} us", self.stage.name(), us);
+    }
+}
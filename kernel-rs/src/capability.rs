@@ -0,0 +1,63 @@
+//! Capabilities, which allow privileged operations, shared with user programs
+//! through kernel/capability.h.
+//!
+//! Capabilities do not come from user ids: init starts with every capability,
+//! and a child inherits those of its parent. A process may drop a capability for
+//! good with prctl(PR_CAPBSET_DROP, cap); nothing gives it back.
+
+use bitflags::bitflags;
+
+use crate::proc::CurrentProc;
+
+/// Change the user id with setuid().
+pub const CAP_SETUID: i32 = 7;
+
+/// Change the root directory with chroot().
+pub const CAP_SYS_CHROOT: i32 = 18;
+
+/// Trace processes other than children with ptrace().
+pub const CAP_SYS_PTRACE: i32 = 19;
+
+/// Mount and unmount file systems.
+pub const CAP_SYS_ADMIN: i32 = 21;
+
+/// Shut down or restart the machine.
+pub const CAP_SYS_BOOT: i32 = 22;
+
+/// Create device files with mknod().
+pub const CAP_MKNOD: i32 = 27;
+
+bitflags! {
+    /// A set of capabilities. Each is the bit at its number.
+    pub struct Capabilities: u64 {
+        const SETUID = 1 << CAP_SETUID;
+        const SYS_CHROOT = 1 << CAP_SYS_CHROOT;
+        const SYS_PTRACE = 1 << CAP_SYS_PTRACE;
+        const SYS_ADMIN = 1 << CAP_SYS_ADMIN;
+        const SYS_BOOT = 1 << CAP_SYS_BOOT;
+        const MKNOD = 1 << CAP_MKNOD;
+    }
+}
+
+impl Capabilities {
+    /// The capability numbered `cap`, if any.
+    pub fn from_number(cap: i32) -> Option<Self> {
+        match cap {
+            CAP_SETUID => Some(Self::SETUID),
+            CAP_SYS_CHROOT => Some(Self::SYS_CHROOT),
+            CAP_SYS_PTRACE => Some(Self::SYS_PTRACE),
+            CAP_SYS_ADMIN => Some(Self::SYS_ADMIN),
+            CAP_SYS_BOOT => Some(Self::SYS_BOOT),
+            CAP_MKNOD => Some(Self::MKNOD),
+            _ => None,
+        }
+    }
+}
+
+impl CurrentProc<'_> {
+    /// Does the process have every capability in `caps`?
+    /// Every privileged operation checks it here.
+    pub fn is_privileged(&self, caps: Capabilities) -> bool {
+        self.deref_data().caps.contains(caps)
+    }
+}
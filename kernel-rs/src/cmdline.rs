@@ -0,0 +1,68 @@
+//! The kernel command line.
+//!
+//! The boot loader passes it in the `bootargs` property of /chosen in the
+//! device tree, which qemu fills from -append. It is a list of words
+//! separated by spaces, each `key=value` or just `key`, and subsystems look
+//! up their keys with `cmdline().get`. The known keys are:
+//!
+//! * `aslr=off`: load ET_DYN programs at a fixed address.
+//! * `watchdog=off`: do not watch the harts for lockups.
+
+use core::str;
+
+use crate::{param::CMDLINE, platform::platform};
+
+pub struct Cmdline {
+    buf: [u8; CMDLINE],
+    len: usize,
+}
+
+impl Cmdline {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; CMDLINE],
+            len: 0,
+        }
+    }
+
+    /// Copies the command line up to the first NUL in bytes. What does not
+    /// fit is cut off.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut cmdline = Self::new();
+        let bytes = bytes.split(|c| *c == 0).next().unwrap_or(&[]);
+        let mut len = bytes.len().min(CMDLINE);
+        // Do not cut a UTF-8 character in the middle.
+        while str::from_utf8(&bytes[..len]).is_err() {
+            len -= 1;
+        }
+        cmdline.buf[..len].copy_from_slice(&bytes[..len]);
+        cmdline.len = len;
+        cmdline
+    }
+
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    /// The value of `key`, "" if it is given without one, or None if it is
+    /// not given. The last one counts if it is given twice.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.as_str()
+            .split(' ')
+            .filter_map(|word| {
+                let mut parts = word.splitn(2, '=');
+                (parts.next() == Some(key)).then(|| parts.next().unwrap_or(""))
+            })
+            .last()
+    }
+
+    /// Is `key` turned off, with "off", "no" or "0"?
+    pub fn is_off(&self, key: &str) -> bool {
+        matches!(self.get(key), Some("off") | Some("no") | Some("0"))
+    }
+}
+
+/// The command line that the boot loader passed.
+pub fn cmdline() -> &'static Cmdline {
+    &platform().cmdline
+}
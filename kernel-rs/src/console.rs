@@ -1,18 +1,34 @@
-use core::fmt;
+use core::{
+    cmp, fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use array_macro::array;
 
 use crate::{
-    file::Devsw,
+    file::{Devsw, DevswTable},
     kernel::{kernel, kernel_builder},
-    lock::SleepablelockGuard,
-    param::NDEV,
+    lock::{Sleepablelock, SleepablelockGuard, Spinlock},
+    ok_or,
+    param::NCONSOLE,
+    some_or,
+    termios::{InputFlags, LocalFlags, Termios, TCGETS, TCSETS, VEOF, VERASE, VKILL, VMIN},
     uart::Uart,
-    vm::UVAddr,
+    vm::{UserPtr, UserSlice},
+    vt::{Screen, ROWS},
 };
 
 const CONSOLE_IN_DEVSW: usize = 1;
 /// Size of console input buffer.
 const INPUT_BUF: usize = 128;
+/// Maximum length of a key sequence handled by the console itself.
+const KEYSEQ_MAX: usize = 8;
+/// Shift-PageUp, which scrolls the active console back.
+const SCROLL_UP: &[u8] = b"\x1b[5;2~";
+/// Shift-PageDown, which scrolls the active console forward.
+const SCROLL_DOWN: &[u8] = b"\x1b[6;2~";
 
+/// A virtual console.
 pub struct Console {
     buf: [u8; INPUT_BUF],
 
@@ -24,6 +40,16 @@ pub struct Console {
 
     /// Edit index.
     e: u32,
+
+    /// Terminal attributes set by ioctl(TCSETS).
+    termios: Termios,
+
+    /// What the terminal shows, if this console is active.
+    screen: Screen,
+
+    /// Number of lines the view is scrolled back with Shift-PageUp.
+    /// Output is not sent to the uart while it is nonzero.
+    back: usize,
 }
 
 impl Console {
@@ -33,42 +59,67 @@ impl Console {
             r: 0,
             w: 0,
             e: 0,
+            termios: Termios::new(),
+            screen: Screen::new(),
+            back: 0,
         }
     }
 
     /// putc for Console.
+    /// Only called on the active virtual console.
     /// TODO(https://github.com/kaist-cp/rv6/issues/298)
     /// This function should be changed after refactoring Console-Uart-Printer relationship.
     pub fn putc(&mut self, c: i32) {
-        putc(c);
+        if c == BACKSPACE {
+            for &b in b"\x08 \x08" {
+                self.screen.putc(b);
+            }
+        } else {
+            self.screen.putc(c as u8);
+        }
+        if self.back == 0 {
+            putc(c);
+        }
     }
 
-    unsafe fn write(&mut self, src: UVAddr, n: i32) -> i32 {
-        for i in 0..n {
-            let mut c = [0u8];
-            // TODO: remove kernel_builder()
-            if kernel_builder()
-                .current_proc()
-                .expect("No current proc")
-                .memory_mut()
-                .copy_in_bytes(&mut c, src + i as usize)
-                .is_err()
-            {
-                return i;
-            }
-            // TODO(https://github.com/kaist-cp/rv6/issues/298): Temporarily using global function kernel().
-            // This implementation should be changed after refactoring Console-Uart-Printer relationship.
-            kernel_builder().uart.putc(c[0] as i32);
+    /// Redraw the terminal from the screen of this console.
+    fn redraw(&self) {
+        self.screen
+            .redraw(self.back, &mut Printer::new())
+            .expect("Console::redraw");
+    }
+
+    /// Scroll the view back (up) or forward (down) by a page.
+    fn scroll(&mut self, up: bool) {
+        self.back = if up {
+            cmp::min(self.back + ROWS - 1, self.screen.history())
+        } else {
+            self.back.saturating_sub(ROWS - 1)
+        };
+        self.redraw();
+    }
+
+    /// Echo `c` back to the user, unless echoing has been turned off.
+    fn echo(&mut self, c: i32) {
+        if self.termios.lflag.contains(LocalFlags::ECHO) {
+            self.putc(c);
         }
-        n
     }
 
-    unsafe fn read(this: &mut SleepablelockGuard<'_, Self>, mut dst: UVAddr, mut n: i32) -> i32 {
-        let target = n as u32;
+    unsafe fn read(this: &mut SleepablelockGuard<'_, Self>, dst: UserSlice) -> i32 {
+        let target = dst.len() as u32;
+        let mut n = target as i32;
+        let canonical = this.termios.is_canonical();
+        // In non-canonical mode, a read returns as soon as VMIN bytes
+        // (or as many as requested, if fewer) have arrived.
+        let min = cmp::min(this.termios.cc[VMIN] as u32, target);
         while n > 0 {
             // Wait until interrupt handler has put some
             // input into CONS.buffer.
             while this.r == this.w {
+                if !canonical && target.wrapping_sub(n as u32) >= min {
+                    return target.wrapping_sub(n as u32) as i32;
+                }
                 // TODO: remove kernel_builder()
                 if kernel_builder()
                     .current_proc()
@@ -84,7 +135,7 @@ impl Console {
             let cin = this.buf[fresh0.wrapping_rem(INPUT_BUF as u32) as usize] as i32;
 
             // end-of-file
-            if cin == ctrl('D') {
+            if canonical && this.termios.is_special(cin, VEOF) {
                 if (n as u32) < target {
                     // Save ^D for next time, to make sure
                     // caller gets a 0-byte result.
@@ -95,18 +146,13 @@ impl Console {
                 // Copy the input byte to the user-space buffer.
                 let cbuf = [cin as u8];
                 // TODO: remove kernel_builder()
-                if kernel_builder()
-                    .current_proc()
-                    .expect("No current proc")
-                    .memory_mut()
-                    .copy_out_bytes(dst, &cbuf)
-                    .is_err()
-                {
+                let mut proc = kernel_builder().current_proc().expect("No current proc");
+                let i = target.wrapping_sub(n as u32) as usize;
+                if dst.sub(i, 1).write(&cbuf, proc.memory_mut()).is_err() {
                     break;
                 }
-                dst = dst + 1;
                 n -= 1;
-                if cin == '\n' as i32 {
+                if canonical && cin == '\n' as i32 {
                     // A whole line has arrived, return to
                     // the user-level read().
                     break;
@@ -117,56 +163,92 @@ impl Console {
     }
 
     unsafe fn intr(this: &mut SleepablelockGuard<'_, Self>, mut cin: i32) {
+        let termios = this.termios;
+        let canonical = termios.is_canonical();
         match cin {
             // Print process list.
-            m if m == ctrl('P') => {
+            m if m == ctrl('P') && termios.lflag.contains(LocalFlags::ISIG) => {
                 // TODO: remove kernel()
                 unsafe { kernel().procs().dump() };
             }
 
             // Kill line.
-            m if m == ctrl('U') => {
+            m if canonical && termios.is_special(m, VKILL) => {
                 while this.e != this.w
                     && this.buf[this.e.wrapping_sub(1).wrapping_rem(INPUT_BUF as u32) as usize]
                         as i32
                         != '\n' as i32
                 {
                     this.e = this.e.wrapping_sub(1);
-                    this.putc(BACKSPACE);
+                    this.echo(BACKSPACE);
                 }
             }
 
             // Backspace
-            m if m == ctrl('H') | '\x7f' as i32 => {
+            m if canonical && (termios.is_special(m, VERASE) || m == '\x7f' as i32) => {
                 if this.e != this.w {
                     this.e = this.e.wrapping_sub(1);
-                    this.putc(BACKSPACE);
+                    this.echo(BACKSPACE);
                 }
             }
             _ => {
                 if cin != 0 && this.e.wrapping_sub(this.r) < INPUT_BUF as u32 {
-                    cin = if cin == '\r' as i32 { '\n' as i32 } else { cin };
+                    if cin == '\r' as i32 && termios.iflag.contains(InputFlags::ICRNL) {
+                        cin = '\n' as i32;
+                    }
 
                     // Echo back to the user.
-                    this.putc(cin);
+                    this.echo(cin);
 
                     // Store for consumption by consoleread().
                     let fresh1 = this.e;
                     this.e = this.e.wrapping_add(1);
                     this.buf[fresh1.wrapping_rem(INPUT_BUF as u32) as usize] = cin as u8;
-                    if cin == '\n' as i32
-                        || cin == ctrl('D')
+                    if !canonical
+                        || cin == '\n' as i32
+                        || termios.is_special(cin, VEOF)
                         || this.e == this.r.wrapping_add(INPUT_BUF as u32)
                     {
                         // Wake up consoleread() if a whole line (or end-of-file)
-                        // has arrived.
+                        // has arrived, or at every byte in non-canonical mode.
                         this.w = this.e;
-                        this.wakeup();
+                        this.wakeup_all();
                     }
                 }
             }
         }
     }
+
+    /// Get (TCGETS) or set (TCSETS) the terminal attributes.
+    /// `arg` is a user virtual address, pointing to a struct termios.
+    fn ioctl(this: &mut SleepablelockGuard<'_, Self>, req: i32, arg: usize) -> i32 {
+        // TODO: remove kernel_builder()
+        let mut proc = kernel_builder().current_proc().expect("No current proc");
+        let arg = ok_or!(UserPtr::<Termios>::new(arg), return -1);
+        match req {
+            TCGETS => {
+                if arg.write(&this.termios, proc.memory_mut()).is_err() {
+                    return -1;
+                }
+            }
+            TCSETS => {
+                let mut termios = this.termios;
+                // SAFETY: Termios only contains integers and bitflags of integers.
+                if unsafe { arg.read(&mut termios, proc.memory_mut()) }.is_err() {
+                    return -1;
+                }
+                termios.sanitize();
+                if !termios.is_canonical() {
+                    // Hand the partially edited line over to readers.
+                    this.w = this.e;
+                    this.wakeup_all();
+                }
+                this.termios = termios;
+            }
+            _ => return -1,
+        }
+        0
+    }
 }
 
 pub struct Printer {}
@@ -207,14 +289,147 @@ pub fn putc(c: i32) {
     };
 }
 
+/// The virtual consoles, multiplexed onto the uart.
+pub struct Consoles {
+    /// Each sleeps waiting for there are some input in its buffer.
+    vcs: [Sleepablelock<Console>; NCONSOLE],
+
+    /// The virtual console shown on the uart and receiving its input.
+    active: AtomicUsize,
+
+    input: Spinlock<Input>,
+}
+
+/// State of the input path, shared by the virtual consoles.
+struct Input {
+    /// Was the last input character the switch prefix, control-a?
+    prefix: bool,
+
+    /// Input held back because it may be a key sequence handled by the
+    /// console, such as Shift-PageUp.
+    seq: [u8; KEYSEQ_MAX],
+
+    /// Length of the held back input.
+    len: usize,
+}
+
+impl Consoles {
+    pub const fn new() -> Self {
+        Self {
+            vcs: array![_ => Sleepablelock::new("CONS", Console::new()); NCONSOLE],
+            active: AtomicUsize::new(0),
+            input: Spinlock::new(
+                "CONS_INPUT",
+                Input {
+                    prefix: false,
+                    seq: [0; KEYSEQ_MAX],
+                    len: 0,
+                },
+            ),
+        }
+    }
+
+    fn get(&self, minor: u16) -> Option<&Sleepablelock<Console>> {
+        self.vcs.get(minor as usize)
+    }
+
+    /// Show the virtual console `n` on the uart, and direct input to it.
+    pub fn switch(&self, n: usize) {
+        let console = some_or!(self.vcs.get(n), return).lock();
+        self.active.store(n, Ordering::Release);
+        console.redraw();
+    }
+
+    fn write(&self, minor: u16, src: UserSlice) -> i32 {
+        let vc = some_or!(self.get(minor), return -1);
+        for i in 0..src.len() {
+            let mut c = [0u8];
+            // TODO: remove kernel_builder()
+            let mut proc = kernel_builder().current_proc().expect("No current proc");
+            if src.sub(i, 1).read(&mut c, proc.memory_mut()).is_err() {
+                return i as i32;
+            }
+            // The uart may sleep, so record the byte and release the lock before sending it.
+            let mut console = vc.lock();
+            console.screen.putc(c[0]);
+            let active = self.active.load(Ordering::Acquire) == minor as usize && console.back == 0;
+            drop(console);
+            if active {
+                // TODO(https://github.com/kaist-cp/rv6/issues/298): Temporarily using global function kernel().
+                // This implementation should be changed after refactoring Console-Uart-Printer relationship.
+                kernel_builder().uart.putc(c[0] as i32);
+            }
+        }
+        src.len() as i32
+    }
+
+    fn intr(&self, cin: i32) {
+        let mut input = self.input.lock();
+        if input.prefix {
+            input.prefix = false;
+            // control-a 1 switches to the first virtual console, and so on.
+            // control-a control-a sends a control-a.
+            let n = cin - '1' as i32;
+            if 0 <= n && (n as usize) < NCONSOLE {
+                self.switch(n as usize);
+                return;
+            }
+        } else if cin == ctrl('A') {
+            input.prefix = true;
+            return;
+        }
+
+        if input.len == 0 && cin != 0x1b {
+            self.deliver(cin);
+            return;
+        }
+        let len = input.len;
+        input.seq[len] = cin as u8;
+        input.len += 1;
+        let seq = &input.seq[..input.len];
+        if seq == SCROLL_UP || seq == SCROLL_DOWN {
+            let up = seq == SCROLL_UP;
+            input.len = 0;
+            self.vcs[self.active.load(Ordering::Acquire)]
+                .lock()
+                .scroll(up);
+        } else if !SCROLL_UP.starts_with(seq) && !SCROLL_DOWN.starts_with(seq) {
+            self.flush(&mut input);
+        }
+    }
+
+    /// Deliver the held back input that turned out not to be a key sequence.
+    fn flush(&self, input: &mut Input) {
+        for &c in &input.seq[..input.len] {
+            self.deliver(c as i32);
+        }
+        input.len = 0;
+    }
+
+    /// Give an input character to the active virtual console.
+    fn deliver(&self, cin: i32) {
+        let mut console = self.vcs[self.active.load(Ordering::Acquire)].lock();
+        if console.back != 0 {
+            // Any key returns to the live view.
+            console.back = 0;
+            console.redraw();
+        }
+        unsafe { Console::intr(&mut console, cin) };
+    }
+}
+
 /// Console input and output, to the uart.
-/// Reads are line at a time.
-/// Implements special input characters:
+/// Reads are line at a time, unless canonical mode is
+/// turned off with ioctl(TCSETS).
+/// In canonical mode, implements special input characters:
 ///   newline -- end of line
 ///   control-h -- backspace
 ///   control-u -- kill line
 ///   control-d -- end of file
 ///   control-p -- print process list
+/// and, regardless of the mode,
+///   control-a n -- switch to the nth virtual console
+///   shift-pageup, shift-pagedown -- scroll the console back and forward
 const BACKSPACE: i32 = 0x100;
 
 /// Control-x
@@ -222,39 +437,55 @@ const fn ctrl(x: char) -> i32 {
     x as i32 - '@' as i32
 }
 
-pub unsafe fn consoleinit(devsw: &mut [Devsw; NDEV]) {
+pub unsafe fn consoleinit(devsw: &DevswTable) {
     // Connect read and write system calls
     // to consoleread and consolewrite.
-    devsw[CONSOLE_IN_DEVSW] = Devsw {
+    static CONSOLE: Devsw = Devsw {
         read: Some(consoleread),
         write: Some(consolewrite),
+        ioctl: Some(consoleioctl),
     };
+    devsw.register(CONSOLE_IN_DEVSW, &CONSOLE);
 }
 
 /// User write()s to the console go here.
-fn consolewrite(src: UVAddr, n: i32) -> i32 {
-    // TODO(https://github.com/kaist-cp/rv6/issues/298) Remove below comment.
-    // consolewrite() does not need console.lock() -- can lead to sleep() with lock held.
+fn consolewrite(minor: u16, src: UserSlice) -> i32 {
     // TODO: remove kernel_builder()
-    unsafe { (*kernel_builder().console.get_mut_raw()).write(src, n) }
+    kernel_builder().console.write(minor, src)
 }
 
 /// User read()s from the console go here.
 /// Copy (up to) a whole input line to dst.
 /// User_dist indicates whether dst is a user
 /// or kernel address.
-fn consoleread(dst: UVAddr, n: i32) -> i32 {
+fn consoleread(minor: u16, dst: UserSlice, _off: u32) -> i32 {
     // TODO: remove kernel_builder()
-    let mut console = kernel_builder().console.lock();
-    unsafe { Console::read(&mut console, dst, n) }
+    let mut console = some_or!(kernel_builder().console.get(minor), return -1).lock();
+    unsafe { Console::read(&mut console, dst) }
+}
+
+/// User ioctl()s on the console go here.
+fn consoleioctl(minor: u16, req: i32, arg: usize) -> i32 {
+    // TODO: remove kernel_builder()
+    let mut console = some_or!(kernel_builder().console.get(minor), return -1).lock();
+    Console::ioctl(&mut console, req, arg)
 }
 
 /// The console input interrupt handler.
 /// uartintr() calls this for input character.
-/// Do erase/kill processing, append to CONS.buf,
+/// Handle virtual console switching, then do erase/kill
+/// processing, append to the active console's buf, and
 /// wake up consoleread() if a whole line has arrived.
 pub unsafe fn consoleintr(cin: i32) {
     // TODO: remove kernel_builder()
-    let mut console = kernel_builder().console.lock();
-    unsafe { Console::intr(&mut console, cin) };
+    kernel_builder().console.intr(cin);
+}
+
+/// uartintr() calls this after handing over the input characters it has read.
+/// Input held back as the beginning of a key sequence, such as a lone escape,
+/// is delivered now instead of waiting for the rest.
+pub unsafe fn consoleintr_end() {
+    // TODO: remove kernel_builder()
+    let console = &kernel_builder().console;
+    console.flush(&mut console.input.lock());
 }
@@ -0,0 +1,247 @@
+//! Core dumps of processes that die from a fault.
+//!
+//! The core file is named "core", in the process's current directory. It is
+//! an ELF file that gdb reads: a note segment with the registers from the
+//! trapframe, and a load segment with the whole user memory, in which pages
+//! that the process cannot access read as zeros.
+
+use core::{cmp, mem};
+
+use crate::{
+    exec::{
+        ElfHdr, ProgFlags, ProgHdr, ELFCLASS64, ELFDATA2LSB, ELF_MAGIC, ELF_PROG_LOAD, EM_RISCV,
+    },
+    fs::{InodeType, Path, RcInode},
+    kernel::Kernel,
+    param::{BSIZE, MAXOPBLOCKS},
+    proc::CurrentProc,
+    riscv::{pgroundup, PGSIZE},
+    trap::ScauseKind,
+    vm::UserSlice,
+};
+
+/// ElfHdr type of core files
+const ET_CORE: u16 = 4;
+
+/// ProgHdr type of notes
+const ELF_PROG_NOTE: u32 = 4;
+
+// Types of notes.
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+
+// Signals as gdb reports them.
+const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGSEGV: i32 = 11;
+
+/// Number of notes.
+const NNOTE: usize = 2;
+
+/// Note header
+// It needs repr(C) because it's struct for in-disk representation
+// which should follow C(=machine) representation
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+struct NoteHdr {
+    namesz: u32,
+    descsz: u32,
+    typ: u32,
+    /// "CORE", padded to 4-byte alignment.
+    name: [u8; 8],
+}
+
+/// struct elf_prstatus of Linux, which gdb expects in NT_PRSTATUS.
+// It needs repr(C) because it's struct for in-disk representation
+// which should follow C(=machine) representation
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+struct Prstatus {
+    /// si_signo, si_code, and si_errno.
+    info: [i32; 3],
+    cursig: i16,
+    sigpend: usize,
+    sighold: usize,
+    pid: i32,
+    ppid: i32,
+    pgrp: i32,
+    sid: i32,
+    /// User, system, and children's times.
+    times: [[i64; 2]; 4],
+    /// pc, and then x1 to x31.
+    reg: [usize; 32],
+    fpvalid: i32,
+}
+
+impl NoteHdr {
+    const fn new(typ: u32, descsz: usize) -> Self {
+        Self {
+            namesz: 5,
+            descsz: descsz as u32,
+            typ,
+            name: *b"CORE\0\0\0\0",
+        }
+    }
+}
+
+/// The signal that gdb shows for a trap with the given cause.
+pub fn signal(cause: ScauseKind) -> i32 {
+    match cause {
+        ScauseKind::InstrMisaligned | ScauseKind::LoadMisaligned | ScauseKind::StoreMisaligned => {
+            SIGBUS
+        }
+        ScauseKind::IllegalInstr => SIGILL,
+        ScauseKind::Breakpoint => SIGTRAP,
+        _ => SIGSEGV,
+    }
+}
+
+/// Copies `value` into `buf` at `*off`, and advances `*off`.
+fn put<T>(buf: &mut [u8], off: &mut usize, value: &T) {
+    let size = mem::size_of::<T>();
+    // SAFETY: value is a valid reference to T and
+    // u8 does not have any internal structure.
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const _ as *const u8, size) };
+    buf[*off..*off + size].copy_from_slice(bytes);
+    *off += size;
+}
+
+impl Kernel {
+    /// Write the core file of the current process, which has taken a trap with
+    /// the given cause.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn dump_core(&self, cause: ScauseKind, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
+        // The first page holds the headers and the notes, and user memory
+        // follows from the next page.
+        let mut page = self.kmem.alloc_zeroed().ok_or(())?;
+
+        let tx = self.file_system.begin_transaction();
+        // SAFETY: b"core" does not contain any NUL characters.
+        let path = unsafe { Path::from_bytes(b"core") };
+        let created = self.create(path, None, InodeType::File, &tx, proc, |ip| ip.itrunc(&tx));
+        drop(tx);
+        let ptr = match created {
+            Ok((ptr, _)) => ptr,
+            Err(()) => {
+                self.kmem.free(page);
+                return Err(());
+            }
+        };
+
+        let headers_size = mem::size_of::<ElfHdr>() + 2 * mem::size_of::<ProgHdr>();
+        let note_size = NNOTE * mem::size_of::<NoteHdr>()
+            + mem::size_of::<Prstatus>()
+            + mem::size_of_val(&proc.trap_frame().fp);
+        let memory_size = pgroundup(proc.memory().size());
+        let mut off = 0;
+        put(
+            &mut page[..],
+            &mut off,
+            &ElfHdr {
+                magic: ELF_MAGIC,
+                elf: [ELFCLASS64, ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                typ: ET_CORE,
+                machine: EM_RISCV,
+                version: 1,
+                phoff: mem::size_of::<ElfHdr>(),
+                ehsize: mem::size_of::<ElfHdr>() as u16,
+                phentsize: mem::size_of::<ProgHdr>() as u16,
+                phnum: 2,
+                ..Default::default()
+            },
+        );
+        put(
+            &mut page[..],
+            &mut off,
+            &ProgHdr {
+                typ: ELF_PROG_NOTE,
+                off: headers_size,
+                filesz: note_size,
+                ..Default::default()
+            },
+        );
+        put(
+            &mut page[..],
+            &mut off,
+            &ProgHdr {
+                typ: ELF_PROG_LOAD,
+                flags: ProgFlags::READ | ProgFlags::WRITE | ProgFlags::EXEC,
+                off: PGSIZE,
+                filesz: memory_size,
+                memsz: memory_size,
+                align: PGSIZE,
+                ..Default::default()
+            },
+        );
+
+        let tf = proc.trap_frame();
+        put(
+            &mut page[..],
+            &mut off,
+            &NoteHdr::new(NT_PRSTATUS, mem::size_of::<Prstatus>()),
+        );
+        put(
+            &mut page[..],
+            &mut off,
+            &Prstatus {
+                info: [signal(cause), 0, 0],
+                cursig: signal(cause) as i16,
+                sigpend: 0,
+                sighold: 0,
+                pid: proc.pid(),
+                ppid: 0,
+                pgrp: 0,
+                sid: 0,
+                times: [[0; 2]; 4],
+                reg: tf.user_regs(),
+                fpvalid: 1,
+            },
+        );
+        put(
+            &mut page[..],
+            &mut off,
+            &NoteHdr::new(NT_FPREGSET, mem::size_of_val(&tf.fp)),
+        );
+        put(&mut page[..], &mut off, &tf.fp);
+
+        let mut file_off = 0;
+        let mut result = self.write_core(&ptr, &page[..], &mut file_off);
+
+        // Dump user memory a page at a time.
+        for va in num_iter::range_step(0, memory_size, PGSIZE) {
+            if result.is_err() {
+                break;
+            }
+            let len = cmp::min(PGSIZE, proc.memory().size() - va);
+            page.write_bytes(0);
+            let _ = UserSlice::new(va, len)
+                .and_then(|src| src.read(&mut page[..len], proc.memory_mut()));
+            result = self.write_core(&ptr, &page[..], &mut file_off);
+        }
+        self.kmem.free(page);
+
+        let tx = self.file_system.begin_transaction();
+        drop(ptr);
+        drop(tx);
+        result
+    }
+
+    /// Append `src` to the core file at `*off`, in as many transactions as
+    /// needed, and advance `*off`.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn write_core(&self, ptr: &RcInode, src: &[u8], off: &mut u32) -> Result<(), ()> {
+        // As in filewrite(), write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size.
+        let max = (MAXOPBLOCKS - 1 - 1 - 2) / 2 * BSIZE;
+        for chunk in src.chunks(max) {
+            let tx = self.file_system.begin_transaction();
+            let n = ptr.lock()?.write_bytes_kernel(chunk, *off, &tx)?;
+            if n != chunk.len() {
+                return Err(());
+            }
+            *off += n as u32;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Event and timer files, of eventfd() and timerfd().
+//!
+//! An event file is a counter. write() adds the u64 it is given, and read()
+//! returns the count as a u64 and resets it to 0, waiting while it is 0.
+//! A timer file counts the expirations of a timer, which expires `value`
+//! clock ticks after it is made, and then every `interval` ticks unless
+//! `interval` is 0. read() returns the expirations since the previous read
+//! as a u64, waiting while there are none. With O_NONBLOCK, read() fails
+//! instead of waiting.
+
+use core::mem;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{lock::Sleepablelock, proc::CurrentProc, vm::UserSlice};
+
+pub struct EventFile {
+    count: Sleepablelock<u64>,
+    nonblock: bool,
+}
+
+pub struct TimerFile {
+    /// The tick when the timer was made.
+    start: u32,
+    value: u32,
+    interval: u32,
+    /// Expirations that read() returned.
+    consumed: AtomicU32,
+    nonblock: bool,
+}
+
+impl EventFile {
+    pub const fn new(count: u64, nonblock: bool) -> Self {
+        Self {
+            count: Sleepablelock::new("eventfd", count),
+            nonblock,
+        }
+    }
+
+    pub fn read(&self, dst: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        if dst.len() < mem::size_of::<u64>() {
+            return Err(());
+        }
+        let mut count = self.count.lock();
+        while *count == 0 {
+            if self.nonblock || proc.killed() {
+                return Err(());
+            }
+            count.sleep();
+        }
+        dst.sub(0, mem::size_of::<u64>())
+            .write(&count.to_ne_bytes(), proc.memory_mut())?;
+        *count = 0;
+        Ok(mem::size_of::<u64>())
+    }
+
+    pub fn write(&self, src: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        if src.len() < mem::size_of::<u64>() {
+            return Err(());
+        }
+        let mut bytes = [0; mem::size_of::<u64>()];
+        src.sub(0, bytes.len())
+            .read(&mut bytes, proc.memory_mut())?;
+        let mut count = self.count.lock();
+        *count = count.checked_add(u64::from_ne_bytes(bytes)).ok_or(())?;
+        count.wakeup_all();
+        Ok(mem::size_of::<u64>())
+    }
+}
+
+impl TimerFile {
+    pub fn new(start: u32, value: u32, interval: u32, nonblock: bool) -> Self {
+        Self {
+            start,
+            value,
+            interval,
+            consumed: AtomicU32::new(0),
+            nonblock,
+        }
+    }
+
+    /// The expirations of the timer until `ticks`.
+    fn expirations(&self, ticks: u32) -> u32 {
+        let elapsed = ticks.wrapping_sub(self.start);
+        if elapsed < self.value {
+            0
+        } else if self.interval == 0 {
+            1
+        } else {
+            (elapsed - self.value) / self.interval + 1
+        }
+    }
+
+    pub fn read(
+        &self,
+        dst: UserSlice,
+        proc: &mut CurrentProc<'_>,
+        ticks: &Sleepablelock<u32>,
+    ) -> Result<usize, ()> {
+        if dst.len() < mem::size_of::<u64>() {
+            return Err(());
+        }
+        let mut ticks = ticks.lock();
+        let n = loop {
+            // `consumed` changes only while holding the lock of the ticks.
+            let consumed = self.consumed.load(Ordering::Relaxed);
+            let n = self.expirations(*ticks) - consumed;
+            if n > 0 {
+                self.consumed.store(consumed + n, Ordering::Relaxed);
+                break n;
+            }
+            if self.nonblock || proc.killed() {
+                return Err(());
+            }
+            ticks.sleep();
+        };
+        drop(ticks);
+        dst.sub(0, mem::size_of::<u64>())
+            .write(&(n as u64).to_ne_bytes(), proc.memory_mut())?;
+        Ok(mem::size_of::<u64>())
+    }
+}
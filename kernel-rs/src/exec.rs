@@ -2,24 +2,69 @@
 
 use core::{cmp, mem};
 
+use arrayvec::ArrayVec;
 use bitflags::bitflags;
-use itertools::*;
 
 use crate::{
+    cmdline::cmdline,
+    fpu::{fpu_off, FpContext},
     fs::Path,
+    kalloc::Kmem,
     kernel::Kernel,
+    lock::Spinlock,
+    memlayout::VDSO_PROC,
     page::Page,
-    param::MAXARG,
+    param::{MAXARG, MAXPATH},
     proc::CurrentProc,
-    riscv::{pgroundup, PGSIZE},
-    vm::{PAddr, UserMemory},
+    riscv::{pgroundup, r_time, PteFlags, PGSIZE},
+    vm::{PAddr, UserMemory, UserPtr, UserSlice},
 };
 
 /// "\x7FELF" in little endian
-const ELF_MAGIC: u32 = 0x464c457f;
+pub const ELF_MAGIC: u32 = 0x464c457f;
+
+/// 64-bit objects
+pub const ELFCLASS64: u8 = 2;
+
+/// Little-endian objects
+pub const ELFDATA2LSB: u8 = 1;
+
+// Values for ElfHdr type
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+/// RISC-V machine
+pub const EM_RISCV: u16 = 243;
 
 /// Values for Proghdr type
-const ELF_PROG_LOAD: u32 = 1;
+pub const ELF_PROG_LOAD: u32 = 1;
+const ELF_PROG_DYNAMIC: u32 = 2;
+
+// Tags of dynamic section entries.
+const DT_NULL: usize = 0;
+const DT_RELA: usize = 7;
+const DT_RELASZ: usize = 8;
+const DT_RELAENT: usize = 9;
+
+// Types of relocations.
+const R_RISCV_NONE: usize = 0;
+const R_RISCV_RELATIVE: usize = 3;
+
+/// ET_DYN files are loaded at a random page among the first ASLR_PAGES pages
+/// except page zero, which stays invalid for user access.
+const ASLR_PAGES: usize = 16;
+
+// Types of auxiliary vector entries.
+const AT_NULL: usize = 0;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+/// Number of words in the auxiliary vector.
+const AUXV_LEN: usize = 6;
+
+/// Maximum number of `#!` lines that exec follows, e.g. two for a script whose
+/// interpreter is also a script.
+const MAXINTERP: usize = 4;
 
 /// File header
 #[derive(Default, Clone)]
@@ -27,29 +72,29 @@ const ELF_PROG_LOAD: u32 = 1;
 // which should follow C(=machine) representation
 // https://github.com/kaist-cp/rv6/issues/52
 #[repr(C)]
-struct ElfHdr {
+pub struct ElfHdr {
     /// must equal ELF_MAGIC
-    magic: u32,
-    elf: [u8; 12],
-    typ: u16,
-    machine: u16,
-    version: u32,
-    entry: usize,
-    phoff: usize,
-    shoff: usize,
-    flags: u32,
-    ehsize: u16,
-    phentsize: u16,
-    phnum: u16,
-    shentsize: u16,
-    shnum: u16,
-    shstrndx: u16,
+    pub magic: u32,
+    pub elf: [u8; 12],
+    pub typ: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: usize,
+    pub phoff: usize,
+    pub shoff: usize,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
 }
 
 bitflags! {
     /// Flag bits for ProgHdr flags
     #[repr(C)]
-    struct ProgFlags: u32 {
+    pub struct ProgFlags: u32 {
         const EXEC = 1;
         const WRITE = 2;
         const READ = 4;
@@ -68,20 +113,48 @@ impl Default for ProgFlags {
 // which should follow C(=machine) representation
 // https://github.com/kaist-cp/rv6/issues/52
 #[repr(C)]
-struct ProgHdr {
-    typ: u32,
-    flags: ProgFlags,
-    off: usize,
-    vaddr: usize,
-    paddr: usize,
-    filesz: usize,
-    memsz: usize,
-    align: usize,
+pub struct ProgHdr {
+    pub typ: u32,
+    pub flags: ProgFlags,
+    pub off: usize,
+    pub vaddr: usize,
+    pub paddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub align: usize,
+}
+
+/// Dynamic section entry
+#[derive(Default, Clone)]
+// It needs repr(C) because it's struct for in-disk representation
+// which should follow C(=machine) representation
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+struct Dyn {
+    tag: usize,
+    val: usize,
+}
+
+/// Relocation with an addend
+#[derive(Default, Clone)]
+// It needs repr(C) because it's struct for in-disk representation
+// which should follow C(=machine) representation
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
 }
 
 impl ElfHdr {
     pub fn is_valid(&self) -> bool {
         self.magic == ELF_MAGIC
+            && self.elf[0] == ELFCLASS64
+            && self.elf[1] == ELFDATA2LSB
+            && (self.typ == ET_EXEC || self.typ == ET_DYN)
+            && self.machine == EM_RISCV
+            && self.phentsize as usize == mem::size_of::<ProgHdr>()
     }
 }
 
@@ -89,27 +162,279 @@ impl ProgHdr {
     pub fn is_prog_load(&self) -> bool {
         self.typ == ELF_PROG_LOAD
     }
+
+    pub fn is_prog_dynamic(&self) -> bool {
+        self.typ == ELF_PROG_DYNAMIC
+    }
+
+    /// Checks a loadable segment of a file of `size` bytes. The segment must
+    /// lie in the file, and must not be both writable and executable.
+    pub fn is_valid(&self, size: usize) -> bool {
+        self.memsz >= self.filesz
+            && self.vaddr % PGSIZE == 0
+            && self
+                .off
+                .checked_add(self.filesz)
+                .map_or(false, |end| end <= size)
+            && !self.flags.contains(ProgFlags::WRITE | ProgFlags::EXEC)
+    }
+
+    /// The permission of the pages of a loadable segment.
+    pub fn perm(&self) -> PteFlags {
+        let mut perm = PteFlags::empty();
+        perm.set(PteFlags::R, self.flags.contains(ProgFlags::READ));
+        perm.set(PteFlags::W, self.flags.contains(ProgFlags::WRITE));
+        perm.set(PteFlags::X, self.flags.contains(ProgFlags::EXEC));
+        perm
+    }
+}
+
+/// A page-aligned address at which to load an ET_DYN file, or the first one
+/// with aslr=off on the kernel command line.
+fn random_base() -> usize {
+    if cmdline().is_off("aslr") {
+        return PGSIZE;
+    }
+    // TODO: use a better source of randomness than the time counter.
+    let t = r_time() as usize;
+    let r = t ^ (t >> 7) ^ (t >> 13);
+    (1 + r % (ASLR_PAGES - 1)) * PGSIZE
+}
+
+/// Apply the relocations of an ET_DYN file loaded at `base`, whose dynamic
+/// section is `dynamic`. Only R_RISCV_RELATIVE is supported, which is the
+/// only kind in a static position-independent executable.
+/// Returns Ok(()) on success, Err(()) on error.
+fn relocate(mem: &mut UserMemory, base: usize, dynamic: &ProgHdr) -> Result<(), ()> {
+    let (mut rela, mut relasz, mut relaent) = (0, 0, mem::size_of::<Rela>());
+    let dynamic_ptr = UserPtr::<Dyn>::new(base.checked_add(dynamic.vaddr).ok_or(())?)?;
+    for i in 0..dynamic.memsz / mem::size_of::<Dyn>() {
+        let mut d: Dyn = Default::default();
+        // SAFETY: Dyn can be safely transmuted to [u8; _], as it
+        // contains only integers, which do not have internal structures.
+        unsafe { dynamic_ptr.add(i)?.read(&mut d, mem) }?;
+        match d.tag {
+            DT_NULL => break,
+            DT_RELA => rela = d.val,
+            DT_RELASZ => relasz = d.val,
+            DT_RELAENT => relaent = d.val,
+            _ => (),
+        }
+    }
+    if relaent != mem::size_of::<Rela>() {
+        return Err(());
+    }
+
+    let rela_ptr = UserPtr::<Rela>::new(base.checked_add(rela).ok_or(())?)?;
+    for i in 0..relasz / relaent {
+        let mut r: Rela = Default::default();
+        // SAFETY: Rela can be safely transmuted to [u8; _], as it
+        // contains only integers, which do not have internal structures.
+        unsafe { rela_ptr.add(i)?.read(&mut r, mem) }?;
+        match r.info & 0xffffffff {
+            R_RISCV_NONE => (),
+            R_RISCV_RELATIVE => {
+                let ptr = UserPtr::<usize>::new(base.checked_add(r.offset).ok_or(())?)?;
+                ptr.write(&base.wrapping_add(r.addend), mem)?;
+            }
+            _ => return Err(()),
+        }
+    }
+    Ok(())
+}
+
+/// The argument and environment strings of exec, each with its NUL, packed
+/// into one page. They have to fit in the one-page user stack anyway.
+pub struct ExecArgs {
+    page: Page,
+
+    /// Number of bytes of `page` in use.
+    len: usize,
+
+    /// Offsets of the argument strings in `page`.
+    args: ArrayVec<[usize; MAXARG]>,
+
+    /// Offsets of the environment strings in `page`.
+    envs: ArrayVec<[usize; MAXARG]>,
+}
+
+impl ExecArgs {
+    /// Returns Ok(no arguments and no environment) on success, Err(()) if
+    /// out of memory.
+    pub fn new(allocator: &Spinlock<Kmem>) -> Result<Self, ()> {
+        Ok(Self {
+            page: allocator.alloc().ok_or(())?,
+            len: 0,
+            args: ArrayVec::new(),
+            envs: ArrayVec::new(),
+        })
+    }
+
+    pub fn free(self, allocator: &Spinlock<Kmem>) {
+        allocator.free(self.page);
+    }
+
+    /// Copy the strings of the null-terminated array at user address
+    /// `uarray` into the arguments, or into the environment if `env`.
+    /// Returns Ok(()) on success, Err(()) on error, or if they do not fit.
+    pub fn fetch(
+        &mut self,
+        uarray: UserPtr<usize>,
+        env: bool,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<(), ()> {
+        for i in 0..=MAXARG {
+            let ustr = proc.fetchaddr(uarray.add(i)?)?;
+            if ustr == 0 {
+                return Ok(());
+            }
+            let s = proc.fetchstr(UserPtr::new(ustr)?, &mut self.page[self.len..])?;
+            let len = s.to_bytes().len() + 1;
+            let strs = if env { &mut self.envs } else { &mut self.args };
+            strs.try_push(self.len).map_err(|_| ())?;
+            self.len += len;
+        }
+        Err(())
+    }
+
+    /// Append `s` and a NUL to the page.
+    /// Returns Ok(its offset) on success, Err(()) if the page is full.
+    fn push_str(&mut self, s: &[u8]) -> Result<usize, ()> {
+        let off = self.len;
+        let dst = self.page.get_mut(off..off + s.len() + 1).ok_or(())?;
+        dst[..s.len()].copy_from_slice(s);
+        dst[s.len()] = 0;
+        self.len += s.len() + 1;
+        Ok(off)
+    }
+
+    /// Make the arguments of `script` into those of its interpreter:
+    /// `interp [arg] script args[1..]`.
+    /// Returns Ok(()) on success, Err(()) if they do not fit.
+    fn interpret(&mut self, script: &[u8], shebang: &Shebang<'_>) -> Result<(), ()> {
+        let script = self.push_str(script)?;
+        match self.args.first_mut() {
+            Some(arg0) => *arg0 = script,
+            None => self.args.try_push(script).map_err(|_| ())?,
+        }
+        for s in shebang.arg.iter().chain(Some(&shebang.interp)) {
+            let off = self.push_str(s)?;
+            self.args.try_insert(0, off).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the string at offset `off`, with its NUL.
+    fn get(&self, off: usize) -> &[u8] {
+        let len = self.page[off..]
+            .iter()
+            .position(|c| *c == 0)
+            .expect("exec: no null char found");
+        &self.page[off..=off + len]
+    }
+}
+
+/// The `#!interpreter [arg]` line of a script.
+struct Shebang<'a> {
+    interp: &'a [u8],
+    arg: Option<&'a [u8]>,
+}
+
+fn is_blank(c: &u8) -> bool {
+    *c == b' ' || *c == b'\t'
+}
+
+/// Removes leading and trailing spaces and tabs.
+fn trim(s: &[u8]) -> &[u8] {
+    match s.iter().position(|c| !is_blank(c)) {
+        Some(start) => &s[start..=s.iter().rposition(|c| !is_blank(c)).unwrap()],
+        None => &[],
+    }
 }
 
 impl Kernel {
+    /// Execute the file at `path`. If the file is a script that starts with
+    /// `#!interpreter [arg]`, execute the interpreter instead, with arguments
+    /// `interpreter [arg] path args[1..]`.
+    /// Returns Ok(argc argument to user main) on success, Err(()) on error.
     pub fn exec(
         &self,
         path: &Path,
-        args: &[Page],
+        args: &mut ExecArgs,
         proc: &mut CurrentProc<'_>,
     ) -> Result<usize, ()> {
-        if args.len() > MAXARG {
+        // The path of the file to execute, which each `#!` line replaces.
+        let mut file = [0u8; MAXPATH];
+        let mut len = path.as_bytes().len();
+        file.get_mut(..len)
+            .ok_or(())?
+            .copy_from_slice(path.as_bytes());
+
+        for _ in 0..=MAXINTERP {
+            // SAFETY: file[..len] is a path or an interpreter, and neither
+            // contains NUL.
+            let path = unsafe { Path::from_bytes(&file[..len]) };
+            let mut line = [0u8; MAXPATH];
+            let shebang = match self.read_shebang(path, &mut line, proc)? {
+                Some(shebang) => shebang,
+                None => return self.load(path, args, proc),
+            };
+            args.interpret(path.as_bytes(), &shebang)?;
+
+            len = shebang.interp.len();
+            file[..len].copy_from_slice(shebang.interp);
+        }
+        Err(())
+    }
+
+    /// Read the `#!` line at the start of the file at `path` into `line`.
+    /// Returns Ok(Some(shebang)) if there is one, Ok(None) if there is not,
+    /// Err(()) on error.
+    fn read_shebang<'a>(
+        &self,
+        path: &Path,
+        line: &'a mut [u8; MAXPATH],
+        proc: &CurrentProc<'_>,
+    ) -> Result<Option<Shebang<'a>>, ()> {
+        // As in load(), dropping the inode may write to the disk.
+        let tx = self.file_system.begin_transaction();
+        let ptr = self.itable.namei(path, None, proc)?;
+        let n = ptr.lock()?.read_bytes_kernel(line, 0)?;
+        drop(ptr);
+        drop(tx);
+
+        let line = &line[..n];
+        if !line.starts_with(b"#!") {
+            return Ok(None);
+        }
+        // The whole line must fit, and must not contain NUL.
+        let end = match line.iter().position(|c| *c == b'\n') {
+            Some(end) => end,
+            None if n < MAXPATH => n,
+            None => return Err(()),
+        };
+        let line = trim(&line[2..end]);
+        if line.is_empty() || line.contains(&0) {
             return Err(());
         }
+        let (interp, arg) = match line.iter().position(is_blank) {
+            Some(i) => (&line[..i], Some(trim(&line[i..]))),
+            None => (line, None),
+        };
+        Ok(Some(Shebang { interp, arg }))
+    }
 
+    /// Load the ELF file at `path` into a new user memory, and start it.
+    /// Returns Ok(argc argument to user main) on success, Err(()) on error.
+    fn load(&self, path: &Path, args: &ExecArgs, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         // TODO(https://github.com/kaist-cp/rv6/issues/290)
         // The method namei can drop inodes. If namei succeeds, its return
         // value, ptr, will be dropped when this method returns. Deallocation
         // of an inode may cause disk write operations, so we must begin a
         // transaction here.
         let tx = self.file_system.begin_transaction();
-        let ptr = self.itable.namei(path, proc)?;
-        let mut ip = ptr.lock();
+        let ptr = self.itable.namei(path, None, proc)?;
+        let mut ip = ptr.lock()?;
 
         // Check ELF header
         let mut elf: ElfHdr = Default::default();
@@ -123,20 +448,93 @@ impl Kernel {
         let trap_frame: PAddr = (proc.trap_frame() as *const _ as usize).into();
         let mem = UserMemory::new(trap_frame, None, &self.kmem).ok_or(())?;
         let mut mem = scopeguard::guard(mem, |mem| mem.free(&self.kmem));
+        mem.set_pid(proc.pid());
+        let text = self.texts.get(&ip);
+        if let Some(text) = &text {
+            mem.set_text(text.clone());
+        }
+
+        // Load an ET_DYN file at a random base, and leave the pages below it
+        // invalid for user access.
+        let base = if elf.typ == ET_DYN { random_base() } else { 0 };
+        let _ = mem.alloc(base, &self.kmem)?;
+        for va in num_iter::range_step(0, base, PGSIZE) {
+            mem.clear(va.into());
+        }
+
         // Load program into memory.
+        let size = ip.deref_inner().size as usize;
+        let entry = base.checked_add(elf.entry).ok_or(())?;
+        let mut entry_found = false;
+        let mut dynamic = None;
         for i in 0..elf.phnum as usize {
-            let off = elf.phoff + i * mem::size_of::<ProgHdr>();
+            let off = elf
+                .phoff
+                .checked_add(i * mem::size_of::<ProgHdr>())
+                .ok_or(())?;
 
             let mut ph: ProgHdr = Default::default();
             // SAFETY: ProgHdr can be safely transmuted to [u8; _], as it
             // contains only integers, which do not have internal structures.
             unsafe { ip.read_kernel(&mut ph, off as _) }?;
-            if ph.is_prog_load() {
-                if ph.memsz < ph.filesz || ph.vaddr % PGSIZE != 0 {
+            if ph.is_prog_dynamic() {
+                dynamic = Some(ph);
+            } else if ph.is_prog_load() {
+                if !ph.is_valid(size) {
+                    return Err(());
+                }
+                // Segments must be in increasing order without overlapping,
+                // and below the pages that the kernel maps.
+                let va = base.checked_add(ph.vaddr).ok_or(())?;
+                let end = va.checked_add(ph.memsz).ok_or(())?;
+                if va < mem.size() || end > VDSO_PROC {
                     return Err(());
                 }
-                let _ = mem.alloc(ph.vaddr.checked_add(ph.memsz).ok_or(())?, &self.kmem)?;
-                mem.load_file(ph.vaddr.into(), &mut ip, ph.off as _, ph.filesz as _)?;
+                if ph.flags.contains(ProgFlags::EXEC) && (va..end).contains(&entry) {
+                    entry_found = true;
+                }
+                let _ = mem.alloc(end, &self.kmem)?;
+                if ph.flags.contains(ProgFlags::WRITE) {
+                    mem.load_file(va.into(), &mut ip, ph.off as _, ph.filesz as _)?;
+                    continue;
+                }
+                // Share the pages of read-only segments with other processes
+                // running the program, or load a copy if they cannot be.
+                for i in num_iter::range_step(0, ph.filesz, PGSIZE) {
+                    let n = cmp::min(ph.filesz - i, PGSIZE);
+                    let off = (ph.off + i) as u32;
+                    match text
+                        .as_ref()
+                        .and_then(|text| text.page(&mut ip, off, n as _, &self.kmem).ok())
+                    {
+                        Some(pa) => mem.share((va + i).into(), pa, &self.kmem),
+                        None => mem.load_file((va + i).into(), &mut ip, off, n as _)?,
+                    }
+                }
+            }
+        }
+
+        if !entry_found {
+            return Err(());
+        }
+        if let Some(dynamic) = dynamic {
+            if elf.typ == ET_DYN {
+                relocate(&mut mem, base, &dynamic)?;
+            }
+        }
+
+        // The segments are writable while they are loaded and relocated.
+        // Now give them the permission in their headers, which is never both
+        // writable and executable.
+        for i in 0..elf.phnum as usize {
+            let mut ph: ProgHdr = Default::default();
+            // SAFETY: ProgHdr can be safely transmuted to [u8; _], as it
+            // contains only integers, which do not have internal structures.
+            unsafe { ip.read_kernel(&mut ph, (elf.phoff + i * mem::size_of::<ProgHdr>()) as _) }?;
+            if ph.is_prog_load() {
+                let va = base + ph.vaddr;
+                let end = pgroundup(va + ph.memsz);
+                mem.protect(va.into(), end - va, ph.perm())?;
             }
         }
         drop(ip);
@@ -150,38 +548,43 @@ impl Kernel {
         let mut sp: usize = sz;
         let stackbase: usize = sp - PGSIZE;
 
-        // Push argument strings, prepare rest of stack in ustack.
-        let mut ustack = [0usize; MAXARG + 1];
-        for (arg, stack) in izip!(args, &mut ustack) {
-            let null_idx = arg
-                .iter()
-                .position(|c| *c == 0)
-                .expect("exec: no null char found");
-            let bytes = &arg[..null_idx + 1];
-            sp -= bytes.len();
-
-            // riscv sp must be 16-byte aligned
-            sp &= !0xf;
-            if sp < stackbase {
-                return Err(());
-            }
+        // Push argument and environment strings, prepare rest of stack in
+        // ustack: argv[], envp[], each null-terminated, and then auxv[].
+        let mut ustack = [0usize; 2 * (MAXARG + 1) + AUXV_LEN];
+        let mut len = 0;
+        for strs in [&args.args, &args.envs].iter() {
+            for off in strs.iter() {
+                let bytes = args.get(*off);
+                sp -= bytes.len();
 
-            mem.copy_out_bytes(sp.into(), bytes)?;
-            *stack = sp;
+                // riscv sp must be 16-byte aligned
+                sp &= !0xf;
+                if sp < stackbase {
+                    return Err(());
+                }
+
+                UserSlice::new(sp, bytes.len())?.write(bytes, &mut mem)?;
+                ustack[len] = sp;
+                len += 1;
+            }
+            ustack[len] = 0;
+            len += 1;
         }
-        let argc: usize = args.len();
-        ustack[argc] = 0;
+        let auxv = [AT_PAGESZ, PGSIZE, AT_ENTRY, entry, AT_NULL, 0];
+        ustack[len..len + AUXV_LEN].copy_from_slice(&auxv);
+        len += AUXV_LEN;
+        let argc: usize = args.args.len();
 
-        // push the array of argv[] pointers.
-        let argv_size = (argc + 1) * mem::size_of::<usize>();
-        sp -= argv_size;
+        // push the arrays of argv[] and envp[] pointers, and auxv[].
+        let ustack_size = len * mem::size_of::<usize>();
+        sp -= ustack_size;
         sp &= !0xf;
         if sp < stackbase {
             return Err(());
         }
         // SAFETY: any byte can be considered as a valid u8.
         let (_, ustack, _) = unsafe { ustack.align_to::<u8>() };
-        mem.copy_out_bytes(sp.into(), &ustack[..argv_size])?;
+        UserSlice::new(sp, ustack_size)?.write(&ustack[..ustack_size], &mut mem)?;
 
         // Save program name for debugging.
         let path_str = path.as_bytes();
@@ -197,20 +600,37 @@ impl Kernel {
             proc_name[len] = 0;
         }
 
+        // While user code runs, nothing of the kernel but the trampoline and
+        // the trap frame may be mapped.
+        debug_assert!(
+            mem.is_isolated(trap_frame),
+            "exec: kernel memory mapped for user"
+        );
+
         // Commit to the user image.
         mem::replace(proc.memory_mut(), scopeguard::ScopeGuard::into_inner(mem)).free(&self.kmem);
+        proc.deref_mut_data().ring = None;
+        proc.update_oom_score();
+
+        // Close the files marked close-on-exec.
+        proc.files().close_on_exec();
 
-        // arguments to user main(argc, argv)
+        // arguments to user main(argc, argv, envp)
         // argc is returned via the system call return
         // value, which goes in a0.
         proc.trap_frame_mut().a1 = sp;
+        proc.trap_frame_mut().a2 = sp + (argc + 1) * mem::size_of::<usize>();
 
         // initial program counter = main
-        proc.trap_frame_mut().epc = elf.entry;
+        proc.trap_frame_mut().epc = entry;
 
         // initial stack pointer
         proc.trap_frame_mut().sp = sp;
 
+        // initial FP registers
+        proc.trap_frame_mut().fp = FpContext::new();
+        fpu_off();
+
         // this ends up in a0, the first argument to main(argc, argv)
         Ok(argc)
     }
@@ -0,0 +1,77 @@
+//! Fault injection, with the `fault-inject` feature, shared with user
+//! programs through kernel/fault.h.
+//!
+//! After faultinject(sites, rate), page allocations, arena allocations, and
+//! disk reads of file data fail at random, once in `rate` times, if they are
+//! in `sites` and the calling process makes them. The rest of the machine
+//! runs as usual, so that usertests can take error paths that real machines
+//! hardly ever take. A rate of 0 turns injection off.
+
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+use crate::{kernel::kernel_builder, riscv::r_time, some_or};
+
+/// Where a fault can be injected.
+#[derive(Copy, Clone)]
+pub enum Site {
+    /// `Kmem::alloc`.
+    Kalloc = 1,
+
+    /// `Arena::alloc`.
+    Arena = 2,
+
+    /// `Disk::try_read`.
+    Disk = 4,
+}
+
+struct Fault {
+    /// The `Site`s, as a mask.
+    sites: AtomicU32,
+    rate: AtomicU32,
+
+    /// The process whose requests fail.
+    pid: AtomicI32,
+
+    /// The state of a xorshift generator.
+    random: AtomicU64,
+}
+
+static FAULT: Fault = Fault {
+    sites: AtomicU32::new(0),
+    rate: AtomicU32::new(0),
+    pid: AtomicI32::new(0),
+    random: AtomicU64::new(1),
+};
+
+/// Makes requests of process `pid` at `sites` fail once in `rate` times.
+pub fn set(sites: u32, rate: u32, pid: i32) {
+    FAULT.rate.store(0, Ordering::Relaxed);
+    FAULT.random.store(r_time() | 1, Ordering::Relaxed);
+    FAULT.sites.store(sites, Ordering::Relaxed);
+    FAULT.pid.store(pid, Ordering::Relaxed);
+    FAULT.rate.store(rate, Ordering::Relaxed);
+}
+
+/// Should a request at `site` fail?
+pub fn should_fail(site: Site) -> bool {
+    let rate = FAULT.rate.load(Ordering::Relaxed);
+    if rate == 0 || FAULT.sites.load(Ordering::Relaxed) & site as u32 == 0 {
+        return false;
+    }
+    // TODO: remove kernel_builder()
+    let proc = some_or!(kernel_builder().current_proc(), return false);
+    if proc.pid() != FAULT.pid.load(Ordering::Relaxed) {
+        return false;
+    }
+    let next = |mut x: u64| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        Some(x)
+    };
+    let random = FAULT
+        .random
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, next)
+        .expect("should_fail");
+    next(random).expect("should_fail") % rate as u64 == 0
+}
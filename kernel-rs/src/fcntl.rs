@@ -7,5 +7,47 @@ bitflags! {
         const O_RDWR = 0x2;
         const O_CREATE = 0x200;
         const O_TRUNC = 0x400;
+        const O_NONBLOCK = 0x800;
+        const O_SYNC = 0x101000;
+        const O_CLOEXEC = 0x80000;
     }
 }
+
+/// The directory file descriptor of *at() calls that means the current
+/// directory.
+pub const AT_FDCWD: i32 = -100;
+
+/// unlinkat() flags.
+pub const AT_REMOVEDIR: i32 = 0x200;
+
+/// fcntl() commands.
+pub const F_GETFD: i32 = 1;
+pub const F_SETFD: i32 = 2;
+pub const F_SETPIPE_SZ: i32 = 1031;
+pub const F_GETPIPE_SZ: i32 = 1032;
+
+/// Descriptor flags of F_GETFD and F_SETFD.
+pub const FD_CLOEXEC: i32 = 1;
+
+/// access() modes.
+pub const F_OK: i32 = 0;
+pub const X_OK: i32 = 1;
+pub const W_OK: i32 = 2;
+pub const R_OK: i32 = 4;
+
+/// lseek() whences.
+pub const SEEK_SET: i32 = 0;
+pub const SEEK_CUR: i32 = 1;
+pub const SEEK_END: i32 = 2;
+pub const SEEK_DATA: i32 = 3;
+pub const SEEK_HOLE: i32 = 4;
+
+/// fallocate() modes.
+pub const FALLOC_FL_KEEP_SIZE: i32 = 1;
+pub const FALLOC_FL_PUNCH_HOLE: i32 = 2;
+
+/// flock() operations.
+pub const LOCK_SH: i32 = 1;
+pub const LOCK_EX: i32 = 2;
+pub const LOCK_NB: i32 = 4;
+pub const LOCK_UN: i32 = 8;
@@ -0,0 +1,238 @@
+//! Flattened device tree (FDT) parsing.
+//!
+//! The boot loader passes the physical address of a device tree blob in a1.
+//! Only what is needed to find memory, harts, devices, and the initial RAM
+//! disk is parsed: the nodes, their properties, and `reg` and `interrupts`
+//! values.
+//! See chapter 5 of the devicetree specification (https://www.devicetree.org).
+
+use core::slice;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// Size of the header, up to `size_dt_struct`, which appeared in version 17.
+const HEADER_SIZE: usize = 40;
+
+/// Larger blobs are assumed to be corrupt.
+const MAX_SIZE: usize = 1024 * 1024;
+
+// Tokens of the structure block.
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Maximum depth of nodes whose `#address-cells` and `#size-cells` are remembered.
+const MAXDEPTH: usize = 8;
+
+/// Reads the big-endian 32-bit integer at `off` of `bytes`.
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off.checked_add(4)?)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// The NUL-terminated string at `off` of `bytes`, without the NUL.
+fn cstr(bytes: &[u8], off: usize) -> Option<&[u8]> {
+    let s = bytes.get(off..)?;
+    let len = s.iter().position(|c| *c == 0)?;
+    Some(&s[..len])
+}
+
+/// Rounds `off` up to the 4-byte alignment of tokens.
+fn align(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    /// The structure block.
+    structs: &'a [u8],
+
+    /// The strings block, which holds the names of properties.
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Returns the device tree blob at physical address `addr`,
+    /// or `None` if there is no valid blob.
+    ///
+    /// # Safety
+    ///
+    /// If `addr` is not zero, it must be readable, and if a valid header is
+    /// there, the whole blob must be readable and remain unchanged for `'a`.
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        if addr == 0 || addr % 8 != 0 {
+            return None;
+        }
+        // SAFETY: the header is readable by the safety condition.
+        let header = unsafe { slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+        let field = |i: usize| be32(header, 4 * i).map(|x| x as usize);
+        if be32(header, 0)? != FDT_MAGIC || field(5)? < 17 {
+            return None;
+        }
+        let size = field(1)?;
+        if !(HEADER_SIZE..=MAX_SIZE).contains(&size) {
+            return None;
+        }
+        // SAFETY: the blob is readable by the safety condition.
+        let blob = unsafe { slice::from_raw_parts(addr as *const u8, size) };
+        let (off_struct, off_strings) = (field(2)?, field(3)?);
+        let (size_strings, size_struct) = (field(8)?, field(9)?);
+        Some(Self {
+            structs: blob.get(off_struct..off_struct.checked_add(size_struct)?)?,
+            strings: blob.get(off_strings..off_strings.checked_add(size_strings)?)?,
+        })
+    }
+
+    /// Iterates over all nodes in depth-first order, starting from the root.
+    pub fn nodes(self) -> Nodes<'a> {
+        Nodes {
+            fdt: self,
+            off: 0,
+            depth: 0,
+            cells: [Cells::DEFAULT; MAXDEPTH],
+        }
+    }
+}
+
+/// Numbers of 32-bit cells of an address and a size in `reg`,
+/// which the parent node of a node determines.
+#[derive(Copy, Clone)]
+struct Cells {
+    address: usize,
+    size: usize,
+}
+
+impl Cells {
+    const DEFAULT: Self = Self {
+        address: 2,
+        size: 1,
+    };
+}
+
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+
+    /// Offset of the next token in the structure block.
+    off: usize,
+
+    /// Depth of the next node.
+    depth: usize,
+
+    /// `cells[d]` is for the nodes at depth `d`.
+    cells: [Cells; MAXDEPTH],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = be32(structs, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(structs, self.off)?;
+                    self.off = align(self.off + name.len() + 1);
+                    let node = Node {
+                        fdt: self.fdt,
+                        props: self.off,
+                        cells: self.cells[self.depth.min(MAXDEPTH - 1)],
+                    };
+                    self.depth += 1;
+                    if let Some(cells) = self.cells.get_mut(self.depth) {
+                        *cells = Cells {
+                            address: node.u32("#address-cells").map_or(2, |x| x as usize),
+                            size: node.u32("#size-cells").map_or(1, |x| x as usize),
+                        };
+                    }
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = be32(structs, self.off)? as usize;
+                    self.off = align(self.off + 8 + len);
+                }
+                FDT_NOP => (),
+                // FDT_END, or a corrupt blob.
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+
+    /// Offset of the first property in the structure block.
+    props: usize,
+
+    /// For the `reg` property.
+    cells: Cells,
+}
+
+impl<'a> Node<'a> {
+    /// The value of the property `name`.
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        let structs = self.fdt.structs;
+        let mut off = self.props;
+        loop {
+            match be32(structs, off)? {
+                FDT_PROP => {
+                    let len = be32(structs, off + 4)? as usize;
+                    let nameoff = be32(structs, off + 8)? as usize;
+                    let value = structs.get(off + 12..off + 12 + len)?;
+                    if cstr(self.fdt.strings, nameoff)? == name.as_bytes() {
+                        return Some(value);
+                    }
+                    off = align(off + 12 + len);
+                }
+                FDT_NOP => off += 4,
+                _ => return None,
+            }
+        }
+    }
+
+    /// The value of the property `name`, which is a single 32-bit cell.
+    pub fn u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// The value of the property `name`, a number of one or two 32-bit cells.
+    pub fn number(&self, name: &str) -> Option<usize> {
+        let value = self.prop(name)?;
+        match value.len() {
+            4 => be32(value, 0).map(|x| x as usize),
+            8 => Some((be32(value, 0)? as usize) << 32 | be32(value, 4)? as usize),
+            _ => None,
+        }
+    }
+
+    /// Does the string list property `name` contain `value`?
+    pub fn has_str(&self, name: &str, value: &str) -> bool {
+        self.prop(name).map_or(false, |list| {
+            list.split(|c| *c == 0).any(|s| s == value.as_bytes())
+        })
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.has_str("compatible", compatible)
+    }
+
+    /// The address and size of the `i`th region in `reg`.
+    pub fn reg(&self, i: usize) -> Option<(usize, usize)> {
+        let reg = self.prop("reg")?;
+        let Cells { address, size } = self.cells;
+        let start = i.checked_mul(address + size)?;
+        let number = |from: usize, cells: usize| {
+            (from..from + cells).try_fold(0usize, |x, j| Some(x << 32 | be32(reg, 4 * j)? as usize))
+        };
+        Some((number(start, address)?, number(start + address, size)?))
+    }
+
+    /// The first interrupt number in `interrupts`.
+    pub fn interrupt(&self) -> Option<usize> {
+        self.u32("interrupts").map(|x| x as usize)
+    }
+}
@@ -0,0 +1,165 @@
+//! File descriptor tables.
+//!
+//! The open files of a process, indexed by file descriptors, are in an
+//! `FdTable`. Processes may share a table, so that a descriptor opened or
+//! closed by one is opened or closed by all, or each may have its own copy.
+
+use core::mem;
+
+use array_macro::array;
+
+use crate::{
+    arena::{Arena, ArenaObject, ArrayArena, Rc},
+    file::RcFile,
+    lock::Spinlock,
+    param::{NOFILE, NPROC},
+    some_or,
+};
+
+/// The file descriptor tables. A table is shared at most by every process,
+/// so NPROC tables are enough.
+pub type FdTables = Spinlock<ArrayArena<FdTable, NPROC>>;
+
+pub type RcFdTable = Rc<FdTables>;
+
+pub struct FdTable {
+    inner: Spinlock<FdTableInner>,
+}
+
+struct FdTableInner {
+    /// Open files.
+    files: [Option<RcFile>; NOFILE],
+
+    /// Which of the open files exec closes.
+    cloexec: [bool; NOFILE],
+}
+
+impl FdTable {
+    pub const fn zero() -> Self {
+        Self {
+            inner: Spinlock::new(
+                "FDTABLE",
+                FdTableInner {
+                    files: [None; NOFILE],
+                    cloexec: [false; NOFILE],
+                },
+            ),
+        }
+    }
+
+    /// Returns a new table with the same open files as this one.
+    /// Returns Ok(the table) on success, Err(()) on error.
+    pub fn copy(&self, tables: &FdTables) -> Result<RcFdTable, ()> {
+        tables
+            .alloc(|p| {
+                let inner = self.inner.lock();
+                let new = p.inner.get_mut();
+                new.files = array![fd => inner.files[fd].clone(); NOFILE];
+                new.cloexec = inner.cloexec;
+            })
+            .ok_or(())
+    }
+
+    /// Returns the file of descriptor fd.
+    pub fn get(&self, fd: i32) -> Result<RcFile, ()> {
+        let inner = self.inner.lock();
+        let file = inner.files.get(fd as usize).ok_or(())?;
+        file.clone().ok_or(())
+    }
+
+    /// Allocate the lowest free file descriptor for the given file.
+    /// Takes over file reference from caller on success.
+    /// Exec closes the descriptor if cloexec is true.
+    pub fn alloc(&self, file: RcFile, cloexec: bool) -> Result<i32, RcFile> {
+        let mut inner = self.inner.lock();
+        let fd = some_or!(
+            inner.files.iter().position(|f| f.is_none()),
+            return Err(file)
+        );
+        inner.files[fd] = Some(file);
+        inner.cloexec[fd] = cloexec;
+        Ok(fd as i32)
+    }
+
+    /// Release file descriptor fd.
+    /// Returns Ok(()) on success, Err(()) if fd is not open.
+    pub fn close(&self, fd: i32) -> Result<(), ()> {
+        let file = {
+            let mut inner = self.inner.lock();
+            inner.files.get_mut(fd as usize).ok_or(())?.take()
+        };
+        // Closing a file may sleep, so do it without holding the lock.
+        match file {
+            Some(file) => {
+                drop(file);
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    /// Returns whether exec closes file descriptor fd.
+    pub fn cloexec(&self, fd: i32) -> Result<bool, ()> {
+        let inner = self.inner.lock();
+        match inner.files.get(fd as usize) {
+            Some(Some(_)) => Ok(inner.cloexec[fd as usize]),
+            _ => Err(()),
+        }
+    }
+
+    /// Sets whether exec closes file descriptor fd.
+    pub fn set_cloexec(&self, fd: i32, cloexec: bool) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        match inner.files.get(fd as usize) {
+            Some(Some(_)) => {
+                inner.cloexec[fd as usize] = cloexec;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Close the files marked close-on-exec.
+    pub fn close_on_exec(&self) {
+        for fd in 0..NOFILE {
+            let file = {
+                let mut inner = self.inner.lock();
+                if mem::replace(&mut inner.cloexec[fd], false) {
+                    inner.files[fd].take()
+                } else {
+                    None
+                }
+            };
+            drop(file);
+        }
+    }
+}
+
+#[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
+impl const Default for FdTable {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl ArenaObject for FdTable {
+    fn finalize<'s, A: Arena>(&'s mut self, guard: &'s mut A::Guard<'_>) {
+        let inner = self.inner.get_mut();
+        let files = mem::replace(&mut inner.files, array![_ => None; NOFILE]);
+        inner.cloexec = [false; NOFILE];
+        // SAFETY: `FdTables` does not use `Arena::find_or_alloc`.
+        // Closing the files may sleep, so release the lock.
+        unsafe { A::reacquire_after(guard, || drop(files)) };
+    }
+}
+
+impl FdTables {
+    pub const fn zero() -> Self {
+        Spinlock::new("FDTABLES", ArrayArena::<FdTable, NPROC>::new())
+    }
+
+    /// Allocate a table with no open files.
+    pub fn alloc_fdtable(&self) -> Result<RcFdTable, ()> {
+        self.alloc(|_| ()).ok_or(())
+    }
+}
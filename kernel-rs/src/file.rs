@@ -1,23 +1,73 @@
 //! Support functions for system calls that involve file descriptors.
 
-use core::{cell::UnsafeCell, cmp, mem, ops::Deref, ops::DerefMut};
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use core::{cell::UnsafeCell, cmp, mem, ops::Deref, ops::DerefMut, ptr};
+
+use array_macro::array;
 
 use crate::{
     arena::{Arena, ArenaObject, ArrayArena, Rc},
-    fs::{FileSystem, InodeGuard, RcInode},
+    eventfd::{EventFile, TimerFile},
+    fcntl::{
+        FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN, SEEK_CUR,
+        SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET,
+    },
+    flock,
+    fs::{Fat32File, FileSystem, InodeGuard, RcInode, MAXFILE},
     kernel::kernel_builder,
     lock::Spinlock,
-    param::{BSIZE, MAXOPBLOCKS, NFILE},
+    mq::RcMessageQueue,
+    param::{BSIZE, MAXOPBLOCKS, NDEV, NFILE},
     pipe::AllocatedPipe,
     proc::CurrentProc,
-    vm::UVAddr,
+    rcu::{self, RcuPtr},
+    riscv::PGSIZE,
+    sem::RcSemaphore,
+    stat::Stat,
+    vm::{UserPtr, UserSlice},
 };
 
 pub enum FileType {
     None,
-    Pipe { pipe: AllocatedPipe },
-    Inode { inner: InodeFileType },
-    Device { ip: RcInode, major: &'static Devsw },
+    Pipe {
+        pipe: AllocatedPipe,
+    },
+    Inode {
+        inner: InodeFileType,
+    },
+    Device {
+        ip: RcInode,
+        major: u16,
+        minor: u16,
+        off: AtomicU32,
+    },
+    /// A named pipe, whose I/O goes to the pipe attached to its inode.
+    Fifo {
+        ip: RcInode,
+        pipe: AllocatedPipe,
+    },
+    /// A file or directory in the mounted FAT32 volume.
+    Fat32 {
+        file: Fat32File,
+    },
+    /// A counter of eventfd().
+    Event {
+        event: EventFile,
+    },
+    /// A timer of timerfd().
+    Timer {
+        timer: TimerFile,
+    },
+    /// A semaphore of sem_open().
+    Semaphore {
+        sem: RcSemaphore,
+    },
+    /// A message queue of mq_open().
+    MessageQueue {
+        mq: RcMessageQueue,
+        nonblock: bool,
+    },
 }
 
 /// It has an inode and an offset.
@@ -29,6 +79,8 @@ pub struct InodeFileType {
     pub ip: RcInode,
     // It should be accessed only when `ip` is locked.
     pub off: UnsafeCell<u32>,
+    /// Does each write() commit before it returns? O_SYNC.
+    pub sync: bool,
 }
 
 /// It can be acquired when the inode of `InodeFileType` is locked. `ip` is the guard of the locked
@@ -43,6 +95,9 @@ pub struct File {
     pub typ: FileType,
     readable: bool,
     writable: bool,
+
+    /// The flock() lock that this holds on its inode.
+    flock: AtomicU8,
 }
 
 pub type FileTable = Spinlock<ArrayArena<File, NFILE>>;
@@ -50,8 +105,46 @@ pub type FileTable = Spinlock<ArrayArena<File, NFILE>>;
 /// map major device number to device functions.
 #[derive(Copy, Clone)]
 pub struct Devsw {
-    pub read: Option<fn(_: UVAddr, _: i32) -> i32>,
-    pub write: Option<fn(_: UVAddr, _: i32) -> i32>,
+    /// Reads from the offset of the file, which then moves past what is read.
+    pub read: Option<fn(minor: u16, _: UserSlice, off: u32) -> i32>,
+    pub write: Option<fn(minor: u16, _: UserSlice) -> i32>,
+    pub ioctl: Option<fn(minor: u16, _: i32, _: usize) -> i32>,
+}
+
+/// The device registry, from major device numbers to device functions.
+///
+/// Each open, read, write, and ioctl of a device looks its major up, without
+/// a lock. Drivers may register and unregister at any time.
+pub struct DevswTable {
+    table: [RcuPtr<Devsw>; NDEV],
+}
+
+impl DevswTable {
+    pub const fn new() -> Self {
+        Self {
+            table: array![_ => RcuPtr::null(); NDEV],
+        }
+    }
+
+    /// Makes `major` go to `devsw`.
+    pub fn register(&self, major: usize, devsw: &'static Devsw) {
+        // SAFETY: `devsw` lives forever.
+        let _ = unsafe { self.table[major].replace(devsw) };
+    }
+
+    /// Makes `major` go nowhere. When this returns, no one is looking at the
+    /// old functions any more, though a call to one may still be running.
+    pub fn unregister(&self, major: usize) {
+        // SAFETY: null.
+        let _ = unsafe { self.table[major].replace(ptr::null()) };
+        rcu::synchronize();
+    }
+
+    /// Returns the functions of `major`, if it is registered.
+    pub fn get(&self, major: u16) -> Option<Devsw> {
+        let guard = rcu::read_lock();
+        self.table.get(major as usize)?.get(&guard).copied()
+    }
 }
 
 /// A reference counted smart pointer to a `File`.
@@ -64,11 +157,13 @@ impl Default for FileType {
 }
 
 impl InodeFileType {
-    fn lock(&self) -> InodeFileTypeGuard<'_> {
-        let ip = self.ip.lock();
+    /// Returns Ok(the guard) on success, Err(()) if no buffer is free to read
+    /// the inode.
+    fn lock(&self) -> Result<InodeFileTypeGuard<'_>, ()> {
+        let ip = self.ip.lock()?;
         // SAFETY: `ip` is locked and `off` can be exclusively accessed.
         let off = unsafe { &mut *self.off.get() };
-        InodeFileTypeGuard { ip, off }
+        Ok(InodeFileTypeGuard { ip, off })
     }
 }
 
@@ -92,6 +187,7 @@ impl File {
             typ,
             readable,
             writable,
+            flock: AtomicU8::new(flock::UNLOCKED),
         }
     }
 
@@ -100,49 +196,80 @@ impl File {
     }
 
     /// Get metadata about file self.
-    /// addr is a user virtual address, pointing to a struct stat.
-    pub fn stat(&self, addr: UVAddr, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
+    /// addr points to a struct stat in user memory.
+    pub fn stat(&self, addr: UserPtr<Stat>, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
         match &self.typ {
             FileType::Inode {
                 inner: InodeFileType { ip, .. },
             }
-            | FileType::Device { ip, .. } => {
+            | FileType::Device { ip, .. }
+            | FileType::Fifo { ip, .. } => {
                 let st = ip.stat();
-                proc.memory_mut().copy_out(addr, &st)
+                addr.write(&st, proc.memory_mut())
+            }
+            FileType::Fat32 { file } => {
+                // TODO: remove kernel_builder()
+                let st = kernel_builder().fat32.stat(file)?;
+                addr.write(&st, proc.memory_mut())
             }
             _ => Err(()),
         }
     }
 
-    /// Read from file self.
-    /// addr is a user virtual address.
-    pub fn read(&self, addr: UVAddr, n: i32, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+    /// Read from file self into dst in user memory.
+    pub fn read(&self, dst: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         if !self.readable {
             return Err(());
         }
 
         match &self.typ {
-            FileType::Pipe { pipe } => pipe.read(addr, n as usize, proc),
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe.read(dst, proc),
+            FileType::Event { event } => event.read(dst, proc),
+            // TODO: remove kernel_builder()
+            FileType::Timer { timer } => timer.read(dst, proc, &kernel_builder().ticks),
+            FileType::Semaphore { .. } | FileType::MessageQueue { .. } => Err(()),
             FileType::Inode { inner } => {
-                let mut ip = inner.lock();
+                let mut ip = inner.lock()?;
                 let curr_off = *ip.off;
-                let ret = ip.read_user(addr, curr_off, n as u32, proc);
+                let ret = ip.read_user(dst, curr_off, proc);
                 if let Ok(v) = ret {
                     *ip.off += v as u32;
                 }
                 ret
             }
-            FileType::Device { major, .. } => major.read.ok_or(()).map(|f| f(addr, n) as usize),
+            FileType::Device {
+                major, minor, off, ..
+            } => {
+                // TODO: remove kernel_builder()
+                let f = kernel_builder()
+                    .devsw
+                    .get(*major)
+                    .ok_or(())?
+                    .read
+                    .ok_or(())?;
+                let n = f(*minor, dst, off.load(Ordering::Relaxed));
+                if n > 0 {
+                    let _ = off.fetch_add(n as u32, Ordering::Relaxed);
+                }
+                Ok(n as usize)
+            }
+            FileType::Fat32 { file } => {
+                // TODO: remove kernel_builder()
+                kernel_builder()
+                    .fat32
+                    .read(file, dst.len() as u32, |off, src| {
+                        dst.sub(off as usize, src.len())
+                            .write(src, proc.memory_mut())
+                    })
+            }
             FileType::None => panic!("File::read"),
         }
     }
 
-    /// Write to file self.
-    /// addr is a user virtual address.
+    /// Write to file self from src in user memory.
     pub fn write(
         &self,
-        addr: UVAddr,
-        n: i32,
+        src: UserSlice,
         proc: &mut CurrentProc<'_>,
         fs: &FileSystem,
     ) -> Result<usize, ()> {
@@ -151,9 +278,13 @@ impl File {
         }
 
         match &self.typ {
-            FileType::Pipe { pipe } => pipe.write(addr, n as usize, proc),
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe.write(src, proc),
+            FileType::Event { event } => event.write(src, proc),
+            FileType::Timer { .. } | FileType::Semaphore { .. } | FileType::MessageQueue { .. } => {
+                Err(())
+            }
             FileType::Inode { inner } => {
-                let n = n as usize;
+                let n = src.len();
 
                 // write a few blocks at a time to avoid exceeding
                 // the maximum log transaction size, including
@@ -167,16 +298,10 @@ impl File {
                 while bytes_written < n {
                     let bytes_to_write = cmp::min(n - bytes_written, max);
                     let tx = fs.begin_transaction();
-                    let mut ip = inner.lock();
+                    let mut ip = inner.lock()?;
                     let curr_off = *ip.off;
                     let r = ip
-                        .write_user(
-                            addr + bytes_written,
-                            curr_off,
-                            bytes_to_write as u32,
-                            proc,
-                            &tx,
-                        )
+                        .write_user(src.sub(bytes_written, bytes_to_write), curr_off, proc, &tx)
                         .map(|v| {
                             *ip.off += v as u32;
                             v
@@ -187,15 +312,280 @@ impl File {
                     }
                     bytes_written += r;
                 }
+                if inner.sync || fs.is_sync() {
+                    fs.log.sync()?;
+                }
                 if bytes_written != n {
                     return Err(());
                 }
                 Ok(n)
             }
-            FileType::Device { major, .. } => major.write.ok_or(()).map(|f| f(addr, n) as usize),
+            FileType::Device { major, minor, .. } => {
+                // TODO: remove kernel_builder()
+                let f = kernel_builder()
+                    .devsw
+                    .get(*major)
+                    .ok_or(())?
+                    .write
+                    .ok_or(())?;
+                Ok(f(*minor, src) as usize)
+            }
+            FileType::Fat32 { file } => {
+                let n = src.len();
+                // TODO: remove kernel_builder()
+                let written = kernel_builder().fat32.write(file, n as u32, |off, dst| {
+                    src.sub(off as usize, dst.len())
+                        .read(dst, proc.memory_mut())
+                })?;
+                if written != n {
+                    return Err(());
+                }
+                Ok(n)
+            }
             FileType::None => panic!("File::read"),
         }
     }
+
+    /// Copy up to n bytes from the regular file src to self, through a
+    /// kernel page rather than user memory. Reads src at *off and advances
+    /// *off if off is given, and reads at and advances the offset of src
+    /// otherwise. If self is a regular file, it gets the holes of src, but
+    /// for one at the end of src, which is written so that self gets the
+    /// same size.
+    /// Returns Ok(number of bytes copied) on success, Err(()) on error.
+    pub fn sendfile(
+        &self,
+        src: &File,
+        mut off: Option<&mut u32>,
+        n: usize,
+        proc: &mut CurrentProc<'_>,
+        fs: &FileSystem,
+    ) -> Result<usize, ()> {
+        if !src.readable || !self.writable {
+            return Err(());
+        }
+        let src = match &src.typ {
+            FileType::Inode { inner } => inner,
+            _ => return Err(()),
+        };
+
+        // TODO: remove kernel_builder()
+        let kmem = &kernel_builder().kmem;
+        let mut page = scopeguard::guard(kmem.alloc().ok_or(())?, |page| kmem.free(page));
+        // A chunk must fit in a transaction if self is a regular file, as in
+        // File::write().
+        let max = cmp::min(PGSIZE, (MAXOPBLOCKS - 1 - 1 - 2) / 2 * BSIZE);
+
+        let mut copied = 0;
+        while copied < n {
+            let m = cmp::min(n - copied, max);
+            // Neither inode stays locked while writing, so self may be a
+            // pipe that waits for a reader of src, or src itself.
+            let (pos, read, hole) = {
+                let mut ip = src.lock()?;
+                let pos = off.as_deref().copied().unwrap_or(*ip.off);
+                // A chunk is all in data or all in a hole.
+                let hole = ip.seek_hole_or_data(pos, true)? == Some(pos);
+                let m = ip
+                    .seek_hole_or_data(pos, !hole)?
+                    .map_or(m, |next| cmp::min(m, (next - pos) as usize));
+                let read = ip.read_bytes_kernel(&mut page[..m], pos)?;
+                let hole = hole && pos + (read as u32) < ip.deref_inner().size;
+                (pos, read, hole)
+            };
+            if read == 0 {
+                break;
+            }
+            let written = match &self.typ {
+                FileType::Inode { inner } if hole => {
+                    *inner.lock()?.off += read as u32;
+                    read
+                }
+                _ => self.write_kernel(&page[..read], proc, fs)?,
+            };
+            match off.as_deref_mut() {
+                Some(off) => *off = pos + written as u32,
+                None => *src.lock()?.off = pos + written as u32,
+            }
+            copied += written;
+            if written < read {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Move the offset of self, a regular file, to off from where whence
+    /// says: the start, the offset, or the end. SEEK_DATA and SEEK_HOLE move
+    /// it to the first byte at or after off in data or in a hole instead. The
+    /// offset may go past the end, and a write there leaves a hole.
+    /// Returns Ok(the new offset) on success, Err(()) on error.
+    pub fn seek(&self, off: i32, whence: i32) -> Result<usize, ()> {
+        let inner = match &self.typ {
+            FileType::Inode { inner } => inner,
+            _ => return Err(()),
+        };
+        let mut ip = inner.lock()?;
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *ip.off,
+            SEEK_END => ip.deref_inner().size,
+            SEEK_DATA | SEEK_HOLE => {
+                let off = u32::try_from(off).map_err(|_| ())?;
+                *ip.off = ip.seek_hole_or_data(off, whence == SEEK_HOLE)?.ok_or(())?;
+                return Ok(*ip.off as usize);
+            }
+            _ => return Err(()),
+        };
+        *ip.off = u32::try_from(i64::from(base) + i64::from(off)).map_err(|_| ())?;
+        Ok(*ip.off as usize)
+    }
+
+    /// Apply or remove an advisory lock on the inode of self. op is LOCK_SH,
+    /// LOCK_EX, or LOCK_UN, and LOCK_SH and LOCK_EX may have LOCK_NB not to
+    /// wait for a conflicting lock.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn flock(&self, op: i32, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let ip = match &self.typ {
+            FileType::Inode {
+                inner: InodeFileType { ip, .. },
+            }
+            | FileType::Device { ip, .. }
+            | FileType::Fifo { ip, .. } => ip,
+            _ => return Err(()),
+        };
+        let nonblock = op & LOCK_NB != 0;
+        match op & !LOCK_NB {
+            LOCK_SH => ip.flock.lock(&self.flock, flock::SHARED, nonblock, proc),
+            LOCK_EX => ip.flock.lock(&self.flock, flock::EXCLUSIVE, nonblock, proc),
+            LOCK_UN => {
+                ip.flock.unlock(&self.flock);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Allocate blocks for len bytes of self, a regular file, at off, and
+    /// grow it to off + len unless mode has FALLOC_FL_KEEP_SIZE. With mode
+    /// FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, free them instead, leaving
+    /// a hole, and zero the parts of blocks at either end.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn fallocate(&self, mode: i32, off: u32, len: u32, fs: &FileSystem) -> Result<(), ()> {
+        if !self.writable || len == 0 {
+            return Err(());
+        }
+        let inner = match &self.typ {
+            FileType::Inode { inner } => inner,
+            _ => return Err(()),
+        };
+        let end = off.checked_add(len).ok_or(())?;
+        if end as usize > MAXFILE * BSIZE {
+            return Err(());
+        }
+        // Blocks per transaction, each of which may log a bit map block and
+        // the block itself, as in File::write().
+        let max = (MAXOPBLOCKS - 1 - 1 - 2) / 2;
+        let bsize = BSIZE as u32;
+
+        if mode == 0 || mode == FALLOC_FL_KEEP_SIZE {
+            let mut bn = (off / bsize) as usize;
+            let last = ((end - 1) / bsize) as usize;
+            while bn <= last {
+                let next = cmp::min(bn + max, last + 1);
+                let tx = fs.begin_transaction();
+                let mut ip = inner.lock()?;
+                ip.alloc_blocks(bn..next, &tx)?;
+                if mode == 0 && end > ip.deref_inner().size {
+                    ip.deref_inner_mut().size = end;
+                    ip.update(&tx)?;
+                }
+                bn = next;
+            }
+            return Ok(());
+        }
+        if mode != FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE {
+            return Err(());
+        }
+
+        let (first, last) = {
+            let tx = fs.begin_transaction();
+            let mut ip = inner.lock()?;
+            let size = ip.deref_inner().size;
+            let end = cmp::min(end, size);
+            if off >= end {
+                return Ok(());
+            }
+            // The blocks wholly in the range, and the last block if the range
+            // reaches the end of the file.
+            let first = (off + bsize - 1) / bsize;
+            let last = if end == size {
+                (end + bsize - 1) / bsize
+            } else {
+                end / bsize
+            };
+            let head_end = cmp::min(first * bsize, end);
+            if off < head_end {
+                ip.zero_bytes(off, head_end - off, &tx)?;
+            }
+            if first <= last && last * bsize < end {
+                ip.zero_bytes(last * bsize, end - last * bsize, &tx)?;
+            }
+            (first as usize, cmp::max(first, last) as usize)
+        };
+        let mut bn = first;
+        while bn < last {
+            let next = cmp::min(bn + max, last);
+            let tx = fs.begin_transaction();
+            inner.lock()?.free_blocks(bn..next, &tx)?;
+            bn = next;
+        }
+        Ok(())
+    }
+
+    /// Write src in kernel memory to self, which must be a pipe or a regular
+    /// file.
+    /// Returns Ok(number of bytes written) on success, Err(()) on error.
+    fn write_kernel(
+        &self,
+        src: &[u8],
+        proc: &mut CurrentProc<'_>,
+        fs: &FileSystem,
+    ) -> Result<usize, ()> {
+        match &self.typ {
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe.write_kernel(src, proc),
+            FileType::Inode { inner } => {
+                let tx = fs.begin_transaction();
+                let mut ip = inner.lock()?;
+                let curr_off = *ip.off;
+                let r = ip.write_bytes_kernel(src, curr_off, &tx)?;
+                *ip.off += r as u32;
+                Ok(r)
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Device-specific control of file self.
+    /// arg is usually a user virtual address, whose meaning depends on req.
+    pub fn ioctl(&self, req: i32, arg: usize) -> Result<(), ()> {
+        match &self.typ {
+            FileType::Device { major, minor, .. } => {
+                // TODO: remove kernel_builder()
+                let f = kernel_builder()
+                    .devsw
+                    .get(*major)
+                    .ok_or(())?
+                    .ioctl
+                    .ok_or(())?;
+                if f(*minor, req, arg) < 0 {
+                    return Err(());
+                }
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
 }
 
 #[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
@@ -213,15 +603,23 @@ impl ArenaObject for File {
                 let typ = mem::replace(&mut self.typ, FileType::None);
                 match typ {
                     FileType::Pipe { pipe } => {
-                        if let Some(page) = pipe.close(self.writable) {
-                            // TODO: remove kernel_builder()
-                            kernel_builder().kmem.free(page);
-                        }
+                        // TODO: remove kernel_builder()
+                        let _ = pipe.close(self.readable, self.writable, &kernel_builder().kmem);
+                    }
+                    FileType::Fifo { ip, pipe } => {
+                        ip.flock.unlock(&self.flock);
+                        // TODO: remove kernel_builder()
+                        let kernel = kernel_builder();
+                        pipe.close_fifo(&ip, self.readable, self.writable, &kernel.kmem);
+                        // Dropping ip may free it on disk; see below.
+                        let _tx = kernel.file_system.begin_transaction();
+                        drop(ip);
                     }
                     FileType::Inode {
                         inner: InodeFileType { ip, .. },
                     }
                     | FileType::Device { ip, .. } => {
+                        ip.flock.unlock(&self.flock);
                         // TODO(https://github.com/kaist-cp/rv6/issues/290)
                         // The inode ip will be dropped by drop(ip). Deallocation
                         // of an inode may cause disk write operations, so we must
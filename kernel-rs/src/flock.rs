@@ -0,0 +1,94 @@
+//! Advisory whole-file locks of flock().
+//!
+//! An inode can have any number of shared locks, or one exclusive lock. A
+//! lock belongs to an open file, so the descriptors that dup() and fork()
+//! make share it, and it is released when the file is closed for the last
+//! time. Converting a lock releases the old one first, so it is not atomic.
+//! Locks are advisory: nothing stops I/O by a process that does not ask.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{lock::Sleepablelock, proc::CurrentProc};
+
+/// What an open file holds, kept in the file.
+pub const UNLOCKED: u8 = 0;
+pub const SHARED: u8 = 1;
+pub const EXCLUSIVE: u8 = 2;
+
+/// The locks on an inode.
+pub struct Flock {
+    inner: Sleepablelock<FlockInner>,
+}
+
+struct FlockInner {
+    /// Number of shared locks.
+    shared: u32,
+
+    /// Is there an exclusive lock?
+    exclusive: bool,
+}
+
+impl Flock {
+    pub const fn new() -> Self {
+        Self {
+            inner: Sleepablelock::new(
+                "flock",
+                FlockInner {
+                    shared: 0,
+                    exclusive: false,
+                },
+            ),
+        }
+    }
+
+    /// Takes a lock of kind, SHARED or EXCLUSIVE, for a file that holds
+    /// `held`, releasing what it held before. Waits until no other file
+    /// holds a conflicting lock, unless nonblock is true.
+    /// Returns Ok(()) on success, Err(()) if it would wait with nonblock, or
+    /// the process was killed while waiting.
+    pub fn lock(
+        &self,
+        held: &AtomicU8,
+        kind: u8,
+        nonblock: bool,
+        proc: &CurrentProc<'_>,
+    ) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        // `held` changes only while `inner` is locked.
+        if held.load(Ordering::Relaxed) == kind {
+            return Ok(());
+        }
+        inner.remove(held.swap(UNLOCKED, Ordering::Relaxed));
+        inner.wakeup_all();
+        while inner.exclusive || (kind == EXCLUSIVE && inner.shared > 0) {
+            if nonblock || proc.killed() {
+                return Err(());
+            }
+            inner.sleep();
+        }
+        if kind == EXCLUSIVE {
+            inner.exclusive = true;
+        } else {
+            inner.shared += 1;
+        }
+        held.store(kind, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Releases what a file that holds `held` holds, if anything.
+    pub fn unlock(&self, held: &AtomicU8) {
+        let mut inner = self.inner.lock();
+        inner.remove(held.swap(UNLOCKED, Ordering::Relaxed));
+        inner.wakeup_all();
+    }
+}
+
+impl FlockInner {
+    fn remove(&mut self, kind: u8) {
+        match kind {
+            SHARED => self.shared -= 1,
+            EXCLUSIVE => self.exclusive = false,
+            _ => (),
+        }
+    }
+}
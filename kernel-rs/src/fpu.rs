@@ -0,0 +1,145 @@
+//! Floating-point registers of user processes.
+//!
+//! The kernel itself does not use floating point, so the hart's FP registers
+//! keep the current process's values while the kernel runs. The FS field of
+//! sstatus tracks them:
+//! * Off: the registers may belong to another process. scheduler() turns FP
+//!   off before switching to a process, so that its first FP instruction
+//!   traps, and usertrap() restores its registers from the trapframe.
+//! * Clean: the registers hold the process's values, also in the trapframe.
+//! * Dirty: the process has changed them since. usertrap() saves them in the
+//!   trapframe, so the trapframe is up to date while the kernel runs.
+
+use crate::riscv::Sstatus;
+
+/// FP registers f0-f31 and the FP control and status register.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct FpContext {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl FpContext {
+    pub const fn new() -> Self {
+        Self {
+            f: [0; 32],
+            fcsr: 0,
+        }
+    }
+
+    /// If the process has changed the FP registers, save them here.
+    /// Called on every trap from user space.
+    pub fn save_if_dirty(&mut self) {
+        let mut x = Sstatus::read();
+        if !x.contains(Sstatus::FS_DIRTY) {
+            return;
+        }
+        let fcsr: usize;
+        // SAFETY: FP is on, and `self.f` has room for all registers.
+        unsafe {
+            asm!(
+            "fsd f0, 0({0})",
+            "fsd f1, 8({0})",
+            "fsd f2, 16({0})",
+            "fsd f3, 24({0})",
+            "fsd f4, 32({0})",
+            "fsd f5, 40({0})",
+            "fsd f6, 48({0})",
+            "fsd f7, 56({0})",
+            "fsd f8, 64({0})",
+            "fsd f9, 72({0})",
+            "fsd f10, 80({0})",
+            "fsd f11, 88({0})",
+            "fsd f12, 96({0})",
+            "fsd f13, 104({0})",
+            "fsd f14, 112({0})",
+            "fsd f15, 120({0})",
+            "fsd f16, 128({0})",
+            "fsd f17, 136({0})",
+            "fsd f18, 144({0})",
+            "fsd f19, 152({0})",
+            "fsd f20, 160({0})",
+            "fsd f21, 168({0})",
+            "fsd f22, 176({0})",
+            "fsd f23, 184({0})",
+            "fsd f24, 192({0})",
+            "fsd f25, 200({0})",
+            "fsd f26, 208({0})",
+            "fsd f27, 216({0})",
+            "fsd f28, 224({0})",
+            "fsd f29, 232({0})",
+            "fsd f30, 240({0})",
+            "fsd f31, 248({0})",
+                "frcsr {1}",
+                in(reg) self.f.as_mut_ptr(),
+                out(reg) fcsr,
+            );
+        }
+        self.fcsr = fcsr;
+        x.remove(Sstatus::FS_DIRTY);
+        x.insert(Sstatus::FS_CLEAN);
+        unsafe { x.write() };
+    }
+
+    /// If FP is off, turn it on and restore the FP registers from here.
+    /// Returns true if it did, so that the trapping FP instruction can be retried.
+    pub fn restore_if_off(&self) -> bool {
+        let mut x = Sstatus::read();
+        if x.intersects(Sstatus::FS_DIRTY) {
+            return false;
+        }
+        x.insert(Sstatus::FS_CLEAN);
+        unsafe { x.write() };
+        // SAFETY: FP is on, and `self.f` has all registers.
+        unsafe {
+            asm!(
+            "fld f0, 0({0})",
+            "fld f1, 8({0})",
+            "fld f2, 16({0})",
+            "fld f3, 24({0})",
+            "fld f4, 32({0})",
+            "fld f5, 40({0})",
+            "fld f6, 48({0})",
+            "fld f7, 56({0})",
+            "fld f8, 64({0})",
+            "fld f9, 72({0})",
+            "fld f10, 80({0})",
+            "fld f11, 88({0})",
+            "fld f12, 96({0})",
+            "fld f13, 104({0})",
+            "fld f14, 112({0})",
+            "fld f15, 120({0})",
+            "fld f16, 128({0})",
+            "fld f17, 136({0})",
+            "fld f18, 144({0})",
+            "fld f19, 152({0})",
+            "fld f20, 160({0})",
+            "fld f21, 168({0})",
+            "fld f22, 176({0})",
+            "fld f23, 184({0})",
+            "fld f24, 192({0})",
+            "fld f25, 200({0})",
+            "fld f26, 208({0})",
+            "fld f27, 216({0})",
+            "fld f28, 224({0})",
+            "fld f29, 232({0})",
+            "fld f30, 240({0})",
+            "fld f31, 248({0})",
+                "fscsr {1}",
+                in(reg) self.f.as_ptr(),
+                in(reg) self.fcsr,
+            );
+        }
+        // The loads have made the registers dirty.
+        unsafe { x.write() };
+        true
+    }
+}
+
+/// Turn FP off, as the FP registers may not belong to the next process.
+pub fn fpu_off() {
+    let mut x = Sstatus::read();
+    x.remove(Sstatus::FS_DIRTY);
+    unsafe { x.write() };
+}
@@ -0,0 +1,868 @@
+//! FAT32 volumes, for exchanging files with the host.
+//!
+//! A FAT32 image on the second virtio disk or the md device can be mounted on
+//! a directory with mount(), one volume at a time. Only open() goes into a mounted volume: files in
+//! it can be created, read, written, and truncated, and directories listed,
+//! but directories cannot be made, and nothing can be removed or renamed.
+//! Names are 8.3 short names, matched regardless of case and listed in lower
+//! case; long names are not read or written, so a file that only has a long
+//! name is reached through its short alias.
+//!
+//! There is no log: the volume may be left inconsistent by a crash, as it
+//! may on other systems.
+
+use core::cmp;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use super::{InodeType, Path, RcInode, DIRENT_SIZE, DIRSIZ, ROOTINO};
+use crate::{
+    blockdev::{self, BlockDevice},
+    kernel::kernel_builder,
+    lock::{Sleepablelock, Sleeplock},
+    param::BSIZE,
+    some_or,
+    stat::Stat,
+    virtio::Disk,
+};
+
+/// Bytes per sector. Volumes with other sector sizes are not supported.
+const SECTOR_SIZE: usize = 512;
+
+/// Size of a directory entry.
+const ENTRY_SIZE: usize = 32;
+
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+
+/// Attribute bits of directory entries. Long name entries have
+/// `ATTR_VOLUME_ID` among others.
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+/// The first byte of the name of a free entry, and of the entry after the last.
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0;
+
+/// FAT entries have 28 bits. An entry this large or larger ends a chain.
+const FAT_MASK: u32 = 0x0fff_ffff;
+
+pub struct Fat32 {
+    /// The second virtio disk.
+    pub disk: Sleepablelock<Disk>,
+
+    /// Is the second disk attached?
+    attached: AtomicBool,
+
+    /// The mounted volume, if any.
+    volume: Sleeplock<Option<Volume>>,
+
+    /// Number of open files in the volume, which keep it mounted.
+    nopen: AtomicUsize,
+}
+
+struct Volume {
+    /// The directory that the volume is mounted on.
+    dir: RcInode,
+
+    disk: VolumeDisk,
+
+    sectors_per_cluster: u32,
+
+    /// First sector of the first FAT.
+    fat_start: u32,
+
+    /// Sectors in each FAT.
+    fat_sectors: u32,
+
+    /// Number of FATs, which are copies of each other.
+    nfats: u32,
+
+    /// First sector of cluster 2, the first cluster.
+    data_start: u32,
+
+    /// Clusters are numbered from 2 to nclusters + 1.
+    nclusters: u32,
+
+    root_cluster: u32,
+
+    /// Where to look for a free cluster next.
+    next_free: u32,
+}
+
+/// The device that holds a volume.
+#[derive(Copy, Clone)]
+struct VolumeDisk {
+    dev: u32,
+    disk: &'static dyn BlockDevice,
+}
+
+/// Where a directory entry is.
+#[derive(Copy, Clone)]
+struct EntryPos {
+    sector: u32,
+    index: u32,
+}
+
+/// The fields of a directory entry that we use.
+#[derive(Copy, Clone)]
+struct Entry {
+    name: [u8; 11],
+    attr: u8,
+
+    /// First cluster, or 0 if the file is empty. 0 in the ".." entry of a
+    /// directory in the root directory means the root directory.
+    cluster: u32,
+    size: u32,
+}
+
+/// A file or directory in the mounted volume, which an open file refers to.
+pub struct Fat32File {
+    /// Its directory entry, or None for the root directory, which has none.
+    entry: Option<EntryPos>,
+
+    /// Offset in the file. It is changed only while the volume is locked.
+    off: AtomicU32,
+}
+
+impl Fat32 {
+    pub const fn zero() -> Self {
+        Self {
+            disk: Sleepablelock::new("FAT32 DISK", Disk::zero()),
+            attached: AtomicBool::new(false),
+            volume: Sleeplock::new("FAT32", None),
+            nopen: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initializes the second disk at the virtio mmio interface at `base`,
+    /// one of platform(), if one is attached there and it is not initialized
+    /// yet. It may be called again later, for a disk plugged in after boot.
+    /// Returns whether it initialized one.
+    pub fn attach(&self, base: usize) -> bool {
+        let mut disk = self.disk.lock();
+        if self.is_attached() || !Disk::is_attached(base) {
+            return false;
+        }
+        disk.init(base);
+        self.attached.store(true, Ordering::Release);
+        true
+    }
+
+    /// Is the second disk attached?
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    /// Is a volume mounted?
+    pub fn is_mounted(&self) -> bool {
+        self.volume.lock().is_some()
+    }
+
+    /// Mounts the volume of the block device numbered dev on the directory
+    /// dir.
+    /// Returns Ok(()) on success, Err(()) if there is no such device, a volume
+    /// is mounted already, dir is not a directory or is the root, or the
+    /// device does not hold a FAT32 volume.
+    pub fn mount(&self, dev: u32, dir: RcInode) -> Result<(), ()> {
+        if dir.inum == ROOTINO || dir.lock()?.deref_inner().typ != InodeType::Dir {
+            return Err(());
+        }
+        let disk = VolumeDisk {
+            dev,
+            disk: blockdev::get(dev).ok_or(())?,
+        };
+        let mut volume = self.volume.lock();
+        if volume.is_some() {
+            return Err(());
+        }
+        let mut bpb = [0; SECTOR_SIZE];
+        read_sector(disk, 0, |data| bpb.copy_from_slice(data))?;
+        let u16_at = |i: usize| u16::from_le_bytes(bpb[i..i + 2].try_into().unwrap()) as u32;
+        let u32_at = |i: usize| u32::from_le_bytes(bpb[i..i + 4].try_into().unwrap());
+
+        let sectors_per_cluster = bpb[13] as u32;
+        let fat_start = u16_at(14);
+        let nfats = bpb[16] as u32;
+        let fat_sectors = u32_at(36);
+        let total_sectors = if u16_at(19) != 0 {
+            u16_at(19)
+        } else {
+            u32_at(32)
+        };
+        // FAT12 and FAT16 volumes have a root directory of a fixed size and
+        // a 16-bit FAT size instead.
+        if bpb[510..] != [0x55, 0xaa]
+            || u16_at(11) != SECTOR_SIZE as u32
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_start == 0
+            || nfats == 0
+            || u16_at(17) != 0
+            || u16_at(22) != 0
+            || fat_sectors == 0
+        {
+            return Err(());
+        }
+        // The FATs, and then at least a sector of data, must fit in the volume.
+        let data_start = nfats
+            .checked_mul(fat_sectors)
+            .and_then(|sectors| sectors.checked_add(fat_start))
+            .ok_or(())?;
+        if total_sectors <= data_start {
+            return Err(());
+        }
+        let fat_entries = fat_sectors
+            .checked_mul((SECTOR_SIZE / 4) as u32)
+            .ok_or(())?;
+        // Every cluster has an entry in the FAT, and ends within the volume,
+        // so the sectors of clusters and FAT entries do not overflow.
+        let nclusters = cmp::min(
+            (total_sectors - data_start) / sectors_per_cluster,
+            fat_entries - 2,
+        );
+        let new = Volume {
+            dir,
+            disk,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            nfats,
+            data_start,
+            nclusters,
+            root_cluster: u32_at(44),
+            next_free: 2,
+        };
+        if !new.is_cluster(new.root_cluster) {
+            return Err(());
+        }
+        // We do not keep the count of free clusters in the FSInfo sector up
+        // to date, so mark it unknown.
+        let fsinfo = u16_at(48);
+        if fsinfo != 0 && fsinfo < fat_start {
+            write_sector(disk, fsinfo, |data| {
+                if data[..4] == *b"RRaA" {
+                    data[488..492].copy_from_slice(&u32::MAX.to_le_bytes());
+                }
+            })?;
+        }
+        new.dir.mounted.store(true, Ordering::Release);
+        *volume = Some(new);
+        Ok(())
+    }
+
+    /// Unmounts the volume. The directory that it was mounted on is dropped,
+    /// so this must be called in a transaction.
+    /// Returns Ok(()) on success, Err(()) if no volume is mounted or a file
+    /// in it is open.
+    pub fn umount(&self) -> Result<(), ()> {
+        let mut volume = self.volume.lock();
+        if volume.is_none() || self.nopen.load(Ordering::Acquire) > 0 {
+            return Err(());
+        }
+        let volume = volume.take().unwrap();
+        volume.dir.mounted.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Flushes the write cache of the disk of the mounted volume, if any.
+    /// Everything else is written at once.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    pub fn sync(&self) -> Result<(), ()> {
+        match &*self.volume.lock() {
+            Some(volume) => volume.disk.disk.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Opens the file at path in the volume, which is relative to its root.
+    /// If create is true, makes a file there if there is none. If writable
+    /// is true, the file must not be a directory, and if trunc is also true,
+    /// it is emptied.
+    /// Returns Ok(the file) on success, Err(()) on error.
+    pub fn open(
+        &self,
+        path: &Path,
+        create: bool,
+        writable: bool,
+        trunc: bool,
+    ) -> Result<Fat32File, ()> {
+        let mut volume = self.volume.lock();
+        let volume = volume.as_mut().ok_or(())?;
+        let entry = volume.walk(path, create)?;
+        if writable {
+            let pos = entry.ok_or(())?;
+            if volume.entry(entry)?.is_dir() {
+                return Err(());
+            }
+            if trunc {
+                volume.truncate(pos)?;
+            }
+        }
+        let _ = self.nopen.fetch_add(1, Ordering::AcqRel);
+        Ok(Fat32File {
+            entry,
+            off: AtomicU32::new(0),
+        })
+    }
+
+    /// Gets metadata about file. Its inode number is made up from where its
+    /// directory entry is.
+    pub fn stat(&self, file: &Fat32File) -> Result<Stat, ()> {
+        let volume = self.volume.lock();
+        let volume = volume.as_ref().ok_or(())?;
+        let entry = volume.entry(file.entry)?;
+        Ok(Stat {
+            dev: volume.disk.dev as i32,
+            ino: file
+                .entry
+                .map_or(ROOTINO, |pos| pos.sector * ENTRIES_PER_SECTOR + pos.index),
+            // T_DIR or T_FILE.
+            typ: if entry.is_dir() { 1 } else { 2 },
+            nlink: 1,
+            size: entry.size as usize,
+        })
+    }
+
+    /// Reads up to n bytes from the offset of file, passing each piece to
+    /// `f` with its offset from the start of what is read, and moves the
+    /// offset past what is read. A directory reads as `Dirent`s, one for
+    /// each of its entries, of which only files and directories have an
+    /// inum other than 0.
+    /// Returns Ok(number of bytes read) on success, Err(()) on error.
+    pub fn read<F: FnMut(u32, &[u8]) -> Result<(), ()>>(
+        &self,
+        file: &Fat32File,
+        n: u32,
+        mut f: F,
+    ) -> Result<usize, ()> {
+        let volume = self.volume.lock();
+        let volume = volume.as_ref().ok_or(())?;
+        let entry = volume.entry(file.entry)?;
+        let off = file.off.load(Ordering::Relaxed);
+        let read = if entry.is_dir() {
+            let first = volume.first_cluster(&entry);
+            let mut read = 0;
+            while read + DIRENT_SIZE as u32 <= n {
+                let index = (off + read) / DIRENT_SIZE as u32;
+                let dirent = match volume.dirent(first, index)? {
+                    Some(dirent) => dirent,
+                    None => break,
+                };
+                f(read, &dirent)?;
+                read += DIRENT_SIZE as u32;
+            }
+            read
+        } else {
+            volume.read(&entry, off, n, f)?
+        };
+        file.off.store(off + read, Ordering::Relaxed);
+        Ok(read as usize)
+    }
+
+    /// Writes n bytes at the offset of file, a regular file, getting each
+    /// piece from `f` with its offset from the start of what is written, and
+    /// moves the offset past what is written. The offset must not be past the
+    /// end.
+    /// Returns Ok(number of bytes written) on success, Err(()) on error. It
+    /// is less than n if the volume is full.
+    pub fn write<F: FnMut(u32, &mut [u8]) -> Result<(), ()>>(
+        &self,
+        file: &Fat32File,
+        n: u32,
+        f: F,
+    ) -> Result<usize, ()> {
+        let mut volume = self.volume.lock();
+        let volume = volume.as_mut().ok_or(())?;
+        let pos = file.entry.ok_or(())?;
+        let off = file.off.load(Ordering::Relaxed);
+        let written = volume.write(pos, off, n, f)?;
+        file.off.store(off + written, Ordering::Relaxed);
+        Ok(written as usize)
+    }
+}
+
+impl Drop for Fat32File {
+    fn drop(&mut self) {
+        // TODO: remove kernel_builder()
+        let _ = kernel_builder().fat32.nopen.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Volume {
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.nclusters
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    /// Returns Ok(the first sector of cluster) on success, Err(()) if it is not
+    /// a cluster of the volume.
+    fn cluster_sector(&self, cluster: u32) -> Result<u32, ()> {
+        if !self.is_cluster(cluster) {
+            return Err(());
+        }
+        Ok(self.data_start + (cluster - 2) * self.sectors_per_cluster)
+    }
+
+    /// Returns the FAT entry of cluster: the next cluster in its chain, an
+    /// end of chain, or 0 if it is free.
+    /// Returns Ok(the entry) on success, Err(()) on an I/O error.
+    fn fat_get(&self, cluster: u32) -> Result<u32, ()> {
+        let (sector, i) = self.fat_offset(cluster);
+        read_sector(self.disk, self.fat_start + sector, |data| {
+            u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) & FAT_MASK
+        })
+    }
+
+    /// Sets the FAT entry of cluster in every FAT.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn fat_set(&self, cluster: u32, value: u32) -> Result<(), ()> {
+        let (sector, i) = self.fat_offset(cluster);
+        for fat in 0..self.nfats {
+            write_sector(
+                self.disk,
+                self.fat_start + fat * self.fat_sectors + sector,
+                |data| {
+                    let old = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+                    // The top 4 bits are reserved.
+                    let new = (old & !FAT_MASK) | value;
+                    data[i..i + 4].copy_from_slice(&new.to_le_bytes());
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Where the FAT entry of cluster is: the sector in a FAT, and the offset
+    /// in the sector.
+    fn fat_offset(&self, cluster: u32) -> (u32, usize) {
+        let off = cluster as usize * 4;
+        ((off / SECTOR_SIZE) as u32, off % SECTOR_SIZE)
+    }
+
+    /// Allocates a cluster, fills it with zeros, and appends it to the chain
+    /// that ends at prev, if any.
+    /// Returns Ok(the cluster) on success, Err(()) if the volume is full or on
+    /// an I/O error.
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, ()> {
+        for i in 0..self.nclusters {
+            let cluster = 2 + (self.next_free - 2 + i) % self.nclusters;
+            if self.fat_get(cluster)? != 0 {
+                continue;
+            }
+            self.fat_set(cluster, FAT_MASK)?;
+            let first = self.cluster_sector(cluster)?;
+            for sector in first..first + self.sectors_per_cluster {
+                write_sector(self.disk, sector, |data| data.fill(0))?;
+            }
+            if let Some(prev) = prev {
+                self.fat_set(prev, cluster)?;
+            }
+            self.next_free = 2 + (cluster - 1) % self.nclusters;
+            return Ok(cluster);
+        }
+        Err(())
+    }
+
+    /// Frees the chain that starts at cluster.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn free_chain(&mut self, mut cluster: u32) -> Result<(), ()> {
+        // A broken FAT may have a cycle.
+        for _ in 0..self.nclusters {
+            if !self.is_cluster(cluster) {
+                break;
+            }
+            let next = self.fat_get(cluster)?;
+            self.fat_set(cluster, 0)?;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// Returns the cluster after cluster in its chain, allocating one at the
+    /// end if there is none.
+    fn next_or_alloc(&mut self, cluster: u32) -> Result<u32, ()> {
+        let next = self.fat_get(cluster)?;
+        if self.is_cluster(next) {
+            Ok(next)
+        } else {
+            self.alloc_cluster(Some(cluster))
+        }
+    }
+
+    /// Returns the first cluster of the directory or file of entry.
+    fn first_cluster(&self, entry: &Entry) -> u32 {
+        if entry.cluster == 0 && entry.is_dir() {
+            self.root_cluster
+        } else {
+            entry.cluster
+        }
+    }
+
+    /// Returns the directory entry at pos, or a made-up one for the root
+    /// directory if pos is None.
+    /// Returns Ok(the entry) on success, Err(()) on an I/O error.
+    fn entry(&self, pos: Option<EntryPos>) -> Result<Entry, ()> {
+        match pos {
+            Some(pos) => read_sector(self.disk, pos.sector, |data| Entry::parse(pos.of(data))),
+            None => {
+                Ok(Entry {
+                    name: [b' '; 11],
+                    attr: ATTR_DIRECTORY,
+                    cluster: self.root_cluster,
+                    size: 0,
+                })
+            }
+        }
+    }
+
+    /// Stores entry at pos, keeping the fields that `Entry` does not have.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn set_entry(&self, pos: EntryPos, entry: &Entry) -> Result<(), ()> {
+        write_sector(self.disk, pos.sector, |data| entry.store(pos.of_mut(data)))
+    }
+
+    /// Calls `f` on the entries of the directory that starts at cluster
+    /// first, in order, until it returns Some.
+    /// Returns Ok(what `f` returned, or None if it never returned Some) on
+    /// success, Err(()) on an I/O error.
+    fn scan_dir<R, F: FnMut(EntryPos, &Entry) -> Option<R>>(
+        &self,
+        first: u32,
+        mut f: F,
+    ) -> Result<Option<R>, ()> {
+        let mut cluster = first;
+        // A broken FAT may have a cycle.
+        for _ in 0..self.nclusters {
+            if !self.is_cluster(cluster) {
+                break;
+            }
+            let start = self.cluster_sector(cluster)?;
+            for sector in start..start + self.sectors_per_cluster {
+                let found = read_sector(self.disk, sector, |data| {
+                    (0..ENTRIES_PER_SECTOR).find_map(|index| {
+                        let pos = EntryPos { sector, index };
+                        f(pos, &Entry::parse(pos.of(data)))
+                    })
+                })?;
+                if found.is_some() {
+                    return Ok(found);
+                }
+            }
+            cluster = self.fat_get(cluster)?;
+        }
+        Ok(None)
+    }
+
+    /// Returns the `Dirent` for entry index of the directory that starts at
+    /// cluster first, or None if there is no such entry.
+    /// Returns Ok(the `Dirent` or None) on success, Err(()) on an I/O error.
+    fn dirent(&self, first: u32, index: u32) -> Result<Option<[u8; DIRENT_SIZE]>, ()> {
+        let mut i = 0;
+        let entry = some_or!(
+            self.scan_dir(first, |_, entry| {
+                if entry.name[0] == ENTRY_END {
+                    return Some(None);
+                }
+                i += 1;
+                if i > index {
+                    Some(Some(*entry))
+                } else {
+                    None
+                }
+            })?
+            .flatten(),
+            return Ok(None)
+        );
+        let mut dirent = [0; DIRENT_SIZE];
+        if entry.is_file_or_dir() {
+            // It only needs not to be 0.
+            dirent[..2].copy_from_slice(&(index as u16).wrapping_add(1).max(1).to_le_bytes());
+            dirent[2..].copy_from_slice(&entry.long_name());
+        }
+        Ok(Some(dirent))
+    }
+
+    /// Finds the file or directory at path. If there is none and create is
+    /// true, makes a file there if its directory exists.
+    /// Returns Ok(where its entry is, or None for the root directory) on
+    /// success, Err(()) on error.
+    fn walk(&mut self, mut path: &Path, create: bool) -> Result<Option<EntryPos>, ()> {
+        let mut pos = None;
+        while let Some((rest, name)) = path.skipelem() {
+            path = rest;
+            let dir = self.entry(pos)?;
+            if !dir.is_dir() {
+                return Err(());
+            }
+            if name.as_bytes() == b"." {
+                continue;
+            }
+            let name = short_name(name.as_bytes())?;
+            let first = self.first_cluster(&dir);
+            let found = self
+                .scan_dir(first, |pos, entry| {
+                    if entry.name[0] == ENTRY_END {
+                        Some(None)
+                    } else if entry.is_file_or_dir() && entry.name == name {
+                        Some(Some(pos))
+                    } else {
+                        None
+                    }
+                })?
+                .flatten();
+            pos = match found {
+                Some(found) => Some(found),
+                None if create && path.is_empty_string() => Some(self.create(first, name)?),
+                None => return Err(()),
+            };
+        }
+        Ok(pos)
+    }
+
+    /// Makes an empty file named name in the directory that starts at
+    /// cluster dir.
+    /// Returns Ok(where its entry is) on success, Err(()) if the volume is full.
+    fn create(&mut self, dir: u32, name: [u8; 11]) -> Result<EntryPos, ()> {
+        let mut last = self.cluster_sector(dir)?;
+        let free = self.scan_dir(dir, |pos, entry| {
+            last = pos.sector;
+            if entry.name[0] == ENTRY_END || entry.name[0] == ENTRY_FREE {
+                Some(pos)
+            } else {
+                None
+            }
+        })?;
+        let pos = match free {
+            Some(pos) => pos,
+            None => {
+                // The directory is full, so it grows by a cluster.
+                let last = (last - self.data_start) / self.sectors_per_cluster + 2;
+                let cluster = self.alloc_cluster(Some(last))?;
+                EntryPos {
+                    sector: self.cluster_sector(cluster)?,
+                    index: 0,
+                }
+            }
+        };
+        let entry = Entry {
+            name,
+            attr: ATTR_ARCHIVE,
+            cluster: 0,
+            size: 0,
+        };
+        write_sector(self.disk, pos.sector, |data| {
+            let raw = pos.of_mut(data);
+            raw.fill(0);
+            entry.store(raw);
+        })?;
+        Ok(pos)
+    }
+
+    /// Empties the file at pos.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn truncate(&mut self, pos: EntryPos) -> Result<(), ()> {
+        let mut entry = self.entry(Some(pos))?;
+        self.free_chain(entry.cluster)?;
+        entry.cluster = 0;
+        entry.size = 0;
+        self.set_entry(pos, &entry)
+    }
+
+    /// Reads up to n bytes at off of the file of entry, as `Fat32::read`.
+    /// Returns Ok(number of bytes read) on success, Err(()) on error.
+    fn read<F: FnMut(u32, &[u8]) -> Result<(), ()>>(
+        &self,
+        entry: &Entry,
+        mut off: u32,
+        n: u32,
+        mut f: F,
+    ) -> Result<u32, ()> {
+        if off >= entry.size {
+            return Ok(0);
+        }
+        let n = cmp::min(n, entry.size - off);
+        let csize = self.cluster_size();
+        let mut cluster = entry.cluster;
+        for _ in 0..off / csize {
+            if !self.is_cluster(cluster) {
+                return Err(());
+            }
+            cluster = self.fat_get(cluster)?;
+        }
+        let mut tot = 0;
+        while tot < n {
+            let sector = self.cluster_sector(cluster)? + off % csize / SECTOR_SIZE as u32;
+            let begin = off as usize % SECTOR_SIZE;
+            let m = cmp::min(n - tot, (SECTOR_SIZE - begin) as u32);
+            read_sector(self.disk, sector, |data| {
+                f(tot, &data[begin..begin + m as usize])
+            })??;
+            tot += m;
+            off += m;
+            if off % csize == 0 {
+                cluster = self.fat_get(cluster)?;
+            }
+        }
+        Ok(tot)
+    }
+
+    /// Writes n bytes at off of the file at pos, as `Fat32::write`.
+    /// Returns Ok(number of bytes written) on success, Err(()) on error.
+    fn write<F: FnMut(u32, &mut [u8]) -> Result<(), ()>>(
+        &mut self,
+        pos: EntryPos,
+        mut off: u32,
+        n: u32,
+        mut f: F,
+    ) -> Result<u32, ()> {
+        let mut entry = self.entry(Some(pos))?;
+        if entry.is_dir() || off > entry.size || off.checked_add(n).is_none() {
+            return Err(());
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+        if entry.cluster == 0 {
+            entry.cluster = self.alloc_cluster(None)?;
+            self.set_entry(pos, &entry)?;
+        }
+        let csize = self.cluster_size();
+        let mut cluster = entry.cluster;
+        let mut tot = 0;
+        for _ in 0..off / csize {
+            cluster = self.next_or_alloc(cluster)?;
+        }
+        while tot < n {
+            let sector = self.cluster_sector(cluster)? + off % csize / SECTOR_SIZE as u32;
+            let begin = off as usize % SECTOR_SIZE;
+            let m = cmp::min(n - tot, (SECTOR_SIZE - begin) as u32);
+            let mut result = Ok(());
+            let written = write_sector(self.disk, sector, |data| {
+                result = f(tot, &mut data[begin..begin + m as usize])
+            });
+            if result.and(written).is_err() {
+                break;
+            }
+            tot += m;
+            off += m;
+            if off % csize == 0 && tot < n {
+                cluster = match self.next_or_alloc(cluster) {
+                    Ok(next) => next,
+                    Err(()) => break,
+                };
+            }
+        }
+        if off > entry.size {
+            entry.size = off;
+            self.set_entry(pos, &entry)?;
+        }
+        Ok(tot)
+    }
+}
+
+impl EntryPos {
+    fn of<'a>(&self, sector: &'a [u8]) -> &'a [u8] {
+        let begin = self.index as usize * ENTRY_SIZE;
+        &sector[begin..begin + ENTRY_SIZE]
+    }
+
+    fn of_mut<'a>(&self, sector: &'a mut [u8]) -> &'a mut [u8] {
+        let begin = self.index as usize * ENTRY_SIZE;
+        &mut sector[begin..begin + ENTRY_SIZE]
+    }
+}
+
+impl Entry {
+    fn parse(raw: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes(raw[i..i + 2].try_into().unwrap()) as u32;
+        Self {
+            name: raw[..11].try_into().unwrap(),
+            attr: raw[11],
+            cluster: u16_at(20) << 16 | u16_at(26),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+        }
+    }
+
+    fn store(&self, raw: &mut [u8]) {
+        raw[..11].copy_from_slice(&self.name);
+        raw[11] = self.attr;
+        raw[20..22].copy_from_slice(&((self.cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(self.cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Is this the entry of a file or directory, rather than a free entry,
+    /// a part of a long name, or the volume label?
+    fn is_file_or_dir(&self) -> bool {
+        self.name[0] != ENTRY_FREE && self.name[0] != ENTRY_END && self.attr & ATTR_VOLUME_ID == 0
+    }
+
+    /// Returns the name as "name.ext" in lower case, padded with NULs.
+    fn long_name(&self) -> [u8; DIRSIZ] {
+        let mut name = [0; DIRSIZ];
+        let trim = |part: &[u8]| part.len() - part.iter().rev().take_while(|&&c| c == b' ').count();
+        let base = &self.name[..trim(&self.name[..8])];
+        let ext = &self.name[8..8 + trim(&self.name[8..])];
+        let dot: &[u8] = if ext.is_empty() { b"" } else { b"." };
+        for (dst, src) in name.iter_mut().zip(base.iter().chain(dot).chain(ext)) {
+            *dst = src.to_ascii_lowercase();
+        }
+        name
+    }
+}
+
+/// Returns the 8.3 short name of name, as stored in directory entries.
+/// Returns Ok(the short name) on success, Err(()) if name has none.
+fn short_name(name: &[u8]) -> Result<[u8; 11], ()> {
+    let mut short = [b' '; 11];
+    if name == b".." {
+        short[..2].copy_from_slice(name);
+        return Ok(short);
+    }
+    let (base, ext) = match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return Err(());
+    }
+    let (short_base, short_ext) = short.split_at_mut(8);
+    for (dst, &c) in short_base
+        .iter_mut()
+        .zip(base)
+        .chain(short_ext.iter_mut().zip(ext))
+    {
+        if c <= b' ' || c >= 0x7f || b"\"*+,./:;<=>?[\\]|".contains(&c) {
+            return Err(());
+        }
+        *dst = c.to_ascii_uppercase();
+    }
+    Ok(short)
+}
+
+/// Calls `f` with sector of disk.
+/// Returns Ok(what `f` returned) on success, Err(()) on an I/O error.
+fn read_sector<R, F: FnOnce(&[u8]) -> R>(disk: VolumeDisk, sector: u32, f: F) -> Result<R, ()> {
+    let per_block = (BSIZE / SECTOR_SIZE) as u32;
+    let buf = disk.disk.try_read(disk.dev, sector / per_block)?;
+    let begin = (sector % per_block) as usize * SECTOR_SIZE;
+    Ok(f(&buf.deref_inner().data[begin..begin + SECTOR_SIZE]))
+}
+
+/// Calls `f` with sector of disk, and writes it back. Nothing is written if
+/// the sector cannot be read, as the rest of its block would be lost.
+/// Returns Ok(()) on success, Err(()) on an I/O error.
+fn write_sector<F: FnOnce(&mut [u8])>(disk: VolumeDisk, sector: u32, f: F) -> Result<(), ()> {
+    let per_block = (BSIZE / SECTOR_SIZE) as u32;
+    let mut buf = disk.disk.try_read(disk.dev, sector / per_block)?;
+    let begin = (sector % per_block) as usize * SECTOR_SIZE;
+    f(&mut buf.deref_inner_mut().data[begin..begin + SECTOR_SIZE]);
+    disk.disk.write(&mut buf)
+}
@@ -67,7 +67,9 @@
 //! dev, and inum.  One must hold ip->lock in order to
 //! read or write that inode's ip->valid, ip->size, ip->type, &c.
 
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{
+    cmp,
     iter::StepBy,
     mem,
     ops::{Deref, Range},
@@ -79,17 +81,27 @@ use static_assertions::const_assert;
 use super::{FileName, IPB, MAXFILE, NDIRECT, NINDIRECT};
 use crate::{
     arena::{Arena, ArenaObject, ArrayArena, Rc},
-    bio::BufData,
+    bio::{Buf, BufData},
+    flock::Flock,
     fs::{FsTransaction, Path, ROOTINO},
     kernel::kernel_builder,
     lock::{Sleeplock, Spinlock},
+    ok_or,
     param::ROOTDEV,
-    param::{BSIZE, NINODE},
+    param::{BSIZE, MAXPATH, MAXPATHELEM, NINODE},
+    pipe::AllocatedPipe,
     proc::CurrentProc,
+    riscv::PGSIZE,
     stat::Stat,
-    vm::UVAddr,
+    vm::UserSlice,
 };
 
+/// What a hole reads as.
+static HOLE: [u8; BSIZE] = [0; BSIZE];
+
+// A page of the page cache holds whole blocks.
+const_assert!(PGSIZE % BSIZE == 0);
+
 /// Directory is a file containing a sequence of Dirent structures.
 pub const DIRSIZ: usize = 14;
 
@@ -103,6 +115,7 @@ pub enum InodeType {
     Dir,
     File,
     Device { major: u16, minor: u16 },
+    Fifo,
 }
 #[derive(Copy, Clone, PartialEq, Debug)]
 #[repr(i16)]
@@ -111,6 +124,7 @@ pub enum DInodeType {
     Dir,
     File,
     Device,
+    Fifo,
 }
 
 pub struct InodeInner {
@@ -120,8 +134,13 @@ pub struct InodeInner {
     pub typ: InodeType,
     pub nlink: i16,
     pub size: u32,
+    pub uid: u32,
     pub addr_direct: [u32; NDIRECT],
     pub addr_indirect: u32,
+
+    /// The block allocated last, near which the next one is allocated, or 0.
+    /// It is not on disk.
+    pub last_block: u32,
 }
 
 /// in-memory copy of an inode
@@ -133,6 +152,15 @@ pub struct Inode {
     pub inum: u32,
 
     pub inner: Sleeplock<InodeInner>,
+
+    /// The pipe of a FIFO while it is open, shared by whoever opened it.
+    pub fifo: Spinlock<Option<AllocatedPipe>>,
+
+    /// Advisory locks of flock().
+    pub flock: Flock,
+
+    /// Is the FAT32 volume mounted on this directory?
+    pub mounted: AtomicBool,
 }
 
 /// On-disk inode structure
@@ -157,11 +185,35 @@ pub struct Dinode {
     /// Size of file (bytes)
     size: u32,
 
+    /// User id of the owner, which is charged for the inode and its blocks
+    pub(super) uid: u32,
+
     /// Direct data block addresses
-    addr_direct: [u32; NDIRECT],
+    pub(super) addr_direct: [u32; NDIRECT],
 
     /// Indirect data block address
-    addr_indirect: u32,
+    pub(super) addr_indirect: u32,
+}
+
+impl Dinode {
+    /// The dinode of inode inum in bp, the block that holds it.
+    pub(super) fn of(bp: &Buf, inum: u32) -> &Self {
+        const_assert!(IPB <= mem::size_of::<BufData>() / mem::size_of::<Dinode>());
+        const_assert!(mem::align_of::<BufData>() % mem::align_of::<Dinode>() == 0);
+        // SAFETY: dip is inside bp.data.
+        let dip =
+            unsafe { (bp.deref_inner().data.as_ptr() as *const Dinode).add(inum as usize % IPB) };
+        // SAFETY: i16 does not have internal structure.
+        let t = unsafe { *(dip as *const i16) };
+        // If t >= #(variants of DInodeType), UB will happen when we read dip.typ.
+        assert!(t < core::mem::variant_count::<DInodeType>() as i16);
+        // SAFETY: dip is aligned properly and t < #(variants of DInodeType).
+        unsafe { &*dip }
+    }
+
+    pub(super) fn is_free(&self) -> bool {
+        self.typ == DInodeType::None
+    }
 }
 
 pub type Itable = Spinlock<ArrayArena<Inode, NINODE>>;
@@ -230,12 +282,12 @@ struct DirentIter<'s, 't> {
 }
 
 impl Iterator for DirentIter<'_, '_> {
-    type Item = (Dirent, u32);
+    /// Err(()) if the disk failed to read the entry.
+    type Item = Result<(Dirent, u32), ()>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let off = self.iter.next()?;
-        let dirent = Dirent::new(self.guard, off).expect("DirentIter");
-        Some((dirent, off))
+        Some(Dirent::new(self.guard, off).map(|dirent| (dirent, off)))
     }
 }
 
@@ -244,6 +296,21 @@ impl<'t> InodeGuard<'t> {
         let iter = (0..self.deref_inner().size).step_by(DIRENT_SIZE);
         DirentIter { guard: self, iter }
     }
+
+    /// Returns Ok(the first entry that pred holds for and its offset, if any)
+    /// on success, Err(()) if the disk failed to read the directory.
+    fn find_dirent<P: FnMut(&Dirent) -> bool>(
+        &mut self,
+        mut pred: P,
+    ) -> Result<Option<(Dirent, u32)>, ()> {
+        for entry in self.iter_dirents() {
+            let (de, off) = entry?;
+            if pred(&de) {
+                return Ok(Some((de, off)));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl Deref for InodeGuard<'_> {
@@ -285,34 +352,50 @@ impl InodeGuard<'_> {
         itable: &Itable,
     ) -> Result<(), ()> {
         // Check that name is not present.
-        if let Ok((_ip, _)) = self.dirlookup(name, itable) {
+        if self.dirlookup(name, itable)?.is_some() {
             return Err(());
         };
 
         // Look for an empty Dirent.
         let (mut de, off) = self
-            .iter_dirents()
-            .find(|(de, _)| de.inum == 0)
+            .find_dirent(|de| de.inum == 0)?
             .unwrap_or((Default::default(), self.deref_inner().size));
         de.inum = inum as _;
         de.set_name(name);
-        self.write_kernel(&de, off, tx).expect("dirlink");
-        Ok(())
+        // Growing the directory fails if the disk is full.
+        self.write_kernel(&de, off, tx)
     }
 
     /// Look for a directory entry in a directory.
-    /// If found, return the entry and byte offset of entry.
+    /// Returns Ok(the entry and byte offset of entry, if found) on success,
+    /// Err(()) if the disk failed to read the directory, or if no entry of
+    /// the inode table is free.
     pub fn dirlookup<'a>(
         &mut self,
         name: &FileName,
         itable: &'a Itable,
-    ) -> Result<(RcInode, u32), ()> {
+    ) -> Result<Option<(RcInode, u32)>, ()> {
         assert_eq!(self.deref_inner().typ, InodeType::Dir, "dirlookup not DIR");
 
-        self.iter_dirents()
-            .find(|(de, _)| de.inum != 0 && de.get_name() == name)
-            .map(|(de, off)| (itable.get_inode(self.dev, de.inum as u32), off))
-            .ok_or(())
+        let found = self.find_dirent(|de| de.inum != 0 && de.get_name() == name)?;
+        found
+            .map(|(de, off)| Ok((itable.get_inode(self.dev, de.inum as u32)?, off)))
+            .transpose()
+    }
+
+    /// Look for the name of inode inum in a directory, other than "." or
+    /// "..". If found, copy it into dst and return its length.
+    pub fn dirname(&mut self, inum: u32, dst: &mut [u8; DIRSIZ]) -> Result<usize, ()> {
+        assert_eq!(self.deref_inner().typ, InodeType::Dir, "dirname not DIR");
+
+        let (de, _) = self
+            .find_dirent(|de| {
+                de.inum as u32 == inum && !matches!(de.get_name().as_bytes(), b"." | b"..")
+            })?
+            .ok_or(())?;
+        let name = de.get_name().as_bytes();
+        dst[..name.len()].copy_from_slice(name);
+        Ok(name.len())
     }
 }
 
@@ -320,13 +403,14 @@ impl InodeGuard<'_> {
     /// Copy a modified in-memory inode to disk.
     /// Must be called after every change to an ip->xxx field
     /// that lives on disk.
-    pub fn update(&self, tx: &FsTransaction<'_>) {
+    /// Returns Ok(()) on success, Err(()) if no buffer is free.
+    pub fn update(&self, tx: &FsTransaction<'_>) -> Result<(), ()> {
         // TODO: remove kernel_builder()
         let mut bp = kernel_builder().file_system.log.disk.read(
             self.dev,
             // TODO: remove kernel_builder()
             kernel_builder().file_system.superblock().iblock(self.inum),
-        );
+        )?;
 
         const_assert!(IPB <= mem::size_of::<BufData>() / mem::size_of::<Dinode>());
         const_assert!(mem::align_of::<BufData>() % mem::align_of::<Dinode>() == 0);
@@ -361,48 +445,67 @@ impl InodeGuard<'_> {
                 dip.major = 0;
                 dip.minor = 0;
             }
+            InodeType::Fifo => {
+                dip.typ = DInodeType::Fifo;
+                dip.major = 0;
+                dip.minor = 0;
+            }
         }
 
         (*dip).nlink = inner.nlink;
         (*dip).size = inner.size;
+        (*dip).uid = inner.uid;
         (*dip).addr_direct.copy_from_slice(&inner.addr_direct);
         (*dip).addr_indirect = inner.addr_indirect;
         tx.write(bp);
+        Ok(())
     }
 
     /// Truncate inode (discard contents).
     /// This function is called with Inode's lock is held.
-    pub fn itrunc(&mut self, tx: &FsTransaction<'_>) {
+    /// Returns Ok(()) on success, Err(()) if no buffer is free. The inode
+    /// forgets its blocks before they are freed, so the blocks left then
+    /// leak, but none is freed twice.
+    pub fn itrunc(&mut self, tx: &FsTransaction<'_>) -> Result<(), ()> {
         let dev = self.dev;
-        for addr in &mut self.deref_inner_mut().addr_direct {
-            if *addr != 0 {
-                tx.bfree(dev, *addr);
-                *addr = 0;
-            }
-        }
+        let uid = self.deref_inner().uid;
+        // TODO: remove kernel_builder()
+        kernel_builder().texts.invalidate(dev, self.inum);
+        self.invalidate_pages();
 
-        if self.deref_inner().addr_indirect != 0 {
+        let indirect = self.deref_inner().addr_indirect;
+        let bp = if indirect != 0 {
             // TODO: remove kernel_builder()
-            let mut bp = kernel_builder()
-                .file_system
-                .log
-                .disk
-                .read(dev, self.deref_inner().addr_indirect);
+            Some(kernel_builder().file_system.log.disk.read(dev, indirect)?)
+        } else {
+            None
+        };
+        let inner = self.deref_inner_mut();
+        let direct = mem::take(&mut inner.addr_direct);
+        let size = mem::replace(&mut inner.size, 0);
+        inner.addr_indirect = 0;
+        if let Err(()) = self.update(tx) {
+            let inner = self.deref_inner_mut();
+            inner.addr_direct = direct;
+            inner.addr_indirect = indirect;
+            inner.size = size;
+            return Err(());
+        }
+
+        for addr in direct.iter().filter(|addr| **addr != 0) {
+            tx.bfree(dev, *addr, uid)?;
+        }
+        if let Some(bp) = bp {
             // SAFETY: u32 does not have internal structure.
-            let (prefix, data, _) = unsafe { bp.deref_inner_mut().data.align_to_mut::<u32>() };
+            let (prefix, data, _) = unsafe { bp.deref_inner().data.align_to::<u32>() };
             debug_assert_eq!(prefix.len(), 0, "itrunc: Buf data unaligned");
-            for a in data {
-                if *a != 0 {
-                    tx.bfree(dev, *a);
-                }
+            for addr in data.iter().filter(|addr| **addr != 0) {
+                tx.bfree(dev, *addr, uid)?;
             }
             drop(bp);
-            tx.bfree(dev, self.deref_inner().addr_indirect);
-            self.deref_inner_mut().addr_indirect = 0
+            tx.bfree(dev, indirect, uid)?;
         }
-
-        self.deref_inner_mut().size = 0;
-        self.update(tx);
+        Ok(())
     }
 
     /// Copy data into `dst` from the content of inode at offset `off`.
@@ -416,7 +519,7 @@ impl InodeGuard<'_> {
             // SAFETY: the safety assumption of this method.
             unsafe { core::slice::from_raw_parts_mut(dst as *mut _ as _, mem::size_of::<T>()) },
             off,
-        );
+        )?;
         if bytes == mem::size_of::<T>() {
             Ok(())
         } else {
@@ -425,28 +528,28 @@ impl InodeGuard<'_> {
     }
 
     /// Copy data into `dst` from the content of inode at offset `off`.
-    /// Return the number of bytes copied.
-    pub fn read_bytes_kernel(&mut self, dst: &mut [u8], off: u32) -> usize {
+    /// Returns Ok(number of bytes copied) on success, Err(()) on failure to
+    /// read the disk.
+    pub fn read_bytes_kernel(&mut self, dst: &mut [u8], off: u32) -> Result<usize, ()> {
         self.read_internal(off, dst.len() as u32, |off, src| {
             dst[off as usize..off as usize + src.len()].clone_from_slice(src);
             Ok(())
         })
-        .expect("read: should never fail")
     }
 
-    /// Copy data into virtual address `dst` of the current process by `n` bytes
-    /// from the content of inode at offset `off`.
+    /// Copy data into `dst` in the memory of the current process from the
+    /// content of inode at offset `off`.
     /// Returns Ok(number of bytes copied) on success, Err(()) on failure due to
     /// accessing an invalid virtual address.
     pub fn read_user(
         &mut self,
-        dst: UVAddr,
+        dst: UserSlice,
         off: u32,
-        n: u32,
         proc: &mut CurrentProc<'_>,
     ) -> Result<usize, ()> {
-        self.read_internal(off, n, |off, src| {
-            proc.memory_mut().copy_out_bytes(dst + off as usize, src)
+        self.read_internal(off, dst.len() as u32, |off, src| {
+            dst.sub(off as usize, src.len())
+                .write(src, proc.memory_mut())
         })
     }
 
@@ -474,24 +577,50 @@ impl InodeGuard<'_> {
         if off + n > inner.size {
             n = inner.size - off;
         }
+        let (dev, inum) = (self.dev, self.inum);
+        // TODO: remove kernel_builder()
+        // SAFETY: the kernel is initialized, as files are read.
+        let page_cache = unsafe { kernel_builder().get_page_cache() };
         let mut tot: u32 = 0;
         while tot < n {
-            // TODO: remove kernel_builder()
-            let bp = kernel_builder()
-                .file_system
-                .log
-                .disk
-                .read(self.dev, self.bmap(off as usize / BSIZE));
-            let m = core::cmp::min(n - tot, BSIZE as u32 - off % BSIZE as u32);
-            let begin = (off % BSIZE as u32) as usize;
+            let m = core::cmp::min(n - tot, PGSIZE as u32 - off % PGSIZE as u32);
+            let index = off / PGSIZE as u32;
+            let begin = (off % PGSIZE as u32) as usize;
             let end = begin + m as usize;
-            f(tot, &bp.deref_inner().data[begin..end])?;
+            page_cache.with_page(
+                dev,
+                inum,
+                index,
+                |page| self.read_page(index, page),
+                |page| f(tot, &page[begin..end]),
+            )??;
             tot += m;
             off += m;
         }
         Ok(tot as usize)
     }
 
+    /// Read the page at `index` of the inode into `page`, a block at a time.
+    /// Returns Ok(()) on success, Err(()) on failure.
+    fn read_page(&mut self, index: u32, page: &mut [u8]) -> Result<(), ()> {
+        for (i, dst) in page.chunks_mut(BSIZE).enumerate() {
+            let bn = index as usize * (PGSIZE / BSIZE) + i;
+            match if bn < MAXFILE { self.bmap(bn)? } else { None } {
+                Some(addr) => {
+                    // TODO: remove kernel_builder()
+                    let bp = kernel_builder()
+                        .file_system
+                        .log
+                        .disk
+                        .try_read(self.dev, addr)?;
+                    dst.copy_from_slice(&bp.deref_inner().data[..]);
+                }
+                None => dst.copy_from_slice(&HOLE[..]),
+            }
+        }
+        Ok(())
+    }
+
     /// Copy data from `src` into the inode at offset `off`.
     /// Return Ok(()) on success, Err(()) on failure.
     pub fn write_kernel<T>(&mut self, src: &T, off: u32, tx: &FsTransaction<'_>) -> Result<(), ()> {
@@ -528,21 +657,23 @@ impl InodeGuard<'_> {
         )
     }
 
-    /// Copy data from virtual address `src` of the current process by `n` bytes
-    /// into the inode at offset `off`.
+    /// Copy data from `src` in the memory of the current process into the
+    /// inode at offset `off`.
     /// Returns Ok(number of bytes copied) on success, Err(()) on failure.
     pub fn write_user(
         &mut self,
-        src: UVAddr,
+        src: UserSlice,
         off: u32,
-        n: u32,
         proc: &mut CurrentProc<'_>,
         tx: &FsTransaction<'_>,
     ) -> Result<usize, ()> {
         self.write_internal(
             off,
-            n,
-            |off, dst| proc.memory_mut().copy_in_bytes(dst, src + off as usize),
+            src.len() as u32,
+            |off, dst| {
+                src.sub(off as usize, dst.len())
+                    .read(dst, proc.memory_mut())
+            },
             tx,
         )
     }
@@ -567,26 +698,41 @@ impl InodeGuard<'_> {
         mut f: F,
         tx: &FsTransaction<'_>,
     ) -> Result<usize, ()> {
-        if off > self.deref_inner().size {
+        if tx.is_read_only() {
             return Err(());
         }
+        // Writing past the end leaves a hole in between.
         if off.checked_add(n).ok_or(())? as usize > MAXFILE * BSIZE {
             return Err(());
         }
+        // TODO: remove kernel_builder()
+        kernel_builder().texts.invalidate(self.dev, self.inum);
         let mut tot: u32 = 0;
         while tot < n {
             // TODO: remove kernel_builder()
-            let mut bp = kernel_builder()
-                .file_system
-                .log
-                .disk
-                .read(self.dev, self.bmap_or_alloc(off as usize / BSIZE, tx));
+            let addr = ok_or!(self.bmap_or_alloc(off as usize / BSIZE, tx), break);
+            let mut bp = ok_or!(
+                kernel_builder()
+                    .file_system
+                    .log
+                    .disk
+                    .try_read(self.dev, addr),
+                break
+            );
             let m = core::cmp::min(n - tot, BSIZE as u32 - off % BSIZE as u32);
             let begin = (off % BSIZE as u32) as usize;
             let end = begin + m as usize;
             if f(tot, &mut bp.deref_inner_mut().data[begin..end]).is_err() {
                 break;
             }
+            // TODO: remove kernel_builder()
+            // SAFETY: the kernel is initialized, as files are written.
+            unsafe { kernel_builder().get_page_cache() }.update(
+                self.dev,
+                self.inum,
+                off,
+                &bp.deref_inner().data[begin..end],
+            );
             tx.write(bp);
             tot += m;
             off += m;
@@ -599,7 +745,7 @@ impl InodeGuard<'_> {
         // Write the i-node back to disk even if the size didn't change
         // because the loop above might have called bmap() and added a new
         // block to self->addrs[].
-        self.update(tx);
+        self.update(tx)?;
         Ok(tot as usize)
     }
 
@@ -611,31 +757,24 @@ impl InodeGuard<'_> {
     /// listed in block self->addr_indirect.
     /// Return the disk block address of the nth block in inode self.
     /// If there is no such block, bmap allocates one.
-    fn bmap_or_alloc(&mut self, bn: usize, tx: &FsTransaction<'_>) -> u32 {
-        self.bmap_internal(bn, Some(tx))
-    }
-
-    fn bmap(&mut self, bn: usize) -> u32 {
-        self.bmap_internal(bn, None)
-    }
-
-    fn bmap_internal(&mut self, bn: usize, tx_opt: Option<&FsTransaction<'_>>) -> u32 {
+    /// Returns Err(()) if it cannot allocate a block.
+    fn bmap_or_alloc(&mut self, bn: usize, tx: &FsTransaction<'_>) -> Result<u32, ()> {
         let inner = self.deref_inner();
 
         if bn < NDIRECT {
             let mut addr = inner.addr_direct[bn];
             if addr == 0 {
-                addr = tx_opt.expect("bmap: out of range").balloc(self.dev);
+                addr = self.balloc(tx)?;
                 self.deref_inner_mut().addr_direct[bn] = addr;
             }
-            addr
+            Ok(addr)
         } else {
             let bn = bn - NDIRECT;
             assert!(bn < NINDIRECT, "bmap: out of range");
 
             let mut indirect = inner.addr_indirect;
             if indirect == 0 {
-                indirect = tx_opt.expect("bmap: out of range").balloc(self.dev);
+                indirect = self.balloc(tx)?;
                 self.deref_inner_mut().addr_indirect = indirect;
             }
 
@@ -644,17 +783,147 @@ impl InodeGuard<'_> {
                 .file_system
                 .log
                 .disk
-                .read(self.dev, indirect);
+                .read(self.dev, indirect)?;
             let (prefix, data, _) = unsafe { bp.deref_inner_mut().data.align_to_mut::<u32>() };
             debug_assert_eq!(prefix.len(), 0, "bmap: Buf data unaligned");
             let mut addr = data[bn];
             if addr == 0 {
-                let tx = tx_opt.expect("bmap: out of range");
-                addr = tx.balloc(self.dev);
+                addr = self.balloc(tx)?;
                 data[bn] = addr;
                 tx.write(bp);
             }
-            addr
+            Ok(addr)
+        }
+    }
+
+    /// Allocate a zeroed block for the inode, near the one allocated last,
+    /// and charge it to the owner.
+    fn balloc(&mut self, tx: &FsTransaction<'_>) -> Result<u32, ()> {
+        let inner = self.deref_inner();
+        let addr = tx.balloc(self.dev, inner.last_block, inner.uid)?;
+        self.deref_inner_mut().last_block = addr;
+        Ok(addr)
+    }
+
+    /// Invalidate the pages of the inode in the page cache, as its blocks are
+    /// freed.
+    fn invalidate_pages(&self) {
+        // TODO: remove kernel_builder()
+        let kernel = kernel_builder();
+        // SAFETY: the kernel is initialized, as files are written.
+        unsafe { kernel.get_page_cache() }.invalidate(self.dev, self.inum, &kernel.kmem);
+    }
+
+    /// Allocate zeroed blocks for the blocks of range that are in a hole.
+    /// Returns Ok(()) on success, Err(()) if the disk is full or if no
+    /// buffer is free. The blocks allocated before then stay allocated.
+    pub fn alloc_blocks(
+        &mut self,
+        mut range: Range<usize>,
+        tx: &FsTransaction<'_>,
+    ) -> Result<(), ()> {
+        let ret = range.try_for_each(|bn| self.bmap_or_alloc(bn, tx).map(|_| ()));
+        self.update(tx).and(ret)
+    }
+
+    /// Free the blocks of range, leaving a hole.
+    /// Returns Ok(()) on success, Err(()) if no buffer is free. The blocks
+    /// freed before then stay freed.
+    pub fn free_blocks(&mut self, range: Range<usize>, tx: &FsTransaction<'_>) -> Result<(), ()> {
+        let dev = self.dev;
+        let uid = self.deref_inner().uid;
+        self.invalidate_pages();
+        for bn in range {
+            if bn < NDIRECT {
+                let addr = &mut self.deref_inner_mut().addr_direct[bn];
+                if *addr != 0 {
+                    tx.bfree(dev, *addr, uid)?;
+                    *addr = 0;
+                }
+                continue;
+            }
+            let bn = bn - NDIRECT;
+            assert!(bn < NINDIRECT, "free_blocks: out of range");
+            let indirect = self.deref_inner().addr_indirect;
+            if indirect == 0 {
+                break;
+            }
+            // TODO: remove kernel_builder()
+            let mut bp = kernel_builder().file_system.log.disk.read(dev, indirect)?;
+            // SAFETY: u32 does not have internal structure.
+            let (prefix, data, _) = unsafe { bp.deref_inner_mut().data.align_to_mut::<u32>() };
+            debug_assert_eq!(prefix.len(), 0, "free_blocks: Buf data unaligned");
+            if data[bn] != 0 {
+                tx.bfree(dev, data[bn], uid)?;
+                data[bn] = 0;
+                tx.write(bp);
+            }
+        }
+        self.update(tx)
+    }
+
+    /// Write len zeros at off, within a block, unless the block is in a hole.
+    /// Returns Ok(()) on success, Err(()) on failure.
+    pub fn zero_bytes(&mut self, off: u32, len: u32, tx: &FsTransaction<'_>) -> Result<(), ()> {
+        if self.bmap(off as usize / BSIZE)?.is_some() {
+            let _ = self.write_bytes_kernel(&HOLE[..len as usize], off, tx)?;
+        }
+        Ok(())
+    }
+
+    /// Return the disk block address of the nth block in inode self, or None
+    /// if it is in a hole, which has no block and reads as zeros.
+    /// Returns Err(()) if no buffer is free.
+    fn bmap(&mut self, bn: usize) -> Result<Option<u32>, ()> {
+        let inner = self.deref_inner();
+
+        let addr = if bn < NDIRECT {
+            inner.addr_direct[bn]
+        } else {
+            let bn = bn - NDIRECT;
+            assert!(bn < NINDIRECT, "bmap: out of range");
+
+            if inner.addr_indirect == 0 {
+                return Ok(None);
+            }
+            // TODO: remove kernel_builder()
+            let bp = kernel_builder()
+                .file_system
+                .log
+                .disk
+                .read(self.dev, inner.addr_indirect)?;
+            let (prefix, data, _) = unsafe { bp.deref_inner().data.align_to::<u32>() };
+            debug_assert_eq!(prefix.len(), 0, "bmap: Buf data unaligned");
+            data[bn]
+        };
+        if addr == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(addr))
+        }
+    }
+
+    /// Returns the offset of the first byte at or after off that is in a hole
+    /// if hole is true, or in data otherwise. Holes are whole blocks, and the
+    /// end of the file counts as one.
+    /// Returns Ok(None) if there is no such byte before the end, Err(()) if
+    /// no buffer is free.
+    pub fn seek_hole_or_data(&mut self, off: u32, hole: bool) -> Result<Option<u32>, ()> {
+        let size = self.deref_inner().size;
+        if off >= size {
+            return Ok(None);
+        }
+        let first = off as usize / BSIZE;
+        let last = (size as usize - 1) / BSIZE;
+        for bn in first..=last {
+            if self.bmap(bn)?.is_none() == hole {
+                return Ok(Some(cmp::max(off, (bn * BSIZE) as u32)));
+            }
+        }
+        if hole {
+            Ok(Some(size))
+        } else {
+            Ok(None)
         }
     }
 
@@ -664,7 +933,10 @@ impl InodeGuard<'_> {
         for off in (2 * DIRENT_SIZE as u32..self.deref_inner().size).step_by(DIRENT_SIZE) {
             // SAFETY: Dirent can be safely transmuted to [u8; _], as it
             // contains only u16 and u8's, which do not have internal structures.
-            unsafe { self.read_kernel(&mut de, off) }.expect("is_dir_empty: read_kernel");
+            // A directory that cannot be read is not known to be empty.
+            if unsafe { self.read_kernel(&mut de, off) }.is_err() {
+                return false;
+            }
             if de.inum != 0 {
                 return false;
             }
@@ -711,16 +983,27 @@ impl ArenaObject for Inode {
             });
 
             // self->ref == 1 means no other process can have self locked,
-            // so this acquiresleep() won't block (or deadlock).
-            let mut ip = self.lock();
+            // so this lock() won't block (or deadlock). The inode is valid,
+            // so it reads nothing, and does not fail.
+            let mut ip = ok_or!(self.lock(), return);
 
             // SAFETY: `nlink` is 0. That is, there is no way to reach to inode,
             // so the `Itable` never tries to obtain an `Rc` referring this `Inode`.
             unsafe {
                 A::reacquire_after(guard, move || {
-                    ip.itrunc(&tx);
-                    ip.deref_inner_mut().typ = InodeType::None;
-                    ip.update(&tx);
+                    // If no buffer is free, the inode stays on the orphan
+                    // list, if it is there, so that the next mount frees it,
+                    // or leaks once it is off the list. It is never freed
+                    // while still on the list.
+                    let freed = tx.remove_orphan(ip.dev, ip.inum).and_then(|_| {
+                        ip.itrunc(&tx)?;
+                        ip.deref_inner_mut().typ = InodeType::None;
+                        ip.update(&tx)
+                    });
+                    if freed.is_ok() {
+                        let _ = tx.fs.nfree_inodes.fetch_add(1, Ordering::Relaxed);
+                        tx.fs.quota.uncharge(ip.deref_inner().uid, 0, 1);
+                    }
                     ip.deref_inner_mut().valid = false;
                     drop(ip);
                 });
@@ -732,7 +1015,9 @@ impl ArenaObject for Inode {
 impl Inode {
     /// Lock the given inode.
     /// Reads the inode from disk if necessary.
-    pub fn lock(&self) -> InodeGuard<'_> {
+    /// Returns Ok(the guard) on success, Err(()) if no buffer is free to read
+    /// it.
+    pub fn lock(&self) -> Result<InodeGuard<'_>, ()> {
         let mut guard = self.inner.lock();
         if !guard.valid {
             // TODO: remove kernel_builder()
@@ -740,7 +1025,7 @@ impl Inode {
                 self.dev,
                 // TODO: remove kernel_builder()
                 kernel_builder().file_system.superblock().iblock(self.inum),
-            );
+            )?;
 
             // SAFETY: dip is inside bp.data.
             let dip = unsafe {
@@ -758,6 +1043,7 @@ impl Inode {
                 DInodeType::None => guard.typ = InodeType::None,
                 DInodeType::Dir => guard.typ = InodeType::Dir,
                 DInodeType::File => guard.typ = InodeType::File,
+                DInodeType::Fifo => guard.typ = InodeType::Fifo,
                 DInodeType::Device => {
                     guard.typ = InodeType::Device {
                         major: dip.major,
@@ -767,6 +1053,7 @@ impl Inode {
             }
             guard.nlink = dip.nlink;
             guard.size = dip.size;
+            guard.uid = dip.uid;
             guard.addr_direct.copy_from_slice(&dip.addr_direct);
             guard.addr_indirect = dip.addr_indirect;
             drop(bp);
@@ -774,7 +1061,7 @@ impl Inode {
             assert_ne!(guard.typ, InodeType::None, "Inode::lock: no type");
         };
         mem::forget(guard);
-        InodeGuard { inode: self }
+        Ok(InodeGuard { inode: self })
     }
 
     pub const fn zero() -> Self {
@@ -788,10 +1075,15 @@ impl Inode {
                     typ: InodeType::None,
                     nlink: 0,
                     size: 0,
+                    uid: 0,
                     addr_direct: [0; NDIRECT],
                     addr_indirect: 0,
+                    last_block: 0,
                 },
             ),
+            fifo: Spinlock::new("fifo", None),
+            flock: Flock::new(),
+            mounted: AtomicBool::new(false),
         }
     }
 
@@ -806,6 +1098,7 @@ impl Inode {
                 InodeType::Dir => 1,
                 InodeType::File => 2,
                 InodeType::Device { .. } => 3,
+                InodeType::Fifo => 4,
             },
             nlink: inner.nlink,
             size: inner.size as usize,
@@ -813,6 +1106,15 @@ impl Inode {
     }
 }
 
+/// Where a path leads.
+pub enum Lookup<'s> {
+    Inode(RcInode),
+
+    /// Into the FAT32 volume, mounted on a directory on the way. It has the
+    /// rest of the path, relative to the root of the volume.
+    Fat32(&'s Path),
+}
+
 impl Itable {
     pub const fn zero() -> Self {
         Spinlock::new("ITABLE", ArrayArena::<Inode, NINODE>::new())
@@ -821,22 +1123,37 @@ impl Itable {
     /// Find the inode with number inum on device dev
     /// and return the in-memory copy. Does not lock
     /// the inode and does not read it from disk.
-    pub fn get_inode(&self, dev: u32, inum: u32) -> RcInode {
+    /// Returns Ok(the inode) on success, Err(()) if every entry of the table
+    /// is in use.
+    pub fn get_inode(&self, dev: u32, inum: u32) -> Result<RcInode, ()> {
         self.find_or_alloc(
             |inode| inode.dev == dev && inode.inum == inum,
             |inode| {
                 inode.dev = dev;
                 inode.inum = inum;
                 inode.inner.get_mut().valid = false;
+                inode.inner.get_mut().last_block = 0;
             },
         )
-        .expect("[Itable::get_inode] no inodes")
+        .ok_or(())
     }
 
-    /// Allocate an inode on device dev.
+    /// Allocate an inode on device dev, owned by user id uid, and charge it to
+    /// uid.
     /// Mark it as allocated by giving it type.
-    /// Returns an unlocked but allocated and referenced inode.
-    pub fn alloc_inode(&self, dev: u32, typ: InodeType, tx: &FsTransaction<'_>) -> RcInode {
+    /// Returns Ok(an unlocked but allocated and referenced inode) on success,
+    /// Err(()) if there are no free inodes, if uid is over its quota, or if no
+    /// buffer or entry of the inode table is free.
+    pub fn alloc_inode(
+        &self,
+        dev: u32,
+        typ: InodeType,
+        uid: u32,
+        tx: &FsTransaction<'_>,
+    ) -> Result<RcInode, ()> {
+        if tx.is_read_only() {
+            return Err(());
+        }
         // TODO: remove kernel_builder()
         for inum in 1..kernel_builder().file_system.superblock().ninodes {
             // TODO: remove kernel_builder()
@@ -845,7 +1162,7 @@ impl Itable {
                 .log
                 .disk
                 // TODO: remove kernel_builder()
-                .read(dev, kernel_builder().file_system.superblock().iblock(inum));
+                .read(dev, kernel_builder().file_system.superblock().iblock(inum))?;
 
             const_assert!(IPB <= mem::size_of::<BufData>() / mem::size_of::<Dinode>());
             const_assert!(mem::align_of::<BufData>() % mem::align_of::<Dinode>() == 0);
@@ -862,11 +1179,20 @@ impl Itable {
 
             // a free inode
             if dip.typ == DInodeType::None {
+                // Charge uid and take the entry first, so that nothing is
+                // allocated if uid is over its quota or there is no entry.
+                tx.fs.quota.charge(uid, 0, 1)?;
+                let ip = ok_or!(self.get_inode(dev, inum), {
+                    tx.fs.quota.uncharge(uid, 0, 1);
+                    return Err(());
+                });
                 unsafe { ptr::write_bytes(dip as _, 0, 1) };
+                dip.uid = uid;
                 match typ {
                     InodeType::None => dip.typ = DInodeType::None,
                     InodeType::Dir => dip.typ = DInodeType::Dir,
                     InodeType::File => dip.typ = DInodeType::File,
+                    InodeType::Fifo => dip.typ = DInodeType::Fifo,
                     InodeType::Device { major, minor } => {
                         dip.typ = DInodeType::Device;
                         dip.major = major;
@@ -876,61 +1202,114 @@ impl Itable {
 
                 // mark it allocated on the disk
                 tx.write(bp);
-                return self.get_inode(dev, inum);
+                let _ = tx.fs.nfree_inodes.fetch_sub(1, Ordering::Relaxed);
+                return Ok(ip);
             }
         }
-        panic!("[Itable::alloc_inode] no inodes");
+        Err(())
     }
 
-    pub fn root(&self) -> RcInode {
+    pub fn root(&self) -> Result<RcInode, ()> {
         self.get_inode(ROOTDEV, ROOTINO)
     }
 
-    pub fn namei(&self, path: &Path, proc: &CurrentProc<'_>) -> Result<RcInode, ()> {
-        Ok(self.namex(path, false, proc)?.0)
+    /// Like `namei`, but also goes into the mounted FAT32 volume.
+    pub fn lookup<'s>(
+        &self,
+        path: &'s Path,
+        dir: Option<&RcInode>,
+        proc: &CurrentProc<'_>,
+    ) -> Result<Lookup<'s>, ()> {
+        Ok(self.namex(path, false, dir, proc)?.0)
+    }
+
+    /// Look up path. A relative path starts at dir, or at the current
+    /// directory if dir is None.
+    pub fn namei(
+        &self,
+        path: &Path,
+        dir: Option<&RcInode>,
+        proc: &CurrentProc<'_>,
+    ) -> Result<RcInode, ()> {
+        match self.namex(path, false, dir, proc)?.0 {
+            Lookup::Inode(ip) => Ok(ip),
+            Lookup::Fat32(_) => Err(()),
+        }
     }
 
     pub fn nameiparent<'s>(
         &self,
         path: &'s Path,
+        dir: Option<&RcInode>,
         proc: &CurrentProc<'_>,
     ) -> Result<(RcInode, &'s FileName), ()> {
-        let (ip, name_in_path) = self.namex(path, true, proc)?;
-        let name_in_path = name_in_path.ok_or(())?;
-        Ok((ip, name_in_path))
+        match self.namex(path, true, dir, proc)? {
+            (Lookup::Inode(ip), Some(name_in_path)) => Ok((ip, name_in_path)),
+            _ => Err(()),
+        }
     }
 
     fn namex<'s>(
         &self,
         mut path: &'s Path,
         parent: bool,
+        dir: Option<&RcInode>,
         proc: &CurrentProc<'_>,
-    ) -> Result<(RcInode, Option<&'s FileName>), ()> {
+    ) -> Result<(Lookup<'s>, Option<&'s FileName>), ()> {
+        // Paths from user space are shorter.
+        if path.as_bytes().len() >= MAXPATH {
+            return Err(());
+        }
+        let must_be_dir = path.has_trailing_slash();
+        let mut nelem = 0;
+
         let mut ptr = if path.is_absolute() {
-            self.root()
+            proc.root().clone()
+        } else if let Some(dir) = dir {
+            dir.clone()
         } else {
             proc.cwd().clone()
         };
+        if ptr.mounted.load(Ordering::Acquire) {
+            return Ok((Lookup::Fat32(path), None));
+        }
 
         while let Some((new_path, name)) = path.skipelem() {
             path = new_path;
+            nelem += 1;
+            if nelem > MAXPATHELEM {
+                return Err(());
+            }
 
-            let mut ip = ptr.lock();
+            let mut ip = ptr.lock()?;
             if ip.deref_inner().typ != InodeType::Dir {
                 return Err(());
             }
             if parent && path.is_empty_string() {
                 // Stop one level early.
                 drop(ip);
-                return Ok((ptr, Some(name)));
+                return Ok((Lookup::Inode(ptr), Some(name)));
+            }
+            // ".." of the root of the process is the root itself.
+            if name.as_bytes() == b".."
+                && ptr.dev == proc.root().dev
+                && ptr.inum == proc.root().inum
+            {
+                continue;
             }
             let next = ip.dirlookup(name, self);
             drop(ip);
-            ptr = next?.0
+            ptr = next?.ok_or(())?.0;
+            if ptr.mounted.load(Ordering::Acquire) {
+                return Ok((Lookup::Fat32(path), None));
+            }
         }
         if parent {
             return Err(());
         }
-        Ok((ptr, None))
+        if must_be_dir && ptr.lock()?.deref_inner().typ != InodeType::Dir {
+            return Err(());
+        }
+        Ok((Lookup::Inode(ptr), None))
     }
 }
@@ -6,12 +6,23 @@
 //! any reasoning required about whether a commit might
 //! write an uncommitted system call's updates to disk.
 //!
-//! A system call should call begin_op()/end_op() to mark
-//! its start and end. Usually begin_op() just increments
+//! A system call marks its start and end with an `FsTransaction`,
+//! which calls begin_op() when it begins and end_op() when it is
+//! dropped, so no path can forget end_op(). Usually begin_op() just increments
 //! the count of in-progress FS system calls and returns.
 //! But if it thinks the LOG is close to running out, it
 //! sleeps until the last outstanding end_op() commits.
 //!
+//! A commit logs no more blocks than the LOG in the superblock holds, nor
+//! than MAXLOGSIZE, the size that mkfs gives it: the buffer cache keeps the
+//! blocks of two commits pinned, so a larger LOG is not used.
+//!
+//! New FS system calls may start as soon as a commit is written to the LOG,
+//! while its blocks are installed at their home locations; the next commit
+//! waits for the install, as the LOG still holds the last one. The home locations are written from
+//! the LOG, since the cached copies may already hold newer, uncommitted
+//! contents.
+//!
 //! The LOG is a physical re-do LOG containing disk blocks.
 //! The on-disk LOG format:
 //!   header block, containing block #s for block A, B, C, ...
@@ -20,8 +31,18 @@
 //!   block C
 //!   ...
 //! Log appends are synchronous.
-use core::mem;
-use core::ops::{Deref, DerefMut};
+//!
+//! With the discard mount option, blocks freed in a transaction are
+//! discarded after it commits, unless they are allocated again in it.
+//!
+//! Once the disk fails, the LOG is failed: the FS is read-only from then on.
+//! A transaction that has not reached its commit point is dropped, along with
+//! the cached copies of its blocks, and so are the later ones. A commit that
+//! failed to install is installed by the recovery at the next boot.
+use core::array::IntoIter;
+use core::ops::{Deref, DerefMut, Range};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{cmp, iter, mem};
 
 use arrayvec::ArrayVec;
 use itertools::*;
@@ -30,39 +51,30 @@ use static_assertions::const_assert;
 
 use crate::{
     bio::{Buf, BufData, BufUnlocked},
+    blockdev,
+    kernel::kernel_builder,
+    klog,
+    kstat::Counter,
     lock::{Sleepablelock, SleepablelockGuard},
-    param::{BSIZE, LOGSIZE, MAXOPBLOCKS},
+    param::{BSIZE, MAXLOGSIZE, MAXOPBLOCKS},
     virtio::Disk,
 };
 
 pub struct Log {
     inner: Once<Sleepablelock<LogInner>>,
     pub disk: Sleepablelock<Disk>,
+
+    /// Has writing the LOG failed? Nothing is committed then.
+    failed: AtomicBool,
 }
 
 /// A `LogLocked` is a `Log` whose `inner` can be accessed safely.
-/// Its `inner`, whose type is `LogLockedInner<'a>`, provides a reference to a `LogInner`.
 pub struct LogLocked<'a> {
-    inner: LogLockedInner<'a>,
-    disk: &'a Sleepablelock<Disk>,
-}
-
-/// A `LogLockedInner` provides a reference to a `LogInner`.
-///
-/// * A `Guard` has a guard of a `Sleepablelock` holding a `LogInner`.
-/// * A `Ref` has a mutable reference to a `LogInner`.
-///
-/// We need both variants. To access a `LogInner` by acquiring a lock, we make a `Guard`.
-/// In `Log::init` and `Log::end_op`, we need to access a `LogInner` without acquiring a lock. (To
-/// check their safety, see their implementations.) For this purpose, we make a `Ref`.
-pub enum LogLockedInner<'a> {
-    Guard(SleepablelockGuard<'a, LogInner>),
-    Ref(&'a mut LogInner),
+    inner: SleepablelockGuard<'a, LogInner>,
 }
 
 pub struct LogInner {
-    dev: u32,
-    start: i32,
+    area: LogArea,
     size: i32,
 
     /// How many FS sys calls are executing?
@@ -71,14 +83,41 @@ pub struct LogInner {
     /// In commit(), please wait.
     committing: bool,
 
+    /// Installing the last commit. FS sys calls may execute, but the next
+    /// commit must wait.
+    installing: bool,
+
+    /// The machine is shutting down; no new FS sys calls may start.
+    frozen: bool,
+
+    /// How many commits have taken their blocks, and how many of them have
+    /// been written to the log.
+    ncommit: u64,
+    ncommitted: u64,
+
     /// Contents of the header block, used to keep track in memory of logged block# before commit.
-    bufs: ArrayVec<[BufUnlocked; LOGSIZE]>,
+    bufs: ArrayVec<[BufUnlocked; MAXLOGSIZE]>,
+
+    /// Blocks to discard after commit. Blocks that do not fit are not
+    /// discarded, which is harmless.
+    discards: ArrayVec<[Range<u32>; NDISCARD]>,
+}
+
+/// Runs of blocks that a transaction can discard.
+const NDISCARD: usize = 16;
+
+/// Where the log is on disk: the header block at `start`, and the logged
+/// blocks after it.
+#[derive(Clone, Copy)]
+struct LogArea {
+    dev: u32,
+    start: u32,
 }
 
 /// Contents of the header block, used for the on-disk header block.
 struct LogHeader {
     n: u32,
-    block: [u32; LOGSIZE],
+    block: [u32; MAXLOGSIZE],
 }
 
 impl Log {
@@ -86,47 +125,66 @@ impl Log {
         Self {
             inner: Once::new(),
             disk: Sleepablelock::new("DISK", Disk::zero()),
+            failed: AtomicBool::new(false),
         }
     }
 
     pub fn init(&self, dev: u32, start: i32, size: i32) {
-        let mut inner = LogInner {
+        let area = LogArea {
             dev,
-            start,
-            size,
-            outstanding: 0,
-            committing: false,
-            bufs: ArrayVec::new(),
+            start: start as u32,
         };
-        LogLocked::new(LogLockedInner::Ref(&mut inner), &self.disk).recover_from_log();
-        let _ = self.inner.call_once(|| Sleepablelock::new("LOG", inner));
+        if area.recover(&self.disk).is_err() {
+            self.fail();
+        }
+        let _ = self.inner.call_once(|| {
+            Sleepablelock::new(
+                "LOG",
+                LogInner {
+                    area,
+                    size,
+                    outstanding: 0,
+                    committing: false,
+                    installing: false,
+                    frozen: false,
+                    ncommit: 0,
+                    ncommitted: 0,
+                    bufs: ArrayVec::new(),
+                    discards: ArrayVec::new(),
+                },
+            )
+        });
     }
 
-    fn inner(&self) -> &Sleepablelock<LogInner> {
-        self.inner.get().expect("LogInner")
+    /// Is the FS read-only, as the LOG failed?
+    pub fn is_read_only(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
     }
 
-    pub fn lock(&self) -> LogLocked<'_> {
-        LogLocked::new(LogLockedInner::Guard(self.inner().lock()), &self.disk)
+    /// Marks the LOG failed.
+    fn fail(&self) {
+        if !self.failed.swap(true, Ordering::AcqRel) {
+            klog!(Error, "fs: I/O error, the file system is read-only");
+        }
     }
 
-    /// # Safety
-    ///
-    /// Other threads must not read nor write this log while the returned `LogLocked` is alive.
-    unsafe fn lock_unchecked(&self) -> LogLocked<'_> {
-        LogLocked::new(
-            LogLockedInner::Ref(unsafe { &mut *self.inner().get_mut_raw() }),
-            &self.disk,
-        )
+    fn inner(&self) -> &Sleepablelock<LogInner> {
+        self.inner.get().expect("LogInner")
+    }
+
+    pub(super) fn lock(&self) -> LogLocked<'_> {
+        LogLocked {
+            inner: self.inner().lock(),
+        }
     }
 
     /// Called at the start of each FS system call.
-    pub fn begin_op(&self) {
+    pub(super) fn begin_op(&self) {
         let mut guard = self.inner().lock();
         loop {
-            if guard.committing ||
+            if guard.frozen || guard.committing ||
             // This op might exhaust log space; wait for commit.
-            guard.bufs.len() as i32 + (guard.outstanding + 1) * MAXOPBLOCKS as i32 > LOGSIZE as i32
+            guard.bufs.len() + (guard.outstanding as usize + 1) * MAXOPBLOCKS > guard.capacity()
             {
                 guard.sleep();
             } else {
@@ -136,9 +194,34 @@ impl Log {
         }
     }
 
+    /// Waits until every FS system call in progress has committed,
+    /// and keeps new ones from starting. Called before the machine shuts down.
+    pub fn freeze(&self) {
+        let mut guard = self.inner().lock();
+        guard.frozen = true;
+        while guard.outstanding > 0 || guard.committing || guard.installing {
+            guard.sleep();
+        }
+    }
+
+    /// Waits until the FS sys calls that have ended are committed, so that
+    /// what they wrote survives a crash. Must not be called in one.
+    /// Returns Ok(()) on success, Err(()) if the LOG has failed.
+    pub fn sync(&self) -> Result<(), ()> {
+        let mut guard = self.inner().lock();
+        let ncommit = guard.ncommit + if guard.bufs.is_empty() { 0 } else { 1 };
+        while guard.ncommitted < ncommit {
+            guard.sleep();
+        }
+        if self.is_read_only() {
+            return Err(());
+        }
+        Ok(())
+    }
+
     /// Called at the end of each FS system call.
     /// Commits if this was the last outstanding operation.
-    pub fn end_op(&self) {
+    pub(super) fn end_op(&self) {
         let mut guard = self.inner().lock();
         guard.outstanding -= 1;
         assert!(!guard.committing, "guard.committing");
@@ -148,53 +231,165 @@ impl Log {
             // The lock is still held, so new transactions cannot start.
             guard.committing = true;
             // Committing is true, so new transactions cannot start even after releasing the lock.
+            self.commit(&mut guard);
+        }
 
-            // Call commit w/o holding locks, since not allowed to sleep with locks.
-            guard.reacquire_after(||
-                // SAFETY: there is no another transaction, so `inner` cannot be read or written.
-                unsafe { self.lock_unchecked() }.commit());
+        // begin_op() may be waiting for LOG space, and decrementing log.outstanding has decreased
+        // the amount of reserved space.
+        guard.wakeup_all();
+    }
 
+    /// Commits the ended FS sys calls, and installs them. New ones may start
+    /// once the commit is written, as it clears `committing` then.
+    fn commit(&self, guard: &mut SleepablelockGuard<'_, LogInner>) {
+        // The log holds the last commit until it is installed.
+        while guard.installing {
+            guard.sleep();
+        }
+        let area = guard.area;
+        let bufs = mem::take(&mut guard.bufs);
+        let discards = mem::take(&mut guard.discards);
+        if bufs.is_empty() {
             guard.committing = false;
+            return;
         }
+        guard.ncommit += 1;
+
+        // Call commit w/o holding locks, since not allowed to sleep with locks.
+        let committed = guard.reacquire_after(|| {
+            // A block read may have failed in the transaction, which then
+            // holds zeros instead of its contents.
+            if self.is_read_only() || self.disk.has_failed() {
+                return Err(());
+            }
 
-        // begin_op() may be waiting for LOG space, and decrementing log.outstanding has decreased
-        // the amount of reserved space.
-        guard.wakeup();
+            // Write modified blocks from cache to log.
+            area.write_log(&self.disk, &bufs)?;
+
+            // Write header to disk -- the real commit.
+            area.write_head(&self.disk, bufs.iter().map(|buf| buf.blockno))
+        });
+        guard.committing = false;
+        guard.ncommitted += 1;
+        if committed.is_err() {
+            self.fail();
+            guard.wakeup_all();
+            guard.reacquire_after(|| {
+                // Drop the transaction.
+                for buf in bufs {
+                    buf.unpin();
+                    // A buffer without a page is invalid already.
+                    if let Ok(mut buf) = buf.lock() {
+                        buf.deref_inner_mut().valid = false;
+                    }
+                }
+            });
+            return;
+        }
+        guard.installing = true;
+        guard.wakeup_all();
+
+        guard.reacquire_after(|| {
+            // Now install writes to home locations, and erase the
+            // transaction from the log.
+            let installed = area
+                .install(&self.disk, bufs.iter().map(|buf| buf.blockno))
+                .and_then(|_| area.write_head(&self.disk, iter::empty()));
+            for buf in bufs {
+                buf.unpin();
+            }
+            if installed.is_err() {
+                self.fail();
+                return;
+            }
+
+            // The freed blocks are free on disk now.
+            for blocks in discards {
+                self.disk.discard(blocks.start, blocks.end - blocks.start);
+            }
+
+            // TODO: remove kernel_builder()
+            kernel_builder().kstat.inc(Counter::LogCommit);
+        });
+        guard.installing = false;
+    }
+
+    /// Ends the only FS operation like end_op(), but the machine "crashes" right after the commit
+    /// point: the blocks are not installed, and the cached copies are lost. Then the log is
+    /// recovered, as at boot.
+    #[cfg(feature = "ktest")]
+    pub(super) fn end_op_and_crash(&self) {
+        let mut guard = self.inner().lock();
+        assert!(
+            guard.outstanding == 1 && !guard.committing,
+            "end_op_and_crash"
+        );
+        guard.outstanding -= 1;
+        guard.committing = true;
+        while guard.installing {
+            guard.sleep();
+        }
+        let area = guard.area;
+        let bufs = mem::take(&mut guard.bufs);
+        guard.discards.clear();
+        guard.reacquire_after(|| {
+            let committed = area
+                .write_log(&self.disk, &bufs)
+                .and_then(|_| area.write_head(&self.disk, bufs.iter().map(|buf| buf.blockno)));
+            for buf in bufs {
+                buf.unpin();
+                if let Ok(mut buf) = buf.lock() {
+                    buf.deref_inner_mut().valid = false;
+                }
+            }
+            if committed.and_then(|_| area.recover(&self.disk)).is_err() {
+                self.fail();
+            }
+        });
+        guard.committing = false;
+        guard.wakeup_all();
     }
 }
 
-impl<'a> LogLocked<'a> {
-    fn new(inner: LogLockedInner<'a>, disk: &'a Sleepablelock<Disk>) -> Self {
-        Self { inner, disk }
+impl LogInner {
+    /// How many blocks a commit may log: as many as the log holds, but no
+    /// more than the buffer cache can keep pinned for the commit being
+    /// installed and the next one.
+    fn capacity(&self) -> usize {
+        cmp::min(self.size as usize - 1, MAXLOGSIZE)
     }
 }
 
-impl LogLocked<'_> {
+impl LogArea {
     /// Copy committed blocks from log to their home location.
-    fn install_trans(&mut self) {
-        let dev = self.inner.dev;
-        let start = self.inner.start;
-
-        for (tail, dbuf) in self.inner.bufs.drain(..).enumerate() {
+    /// Returns Ok(()) on success, Err(()) on an I/O error or if no buffer
+    /// is free.
+    fn install<I: Iterator<Item = u32>>(
+        self,
+        disk: &Sleepablelock<Disk>,
+        blocks: I,
+    ) -> Result<(), ()> {
+        for (tail, blockno) in blocks.enumerate() {
             // Read log block.
-            let lbuf = self.disk.read(dev, (start + tail as i32 + 1) as u32);
-
-            // Read dst.
-            let mut dbuf = dbuf.lock();
-
-            // Copy block to dst.
-            dbuf.deref_inner_mut()
-                .data
-                .copy_from_slice(&lbuf.deref_inner().data[..]);
+            let mut lbuf = disk.read(self.dev, self.start + tail as u32 + 1)?;
+            if disk.has_failed() {
+                return Err(());
+            }
 
-            // Write dst to disk.
-            self.disk.write(&mut dbuf);
+            // Write it to dst on disk.
+            blockdev::account(self.dev, true, || disk.rw_at(&mut lbuf, blockno, true))?;
         }
+        Ok(())
     }
 
-    /// Read the log header from disk into the in-memory log header.
-    fn read_head(&mut self) {
-        let mut buf = self.disk.read(self.dev, self.start as u32);
+    /// Read the block numbers in the log header from disk.
+    /// Returns Ok(the block numbers) on success, Err(()) on an I/O error or if
+    /// no buffer is free.
+    fn read_head(self, disk: &Sleepablelock<Disk>) -> Result<ArrayVec<[u32; MAXLOGSIZE]>, ()> {
+        let mut buf = disk.read(self.dev, self.start)?;
+        if disk.has_failed() {
+            return Err(());
+        }
 
         const_assert!(mem::size_of::<LogHeader>() <= BSIZE);
         const_assert!(mem::align_of::<BufData>() % mem::align_of::<LogHeader>() == 0);
@@ -205,17 +400,20 @@ impl LogLocked<'_> {
         // * buf is locked, so we can access it exclusively.
         let lh = unsafe { &mut *(buf.deref_inner_mut().data.as_mut_ptr() as *mut LogHeader) };
 
-        for b in &lh.block[0..lh.n as usize] {
-            let buf = self.disk.read(self.dev, *b).unlock();
-            self.bufs.push(buf);
-        }
+        Ok(lh.block[0..lh.n as usize].iter().copied().collect())
     }
 
-    /// Write in-memory log header to disk.
+    /// Write the log header with the block numbers `blocks` to disk.
     /// This is the true point at which the
     /// current transaction commits.
-    fn write_head(&mut self) {
-        let mut buf = self.disk.read(self.dev, self.start as u32);
+    /// Returns Ok(()) on success, Err(()) on an I/O error or if no buffer
+    /// is free.
+    fn write_head<I: Iterator<Item = u32>>(
+        self,
+        disk: &Sleepablelock<Disk>,
+        blocks: I,
+    ) -> Result<(), ()> {
+        let mut buf = disk.read(self.dev, self.start)?;
 
         const_assert!(mem::size_of::<LogHeader>() <= BSIZE);
         const_assert!(mem::align_of::<BufData>() % mem::align_of::<LogHeader>() == 0);
@@ -226,61 +424,68 @@ impl LogLocked<'_> {
         // * buf is locked, so we can access it exclusively.
         let mut lh = unsafe { &mut *(buf.deref_inner_mut().data.as_mut_ptr() as *mut LogHeader) };
 
-        lh.n = self.bufs.len() as u32;
-        for (db, b) in izip!(&mut lh.block, &self.bufs) {
-            *db = b.blockno;
+        lh.n = 0;
+        for (db, b) in izip!(&mut lh.block, blocks) {
+            *db = b;
+            lh.n += 1;
         }
-        self.disk.write(&mut buf)
+        // A disk with a write cache may reorder writes. The logged blocks
+        // must reach the disk before the header that commits them, and the
+        // header before the blocks are installed or the log is reused.
+        disk.flush()?;
+        disk.write(&mut buf)?;
+        disk.flush()
     }
 
-    fn recover_from_log(&mut self) {
-        self.read_head();
+    /// Returns Ok(()) on success, Err(()) on an I/O error or if no buffer
+    /// is free.
+    fn recover(self, disk: &Sleepablelock<Disk>) -> Result<(), ()> {
+        let blocks = self.read_head(disk)?;
 
         // If committed, copy from log to disk.
-        self.install_trans();
+        self.install(disk, blocks.iter().copied())?;
+
+        // The cached copies, if any, are older. A block that gets no buffer,
+        // or no page, is not cached.
+        for b in &blocks {
+            // TODO: remove kernel_builder()
+            if let Ok(mut buf) = unsafe { kernel_builder().get_bcache() }
+                .get_buf(self.dev, *b)
+                .and_then(|buf| buf.lock())
+            {
+                buf.deref_inner_mut().valid = false;
+            }
+        }
 
         // Clear the log.
-        self.write_head();
+        self.write_head(disk, iter::empty())
     }
 
-    /// Copy modified blocks from cache to self.
-    fn write_log(&mut self) {
-        for (tail, from) in self.bufs.iter().enumerate() {
+    /// Copy modified blocks from cache to log.
+    /// Returns Ok(()) on success, Err(()) on an I/O error or if no buffer
+    /// is free.
+    fn write_log(self, disk: &Sleepablelock<Disk>, bufs: &[BufUnlocked]) -> Result<(), ()> {
+        for (tail, from) in bufs.iter().enumerate() {
             // Log block.
-            let mut to = self
-                .disk
-                .read(self.dev, (self.start + tail as i32 + 1) as u32);
+            let mut to = disk.read(self.dev, self.start + tail as u32 + 1)?;
 
             // Cache block.
-            let from = self.disk.read(self.dev, from.blockno);
+            let from = disk.read(self.dev, from.blockno)?;
 
             to.deref_inner_mut()
                 .data
                 .copy_from_slice(&from.deref_inner().data[..]);
 
             // Write the log.
-            self.disk.write(&mut to);
+            disk.write(&mut to)?;
         }
+        Ok(())
     }
+}
 
-    fn commit(&mut self) {
-        if !self.bufs.is_empty() {
-            // Write modified blocks from cache to self.
-            self.write_log();
-
-            // Write header to disk -- the real commit.
-            self.write_head();
-
-            // Now install writes to home locations.
-            self.install_trans();
-
-            // Erase the transaction from the self.
-            self.write_head();
-        };
-    }
-
+impl LogLocked<'_> {
     /// Caller has modified b->data and is done with the buffer.
-    /// Record the block number and pin in the cache by increasing refcnt.
+    /// Record the block number and pin it in the cache.
     /// commit()/write_log() will do the disk write.
     ///
     /// write() replaces write(); a typical use is:
@@ -288,49 +493,60 @@ impl LogLocked<'_> {
     ///   modify bp->data[]
     ///   write(bp)
     pub fn write(&mut self, b: Buf) {
-        assert!(
-            !(self.bufs.len() >= LOGSIZE || self.bufs.len() as i32 >= self.size - 1),
-            "too big a transaction"
-        );
+        // Callers that log a varying number of blocks check has_room() first.
+        assert!(self.bufs.len() < self.capacity(), "too big a transaction");
         assert!(self.outstanding >= 1, "write outside of trans");
-
         if self.bufs.iter().all(|buf| buf.blockno != b.blockno) {
-            // Add new block to log
+            // Add new block to log, and keep it in the cache until it is
+            // installed.
+            b.pin();
             self.bufs.push(b.unlock());
         }
     }
-}
 
-impl<'a> Deref for LogLocked<'a> {
-    type Target = LogLockedInner<'a>;
+    /// Returns whether the log can take n more blocks.
+    pub fn has_room(&self, n: usize) -> bool {
+        self.bufs.len() + n <= self.capacity()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    /// Discards block b, which is freed, after commit.
+    pub fn discard(&mut self, b: u32) {
+        if let Some(blocks) = self.discards.iter_mut().find(|blocks| blocks.end == b) {
+            blocks.end += 1;
+        } else if let Some(blocks) = self
+            .discards
+            .iter_mut()
+            .find(|blocks| blocks.start == b + 1)
+        {
+            blocks.start -= 1;
+        } else {
+            let _ = self.discards.try_push(b..b + 1);
+        }
     }
-}
 
-impl<'a> DerefMut for LogLocked<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    /// Does not discard block b, which is allocated again.
+    pub fn keep(&mut self, b: u32) {
+        if let Some(i) = self.discards.iter().position(|blocks| blocks.contains(&b)) {
+            let blocks = self.discards.remove(i);
+            for rest in IntoIter::new([blocks.start..b, b + 1..blocks.end]) {
+                if !rest.is_empty() {
+                    let _ = self.discards.try_push(rest);
+                }
+            }
+        }
     }
 }
 
-impl Deref for LogLockedInner<'_> {
+impl Deref for LogLocked<'_> {
     type Target = LogInner;
 
     fn deref(&self) -> &Self::Target {
-        match self {
-            LogLockedInner::Guard(guard) => &guard,
-            LogLockedInner::Ref(r) => &r,
-        }
+        &self.inner
     }
 }
 
-impl DerefMut for LogLockedInner<'_> {
+impl DerefMut for LogLocked<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        match self {
-            LogLockedInner::Guard(ref mut guard) => guard,
-            LogLockedInner::Ref(ref mut r) => r,
-        }
+        &mut self.inner
     }
 }
@@ -11,30 +11,42 @@
 //!
 //! On-disk file system format used for both kernel and user programs are also included here.
 
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use core::{cmp, mem};
 
+use quota::Quota;
 use spin::Once;
 
-use crate::{bio::Buf, kernel::kernel_builder, param::BSIZE};
+use crate::{bio::Buf, kernel::kernel_builder, ok_or, param::BSIZE, stat::Statfs};
 
+mod fat32;
 mod inode;
 mod log;
 mod path;
+mod quota;
 mod superblock;
 
+pub use fat32::{Fat32, Fat32File};
 pub use inode::{
-    Dinode, Dirent, Inode, InodeGuard, InodeInner, InodeType, Itable, RcInode, DIRENT_SIZE, DIRSIZ,
+    Dinode, Dirent, Inode, InodeGuard, InodeInner, InodeType, Itable, Lookup, RcInode, DIRENT_SIZE,
+    DIRSIZ,
 };
 pub use log::{Log, LogLocked};
 pub use path::{FileName, Path};
-pub use superblock::{Superblock, BPB, IPB};
+pub use quota::{Dqblk, Q_GETQUOTA, Q_SETQUOTA};
+pub use superblock::{Superblock, BPB, IPB, NORPHAN};
 
 /// root i-number
-const ROOTINO: u32 = 1;
+pub const ROOTINO: u32 = 1;
 
-const NDIRECT: usize = 12;
+/// i-number of the quota file, which mkfs makes and no directory links.
+pub const QUOTAINO: u32 = 2;
+
+const NDIRECT: usize = 11;
 const NINDIRECT: usize = BSIZE.wrapping_div(mem::size_of::<u32>());
-const MAXFILE: usize = NDIRECT.wrapping_add(NINDIRECT);
+/// Maximum size of a file, in blocks.
+pub const MAXFILE: usize = NDIRECT.wrapping_add(NINDIRECT);
 
 pub struct FileSystem {
     /// TODO(https://github.com/kaist-cp/rv6/issues/358)
@@ -47,6 +59,22 @@ pub struct FileSystem {
     /// document it / initializing log should be run
     /// only once because forkret() calls fsinit()
     pub log: Log,
+
+    /// Number of free blocks, counted from the free bit map at boot, and
+    /// kept up to date as blocks are allocated and freed.
+    nfree_blocks: AtomicU32,
+
+    /// Number of free inodes, counted likewise.
+    nfree_inodes: AtomicU32,
+
+    /// Limits and usage of the blocks and inodes of each user id.
+    quota: Quota,
+
+    /// Are freed blocks discarded? The discard mount option.
+    discard: AtomicBool,
+
+    /// Does each write() commit before it returns? The sync mount option.
+    sync: AtomicBool,
 }
 
 pub struct FsTransaction<'s> {
@@ -58,19 +86,118 @@ impl FileSystem {
         Self {
             superblock: Once::new(),
             log: Log::zero(),
+            nfree_blocks: AtomicU32::new(0),
+            nfree_inodes: AtomicU32::new(0),
+            quota: Quota::new(),
+            discard: AtomicBool::new(false),
+            sync: AtomicBool::new(false),
         }
     }
 
-    pub fn init(&self, dev: u32) {
+    /// Returns Ok(()) on success, Err(()) if the file system is invalid, has
+    /// blocks of another size, or no buffer is free.
+    pub fn init(&self, dev: u32) -> Result<(), ()> {
         if !self.superblock.is_completed() {
-            let superblock = self
-                .superblock
-                .call_once(|| Superblock::new(&self.log.disk.read(dev, 1)));
+            let superblock =
+                Superblock::new(&self.log.disk.read(dev, 1)?, &self.log.disk.read(dev, 0)?)?;
+            let superblock = self.superblock.call_once(|| superblock);
             self.log
                 .init(dev, superblock.logstart as i32, superblock.nlog as i32);
+            // TODO: remove kernel_builder()
+            self.reclaim_orphans(dev, &kernel_builder().itable)?;
+            // Count after recovery, which may change the bit map and inodes.
+            self.nfree_blocks
+                .store(self.count_free_blocks(dev)?, Ordering::Relaxed);
+            self.nfree_inodes
+                .store(self.count_free_inodes(dev)?, Ordering::Relaxed);
+            // TODO: remove kernel_builder()
+            self.init_quota(dev, &kernel_builder().itable)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the inodes on the orphan list, which lost their last link but
+    /// were still open when the system went down.
+    /// Returns Ok(()) on success, Err(()) if no buffer or entry of the inode
+    /// table is free.
+    pub fn reclaim_orphans(&self, dev: u32, itable: &Itable) -> Result<(), ()> {
+        // The list is too large to copy onto the stack, so read an entry at a
+        // time.
+        for i in 0..NORPHAN {
+            let inum = Superblock::orphans(&mut self.log.disk.read(dev, 1)?)[i];
+            if inum == 0 {
+                continue;
+            }
+            let tx = self.begin_transaction();
+            let ptr = itable.get_inode(dev, inum)?;
+            if ptr.lock()?.deref_inner().nlink != 0 {
+                tx.remove_orphan(dev, inum)?;
+            }
+            // Dropping the last reference frees the inode and removes it
+            // from the list.
+            drop(ptr);
+        }
+        Ok(())
+    }
+
+    /// Sets the discard mount option.
+    pub fn set_discard(&self, discard: bool) {
+        self.discard.store(discard, Ordering::Relaxed);
+    }
+
+    /// Sets the sync mount option.
+    pub fn set_sync(&self, sync: bool) {
+        self.sync.store(sync, Ordering::Relaxed);
+    }
+
+    pub fn is_sync(&self) -> bool {
+        self.sync.load(Ordering::Relaxed)
+    }
+
+    /// Returns the usage of the file system.
+    pub fn statfs(&self) -> Statfs {
+        Statfs {
+            bsize: BSIZE as u32,
+            blocks: self.superblock().size,
+            bfree: self.nfree_blocks.load(Ordering::Relaxed),
+            files: self.superblock().ninodes,
+            ffree: self.nfree_inodes.load(Ordering::Relaxed),
         }
     }
 
+    /// Counts the clear bits of the free bit map.
+    /// Returns Ok(the count) on success, Err(()) if no buffer is free.
+    fn count_free_blocks(&self, dev: u32) -> Result<u32, ()> {
+        let size = self.superblock().size;
+        let mut nfree = 0;
+        for b in num_iter::range_step(0, size, BPB as u32) {
+            let bp = self.log.disk.read(dev, self.superblock().bblock(b))?;
+            for bi in 0..cmp::min(BPB as u32, size - b) {
+                if bp.deref_inner().data[(bi / 8) as usize] & (1 << (bi % 8)) == 0 {
+                    nfree += 1;
+                }
+            }
+        }
+        Ok(nfree)
+    }
+
+    /// Counts the inodes whose type is none. Inode 0 is never used.
+    /// Returns Ok(the count) on success, Err(()) if no buffer is free.
+    fn count_free_inodes(&self, dev: u32) -> Result<u32, ()> {
+        let mut nfree = 0;
+        for inum in 1..self.superblock().ninodes {
+            let bp = self.log.disk.read(dev, self.superblock().iblock(inum))?;
+            let off = inum as usize % IPB * mem::size_of::<Dinode>();
+            // The type is the first field of a Dinode.
+            let typ =
+                i16::from_ne_bytes([bp.deref_inner().data[off], bp.deref_inner().data[off + 1]]);
+            if typ == inode::DInodeType::None as i16 {
+                nfree += 1;
+            }
+        }
+        Ok(nfree)
+    }
+
     /// TODO(https://github.com/kaist-cp/rv6/issues/358)
     /// Calling superblock() after initialize is safe
     fn superblock(&self) -> &Superblock {
@@ -105,44 +232,135 @@ impl FsTransaction<'_> {
     ///   bp = kernel().file_system.disk.read(...)
     ///   modify bp->data[]
     ///   write(bp)
-    fn write(&self, b: Buf) {
+    pub fn write(&self, b: Buf) {
         self.fs.log.lock().write(b);
     }
 
+    /// Is the FS read-only, as an I/O error failed its log?
+    pub fn is_read_only(&self) -> bool {
+        self.fs.log.is_read_only()
+    }
+
+    /// Ends the transaction, which must be the only one, like dropping it,
+    /// but the machine "crashes" right after the commit point.
+    #[cfg(feature = "ktest")]
+    pub fn end_and_crash(self) {
+        let fs = self.fs;
+        mem::forget(self);
+        fs.log.end_op_and_crash();
+    }
+
     /// Zero a block.
-    fn bzero(&self, dev: u32, bno: u32) {
+    /// Returns Ok(()) on success, Err(()) if no buffer is free.
+    fn bzero(&self, dev: u32, bno: u32) -> Result<(), ()> {
         // TODO: remove kernel_builder()
         let mut buf = unsafe { kernel_builder().get_bcache() }
-            .get_buf(dev, bno)
-            .lock();
+            .get_buf(dev, bno)?
+            .lock()?;
         buf.deref_inner_mut().data.fill(0);
         buf.deref_inner_mut().valid = true;
         self.write(buf);
+        Ok(())
     }
 
     /// Blocks.
-    /// Allocate a zeroed disk block.
-    fn balloc(&self, dev: u32) -> u32 {
-        for b in num_iter::range_step(0, self.fs.superblock().size, BPB as u32) {
-            let mut bp = self.fs.log.disk.read(dev, self.fs.superblock().bblock(b));
-            for bi in 0..cmp::min(BPB as u32, self.fs.superblock().size - b) {
-                let m = 1 << (bi % 8);
-                if bp.deref_inner_mut().data[(bi / 8) as usize] & m == 0 {
-                    // Is block free?
-                    bp.deref_inner_mut().data[(bi / 8) as usize] |= m; // Mark block in use.
-                    self.write(bp);
-                    self.bzero(dev, b + bi);
-                    return b + bi;
-                }
+    /// Allocate a zeroed disk block for a file of user id uid, and charge it
+    /// to uid. If near is not 0, prefer a block after it, or else before it,
+    /// with the same bitmap block, so that the blocks of a file stay
+    /// together.
+    /// Returns Ok(the block) on success, Err(()) if the disk is full, if uid
+    /// is over its quota, if the transaction has no room to log the bitmap
+    /// block and the block, or if no buffer is free.
+    fn balloc(&self, dev: u32, near: u32, uid: u32) -> Result<u32, ()> {
+        if !self.fs.log.lock().has_room(2) {
+            return Err(());
+        }
+        self.fs.quota.charge(uid, 1, 0)?;
+        let block = self.balloc_near(dev, near);
+        if block.is_err() {
+            self.fs.quota.uncharge(uid, 1, 0);
+        }
+        block
+    }
+
+    /// Allocate a zeroed disk block, near near if it is not 0.
+    /// Returns Ok(the block) on success, Err(()) if the disk is full or if no
+    /// buffer is free.
+    fn balloc_near(&self, dev: u32, near: u32) -> Result<u32, ()> {
+        let size = self.fs.superblock().size;
+        if near != 0 && near < size {
+            let b = near - near % BPB as u32;
+            let end = cmp::min(BPB as u32, size - b);
+            let bi = near - b;
+            if let Some(block) = self.balloc_in(dev, b, bi + 1..end)? {
+                return Ok(block);
+            }
+            if let Some(block) = self.balloc_in(dev, b, 0..bi)? {
+                return Ok(block);
             }
         }
+        for b in num_iter::range_step(0, size, BPB as u32) {
+            if let Some(block) = self.balloc_in(dev, b, 0..cmp::min(BPB as u32, size - b))? {
+                return Ok(block);
+            }
+        }
+        Err(())
+    }
+
+    /// Allocate a zeroed disk block among b + bits, where b is the first
+    /// block of a bitmap block.
+    /// Returns Ok(the block, if any is free) on success, Err(()) if no buffer
+    /// is free.
+    fn balloc_in(&self, dev: u32, b: u32, bits: Range<u32>) -> Result<Option<u32>, ()> {
+        let mut bp = self.fs.log.disk.read(dev, self.fs.superblock().bblock(b))?;
+        for bi in bits {
+            let m = 1 << (bi % 8);
+            if bp.deref_inner_mut().data[(bi / 8) as usize] & m == 0 {
+                // Is block free?
+                // Zero it first, so that it stays free if there is no buffer
+                // to zero it in.
+                self.bzero(dev, b + bi)?;
+                bp.deref_inner_mut().data[(bi / 8) as usize] |= m; // Mark block in use.
+                self.write(bp);
+                let _ = self.fs.nfree_blocks.fetch_sub(1, Ordering::Relaxed);
+                self.fs.log.lock().keep(b + bi);
+                return Ok(Some(b + bi));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Orphans.
+    /// Add inode inum, which has no links but may still be open, to the
+    /// orphan list, so that mount frees it after a crash. If the list is
+    /// full, or no buffer is free, the inode leaks on a crash, as without the
+    /// list.
+    pub fn add_orphan(&self, dev: u32, inum: u32) {
+        let mut bp = ok_or!(self.fs.log.disk.read(dev, 1), return);
+        if let Some(slot) = Superblock::orphans(&mut bp).iter_mut().find(|o| **o == 0) {
+            *slot = inum;
+            self.write(bp);
+        }
+    }
 
-        panic!("balloc: out of blocks");
+    /// Remove inode inum from the orphan list, if it is there.
+    /// Returns Ok(()) on success, Err(()) if no buffer is free.
+    pub fn remove_orphan(&self, dev: u32, inum: u32) -> Result<(), ()> {
+        let mut bp = self.fs.log.disk.read(dev, 1)?;
+        if let Some(slot) = Superblock::orphans(&mut bp)
+            .iter_mut()
+            .find(|o| **o == inum)
+        {
+            *slot = 0;
+            self.write(bp);
+        }
+        Ok(())
     }
 
-    /// Free a disk block.
-    fn bfree(&self, dev: u32, b: u32) {
-        let mut bp = self.fs.log.disk.read(dev, self.fs.superblock().bblock(b));
+    /// Free a disk block of a file of user id uid, and give it back to uid.
+    /// Returns Ok(()) on success, Err(()) if no buffer is free.
+    fn bfree(&self, dev: u32, b: u32, uid: u32) -> Result<(), ()> {
+        let mut bp = self.fs.log.disk.read(dev, self.fs.superblock().bblock(b))?;
         let bi = b as usize % BPB;
         let m = 1u8 << (bi % 8);
         assert_ne!(
@@ -152,5 +370,11 @@ impl FsTransaction<'_> {
         );
         bp.deref_inner_mut().data[bi / 8] &= !m;
         self.write(bp);
+        let _ = self.fs.nfree_blocks.fetch_add(1, Ordering::Relaxed);
+        self.fs.quota.uncharge(uid, 1, 0);
+        if self.fs.discard.load(Ordering::Relaxed) {
+            self.fs.log.lock().discard(b);
+        }
+        Ok(())
     }
 }
@@ -121,6 +121,11 @@ impl Path {
         !self.inner.is_empty() && self.inner[0] == b'/'
     }
 
+    /// Returns `true` if `Path` ends with `'/'`, so that it must name a directory.
+    pub fn has_trailing_slash(&self) -> bool {
+        self.inner.last() == Some(&b'/')
+    }
+
     pub fn is_empty_string(&self) -> bool {
         self.inner.is_empty()
     }
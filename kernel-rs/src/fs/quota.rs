@@ -0,0 +1,180 @@
+//! Disk quotas, shared with user programs through kernel/quota.h.
+//!
+//! The files of each user id below NQUOTA may take at most as many blocks
+//! and inodes as its quota allows. Each inode and block is charged to the
+//! owner of its file, when it is allocated in a transaction, and given back
+//! when it is freed. A limit of 0 means no limit.
+//!
+//! The limits are in the quota file, inode QUOTAINO, an array of `Dquot`
+//! indexed by user id. The usage is not on disk: mount counts it from the
+//! inodes.
+
+use core::mem;
+
+use super::{Dinode, FileSystem, FsTransaction, Itable, QUOTAINO};
+use crate::{
+    lock::Spinlock,
+    param::{NQUOTA, ROOTDEV},
+    some_or,
+};
+
+/// Get the limits and usage of a user id.
+pub const Q_GETQUOTA: i32 = 1;
+
+/// Set the limits of a user id.
+pub const Q_SETQUOTA: i32 = 2;
+
+/// Limits and usage of the blocks and inodes of a user id.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Dqblk {
+    /// Most blocks that its files may take, or 0
+    pub bhardlimit: u32,
+
+    /// Most inodes that its files may take, or 0
+    pub ihardlimit: u32,
+
+    /// Blocks that its files take
+    pub curblocks: u32,
+
+    /// Inodes that its files take
+    pub curinodes: u32,
+}
+
+/// An entry of the quota file.
+#[repr(C)]
+struct Dquot {
+    bhardlimit: u32,
+    ihardlimit: u32,
+}
+
+pub struct Quota {
+    table: Spinlock<[Dqblk; NQUOTA]>,
+}
+
+impl Dqblk {
+    pub const fn zero() -> Self {
+        Self {
+            bhardlimit: 0,
+            ihardlimit: 0,
+            curblocks: 0,
+            curinodes: 0,
+        }
+    }
+}
+
+impl Quota {
+    pub const fn new() -> Self {
+        Self {
+            table: Spinlock::new("quota", [Dqblk::zero(); NQUOTA]),
+        }
+    }
+
+    /// Charges blocks and inodes to user id uid.
+    /// Returns Ok(()) on success, Err(()) if they would take uid over its
+    /// quota.
+    pub fn charge(&self, uid: u32, blocks: u32, inodes: u32) -> Result<(), ()> {
+        let mut table = self.table.lock();
+        let dq = some_or!(table.get_mut(uid as usize), return Ok(()));
+        let over = |used: u32, n: u32, limit: u32| n > 0 && limit != 0 && used + n > limit;
+        if over(dq.curblocks, blocks, dq.bhardlimit) || over(dq.curinodes, inodes, dq.ihardlimit) {
+            return Err(());
+        }
+        dq.curblocks += blocks;
+        dq.curinodes += inodes;
+        Ok(())
+    }
+
+    /// Gives blocks and inodes back to user id uid.
+    pub fn uncharge(&self, uid: u32, blocks: u32, inodes: u32) {
+        let mut table = self.table.lock();
+        let dq = some_or!(table.get_mut(uid as usize), return);
+        dq.curblocks = dq.curblocks.saturating_sub(blocks);
+        dq.curinodes = dq.curinodes.saturating_sub(inodes);
+    }
+}
+
+impl FileSystem {
+    /// Returns the limits and usage of user id uid, if it may have a quota.
+    pub fn quota(&self, uid: u32) -> Option<Dqblk> {
+        self.quota.table.lock().get(uid as usize).copied()
+    }
+
+    /// Counts the blocks and inodes of each user id, and reads the limits
+    /// from the quota file.
+    /// Returns Ok(()) on success, Err(()) if there is no quota file, or if no
+    /// buffer or entry of the inode table is free.
+    pub(super) fn init_quota(&self, dev: u32, itable: &Itable) -> Result<(), ()> {
+        *self.quota.table.lock() = [Dqblk::zero(); NQUOTA];
+        for inum in 1..self.superblock().ninodes {
+            let bp = self.log.disk.read(dev, self.superblock().iblock(inum))?;
+            let dip = Dinode::of(&bp, inum);
+            if dip.is_free() {
+                if inum == QUOTAINO {
+                    return Err(());
+                }
+                continue;
+            }
+            let uid = dip.uid;
+            let mut blocks = dip.addr_direct.iter().filter(|addr| **addr != 0).count() as u32;
+            let indirect = dip.addr_indirect;
+            drop(bp);
+            if indirect != 0 {
+                let bp = self.log.disk.read(dev, indirect)?;
+                // SAFETY: u32 does not have internal structure.
+                let (prefix, data, _) = unsafe { bp.deref_inner().data.align_to::<u32>() };
+                debug_assert_eq!(prefix.len(), 0, "init_quota: Buf data unaligned");
+                blocks += 1 + data.iter().filter(|addr| **addr != 0).count() as u32;
+            }
+            if let Some(dq) = self.quota.table.lock().get_mut(uid as usize) {
+                dq.curblocks += blocks;
+                dq.curinodes += 1;
+            }
+        }
+
+        // Dropping the inode may write to the disk.
+        let _tx = self.begin_transaction();
+        let ptr = itable.get_inode(dev, QUOTAINO)?;
+        let mut ip = ptr.lock()?;
+        for uid in 0..NQUOTA {
+            let mut dquot = Dquot {
+                bhardlimit: 0,
+                ihardlimit: 0,
+            };
+            let off = (uid * mem::size_of::<Dquot>()) as u32;
+            // User ids beyond the end of the file have no limits.
+            // SAFETY: Dquot contains only u32's.
+            if unsafe { ip.read_kernel(&mut dquot, off) }.is_err() {
+                break;
+            }
+            let mut table = self.quota.table.lock();
+            table[uid].bhardlimit = dquot.bhardlimit;
+            table[uid].ihardlimit = dquot.ihardlimit;
+        }
+        Ok(())
+    }
+}
+
+impl FsTransaction<'_> {
+    /// Sets the limits of user id uid to those in limits, in the quota file
+    /// too.
+    /// Returns Ok(()) on success, Err(()) if uid may not have a quota, or if
+    /// the quota file cannot be written.
+    pub fn set_quota(&self, uid: u32, limits: &Dqblk, itable: &Itable) -> Result<(), ()> {
+        if uid as usize >= NQUOTA {
+            return Err(());
+        }
+        let ptr = itable.get_inode(ROOTDEV, QUOTAINO)?;
+        let mut ip = ptr.lock()?;
+        let dquot = Dquot {
+            bhardlimit: limits.bhardlimit,
+            ihardlimit: limits.ihardlimit,
+        };
+        ip.write_kernel(&dquot, uid * mem::size_of::<Dquot>() as u32, self)?;
+        // The file and the table change together under the lock of the file.
+        let mut table = self.fs.quota.table.lock();
+        table[uid as usize].bhardlimit = limits.bhardlimit;
+        table[uid as usize].ihardlimit = limits.ihardlimit;
+        Ok(())
+    }
+}
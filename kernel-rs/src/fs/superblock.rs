@@ -5,17 +5,23 @@ use static_assertions::const_assert;
 use super::Dinode;
 use crate::{
     bio::{Buf, BufData},
+    klog,
     param::BSIZE,
+    riscv::PGSIZE,
 };
 
-const FSMAGIC: u32 = 0x10203040;
+const FSMAGIC: u32 = 0x10203041;
+
+/// Magic number of file systems made before inodes had owners.
+const OLD_FSMAGIC: u32 = 0x10203040;
 
 /// Disk layout:
 /// [ boot block | super block | log | inode blocks |
 ///                                          free bit map | data blocks]
 ///
 /// mkfs computes the super block and builds an initial file system. The
-/// super block describes the disk layout:
+/// super block describes the disk layout. The rest of its block is the
+/// orphan list.
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Superblock {
@@ -42,17 +48,33 @@ pub struct Superblock {
 
     /// Block number of first free map block
     pub bmapstart: u32,
+
+    /// Block size (bytes), or 0 if the file system was made before the super
+    /// block recorded it, when blocks were LEGACY_BSIZE bytes.
+    bsize: u32,
 }
 
+/// Block size of file systems made before the super block recorded it.
+const LEGACY_BSIZE: usize = 1024;
+
+const_assert!(BSIZE % 512 == 0 && BSIZE <= PGSIZE);
+
 /// Inodes per block.
 pub const IPB: usize = BSIZE / mem::size_of::<Dinode>();
 
 /// Bitmap bits per block
 pub const BPB: usize = BSIZE * 8;
 
+/// Number of entries of the orphan list.
+pub const NORPHAN: usize = (BSIZE - mem::size_of::<Superblock>()) / mem::size_of::<u32>();
+
 impl Superblock {
-    /// Read the super block.
-    pub fn new(buf: &Buf) -> Self {
+    /// Read the super block, which is in `buf`, block 1. `boot` is block 0,
+    /// which holds the super block instead if the file system has smaller
+    /// blocks.
+    /// Returns Ok(the super block) on success, Err(()) unless the file system
+    /// is valid and has blocks of BSIZE bytes, which the kernel is built for.
+    pub fn new(buf: &Buf, boot: &Buf) -> Result<Self, ()> {
         const_assert!(mem::size_of::<Superblock>() <= BSIZE);
         const_assert!(mem::align_of::<BufData>() % mem::align_of::<Superblock>() == 0);
         // SAFETY:
@@ -61,8 +83,50 @@ impl Superblock {
         // * Superblock contains only u32's, so does not have any requirements.
         // * buf is locked, so we can access it exclusively.
         let result = unsafe { ptr::read(buf.deref_inner().data.as_ptr() as *const Superblock) };
-        assert_eq!(result.magic, FSMAGIC, "invalid file system");
-        result
+        if result.magic != FSMAGIC {
+            // The magic number of a file system of LEGACY_BSIZE-byte blocks.
+            let legacy = &boot.deref_inner().data[LEGACY_BSIZE..LEGACY_BSIZE + 4];
+            if result.magic == OLD_FSMAGIC {
+                klog!(
+                    Error,
+                    "fs: file system has inodes without owners: make it again with mkfs"
+                );
+            } else if BSIZE > LEGACY_BSIZE && legacy == OLD_FSMAGIC.to_le_bytes() {
+                klog!(
+                    Error,
+                    "fs: file system has {}-byte blocks, not {}: make it again with mkfs",
+                    LEGACY_BSIZE,
+                    BSIZE
+                );
+            } else {
+                klog!(Error, "fs: invalid file system");
+            }
+            return Err(());
+        }
+        let bsize = if result.bsize == 0 {
+            LEGACY_BSIZE
+        } else {
+            result.bsize as usize
+        };
+        if bsize != BSIZE {
+            klog!(
+                Error,
+                "fs: file system has {}-byte blocks, not {}",
+                bsize,
+                BSIZE
+            );
+            return Err(());
+        }
+        Ok(result)
+    }
+
+    /// The orphan list in `buf`, the block of the super block: the inode
+    /// numbers of inodes that have no links but may still be open, or 0.
+    pub fn orphans(buf: &mut Buf) -> &mut [u32] {
+        // SAFETY: u32 does not have internal structure.
+        let (prefix, data, _) = unsafe { buf.deref_inner_mut().data.align_to_mut::<u32>() };
+        debug_assert_eq!(prefix.len(), 0, "orphans: Buf data unaligned");
+        &mut data[mem::size_of::<Superblock>() / mem::size_of::<u32>()..]
     }
 
     /// Block containing inode i
@@ -0,0 +1,634 @@
+//! A gdb remote stub for kernel code, with the `gdbstub` feature.
+//!
+//! gdb talks to the stub over a virtio serial device on the sixth virtio
+//! mmio interface, if there is one: with `make qemu GDBSTUB=yes`, `target
+//! remote localhost:PORT`, where PORT is GDBSTUBPORT in the Makefile. The
+//! console stays on the UART. The first hart stops for gdb at boot.
+//! Afterwards, kernel code stops when it hits a breakpoint, or when gdb
+//! interrupts it with ^C. While a hart is stopped, the others wait at their
+//! next interrupt.
+//!
+//! The stub reads and writes registers and memory, and sets breakpoints.
+//! RISC-V has no single-step in supervisor mode, so the stub steps by
+//! placing a breakpoint on the instruction that runs next.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use arrayvec::ArrayString;
+
+use crate::{
+    klog, memlayout::kstack, param::NPROC, platform::platform, plic, riscv::PGSIZE, virtio::Serial,
+};
+
+/// Size of the packets that the stub receives and sends.
+const BUFSIZE: usize = 1024;
+
+/// Breakpoints set at a time, at most.
+const MAXBREAK: usize = 16;
+
+/// Bytes read from memory for a packet, at most.
+const MAXREAD: usize = 256;
+
+/// ebreak, and its compressed form.
+const EBREAK: [u8; 4] = [0x73, 0x00, 0x10, 0x00];
+const C_EBREAK: [u8; 2] = [0x02, 0x90];
+
+/// The signal in stop replies, which gdb shows.
+const SIGTRAP: u8 = 5;
+
+/// Registers that kernelvec.S saves on the stack: x1 to x31.
+// It needs repr(C) because kernelvec.S lays it out.
+#[repr(C)]
+pub struct KernelFrame {
+    /// sp is saved after kernelvec.S makes room for the frame.
+    regs: [usize; 31],
+}
+
+/// Size of the room that kernelvec.S makes on the stack.
+const KERNEL_FRAME_SIZE: usize = 256;
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+
+    /// The ebreak is 2 or 4 bytes long.
+    len: usize,
+
+    /// The bytes that the ebreak replaces.
+    orig: [u8; 4],
+}
+
+struct Breakpoints {
+    set: [Option<Breakpoint>; MAXBREAK],
+
+    /// Are the breakpoints in `set` in memory now?
+    inserted: bool,
+
+    /// The breakpoint placed to single-step, if any.
+    step: Option<Breakpoint>,
+}
+
+struct Stub {
+    /// The virtio serial device that gdb talks to.
+    port: Serial,
+    breakpoints: Breakpoints,
+
+    /// gdb waits for a stop reply, after it continued or stepped.
+    running: bool,
+
+    packet: [u8; BUFSIZE],
+
+    /// Set on the first use, as it cannot be made in a static.
+    reply: Option<ArrayString<[u8; BUFSIZE]>>,
+}
+
+struct Gdbstub {
+    /// Held by the hart that talks with gdb.
+    locked: AtomicBool,
+    stub: UnsafeCell<Stub>,
+}
+
+// SAFETY: `stub` is accessed only while holding `locked`.
+unsafe impl Sync for Gdbstub {}
+
+static GDBSTUB: Gdbstub = Gdbstub {
+    locked: AtomicBool::new(false),
+    stub: UnsafeCell::new(Stub {
+        port: Serial::zero(),
+        breakpoints: Breakpoints {
+            set: [None; MAXBREAK],
+            inserted: false,
+            step: None,
+        },
+        running: false,
+        packet: [0; BUFSIZE],
+        reply: None,
+    }),
+};
+
+/// Set while a hart is stopped for gdb, so that the others wait.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+impl KernelFrame {
+    /// x0 to x31, and then `pc`.
+    fn regs(&self, pc: usize) -> [usize; 33] {
+        let mut regs = [0; 33];
+        regs[1..32].copy_from_slice(&self.regs);
+        regs[2] += KERNEL_FRAME_SIZE;
+        regs[32] = pc;
+        regs
+    }
+
+    /// Set x1 to x31 from `regs`, given as regs() returns them, except sp,
+    /// from where kernelvec.S restores the others, and tp, which it does not
+    /// restore.
+    fn set_regs(&mut self, regs: &[usize; 33]) {
+        let (sp, tp) = (self.regs[1], self.regs[3]);
+        self.regs.copy_from_slice(&regs[1..32]);
+        self.regs[1] = sp;
+        self.regs[3] = tp;
+    }
+}
+
+impl Gdbstub {
+    /// Calls `f` with the stub locked.
+    fn with<R, F: FnOnce(&mut Stub) -> R>(&self, f: F) -> R {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // SAFETY: we hold `locked`.
+        let result = f(unsafe { &mut *self.stub.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl Breakpoints {
+    fn add(&mut self, addr: usize, len: usize) {
+        if self.set.iter().flatten().any(|bp| bp.addr == addr) {
+            return;
+        }
+        if let Some(slot) = self.set.iter_mut().find(|bp| bp.is_none()) {
+            *slot = Some(Breakpoint {
+                addr,
+                len,
+                orig: [0; 4],
+            });
+        }
+    }
+
+    fn delete(&mut self, addr: usize) {
+        for slot in self.set.iter_mut() {
+            if slot.map_or(false, |bp| bp.addr == addr) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Puts the breakpoints in memory, before the hart continues.
+    fn insert(&mut self) {
+        for bp in self.set.iter_mut().flatten() {
+            for i in 0..bp.len {
+                bp.orig[i] = peek(bp.addr + i);
+            }
+            let ebreak: &[u8] = if bp.len == 2 { &C_EBREAK } else { &EBREAK };
+            for (i, b) in ebreak.iter().enumerate() {
+                poke(bp.addr + i, *b);
+            }
+        }
+        self.inserted = true;
+    }
+
+    /// Puts a breakpoint on the instruction after the one at pc, before the
+    /// hart steps. The others stay out of memory.
+    fn insert_step(&mut self, regs: &[usize; 33]) {
+        let addr = next_pc(regs);
+        if is_mapped(addr, 2) {
+            let orig = [peek(addr), peek(addr + 1), 0, 0];
+            self.step = Some(Breakpoint { addr, len: 2, orig });
+            poke(addr, C_EBREAK[0]);
+            poke(addr + 1, C_EBREAK[1]);
+        }
+    }
+
+    /// Restores the bytes that the breakpoints in memory replaced.
+    fn remove(&mut self) {
+        let inserted = if self.inserted { &self.set[..] } else { &[] };
+        for bp in inserted.iter().flatten().chain(self.step.iter()) {
+            for i in 0..bp.len {
+                poke(bp.addr + i, bp.orig[i]);
+            }
+        }
+        self.inserted = false;
+        self.step = None;
+    }
+}
+
+impl Stub {
+    /// Talks with gdb until it resumes the hart, whose registers are x0 to
+    /// x31 and pc in `regs`.
+    fn serve(&mut self, regs: &mut [usize; 33]) {
+        let reply = self.reply.get_or_insert_with(ArrayString::new);
+        if self.running {
+            self.running = false;
+            reply.clear();
+            let _ = write!(reply, "S{:02x}", SIGTRAP);
+            send(&mut self.port, reply);
+        }
+
+        loop {
+            let len = recv(&mut self.port, &mut self.packet);
+            let packet = &self.packet[..len];
+            reply.clear();
+            let (cmd, args) = match packet.split_first() {
+                Some((cmd, args)) => (*cmd, args),
+                None => continue,
+            };
+            match cmd {
+                b'?' => {
+                    let _ = write!(reply, "S{:02x}", SIGTRAP);
+                }
+                b'g' => {
+                    for reg in regs.iter() {
+                        put_hex(reply, &reg.to_le_bytes());
+                    }
+                }
+                b'G' => {
+                    for (i, reg) in regs.iter_mut().enumerate().skip(1) {
+                        if let Some(v) = args.get(i * 16..i * 16 + 16).and_then(parse_le) {
+                            *reg = v;
+                        }
+                    }
+                    reply.push_str("OK");
+                }
+                b'p' => {
+                    match parse_hex(args).and_then(|n| regs.get(n)) {
+                        Some(reg) => put_hex(reply, &reg.to_le_bytes()),
+                        None => reply.push_str("E01"),
+                    }
+                }
+                b'P' => {
+                    let mut it = args.splitn(2, |c| *c == b'=');
+                    match (it.next().and_then(parse_hex), it.next().and_then(parse_le)) {
+                        (Some(n), Some(v)) if 0 < n && n < regs.len() => {
+                            regs[n] = v;
+                            reply.push_str("OK");
+                        }
+                        _ => reply.push_str("E01"),
+                    }
+                }
+                b'm' => {
+                    match parse_range(args) {
+                        Some((addr, len)) if is_mapped(addr, len.min(MAXREAD)) => {
+                            for i in 0..len.min(MAXREAD) {
+                                put_hex(reply, &[peek(addr + i)]);
+                            }
+                        }
+                        _ => reply.push_str("E14"),
+                    }
+                }
+                b'M' => {
+                    let mut it = args.splitn(2, |c| *c == b':');
+                    match (it.next().and_then(parse_range), it.next()) {
+                        (Some((addr, len)), Some(data))
+                            if data.len() == 2 * len && is_mapped(addr, len) =>
+                        {
+                            for i in 0..len {
+                                let b = parse_hex(&data[2 * i..2 * i + 2]).unwrap_or(0);
+                                poke(addr + i, b as u8);
+                            }
+                            reply.push_str("OK");
+                        }
+                        _ => reply.push_str("E14"),
+                    }
+                }
+                // Software breakpoints.
+                b'Z' | b'z' if args.starts_with(b"0,") => {
+                    match parse_range(&args[2..]) {
+                        Some((addr, len)) if (len == 2 || len == 4) && is_mapped(addr, len) => {
+                            if cmd == b'Z' {
+                                self.breakpoints.add(addr, len);
+                            } else {
+                                self.breakpoints.delete(addr);
+                            }
+                            reply.push_str("OK");
+                        }
+                        _ => reply.push_str("E01"),
+                    }
+                }
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        regs[32] = addr;
+                    }
+                    // Skip an ebreak compiled into the code, or it would stop
+                    // there again.
+                    regs[32] += ebreak_len(regs[32]);
+                    if cmd == b's' {
+                        self.breakpoints.insert_step(regs);
+                    } else {
+                        self.breakpoints.insert();
+                    }
+                    self.running = true;
+                    return;
+                }
+                b'D' => {
+                    self.breakpoints.set = [None; MAXBREAK];
+                    reply.push_str("OK");
+                    send(&mut self.port, reply);
+                    return;
+                }
+                b'k' => return,
+                b'q' if packet.starts_with(b"qSupported") => {
+                    let _ = write!(reply, "PacketSize={:x}", BUFSIZE - 1);
+                }
+                b'q' if packet.starts_with(b"qAttached") => reply.push_str("1"),
+                b'H' => reply.push_str("OK"),
+                // Unsupported: an empty reply.
+                _ => {}
+            }
+            send(&mut self.port, reply);
+        }
+    }
+}
+
+/// Receives a packet into `buf`, and returns its length.
+fn recv(port: &mut Serial, buf: &mut [u8; BUFSIZE]) -> usize {
+    loop {
+        while port.getc() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            let c = port.getc();
+            if c == b'#' {
+                break;
+            }
+            if len < BUFSIZE {
+                buf[len] = c;
+                len += 1;
+            }
+            sum = sum.wrapping_add(c);
+        }
+        let check = [port.getc(), port.getc()];
+        if parse_hex(&check) == Some(sum as usize) && len < BUFSIZE {
+            port.putc(b'+');
+            return len;
+        }
+        port.putc(b'-');
+    }
+}
+
+/// Sends `reply` as a packet, until gdb acknowledges it.
+fn send(port: &mut Serial, reply: &str) {
+    loop {
+        port.putc(b'$');
+        let mut sum = 0u8;
+        for c in reply.bytes() {
+            port.putc(c);
+            sum = sum.wrapping_add(c);
+        }
+        port.putc(b'#');
+        port.putc(HEX[(sum >> 4) as usize]);
+        port.putc(HEX[(sum & 0xf) as usize]);
+        if port.getc() == b'+' {
+            return;
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// The length of the ebreak or c.ebreak at `addr`, or 0 if there is none.
+fn ebreak_len(addr: usize) -> usize {
+    if is_mapped(addr, 2) && [peek(addr), peek(addr + 1)] == C_EBREAK {
+        2
+    } else if is_mapped(addr, 4) && (0..4).all(|i| peek(addr + i) == EBREAK[i]) {
+        4
+    } else {
+        0
+    }
+}
+
+fn put_hex(reply: &mut ArrayString<[u8; BUFSIZE]>, bytes: &[u8]) {
+    for b in bytes {
+        let _ = reply.try_push(HEX[(b >> 4) as usize] as char);
+        let _ = reply.try_push(HEX[(b & 0xf) as usize] as char);
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0, |n, c| Some(n << 4 | (*c as char).to_digit(16)? as usize))
+}
+
+/// Parses a little-endian 64-bit value in hex, as in register packets.
+fn parse_le(s: &[u8]) -> Option<usize> {
+    if s.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = parse_hex(&s[2 * i..2 * i + 2])? as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Parses "addr,len".
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let mut it = s.splitn(2, |c| *c == b',');
+    Some((parse_hex(it.next()?)?, parse_hex(it.next()?)?))
+}
+
+/// Is `addr..addr + len` in RAM, or in a kernel stack?
+fn is_mapped(addr: usize, len: usize) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let memory = &platform().memory;
+    if memory.start <= addr && end <= memory.end {
+        return true;
+    }
+    (0..NPROC).any(|i| kstack(i) <= addr && end <= kstack(i) + PGSIZE)
+}
+
+fn peek(addr: usize) -> u8 {
+    // SAFETY: is_mapped(addr, 1).
+    unsafe { ptr::read_volatile(addr as *const u8) }
+}
+
+/// Writes `b` at `addr`, even in kernel code, which is mapped read-only.
+fn poke(addr: usize, b: u8) {
+    let memory = &platform().memory;
+    if !memory.contains(&addr) {
+        // SAFETY: a kernel stack, which is writable.
+        unsafe { ptr::write_volatile(addr as *mut u8, b) };
+        return;
+    }
+    // RAM is mapped at the same addresses, as is this code, so the stub turns
+    // paging off to write past the page permissions.
+    // SAFETY: interrupts are off, and the asm uses no memory but addr.
+    unsafe {
+        asm!(
+            "csrrw {satp}, satp, zero",
+            "sfence.vma zero, zero",
+            "sb {b}, 0({addr})",
+            "csrw satp, {satp}",
+            "sfence.vma zero, zero",
+            "fence.i",
+            satp = out(reg) _,
+            b = in(reg) b as usize,
+            addr = in(reg) addr,
+        )
+    };
+}
+
+/// Sign-extends the low `bits` bits of `x`.
+fn sext(x: usize, bits: u32) -> usize {
+    ((x << (64 - bits)) as isize >> (64 - bits)) as usize
+}
+
+/// The address of the instruction that runs after the one at pc, given
+/// the registers x0 to x31 and pc.
+fn next_pc(regs: &[usize; 33]) -> usize {
+    let pc = regs[32];
+    let reg = |n: usize| regs[n & 31];
+    let lo = peek(pc) as usize | (peek(pc + 1) as usize) << 8;
+    if lo & 3 != 3 {
+        // A compressed instruction.
+        let i = lo;
+        let rs1 = i >> 7 & 31;
+        let rs1c = 8 + (i >> 7 & 7);
+        let offset_j = sext(
+            (i >> 12 & 1) << 11
+                | (i >> 11 & 1) << 4
+                | (i >> 9 & 3) << 8
+                | (i >> 8 & 1) << 10
+                | (i >> 7 & 1) << 6
+                | (i >> 6 & 1) << 7
+                | (i >> 3 & 7) << 1
+                | (i >> 2 & 1) << 5,
+            12,
+        );
+        let offset_b = sext(
+            (i >> 12 & 1) << 8
+                | (i >> 10 & 3) << 3
+                | (i >> 5 & 3) << 6
+                | (i >> 3 & 3) << 1
+                | (i >> 2 & 1) << 5,
+            9,
+        );
+        return match (i & 3, i >> 13 & 7) {
+            // c.j
+            (1, 5) => pc.wrapping_add(offset_j),
+            // c.beqz
+            (1, 6) if reg(rs1c) == 0 => pc.wrapping_add(offset_b),
+            // c.bnez
+            (1, 7) if reg(rs1c) != 0 => pc.wrapping_add(offset_b),
+            // c.jr and c.jalr
+            (2, 4) if i >> 2 & 31 == 0 && rs1 != 0 => reg(rs1) & !1,
+            _ => pc + 2,
+        };
+    }
+
+    let i = lo | (peek(pc + 2) as usize) << 16 | (peek(pc + 3) as usize) << 24;
+    let (rs1, rs2) = (reg(i >> 15), reg(i >> 20));
+    match i & 0x7f {
+        // jal
+        0x6f => {
+            pc.wrapping_add(sext(
+                (i >> 31 & 1) << 20
+                    | (i >> 21 & 0x3ff) << 1
+                    | (i >> 20 & 1) << 11
+                    | (i >> 12 & 0xff) << 12,
+                21,
+            ))
+        }
+        // jalr
+        0x67 => rs1.wrapping_add(sext(i >> 20, 12)) & !1,
+        // Branches.
+        0x63 => {
+            let taken = match i >> 12 & 7 {
+                0 => rs1 == rs2,
+                1 => rs1 != rs2,
+                4 => (rs1 as isize) < rs2 as isize,
+                5 => (rs1 as isize) >= rs2 as isize,
+                6 => rs1 < rs2,
+                7 => rs1 >= rs2,
+                _ => false,
+            };
+            if taken {
+                pc.wrapping_add(sext(
+                    (i >> 31 & 1) << 12
+                        | (i >> 7 & 1) << 11
+                        | (i >> 25 & 0x3f) << 5
+                        | (i >> 8 & 0xf) << 1,
+                    13,
+                ))
+            } else {
+                pc + 4
+            }
+        }
+        _ => pc + 4,
+    }
+}
+
+/// Sets up the virtio serial device for gdb, if any, and waits for gdb to
+/// connect.
+/// Called once, by the first hart at boot, with the kernel trap vector installed.
+pub fn init() {
+    let device = platform().gdb_virtio;
+    if !GDBSTUB.with(|stub| stub.port.init(device.base)) {
+        klog!(Warn, "gdbstub: no virtio serial device");
+        return;
+    }
+    plic::register(device.irq, |_| intr());
+    klog!(Info, "gdbstub: waiting for gdb on the virtio serial device");
+    breakpoint();
+}
+
+/// Stops for gdb here.
+pub fn breakpoint() {
+    // SAFETY: kerneltrap() handles the breakpoint.
+    unsafe { asm!(".4byte 0x00100073") };
+}
+
+/// Handles a breakpoint in kernel code, which trapped at `sepc` with its
+/// registers saved in `frame`.
+/// Returns the address to resume at, or None if the stub is off.
+pub fn trap(frame: &mut KernelFrame, sepc: usize) -> Option<usize> {
+    GDBSTUB.with(|stub| {
+        if !stub.port.is_attached() {
+            return None;
+        }
+        STOPPED.store(true, Ordering::Release);
+        #[cfg(feature = "sbi")]
+        crate::sbi::send_ipi(((1 << crate::param::NCPU) - 1) & !(1 << crate::proc::cpuid()));
+
+        stub.breakpoints.remove();
+        let mut regs = frame.regs(sepc);
+        stub.serve(&mut regs);
+        frame.set_regs(&regs);
+
+        STOPPED.store(false, Ordering::Release);
+        Some(regs[32])
+    })
+}
+
+/// Handles an interrupt from gdb's serial device: ^C stops for gdb.
+pub fn intr() {
+    let interrupted = GDBSTUB.with(|stub| {
+        let mut interrupted = false;
+        stub.port.ack();
+        while let Some(c) = stub.port.poll() {
+            interrupted |= c == 0x03;
+        }
+        interrupted
+    });
+    if interrupted {
+        breakpoint();
+    }
+}
+
+/// Waits while another hart is stopped for gdb.
+pub fn wait() {
+    if !STOPPED.load(Ordering::Acquire) {
+        return;
+    }
+    while STOPPED.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    // gdb may have changed kernel code meanwhile.
+    // SAFETY: fence.i only orders instruction fetches.
+    unsafe { asm!("fence.i") };
+}
@@ -0,0 +1,69 @@
+//! Attaching the optional virtio disks: the second disk of the FAT32 volume
+//! and the two disks of the md device.
+//!
+//! The first hart scans their virtio mmio interfaces at boot, and rescan()
+//! scans those that were empty again, so that a disk that qemu plugs in
+//! later can be mounted without rebooting. A disk stays attached once it
+//! is: there is no unplugging.
+
+use crate::{
+    capability::Capabilities,
+    fs::Fat32,
+    kernel::Kernel,
+    md::Md,
+    platform::platform,
+    plic,
+    proc::CurrentProc,
+    virtio::Disk,
+    workqueue::{self, Work},
+};
+
+/// Attaches the optional disks that are plugged in and not attached yet,
+/// and handles their interrupts.
+/// Returns the number of devices that it attached.
+pub fn scan(fat32: &Fat32, md: &Md) -> usize {
+    let platform = platform();
+    let mut attached = 0;
+
+    // Second disk.
+    if fat32.attach(platform.virtio[2].base) {
+        static FAT32_DISK_WORK: Work =
+            Work::new(|kernel| Disk::complete(&mut kernel.fat32.disk.lock()));
+        plic::register(platform.virtio[2].irq, |_| {
+            Disk::ack(crate::platform::platform().virtio[2].base);
+            workqueue::queue(&FAT32_DISK_WORK);
+        });
+        attached += 1;
+    }
+
+    // The disks of the md device.
+    if md.attach([platform.virtio[3].base, platform.virtio[4].base]) {
+        static MD_DISK_WORK: [Work; 2] = [
+            Work::new(|kernel| Disk::complete(&mut kernel.md.disks[0].lock())),
+            Work::new(|kernel| Disk::complete(&mut kernel.md.disks[1].lock())),
+        ];
+        plic::register(platform.virtio[3].irq, |_| {
+            Disk::ack(crate::platform::platform().virtio[3].base);
+            workqueue::queue(&MD_DISK_WORK[0]);
+        });
+        plic::register(platform.virtio[4].irq, |_| {
+            Disk::ack(crate::platform::platform().virtio[4].base);
+            workqueue::queue(&MD_DISK_WORK[1]);
+        });
+        attached += 1;
+    }
+
+    attached
+}
+
+impl Kernel {
+    /// Attach the optional disks that were plugged in since boot or the last
+    /// rescan(). It needs CAP_SYS_ADMIN.
+    /// Returns Ok(the number of devices attached) on success, Err(()) on error.
+    pub fn sys_rescan(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        if !proc.is_privileged(Capabilities::SYS_ADMIN) {
+            return Err(());
+        }
+        Ok(scan(&self.fat32, &self.md))
+    }
+}
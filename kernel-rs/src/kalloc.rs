@@ -1,16 +1,35 @@
 //! Physical memory allocator, for user processes,
 //! kernel stacks, page-table pages,
 //! and pipe buffers. Allocates whole 4096-byte pages.
+//!
+//! Pages may be reserved, for the buffer cache and for user memory that maps
+//! the zero page until written. Reserved pages are free, but only
+//! `alloc_reserved` takes them, and the caches, whose pages the shrinkers
+//! give back.
+//!
+//! Freed pages are filled with a poison pattern. With the `kalloc-poison`
+//! feature, allocation checks that the pattern is intact, to catch writes
+//! through dangling references to freed pages.
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{mem, pin::Pin};
 
 use pin_project::pin_project;
 
+#[cfg(feature = "fault-inject")]
+use crate::fault::{self, Site};
+#[cfg(feature = "kalloc-poison")]
+use crate::vm::Addr;
 use crate::{
+    bio,
+    kernel::kernel_builder,
+    kstat::Counter,
     list::{List, ListEntry, ListNode},
     lock::Spinlock,
-    memlayout::PHYSTOP,
+    memlayout::phystop,
     page::Page,
+    platform::platform,
     riscv::{pgrounddown, pgroundup, PGSIZE},
+    some_or,
 };
 
 extern "C" {
@@ -19,6 +38,23 @@ extern "C" {
     pub static mut end: [u8; 0];
 }
 
+/// Fills freed pages, except their `Run`.
+const FREE_POISON: u8 = 0x6b;
+
+/// Fills allocated pages, to catch reads of uninitialized memory.
+const ALLOC_JUNK: u8 = 0xa5;
+
+/// Free pages below which allocation first has the caches give pages back.
+const LOW_WATERMARK: usize = 64;
+
+/// Free pages that the caches give pages back up to.
+const HIGH_WATERMARK: usize = 128;
+
+/// Caches that can give pages back. Each frees up to the given number of
+/// pages and returns the number it freed. It may not sleep, and must not be
+/// called while holding a lock that it acquires.
+const SHRINKERS: [fn(&Spinlock<Kmem>, usize) -> usize; 2] = [bio::shrink_pages, bio::shrink];
+
 #[repr(transparent)]
 #[pin_project]
 struct Run {
@@ -65,6 +101,15 @@ unsafe impl ListNode for Run {
 pub struct Kmem {
     #[pin]
     runs: List<Run>,
+
+    /// Number of pages in `runs`.
+    nfree: AtomicUsize,
+
+    /// Number of pages in `runs` that are reserved.
+    reserved: AtomicUsize,
+
+    /// Number of pages that `init` created.
+    npages: AtomicUsize,
 }
 
 impl Kmem {
@@ -74,10 +119,14 @@ impl Kmem {
     pub const unsafe fn new() -> Self {
         Self {
             runs: unsafe { List::new() },
+            nfree: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(0),
+            npages: AtomicUsize::new(0),
         }
     }
 
-    /// Create pages between `end` and `PHYSTOP`.
+    /// Create pages between `end` and `phystop()`, but for those of the
+    /// initial RAM disk.
     ///
     /// # Safety
     ///
@@ -88,13 +137,20 @@ impl Kmem {
 
         // SAFETY: safe to acquire only the address of a static variable.
         let pa_start = pgroundup(unsafe { end.as_ptr() as usize });
-        let pa_end = pgrounddown(PHYSTOP);
+        let pa_end = pgrounddown(phystop());
+        let initrd = platform().initrd.as_ref().map_or(0..0, |initrd| {
+            pgrounddown(initrd.start)..pgroundup(initrd.end)
+        });
         for pa in num_iter::range_step(pa_start, pa_end, PGSIZE) {
+            if initrd.contains(&pa) {
+                continue;
+            }
             // SAFETY:
             // * pa_start is a multiple of PGSIZE, and pa is so
-            // * end <= pa < PHYSTOP
+            // * end <= pa < phystop()
             // * the safety condition of this method guarantees that the
             //   created page does not overlap with existing pages
+            let _ = self.npages.fetch_add(1, Ordering::Relaxed);
             self.as_ref()
                 .get_ref()
                 .free(unsafe { Page::from_usize(pa) });
@@ -103,7 +159,7 @@ impl Kmem {
 
     pub fn free(&self, mut page: Page) {
         // Fill with junk to catch dangling refs.
-        page.write_bytes(1);
+        page.write_bytes(FREE_POISON);
 
         let run = page.as_uninit_mut();
         // SAFETY: `run` will be initialized by the following `init`.
@@ -111,27 +167,163 @@ impl Kmem {
         let mut run = unsafe { Pin::new_unchecked(run) };
         run.as_mut().init();
         self.runs.push_front(run.as_ref().get_ref());
+        let _ = self.nfree.fetch_add(1, Ordering::Relaxed);
 
         // Since the page has returned to the list, forget the page.
         mem::forget(page);
     }
 
     pub fn alloc(&self) -> Option<Page> {
-        let run = self.runs.pop_front()?;
+        #[cfg(feature = "fault-inject")]
+        if fault::should_fail(Site::Kalloc) {
+            return None;
+        }
+        if self.free_pages() == 0 {
+            // TODO: remove kernel_builder()
+            kernel_builder().kstat.inc(Counter::KallocFail);
+            return None;
+        }
+        self.alloc_nofault()
+    }
+
+    /// Like `alloc`, but faults are never injected, and it may take reserved
+    /// pages. Only the caches use it.
+    pub fn alloc_nofault(&self) -> Option<Page> {
+        let run = some_or!(self.runs.pop_front(), {
+            // TODO: remove kernel_builder()
+            kernel_builder().kstat.inc(Counter::KallocFail);
+            return None;
+        });
+        let _ = self.nfree.fetch_sub(1, Ordering::Relaxed);
         // SAFETY: the invariant of `Kmem`.
         let mut page = unsafe { Page::from_usize(run as _) };
+        #[cfg(feature = "kalloc-poison")]
+        if let Some(i) = page[mem::size_of::<Run>()..]
+            .iter()
+            .position(|&c| c != FREE_POISON)
+        {
+            panic!(
+                "kalloc: page {:018p} written at offset {} after free",
+                page.addr().into_usize() as *const u8,
+                mem::size_of::<Run>() + i
+            );
+        }
         // fill with junk
-        page.write_bytes(5);
+        page.write_bytes(ALLOC_JUNK);
+        Some(page)
+    }
+
+    /// Returns the number of free pages that are not reserved.
+    pub fn free_pages(&self) -> usize {
+        self.nfree
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.reserved.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of pages, free or not.
+    pub fn total_pages(&self) -> usize {
+        self.npages.load(Ordering::Relaxed)
+    }
+
+    /// Reserve n free pages.
+    /// Returns Ok(()) on success, Err(()) if there are not as many.
+    pub fn reserve(&self, n: usize) -> Result<(), ()> {
+        if self.free_pages() < n {
+            return Err(());
+        }
+        let _ = self.reserved.fetch_add(n, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Release n pages reserved by `reserve`.
+    pub fn unreserve(&self, n: usize) {
+        let reserved = self.reserved.fetch_sub(n, Ordering::Relaxed);
+        assert!(reserved >= n, "unreserve");
+    }
+
+    /// Free a page, and reserve it.
+    pub fn free_reserved(&self, page: Page) {
+        self.free(page);
+        let _ = self.reserved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a page reserved by `reserve`, filled with zeros. It fails only if
+    /// the caches took the reserved pages.
+    pub fn alloc_reserved(&self) -> Option<Page> {
+        let mut page = self.alloc_nofault()?;
+        self.unreserve(1);
+        page.write_bytes(0);
+        Some(page)
+    }
+
+    /// Like `alloc`, but the page is filled with zeros.
+    /// Pages that user space can read must come from here, so that they do
+    /// not leak what their previous owner left.
+    pub fn alloc_zeroed(&self) -> Option<Page> {
+        let mut page = self.alloc()?;
+        page.write_bytes(0);
         Some(page)
     }
 }
 
 impl Spinlock<Kmem> {
+    pub fn free_pages(&self) -> usize {
+        self.lock().free_pages()
+    }
+
+    pub fn total_pages(&self) -> usize {
+        self.lock().total_pages()
+    }
+
     pub fn free(&self, page: Page) {
         self.lock().free(page);
     }
 
     pub fn alloc(&self) -> Option<Page> {
+        self.shrink_caches();
         self.lock().alloc()
     }
+
+    pub fn alloc_nofault(&self) -> Option<Page> {
+        self.shrink_caches();
+        self.lock().alloc_nofault()
+    }
+
+    pub fn alloc_zeroed(&self) -> Option<Page> {
+        self.shrink_caches();
+        self.lock().alloc_zeroed()
+    }
+
+    pub fn reserve(&self, n: usize) -> Result<(), ()> {
+        self.lock().reserve(n)
+    }
+
+    pub fn unreserve(&self, n: usize) {
+        self.lock().unreserve(n);
+    }
+
+    pub fn free_reserved(&self, page: Page) {
+        self.lock().free_reserved(page);
+    }
+
+    pub fn alloc_reserved(&self) -> Option<Page> {
+        self.shrink_caches();
+        self.lock().alloc_reserved()
+    }
+
+    /// If free pages are below `LOW_WATERMARK`, has the caches give pages
+    /// back until they reach `HIGH_WATERMARK`.
+    fn shrink_caches(&self) {
+        let free = self.free_pages();
+        if free >= LOW_WATERMARK {
+            return;
+        }
+        let mut pages = HIGH_WATERMARK - free;
+        for shrink in SHRINKERS.iter() {
+            if pages == 0 {
+                break;
+            }
+            pages -= shrink(self, pages).min(pages);
+        }
+    }
 }
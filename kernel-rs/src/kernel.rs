@@ -1,4 +1,3 @@
-use core::cell::UnsafeCell;
 use core::fmt::{self, Write};
 use core::hint::spin_loop;
 use core::mem::MaybeUninit;
@@ -10,19 +9,42 @@ use array_macro::array;
 use pin_project::pin_project;
 
 use crate::{
-    bio::Bcache,
-    console::{consoleinit, Console, Printer},
-    file::{Devsw, FileTable},
-    fs::{FileSystem, Itable},
+    backtrace::backtrace,
+    bio::{self, Bcache, PageCache},
+    boot::Stage,
+    console::{consoleinit, Consoles, Printer},
+    fdtable::FdTables,
+    file::{DevswTable, FileTable},
+    fs::{Fat32, FileSystem, Itable},
+    hotplug,
     kalloc::Kmem,
+    klog,
+    klog::{Klog, Level, CONSOLE_LEVEL},
+    kstat::Kstat,
     lock::{Sleepablelock, Spinlock},
-    param::{NCPU, NDEV},
-    plic::{plicinit, plicinithart},
+    md::Md,
+    memlayout::{phystop, KERNBASE},
+    module::log_modules,
+    mq::MessageQueues,
+    page::RawPage,
+    param::NCPU,
+    percpu::PerCpu,
+    platform::platform,
+    plic::{self, plicinithart},
     println,
     proc::{cpuid, scheduler, Cpu, Procs, ProcsBuilder},
-    trap::{trapinit, trapinithart},
+    profile::Profile,
+    riscv::intr_off,
+    sem::Semaphores,
+    text::Texts,
+    time::Clock,
+    trap::trapinithart,
     uart::Uart,
+    vdso::VdsoTime,
+    virtio::{Disk, Keyboard},
     vm::KernelMemory,
+    watchdog,
+    workqueue::{self, Work},
 };
 
 /// The kernel.
@@ -65,8 +87,11 @@ unsafe fn kernel_builder_unchecked_pin() -> Pin<&'static mut KernelBuilder> {
 pub struct KernelBuilder {
     panicked: AtomicBool,
 
-    /// Sleeps waiting for there are some input in console buffer.
-    pub console: Sleepablelock<Console>,
+    /// The machine is shutting down, and harts stop at their next interrupt.
+    halted: AtomicBool,
+
+    /// The virtual consoles.
+    pub console: Consoles,
 
     /// TODO(https://github.com/kaist-cp/rv6/issues/298): Kernel owns uart temporarily.
     /// This might be changed after refactoring relationship between Console-Uart-Printer.
@@ -74,6 +99,18 @@ pub struct KernelBuilder {
 
     pub printer: Spinlock<Printer>,
 
+    /// The kernel log.
+    pub klog: Spinlock<Klog>,
+
+    /// Statistics counters.
+    pub kstat: Kstat,
+
+    /// Samples of the profiler.
+    pub profile: Profile,
+
+    /// Keyboard input, in addition to the uart.
+    pub keyboard: Spinlock<Keyboard>,
+
     #[pin]
     pub kmem: Spinlock<Kmem>,
 
@@ -82,25 +119,48 @@ pub struct KernelBuilder {
 
     pub ticks: Sleepablelock<u32>,
 
+    /// Wall-clock and monotonic time.
+    pub clock: Clock,
+
+    /// The time that user programs read without a system call.
+    pub vdso: VdsoTime,
+
+    /// The page of zeros that user memory maps until written.
+    pub zero_page: RawPage,
+
     /// Current process system.
     #[pin]
     pub procs: ProcsBuilder,
 
-    // The `Cpu` struct of the current cpu can be mutated. To do so, we need to
-    // obtain mutable pointers to the elements of `cpus` from a shared reference
-    // of a `Kernel`. It requires interior mutability, so we use `UnsafeCell`.
-    cpus: [UnsafeCell<Cpu>; NCPU],
+    cpus: PerCpu<Cpu>,
 
     #[pin]
     bcache: Bcache,
 
-    pub devsw: [Devsw; NDEV],
+    #[pin]
+    page_cache: PageCache,
+
+    pub devsw: DevswTable,
 
     pub ftable: FileTable,
 
+    pub fdtables: FdTables,
+
+    pub texts: Texts,
+
+    pub semaphores: Semaphores,
+
+    pub mqs: MessageQueues,
+
     pub itable: Itable,
 
     pub file_system: FileSystem,
+
+    /// The FAT32 volume on the second disk or the md device, if it is mounted.
+    pub fat32: Fat32,
+
+    /// The md device.
+    pub md: Md,
 }
 
 #[repr(transparent)]
@@ -130,23 +190,36 @@ impl KernelBuilder {
     const fn zero() -> Self {
         Self {
             panicked: AtomicBool::new(false),
-            console: Sleepablelock::new("CONS", Console::new()),
+            halted: AtomicBool::new(false),
+            console: Consoles::new(),
             uart: Uart::new(),
             printer: Spinlock::new("PRINTLN", Printer::new()),
+            klog: Spinlock::new("KLOG", Klog::new()),
+            kstat: Kstat::new(),
+            profile: Profile::new(),
+            keyboard: Spinlock::new("KEYBOARD", Keyboard::zero()),
             kmem: Spinlock::new("KMEM", unsafe { Kmem::new() }),
             memory: MaybeUninit::uninit(),
             ticks: Sleepablelock::new("time", 0),
+            clock: Clock::new(),
+            vdso: VdsoTime::new(),
+            zero_page: RawPage::DEFAULT,
             procs: ProcsBuilder::zero(),
-            cpus: array![_ => UnsafeCell::new(Cpu::new()); NCPU],
+            cpus: PerCpu::new(array![_ => Cpu::new(); NCPU]),
             // SAFETY: the only way to access `bcache` is through `kernel()`, which is an immutable reference.
             bcache: unsafe { Bcache::zero() },
-            devsw: [Devsw {
-                read: None,
-                write: None,
-            }; NDEV],
+            // SAFETY: the only way to access `page_cache` is through `kernel()`, which is an immutable reference.
+            page_cache: unsafe { PageCache::zero() },
+            devsw: DevswTable::new(),
             ftable: FileTable::zero(),
+            fdtables: FdTables::zero(),
+            texts: Texts::zero(),
+            semaphores: Semaphores::zero(),
+            mqs: MessageQueues::zero(),
             itable: Itable::zero(),
             file_system: FileSystem::zero(),
+            fat32: Fat32::zero(),
+            md: Md::zero(),
         }
     }
 
@@ -158,6 +231,22 @@ impl KernelBuilder {
         self.panicked.load(Ordering::Acquire)
     }
 
+    /// Stops the harts other than this one, before the machine shuts down.
+    /// They stop at their next interrupt. With SBI, an IPI interrupts them right away;
+    /// otherwise, supervisor mode cannot send IPIs and they stop at their next timer interrupt.
+    /// This hart runs with interrupts off from now on.
+    pub fn halt_others(&self) {
+        unsafe { intr_off() };
+        self.halted.store(true, Ordering::Release);
+
+        #[cfg(feature = "sbi")]
+        crate::sbi::send_ipi(((1 << NCPU) - 1) & !(1 << cpuid()));
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::Acquire)
+    }
+
     /// Prints the given formatted string with the Printer.
     pub fn printer_write_fmt(&self, args: fmt::Arguments<'_>) -> fmt::Result {
         if self.is_panicked() {
@@ -168,13 +257,24 @@ impl KernelBuilder {
         }
     }
 
+    /// Writes a message of the given level to the kernel log, and to the
+    /// console if the level is `CONSOLE_LEVEL` or above.
+    pub fn klog_write_fmt(&self, level: Level, args: fmt::Arguments<'_>) {
+        // The log may be what the panic is about.
+        if !self.is_panicked() {
+            let _ = writeln!(self.klog.lock(), "[{}] {}", level.tag(), args);
+        }
+        if level >= CONSOLE_LEVEL {
+            let _ = self.printer_write_fmt(format_args!("{}\n", args));
+        }
+    }
+
     /// Return this CPU's cpu struct.
     ///
     /// It is safe to call this function with interrupts enabled, but returned address may not be the
     /// current CPU since the scheduler can move the process to another CPU on time interrupt.
     pub fn current_cpu(&self) -> *mut Cpu {
-        let id: usize = cpuid();
-        self.cpus[id].get()
+        self.cpus.current_raw()
     }
 
     /// Returns an immutable reference to the kernel's bcache.
@@ -185,6 +285,15 @@ impl KernelBuilder {
     pub unsafe fn get_bcache(&self) -> &Bcache {
         &self.bcache
     }
+
+    /// Returns an immutable reference to the kernel's page cache.
+    ///
+    /// # Safety
+    ///
+    /// Access it only after initializing the kernel using `kernel_main()`.
+    pub unsafe fn get_page_cache(&self) -> &PageCache {
+        &self.page_cache
+    }
 }
 
 /// print! macro prints to the console using printer.
@@ -202,6 +311,16 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// klog! macro writes to the kernel log, at a level of `klog::Level`.
+/// e.g. `klog!(Warn, "cannot dump core pid={}", pid)`.
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::kernel::kernel_builder()
+            .klog_write_fmt($crate::klog::Level::$level, format_args!($($arg)*))
+    };
+}
+
 /// Handles panic.
 #[cfg(not(test))]
 #[panic_handler]
@@ -209,6 +328,14 @@ fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
     // Freeze other CPUs.
     kernel_builder().panic();
     println!("{}", info);
+    backtrace();
+
+    // Stop the other harts, which dump their state as they stop.
+    kernel_builder().halt_others();
+
+    // Halt the machine, if the SBI firmware can.
+    #[cfg(feature = "sbi")]
+    crate::sbi::shutdown(crate::sbi::ResetReason::SystemFailure);
 
     crate::utils::spin_loop()
 }
@@ -219,17 +346,30 @@ pub unsafe fn kernel_main() -> ! {
 
     if cpuid() == 0 {
         let mut kernel = unsafe { kernel_builder_unchecked_pin().project() };
+        let platform = platform();
 
-        // Initialize the kernel.
-
-        // Console.
+        let stage = Stage::EarlyConsole.begin();
         Uart::init();
+        plic::register(platform.uart.irq, |kernel| kernel.uart.intr());
         unsafe { consoleinit(kernel.devsw) };
 
         println!();
-        println!("rv6 kernel is booting");
+        klog!(Info, "rv6 kernel is booting");
         println!();
 
+        klog!(
+            Info,
+            "{} harts, {} MiB of memory",
+            platform.ncpu,
+            (phystop() - KERNBASE) / (1024 * 1024)
+        );
+        if !platform.cmdline.as_str().is_empty() {
+            klog!(Info, "command line: {}", platform.cmdline.as_str());
+        }
+        log_modules();
+        stage.end();
+
+        let stage = Stage::Memory.begin();
         // Physical page allocator.
         unsafe { kernel.kmem.as_mut().get_pin_mut().init() };
 
@@ -239,30 +379,73 @@ pub unsafe fn kernel_main() -> ! {
 
         // Turn on paging.
         unsafe { kernel.memory.write(memory).init_hart() };
+        stage.end();
+
+        let stage = Stage::PerCpu.begin();
+        // Time of day.
+        kernel.clock.init();
 
         // Process system.
         let procs = kernel.procs.init();
 
-        // Trap vectors.
-        trapinit();
-
         // Install kernel trap vector.
         unsafe { trapinithart() };
 
-        // Set up interrupt controller.
-        unsafe { plicinit() };
-
         // Ask PLIC for device interrupts.
         unsafe { plicinithart() };
+        stage.end();
+
+        let stage = Stage::Devices.begin();
+        // Emulated hard disk, or the initial RAM disk if there is one.
+        let disk = kernel.file_system.log.disk.get_mut();
+        if let Some(initrd) = platform.initrd.clone() {
+            klog!(
+                Info,
+                "root on the {} KiB initial RAM disk",
+                initrd.len() / 1024
+            );
+            disk.init_ram(initrd);
+        } else {
+            disk.init(platform.virtio[0].base);
+            static DISK_WORK: Work =
+                Work::new(|kernel| Disk::complete(&mut kernel.file_system.log.disk.lock()));
+            plic::register(platform.virtio[0].irq, |_| {
+                Disk::ack(crate::platform::platform().virtio[0].base);
+                workqueue::queue(&DISK_WORK);
+            });
+        }
+
+        // Second disk and the disks of the md device, if any.
+        let _ = hotplug::scan(&kernel.fat32, &kernel.md);
 
+        // Keyboard, if any.
+        kernel.keyboard.get_mut().init();
+        plic::register(platform.virtio[1].irq, |kernel| {
+            kernel.keyboard.lock().intr()
+        });
+        stage.end();
+
+        let stage = Stage::Fs.begin();
         // Buffer cache.
         kernel.bcache.get_pin_mut().init();
+        bio::reserve(kernel.kmem.as_ref().get_ref());
 
-        // Emulated hard disk.
-        kernel.file_system.log.disk.get_mut().init();
+        // Page cache.
+        kernel.page_cache.get_pin_mut().init();
+        stage.end();
 
-        // First user process.
+        let stage = Stage::Userspace.begin();
+        // First user process. It mounts the root file system when it
+        // starts running.
         procs.user_proc_init(kernel.kmem.as_ref().get_ref());
+        stage.end();
+
+        // Wait for gdb, if it debugs the kernel.
+        #[cfg(feature = "gdbstub")]
+        crate::gdbstub::init();
+
+        // Watch the harts for lockups from now on.
+        watchdog::init();
 
         STARTED.store(true, Ordering::Release);
     } else {
@@ -270,7 +453,7 @@ pub unsafe fn kernel_main() -> ! {
             spin_loop();
         }
 
-        println!("hart {} starting", cpuid());
+        klog!(Info, "hart {} starting", cpuid());
 
         // Turn on paging.
         unsafe { kernel().memory.assume_init_ref().init_hart() };
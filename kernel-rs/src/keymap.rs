@@ -0,0 +1,111 @@
+//! Translation of keyboard events into console input.
+//!
+//! Key codes are the ones of Linux input events, which virtio-input uses.
+//! The layout is a US keyboard.
+
+/// Characters of the keys with codes below `KEY_SPACE` + 1, without shift.
+const NORMAL: &[u8; 58] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+
+/// Characters of the keys with codes below `KEY_SPACE` + 1, with shift.
+const SHIFTED: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_F1: u16 = 59;
+const KEY_F10: u16 = 68;
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+
+/// What a key event means to the console.
+pub enum Key {
+    /// Nothing, e.g. for a modifier key or a key release.
+    None,
+    /// A character.
+    Char(u8),
+    /// An escape sequence, as a terminal sends it for the key.
+    Seq(&'static [u8]),
+    /// Alt-Fn: switch to the nth virtual console.
+    Switch(usize),
+}
+
+/// State of the modifier keys.
+pub struct Keymap {
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+    capslock: bool,
+}
+
+impl Keymap {
+    pub const fn new() -> Self {
+        Self {
+            shift: false,
+            ctrl: false,
+            alt: false,
+            capslock: false,
+        }
+    }
+
+    /// Translate the key `code` being released (`value` 0), pressed (1), or
+    /// repeated (2).
+    pub fn key(&mut self, code: u16, value: u32) -> Key {
+        let pressed = value != 0;
+        match code {
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => self.shift = pressed,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => self.ctrl = pressed,
+            KEY_LEFTALT | KEY_RIGHTALT => self.alt = pressed,
+            KEY_CAPSLOCK if value == 1 => self.capslock = !self.capslock,
+            _ if !pressed => (),
+            KEY_F1..=KEY_F10 if self.alt => return Key::Switch((code - KEY_F1) as usize),
+            KEY_KPENTER => return Key::Char(b'\r'),
+            KEY_UP => return Key::Seq(b"\x1b[A"),
+            KEY_DOWN => return Key::Seq(b"\x1b[B"),
+            KEY_RIGHT => return Key::Seq(b"\x1b[C"),
+            KEY_LEFT => return Key::Seq(b"\x1b[D"),
+            KEY_HOME => return Key::Seq(b"\x1b[H"),
+            KEY_END => return Key::Seq(b"\x1b[F"),
+            KEY_INSERT => return Key::Seq(b"\x1b[2~"),
+            KEY_DELETE => return Key::Seq(b"\x1b[3~"),
+            KEY_PAGEUP if self.shift => return Key::Seq(b"\x1b[5;2~"),
+            KEY_PAGEUP => return Key::Seq(b"\x1b[5~"),
+            KEY_PAGEDOWN if self.shift => return Key::Seq(b"\x1b[6;2~"),
+            KEY_PAGEDOWN => return Key::Seq(b"\x1b[6~"),
+            _ => return self.char(code),
+        }
+        Key::None
+    }
+
+    fn char(&self, code: u16) -> Key {
+        let c = match NORMAL.get(code as usize) {
+            Some(&c) if c.is_ascii_alphabetic() && self.capslock != self.shift => {
+                c.to_ascii_uppercase()
+            }
+            Some(&c) if c.is_ascii_alphabetic() => c,
+            Some(_) if self.shift => SHIFTED[code as usize],
+            Some(&c) => c,
+            None => 0,
+        };
+        match c {
+            0 => Key::None,
+            // Control-x
+            b'@'..=b'_' | b'a'..=b'z' if self.ctrl => Key::Char(c & 0x1f),
+            _ => Key::Char(c),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Kernel log.
+//!
+//! `klog!` writes kernel diagnostics, each with a level, to a ring buffer that
+//! dmesg(2) and /proc/kmsg read, so that boot and driver messages can be read
+//! after they scroll away. Messages at `CONSOLE_LEVEL` or above also go to the
+//! console.
+
+use core::{cmp, fmt};
+
+use crate::vm::{UserMemory, UserSlice};
+
+/// Size of the ring buffer. Older messages are overwritten.
+const KLOGSIZE: usize = 4096;
+
+/// Messages at this level or above are printed on the console.
+pub const CONSOLE_LEVEL: Level = Level::Info;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+pub struct Klog {
+    buf: [u8; KLOGSIZE],
+
+    /// Bytes written since boot. The last `min(len, KLOGSIZE)` are in `buf`.
+    len: usize,
+}
+
+impl Klog {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; KLOGSIZE],
+            len: 0,
+        }
+    }
+
+    /// Copies the most recent bytes of the log, at most `dst.len()`, to `dst`,
+    /// oldest first.
+    /// Returns Ok(the number of bytes copied) on success, Err(()) on error.
+    pub fn read(&self, dst: UserSlice, mem: &mut UserMemory) -> Result<usize, ()> {
+        let n = cmp::min(dst.len(), self.kept());
+        self.copy(dst, self.len - n, n, mem)
+    }
+
+    /// Copies the bytes of the log that are still in the ring buffer, from
+    /// the `off`th of them, to `dst`.
+    /// Returns Ok(the number of bytes copied) on success, Err(()) on error.
+    pub fn read_at(&self, dst: UserSlice, off: u32, mem: &mut UserMemory) -> Result<usize, ()> {
+        let off = cmp::min(off as usize, self.kept());
+        let n = cmp::min(dst.len(), self.kept() - off);
+        self.copy(dst, self.len - self.kept() + off, n, mem)
+    }
+
+    /// Bytes of the log that are still in the ring buffer.
+    fn kept(&self) -> usize {
+        cmp::min(self.len, KLOGSIZE)
+    }
+
+    /// Copies `n` bytes of the log, from the `from`th byte written since boot,
+    /// to `dst`.
+    fn copy(
+        &self,
+        dst: UserSlice,
+        from: usize,
+        n: usize,
+        mem: &mut UserMemory,
+    ) -> Result<usize, ()> {
+        let start = from % KLOGSIZE;
+        let first = cmp::min(n, KLOGSIZE - start);
+        dst.sub(0, first)
+            .write(&self.buf[start..start + first], mem)?;
+        dst.sub(first, n - first)
+            .write(&self.buf[..n - first], mem)?;
+        Ok(n)
+    }
+}
+
+impl fmt::Write for Klog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            self.buf[self.len % KLOGSIZE] = c;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Statistics counters of the kernel, exported through /proc/stat.
+//!
+//! Each hart counts events in its own row, so that counting does not bounce
+//! a shared cache line between harts. Reading /proc/stat sums the rows, and
+//! prints a line of "name count" for each counter.
+//!
+//! Minor 1 of the device is /proc/diskstats, the statistics of the block
+//! devices, minor 2 is /proc/self, the identity of the process that reads
+//! it, minor 3 is /proc/cmdline, the kernel command line, and minor 4 is
+//! /proc/kmsg, the kernel log.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use array_macro::array;
+use arrayvec::ArrayString;
+
+use crate::{
+    blockdev,
+    cmdline::cmdline,
+    file::{Devsw, DevswTable},
+    kernel::{kernel, kernel_builder},
+    param::NCPU,
+    proc::cpuid,
+    some_or,
+    trap::ScauseKind,
+    vm::UserSlice,
+};
+
+/// The major device number of /proc/stat.
+const STAT_DEVSW: usize = 3;
+
+/// Size of the text of /proc/stat, at most.
+const STATSIZE: usize = 512;
+
+/// An event that the kernel counts.
+#[derive(Copy, Clone)]
+pub enum Counter {
+    /// A block was found in the buffer cache.
+    BcacheHit,
+
+    /// A block was not in the buffer cache.
+    BcacheMiss,
+
+    /// A page of a file was found in the page cache.
+    PageCacheHit,
+
+    /// A page of a file was not in the page cache.
+    PageCacheMiss,
+
+    /// The log committed a transaction.
+    LogCommit,
+
+    /// The scheduler switched to a process.
+    ContextSwitch,
+
+    /// A process fetched an instruction from an invalid page.
+    InstrPageFault,
+
+    /// A process loaded from an invalid page.
+    LoadPageFault,
+
+    /// A process stored to an invalid page.
+    StorePageFault,
+
+    /// kalloc ran out of pages.
+    KallocFail,
+}
+
+const NCOUNTER: usize = 10;
+
+const COUNTERS: [Counter; NCOUNTER] = [
+    Counter::BcacheHit,
+    Counter::BcacheMiss,
+    Counter::PageCacheHit,
+    Counter::PageCacheMiss,
+    Counter::LogCommit,
+    Counter::ContextSwitch,
+    Counter::InstrPageFault,
+    Counter::LoadPageFault,
+    Counter::StorePageFault,
+    Counter::KallocFail,
+];
+
+impl Counter {
+    fn name(self) -> &'static str {
+        match self {
+            Counter::BcacheHit => "bcache_hit",
+            Counter::BcacheMiss => "bcache_miss",
+            Counter::PageCacheHit => "pagecache_hit",
+            Counter::PageCacheMiss => "pagecache_miss",
+            Counter::LogCommit => "log_commit",
+            Counter::ContextSwitch => "context_switch",
+            Counter::InstrPageFault => "instr_page_fault",
+            Counter::LoadPageFault => "load_page_fault",
+            Counter::StorePageFault => "store_page_fault",
+            Counter::KallocFail => "kalloc_fail",
+        }
+    }
+
+    /// The page fault of a trap with `cause`, if it is one.
+    pub fn page_fault(cause: ScauseKind) -> Option<Self> {
+        match cause {
+            ScauseKind::InstrPageFault => Some(Counter::InstrPageFault),
+            ScauseKind::LoadPageFault => Some(Counter::LoadPageFault),
+            ScauseKind::StorePageFault => Some(Counter::StorePageFault),
+            _ => None,
+        }
+    }
+}
+
+pub struct Kstat {
+    cpus: [[AtomicU64; NCOUNTER]; NCPU],
+}
+
+impl Kstat {
+    pub const fn new() -> Self {
+        Self {
+            cpus: array![_ => array![_ => AtomicU64::new(0); NCOUNTER]; NCPU],
+        }
+    }
+
+    /// Counts an event on this hart.
+    pub fn inc(&self, counter: Counter) {
+        // The hart may change meanwhile, but the rows are only summed.
+        let _ = self.cpus[cpuid()][counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The count of an event, on all harts.
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.cpus
+            .iter()
+            .map(|cpu| cpu[counter as usize].load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The text of /proc/stat.
+    fn text(&self) -> ArrayString<[u8; STATSIZE]> {
+        let mut text = ArrayString::new();
+        for counter in COUNTERS.iter() {
+            let _ = writeln!(text, "{} {}", counter.name(), self.get(*counter));
+        }
+        text
+    }
+}
+
+pub fn kstatinit(devsw: &DevswTable) {
+    static STAT: Devsw = Devsw {
+        read: Some(kstatread),
+        write: None,
+        ioctl: None,
+    };
+    devsw.register(STAT_DEVSW, &STAT);
+}
+
+/// The minor device number of /proc/diskstats.
+const DISKSTATS_MINOR: u16 = 1;
+
+/// The minor device number of /proc/self.
+const SELF_MINOR: u16 = 2;
+
+/// The minor device number of /proc/cmdline.
+const CMDLINE_MINOR: u16 = 3;
+
+/// The minor device number of /proc/kmsg.
+const KMSG_MINOR: u16 = 4;
+
+/// Size of the text of /proc/self, at most.
+const SELFSIZE: usize = 64;
+
+/// The text of /proc/self: lines of "pid", "ppid", "tid" and "name" of the
+/// current process.
+fn self_text() -> ArrayString<[u8; SELFSIZE]> {
+    let mut text = ArrayString::new();
+    // TODO: remove kernel()
+    let kernel = unsafe { kernel() };
+    if let Some(proc) = kernel.current_proc() {
+        let _ = writeln!(text, "pid {}", proc.pid());
+        let _ = writeln!(text, "ppid {}", kernel.procs().parent_pid(&proc));
+        let _ = writeln!(text, "tid {}", proc.pid());
+        let _ = writeln!(text, "name {}", proc.name());
+    }
+    text
+}
+
+/// User read()s from /proc/stat, /proc/diskstats, /proc/self, /proc/cmdline
+/// and /proc/kmsg go here.
+fn kstatread(minor: u16, dst: UserSlice, off: u32) -> i32 {
+    if minor == DISKSTATS_MINOR {
+        read_text(&blockdev::diskstats(), dst, off)
+    } else if minor == SELF_MINOR {
+        read_text(&self_text(), dst, off)
+    } else if minor == CMDLINE_MINOR {
+        read_text(cmdline().as_str(), dst, off)
+    } else if minor == KMSG_MINOR {
+        // TODO: remove kernel_builder()
+        let mut proc = some_or!(kernel_builder().current_proc(), return -1);
+        let klog = kernel_builder().klog.lock();
+        klog.read_at(dst, off, proc.memory_mut())
+            .map_or(-1, |n| n as i32)
+    } else {
+        // TODO: remove kernel_builder()
+        read_text(&kernel_builder().kstat.text(), dst, off)
+    }
+}
+
+/// Copies text from off to dst.
+fn read_text(text: &str, dst: UserSlice, off: u32) -> i32 {
+    let start = (off as usize).min(text.len());
+    let n = dst.len().min(text.len() - start);
+    let mut proc = some_or!(kernel_builder().current_proc(), return -1);
+    if dst
+        .sub(0, n)
+        .write(&text.as_bytes()[start..start + n], proc.memory_mut())
+        .is_err()
+    {
+        return -1;
+    }
+    n as i32
+}
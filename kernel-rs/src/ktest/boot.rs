@@ -0,0 +1,24 @@
+//! Tests of what the kernel takes at boot.
+
+use crate::{cmdline::Cmdline, kernel::Kernel, param::CMDLINE, proc::CurrentProc};
+
+/// The command line is split into keys and values.
+pub fn cmdline(_kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let cmdline = Cmdline::from_bytes(b"root=1  quiet aslr=off root=2 x=a=b\0junk");
+    if cmdline.as_str() != "root=1  quiet aslr=off root=2 x=a=b" {
+        return Err("not cut at the NUL");
+    }
+    if cmdline.get("root") != Some("2") || cmdline.get("x") != Some("a=b") {
+        return Err("wrong value");
+    }
+    if cmdline.get("quiet") != Some("") || cmdline.get("quie").is_some() {
+        return Err("wrong key without a value");
+    }
+    if !cmdline.is_off("aslr") || cmdline.is_off("quiet") {
+        return Err("wrong switch");
+    }
+    if Cmdline::from_bytes(&[b'a'; 1000]).as_str().len() != CMDLINE {
+        return Err("not cut at the end");
+    }
+    Ok(())
+}
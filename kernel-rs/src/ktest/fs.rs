@@ -0,0 +1,272 @@
+//! Tests of the file system.
+
+use crate::{
+    fs::{InodeGuard, InodeType, Path, Superblock, ROOTINO},
+    kernel::Kernel,
+    kstat::Counter,
+    param::{FSSIZE, MAXPATH, NBUF, NPAGECACHE, ROOTDEV},
+    proc::CurrentProc,
+    riscv::PGSIZE,
+};
+
+/// A block written to the log survives a crash right after the commit.
+/// The test writes the last block of the disk, which must be free.
+pub fn log_crash(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let log = &kernel.file_system.log;
+    let blockno = FSSIZE as u32 - 1;
+    if log
+        .disk
+        .read(ROOTDEV, blockno)
+        .map_err(|_| "no buffers")?
+        .deref_inner()
+        .data
+        .iter()
+        .any(|b| *b != 0)
+    {
+        return Err("the last block is in use");
+    }
+
+    let tx = kernel.file_system.begin_transaction();
+    let mut buf = log.disk.read(ROOTDEV, blockno).map_err(|_| "no buffers")?;
+    buf.deref_inner_mut().data[..8].copy_from_slice(b"ktestlog");
+    tx.write(buf);
+    tx.end_and_crash();
+
+    let recovered = &log
+        .disk
+        .read(ROOTDEV, blockno)
+        .map_err(|_| "no buffers")?
+        .deref_inner()
+        .data[..8]
+        == b"ktestlog";
+
+    // Free the block again.
+    let tx = kernel.file_system.begin_transaction();
+    let mut buf = log.disk.read(ROOTDEV, blockno).map_err(|_| "no buffers")?;
+    for b in buf.deref_inner_mut().data.iter_mut() {
+        *b = 0;
+    }
+    tx.write(buf);
+    drop(tx);
+
+    if !recovered {
+        return Err("the committed block was lost");
+    }
+    Ok(())
+}
+
+/// A file that lost its last link while open is freed when the file system
+/// is mounted, if the machine crashes before the file is closed.
+pub fn orphan_crash(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let fs = &kernel.file_system;
+    let tx = fs.begin_transaction();
+    let ptr = kernel
+        .itable
+        .alloc_inode(ROOTDEV, InodeType::File, 0, &tx)
+        .map_err(|_| "no free inodes")?;
+    let inum = ptr.inum;
+    let mut ip = ptr.lock().expect("orphan_crash: lock");
+    let _ = ip
+        .write_bytes_kernel(b"ktestorphan", 0, &tx)
+        .expect("orphan_crash: write");
+    // Unlinked, as by unlink() of an open file, which has the only link.
+    ip.deref_inner_mut().nlink = 0;
+    ip.update(&tx).expect("orphan_crash: update");
+    tx.add_orphan(ROOTDEV, inum);
+    // Forget the inode in memory, so that dropping it does not free it, as
+    // if the file were still open at the crash.
+    ip.deref_inner_mut().valid = false;
+    drop(ip);
+    drop(ptr);
+    tx.end_and_crash();
+
+    let before = fs.statfs();
+    fs.reclaim_orphans(ROOTDEV, &kernel.itable)
+        .map_err(|_| "the orphans were not reclaimed")?;
+    let after = fs.statfs();
+    let mut buf = fs.log.disk.read(ROOTDEV, 1).map_err(|_| "no buffers")?;
+    let listed = Superblock::orphans(&mut buf).contains(&inum);
+    drop(buf);
+
+    if after.ffree != before.ffree + 1 {
+        return Err("the orphaned inode was not freed");
+    }
+    if after.bfree != before.bfree + 1 {
+        return Err("the block of the orphaned inode was not freed");
+    }
+    if listed {
+        return Err("the inode was still on the orphan list");
+    }
+    Ok(())
+}
+
+/// Paths with repeated slashes, trailing slashes, and "." and ".." elements
+/// lead where they should, and too long ones lead nowhere. The test needs
+/// the file README in the root directory, which is the current directory.
+pub fn path_walk(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let mut long = [b'/'; MAXPATH];
+    long[MAXPATH - 1] = b'.';
+    let cases: [(&[u8], Option<u32>); 10] = [
+        (b"", Some(ROOTINO)),
+        (b"/", Some(ROOTINO)),
+        (b"//..//.//", Some(ROOTINO)),
+        (b"./././.", Some(ROOTINO)),
+        (b"/../../..", Some(ROOTINO)),
+        (b"README", Some(0)),
+        (b"///README", Some(0)),
+        (b"././.././README", Some(0)),
+        (b"README/", None),
+        (&long, None),
+    ];
+    // Dropping the inodes may write to the disk.
+    let _tx = kernel.file_system.begin_transaction();
+    // SAFETY: the paths below have no NUL characters.
+    let readme = kernel
+        .itable
+        .namei(unsafe { Path::from_bytes(b"/README") }, None, proc)
+        .map_err(|_| "no /README")?
+        .inum;
+    for (path, expected) in cases.iter() {
+        let found = kernel
+            .itable
+            .namei(unsafe { Path::from_bytes(path) }, None, proc)
+            .ok()
+            .map(|ip| ip.inum);
+        if found != expected.map(|inum| if inum == 0 { readme } else { inum }) {
+            return Err("a path led to the wrong place");
+        }
+    }
+
+    // A file is not a parent directory.
+    if kernel
+        .itable
+        .nameiparent(unsafe { Path::from_bytes(b"README/x") }, None, proc)
+        .is_ok()
+    {
+        return Err("a file was a parent directory");
+    }
+    Ok(())
+}
+
+/// A block written in a transaction stays pinned in the buffer cache until
+/// the transaction commits, even if many more blocks than the cache holds
+/// are read meanwhile. The test writes the last block of the disk, which
+/// must be free, without changing it.
+pub fn bcache_pin(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let log = &kernel.file_system.log;
+    let blockno = FSSIZE as u32 - 1;
+    // SAFETY: the reference is immutable.
+    let bcache = unsafe { kernel.get_bcache() };
+
+    let tx = kernel.file_system.begin_transaction();
+    let buf = log.disk.read(ROOTDEV, blockno).map_err(|_| "no buffers")?;
+    tx.write(buf);
+    // Go through the cache twice. Reading does not evict pinned buffers, or
+    // it panics.
+    for b in 0..2 * NBUF as u32 {
+        let _ = log.disk.read(ROOTDEV, b);
+    }
+    let misses = kernel.kstat.get(Counter::BcacheMiss);
+    let pinned = bcache
+        .get_buf(ROOTDEV, blockno)
+        .map_or(false, |buf| buf.is_pinned());
+    let kept = kernel.kstat.get(Counter::BcacheMiss) == misses;
+    drop(tx);
+    let unpinned = bcache
+        .get_buf(ROOTDEV, blockno)
+        .map_or(false, |buf| !buf.is_pinned());
+
+    if !pinned || !kept {
+        return Err("the written block was not kept in the cache");
+    }
+    if !unpinned {
+        return Err("the block was still pinned after commit");
+    }
+    Ok(())
+}
+
+/// Shrinking the buffer cache frees the pages of unused buffers but not of
+/// used ones, and the blocks read the same afterwards.
+pub fn bcache_shrink(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let log = &kernel.file_system.log;
+    // SAFETY: the reference is immutable.
+    let bcache = unsafe { kernel.get_bcache() };
+
+    let mut superblock = [0; 32];
+    let used = log.disk.read(ROOTDEV, 1).map_err(|_| "no buffers")?;
+    superblock.copy_from_slice(&used.deref_inner().data[..32]);
+    for b in 2..NBUF as u32 {
+        let _ = log.disk.read(ROOTDEV, b);
+    }
+    if bcache.shrink(&kernel.kmem, NBUF) == 0 {
+        return Err("no pages were freed");
+    }
+    if used.deref_inner().data[..32] != superblock {
+        return Err("a used buffer lost its data");
+    }
+    drop(used);
+    let _ = bcache.shrink(&kernel.kmem, NBUF);
+    if log
+        .disk
+        .read(ROOTDEV, 1)
+        .map_err(|_| "no buffers")?
+        .deref_inner()
+        .data[..32]
+        != superblock
+    {
+        return Err("a block read differently after shrinking");
+    }
+    Ok(())
+}
+
+/// Reading a file goes through the page cache, which writes keep up to date,
+/// and which gives its pages back without losing data. The test writes a new
+/// file, which it frees again.
+pub fn page_cache(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    // SAFETY: the reference is immutable.
+    let page_cache = unsafe { kernel.get_page_cache() };
+    let tx = kernel.file_system.begin_transaction();
+    let ptr = kernel
+        .itable
+        .alloc_inode(ROOTDEV, InodeType::File, 0, &tx)
+        .map_err(|_| "no free inodes")?;
+    let mut ip = ptr.lock().expect("page_cache: lock");
+    let off = PGSIZE as u32 + 10;
+    let _ = ip
+        .write_bytes_kernel(b"ktestpage", off, &tx)
+        .expect("page_cache: write");
+
+    let mut buf = [0; 9];
+    let read = |ip: &mut InodeGuard<'_>, buf: &mut [u8; 9]| {
+        ip.read_bytes_kernel(buf, off) == Ok(buf.len())
+    };
+    let filled = read(&mut ip, &mut buf) && &buf == b"ktestpage";
+    let hits = kernel.kstat.get(Counter::PageCacheHit);
+    let cached = read(&mut ip, &mut buf) && kernel.kstat.get(Counter::PageCacheHit) == hits + 1;
+    let _ = ip
+        .write_bytes_kernel(b"PAGE", off + 5, &tx)
+        .expect("page_cache: write");
+    let updated = read(&mut ip, &mut buf) && &buf == b"ktestPAGE";
+    let _ = page_cache.shrink(&kernel.kmem, NPAGECACHE);
+    let reread = read(&mut ip, &mut buf) && &buf == b"ktestPAGE";
+
+    ip.deref_inner_mut().nlink = 0;
+    ip.update(&tx).expect("page_cache: update");
+    drop(ip);
+    drop(ptr);
+    drop(tx);
+
+    if !filled {
+        return Err("a page read differently from what was written");
+    }
+    if !cached {
+        return Err("a page was not cached");
+    }
+    if !updated {
+        return Err("a write did not update a cached page");
+    }
+    if !reread {
+        return Err("a page read differently after shrinking");
+    }
+    Ok(())
+}
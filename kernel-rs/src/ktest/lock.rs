@@ -0,0 +1,99 @@
+//! Tests of the locks.
+
+use crate::{
+    kernel::Kernel,
+    lock::{Sleeplock, Spinlock},
+    proc::CurrentProc,
+    riscv::intr_get,
+};
+
+/// Ticks that sleep_wakeup() waits for.
+const NTICK: u32 = 3;
+
+/// A spinlock keeps interrupts off while held, and can be released and
+/// reacquired in the middle.
+pub fn spinlock(_kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let lock = Spinlock::new("KTEST", 0);
+    {
+        let mut guard = lock.lock();
+        if intr_get() {
+            return Err("interrupts on while holding a spinlock");
+        }
+        *guard += 1;
+        guard.reacquire_after(|| *lock.lock() += 1);
+        if *guard != 2 {
+            return Err("lost an update made while released");
+        }
+    }
+    if !intr_get() {
+        return Err("interrupts stay off after release");
+    }
+    Ok(())
+}
+
+/// A guard mapped to a part of the data holds the lock until it is dropped.
+pub fn lock_map(_kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let lock = Spinlock::new("KTEST", (0, 0));
+    {
+        let mut second = lock.lock().map(|pair| &mut pair.1);
+        if intr_get() {
+            return Err("interrupts on while holding a mapped guard");
+        }
+        *second += 1;
+    }
+    if !intr_get() {
+        return Err("interrupts stay off after dropping a mapped guard");
+    }
+    if *lock.lock() != (0, 1) {
+        return Err("lost an update through a mapped guard");
+    }
+    Ok(())
+}
+
+/// A sleeplock can be held across a sleep, with interrupts on.
+pub fn sleeplock(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let lock = Sleeplock::new("KTEST", 0);
+    let mut guard = lock.lock();
+    if !intr_get() {
+        return Err("interrupts off while holding a sleeplock");
+    }
+    *guard += 1;
+    let mut ticks = kernel.ticks.lock();
+    let start = *ticks;
+    while *ticks == start {
+        ticks.sleep();
+    }
+    Ok(())
+}
+
+/// Two sleeplocks can be held at once and released, which lockdep, if
+/// enabled, takes for no cycle.
+pub fn sleeplock_nest(_kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let first = Sleeplock::new("KTEST_FIRST", 0);
+    let second = Sleeplock::new("KTEST_SECOND", 0);
+    for _ in 0..2 {
+        let mut outer = first.lock();
+        let mut inner = second.lock();
+        *outer += 1;
+        *inner += 1;
+    }
+    if *first.lock() != 2 || *second.lock() != 2 {
+        return Err("lost an update while holding two sleeplocks");
+    }
+    Ok(())
+}
+
+/// Sleeping on the ticks returns after the timer wakes it up.
+pub fn sleep_wakeup(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let mut ticks = kernel.ticks.lock();
+    let start = *ticks;
+    let mut sleeps = 0;
+    while ticks.wrapping_sub(start) < NTICK {
+        ticks.sleep();
+        sleeps += 1;
+    }
+    if sleeps == 0 {
+        return Err("never slept");
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Tests of the page allocator and user memory.
+
+use crate::{
+    kalloc::Kmem,
+    kernel::Kernel,
+    lock::Spinlock,
+    page::Page,
+    platform::platform,
+    proc::CurrentProc,
+    riscv::{PteFlags, PGSIZE},
+    vm::{Addr, PAddr, UserMemory, UserSlice},
+};
+
+/// Allocates every free page, and checks that they are distinct pages of
+/// RAM. Then frees them, and checks that as many can be allocated again.
+pub fn kalloc_stress(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let first = exhaust(&kernel.kmem)?;
+    let second = exhaust(&kernel.kmem)?;
+    if first == 0 {
+        return Err("no free pages");
+    }
+    if first != second {
+        return Err("pages leaked");
+    }
+    Ok(())
+}
+
+/// Allocates pages until none is left, and then frees them.
+/// Returns Ok(the number of pages) if they were distinct pages of RAM.
+fn exhaust(kmem: &Spinlock<Kmem>) -> Result<usize, &'static str> {
+    let memory = &platform().memory;
+    let mut result = Ok(());
+
+    // Each page holds the address of the page allocated before it, and its
+    // own index.
+    let mut head = 0usize;
+    let mut n = 0usize;
+    while let Some(mut page) = kmem.alloc() {
+        let addr = page.addr().into_usize();
+        if addr % PGSIZE != 0 || !memory.contains(&addr) {
+            result = Err("a page is not in RAM");
+        }
+        page[..8].copy_from_slice(&head.to_le_bytes());
+        page[8..16].copy_from_slice(&n.to_le_bytes());
+        head = page.into_usize();
+        n += 1;
+    }
+
+    let mut i = n;
+    while head != 0 {
+        // SAFETY: head is the address of a page allocated above.
+        let page = unsafe { Page::from_usize(head) };
+        i -= 1;
+        // A page handed out twice holds the index of its last allocation.
+        if page[8..16] != i.to_le_bytes() {
+            result = Err("a page was handed out twice");
+        }
+        let mut prev = [0; 8];
+        prev.copy_from_slice(&page[..8]);
+        head = usize::from_le_bytes(prev);
+        kmem.free(page);
+    }
+    result.map(|()| n)
+}
+
+/// Reads and writes user memory through UserSlice, as the memory grows,
+/// shrinks, changes permissions, and is cloned.
+pub fn user_memory(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let allocator = &kernel.kmem;
+    let trap_frame = allocator.alloc().ok_or("out of memory")?;
+    let result = match UserMemory::new(trap_frame.addr(), None, allocator) {
+        Some(mut mem) => {
+            let result = check_user_memory(&mut mem, trap_frame.addr(), allocator);
+            mem.free(allocator);
+            result
+        }
+        None => Err("out of memory"),
+    };
+    allocator.free(trap_frame);
+    result
+}
+
+fn check_user_memory(
+    mem: &mut UserMemory,
+    trap_frame: PAddr,
+    allocator: &Spinlock<Kmem>,
+) -> Result<(), &'static str> {
+    let size = 3 * PGSIZE;
+    if mem.alloc(size, allocator) != Ok(size) {
+        return Err("alloc failed");
+    }
+
+    // Across a page boundary.
+    let slice = UserSlice::new(PGSIZE - 4, 8).map_err(|_| "bad UserSlice")?;
+    slice.write(b"abcdefgh", mem).map_err(|_| "write failed")?;
+    if read(slice, mem)? != *b"abcdefgh" {
+        return Err("read back differs");
+    }
+
+    // Past the end.
+    let past = UserSlice::new(size - 4, 8).map_err(|_| "bad UserSlice")?;
+    if past.write(b"abcdefgh", mem).is_ok() {
+        return Err("wrote past the end");
+    }
+
+    // An inaccessible page, and then a read-only one.
+    let second = UserSlice::new(PGSIZE + 8, 8).map_err(|_| "bad UserSlice")?;
+    mem.protect(PGSIZE.into(), PGSIZE, PteFlags::empty())
+        .map_err(|_| "protect failed")?;
+    if read(second, mem).is_ok() {
+        return Err("read an inaccessible page");
+    }
+    mem.protect(PGSIZE.into(), PGSIZE, PteFlags::R)
+        .map_err(|_| "protect failed")?;
+    if read(second, mem).is_err() || second.write(b"ABCDEFGH", mem).is_ok() {
+        return Err("a read-only page is not");
+    }
+    mem.protect(PGSIZE.into(), PGSIZE, PteFlags::R | PteFlags::W)
+        .map_err(|_| "protect failed")?;
+
+    // A clone has the same contents, but its own pages.
+    let mut clone = mem.clone(trap_frame, allocator).ok_or("clone failed")?;
+    let result = (|| {
+        if read(slice, &mut clone)? != *b"abcdefgh" {
+            return Err("clone differs");
+        }
+        slice
+            .write(b"ABCDEFGH", &mut clone)
+            .map_err(|_| "write failed")?;
+        if read(slice, mem)? != *b"abcdefgh" {
+            return Err("clone shares pages");
+        }
+        Ok(())
+    })();
+    clone.free(allocator);
+    result?;
+
+    // Shrinking.
+    let _ = mem.dealloc(PGSIZE, allocator);
+    if read(second, mem).is_ok() {
+        return Err("read past the end after dealloc");
+    }
+    Ok(())
+}
+
+/// Growing user memory maps the zero page, and a page gets physical memory
+/// of its own only when written.
+pub fn zero_page(kernel: &Kernel, _proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    let allocator = &kernel.kmem;
+    let trap_frame = allocator.alloc().ok_or("out of memory")?;
+    let result = match UserMemory::new(trap_frame.addr(), None, allocator) {
+        Some(mut mem) => {
+            let result = check_zero_page(&mut mem, allocator);
+            mem.free(allocator);
+            result
+        }
+        None => Err("out of memory"),
+    };
+    allocator.free(trap_frame);
+    result
+}
+
+fn check_zero_page(mem: &mut UserMemory, allocator: &Spinlock<Kmem>) -> Result<(), &'static str> {
+    let size = 16 * PGSIZE;
+    if mem.alloc(size, allocator) != Ok(size) {
+        return Err("alloc failed");
+    }
+    if mem.rss() != 0 {
+        return Err("growing took pages");
+    }
+
+    let slice = UserSlice::new(5 * PGSIZE + 8, 8).map_err(|_| "bad UserSlice")?;
+    if read(slice, mem)? != [0; 8] {
+        return Err("the zero page is not zero");
+    }
+    slice.write(b"abcdefgh", mem).map_err(|_| "write failed")?;
+    if mem.rss() != 1 || read(slice, mem)? != *b"abcdefgh" {
+        return Err("a written page did not get a page of its own");
+    }
+    let other = UserSlice::new(6 * PGSIZE + 8, 8).map_err(|_| "bad UserSlice")?;
+    if read(other, mem)? != [0; 8] {
+        return Err("writing changed the zero page");
+    }
+    Ok(())
+}
+
+fn read(slice: UserSlice, mem: &mut UserMemory) -> Result<[u8; 8], &'static str> {
+    let mut buf = [0; 8];
+    slice.read(&mut buf, mem).map_err(|_| "read failed")?;
+    Ok(buf)
+}
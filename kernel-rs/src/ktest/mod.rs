@@ -0,0 +1,156 @@
+//! In-kernel tests, with the `ktest` feature.
+//!
+//! Much of the kernel cannot run on the host, so `cargo test` does not reach
+//! it. Instead, with `make qemu KTEST=yes`, the first process runs the tests
+//! in `TESTS` after the file system is ready, instead of init. Each test
+//! prints "ok" or why it failed on the console. Then the machine powers off
+//! with the number of failed tests as the exit code of qemu.
+//!
+//! A test is a function that returns Err(why) if it fails. Tests may sleep,
+//! and may leave the machine as it was only if they pass. A test that needs
+//! concurrency forks workers with `run_workers`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    kernel::Kernel, lock::Spinlock, poweroff::machine_poweroff, println, proc::CurrentProc,
+};
+
+mod boot;
+mod fs;
+mod lock;
+mod mm;
+mod proc;
+mod torture;
+
+type TestFn = fn(&Kernel, &mut CurrentProc<'_>) -> Result<(), &'static str>;
+
+/// What the `index`th worker of a test does.
+type WorkerFn = fn(&Kernel, &CurrentProc<'_>, usize) -> Result<(), &'static str>;
+
+/// The tests, in the order they run.
+const TESTS: [(&str, TestFn); 20] = [
+    ("kalloc_stress", mm::kalloc_stress),
+    ("user_memory", mm::user_memory),
+    ("zero_page", mm::zero_page),
+    ("spinlock", lock::spinlock),
+    ("lock_map", lock::lock_map),
+    ("sleeplock", lock::sleeplock),
+    ("sleeplock_nest", lock::sleeplock_nest),
+    ("sleep_wakeup", lock::sleep_wakeup),
+    ("log_crash", fs::log_crash),
+    ("orphan_crash", fs::orphan_crash),
+    ("path_walk", fs::path_walk),
+    ("bcache_pin", fs::bcache_pin),
+    ("bcache_shrink", fs::bcache_shrink),
+    ("page_cache", fs::page_cache),
+    ("kthread", proc::kthread),
+    ("cmdline", boot::cmdline),
+    ("torture_spinlock", torture::spinlock),
+    ("torture_sleeplock", torture::sleeplock),
+    ("torture_wakeup", torture::wakeup),
+    ("torture_yield", torture::yield_storm),
+];
+
+/// Has the first process started the tests?
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The workers that run now.
+struct Workers {
+    f: Option<WorkerFn>,
+
+    /// The index of the next worker to start.
+    next: usize,
+
+    /// Why a worker failed first, if one did.
+    failure: Option<&'static str>,
+}
+
+static WORKERS: Spinlock<Workers> = Spinlock::new(
+    "KTEST_WORKERS",
+    Workers {
+        f: None,
+        next: 0,
+        failure: None,
+    },
+);
+
+/// Called by each new process in forkret(). The first process runs the
+/// tests, and the others are workers of a test.
+pub fn start(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> ! {
+    if STARTED.swap(true, Ordering::AcqRel) {
+        worker(kernel, proc)
+    } else {
+        run(kernel, proc)
+    }
+}
+
+/// Runs the tests, and powers off.
+fn run(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> ! {
+    println!("ktest: running {} tests", TESTS.len());
+    let mut failed = 0;
+    for (name, test) in TESTS.iter() {
+        match test(kernel, proc) {
+            Ok(()) => println!("ktest: {} ... ok", name),
+            Err(why) => {
+                println!("ktest: {} ... FAILED: {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+    println!("ktest: {} passed, {} failed", TESTS.len() - failed, failed);
+
+    kernel.file_system.log.freeze();
+    kernel.halt_others();
+    machine_poweroff(failed as u16);
+}
+
+/// Forks `n` workers that run `f` with their indices, and waits for all of
+/// them. Returns Err(why) if one of them failed.
+fn run_workers(
+    kernel: &Kernel,
+    proc: &mut CurrentProc<'_>,
+    n: usize,
+    f: WorkerFn,
+) -> Result<(), &'static str> {
+    {
+        let mut workers = WORKERS.lock();
+        workers.f = Some(f);
+        workers.next = 0;
+        workers.failure = None;
+    }
+    let mut forked = 0;
+    while forked < n && kernel.procs().fork(proc, &kernel.kmem).is_ok() {
+        forked += 1;
+    }
+    for _ in 0..forked {
+        let _ = kernel.procs().wait(None, proc);
+    }
+
+    let mut workers = WORKERS.lock();
+    workers.f = None;
+    if let Some(why) = workers.failure.take() {
+        return Err(why);
+    }
+    if forked < n {
+        return Err("fork failed");
+    }
+    Ok(())
+}
+
+/// Runs the next worker of the test, and exits.
+fn worker(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> ! {
+    let (f, index) = {
+        let mut workers = WORKERS.lock();
+        let index = workers.next;
+        workers.next += 1;
+        (workers.f.expect("ktest: worker without a test"), index)
+    };
+    if let Err(why) = f(kernel, proc, index) {
+        let mut workers = WORKERS.lock();
+        if workers.failure.is_none() {
+            workers.failure = Some(why);
+        }
+    }
+    kernel.procs().exit_current(0, proc)
+}
@@ -0,0 +1,43 @@
+//! Tests of processes.
+
+use crate::{kernel::Kernel, kthread, lock::Sleepablelock, proc::CurrentProc};
+
+/// Kernel threads that kthread() spawns.
+const NTHREAD: usize = 4;
+
+/// The sum of what the kernel threads added.
+static SUM: Sleepablelock<usize> = Sleepablelock::new("KTEST_KTHREAD", 0);
+
+/// Kernel threads run with their arguments, can sleep, and are reaped by
+/// init, which the test runner is.
+pub fn kthread(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    *SUM.lock() = 0;
+    let mut spawned = 0;
+    while spawned < NTHREAD && kthread::spawn(kernel, add, spawned + 1, "ktest").is_ok() {
+        spawned += 1;
+    }
+    for _ in 0..spawned {
+        if kernel.procs().wait(None, proc).is_err() {
+            return Err("a kernel thread was not reaped");
+        }
+    }
+    if spawned < NTHREAD {
+        return Err("spawn failed");
+    }
+    if *SUM.lock() != NTHREAD * (NTHREAD + 1) / 2 {
+        return Err("a kernel thread did not run");
+    }
+    Ok(())
+}
+
+/// Sleeps for a tick, and adds `arg` to `SUM`.
+fn add(kernel: &Kernel, _proc: &CurrentProc<'_>, arg: usize) {
+    {
+        let mut ticks = kernel.ticks.lock();
+        let start = *ticks;
+        while *ticks == start {
+            ticks.sleep();
+        }
+    }
+    *SUM.lock() += arg;
+}
@@ -0,0 +1,260 @@
+//! Torture tests of the locks and the scheduler.
+//!
+//! In each test, workers hammer a lock or a wait channel at once, with random
+//! delays and yields in between, so that they interleave differently on each
+//! run and on each hart. A lost wakeup shows up as no progress, so a watchdog
+//! worker then fails the test, and wakes the others up to exit.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::run_workers;
+use crate::{
+    kernel::Kernel,
+    lock::{Sleepablelock, Sleeplock, Spinlock},
+    proc::CurrentProc,
+    riscv::r_time,
+};
+
+/// Workers of a test, not counting a watchdog.
+const NWORKER: usize = 4;
+
+/// Rounds that each worker makes.
+const NITER: usize = 200;
+
+/// Spins of a delay, at most.
+const MAXDELAY: u64 = 1000;
+
+/// Ticks without progress after which the watchdog fails a test.
+const WATCHDOG: u32 = 50;
+
+/// Two counters that must be equal while unlocked.
+struct Pair {
+    a: usize,
+    b: usize,
+}
+
+/// Whose turn it is, in the wakeup test.
+struct Turn {
+    turn: usize,
+
+    /// Set by the watchdog, to make the workers give up.
+    abort: bool,
+}
+
+static SPIN: Spinlock<Pair> = Spinlock::new("TORTURE_SPIN", Pair { a: 0, b: 0 });
+
+static SLEEP: Sleeplock<Pair> = Sleeplock::new("TORTURE_SLEEP", Pair { a: 0, b: 0 });
+
+static TURN: Sleepablelock<Turn> = Sleepablelock::new(
+    "TORTURE_TURN",
+    Turn {
+        turn: 0,
+        abort: false,
+    },
+);
+
+static ROUNDS: AtomicUsize = AtomicUsize::new(0);
+
+/// A xorshift generator, seeded by the time and the worker.
+struct Rng(u64);
+
+impl Rng {
+    fn new(index: usize) -> Self {
+        Self((r_time() ^ (index as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    /// A random number in 0..n.
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// Spins for a random while.
+    fn delay(&mut self) {
+        for _ in 0..self.below(MAXDELAY) {
+            spin_loop();
+        }
+    }
+
+    /// Gives up the CPU once in `n` times. No spinlock may be held.
+    fn maybe_yield(&mut self, proc: &CurrentProc<'_>, n: u64) {
+        if self.below(n) == 0 {
+            // SAFETY: no spinlock is held.
+            unsafe { proc.proc_yield() };
+        }
+    }
+}
+
+/// Updates of a pair under a spinlock are atomic, with all harts at it.
+pub fn spinlock(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    {
+        let mut pair = SPIN.lock();
+        pair.a = 0;
+        pair.b = 0;
+    }
+    run_workers(kernel, proc, NWORKER, spinlock_worker)?;
+    let pair = SPIN.lock();
+    if pair.a != NWORKER * NITER || pair.b != NWORKER * NITER {
+        return Err("lost an update");
+    }
+    Ok(())
+}
+
+fn spinlock_worker(
+    _kernel: &Kernel,
+    proc: &CurrentProc<'_>,
+    index: usize,
+) -> Result<(), &'static str> {
+    let mut rng = Rng::new(index);
+    for _ in 0..NITER {
+        rng.delay();
+        {
+            let mut pair = SPIN.lock();
+            let a = pair.a;
+            rng.delay();
+            pair.a = a + 1;
+            rng.delay();
+            pair.b += 1;
+            if pair.a != pair.b {
+                return Err("two holders of a spinlock");
+            }
+        }
+        rng.maybe_yield(proc, 4);
+    }
+    Ok(())
+}
+
+/// Updates of a pair under a sleeplock are atomic, even if holders yield in
+/// the middle.
+pub fn sleeplock(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    {
+        let mut pair = SLEEP.lock();
+        pair.a = 0;
+        pair.b = 0;
+    }
+    run_workers(kernel, proc, NWORKER, sleeplock_worker)?;
+    let pair = SLEEP.lock();
+    if pair.a != NWORKER * NITER || pair.b != NWORKER * NITER {
+        return Err("lost an update");
+    }
+    Ok(())
+}
+
+fn sleeplock_worker(
+    _kernel: &Kernel,
+    proc: &CurrentProc<'_>,
+    index: usize,
+) -> Result<(), &'static str> {
+    let mut rng = Rng::new(index);
+    for _ in 0..NITER {
+        rng.delay();
+        {
+            let mut pair = SLEEP.lock();
+            let a = pair.a;
+            rng.maybe_yield(proc, 4);
+            pair.a = a + 1;
+            rng.delay();
+            pair.b += 1;
+            if pair.a != pair.b {
+                return Err("two holders of a sleeplock");
+            }
+        }
+        rng.maybe_yield(proc, 4);
+    }
+    Ok(())
+}
+
+/// Workers pass a turn around, each sleeping until it is theirs. A lost
+/// wakeup stops the turn, and the watchdog notices.
+pub fn wakeup(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    {
+        let mut turn = TURN.lock();
+        turn.turn = 0;
+        turn.abort = false;
+    }
+    run_workers(kernel, proc, NWORKER + 1, wakeup_worker)
+}
+
+fn wakeup_worker(
+    kernel: &Kernel,
+    proc: &CurrentProc<'_>,
+    index: usize,
+) -> Result<(), &'static str> {
+    if index == NWORKER {
+        return watchdog(kernel);
+    }
+    let mut rng = Rng::new(index);
+    for _ in 0..NITER {
+        {
+            let mut turn = TURN.lock();
+            while !turn.abort && turn.turn % NWORKER != index {
+                turn.sleep();
+            }
+            if turn.abort {
+                return Ok(());
+            }
+            turn.turn += 1;
+            turn.wakeup_all();
+        }
+        rng.delay();
+        rng.maybe_yield(proc, 2);
+    }
+    Ok(())
+}
+
+/// Fails if the turn does not move for `WATCHDOG` ticks.
+fn watchdog(kernel: &Kernel) -> Result<(), &'static str> {
+    loop {
+        let last = TURN.lock().turn;
+        if last == NWORKER * NITER {
+            return Ok(());
+        }
+        {
+            let mut ticks = kernel.ticks.lock();
+            let start = *ticks;
+            while ticks.wrapping_sub(start) < WATCHDOG {
+                ticks.sleep();
+            }
+        }
+        let mut turn = TURN.lock();
+        if turn.turn == last {
+            turn.abort = true;
+            turn.wakeup_all();
+            return Err("no progress, a wakeup was lost");
+        }
+    }
+}
+
+/// Many processes yield and sleep on the ticks at random, and all of them
+/// get to run to the end.
+pub fn yield_storm(kernel: &Kernel, proc: &mut CurrentProc<'_>) -> Result<(), &'static str> {
+    ROUNDS.store(0, Ordering::Relaxed);
+    run_workers(kernel, proc, 2 * NWORKER, yield_worker)?;
+    if ROUNDS.load(Ordering::Relaxed) != 2 * NWORKER * NITER {
+        return Err("a worker lost rounds");
+    }
+    Ok(())
+}
+
+// The signature is that of a `WorkerFn`.
+#[allow(clippy::unnecessary_wraps)]
+fn yield_worker(kernel: &Kernel, proc: &CurrentProc<'_>, index: usize) -> Result<(), &'static str> {
+    let mut rng = Rng::new(index);
+    for _ in 0..NITER {
+        if rng.below(16) == 0 {
+            let mut ticks = kernel.ticks.lock();
+            let start = *ticks;
+            while *ticks == start {
+                ticks.sleep();
+            }
+        } else {
+            rng.maybe_yield(proc, 2);
+        }
+        rng.delay();
+        let _ = ROUNDS.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
@@ -0,0 +1,21 @@
+//! Kernel threads, processes that run only in the kernel.
+//!
+//! A kernel thread has no user memory or trap frame, and never returns to
+//! user space. Otherwise it is a process like others: the scheduler runs it,
+//! and it can sleep on wait channels and yield. It exits when its function
+//! returns, and init reaps it like an orphan. It cannot be killed or traced.
+//!
+//! The file system is ready only once the first process has started, so a
+//! kernel thread spawned during boot must not use it before then.
+
+use crate::{kernel::Kernel, proc::CurrentProc};
+
+/// What a kernel thread runs, with the argument given to `spawn`.
+pub type KthreadFn = fn(&Kernel, &CurrentProc<'_>, usize);
+
+/// Starts a kernel thread named `name` that runs `f(kernel, proc, arg)`.
+/// Must be called after the first process is set up.
+/// Returns Ok(its pid) on success, Err(()) if no process is free.
+pub fn spawn(kernel: &Kernel, f: KthreadFn, arg: usize, name: &str) -> Result<i32, ()> {
+    kernel.procs().spawn_kthread(f, arg, name)
+}
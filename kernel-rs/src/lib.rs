@@ -58,34 +58,85 @@
 #![feature(ptr_as_uninit)]
 
 mod arena;
+mod backtrace;
 mod bio;
+mod blockdev;
+mod boot;
+mod capability;
+mod cmdline;
 mod console;
+mod coredump;
 mod etrace;
+mod eventfd;
 mod exec;
+#[cfg(feature = "fault-inject")]
+mod fault;
 mod fcntl;
+mod fdt;
+mod fdtable;
 mod file;
+mod flock;
+mod fpu;
 mod fs;
+#[cfg(feature = "gdbstub")]
+mod gdbstub;
+mod hotplug;
 mod kalloc;
 mod kernel;
+mod keymap;
+mod klog;
+mod kstat;
+#[cfg(feature = "ktest")]
+mod ktest;
+mod kthread;
 mod list;
 mod lock;
+mod md;
 mod memlayout;
+mod mman;
+mod module;
+mod mount;
+mod mq;
+mod oom;
 mod page;
 mod param;
+mod percpu;
 mod pinned_array;
 mod pipe;
+mod platform;
 mod plic;
 mod poweroff;
+mod prctl;
 mod proc;
+mod profile;
+mod ptrace;
+mod ramdisk;
 mod rc_cell;
+mod rcu;
+mod ring;
 mod riscv;
+mod rtc;
+#[cfg(feature = "sbi")]
+mod sbi;
+mod seccomp;
+mod sem;
 mod start;
 mod stat;
+mod suspend;
+mod syncd;
 mod syscall;
 mod sysfile;
+mod sysinfo;
 mod sysproc;
+mod termios;
+mod text;
+mod time;
 mod trap;
 mod uart;
 mod utils;
+mod vdso;
 mod virtio;
 mod vm;
+mod vt;
+mod watchdog;
+mod workqueue;
@@ -0,0 +1,244 @@
+//! Lock dependency checker (lockdep), with the `lockdep` feature.
+//!
+//! Locks are grouped into classes by their names. Whenever a lock is acquired
+//! while others are held, an edge from each held class to the acquired class
+//! is recorded, with the call site. If a new edge closes a cycle, the locks
+//! can deadlock, even if they did not this time, and lockdep panics with the
+//! call sites of the edges in the cycle.
+//!
+//! Spinlocks are held by a CPU, so each `Cpu` records the spinlocks it holds.
+//! Sleeplocks are held by a process across sleeps, so each process records
+//! the sleeplocks it holds.
+//! Nesting locks of the same class, e.g., a directory's inode and its child's
+//! inode, is not checked.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{kernel::kernel_builder, println};
+
+/// Maximum number of lock classes.
+const MAXCLASS: usize = 64;
+
+/// Maximum number of locks that a CPU or a process holds at a time.
+const MAXHELD: usize = 32;
+
+#[derive(Copy, Clone)]
+struct HeldLock {
+    /// Address of the raw lock.
+    addr: usize,
+    class: usize,
+    site: &'static Location<'static>,
+}
+
+/// Locks that a CPU or a process holds.
+#[derive(Copy, Clone)]
+pub struct HeldLocks {
+    locks: [Option<HeldLock>; MAXHELD],
+}
+
+/// The lock classes, and the edges between them.
+struct Graph {
+    names: [&'static str; MAXCLASS],
+    nclass: usize,
+
+    /// `deps[a]` has bit `b` if `b` has been acquired while holding `a`.
+    deps: [u64; MAXCLASS],
+
+    /// `sites[a][b]` is where `b` was first acquired while holding `a`.
+    sites: [[Option<&'static Location<'static>>; MAXCLASS]; MAXCLASS],
+}
+
+struct Lockdep {
+    /// Spins, as interrupts are already off whenever lockdep runs.
+    locked: AtomicBool,
+    graph: UnsafeCell<Graph>,
+}
+
+// SAFETY: `graph` is accessed only while holding `locked`.
+unsafe impl Sync for Lockdep {}
+
+static LOCKDEP: Lockdep = Lockdep {
+    locked: AtomicBool::new(false),
+    graph: UnsafeCell::new(Graph {
+        names: [""; MAXCLASS],
+        nclass: 0,
+        deps: [0; MAXCLASS],
+        sites: [[None; MAXCLASS]; MAXCLASS],
+    }),
+};
+
+/// Set once a cycle is found, so that printing it does not recurse.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            locks: [None; MAXHELD],
+        }
+    }
+
+    fn push(&mut self, lock: HeldLock) {
+        let slot = self
+            .locks
+            .iter_mut()
+            .find(|l| l.is_none())
+            .expect("lockdep: too many locks held");
+        *slot = Some(lock);
+    }
+
+    fn remove(&mut self, addr: usize) {
+        // A lock may be released in another order than it was acquired.
+        if let Some(slot) = self
+            .locks
+            .iter_mut()
+            .find(|l| l.map_or(false, |l| l.addr == addr))
+        {
+            *slot = None;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &HeldLock> {
+        self.locks.iter().filter_map(|l| l.as_ref())
+    }
+}
+
+impl Graph {
+    /// Returns the class of locks named `name`.
+    fn class(&mut self, name: &'static str) -> usize {
+        if let Some(class) = self.names[..self.nclass].iter().position(|n| *n == name) {
+            return class;
+        }
+        assert!(self.nclass < MAXCLASS, "lockdep: too many lock classes");
+        self.names[self.nclass] = name;
+        self.nclass += 1;
+        self.nclass - 1
+    }
+
+    /// Returns a path from `from` to `to` through recorded edges, as
+    /// `parent[c]`, the class before `c`, if there is one.
+    // Classes are u8 to keep it small on the kernel stack.
+    fn path(&self, from: usize, to: usize) -> Option<[u8; MAXCLASS]> {
+        let mut parent = [MAXCLASS as u8; MAXCLASS];
+        let mut queue = [0u8; MAXCLASS];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from as u8;
+        parent[from] = from as u8;
+        while head < tail {
+            let a = queue[head] as usize;
+            head += 1;
+            for b in 0..self.nclass {
+                if self.deps[a] & (1 << b) != 0 && parent[b] == MAXCLASS as u8 {
+                    parent[b] = a as u8;
+                    if b == to {
+                        return Some(parent);
+                    }
+                    queue[tail] = b as u8;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Lockdep {
+    /// Calls `f` with the graph locked.
+    fn with<R, F: FnOnce(&mut Graph) -> R>(&self, f: F) -> R {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // SAFETY: we hold `locked`.
+        let result = f(unsafe { &mut *self.graph.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+fn is_enabled() -> bool {
+    !DISABLED.load(Ordering::Relaxed) && !kernel_builder().is_panicked()
+}
+
+/// Records that the lock at `addr` named `name` has been acquired at `site`,
+/// by this CPU if `sleep` is false, or by the current process if it is true.
+/// Interrupts must be off.
+pub fn acquire(addr: usize, name: &'static str, site: &'static Location<'static>, sleep: bool) {
+    if !is_enabled() {
+        return;
+    }
+
+    let cpu = kernel_builder().current_cpu();
+    let mut proc = kernel_builder().current_proc();
+
+    // SAFETY: interrupts are off, so nothing else uses this CPU's locks.
+    let cpu_held = unsafe { &(*cpu).held_locks };
+    let proc_held = proc.as_ref().map(|p| &p.deref_data().held_locks);
+    let class = LOCKDEP.with(|graph| {
+        let class = graph.class(name);
+        for held in cpu_held
+            .iter()
+            .chain(proc_held.into_iter().flat_map(|h| h.iter()))
+        {
+            if held.class == class || graph.deps[held.class] & (1 << class) != 0 {
+                continue;
+            }
+            if let Some(parent) = graph.path(class, held.class) {
+                // We never unlock the graph; printing does not use it once disabled.
+                DISABLED.store(true, Ordering::Relaxed);
+                println!("lockdep: possible deadlock; earlier, with the locks held in turn:");
+                let mut b = held.class;
+                while b != class {
+                    let a = parent[b] as usize;
+                    println!(
+                        "    {} then {} at {}",
+                        graph.names[a],
+                        graph.names[b],
+                        graph.sites[a][b].expect("lockdep")
+                    );
+                    b = a;
+                }
+                panic!(
+                    "lockdep: acquiring {} at {} while holding {} acquired at {}",
+                    name, site, graph.names[held.class], held.site
+                );
+            }
+            graph.deps[held.class] |= 1 << class;
+            graph.sites[held.class][class] = Some(site);
+        }
+        class
+    });
+
+    let lock = HeldLock { addr, class, site };
+    if sleep {
+        if let Some(proc) = proc.as_mut() {
+            proc.deref_mut_data().held_locks.push(lock);
+        }
+    } else {
+        // SAFETY: the same as above.
+        unsafe { (*cpu).held_locks.push(lock) };
+    }
+}
+
+/// Records that the lock at `addr` has been released.
+/// Interrupts must be off.
+pub fn release(addr: usize, sleep: bool) {
+    if !is_enabled() {
+        return;
+    }
+
+    if sleep {
+        if let Some(mut proc) = kernel_builder().current_proc() {
+            proc.deref_mut_data().held_locks.remove(addr);
+        }
+    } else {
+        let cpu = kernel_builder().current_cpu();
+        // SAFETY: interrupts are off, so nothing else uses this CPU's locks.
+        unsafe { (*cpu).held_locks.remove(addr) };
+    }
+}
@@ -32,15 +32,20 @@
 
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
+use core::mem;
 use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
 
 mod lock_protected;
+#[cfg(feature = "lockdep")]
+mod lockdep;
 mod sleepablelock;
 mod sleeplock;
 mod spinlock;
 
 pub use lock_protected::{RemoteSleepablelock, RemoteSleeplock, RemoteSpinlock};
+#[cfg(feature = "lockdep")]
+pub use lockdep::HeldLocks;
 pub use sleepablelock::{Sleepablelock, SleepablelockGuard};
 pub use sleeplock::{Sleeplock, SleeplockGuard};
 pub use spinlock::{pop_off, push_off, Spinlock, SpinlockGuard};
@@ -71,8 +76,17 @@ pub struct Guard<'s, R: RawLock, T> {
 // Do not implement Send; lock must be unlocked by the CPU that acquired it.
 unsafe impl<'s, R: RawLock, T: Sync> Sync for Guard<'s, R, T> {}
 
+/// Guards of a part of a lock's inner data, made by [`Guard::map`].
+/// Dropping one releases the lock.
+pub struct MappedGuard<'s, R: RawLock, U> {
+    lock: &'s R,
+    data: *mut U,
+    _marker: PhantomData<&'s mut U>,
+}
+
 impl<R: RawLock, T> Lock<R, T> {
     /// Acquires the lock and returns the lock guard.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn lock(&self) -> Guard<'_, R, T> {
         self.lock.acquire();
 
@@ -123,9 +137,28 @@ impl<R: RawLock, T> Lock<R, T> {
     }
 }
 
-impl<R: RawLock, T> Guard<'_, R, T> {
+impl<'s, R: RawLock, T> Guard<'s, R, T> {
+    /// Makes a guard of the part of the inner data that `f` returns, which
+    /// holds the lock until it is dropped.
+    pub fn map<U, F>(mut self, f: F) -> MappedGuard<'s, R, U>
+    where
+        T: Unpin,
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data = f(&mut *self) as *mut U;
+        let lock = &self.lock.lock;
+        // The `MappedGuard` releases the lock instead.
+        mem::forget(self);
+        MappedGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
     /// Temporarily releases the lock and calls function `f`.
     /// After `f` returns, reacquires the lock and returns the result of the function call.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn reacquire_after<F, U>(&mut self, f: F) -> U
     where
         F: FnOnce() -> U,
@@ -164,3 +197,25 @@ impl<R: RawLock, T: Unpin> DerefMut for Guard<'_, R, T> {
         self.get_pin_mut().get_mut()
     }
 }
+
+impl<R: RawLock, U> Drop for MappedGuard<'_, R, U> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+impl<R: RawLock, U> Deref for MappedGuard<'_, R, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: `data` points into the data of the lock, which we hold.
+        unsafe { &*self.data }
+    }
+}
+
+impl<R: RawLock, U> DerefMut for MappedGuard<'_, R, U> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: `data` points into the data of the lock, which we hold.
+        unsafe { &mut *self.data }
+    }
+}
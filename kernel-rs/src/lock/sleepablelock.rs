@@ -13,7 +13,7 @@ pub struct RawSleepablelock {
 
 /// Similar to `Spinlock`, but guards of this lock can sleep.
 pub type Sleepablelock<T> = Lock<RawSleepablelock, T>;
-/// Guards of `Sleepablelock<T>`. These guards can `sleep()`/`wakeup()`.
+/// Guards of `Sleepablelock<T>`. These guards can `sleep()`/`wakeup_all()`.
 pub type SleepablelockGuard<'s, T> = Guard<'s, RawSleepablelock, T>;
 
 impl RawSleepablelock {
@@ -27,6 +27,7 @@ impl RawSleepablelock {
 }
 
 impl RawLock for RawSleepablelock {
+    #[cfg_attr(feature = "lockdep", track_caller)]
     fn acquire(&self) {
         self.lock.acquire();
     }
@@ -59,7 +60,11 @@ impl<T> SleepablelockGuard<'_, T> {
         );
     }
 
-    pub fn wakeup(&self) {
-        self.lock.lock.waitchannel.wakeup();
+    pub fn wakeup_all(&self) {
+        self.lock.lock.waitchannel.wakeup_all();
+    }
+
+    pub fn wakeup_one(&self) {
+        self.lock.lock.waitchannel.wakeup_one();
     }
 }
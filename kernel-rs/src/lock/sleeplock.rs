@@ -1,8 +1,12 @@
 //! Sleeping locks
 use core::cell::UnsafeCell;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
 
+#[cfg(feature = "lockdep")]
+use super::{lockdep, pop_off, push_off};
 use super::{Guard, Lock, RawLock, Sleepablelock};
-use crate::kernel::kernel_builder;
+use crate::{kernel::kernel_builder, proc::might_sleep};
 
 /// Long-term locks for processes
 pub struct RawSleeplock {
@@ -28,7 +32,9 @@ impl RawSleeplock {
 }
 
 impl RawLock for RawSleeplock {
+    #[cfg_attr(feature = "lockdep", track_caller)]
     fn acquire(&self) {
+        might_sleep(0);
         let mut guard = self.locked.lock();
         while *guard != -1 {
             guard.sleep();
@@ -38,12 +44,36 @@ impl RawLock for RawSleeplock {
             .current_proc()
             .expect("No current proc")
             .pid();
+        // Lockdep sees the sleeplock only while the internal lock is not
+        // held, or it would take the two for a cycle. Interrupts are off
+        // meanwhile, so that the process stays on this CPU.
+        drop(guard);
+        #[cfg(feature = "lockdep")]
+        // SAFETY: pop_off() follows.
+        unsafe {
+            push_off();
+            lockdep::acquire(
+                self as *const _ as usize,
+                self.name,
+                Location::caller(),
+                true,
+            );
+            pop_off();
+        }
     }
 
     fn release(&self) {
+        #[cfg(feature = "lockdep")]
+        // SAFETY: pop_off() follows.
+        unsafe {
+            push_off();
+            lockdep::release(self as *const _ as usize, true);
+            pop_off();
+        }
         let mut guard = self.locked.lock();
         *guard = -1;
-        guard.wakeup();
+        // Only one waiter can take the lock.
+        guard.wakeup_one();
     }
 
     fn holding(&self) -> bool {
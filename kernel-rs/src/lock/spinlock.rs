@@ -1,9 +1,13 @@
 //! Spin locks
 use core::cell::UnsafeCell;
 use core::hint::spin_loop;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
 use core::ptr;
 use core::sync::atomic::{AtomicPtr, Ordering};
 
+#[cfg(feature = "lockdep")]
+use super::lockdep;
 use super::{Guard, Lock, RawLock};
 use crate::{
     kernel::kernel_builder,
@@ -53,6 +57,7 @@ impl RawLock for RawSpinlock {
     /// before acquiring (after releasing) the lock. Otherwise, loads could read stale values.
     ///
     /// Additionally, note that an additional fence is unneccessary due to the pair of `Acquire`/`Release` orderings.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     fn acquire(&self) {
         // Disable interrupts to avoid deadlock.
         unsafe {
@@ -83,6 +88,14 @@ impl RawLock for RawSpinlock {
         {
             spin_loop();
         }
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(
+            self as *const _ as usize,
+            self.name,
+            Location::caller(),
+            false,
+        );
     }
 
     /// Releases the lock.
@@ -91,6 +104,8 @@ impl RawLock for RawSpinlock {
     /// We use an atomic store with `Release` ordering here. See `RawSpinlock::acquire()` for more details.
     fn release(&self) {
         assert!(self.holding(), "release {}", self.name);
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self as *const _ as usize, false);
 
         // Release the lock by storing ptr::null_mut() in `self.locked`
         // using an atomic store. This is actually done using a fence in RISC-V.
@@ -0,0 +1,143 @@
+//! The md device, a software RAID over two virtio disks.
+//!
+//! With the `md-stripe` feature, it is RAID-0: block n is block n / 2 of the
+//! disk n % 2, so the device is as large as both disks together and its I/O
+//! is spread over them. Otherwise it is RAID-1: every block is written to
+//! both disks and read from one of them, so either disk alone holds all the
+//! data. There is no superblock and no resync: after a crash in the middle
+//! of a write, the disks may differ in that block, each holding the old or
+//! the new contents.
+//!
+//! The device has the number MDDEV, and mount() can mount a FAT32 volume on
+//! it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use array_macro::array;
+
+use crate::{
+    bio::Buf,
+    blockdev::{self, BlockDevice},
+    kernel::kernel_builder,
+    lock::Sleepablelock,
+    proc::might_sleep,
+    virtio::Disk,
+};
+
+pub struct Md {
+    /// The disks that make the device.
+    pub disks: [Sleepablelock<Disk>; 2],
+
+    /// Are both disks attached?
+    attached: AtomicBool,
+}
+
+impl Md {
+    pub const fn zero() -> Self {
+        Self {
+            disks: array![_ => Sleepablelock::new("MD DISK", Disk::zero()); 2],
+            attached: AtomicBool::new(false),
+        }
+    }
+
+    /// Initializes the disks at the virtio mmio interfaces at `bases`, ones
+    /// of platform(), if disks are attached at both and they are not
+    /// initialized yet. It may be called again later, for disks plugged in
+    /// after boot.
+    /// Returns whether it initialized them.
+    pub fn attach(&self, bases: [usize; 2]) -> bool {
+        let mut disks = [self.disks[0].lock(), self.disks[1].lock()];
+        if self.is_attached() || !bases.iter().all(|base| Disk::is_attached(*base)) {
+            return false;
+        }
+        for (disk, base) in disks.iter_mut().zip(bases.iter()) {
+            disk.init(*base);
+        }
+        self.attached.store(true, Ordering::Release);
+        true
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    /// The disk that holds block blockno of the device, or a copy of it, and
+    /// the block of the disk.
+    fn map(&self, blockno: u32) -> (&Sleepablelock<Disk>, u32) {
+        let disk = &self.disks[blockno as usize % 2];
+        if cfg!(feature = "md-stripe") {
+            (disk, blockno / 2)
+        } else {
+            (disk, blockno)
+        }
+    }
+
+    fn get_buf(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        might_sleep(0);
+        // TODO: remove kernel_builder()
+        unsafe { kernel_builder().get_bcache() }
+            .get_buf(dev, blockno)?
+            .lock()
+    }
+
+    /// Reads the block of buf from its disk, unless buf is valid.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn fill(&self, buf: &mut Buf) -> Result<(), ()> {
+        if !buf.deref_inner().valid {
+            let (disk, blockno) = self.map(buf.blockno);
+            blockdev::account(buf.dev(), false, || disk.rw_at(buf, blockno, false))?;
+            buf.deref_inner_mut().valid = true;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Md {
+    fn read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        let mut buf = self.get_buf(dev, blockno)?;
+        if self.fill(&mut buf).is_err() {
+            buf.deref_inner_mut().data.fill(0);
+        }
+        Ok(buf)
+    }
+
+    fn try_read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        let mut buf = self.get_buf(dev, blockno)?;
+        self.fill(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write(&self, b: &mut Buf) -> Result<(), ()> {
+        blockdev::account(b.dev(), true, || {
+            if cfg!(feature = "md-stripe") {
+                let (disk, blockno) = self.map(b.blockno);
+                disk.rw_at(b, blockno, true)
+            } else {
+                // Both copies are written, even if the first fails.
+                let blockno = b.blockno;
+                self.disks.iter().fold(Ok(()), |result, disk| {
+                    disk.rw_at(b, blockno, true).and(result)
+                })
+            }
+        })
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.disks
+            .iter()
+            .fold(Ok(()), |result, disk| disk.flush().and(result))
+    }
+
+    fn discard(&self, blockno: u32, n: u32) {
+        if cfg!(feature = "md-stripe") {
+            for blockno in blockno..blockno + n {
+                let (disk, blockno) = self.map(blockno);
+                disk.discard(blockno, 1);
+            }
+        } else {
+            for disk in &self.disks {
+                disk.discard(blockno, n);
+            }
+        }
+    }
+}
@@ -4,22 +4,37 @@
 //! based on qemu's hw/riscv/virt.c:
 //!
 //! 00001000 -- boot ROM, provided by qemu
+//! 00101000 -- goldfish RTC
 //! 02000000 -- CLINT
 //! 0C000000 -- PLIC
 //! 10000000 -- uart0
 //! 10001000 -- virtio disk
+//! 10002000 -- virtio keyboard, if any
+//! 10003000 -- second virtio disk, if any
+//! 10004000 -- the disks of the md device, if any
 //! 80000000 -- boot ROM jumps here in machine mode
 //!             -kernel loads the kernel here
+//!             with SBI=yes, the SBI firmware is here instead, and
+//! 80200000 -- the firmware jumps here in supervisor mode
 //! unused RAM after 80000000.
 //! the kernel uses physical memory thus:
 //! 80000000 -- entry.S, then kernel text and data
 //! end -- start of kernel page allocation area
-//! PHYSTOP -- end RAM used by the kernel
-use crate::riscv::{MAXVA, PGSIZE};
+//! phystop() -- end RAM used by the kernel
+//!
+//! The device addresses here are defaults. The ones in use are those of
+//! platform(), found in the device tree at boot.
+use crate::{
+    platform::platform,
+    riscv::{pgrounddown, MAXVA, PGSIZE},
+};
 
 /// SiFive Test Finisher. (virt device only)
 pub const FINISHER: usize = 0x100000;
 
+/// Goldfish real-time clock.
+pub const GOLDFISH_RTC: usize = 0x101000;
+
 /// qemu puts UART registers here in physical memory.
 pub const UART0: usize = 0x10000000;
 pub const UART0_IRQ: usize = 10;
@@ -28,39 +43,78 @@ pub const UART0_IRQ: usize = 10;
 pub const VIRTIO0: usize = 0x10001000;
 pub const VIRTIO0_IRQ: usize = 1;
 
+/// The next virtio mmio interface, where a keyboard may be attached.
+pub const VIRTIO1: usize = 0x10002000;
+pub const VIRTIO1_IRQ: usize = 2;
+
+/// The one after, where a second disk may be attached.
+pub const VIRTIO2: usize = 0x10003000;
+pub const VIRTIO2_IRQ: usize = 3;
+
+/// The two after, where the disks of the md device may be attached.
+pub const VIRTIO3: usize = 0x10004000;
+pub const VIRTIO3_IRQ: usize = 4;
+pub const VIRTIO4: usize = 0x10005000;
+pub const VIRTIO4_IRQ: usize = 5;
+
+/// The one after, where a serial device for gdb may be attached.
+pub const VIRTIO5: usize = 0x10006000;
+pub const VIRTIO5_IRQ: usize = 6;
+
 /// core local interruptor (CLINT), which contains the timer.
 pub const CLINT: usize = 0x2000000;
-pub const fn clint_mtimecmp(hartid: usize) -> usize {
-    CLINT
+pub fn clint_mtimecmp(hartid: usize) -> usize {
+    platform()
+        .clint
         .wrapping_add(0x4000)
         .wrapping_add(hartid.wrapping_mul(8))
 }
 
 /// cycles since boot.
-pub const CLINT_MTIME: usize = CLINT.wrapping_add(0xbff8);
+pub fn clint_mtime() -> usize {
+    platform().clint.wrapping_add(0xbff8)
+}
 
 /// qemu puts platform-level interrupt controller (PLIC) here.
 pub const PLIC: usize = 0xc000000;
 pub const PLIC_PENDING: usize = PLIC.wrapping_add(0x1000);
-pub const fn plic_senable(hart: usize) -> usize {
-    PLIC.wrapping_add(0x2080)
+pub fn plic_senable(hart: usize) -> usize {
+    platform()
+        .plic
+        .wrapping_add(0x2080)
         .wrapping_add((hart).wrapping_mul(0x100))
 }
-pub const fn plic_spriority(hart: usize) -> usize {
-    PLIC.wrapping_add(0x201000)
+pub fn plic_spriority(hart: usize) -> usize {
+    platform()
+        .plic
+        .wrapping_add(0x201000)
         .wrapping_add((hart).wrapping_mul(0x2000))
 }
-pub const fn plic_sclaim(hart: usize) -> usize {
-    PLIC.wrapping_add(0x201004)
+pub fn plic_sclaim(hart: usize) -> usize {
+    platform()
+        .plic
+        .wrapping_add(0x201004)
         .wrapping_add((hart).wrapping_mul(0x2000))
 }
 
 /// the kernel expects there to be RAM
 /// for use by the kernel and user pages
-/// from physical address 0x80000000 to PHYSTOP.
+/// from physical address 0x80000000 to phystop().
 pub const KERNBASE: usize = 0x80000000;
+
+/// end of RAM with qemu's default of 128MB.
 pub const PHYSTOP: usize = KERNBASE.wrapping_add(128 * 1024 * 1024);
 
+/// end of RAM, as found in the device tree at boot.
+pub fn phystop() -> usize {
+    let memory = &platform().memory;
+    if memory.contains(&KERNBASE) {
+        pgrounddown(memory.end)
+    } else {
+        PHYSTOP
+    }
+}
+
 /// map the trampoline page to the highest address,
 /// in both user and kernel space.
 pub const TRAMPOLINE: usize = MAXVA.wrapping_sub(PGSIZE);
@@ -78,6 +132,12 @@ pub const fn kstack(p: usize) -> usize {
 ///   fixed-size stack
 ///   expandable heap
 ///   ...
+///   VDSO_PROC (read-only constants of the process, see vdso.rs)
+///   VDSO_TIME (read-only time, shared by every process)
 ///   TRAPFRAME (p->trapframe, used by the trampoline)
 ///   TRAMPOLINE (the same page as in the kernel)
 pub const TRAPFRAME: usize = TRAMPOLINE.wrapping_sub(PGSIZE);
+
+pub const VDSO_TIME: usize = TRAPFRAME.wrapping_sub(PGSIZE);
+
+pub const VDSO_PROC: usize = VDSO_TIME.wrapping_sub(PGSIZE);
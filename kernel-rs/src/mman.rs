@@ -0,0 +1,17 @@
+//! Memory protection of mprotect(), and advice of madvise(), shared with
+//! user programs through kernel/mman.h.
+
+/// Pages may be read.
+pub const PROT_READ: i32 = 1;
+
+/// Pages may be written.
+pub const PROT_WRITE: i32 = 2;
+
+/// Pages may be executed.
+pub const PROT_EXEC: i32 = 4;
+
+/// Pages will be written soon.
+pub const MADV_WILLNEED: i32 = 3;
+
+/// Pages will not be needed, and may read as zeros.
+pub const MADV_DONTNEED: i32 = 4;
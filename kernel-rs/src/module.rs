@@ -0,0 +1,65 @@
+//! Optional subsystems, each behind a Cargo feature.
+//!
+//! Instead of calling the init function of each from kernel_main(), a
+//! subsystem adds itself to `MODULES` with the boot stage it belongs to, and
+//! the init functions of those whose feature is enabled run at the end of
+//! their stage, in order. A module is still
+//! compiled without its feature, so that turning it off keeps the rest of
+//! the kernel building, but it is never started.
+
+use crate::{
+    boot::Stage, file::DevswTable, kernel::kernel_builder, klog, kstat::kstatinit,
+    profile::profileinit,
+};
+
+pub struct Module {
+    pub name: &'static str,
+
+    /// Is the feature of the module enabled?
+    pub enabled: bool,
+
+    /// The stage of the boot after whose work the module starts.
+    pub stage: Stage,
+
+    /// Registers the devices of the module. It runs before the other harts
+    /// start.
+    pub init: fn(&DevswTable),
+}
+
+/// The optional subsystems, in the order they start.
+pub const MODULES: [Module; 2] = [
+    Module {
+        name: "profile",
+        enabled: cfg!(feature = "procfs"),
+        stage: Stage::EarlyConsole,
+        init: profileinit,
+    },
+    Module {
+        name: "kstat",
+        enabled: cfg!(feature = "procfs"),
+        stage: Stage::EarlyConsole,
+        init: kstatinit,
+    },
+];
+
+/// Starts the modules of stage whose feature is enabled.
+pub fn init_modules(stage: Stage) {
+    let modules = MODULES
+        .iter()
+        .filter(|module| module.enabled && module.stage == stage);
+    for module in modules {
+        (module.init)(&kernel_builder().devsw);
+    }
+}
+
+/// Logs which modules are enabled.
+pub fn log_modules() {
+    for module in MODULES.iter() {
+        klog!(
+            Info,
+            "module {}: {}",
+            module.name,
+            if module.enabled { "on" } else { "off" }
+        );
+    }
+}
@@ -0,0 +1,11 @@
+//! Flags of mount(), shared with user programs through kernel/mount.h.
+
+/// mount flag: change the options of the root file system, mounted at /,
+/// instead of mounting a volume.
+pub const MS_REMOUNT: i32 = 1;
+
+/// mount flag: discard freed blocks, so that the disk may free their storage.
+pub const MS_DISCARD: i32 = 2;
+
+/// mount flag: commit each write() before it returns.
+pub const MS_SYNC: i32 = 4;
@@ -0,0 +1,275 @@
+//! Named message queues.
+//!
+//! mq_open() returns a file descriptor of the message queue with a name,
+//! which processes share by opening the same name. mq_send() queues a
+//! message of up to MQ_MSGSIZE bytes with a priority, and mq_receive()
+//! takes the oldest one of the highest priority. A queue holds up to
+//! MQ_MAXMSG messages in one page, which it takes from the page allocator on
+//! the first send, so that its memory is bounded. Senders wait while it is
+//! full, and receivers while it is empty, unless the file descriptor was
+//! opened with O_NONBLOCK. Like a semaphore, a queue goes away on the last
+//! close.
+
+use core::cell::Cell;
+use core::convert::TryFrom;
+
+use static_assertions::const_assert;
+
+use crate::{
+    arena::{Arena, ArenaObject, ArrayArena, Rc},
+    fcntl::FcntlFlags,
+    file::FileType,
+    kernel::{kernel_builder, Kernel},
+    lock::{Sleepablelock, Spinlock},
+    page::Page,
+    param::{NMQ, SEMNAME},
+    proc::CurrentProc,
+    riscv::PGSIZE,
+    vm::{UserPtr, UserSlice},
+};
+
+/// Maximum number of messages in a queue.
+pub const MQ_MAXMSG: usize = 16;
+
+/// Maximum size of a message.
+pub const MQ_MSGSIZE: usize = PGSIZE / MQ_MAXMSG;
+
+const_assert!(MQ_MAXMSG * MQ_MSGSIZE <= PGSIZE);
+
+pub type MessageQueues = Spinlock<ArrayArena<MessageQueue, NMQ>>;
+
+pub type RcMessageQueue = Rc<MessageQueues>;
+
+#[derive(Clone, Copy)]
+struct Message {
+    prio: u32,
+    /// Number of messages sent to the queue before this one.
+    seq: u32,
+    len: usize,
+}
+
+struct MqInner {
+    /// The bytes of the ith message are at i * MQ_MSGSIZE in the page.
+    page: Option<Page>,
+    msgs: [Option<Message>; MQ_MAXMSG],
+    /// Number of messages sent.
+    sent: u32,
+}
+
+pub struct MessageQueue {
+    name: [u8; SEMNAME],
+    len: usize,
+    inner: Sleepablelock<MqInner>,
+}
+
+impl MessageQueue {
+    pub const fn zero() -> Self {
+        Self {
+            name: [0; SEMNAME],
+            len: 0,
+            inner: Sleepablelock::new(
+                "mq",
+                MqInner {
+                    page: None,
+                    msgs: [None; MQ_MAXMSG],
+                    sent: 0,
+                },
+            ),
+        }
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+
+    /// Queues the message src with priority prio, waiting while the queue is
+    /// full unless nonblock is true.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn send(
+        &self,
+        src: UserSlice,
+        prio: u32,
+        nonblock: bool,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<(), ()> {
+        if src.len() > MQ_MSGSIZE {
+            return Err(());
+        }
+        let mut inner = self.inner.lock();
+        let i = loop {
+            if let Some(i) = inner.msgs.iter().position(Option::is_none) {
+                break i;
+            }
+            if nonblock || proc.killed() {
+                return Err(());
+            }
+            inner.sleep();
+        };
+        if inner.page.is_none() {
+            // TODO: remove kernel_builder()
+            inner.page = Some(kernel_builder().kmem.alloc().ok_or(())?);
+        }
+        let page = inner.page.as_mut().expect("MessageQueue::send");
+        src.read(
+            &mut page[i * MQ_MSGSIZE..i * MQ_MSGSIZE + src.len()],
+            proc.memory_mut(),
+        )?;
+        let seq = inner.sent;
+        inner.sent = seq.wrapping_add(1);
+        inner.msgs[i] = Some(Message {
+            prio,
+            seq,
+            len: src.len(),
+        });
+        inner.wakeup_all();
+        Ok(())
+    }
+
+    /// Takes the oldest message of the highest priority into dst, waiting
+    /// while the queue is empty unless nonblock is true.
+    /// Returns Ok((length, priority)) on success, Err(()) on error, or if
+    /// dst is shorter than the message, which stays in the queue.
+    pub fn receive(
+        &self,
+        dst: UserSlice,
+        nonblock: bool,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<(usize, u32), ()> {
+        let mut inner = self.inner.lock();
+        let (i, msg) = loop {
+            let sent = inner.sent;
+            let first = inner
+                .msgs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, msg)| msg.map(|msg| (i, msg)))
+                .max_by_key(|(_, msg)| (msg.prio, sent.wrapping_sub(msg.seq)));
+            if let Some(first) = first {
+                break first;
+            }
+            if nonblock || proc.killed() {
+                return Err(());
+            }
+            inner.sleep();
+        };
+        if dst.len() < msg.len {
+            return Err(());
+        }
+        let page = inner.page.as_ref().expect("MessageQueue::receive");
+        dst.sub(0, msg.len).write(
+            &page[i * MQ_MSGSIZE..i * MQ_MSGSIZE + msg.len],
+            proc.memory_mut(),
+        )?;
+        inner.msgs[i] = None;
+        inner.wakeup_all();
+        Ok((msg.len, msg.prio))
+    }
+}
+
+#[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
+impl const Default for MessageQueue {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl ArenaObject for MessageQueue {
+    fn finalize<'s, A: Arena>(&'s mut self, _guard: &'s mut A::Guard<'_>) {
+        self.len = 0;
+        let inner = self.inner.get_mut();
+        inner.msgs = [None; MQ_MAXMSG];
+        inner.sent = 0;
+        if let Some(page) = inner.page.take() {
+            // TODO: remove kernel_builder()
+            kernel_builder().kmem.free(page);
+        }
+    }
+}
+
+impl MessageQueues {
+    pub const fn zero() -> Self {
+        Spinlock::new("MQS", ArrayArena::<MessageQueue, NMQ>::new())
+    }
+
+    /// Returns the queue named name. If there is none and create is true,
+    /// makes an empty one.
+    fn open(&self, name: &[u8], create: bool) -> Result<RcMessageQueue, ()> {
+        if name.is_empty() || name.len() > SEMNAME {
+            return Err(());
+        }
+        let created = Cell::new(false);
+        let mq = self
+            .find_or_alloc(
+                |mq| mq.name() == name,
+                |mq| {
+                    mq.name[..name.len()].copy_from_slice(name);
+                    mq.len = name.len();
+                    created.set(true);
+                },
+            )
+            .ok_or(())?;
+        // Dropping mq frees the queue that was just made.
+        if created.get() && !create {
+            return Err(());
+        }
+        Ok(mq)
+    }
+}
+
+impl Kernel {
+    /// Open the message queue named name. With O_CREATE in flags, make it if
+    /// there is none. flags may also contain O_CLOEXEC and O_NONBLOCK.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    pub fn sys_mq_open(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut name = [0; SEMNAME + 1];
+        let name = proc.argstr(0, &mut name)?;
+        let flags = FcntlFlags::from_bits(proc.argint(1)?).ok_or(())?;
+        if !(FcntlFlags::O_CREATE | FcntlFlags::O_CLOEXEC | FcntlFlags::O_NONBLOCK).contains(flags)
+        {
+            return Err(());
+        }
+        let mq = self
+            .mqs
+            .open(name.to_bytes(), flags.contains(FcntlFlags::O_CREATE))?;
+        let nonblock = flags.contains(FcntlFlags::O_NONBLOCK);
+        let f = self
+            .ftable
+            .alloc_file(FileType::MessageQueue { mq, nonblock }, false, false)?;
+        let fd = proc
+            .files()
+            .alloc(f, flags.contains(FcntlFlags::O_CLOEXEC))
+            .map_err(|_| ())?;
+        Ok(fd as usize)
+    }
+
+    /// Send the n bytes at buf with priority prio to the message queue of
+    /// file descriptor fd.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_mq_send(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let f = proc.files().get(proc.argint(0)?)?;
+        let src = proc.argslice(1, 2)?;
+        let prio = u32::try_from(proc.argint(3)?).map_err(|_| ())?;
+        match &f.typ {
+            FileType::MessageQueue { mq, nonblock } => mq.send(src, prio, *nonblock, proc)?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Receive a message of up to n bytes into buf from the message queue of
+    /// file descriptor fd, and store its priority at prio unless it is 0.
+    /// Returns Ok(length of the message) on success, Err(()) on error.
+    pub fn sys_mq_receive(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let f = proc.files().get(proc.argint(0)?)?;
+        let dst = proc.argslice(1, 2)?;
+        let addr = proc.argaddr(3)?;
+        let (len, prio) = match &f.typ {
+            FileType::MessageQueue { mq, nonblock } => mq.receive(dst, *nonblock, proc)?,
+            _ => return Err(()),
+        };
+        if addr != 0 {
+            UserPtr::<u32>::new(addr)?.write(&prio, proc.memory_mut())?;
+        }
+        Ok(len)
+    }
+}
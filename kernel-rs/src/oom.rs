@@ -0,0 +1,34 @@
+//! Out-of-memory policy.
+//!
+//! A system call that cannot get memory fails with -1; nothing panics. If
+//! the failure left fewer than `MIN_FREE` pages free, memory is exhausted,
+//! rather than the request too large. By then, the page allocator has had
+//! the caches, such as the buffer cache, give back what they could, so as a
+//! last resort, the OOM killer kills the process with the highest OOM score,
+//! whose memory comes back when it exits. The score is the number of pages of user memory, but 0 for kernel
+//! threads and for privileged processes, which have CAP_SYS_ADMIN, so that
+//! the killer spares them, and init. If the process that failed to get memory
+//! has the highest score, it just gets the error.
+
+use crate::{kernel::Kernel, klog, proc::CurrentProc};
+
+/// Free pages below which memory is exhausted.
+const MIN_FREE: usize = 16;
+
+impl Kernel {
+    /// Apply the OOM policy after `proc` failed to get memory.
+    pub fn out_of_memory(&self, proc: &CurrentProc<'_>) {
+        if self.kmem.free_pages() >= MIN_FREE {
+            return;
+        }
+        if let Some((pid, score)) = self.procs().oom_kill(proc) {
+            klog!(
+                Warn,
+                "oom: killed pid={} with {} pages, out of memory for pid={}",
+                pid,
+                score,
+                proc.pid()
+            );
+        }
+    }
+}
@@ -17,7 +17,7 @@ pub struct RawPage {
 /// # Safety
 ///
 /// - inner is 4096 bytes-aligned.
-/// - end <= inner < PHYSTOP
+/// - end <= inner < phystop()
 /// - Two different pages never overwrap. If p1: Page and p2: Page, then
 ///   *(p1.inner).inner and *(p1.inner).inner are non-overwrapping arrays.
 pub struct Page {
@@ -64,7 +64,7 @@ impl Page {
     ///
     /// Given addr must not break the invariant of Page.
     /// - addr is a multiple of PGSIZE.
-    /// - end <= addr < PHYSTOP
+    /// - end <= addr < phystop()
     /// - If p: Page, then *(p.inner).inner and (addr as *RawPage).inner are
     ///   non-overwrapping arrays.
     pub unsafe fn from_usize(addr: usize) -> Self {
@@ -13,27 +13,80 @@ pub const NFILE: usize = 100;
 /// Maximum number of active i-nodes.
 pub const NINODE: usize = 50;
 
+/// User ids below this may have disk quotas.
+pub const NQUOTA: usize = 32;
+
+/// Maximum number of programs whose text is shared.
+pub const NTEXT: usize = 16;
+
+/// Maximum number of pages of shared text per program.
+pub const MAXTEXT: usize = 64;
+
+/// Maximum length of the kernel command line.
+pub const CMDLINE: usize = 256;
+
+/// Maximum number of named semaphores.
+pub const NSEM: usize = 32;
+
+/// Maximum length of the name of a semaphore or a message queue.
+pub const SEMNAME: usize = 32;
+
+/// Maximum number of message queues.
+pub const NMQ: usize = 16;
+
+/// Number of virtual consoles.
+pub const NCONSOLE: usize = 4;
+
 /// Maximum major device number.
 pub const NDEV: usize = 10;
 
 /// Device number of file system root disk.
 pub const ROOTDEV: u32 = 1;
 
-/// Max exec arguments.
+/// Device number of the second disk, which may hold a FAT32 volume.
+pub const FATDEV: u32 = 2;
+
+/// Device number of the md device, which may hold a FAT32 volume too.
+pub const MDDEV: u32 = 3;
+
+/// Max exec arguments, and environment strings.
 pub const MAXARG: usize = 32;
 
-/// Block Size.
-pub const BSIZE: usize = 1024;
+/// Block size of the file system, which its super block records. It is a
+/// multiple of the disk sector size, and at most a page, which each buffer
+/// has for its data.
+pub const BSIZE: usize = 4096;
 
 /// Max # of blocks any FS op writes.
 /// Will be handled in #31.
 pub const MAXOPBLOCKS: usize = 10;
 
-/// Max data blocks in on-disk log.
-pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
-
 /// Size of disk block cache.
-pub const NBUF: usize = MAXOPBLOCKS * 3;
+pub const NBUF: usize = MAXOPBLOCKS * 11;
+
+/// Number of pages of files that the page cache holds.
+pub const NPAGECACHE: usize = 32;
+
+/// Max data blocks that a commit logs, however large the on-disk log is.
+/// The blocks of a commit being installed and of the next one stay in the
+/// disk block cache, with room for MAXOPBLOCKS others.
+pub const MAXLOGSIZE: usize = (NBUF - MAXOPBLOCKS) / 2;
+
+/// Pages reserved for the data of buffers, enough for the blocks of a full
+/// log and a few operations, so that the buffer cache always gets a page
+/// however much memory user processes take.
+pub const NBUFRESERVE: usize = MAXLOGSIZE + 2 * MAXOPBLOCKS;
+
+/// Clock ticks between syncs of the sync daemon, about 30 seconds in qemu.
+pub const SYNC_INTERVAL: u32 = 300;
+
+/// Timer intervals a disk request may take before it is retried, about 5
+/// seconds in qemu.
+pub const DISK_TIMEOUT: u64 = 50;
+
+/// Timer intervals a hart may go without a timer interrupt before the
+/// watchdog takes it for locked up, about 10 seconds in qemu.
+pub const WATCHDOG_TICKS: u64 = 100;
 
 /// Size of file system in blocks.
 pub const FSSIZE: usize = 2000;
@@ -41,5 +94,9 @@ pub const FSSIZE: usize = 2000;
 /// Maximum file path name.
 pub const MAXPATH: usize = 128;
 
+/// Maximum number of elements in a path, as many as a path of MAXPATH bytes
+/// can have.
+pub const MAXPATHELEM: usize = MAXPATH / 2;
+
 /// Maximum length of process name.
 pub const MAXPROCNAME: usize = 16;
@@ -0,0 +1,72 @@
+//! Per-hart data, indexed by the hart id in tp.
+//!
+//! Each hart reaches its own element while it cannot move to another hart,
+//! that is, while interrupts are off. `PerCpu::with` turns them off for the
+//! duration of a closure.
+
+use core::cell::UnsafeCell;
+
+use crate::{
+    lock::{pop_off, push_off},
+    param::NCPU,
+    proc::cpuid,
+    riscv::intr_get,
+};
+
+pub struct PerCpu<T> {
+    // The element of the current hart can be mutated through a shared
+    // reference, so we need interior mutability.
+    data: UnsafeCell<[T; NCPU]>,
+}
+
+// SAFETY: a hart accesses the element of another hart only through raw
+// pointers, whose users must ensure that it does not race.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(data: [T; NCPU]) -> Self {
+        Self {
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Returns a raw pointer to the element of hart `id`.
+    pub fn get_raw(&self, id: usize) -> *mut T {
+        assert!(id < NCPU, "PerCpu: bad hart id {}", id);
+        // SAFETY: `id` is in the array.
+        unsafe { (self.data.get() as *mut T).add(id) }
+    }
+
+    /// Returns a raw pointer to the element of this hart.
+    ///
+    /// It is safe to call this function with interrupts enabled, but the
+    /// returned pointer may not be of the current hart, since the scheduler
+    /// can move the process to another hart on a timer interrupt.
+    pub fn current_raw(&self) -> *mut T {
+        self.get_raw(cpuid())
+    }
+
+    /// Returns a mutable reference to the element of this hart.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts must stay off while the reference lives, and no other
+    /// reference to the element may exist meanwhile.
+    // Exclusivity comes from the hart, not from the borrow of `self`.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn current_mut(&self) -> &mut T {
+        assert!(!intr_get(), "PerCpu: interrupts on");
+        // SAFETY: the safety condition of this method.
+        unsafe { &mut *self.current_raw() }
+    }
+
+    /// Calls `f` with the element of this hart, with interrupts off.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        unsafe { push_off() };
+        // SAFETY: interrupts are off, so this hart keeps running this code,
+        // and only shared references are made here.
+        let result = f(unsafe { &*self.current_raw() });
+        unsafe { pop_off() };
+        result
+    }
+}
@@ -1,18 +1,29 @@
-use core::{mem, ops::Deref, ptr::NonNull};
+use core::{cmp, mem, ops::Deref, ptr::NonNull};
+
+use arrayvec::ArrayVec;
 
 use crate::{
     file::{FileType, RcFile},
+    fs::{Inode, RcInode},
+    kalloc::Kmem,
     kernel::Kernel,
     lock::Spinlock,
     page::Page,
     proc::{CurrentProc, WaitChannel},
-    vm::UVAddr,
+    riscv::PGSIZE,
+    vm::UserSlice,
 };
 
-const PIPESIZE: usize = 512;
+/// Pages of the buffer of a new pipe.
+const PIPEPAGES: usize = 1;
+
+/// Pages of the buffer of a pipe, at most.
+const MAXPIPEPAGES: usize = 16;
 
 struct PipeInner {
-    data: [u8; PIPESIZE],
+    /// The buffer, a power of two number of pages, so that the counters
+    /// below wrap around at a multiple of its size.
+    pages: ArrayVec<[Page; MAXPIPEPAGES]>,
 
     /// Number of bytes read.
     nread: u32,
@@ -20,11 +31,18 @@ struct PipeInner {
     /// Number of bytes written.
     nwrite: u32,
 
-    /// Read fd is still open.
-    readopen: bool,
+    /// Number of open read ends.
+    readers: u32,
+
+    /// Number of open write ends.
+    writers: u32,
 
-    /// Write fd is still open.
-    writeopen: bool,
+    /// Number of times a read end was opened, so that a writer waiting in
+    /// `Pipe::wait_peer` notices a reader that came and went.
+    read_opens: u32,
+
+    /// Number of times a write end was opened.
+    write_opens: u32,
 }
 
 pub struct Pipe {
@@ -38,17 +56,17 @@ pub struct Pipe {
 }
 
 impl Pipe {
-    /// Tries to read up to `n` bytes using `Pipe::try_read()`.
+    /// Tries to read up to `dst.len()` bytes into `dst` using `Pipe::try_read()`.
     /// If successfully read i > 0 bytes, wakeups the `write_waitchannel` and returns `Ok(i: usize)`.
     /// If the pipe was empty, sleeps at `read_waitchannel` and tries again after wakeup.
     /// If an error happened, returns `Err(())`.
-    pub fn read(&self, addr: UVAddr, n: usize, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+    pub fn read(&self, dst: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let mut inner = self.inner.lock();
         loop {
-            match inner.try_read(addr, n, proc) {
+            match inner.try_read(dst, proc) {
                 Ok(r) => {
                     //DOC: piperead-wakeup
-                    self.write_waitchannel.wakeup();
+                    self.write_waitchannel.wakeup_all();
                     return Ok(r);
                 }
                 Err(PipeError::WaitForIO) => {
@@ -60,20 +78,49 @@ impl Pipe {
         }
     }
 
-    /// Tries to write up to `n` bytes by repeatedly calling `Pipe::try_write()`.
+    /// Tries to write up to `src.len()` bytes from `src` by repeatedly calling `Pipe::try_write()`.
     /// Wakeups `read_waitchannel` for every successful `Pipe::try_write()`.
     /// After successfully writing i >= 0 bytes, returns `Ok(i)`.
-    /// Note that we may have i < `n` if an copy-in error happened.
+    /// Note that we may have i < `src.len()` if an copy-in error happened.
     /// If the pipe was full, sleeps at `write_waitchannel` and tries again after wakeup.
     /// If an error happened, returns `Err(())`.
-    pub fn write(&self, addr: UVAddr, n: usize, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+    pub fn write(&self, src: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        self.write_internal(
+            src.len(),
+            |off, dst, proc| src.sub(off, dst.len()).read(dst, proc.memory_mut()),
+            proc,
+        )
+    }
+
+    /// Writes `src` in kernel memory, as `Pipe::write()` does.
+    pub fn write_kernel(&self, src: &[u8], proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        self.write_internal(
+            src.len(),
+            |off, dst, _| {
+                dst.copy_from_slice(&src[off..off + dst.len()]);
+                Ok(())
+            },
+            proc,
+        )
+    }
+
+    /// Writes `n` bytes, as `Pipe::write()` does.
+    ///
+    /// `f(off, dst, proc)` should copy the content beginning at the `off`th
+    /// byte of the source, which the caller of this method knows, to `dst`.
+    fn write_internal<F: FnMut(usize, &mut [u8], &mut CurrentProc<'_>) -> Result<(), ()>>(
+        &self,
+        n: usize,
+        mut f: F,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<usize, ()> {
         let mut written = 0;
         let mut inner = self.inner.lock();
         loop {
-            match inner.try_write(addr + written, n - written, proc) {
+            match inner.try_write(written, n - written, &mut f, proc) {
                 Ok(r) => {
                     written += r;
-                    self.read_waitchannel.wakeup();
+                    self.read_waitchannel.wakeup_all();
                     if written < n {
                         self.write_waitchannel.sleep(&mut inner, proc);
                     } else {
@@ -81,7 +128,7 @@ impl Pipe {
                     }
                 }
                 Err(PipeError::InvalidCopyin(i)) => {
-                    self.read_waitchannel.wakeup();
+                    self.read_waitchannel.wakeup_all();
                     return Ok(written + i);
                 }
                 _ => return Err(()),
@@ -89,29 +136,103 @@ impl Pipe {
         }
     }
 
-    fn close(&self, writable: bool) -> bool {
+    /// Returns the size of the buffer.
+    pub fn size(&self) -> usize {
+        self.inner.lock().size() as usize
+    }
+
+    /// Resizes the buffer to at least `size` bytes, keeping what is in it.
+    /// Returns Ok(the new size) on success, Err(()) if `size` is too large,
+    /// is less than what is in the buffer, or pages run out.
+    pub fn set_size(&self, size: usize, allocator: &Spinlock<Kmem>) -> Result<usize, ()> {
+        let npages = cmp::max((size + PGSIZE - 1) / PGSIZE, 1).next_power_of_two();
+        if npages > MAXPIPEPAGES {
+            return Err(());
+        }
         let mut inner = self.inner.lock();
+        inner.resize(npages, allocator)?;
+        // There may be more room for writers now.
+        self.write_waitchannel.wakeup_all();
+        Ok(inner.size() as usize)
+    }
 
+    /// Opens another read end, write end, or both.
+    fn open(&self, readable: bool, writable: bool) {
+        let mut inner = self.inner.lock();
+
+        if readable {
+            inner.readers += 1;
+            inner.read_opens = inner.read_opens.wrapping_add(1);
+            self.write_waitchannel.wakeup_all();
+        }
         if writable {
-            inner.writeopen = false;
-            self.read_waitchannel.wakeup();
-        } else {
-            inner.readopen = false;
-            self.write_waitchannel.wakeup();
+            inner.writers += 1;
+            inner.write_opens = inner.write_opens.wrapping_add(1);
+            self.read_waitchannel.wakeup_all();
+        }
+    }
+
+    fn close(&self, readable: bool, writable: bool) -> bool {
+        let mut inner = self.inner.lock();
+
+        if readable {
+            inner.readers -= 1;
+            self.write_waitchannel.wakeup_all();
+        }
+        if writable {
+            inner.writers -= 1;
+            self.read_waitchannel.wakeup_all();
         }
 
         // Return whether pipe should be freed or not.
-        !inner.readopen && !inner.writeopen
+        inner.readers == 0 && inner.writers == 0
+    }
+
+    /// Waits until the other end of a FIFO is open, after opening one end.
+    /// A FIFO opened for both reading and writing does not wait.
+    /// With `nonblock`, a reader does not wait, and a writer fails at once
+    /// if no reader is there.
+    /// Returns Ok(()) on success, Err(()) if it fails or the process was killed.
+    pub fn wait_peer(
+        &self,
+        readable: bool,
+        writable: bool,
+        nonblock: bool,
+        proc: &CurrentProc<'_>,
+    ) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        match (readable, writable) {
+            (true, false) if !nonblock => {
+                let opens = inner.write_opens;
+                while inner.writers == 0 && inner.write_opens == opens {
+                    if proc.killed() {
+                        return Err(());
+                    }
+                    self.read_waitchannel.sleep(&mut inner, proc);
+                }
+            }
+            (false, true) => {
+                let opens = inner.read_opens;
+                while inner.readers == 0 && inner.read_opens == opens {
+                    if nonblock || proc.killed() {
+                        return Err(());
+                    }
+                    self.write_waitchannel.sleep(&mut inner, proc);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
     }
 }
 
 /// # Safety
 ///
 /// `ptr` always refers to a `Pipe`.
-/// Also, for a single `Pipe`, we have a single read-only `AllocatedPipe` and a single write-only `AllocatedPipe`.
-/// The `PipeInner`'s readopen/writeopen field denotes whether the read-only/write-only `AllocatedPipe` is still open,
-/// and hence, we can safely free the `Pipe` only after both the readopen/writeopen field is false, since this means
-/// all `AllocatedPipe`s were closed.
+/// Also, each `AllocatedPipe` of a `Pipe` but the one in a FIFO's inode is an open read end, write end, or both.
+/// The `PipeInner`'s readers/writers fields count them, and hence, we can safely free the `Pipe` only after
+/// both fields are zero, since this means all `AllocatedPipe`s were closed.
+/// The one in a FIFO's inode is removed under the inode's `fifo` lock when the `Pipe` is freed.
 pub struct AllocatedPipe {
     ptr: NonNull<Pipe>,
 }
@@ -131,8 +252,75 @@ impl Deref for AllocatedPipe {
 
 impl Kernel {
     pub fn allocate_pipe(&self) -> Result<(RcFile, RcFile), ()> {
-        let page = self.kmem.alloc().ok_or(())?;
-        let mut page = scopeguard::guard(page, |page| self.kmem.free(page));
+        let ptr = self.alloc_pipe(1, 1)?.ptr;
+        let f0 = self
+            .ftable
+            .alloc_file(
+                FileType::Pipe {
+                    pipe: AllocatedPipe { ptr },
+                },
+                true,
+                false,
+            )
+            .map_err(|_| {
+                let _ = AllocatedPipe { ptr }.close(true, true, &self.kmem);
+            })?;
+        // If this fails, dropping f0 closes the read end.
+        let f1 = self
+            .ftable
+            .alloc_file(
+                FileType::Pipe {
+                    pipe: AllocatedPipe { ptr },
+                },
+                false,
+                true,
+            )
+            .map_err(|_| {
+                let _ = AllocatedPipe { ptr }.close(false, true, &self.kmem);
+            })?;
+        Ok((f0, f1))
+    }
+
+    /// Opens the FIFO `ip` for reading, writing, or both, and attaches a new
+    /// pipe to it if it has none. The other end may not be open yet; see
+    /// `Pipe::wait_peer`.
+    pub fn open_fifo(&self, ip: RcInode, readable: bool, writable: bool) -> Result<RcFile, ()> {
+        let ptr = {
+            let mut fifo = ip.fifo.lock();
+            if fifo.is_none() {
+                *fifo = Some(self.alloc_pipe(0, 0)?);
+            }
+            let pipe = AllocatedPipe {
+                ptr: fifo.as_ref().expect("open_fifo").ptr,
+            };
+            pipe.open(readable, writable);
+            pipe.ptr
+        };
+        // Kept for closing the pipe if no file is free.
+        let inode = ip.clone();
+        self.ftable
+            .alloc_file(
+                FileType::Fifo {
+                    ip,
+                    pipe: AllocatedPipe { ptr },
+                },
+                readable,
+                writable,
+            )
+            .map_err(|_| AllocatedPipe { ptr }.close_fifo(&inode, readable, writable, &self.kmem))
+    }
+
+    /// Allocates a pipe with `readers` read ends and `writers` write ends open.
+    fn alloc_pipe(&self, readers: u32, writers: u32) -> Result<AllocatedPipe, ()> {
+        let mut pages = scopeguard::guard(ArrayVec::new(), |mut pages| {
+            for page in pages.drain(..) {
+                self.kmem.free(page);
+            }
+        });
+        for _ in 0..PIPEPAGES {
+            pages.push(self.kmem.alloc().ok_or(())?);
+        }
+        let mut page = self.kmem.alloc().ok_or(())?;
         let ptr = page.as_uninit_mut();
 
         // TODO(https://github.com/kaist-cp/rv6/issues/367):
@@ -141,47 +329,54 @@ impl Kernel {
             inner: Spinlock::new(
                 "pipe",
                 PipeInner {
-                    data: [0; PIPESIZE],
+                    pages: scopeguard::ScopeGuard::into_inner(pages),
                     nwrite: 0,
                     nread: 0,
-                    readopen: true,
-                    writeopen: true,
+                    readers,
+                    writers,
+                    read_opens: 0,
+                    write_opens: 0,
                 },
             ),
             read_waitchannel: WaitChannel::new(),
             write_waitchannel: WaitChannel::new(),
         }));
-        let f0 = self.ftable.alloc_file(
-            FileType::Pipe {
-                pipe: AllocatedPipe { ptr },
-            },
-            true,
-            false,
-        )?;
-        let f1 = self.ftable.alloc_file(
-            FileType::Pipe {
-                pipe: AllocatedPipe { ptr },
-            },
-            false,
-            true,
-        )?;
-
-        // Since files have been created successfully, prevent the page from being deallocated.
-        mem::forget(scopeguard::ScopeGuard::into_inner(page));
-        Ok((f0, f1))
+        // The page now holds the pipe, and is freed in `AllocatedPipe::close`.
+        mem::forget(page);
+        Ok(AllocatedPipe { ptr })
     }
 }
 
 impl AllocatedPipe {
-    pub fn close(self, writable: bool) -> Option<Page> {
-        if self.deref().close(writable) {
-            // SAFETY:
-            // If `Pipe::close()` returned true, this means all `AllocatedPipe`s were closed.
-            // Hence, we can free the `Pipe`.
-            // Also, the following is safe since `ptr` holds a `Pipe` stored in a valid page allocated from `Kmem::alloc`.
-            Some(unsafe { Page::from_usize(self.ptr.as_ptr() as _) })
-        } else {
-            None
+    /// Closes this end of the pipe, and frees the pipe if all ends are closed.
+    /// Returns whether it freed the pipe.
+    pub fn close(self, readable: bool, writable: bool, allocator: &Spinlock<Kmem>) -> bool {
+        if !self.deref().close(readable, writable) {
+            return false;
+        }
+        for page in self.inner.lock().pages.drain(..) {
+            allocator.free(page);
+        }
+        // SAFETY:
+        // If `Pipe::close()` returned true, this means all `AllocatedPipe`s were closed.
+        // Hence, we can free the `Pipe`.
+        // Also, the following is safe since `ptr` holds a `Pipe` stored in a valid page allocated from `Kmem::alloc`.
+        allocator.free(unsafe { Page::from_usize(self.ptr.as_ptr() as _) });
+        true
+    }
+
+    /// Closes this end of the pipe of the FIFO `ip`, and detaches the pipe
+    /// from `ip` if it frees the pipe.
+    pub fn close_fifo(
+        self,
+        ip: &Inode,
+        readable: bool,
+        writable: bool,
+        allocator: &Spinlock<Kmem>,
+    ) {
+        let mut fifo = ip.fifo.lock();
+        if self.close(readable, writable, allocator) {
+            *fifo = None;
         }
     }
 }
@@ -193,46 +388,94 @@ pub enum PipeError {
 }
 
 impl PipeInner {
-    /// Tries to write up to `n` bytes.
+    /// Returns the size of the buffer.
+    fn size(&self) -> u32 {
+        (self.pages.len() * PGSIZE) as u32
+    }
+
+    /// Returns the bytes of the buffer at `n`, a value of the counters, up to
+    /// the end of its page.
+    fn chunk(&mut self, n: u32) -> &mut [u8] {
+        let off = (n % self.size()) as usize;
+        &mut self.pages[off / PGSIZE][off % PGSIZE..]
+    }
+
+    /// Replaces the buffer with one of `npages` pages, and moves what is in
+    /// it to there. Returns Err(()) if it does not fit, or pages run out.
+    fn resize(&mut self, npages: usize, allocator: &Spinlock<Kmem>) -> Result<(), ()> {
+        let len = self.nwrite.wrapping_sub(self.nread);
+        if len as usize > npages * PGSIZE {
+            return Err(());
+        }
+        let mut pages = ArrayVec::<[Page; MAXPIPEPAGES]>::new();
+        while pages.len() < npages {
+            match allocator.alloc() {
+                Some(page) => pages.push(page),
+                None => {
+                    for page in pages.drain(..) {
+                        allocator.free(page);
+                    }
+                    return Err(());
+                }
+            }
+        }
+
+        let mut old = mem::replace(&mut self.pages, pages);
+        let old_size = old.len() * PGSIZE;
+        for i in 0..len as usize {
+            let off = (self.nread as usize + i) % old_size;
+            self.pages[i / PGSIZE][i % PGSIZE] = old[off / PGSIZE][off % PGSIZE];
+        }
+        self.nread = 0;
+        self.nwrite = len;
+        for page in old.drain(..) {
+            allocator.free(page);
+        }
+        Ok(())
+    }
+
+    /// Tries to write up to `n` bytes, beginning at the `start`th byte of the
+    /// source that `f` copies from, as in `Pipe::write_internal()`.
     /// If the process was killed, returns `Err(InvalidStatus)`.
     /// If an copy-in error happened after successfully writing i >= 0 bytes, returns `Err(InvalidCopyIn(i))`.
     /// Otherwise, returns `Ok(i)` after successfully writing i >= 0 bytes.
-    fn try_write(
+    fn try_write<F: FnMut(usize, &mut [u8], &mut CurrentProc<'_>) -> Result<(), ()>>(
         &mut self,
-        addr: UVAddr,
+        start: usize,
         n: usize,
+        f: &mut F,
         proc: &mut CurrentProc<'_>,
     ) -> Result<usize, PipeError> {
-        let mut ch = [0u8];
-        if !self.readopen || proc.killed() {
+        if self.readers == 0 || proc.killed() {
             return Err(PipeError::InvalidStatus);
         }
-        for i in 0..n {
-            if self.nwrite == self.nread.wrapping_add(PIPESIZE as u32) {
+        let mut i = 0;
+        while i < n {
+            let room = (self.size() - self.nwrite.wrapping_sub(self.nread)) as usize;
+            if room == 0 {
                 //DOC: pipewrite-full
                 return Ok(i);
             }
-            if proc.memory_mut().copy_in_bytes(&mut ch, addr + i).is_err() {
+            let nwrite = self.nwrite;
+            let chunk = self.chunk(nwrite);
+            let m = cmp::min(cmp::min(n - i, room), chunk.len());
+            if f(start + i, &mut chunk[..m], proc).is_err() {
                 return Err(PipeError::InvalidCopyin(i));
             }
-            self.data[self.nwrite as usize % PIPESIZE] = ch[0];
-            self.nwrite = self.nwrite.wrapping_add(1);
+            self.nwrite = self.nwrite.wrapping_add(m as u32);
+            i += m;
         }
         Ok(n)
     }
 
-    /// Tries to read up to `n` bytes.
+    /// Tries to read up to `dst.len()` bytes into `dst`.
     /// If successful read i > 0 bytes, returns `Ok(i: usize)`.
     /// If the pipe was empty, returns `Err(WaitForIO)`.
     /// If the process was killed, returns `Err(InvalidStatus)`.
-    fn try_read(
-        &mut self,
-        addr: UVAddr,
-        n: usize,
-        proc: &mut CurrentProc<'_>,
-    ) -> Result<usize, PipeError> {
+    fn try_read(&mut self, dst: UserSlice, proc: &mut CurrentProc<'_>) -> Result<usize, PipeError> {
+        let n = dst.len();
         //DOC: pipe-empty
-        if self.nread == self.nwrite && self.writeopen {
+        if self.nread == self.nwrite && self.writers > 0 {
             if proc.killed() {
                 return Err(PipeError::InvalidStatus);
             }
@@ -240,15 +483,20 @@ impl PipeInner {
         }
 
         //DOC: piperead-copy
-        for i in 0..n {
-            if self.nread == self.nwrite {
+        let mut i = 0;
+        while i < n {
+            let len = self.nwrite.wrapping_sub(self.nread) as usize;
+            if len == 0 {
                 return Ok(i);
             }
-            let ch = [self.data[self.nread as usize % PIPESIZE]];
-            self.nread = self.nread.wrapping_add(1);
-            if proc.memory_mut().copy_out_bytes(addr + i, &ch).is_err() {
+            let nread = self.nread;
+            let chunk = self.chunk(nread);
+            let m = cmp::min(cmp::min(n - i, len), chunk.len());
+            if dst.sub(i, m).write(&chunk[..m], proc.memory_mut()).is_err() {
                 return Ok(i);
             }
+            self.nread = self.nread.wrapping_add(m as u32);
+            i += m;
         }
         Ok(n)
     }
@@ -0,0 +1,211 @@
+//! Memory and devices of the machine, as the device tree describes them.
+//!
+//! The first hart in start() parses the device tree blob that the boot loader
+//! passed.
+//! What cannot be found there keeps the address of qemu -machine virt in
+//! memlayout.rs.
+
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::{cmdline::Cmdline, fdt::Fdt, memlayout, riscv::PagingMode};
+
+/// Number of virtio mmio interfaces remembered while parsing.
+const NVIRTIO: usize = 8;
+
+/// An mmio device.
+#[derive(Copy, Clone)]
+pub struct Device {
+    /// Physical address of the registers.
+    pub base: usize,
+
+    /// PLIC interrupt number.
+    pub irq: usize,
+}
+
+pub struct Platform {
+    /// Physical address range of RAM.
+    pub memory: Range<usize>,
+
+    /// Number of harts.
+    pub ncpu: usize,
+
+    /// The largest page table scheme that every hart supports.
+    pub paging: PagingMode,
+
+    /// Core local interruptor (CLINT), which contains the timer.
+    pub clint: usize,
+
+    /// Platform-level interrupt controller (PLIC).
+    pub plic: usize,
+
+    pub uart: Device,
+
+    /// The first five virtio mmio interfaces: the disk, a keyboard if any, a
+    /// second disk if any, and the two disks of the md device if any.
+    pub virtio: [Device; 5],
+
+    /// The sixth virtio mmio interface, for a serial device for gdb if any.
+    pub gdb_virtio: Device,
+
+    /// Goldfish real-time clock.
+    pub rtc: usize,
+
+    /// Physical memory that holds the initial RAM disk, if the boot loader
+    /// loaded one.
+    pub initrd: Option<Range<usize>>,
+
+    /// SiFive Test Finisher.
+    pub finisher: usize,
+
+    /// The kernel command line.
+    pub cmdline: Cmdline,
+}
+
+static PLATFORM: Once<Platform> = Once::new();
+
+/// The layout of qemu -machine virt.
+static QEMU: Platform = Platform::qemu();
+
+impl Platform {
+    const fn qemu() -> Self {
+        Self {
+            memory: memlayout::KERNBASE..memlayout::PHYSTOP,
+            ncpu: 1,
+            paging: PagingMode::Sv39,
+            clint: memlayout::CLINT,
+            plic: memlayout::PLIC,
+            uart: Device {
+                base: memlayout::UART0,
+                irq: memlayout::UART0_IRQ,
+            },
+            virtio: [
+                Device {
+                    base: memlayout::VIRTIO0,
+                    irq: memlayout::VIRTIO0_IRQ,
+                },
+                Device {
+                    base: memlayout::VIRTIO1,
+                    irq: memlayout::VIRTIO1_IRQ,
+                },
+                Device {
+                    base: memlayout::VIRTIO2,
+                    irq: memlayout::VIRTIO2_IRQ,
+                },
+                Device {
+                    base: memlayout::VIRTIO3,
+                    irq: memlayout::VIRTIO3_IRQ,
+                },
+                Device {
+                    base: memlayout::VIRTIO4,
+                    irq: memlayout::VIRTIO4_IRQ,
+                },
+            ],
+            gdb_virtio: Device {
+                base: memlayout::VIRTIO5,
+                irq: memlayout::VIRTIO5_IRQ,
+            },
+            rtc: memlayout::GOLDFISH_RTC,
+            initrd: None,
+            finisher: memlayout::FINISHER,
+            cmdline: Cmdline::new(),
+        }
+    }
+
+    fn new(fdt: Fdt<'_>) -> Self {
+        let mut platform = Self::qemu();
+        let mut ncpu = 0;
+        let mut sv48 = true;
+        let mut virtio = [Device { base: 0, irq: 0 }; NVIRTIO];
+        let mut nvirtio = 0;
+
+        for node in fdt.nodes() {
+            if node.has_str("device_type", "cpu") {
+                ncpu += 1;
+                sv48 &= node.has_str("mmu-type", "riscv,sv48")
+                    || node.has_str("mmu-type", "riscv,sv57");
+                continue;
+            }
+            // These are in /chosen.
+            if let Some(bootargs) = node.prop("bootargs") {
+                platform.cmdline = Cmdline::from_bytes(bootargs);
+            }
+            if let (Some(start), Some(end)) = (
+                node.number("linux,initrd-start"),
+                node.number("linux,initrd-end"),
+            ) {
+                if start < end {
+                    platform.initrd = Some(start..end);
+                }
+                continue;
+            }
+            let (base, size) = match node.reg(0) {
+                Some(reg) => reg,
+                None => continue,
+            };
+            let irq = node.interrupt();
+            if node.has_str("device_type", "memory") {
+                platform.memory = base..base + size;
+            } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+                platform.clint = base;
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                platform.plic = base;
+            } else if node.is_compatible("ns16550a") {
+                platform.uart = Device {
+                    base,
+                    irq: irq.unwrap_or(memlayout::UART0_IRQ),
+                };
+            } else if node.is_compatible("virtio,mmio") && nvirtio < NVIRTIO {
+                if let Some(irq) = irq {
+                    virtio[nvirtio] = Device { base, irq };
+                    nvirtio += 1;
+                }
+            } else if node.is_compatible("google,goldfish-rtc") {
+                platform.rtc = base;
+            } else if node.is_compatible("sifive,test0") {
+                platform.finisher = base;
+            }
+        }
+
+        if ncpu > 0 {
+            platform.ncpu = ncpu;
+            if sv48 {
+                platform.paging = PagingMode::Sv48;
+            }
+        }
+        // qemu lists virtio interfaces in the reverse order of their addresses.
+        let virtio = &mut virtio[..nvirtio];
+        virtio.sort_unstable_by_key(|device| device.base);
+        for (slot, device) in platform.virtio.iter_mut().zip(virtio.iter()) {
+            *slot = *device;
+        }
+        if let Some(device) = virtio.get(5) {
+            platform.gdb_virtio = *device;
+        }
+        platform
+    }
+}
+
+/// Discover the platform from the device tree blob at `dtb`.
+/// Every hart calls it, and the harts other than the first wait until the
+/// first one finishes.
+///
+/// # Safety
+///
+/// It must be called in machine mode, before the physical page allocator
+/// reuses the memory where the blob is.
+pub unsafe fn platforminit(dtb: usize) {
+    let _ = PLATFORM.call_once(|| {
+        // SAFETY: paging is off, and nothing has overwritten the blob yet.
+        match unsafe { Fdt::from_addr(dtb) } {
+            Some(fdt) => Platform::new(fdt),
+            None => Platform::qemu(),
+        }
+    });
+}
+
+/// The platform discovered at boot, or qemu -machine virt before that.
+pub fn platform() -> &'static Platform {
+    PLATFORM.get().unwrap_or(&QEMU)
+}
@@ -1,20 +1,95 @@
-//! the riscv Platform Level Interrupt Controller (PLIC).
+//! the riscv Platform Level Interrupt Controller (PLIC), and the handlers of
+//! the device interrupts that it delivers.
+//!
+//! A driver attaches a handler to the IRQ of its device with `register`,
+//! which also enables the IRQ on every hart. devintr() calls the handler of
+//! each IRQ that the PLIC delivers.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use crate::{
-    memlayout::{plic_sclaim, plic_senable, plic_spriority, PLIC, UART0_IRQ, VIRTIO0_IRQ},
+    kernel::Kernel,
+    lock::Spinlock,
+    memlayout::{plic_sclaim, plic_senable, plic_spriority},
+    param::NCPU,
+    platform::platform,
     proc::cpuid,
 };
 
-pub unsafe fn plicinit() {
-    // set desired IRQ priorities non-zero (otherwise disabled).
-    unsafe { *((PLIC.wrapping_add(UART0_IRQ.wrapping_mul(4))) as *mut u32) = 1 };
-    unsafe { *((PLIC + VIRTIO0_IRQ * 4) as *mut u32) = 1 };
+/// IRQs that can have handlers. The enable bits of a hart are one word.
+const NIRQ: usize = 32;
+
+/// A handler of a device interrupt. Interrupts are off.
+pub type IrqHandler = fn(&Kernel);
+
+struct Irqs {
+    handlers: [Option<IrqHandler>; NIRQ],
+
+    /// Harts that have called plicinithart(), as a mask.
+    harts: usize,
+}
+
+static IRQS: Spinlock<Irqs> = Spinlock::new(
+    "IRQS",
+    Irqs {
+        handlers: [None; NIRQ],
+        harts: 0,
+    },
+);
+
+/// The IRQs that have handlers, as a mask.
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Calls `handler` for interrupts from `irq`, from now on.
+pub fn register(irq: usize, handler: IrqHandler) {
+    assert!(irq != 0 && irq < NIRQ, "register: bad irq {}", irq);
+    let mut irqs = IRQS.lock();
+    assert!(irqs.handlers[irq].is_none(), "register: irq {} taken", irq);
+    irqs.handlers[irq] = Some(handler);
+
+    // set desired IRQ priority non-zero (otherwise disabled).
+    unsafe { *((platform().plic + irq * 4) as *mut u32) = 1 };
+
+    let enable = ENABLED.fetch_or(1 << irq, Ordering::Relaxed) | 1 << irq;
+    for hart in (0..NCPU).filter(|hart| irqs.harts & 1 << hart != 0) {
+        unsafe { *(plic_senable(hart) as *mut u32) = enable };
+    }
+}
+
+/// Stops or resumes sending device interrupts to `hart`, as it goes offline
+/// or online.
+pub fn set_hart_enabled(hart: usize, enabled: bool) {
+    let mut irqs = IRQS.lock();
+    let enable = if enabled {
+        irqs.harts |= 1 << hart;
+        ENABLED.load(Ordering::Relaxed)
+    } else {
+        irqs.harts &= !(1 << hart);
+        0
+    };
+    unsafe { *(plic_senable(hart) as *mut u32) = enable };
+}
+
+/// Calls the handler of `irq`.
+/// Returns false if it has none.
+pub fn handle(kernel: &Kernel, irq: usize) -> bool {
+    let handler = IRQS.lock().handlers.get(irq).copied().flatten();
+    match handler {
+        Some(handler) => {
+            handler(kernel);
+            true
+        }
+        None => false,
+    }
 }
 
 pub unsafe fn plicinithart() {
     let hart: usize = cpuid();
 
-    // set uart's enable bit for this hart's S-mode.
-    unsafe { *(plic_senable(hart) as *mut u32) = (1 << UART0_IRQ | 1 << VIRTIO0_IRQ) as u32 };
+    // set the enable bits of the registered IRQs for this hart's S-mode.
+    let mut irqs = IRQS.lock();
+    irqs.harts |= 1 << hart;
+    unsafe { *(plic_senable(hart) as *mut u32) = ENABLED.load(Ordering::Relaxed) };
 
     // set this hart's S-mode priority threshold to 0.
     unsafe { *(plic_spriority(hart) as *mut u32) = 0 };
@@ -1,21 +1,65 @@
+//! Powering off and restarting the machine.
+
 use core::ptr;
 
-use crate::memlayout;
+use crate::platform::platform;
+#[cfg(feature = "sbi")]
+use crate::sbi::{self, ResetReason};
+
+/// reboot() command: power off, with the status as the exit code.
+pub const RB_POWEROFF: i32 = 0;
+
+/// reboot() command: restart.
+pub const RB_RESTART: i32 = 1;
+
+/// Test finisher value that restarts the machine.
+const FINISHER_RESET: u32 = 0x7777;
 
 /// Shutdowns this machine, discarding all unsaved data.
 ///
 /// This function uses SiFive Test Finalizer, which provides power management for QEMU virt device.
+/// With the `sbi` feature, it asks the SBI firmware first.
 pub fn machine_poweroff(exitcode: u16) -> ! {
+    #[cfg(feature = "sbi")]
+    sbi::shutdown(if exitcode == 0 {
+        ResetReason::NoReason
+    } else {
+        ResetReason::SystemFailure
+    });
+
     const BASE_CODE: u32 = 0x3333;
     let code = ((exitcode as u32) << 16) | BASE_CODE;
     // SAFETY:
-    // - FINISHER is identically mapped from physical address.
-    // - FINISHER is for MMIO. Though this is not specified as document, see the implementation:
+    // - The finisher is identically mapped from physical address.
+    // - The finisher is for MMIO. Though this is not specified as document, see the implementation:
     // https://github.com/qemu/qemu/blob/stable-5.0/hw/riscv/virt.c#L60 and,
     // https://github.com/qemu/qemu/blob/stable-5.0/hw/riscv/sifive_test.c#L34
     unsafe {
-        ptr::write_volatile(memlayout::FINISHER as *mut u32, code);
+        ptr::write_volatile(platform().finisher as *mut u32, code);
     }
 
     unreachable!("Power off failed");
 }
+
+/// Restarts this machine, discarding all unsaved data.
+///
+/// Like `machine_poweroff`, it asks the SBI firmware first if any, and then the test finisher.
+pub fn machine_reboot() -> ! {
+    #[cfg(feature = "sbi")]
+    sbi::reboot();
+
+    // SAFETY: the same as in `machine_poweroff`.
+    unsafe {
+        ptr::write_volatile(platform().finisher as *mut u32, FINISHER_RESET);
+    }
+
+    unreachable!("Reboot failed");
+}
+
+/// Stops this hart for good, after `KernelBuilder::halt_others`.
+pub fn halt_hart() -> ! {
+    #[cfg(feature = "sbi")]
+    sbi::hart_stop();
+
+    crate::utils::spin_loop()
+}
@@ -0,0 +1,13 @@
+//! Options of prctl(), shared with user programs through kernel/prctl.h.
+
+/// prctl option: whether the process dumps core when it dies from a fault.
+pub const PR_GET_DUMPABLE: i32 = 3;
+
+/// prctl option: set whether the process dumps core, to 0 or 1.
+pub const PR_SET_DUMPABLE: i32 = 4;
+
+/// prctl option: whether the process has a capability.
+pub const PR_CAPBSET_READ: i32 = 23;
+
+/// prctl option: drop a capability for good.
+pub const PR_CAPBSET_DROP: i32 = 24;
@@ -2,6 +2,7 @@
 
 use core::{
     cell::UnsafeCell,
+    cmp,
     mem::{self, MaybeUninit},
     ops::Deref,
     pin::Pin,
@@ -12,19 +13,32 @@ use core::{
 use array_macro::array;
 use pin_project::pin_project;
 
+#[cfg(feature = "lockdep")]
+use crate::lock::HeldLocks;
 use crate::{
-    file::RcFile,
+    capability::Capabilities,
+    fdtable::RcFdTable,
+    fpu::{fpu_off, FpContext},
     fs::RcInode,
     kalloc::Kmem,
     kernel::{kernel, kernel_builder, KernelBuilder},
+    klog,
+    kstat::Counter,
+    kthread::KthreadFn,
     lock::{pop_off, push_off, Guard, RawLock, RemoteSpinlock, Spinlock, SpinlockGuard},
     memlayout::kstack,
     page::Page,
-    param::{MAXPROCNAME, NOFILE, NPROC, ROOTDEV},
+    param::{MAXPROCNAME, NPROC, ROOTDEV},
+    poweroff::machine_poweroff,
     println,
-    riscv::{intr_get, intr_on, r_tp, PGSIZE},
+    ptrace::Ptrace,
+    rcu,
+    ring::Ring,
+    riscv::{intr_get, intr_on, pgroundup, r_tp, PGSIZE},
+    seccomp::Seccomp,
+    suspend, syncd,
     trap::usertrapret,
-    vm::{Addr, UVAddr, UserMemory},
+    vm::{UserMemory, UserPtr},
 };
 
 extern "C" {
@@ -35,6 +49,15 @@ extern "C" {
     static mut trampoline: [u8; 0];
 }
 
+/// Written at the bottom of every kernel stack. A kernel stack that grows
+/// past its page overwrites it before running into the guard page below.
+const STACK_CANARY: usize = 0x5354_4143_4b5f_4f4b;
+
+/// With the `stack-check` feature, a trap panics if fewer bytes than this
+/// are left on the kernel stack, while the panic can still run.
+#[cfg(feature = "stack-check")]
+const STACK_RESERVE: usize = 512;
+
 /// Saved registers for kernel context switches.
 #[derive(Copy, Clone, Default)]
 #[repr(C)]
@@ -71,6 +94,13 @@ pub struct Cpu {
 
     /// Were interrupts enabled before push_off()?
     pub interrupt_enabled: bool,
+
+    /// Depth of interrupt handlers running on this cpu.
+    pub nintr: i32,
+
+    /// Spinlocks that this cpu holds.
+    #[cfg(feature = "lockdep")]
+    pub held_locks: HeldLocks,
 }
 
 /// Per-process data for the trap handling code in trampoline.S.
@@ -87,6 +117,7 @@ pub struct Cpu {
 /// return-to-user path via usertrapret() doesn't return through
 /// the entire kernel call stack.
 #[derive(Copy, Clone)]
+#[repr(C)]
 pub struct TrapFrame {
     /// 0 - kernel page table (satp: Supervisor Address Translation and Protection)
     pub kernel_satp: usize,
@@ -195,16 +226,87 @@ pub struct TrapFrame {
 
     /// 280
     pub t6: usize,
+
+    /// 288 - user FP registers, kept up to date while in the kernel by fpu.rs.
+    pub fp: FpContext,
+}
+
+impl TrapFrame {
+    /// The user registers in the order that gdb numbers them: pc, and then
+    /// x1 to x31.
+    pub fn user_regs(&self) -> [usize; 32] {
+        [
+            self.epc, self.ra, self.sp, self.gp, self.tp, self.t0, self.t1, self.t2, self.s0,
+            self.s1, self.a0, self.a1, self.a2, self.a3, self.a4, self.a5, self.a6, self.a7,
+            self.s2, self.s3, self.s4, self.s5, self.s6, self.s7, self.s8, self.s9, self.s10,
+            self.s11, self.t3, self.t4, self.t5, self.t6,
+        ]
+    }
+
+    /// Set the user registers, given in the order of user_regs().
+    pub fn set_user_regs(&mut self, regs: &[usize; 32]) {
+        let [epc, ra, sp, gp, tp, t0, t1, t2, s0, s1, a0, a1, a2, a3, a4, a5, a6, a7, s2, s3, s4, s5, s6, s7, s8, s9, s10, s11, t3, t4, t5, t6] =
+            *regs;
+        *self = TrapFrame {
+            epc,
+            ra,
+            sp,
+            gp,
+            tp,
+            t0,
+            t1,
+            t2,
+            s0,
+            s1,
+            a0,
+            a1,
+            a2,
+            a3,
+            a4,
+            a5,
+            a6,
+            a7,
+            s2,
+            s3,
+            s4,
+            s5,
+            s6,
+            s7,
+            s8,
+            s9,
+            s10,
+            s11,
+            t3,
+            t4,
+            t5,
+            t6,
+            ..*self
+        };
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Procstate {
-    ZOMBIE,
-    RUNNING,
-    RUNNABLE,
-    SLEEPING,
-    UNUSED,
-    USED,
+    /// Exited, until its parent waits for it.
+    Zombie,
+
+    /// Running on a hart.
+    Running,
+
+    /// Waiting for a hart.
+    Runnable,
+
+    /// Waiting on a wait channel.
+    Sleeping,
+
+    /// Free for alloc().
+    Unused,
+
+    /// Allocated, but not ready to run yet.
+    Used,
+
+    /// Stopped for its tracer, until the tracer resumes it.
+    Stopped,
 }
 
 type Pid = i32;
@@ -223,6 +325,8 @@ impl WaitChannel {
     /// Atomically release lock and sleep on waitchannel.
     /// Reacquires lock when awakened.
     pub fn sleep<R: RawLock, T>(&self, lock_guard: &mut Guard<'_, R, T>, proc: &CurrentProc<'_>) {
+        might_sleep(1);
+
         // Must acquire p->lock in order to
         // change p->state and then call sched.
         // Once we hold p->lock, we can be
@@ -236,7 +340,7 @@ impl WaitChannel {
         lock_guard.reacquire_after(move || {
             // Go to sleep.
             guard.deref_mut_info().waitchannel = self;
-            guard.deref_mut_info().state = Procstate::SLEEPING;
+            guard.deref_mut_info().state = Procstate::Sleeping;
             // SAFETY: we hold `p.lock()`, changed the process's state,
             // and device interrupts are disabled by `push_off()` in `p.lock()`.
             unsafe {
@@ -255,9 +359,17 @@ impl WaitChannel {
 
     /// Wake up all processes sleeping on waitchannel.
     /// Must be called without any p->lock.
-    pub fn wakeup(&self) {
+    pub fn wakeup_all(&self) {
         // TODO: remove kernel()
-        unsafe { kernel() }.procs().wakeup_pool(self)
+        unsafe { kernel() }.procs().wakeup_pool(self, false)
+    }
+
+    /// Wake up a process sleeping on waitchannel, if any, for when only one
+    /// of them can proceed.
+    /// Must be called without any p->lock.
+    pub fn wakeup_one(&self) {
+        // TODO: remove kernel()
+        unsafe { kernel() }.procs().wakeup_pool(self, true)
     }
 }
 
@@ -272,8 +384,14 @@ pub struct ProcInfo {
     /// Exit status to be returned to parent's wait.
     xstate: i32,
 
+    /// Tracing state, other than the tracer.
+    ptrace: Ptrace,
+
     /// Process ID.
     pid: Pid,
+
+    /// Pages of user memory, or 0 if the OOM killer must spare the process.
+    oom_score: usize,
 }
 
 /// ProcBuilder::data are private to the process, so lock need not be held.
@@ -290,26 +408,56 @@ pub struct ProcData {
     /// swtch() here to run process.
     context: Context,
 
-    /// Open files.
-    pub open_files: [Option<RcFile>; NOFILE],
+    /// Open files, which other processes may share.
+    files: MaybeUninit<RcFdTable>,
 
     /// Current directory.
     cwd: MaybeUninit<RcInode>,
 
+    /// Root directory, where absolute paths start.
+    root: MaybeUninit<RcInode>,
+
     /// Process name (debugging).
     pub name: [u8; MAXPROCNAME],
+
+    /// Write a core file if the process dies from a fault.
+    pub dumpable: bool,
+
+    /// The system calls that the process may make.
+    pub seccomp: Seccomp,
+
+    /// The privileged operations that the process may do.
+    pub caps: Capabilities,
+
+    /// The user id, which owns the files that the process creates.
+    pub uid: u32,
+
+    /// The system calls to log, as bits indexed by their numbers.
+    pub trace_mask: u128,
+
+    /// The rings of ring_setup(), in the memory of the process.
+    pub ring: Option<Ring>,
+
+    /// Sleeplocks that the process holds.
+    #[cfg(feature = "lockdep")]
+    pub held_locks: HeldLocks,
+
+    /// The function of a kernel thread, and its argument. A kernel thread
+    /// has no trap frame or user memory.
+    kthread: Option<(KthreadFn, usize)>,
 }
 
 /// Per-process state.
 ///
 /// # Safety
 ///
-/// * If `info.state` ≠ `UNUSED`, then
+/// * If `info.state` ≠ `Unused` and `data.kthread` is `None`, then
 ///   - `data.trap_frame` is a valid pointer, and `Page::from_usize(data.trap_frame)` is safe.
 ///   - `data.memory` has been initialized.
-/// * If `info.state` ∉ { `UNUSED`, `USED` }, then
-///   - `data.cwd` has been initialized.
-///   - `parent` contains null or a valid pointer if it has been initialized.
+/// * `data.kthread` does not change while `info.state` ≠ `Unused`.
+/// * If `info.state` ∉ { `Unused`, `Used` }, then
+///   - `data.files`, `data.cwd`, and `data.root` have been initialized.
+///   - `parent` and `tracer` contain null or a valid pointer if they have been initialized.
 pub struct ProcBuilder {
     /// Parent process.
     ///
@@ -319,6 +467,9 @@ pub struct ProcBuilder {
     /// `RemoteSpinlock::new(&procs.wait_lock, ptr::null_mut())`.
     parent: MaybeUninit<RemoteSpinlock<'static, (), *const Proc>>,
 
+    /// Process that traces this one, initialized along with `parent`.
+    tracer: MaybeUninit<RemoteSpinlock<'static, (), *const Proc>>,
+
     pub info: Spinlock<ProcInfo>,
 
     data: UnsafeCell<ProcData>,
@@ -334,7 +485,7 @@ pub struct ProcBuilder {
 ///
 /// # Safety
 ///
-/// `inner` is current Cpu's proc, this means it's state is `RUNNING`.
+/// `inner` is current Cpu's proc, this means it's state is `Running`.
 pub struct CurrentProc<'p> {
     inner: &'p Proc,
 }
@@ -362,30 +513,63 @@ impl<'p> CurrentProc<'p> {
         unsafe { (*self.info.get_mut_raw()).pid }
     }
 
+    /// The name of the process.
+    pub fn name(&self) -> &str {
+        self.deref_data().name_str()
+    }
+
+    /// The OOM score of the process: its pages of user memory, or 0 if it
+    /// is a kernel thread, or privileged with CAP_SYS_ADMIN.
+    fn oom_score(&self) -> usize {
+        if self.deref_data().kthread.is_some()
+            || self.deref_data().caps.contains(Capabilities::SYS_ADMIN)
+        {
+            return 0;
+        }
+        pgroundup(self.memory().size()) / PGSIZE
+    }
+
+    /// Record the OOM score of the process, after its memory or
+    /// capabilities changed.
+    pub fn update_oom_score(&mut self) {
+        let score = self.oom_score();
+        self.lock().deref_mut_info().oom_score = score;
+    }
+
     pub fn trap_frame(&self) -> &TrapFrame {
+        assert!(self.deref_data().kthread.is_none(), "kernel thread");
         // SAFETY: trap_frame is a valid pointer according to the invariants
         // of ProcBuilder and CurrentProc.
         unsafe { &*self.deref_data().trap_frame }
     }
 
     pub fn trap_frame_mut(&mut self) -> &mut TrapFrame {
+        assert!(self.deref_data().kthread.is_none(), "kernel thread");
         // SAFETY: trap_frame is a valid pointer according to the invariants
         // of ProcBuilder and CurrentProc.
         unsafe { &mut *self.deref_mut_data().trap_frame }
     }
 
     pub fn memory(&self) -> &UserMemory {
+        assert!(self.deref_data().kthread.is_none(), "kernel thread");
         // SAFETY: memory has been initialized according to the invariants
         // of ProcBuilder and CurrentProc.
         unsafe { self.deref_data().memory.assume_init_ref() }
     }
 
     pub fn memory_mut(&mut self) -> &mut UserMemory {
+        assert!(self.deref_data().kthread.is_none(), "kernel thread");
         // SAFETY: memory has been initialized according to the invariants
         // of ProcBuilder and CurrentProc.
         unsafe { self.deref_mut_data().memory.assume_init_mut() }
     }
 
+    pub fn files(&self) -> &RcFdTable {
+        // SAFETY: files has been initialized according to the invariants
+        // of ProcBuilder and CurrentProc.
+        unsafe { self.deref_data().files.assume_init_ref() }
+    }
+
     pub fn cwd(&self) -> &RcInode {
         // SAFETY: cwd has been initialized according to the invariants
         // of ProcBuilder and CurrentProc.
@@ -398,10 +582,27 @@ impl<'p> CurrentProc<'p> {
         unsafe { self.deref_mut_data().cwd.assume_init_mut() }
     }
 
+    pub fn root(&self) -> &RcInode {
+        // SAFETY: root has been initialized according to the invariants
+        // of ProcBuilder and CurrentProc.
+        unsafe { self.deref_data().root.assume_init_ref() }
+    }
+
+    pub fn root_mut(&mut self) -> &mut RcInode {
+        // SAFETY: root has been initialized according to the invariants
+        // of ProcBuilder and CurrentProc.
+        unsafe { self.deref_mut_data().root.assume_init_mut() }
+    }
+
+    /// The tracing state of the process.
+    pub fn ptrace(&self) -> Ptrace {
+        self.lock().deref_info().ptrace
+    }
+
     /// Give up the CPU for one scheduling round.
     pub unsafe fn proc_yield(&self) {
         let mut guard = self.lock();
-        guard.deref_mut_info().state = Procstate::RUNNABLE;
+        guard.deref_mut_info().state = Procstate::Runnable;
         unsafe { guard.sched() };
     }
 }
@@ -456,11 +657,13 @@ impl ProcGuard<'_> {
     unsafe fn sched(&mut self) {
         // TODO: remove kernel_builder()
         assert_eq!((*kernel_builder().current_cpu()).noff, 1, "sched locks");
-        assert_ne!(self.state(), Procstate::RUNNING, "sched running");
+        assert_ne!(self.state(), Procstate::Running, "sched running");
         assert!(!intr_get(), "sched interruptible");
 
         // TODO: remove kernel_builder()
         let interrupt_enabled = unsafe { (*kernel_builder().current_cpu()).interrupt_enabled };
+        // SAFETY: this process is the current process.
+        unsafe { self.deref_mut_data() }.check_kstack();
         unsafe {
             swtch(
                 &mut self.deref_mut_data().context,
@@ -478,29 +681,39 @@ impl ProcGuard<'_> {
     ///
     /// # Safety
     ///
-    /// `self.info.state` ≠ `UNUSED`
+    /// `self.info.state` ≠ `Unused`
     unsafe fn clear(&mut self, mut parent_guard: SpinlockGuard<'_, ()>) {
         // SAFETY: this process cannot be the current process any longer.
         let data = unsafe { self.deref_mut_data() };
-        let trap_frame = mem::replace(&mut data.trap_frame, ptr::null_mut());
-        // TODO: remove kernel_builder()
-        let allocator = &kernel_builder().kmem;
-        // SAFETY: trap_frame uniquely refers to a valid page.
-        allocator.free(unsafe { Page::from_usize(trap_frame as _) });
-        // SAFETY:
-        // * ok to assume_init() because memory has been initialized according to the invariant.
-        // * ok to replace memory with uninit() because state will become UNUSED.
-        unsafe {
-            mem::replace(&mut data.memory, MaybeUninit::uninit())
-                .assume_init()
-                .free(allocator)
-        };
+        if data.kthread.take().is_none() {
+            let trap_frame = mem::replace(&mut data.trap_frame, ptr::null_mut());
+            // TODO: remove kernel_builder()
+            let allocator = &kernel_builder().kmem;
+            // SAFETY: trap_frame uniquely refers to a valid page.
+            allocator.free(unsafe { Page::from_usize(trap_frame as _) });
+            // SAFETY:
+            // * ok to assume_init() because memory has been initialized according to the invariant.
+            // * ok to replace memory with uninit() because state will become Unused.
+            unsafe {
+                mem::replace(&mut data.memory, MaybeUninit::uninit())
+                    .assume_init()
+                    .free(allocator)
+            };
+        }
 
         // Clear the name.
         data.name[0] = 0;
-
-        // Clear the process's parent field.
+        data.dumpable = false;
+        data.seccomp = Seccomp::new();
+        data.caps = Capabilities::all();
+        data.uid = 0;
+        data.trace_mask = 0;
+        data.ring = None;
+        self.deref_mut_info().oom_score = 0;
+
+        // Clear the process's parent and tracer fields.
         *self.parent().get_mut(&mut parent_guard) = ptr::null_mut();
+        *self.tracer().get_mut(&mut parent_guard) = ptr::null_mut();
         drop(parent_guard);
 
         // Clear the `ProcInfo`.
@@ -508,15 +721,16 @@ impl ProcGuard<'_> {
         info.waitchannel = ptr::null();
         info.pid = 0;
         info.xstate = 0;
-        info.state = Procstate::UNUSED;
+        info.ptrace = Ptrace::new();
+        info.state = Procstate::Unused;
 
         self.killed.store(false, Ordering::Release);
     }
 
     /// Wake process from sleep().
     fn wakeup(&mut self) {
-        if self.state() == Procstate::SLEEPING {
-            self.deref_mut_info().state = Procstate::RUNNABLE;
+        if self.state() == Procstate::Sleeping {
+            self.deref_mut_info().state = Procstate::Runnable;
         }
     }
 
@@ -524,6 +738,13 @@ impl ProcGuard<'_> {
         self.deref_info().state
     }
 
+    /// Is the process a kernel thread?
+    fn is_kthread(&self) -> bool {
+        // SAFETY: `kthread` does not change while the process is not `Unused`,
+        // and cannot become `Unused` while the lock is held.
+        unsafe { (*self.data.get()).kthread.is_some() }
+    }
+
     fn reacquire_after<F, U>(&mut self, f: F) -> U
     where
         F: FnOnce(&Proc) -> U,
@@ -558,6 +779,9 @@ impl Cpu {
             context: Context::new(),
             noff: 0,
             interrupt_enabled: false,
+            nintr: 0,
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
         }
     }
 }
@@ -586,12 +810,13 @@ impl Context {
 impl Procstate {
     fn to_str(&self) -> &'static str {
         match self {
-            Procstate::USED => "used",
-            Procstate::UNUSED => "unused",
-            Procstate::SLEEPING => "sleep ",
-            Procstate::RUNNABLE => "runble",
-            Procstate::RUNNING => "run   ",
-            Procstate::ZOMBIE => "zombie",
+            Procstate::Used => "used",
+            Procstate::Unused => "unused",
+            Procstate::Sleeping => "sleep ",
+            Procstate::Runnable => "runble",
+            Procstate::Running => "run   ",
+            Procstate::Zombie => "zombie",
+            Procstate::Stopped => "stop  ",
         }
     }
 }
@@ -603,11 +828,63 @@ impl ProcData {
             trap_frame: ptr::null_mut(),
             memory: MaybeUninit::uninit(),
             context: Context::new(),
-            open_files: [None; NOFILE],
+            files: MaybeUninit::uninit(),
             cwd: MaybeUninit::uninit(),
+            root: MaybeUninit::uninit(),
             name: [0; MAXPROCNAME],
+            dumpable: false,
+            seccomp: Seccomp::new(),
+            caps: Capabilities::all(),
+            uid: 0,
+            trace_mask: 0,
+            ring: None,
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
+            kthread: None,
         }
     }
+
+    /// Process name, up to the first NUL.
+    fn name_str(&self) -> &str {
+        // For null character recognization.
+        // Required since str::from_utf8 cannot recognize interior null characters.
+        let length = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        str::from_utf8(&self.name[0..length]).unwrap_or("???")
+    }
+
+    /// Write the canary at the bottom of the kernel stack.
+    fn init_kstack(&mut self) {
+        // SAFETY: the kernel stack is mapped in the kernel page table, and
+        // only the process that owns it uses it.
+        unsafe { ptr::write_volatile(self.kstack as *mut usize, STACK_CANARY) };
+    }
+
+    /// Panics if the kernel stack has overflowed into its canary.
+    pub fn check_kstack(&self) {
+        // SAFETY: the same as in init_kstack().
+        let canary = unsafe { ptr::read_volatile(self.kstack as *const usize) };
+        assert_eq!(
+            canary,
+            STACK_CANARY,
+            "kernel stack overflow: {}",
+            self.name_str()
+        );
+    }
+
+    /// Panics if `sp` is not within the kernel stack, or too close to its bottom.
+    #[cfg(feature = "stack-check")]
+    pub fn check_sp(&self, sp: usize) {
+        assert!(
+            self.kstack + STACK_RESERVE <= sp && sp <= self.kstack + PGSIZE,
+            "kernel stack exhausted: {} sp={:018p}",
+            self.name_str(),
+            sp as *const u8
+        );
+    }
 }
 
 /// TODO(https://github.com/kaist-cp/rv6/issues/363): pid, state, should be methods of ProcGuard.
@@ -615,13 +892,16 @@ impl ProcBuilder {
     const fn zero() -> Self {
         Self {
             parent: MaybeUninit::uninit(),
+            tracer: MaybeUninit::uninit(),
             info: Spinlock::new(
                 "proc",
                 ProcInfo {
-                    state: Procstate::UNUSED,
+                    state: Procstate::Unused,
                     waitchannel: ptr::null(),
                     xstate: 0,
+                    ptrace: Ptrace::new(),
                     pid: 0,
+                    oom_score: 0,
                 },
             ),
             data: UnsafeCell::new(ProcData::new()),
@@ -687,7 +967,7 @@ impl<'a> Iterator for ProcIter<'a> {
 
 /// # Safety
 ///
-/// `inner.parent` has been initialized.
+/// `inner.parent` and `inner.tracer` have been initialized.
 #[repr(transparent)]
 pub struct Proc {
     inner: ProcBuilder,
@@ -699,6 +979,11 @@ impl Proc {
         unsafe { self.parent.assume_init_ref() }
     }
 
+    fn tracer(&self) -> &RemoteSpinlock<'static, (), *const Proc> {
+        // SAFETY: invariant
+        unsafe { self.tracer.assume_init_ref() }
+    }
+
     /// Kill and wake the process up.
     pub fn kill(&self) {
         self.killed.store(true, Ordering::Release);
@@ -746,6 +1031,9 @@ impl ProcsBuilder {
             let _ = p
                 .parent
                 .write(RemoteSpinlock::new(wait_lock, ptr::null_mut()));
+            let _ = p
+                .tracer
+                .write(RemoteSpinlock::new(wait_lock, ptr::null_mut()));
             p.data.get_mut().kstack = kstack(i);
         }
         // SAFETY: `parent` of every process in `self` has been initialized.
@@ -785,50 +1073,77 @@ impl Procs {
         unsafe { &*(self.inner.initial_proc as *const _) }
     }
 
-    /// Look into process system for an UNUSED proc.
+    /// Look into process system for an Unused proc.
     /// If found, initialize state required to run in the kernel,
     /// and return with p->lock held.
     /// If there are no free procs, or a memory allocation fails, return Err.
     fn alloc(&self, trap_frame: Page, memory: UserMemory) -> Result<ProcGuard<'_>, ()> {
+        let mut user = Some((trap_frame, memory));
+        // Start executing at forkret, which returns to user space.
+        let guard = self.alloc_with(forkret as usize, |data, pid| {
+            // Initialize trap frame and page table.
+            let (trap_frame, mut memory) = user.take().expect("alloc");
+            memory.set_pid(pid);
+            data.trap_frame = trap_frame.into_usize() as _;
+            let _ = data.memory.write(memory);
+        });
+        if let Some((trap_frame, memory)) = user {
+            // TODO: remove kernel_builder()
+            let allocator = &kernel_builder().kmem;
+            allocator.free(trap_frame);
+            memory.free(allocator);
+        }
+        guard
+    }
+
+    /// Look into process system for an Unused proc.
+    /// If found, set it up to start executing at `entry` in the kernel,
+    /// call `init` with its data and pid, and return with p->lock held.
+    /// `init` must set up the data that the invariant requires of a `Used` proc.
+    fn alloc_with<F>(&self, entry: usize, init: F) -> Result<ProcGuard<'_>, ()>
+    where
+        F: FnOnce(&mut ProcData, Pid),
+    {
         for p in self.process_pool() {
             let mut guard = p.lock();
-            if guard.deref_info().state == Procstate::UNUSED {
+            if guard.deref_info().state == Procstate::Unused {
                 // SAFETY: this process cannot be the current process yet.
                 let data = unsafe { guard.deref_mut_data() };
 
-                // Initialize trap frame and page table.
-                data.trap_frame = trap_frame.into_usize() as _;
-                let _ = data.memory.write(memory);
+                let pid = self.allocpid();
+                init(data, pid);
 
-                // Set up new context to start executing at forkret,
-                // which returns to user space.
+                // Set up new context to start executing at entry.
                 data.context = Default::default();
-                data.context.ra = forkret as usize;
+                data.context.ra = entry;
                 data.context.sp = data.kstack + PGSIZE;
+                data.init_kstack();
 
                 let info = guard.deref_mut_info();
-                info.pid = self.allocpid();
-                // It's safe because trap_frame and memory now have been initialized.
-                info.state = Procstate::USED;
+                info.pid = pid;
+                // It's safe because `init` has initialized the data.
+                info.state = Procstate::Used;
 
                 return Ok(guard);
             }
         }
-
-        // TODO: remove kernel_builder()
-        let allocator = &kernel_builder().kmem;
-        allocator.free(trap_frame);
-        memory.free(allocator);
         Err(())
     }
 
+    /// Acquire the `wait_lock`, through any process.
+    fn wait_lock(&self) -> SpinlockGuard<'_, ()> {
+        // Assumes that the process_pool has at least 1 element.
+        self.process_pool().next().unwrap().parent().lock()
+    }
+
     fn allocpid(&self) -> Pid {
         self.inner.nextpid.fetch_add(1, Ordering::Relaxed)
     }
 
-    /// Wake up all processes in the pool sleeping on waitchannel.
+    /// Wake up all processes in the pool sleeping on waitchannel, or only the
+    /// first one if `one`.
     /// Must be called without any p->lock.
-    pub fn wakeup_pool(&self, target: &WaitChannel) {
+    pub fn wakeup_pool(&self, target: &WaitChannel, one: bool) {
         // TODO: remove kernel_builder()
         let current_proc = kernel_builder()
             .current_proc()
@@ -837,7 +1152,10 @@ impl Procs {
             if p as *const _ != current_proc {
                 let mut guard = p.lock();
                 if guard.deref_info().waitchannel == target as _ {
-                    guard.wakeup()
+                    guard.wakeup();
+                    if one {
+                        return;
+                    }
                 }
             }
         }
@@ -876,9 +1194,18 @@ impl Procs {
         let name = b"initcode\x00";
         (&mut data.name[..name.len()]).copy_from_slice(name);
         // TODO: remove kernel_builder()
-        let _ = data.cwd.write(kernel_builder().itable.root());
-        // It's safe because cwd now has been initialized.
-        guard.deref_mut_info().state = Procstate::RUNNABLE;
+        let _ = data.files.write(
+            kernel_builder()
+                .fdtables
+                .alloc_fdtable()
+                .expect("userinit: alloc_fdtable"),
+        );
+        // TODO: remove kernel_builder()
+        let root = kernel_builder().itable.root().expect("userinit: root");
+        let _ = data.cwd.write(root.clone());
+        let _ = data.root.write(root);
+        // It's safe because files, cwd, and root now have been initialized.
+        guard.deref_mut_info().state = Procstate::Runnable;
 
         let initial_proc = guard.deref() as *const _;
         drop(guard);
@@ -900,7 +1227,27 @@ impl Procs {
             let parent = pp.parent().get_mut(parent_guard);
             if *parent == proc {
                 *parent = self.initial_proc();
-                self.initial_proc().child_waitchannel.wakeup();
+                self.initial_proc().child_waitchannel.wakeup_all();
+            }
+        }
+    }
+
+    /// Stop tracing the processes that p traces, and resume them.
+    /// Caller must provide a `SpinlockGuard`.
+    fn untrace<'a: 'b, 'b>(
+        &'a self,
+        proc: *const Proc,
+        parent_guard: &'b mut SpinlockGuard<'_, ()>,
+    ) {
+        for pp in self.process_pool() {
+            let tracer = pp.tracer().get_mut(parent_guard);
+            if *tracer == proc {
+                *tracer = ptr::null();
+                let mut guard = pp.lock();
+                guard.deref_mut_info().ptrace = Ptrace::new();
+                if guard.state() == Procstate::Stopped {
+                    guard.deref_mut_info().state = Procstate::Runnable;
+                }
             }
         }
     }
@@ -913,6 +1260,11 @@ impl Procs {
         let trap_frame =
             scopeguard::guard(allocator.alloc().ok_or(())?, |page| allocator.free(page));
 
+        // Copy the file descriptor table, which increments reference counts
+        // on open files.
+        // TODO: remove kernel_builder()
+        let files = proc.files().copy(&kernel_builder().fdtables)?;
+
         // Copy user memory from parent to child.
         let memory = proc
             .memory_mut()
@@ -932,17 +1284,21 @@ impl Procs {
         // SAFETY: trap_frame has been initialized by alloc.
         unsafe { (*npdata.trap_frame).a0 = 0 };
 
-        // Increment reference counts on open file descriptors.
-        for i in 0..NOFILE {
-            if let Some(file) = &proc.deref_data().open_files[i] {
-                npdata.open_files[i] = Some(file.clone())
-            }
-        }
+        let _ = npdata.files.write(files);
         let _ = npdata.cwd.write(proc.cwd_mut().clone());
+        let _ = npdata.root.write(proc.root_mut().clone());
 
         npdata.name.copy_from_slice(&proc.deref_data().name);
+        npdata.dumpable = proc.deref_data().dumpable;
+        npdata.seccomp = proc.deref_data().seccomp;
+        npdata.caps = proc.deref_data().caps;
+        npdata.uid = proc.deref_data().uid;
+        npdata.trace_mask = proc.deref_data().trace_mask;
+        // The child has a copy of the memory, rings included.
+        npdata.ring = proc.deref_data().ring;
 
         let pid = np.deref_mut_info().pid;
+        np.deref_mut_info().oom_score = proc.oom_score();
 
         // Now drop the guard before we acquire the `wait_lock`.
         // This is because the lock order must be `wait_lock` -> `Proc::info`.
@@ -952,42 +1308,89 @@ impl Procs {
             *np.parent().get_mut(&mut parent_guard) = (*proc).deref();
         });
 
-        // Set the process's state to RUNNABLE.
-        // It does not break the invariant because cwd now has been initialized.
-        np.deref_mut_info().state = Procstate::RUNNABLE;
+        // Set the process's state to Runnable.
+        // It does not break the invariant because files, cwd, and root now have been initialized.
+        np.deref_mut_info().state = Procstate::Runnable;
 
         Ok(pid)
     }
 
-    /// Wait for a child process to exit and return its pid.
+    /// Start a kernel thread named `name` that runs `f` with `arg`.
+    /// Its parent is init, which reaps it when `f` returns.
+    /// Returns Ok(its pid) on success, Err(()) on error.
+    pub fn spawn_kthread(&self, f: KthreadFn, arg: usize, name: &str) -> Result<Pid, ()> {
+        // TODO: remove kernel_builder()
+        let files = kernel_builder().fdtables.alloc_fdtable()?;
+        // TODO: remove kernel_builder()
+        let root = kernel_builder().itable.root()?;
+        let mut guard = self.alloc_with(kthread_start as usize, |data, _| {
+            data.kthread = Some((f, arg));
+        })?;
+        // SAFETY: this process cannot be the current process yet.
+        let data = unsafe { guard.deref_mut_data() };
+
+        let len = cmp::min(data.name.len() - 1, name.len());
+        data.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        data.name[len] = 0;
+        let _ = data.files.write(files);
+        let _ = data.cwd.write(root.clone());
+        let _ = data.root.write(root);
+
+        let pid = guard.deref_info().pid;
+
+        // The lock order must be `wait_lock` -> `Proc::info`, as in fork().
+        guard.reacquire_after(|p| {
+            let mut parent_guard = p.parent().lock();
+            *p.parent().get_mut(&mut parent_guard) = self.initial_proc();
+        });
+
+        // It does not break the invariant because files, cwd, and root now have been initialized.
+        guard.deref_mut_info().state = Procstate::Runnable;
+
+        Ok(pid)
+    }
+
+    /// Wait for a child process to exit and return its pid, and copy its exit
+    /// status to addr if any.
     /// Return Err(()) if this process has no children.
-    pub fn wait(&self, addr: UVAddr, proc: &mut CurrentProc<'_>) -> Result<Pid, ()> {
-        // Assumes that the process_pool has at least 1 element.
-        let some_proc = self.process_pool().next().unwrap();
-        let mut parent_guard = some_proc.parent().lock();
+    pub fn wait(&self, addr: Option<UserPtr<i32>>, proc: &mut CurrentProc<'_>) -> Result<Pid, ()> {
+        let mut parent_guard = self.wait_lock();
 
         loop {
-            // Scan through pool looking for exited children.
+            // Scan through pool looking for exited children, and stopped tracees.
             let mut havekids = false;
             for np in self.process_pool() {
-                if *np.parent().get_mut(&mut parent_guard) == (*proc).deref() {
-                    // Found a child.
+                let is_child = *np.parent().get_mut(&mut parent_guard) == (*proc).deref();
+                let is_tracee = *np.tracer().get_mut(&mut parent_guard) == (*proc).deref();
+                if is_child || is_tracee {
+                    // Found a child or a tracee.
                     // Make sure the child isn't still in exit() or swtch().
                     let mut np = np.lock();
 
-                    havekids = true;
-                    if np.state() == Procstate::ZOMBIE {
+                    if is_tracee && np.state() == Procstate::Stopped {
+                        if let Some(status) = np.deref_info().ptrace.status() {
+                            if let Some(addr) = addr {
+                                addr.write(&status, proc.memory_mut())?;
+                            }
+                            np.deref_mut_info().ptrace.set_reported();
+                            return Ok(np.deref_info().pid);
+                        }
+                    }
+
+                    // A tracee that is not a child can only be waited for until it exits.
+                    havekids |= is_child || np.state() != Procstate::Zombie;
+                    if is_child && np.state() == Procstate::Zombie {
                         let pid = np.deref_mut_info().pid;
-                        if !addr.is_null()
-                            && proc
-                                .memory_mut()
-                                .copy_out(addr, &np.deref_info().xstate)
+                        if let Some(addr) = addr {
+                            if addr
+                                .write(&np.deref_info().xstate, proc.memory_mut())
                                 .is_err()
-                        {
-                            return Err(());
+                            {
+                                return Err(());
+                            }
                         }
                         // Reap the zombie child process.
-                        // SAFETY: np.state() equals ZOMBIE.
+                        // SAFETY: np.state() equals Zombie.
                         unsafe { np.clear(parent_guard) };
                         return Ok(pid);
                     }
@@ -1008,19 +1411,49 @@ impl Procs {
     /// Kill the process with the given pid.
     /// The victim won't exit until it tries to return
     /// to user space (see usertrap() in trap.c).
+    /// Kernel threads, which never do, cannot be killed.
     /// Returns Ok(()) on success, Err(()) on error.
     pub fn kill(&self, pid: Pid) -> Result<(), ()> {
         for p in self.process_pool() {
             let mut guard = p.lock();
             if guard.deref_info().pid == pid {
+                if guard.is_kthread() {
+                    return Err(());
+                }
                 p.kill();
                 guard.wakeup();
+                // A stopped process exits once it runs.
+                if guard.state() == Procstate::Stopped {
+                    guard.deref_mut_info().state = Procstate::Runnable;
+                }
                 return Ok(());
             }
         }
         Err(())
     }
 
+    /// Kill the process with the highest OOM score but init, if it is
+    /// higher than that of `proc`, which failed to get memory.
+    /// Returns Some((the victim's pid, its score)) if it killed one.
+    pub fn oom_kill(&self, proc: &CurrentProc<'_>) -> Option<(Pid, usize)> {
+        let own = proc.lock().deref_info().oom_score;
+        let mut victim: Option<(Pid, usize)> = None;
+        for p in self.process_pool() {
+            let guard = p.lock();
+            let score = guard.deref_info().oom_score;
+            if score > victim.map_or(own, |(_, s)| s)
+                && !p.killed()
+                && !ptr::eq(p, self.initial_proc())
+                && !matches!(guard.state(), Procstate::Unused | Procstate::Zombie)
+            {
+                victim = Some((guard.deref_info().pid, score));
+            }
+        }
+        let (pid, score) = victim?;
+        self.kill(pid).ok()?;
+        Some((pid, score))
+    }
+
     /// Exit the current process.  Does not return.
     /// An exited process remains in the zombie state
     /// until its parent calls wait().
@@ -1031,24 +1464,29 @@ impl Procs {
             "init exiting"
         );
 
-        for file in &mut proc.deref_mut_data().open_files {
-            *file = None;
-        }
+        // SAFETY: CurrentProc's files have been initialized.
+        // It's ok to drop them as proc will not be used any longer.
+        unsafe { proc.deref_mut_data().files.assume_init_drop() };
 
         // TODO(https://github.com/kaist-cp/rv6/issues/290)
         // If self.cwd is not None, the inode inside self.cwd will be dropped
         // by assigning None to self.cwd. Deallocation of an inode may cause
         // disk write operations, so we must begin a transaction here.
+        // The same goes for self.root.
         // TODO: remove kernel_builder()
         let tx = kernel_builder().file_system.begin_transaction();
-        // SAFETY: CurrentProc's cwd has been initialized.
-        // It's ok to drop cwd as proc will not be used any longer.
-        unsafe { proc.deref_mut_data().cwd.assume_init_drop() };
+        // SAFETY: CurrentProc's cwd and root have been initialized.
+        // It's ok to drop them as proc will not be used any longer.
+        unsafe {
+            proc.deref_mut_data().cwd.assume_init_drop();
+            proc.deref_mut_data().root.assume_init_drop();
+        }
         drop(tx);
 
-        // Give all children to init.
+        // Give all children to init, and let go of all tracees.
         let mut parent_guard = proc.parent().lock();
         self.reparent((*proc).deref(), &mut parent_guard);
+        self.untrace((*proc).deref(), &mut parent_guard);
 
         // Parent might be sleeping in wait().
         let parent = *proc.parent().get_mut(&mut parent_guard);
@@ -1057,12 +1495,19 @@ impl Procs {
         assert!(!parent.is_null());
         // SAFETY: parent is a valid pointer according to the invariants of
         // ProcBuilder and CurrentProc.
-        unsafe { (*parent).child_waitchannel.wakeup() };
+        unsafe { (*parent).child_waitchannel.wakeup_all() };
+
+        // So might its tracer.
+        let tracer = *proc.tracer().get_mut(&mut parent_guard);
+        if !tracer.is_null() && tracer != parent {
+            // SAFETY: the same as above.
+            unsafe { (*tracer).child_waitchannel.wakeup_all() };
+        }
 
         let mut guard = proc.lock();
 
         guard.deref_mut_info().xstate = status;
-        guard.deref_mut_info().state = Procstate::ZOMBIE;
+        guard.deref_mut_info().state = Procstate::Zombie;
 
         // Should manually drop since this function never returns.
         drop(parent_guard);
@@ -1073,6 +1518,173 @@ impl Procs {
         unreachable!("zombie exit")
     }
 
+    /// Returns the number of processes, but for kernel threads.
+    pub fn count(&self) -> usize {
+        self.process_pool()
+            .filter(|p| {
+                let guard = p.lock();
+                guard.deref_info().state != Procstate::Unused && !guard.is_kthread()
+            })
+            .count()
+    }
+
+    /// Returns the pid of the parent of the current process, or 0 if it has
+    /// none.
+    pub fn parent_pid(&self, proc: &CurrentProc<'_>) -> Pid {
+        let mut parent_guard = self.wait_lock();
+        let parent = *proc.parent().get_mut(&mut parent_guard);
+        if parent.is_null() {
+            return 0;
+        }
+        // SAFETY: a process reparents its children while holding the
+        // `wait_lock` before it exits, so parent refers to a live process.
+        let pid = unsafe { &*parent }.lock().deref_info().pid;
+        pid
+    }
+
+    /// Make the parent of the current process its tracer.
+    /// Returns Ok(()) on success, Err(()) if it is already traced.
+    pub fn trace_me(&self, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let mut parent_guard = self.wait_lock();
+        let parent = *proc.parent().get_mut(&mut parent_guard);
+        let tracer = proc.tracer().get_mut(&mut parent_guard);
+        if !tracer.is_null() {
+            return Err(());
+        }
+        *tracer = parent;
+        Ok(())
+    }
+
+    /// Make the current process the tracer of the process with the given
+    /// pid, which stops the next time it is about to return to user space.
+    /// Only a child may be attached, unless the current process has
+    /// CAP_SYS_PTRACE.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn attach(&self, pid: Pid, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let privileged = proc.is_privileged(Capabilities::SYS_PTRACE);
+        let mut parent_guard = self.wait_lock();
+        for p in self.process_pool() {
+            if ptr::eq(p, (*proc).deref()) {
+                continue;
+            }
+            let mut guard = p.lock();
+            if guard.deref_info().pid != pid
+                || guard.is_kthread()
+                || !matches!(
+                    guard.state(),
+                    Procstate::Running
+                        | Procstate::Runnable
+                        | Procstate::Sleeping
+                        | Procstate::Stopped
+                )
+            {
+                continue;
+            }
+            let is_child = *p.parent().get_mut(&mut parent_guard) == (*proc).deref();
+            let tracer = p.tracer().get_mut(&mut parent_guard);
+            if !tracer.is_null() || !(is_child || privileged) {
+                return Err(());
+            }
+            *tracer = (*proc).deref();
+            guard.deref_mut_info().ptrace.request_stop();
+            return Ok(());
+        }
+        Err(())
+    }
+
+    /// Stop the current process if it is traced, until its tracer resumes it.
+    /// The tracer sees `reason` in wait().
+    /// Returns whether it is traced.
+    pub fn ptrace_stop(&self, reason: i32, proc: &mut CurrentProc<'_>) -> bool {
+        let mut parent_guard = self.wait_lock();
+        let tracer = *proc.tracer().get_mut(&mut parent_guard);
+        if tracer.is_null() {
+            return false;
+        }
+
+        // The tracer might be sleeping in wait().
+        // SAFETY: tracer is a valid pointer according to the invariants of
+        // ProcBuilder and CurrentProc.
+        unsafe { (*tracer).child_waitchannel.wakeup_all() };
+
+        let mut guard = proc.lock();
+        // A killed process exits instead, as it would not be resumed.
+        if guard.killed() {
+            return true;
+        }
+        guard.deref_mut_info().ptrace.stop(reason);
+        guard.deref_mut_info().state = Procstate::Stopped;
+        drop(parent_guard);
+        unsafe { guard.sched() };
+        true
+    }
+
+    /// Calls `f` with the process with the given pid locked, if the current
+    /// process traces it, and it is stopped.
+    /// Returns Ok(what f returns) on success, Err(()) on error.
+    fn with_stopped_tracee<R, F>(&self, pid: Pid, proc: &mut CurrentProc<'_>, f: F) -> Result<R, ()>
+    where
+        F: FnOnce(&mut ProcGuard<'_>, &mut SpinlockGuard<'_, ()>, &mut CurrentProc<'_>) -> R,
+    {
+        let mut parent_guard = self.wait_lock();
+        for p in self.process_pool() {
+            if *p.tracer().get_mut(&mut parent_guard) == (*proc).deref() {
+                let mut guard = p.lock();
+                if guard.deref_info().pid == pid && guard.state() == Procstate::Stopped {
+                    return Ok(f(&mut guard, &mut parent_guard, proc));
+                }
+            }
+        }
+        Err(())
+    }
+
+    /// Calls `f` with the trap frame and memory of the process with the given
+    /// pid, if the current process traces it, and it is stopped.
+    /// Returns Ok(what f returns) on success, Err(()) on error.
+    pub fn with_tracee<R, F>(&self, pid: Pid, proc: &mut CurrentProc<'_>, f: F) -> Result<R, ()>
+    where
+        F: FnOnce(&mut TrapFrame, &mut UserMemory, &mut CurrentProc<'_>) -> R,
+    {
+        self.with_stopped_tracee(pid, proc, |guard, _, proc| {
+            // SAFETY: a stopped process does not use its data until its tracer resumes it.
+            let data = unsafe { guard.deref_mut_data() };
+            // SAFETY: trap_frame and memory have been initialized according to
+            // the invariants of ProcBuilder, as the process is not Unused.
+            let (trap_frame, memory) =
+                unsafe { (&mut *data.trap_frame, data.memory.assume_init_mut()) };
+            f(trap_frame, memory, proc)
+        })
+    }
+
+    /// Resume the process with the given pid, if the current process traces
+    /// it, and it is stopped. If `syscalls` is true, it stops again at the
+    /// next entry to or exit from a system call.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn resume_tracee(
+        &self,
+        pid: Pid,
+        syscalls: bool,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<(), ()> {
+        self.with_stopped_tracee(pid, proc, |guard, _, _| {
+            let info = guard.deref_mut_info();
+            info.ptrace.set_syscalls(syscalls);
+            info.state = Procstate::Runnable;
+        })
+    }
+
+    /// Stop tracing the process with the given pid, if the current process
+    /// traces it, and it is stopped, and resume it.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn detach_tracee(&self, pid: Pid, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
+        self.with_stopped_tracee(pid, proc, |guard, parent_guard, _| {
+            *guard.tracer().get_mut(parent_guard) = ptr::null();
+            let info = guard.deref_mut_info();
+            info.ptrace = Ptrace::new();
+            info.state = Procstate::Runnable;
+        })
+    }
+
     /// Print a process listing to the console for debugging.
     /// Runs when user types ^P on console.
     /// Doesn't acquire locks in order to avoid wedging a stuck machine further.
@@ -1085,22 +1697,31 @@ impl Procs {
         for p in self.process_pool() {
             let info = p.info.get_mut_raw();
             let state = unsafe { &(*info).state };
-            if *state != Procstate::UNUSED {
-                let name = unsafe { &(*p.data.get()).name };
-                // For null character recognization.
-                // Required since str::from_utf8 cannot recognize interior null characters.
-                let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            if *state != Procstate::Unused {
                 println!(
                     "{} {} {}",
                     unsafe { (*info).pid },
                     Procstate::to_str(state),
-                    str::from_utf8(&name[0..length]).unwrap_or("???")
+                    unsafe { (*p.data.get()).name_str() }
                 );
             }
         }
     }
 }
 
+/// Panics if sleeping now could hang: in an interrupt handler, or while
+/// holding spinlocks other than the `nlocks` ones that sleeping releases.
+pub fn might_sleep(nlocks: i32) {
+    unsafe { push_off() };
+    // TODO: remove kernel_builder()
+    let cpu = kernel_builder().current_cpu();
+    // Do not count the push_off() above.
+    let (nintr, noff) = unsafe { ((*cpu).nintr, (*cpu).noff - 1) };
+    unsafe { pop_off() };
+    assert_eq!(nintr, 0, "might_sleep: in interrupt handler");
+    assert!(noff <= nlocks, "might_sleep: holding {} spinlocks", noff);
+}
+
 /// Return this CPU's ID.
 ///
 /// It is safe to call this function with interrupts enabled, but the returned id may not be the
@@ -1129,17 +1750,26 @@ pub unsafe fn scheduler() -> ! {
     let mut cpu = kernel.current_cpu();
     unsafe { (*cpu).proc = ptr::null_mut() };
     loop {
+        // This hart is between processes, out of any RCU read-side critical section.
+        rcu::quiescent();
+
         // Avoid deadlock by ensuring that devices can interrupt.
         unsafe { intr_on() };
 
+        // Stay here while this hart is offline.
+        suspend::park_if_offline();
+
         for p in kernel.procs().process_pool() {
             let mut guard = p.lock();
-            if guard.state() == Procstate::RUNNABLE {
+            if guard.state() == Procstate::Runnable {
                 // Switch to chosen process.  It is the process's job
                 // to release its lock and then reacquire it
                 // before jumping back to us.
-                guard.deref_mut_info().state = Procstate::RUNNING;
+                guard.deref_mut_info().state = Procstate::Running;
                 unsafe { (*cpu).proc = p as *const _ };
+                // The FP registers may belong to the previous process.
+                fpu_off();
+                kernel.kstat.inc(Counter::ContextSwitch);
                 unsafe { swtch(&mut (*cpu).context, &mut guard.deref_mut_data().context) };
 
                 // Process is done running for now.
@@ -1152,22 +1782,47 @@ pub unsafe fn scheduler() -> ! {
 
 /// A fork child's very first scheduling by scheduler()
 /// will swtch to forkret.
+#[cfg_attr(feature = "ktest", allow(unreachable_code))]
 unsafe fn forkret() {
     // TODO: remove kernel_builder()
     let kernel = kernel_builder();
 
-    let proc = kernel.current_proc().expect("No current proc");
+    #[allow(unused_mut)]
+    let mut proc = kernel.current_proc().expect("No current proc");
     // Still holding p->lock from scheduler.
     unsafe { proc.info.unlock() };
 
     // File system initialization must be run in the context of a
     // regular process (e.g., because it calls sleep), and thus cannot
     // be run from main().
-    kernel.file_system.init(ROOTDEV);
+    if kernel.file_system.init(ROOTDEV).is_err() {
+        klog!(Error, "fs: cannot mount the root file system");
+        machine_poweroff(1);
+    }
+    // SAFETY: the kernel has been initialized.
+    syncd::start(unsafe { crate::kernel::kernel() });
+
+    // Processes run the kernel tests, or their workers, instead of init.
+    #[cfg(feature = "ktest")]
+    crate::ktest::start(unsafe { crate::kernel::kernel() }, &mut proc);
 
     unsafe { usertrapret(proc) };
 }
 
+/// A kernel thread's very first scheduling by scheduler()
+/// will swtch to kthread_start.
+unsafe fn kthread_start() {
+    // SAFETY: kernel threads run only after the initialization of the kernel.
+    let kernel = unsafe { kernel() };
+    let mut proc = kernel.current_proc().expect("No current proc");
+    // Still holding p->lock from scheduler.
+    unsafe { proc.info.unlock() };
+
+    let (f, arg) = proc.deref_data().kthread.expect("kthread_start");
+    f(kernel, &proc, arg);
+    kernel.procs().exit_current(0, &mut proc)
+}
+
 impl KernelBuilder {
     /// Returns `Some<CurrentProc<'_>>` if current proc exists (i.e. When (*cpu).proc is non-null).
     /// Otherwise, returns `None` (when current proc is null).
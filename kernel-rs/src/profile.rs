@@ -0,0 +1,201 @@
+//! Sampling profiler, shared with user programs through kernel/profile.h.
+//!
+//! While profiling is on, each timer interrupt records the pc that it
+//! interrupted, in user or kernel code, with the pid of the current process,
+//! in a ring buffer of the hart. When a buffer is full, the oldest samples
+//! are overwritten.
+//!
+//! The device file /proc/profile, made by init, exports the samples: writing
+//! "1" or "0" to it turns profiling on or off, and reading it takes the
+//! samples out, as `struct profsample`s. user/prof.c makes a flat profile.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use array_macro::array;
+
+use crate::{
+    file::{Devsw, DevswTable},
+    kernel::kernel_builder,
+    lock::Spinlock,
+    param::NCPU,
+    proc::cpuid,
+    some_or,
+    vm::UserSlice,
+};
+
+/// The major device number of /proc/profile.
+const PROFILE_DEVSW: usize = 2;
+
+/// Samples that a hart keeps.
+const NSAMPLE: usize = 512;
+
+/// Size of `struct profsample`.
+const SAMPLESIZE: usize = 16;
+
+/// Samples copied out at a time, without holding a lock.
+const NCOPY: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Sample {
+    pc: usize,
+
+    /// -1 if no process was running.
+    pid: i32,
+
+    /// Was the pc in kernel code?
+    kernel: bool,
+}
+
+struct Samples {
+    buf: [Sample; NSAMPLE],
+
+    /// The oldest sample is at `buf[head]`.
+    head: usize,
+    len: usize,
+}
+
+pub struct Profile {
+    enabled: AtomicBool,
+    cpus: [Spinlock<Samples>; NCPU],
+}
+
+impl Sample {
+    /// The bytes of `struct profsample`.
+    fn to_bytes(self) -> [u8; SAMPLESIZE] {
+        let mut bytes = [0; SAMPLESIZE];
+        bytes[..8].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.pid.to_le_bytes());
+        bytes[12] = self.kernel as u8;
+        bytes
+    }
+}
+
+impl Samples {
+    const fn new() -> Self {
+        Self {
+            buf: [Sample {
+                pc: 0,
+                pid: 0,
+                kernel: false,
+            }; NSAMPLE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, sample: Sample) {
+        self.buf[(self.head + self.len) % NSAMPLE] = sample;
+        if self.len < NSAMPLE {
+            self.len += 1;
+        } else {
+            self.head = (self.head + 1) % NSAMPLE;
+        }
+    }
+
+    fn pop(&mut self) -> Option<Sample> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.buf[self.head];
+        self.head = (self.head + 1) % NSAMPLE;
+        self.len -= 1;
+        Some(sample)
+    }
+}
+
+impl Profile {
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            cpus: array![_ => Spinlock::new("PROFILE", Samples::new()); NCPU],
+        }
+    }
+
+    /// Records that a timer interrupt on this hart interrupted `pc`, if
+    /// profiling is on. Interrupts must be off.
+    pub fn sample(&self, pc: usize, pid: Option<i32>, kernel: bool) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        self.cpus[cpuid()].lock().push(Sample {
+            pc,
+            pid: pid.unwrap_or(-1),
+            kernel,
+        });
+    }
+
+    /// Turns profiling on or off. Turning it on drops the samples of before.
+    fn set_enabled(&self, enabled: bool) {
+        if enabled {
+            for cpu in &self.cpus {
+                let mut samples = cpu.lock();
+                samples.head = 0;
+                samples.len = 0;
+            }
+        }
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Moves samples, oldest first on each hart, to `dst`, as many as fit.
+    /// Returns Ok(the number of bytes copied) on success, Err(()) on error.
+    fn read(&self, dst: UserSlice) -> Result<usize, ()> {
+        let mut proc = kernel_builder().current_proc().ok_or(())?;
+        let mut n = 0;
+        for cpu in &self.cpus {
+            loop {
+                let mut buf = [[0; SAMPLESIZE]; NCOPY];
+                let max = ((dst.len() - n) / SAMPLESIZE).min(NCOPY);
+                let mut count = 0;
+                {
+                    let mut samples = cpu.lock();
+                    while count < max {
+                        buf[count] = some_or!(samples.pop(), break).to_bytes();
+                        count += 1;
+                    }
+                }
+                for bytes in &buf[..count] {
+                    dst.sub(n, SAMPLESIZE).write(bytes, proc.memory_mut())?;
+                    n += SAMPLESIZE;
+                }
+                if count < NCOPY {
+                    break;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+pub fn profileinit(devsw: &DevswTable) {
+    static PROFILE: Devsw = Devsw {
+        read: Some(profileread),
+        write: Some(profilewrite),
+        ioctl: None,
+    };
+    devsw.register(PROFILE_DEVSW, &PROFILE);
+}
+
+/// User read()s from /proc/profile go here.
+fn profileread(_minor: u16, dst: UserSlice, _off: u32) -> i32 {
+    // TODO: remove kernel_builder()
+    kernel_builder().profile.read(dst).map_or(-1, |n| n as i32)
+}
+
+/// User write()s to /proc/profile go here.
+fn profilewrite(_minor: u16, src: UserSlice) -> i32 {
+    if src.is_empty() {
+        return 0;
+    }
+    let mut proc = some_or!(kernel_builder().current_proc(), return -1);
+    let mut c = [0u8];
+    if src.sub(0, 1).read(&mut c, proc.memory_mut()).is_err() {
+        return -1;
+    }
+    match c[0] {
+        // TODO: remove kernel_builder()
+        b'0' => kernel_builder().profile.set_enabled(false),
+        b'1' => kernel_builder().profile.set_enabled(true),
+        _ => return -1,
+    }
+    src.len() as i32
+}
@@ -0,0 +1,110 @@
+//! Tracing of processes by a debugger, shared with user programs through
+//! kernel/ptrace.h.
+//!
+//! A process is traced by its parent after PTRACE_TRACEME, or by another
+//! process after PTRACE_ATTACH. A traced process stops when it faults, or
+//! hits a breakpoint, after exec, and at system calls if its tracer asks.
+//! While it is stopped, its tracer reads and writes its memory and
+//! registers, and then resumes it. The tracer learns of stops from wait(),
+//! as of exits.
+//!
+//! There is no single-stepping: a debugger steps by setting a breakpoint
+//! after the instruction, as debuggers for RISC-V do anyway.
+
+use crate::coredump::SIGTRAP;
+
+/// ptrace request: be traced by the parent.
+pub const PTRACE_TRACEME: i32 = 0;
+
+/// ptrace request: copy the word at an address in the tracee.
+pub const PTRACE_PEEKDATA: i32 = 2;
+
+/// ptrace request: write a word at an address in the tracee.
+pub const PTRACE_POKEDATA: i32 = 5;
+
+/// ptrace request: resume the tracee.
+pub const PTRACE_CONT: i32 = 7;
+
+/// ptrace request: copy the registers of the tracee.
+pub const PTRACE_GETREGS: i32 = 12;
+
+/// ptrace request: set the registers of the tracee.
+pub const PTRACE_SETREGS: i32 = 13;
+
+/// ptrace request: trace a process.
+pub const PTRACE_ATTACH: i32 = 16;
+
+/// ptrace request: stop tracing the tracee, and resume it.
+pub const PTRACE_DETACH: i32 = 17;
+
+/// ptrace request: resume the tracee until it enters or exits a system call.
+pub const PTRACE_SYSCALL: i32 = 24;
+
+/// Why a process stops after it is attached.
+pub const SIGSTOP: i32 = 19;
+
+/// Why a process stops at a system call.
+pub const SYSCALL_STOP: i32 = SIGTRAP | 0x80;
+
+/// The tracing state of a process, other than its tracer.
+#[derive(Copy, Clone)]
+pub struct Ptrace {
+    /// Stop the next time it is about to return to user space.
+    pending: bool,
+
+    /// Stop at every entry to and exit from a system call.
+    syscalls: bool,
+
+    /// Why it stopped last.
+    reason: i32,
+
+    /// Has wait() returned the last stop?
+    reported: bool,
+}
+
+impl Ptrace {
+    pub const fn new() -> Self {
+        Self {
+            pending: false,
+            syscalls: false,
+            reason: 0,
+            reported: true,
+        }
+    }
+
+    pub fn request_stop(&mut self) {
+        self.pending = true;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn set_syscalls(&mut self, syscalls: bool) {
+        self.syscalls = syscalls;
+    }
+
+    pub fn syscalls(&self) -> bool {
+        self.syscalls
+    }
+
+    /// Record a stop for `reason`.
+    pub fn stop(&mut self, reason: i32) {
+        self.pending = false;
+        self.reason = reason;
+        self.reported = false;
+    }
+
+    /// The status that wait() returns for the last stop, unless it has been
+    /// returned already.
+    pub fn status(&self) -> Option<i32> {
+        if self.reported {
+            return None;
+        }
+        Some(self.reason << 8 | 0x7f)
+    }
+
+    pub fn set_reported(&mut self) {
+        self.reported = true;
+    }
+}
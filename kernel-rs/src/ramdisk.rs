@@ -0,0 +1,26 @@
+//! The initial RAM disk, a disk in memory that the boot loader loads along
+//! with the kernel: qemu -initrd fs.img. It stands in for the virtio disk,
+//! so that the kernel can boot without one. What is written to it is lost
+//! when the machine stops.
+
+use core::ops::Range;
+use core::ptr;
+
+use crate::{bio::Buf, param::BSIZE};
+
+/// Reads or writes b from or to block blockno of the RAM disk in the physical
+/// memory `ram`.
+pub fn rw(ram: &Range<usize>, b: &mut Buf, blockno: u32, write: bool) {
+    let addr = ram.start + blockno as usize * BSIZE;
+    assert!(addr + BSIZE <= ram.end, "ramdisk: block out of range");
+    let data = b.deref_inner_mut().data.as_mut_ptr();
+    // SAFETY: the boot loader put the RAM disk in `ram`, which kalloc leaves
+    // alone and the kernel maps, and no other buffer is in it.
+    unsafe {
+        if write {
+            ptr::copy_nonoverlapping(data, addr as *mut u8, BSIZE);
+        } else {
+            ptr::copy_nonoverlapping(addr as *const u8, data, BSIZE);
+        }
+    }
+}
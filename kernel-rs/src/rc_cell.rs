@@ -34,11 +34,25 @@ use core::pin::Pin;
 
 const BORROWED_MUT: usize = usize::MAX;
 
+/// Fills the red zones around the data with the `arena-sanitize` feature.
+#[cfg(feature = "arena-sanitize")]
+const REDZONE: usize = 0xfbfb_fbfb_fbfb_fbfb;
+
 /// Similar to `RefCell<T>`, but provides lifetime-less `Ref<T>` and `RefMut<T>`.
 /// See the module documentation for details.
+///
+/// With the `arena-sanitize` feature, red zones surround the data. They are
+/// checked whenever the last borrow ends, and dereferencing a `Ref` or
+/// `RefMut` whose cell is no longer borrowed panics.
+// It needs repr(C) so that the red zones stay around the data.
+#[cfg_attr(feature = "arena-sanitize", repr(C))]
 pub struct RcCell<T> {
+    #[cfg(feature = "arena-sanitize")]
+    head: usize,
     data: UnsafeCell<T>,
     refcnt: Cell<usize>,
+    #[cfg(feature = "arena-sanitize")]
+    tail: usize,
     _pin: PhantomPinned,
 }
 
@@ -56,12 +70,33 @@ impl<T> RcCell<T> {
     /// Returns a new `RcCell<T>` that owns `data`.
     pub const fn new(data: T) -> Self {
         Self {
+            #[cfg(feature = "arena-sanitize")]
+            head: REDZONE,
             data: UnsafeCell::new(data),
             refcnt: Cell::new(0),
+            #[cfg(feature = "arena-sanitize")]
+            tail: REDZONE,
             _pin: PhantomPinned,
         }
     }
 
+    /// With the `arena-sanitize` feature, panics if the red zones have been
+    /// overwritten.
+    fn check_redzones(&self) {
+        #[cfg(feature = "arena-sanitize")]
+        assert!(
+            self.head == REDZONE && self.tail == REDZONE,
+            "RcCell: red zone overwritten"
+        );
+    }
+
+    /// With the `arena-sanitize` feature, panics if the cell is not borrowed,
+    /// i.e., a `Ref` or `RefMut` to it is used after its object was freed.
+    fn check_borrowed(&self) {
+        #[cfg(feature = "arena-sanitize")]
+        assert!(self.is_borrowed(), "RcCell: use after free");
+    }
+
     /// Returns true if its borrowed immutably or mutably.
     pub fn is_borrowed(&self) -> bool {
         self.refcnt.get() != 0
@@ -164,6 +199,7 @@ impl<T> Deref for Ref<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
+        unsafe { (*self.ptr).check_borrowed() };
         unsafe { &*(*self.ptr).data.get() }
     }
 }
@@ -173,12 +209,16 @@ impl<T> Drop for Ref<T> {
         let refcnt = unsafe { &(*self.ptr).refcnt };
         debug_assert!(refcnt.get() != 0 && refcnt.get() != BORROWED_MUT);
         refcnt.set(refcnt.get() - 1);
+        if refcnt.get() == 0 {
+            unsafe { (*self.ptr).check_redzones() };
+        }
     }
 }
 
 impl<T> RefMut<T> {
     /// Returns a pinned mutable reference to the inner data.
     pub fn get_pin_mut(&mut self) -> Pin<&mut T> {
+        unsafe { (*self.ptr).check_borrowed() };
         // TODO: Add safety reasoning after fixing issue #439
         unsafe { Pin::new_unchecked(&mut *(*self.ptr).data.get()) }
     }
@@ -209,6 +249,7 @@ impl<T> Deref for RefMut<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
+        unsafe { (*self.ptr).check_borrowed() };
         unsafe { &*(*self.ptr).data.get() }
     }
 }
@@ -227,6 +268,7 @@ impl<T> Drop for RefMut<T> {
         unsafe {
             debug_assert!((*self.ptr).refcnt.get() == BORROWED_MUT);
             (*self.ptr).refcnt.set(0);
+            (*self.ptr).check_redzones();
         }
     }
 }
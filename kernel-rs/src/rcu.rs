@@ -0,0 +1,118 @@
+//! Read-copy-update, for data that is read far more often than it changes.
+//!
+//! Readers look at RCU-protected data inside a read-side critical section,
+//! made by `read_lock`, without taking any lock. A section runs with
+//! interrupts off, so it cannot sleep or yield, and the hart stays out of
+//! the scheduler until it ends.
+//!
+//! Each hart counts its trips through the scheduler, its quiescent points.
+//! An updater publishes a new version with `RcuPtr::replace`, and then
+//! `synchronize` waits until every running hart has passed a quiescent
+//! point. By then, no reader can still be looking at the old version, and the
+//! updater may reuse it.
+
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+
+use array_macro::array;
+
+use crate::{
+    lock::{pop_off, push_off},
+    param::NCPU,
+    percpu::PerCpu,
+    proc::cpuid,
+    riscv::intr_get,
+};
+
+/// Trips through the scheduler of each hart. 0 if the hart has not reached
+/// the scheduler yet.
+static QUIESCENT: PerCpu<AtomicUsize> = PerCpu::new(array![_ => AtomicUsize::new(0); NCPU]);
+
+/// A read-side critical section, which ends when this is dropped.
+pub struct ReadGuard {
+    _marker: PhantomData<*const ()>,
+}
+
+/// Begins a read-side critical section.
+pub fn read_lock() -> ReadGuard {
+    unsafe { push_off() };
+    ReadGuard {
+        _marker: PhantomData,
+    }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        unsafe { pop_off() };
+    }
+}
+
+/// Records that this hart is in the scheduler, outside any read-side
+/// critical section.
+pub fn quiescent() {
+    // SAFETY: only this hart writes its counter.
+    let _ = unsafe { &*QUIESCENT.current_raw() }.fetch_add(1, Ordering::Release);
+}
+
+/// Waits until the read-side critical sections that are running now end.
+///
+/// The caller must not be in one, as it would wait for itself.
+pub fn synchronize() {
+    assert!(
+        intr_get(),
+        "rcu::synchronize: in a read-side critical section"
+    );
+    fence(Ordering::SeqCst);
+    let mut seen = [0; NCPU];
+    for (id, seen) in seen.iter_mut().enumerate() {
+        // SAFETY: the counter is atomic.
+        *seen = unsafe { &*QUIESCENT.get_raw(id) }.load(Ordering::Acquire);
+    }
+    let me = cpuid();
+    for (id, seen) in seen.iter().enumerate() {
+        // This hart is not in a section, and a hart that has not reached the
+        // scheduler has not begun one.
+        if id == me || *seen == 0 {
+            continue;
+        }
+        // SAFETY: the counter is atomic.
+        while unsafe { &*QUIESCENT.get_raw(id) }.load(Ordering::Acquire) == *seen {
+            spin_loop();
+        }
+    }
+}
+
+/// A pointer to RCU-protected data, or null.
+pub struct RcuPtr<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> RcuPtr<T> {
+    pub const fn null() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns the current version, which lives as long as the read-side
+    /// critical section.
+    pub fn get<'g>(&self, _guard: &'g ReadGuard) -> Option<&'g T> {
+        let ptr = self.ptr.load(Ordering::Acquire);
+        // SAFETY: by the safety condition of `replace`, the version is valid
+        // until a `synchronize` after it is replaced, which waits for `_guard`.
+        unsafe { ptr.as_ref() }
+    }
+
+    /// Publishes `new` and returns the old version, which readers may still
+    /// be looking at until `synchronize` returns.
+    ///
+    /// # Safety
+    ///
+    /// `new` must be null or valid until it is replaced and a `synchronize`
+    /// after that returns.
+    pub unsafe fn replace(&self, new: *const T) -> *const T {
+        self.ptr.swap(new as *mut T, Ordering::AcqRel)
+    }
+}
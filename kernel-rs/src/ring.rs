@@ -0,0 +1,177 @@
+//! Submission and completion rings of ring_setup(), for batching reads,
+//! writes and syncs, shared with user programs through kernel/ring.h.
+//!
+//! A process sets up two pages of its memory as the rings. It queues
+//! operations in the submission ring, and a single ring_enter() does them
+//! all, in one trap, queueing a completion for each in the completion ring.
+//! Each ring is a `RingHeader` followed by RING_ENTRIES entries. The head
+//! and the tail count the entries ever consumed and produced: user code
+//! advances the tail of the submission ring and the head of the completion
+//! ring, and the kernel the others.
+
+use core::mem;
+
+use static_assertions::const_assert;
+
+use crate::{
+    kernel::Kernel,
+    proc::CurrentProc,
+    riscv::PGSIZE,
+    vm::{UserPtr, UserSlice},
+};
+
+/// Entries of each ring.
+pub const RING_ENTRIES: u32 = 64;
+
+/// Read len bytes from fd into addr, like read().
+pub const RING_READ: u32 = 1;
+
+/// Write len bytes at addr to fd, like write().
+pub const RING_WRITE: u32 = 2;
+
+/// Sync the file systems, like sync(). fd must be open.
+pub const RING_FSYNC: u32 = 3;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct RingHeader {
+    head: u32,
+    tail: u32,
+    _pad: [u32; 2],
+}
+
+/// A submission.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct RingSqe {
+    op: u32,
+    fd: i32,
+    addr: u64,
+    len: u32,
+    _pad: u32,
+    /// Copied into the completion, for user code to match them.
+    data: u64,
+}
+
+/// A completion.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct RingCqe {
+    data: u64,
+    /// What the system call that the operation is like would return.
+    res: i32,
+    _pad: u32,
+}
+
+const_assert!(
+    mem::size_of::<RingHeader>() + RING_ENTRIES as usize * mem::size_of::<RingSqe>() <= PGSIZE
+);
+const_assert!(
+    mem::size_of::<RingHeader>() + RING_ENTRIES as usize * mem::size_of::<RingCqe>() <= PGSIZE
+);
+
+/// The rings of a process, at the addresses of their pages.
+#[derive(Clone, Copy)]
+pub struct Ring {
+    sq: usize,
+    cq: usize,
+}
+
+impl Ring {
+    fn header(addr: usize) -> Result<UserPtr<RingHeader>, ()> {
+        UserPtr::new(addr)
+    }
+
+    fn entry<T>(addr: usize, index: u32) -> Result<UserPtr<T>, ()> {
+        UserPtr::<T>::new(addr + mem::size_of::<RingHeader>())?.add((index % RING_ENTRIES) as usize)
+    }
+}
+
+impl Kernel {
+    /// Set up the pages at the first and the second argument, which must be
+    /// distinct, page-aligned and writable, as the submission and the
+    /// completion ring of the process. Exec forgets them.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_ring_setup(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let sq = proc.argaddr(0)?;
+        let cq = proc.argaddr(1)?;
+        if sq == cq {
+            return Err(());
+        }
+        for &addr in [sq, cq].iter() {
+            let size = proc.memory_mut().size();
+            if addr % PGSIZE != 0 || addr.checked_add(PGSIZE).map_or(true, |end| end > size) {
+                return Err(());
+            }
+            // Check that the page is writable, without changing it.
+            let mut header = RingHeader::default();
+            // SAFETY: RingHeader contains only integers.
+            unsafe { Ring::header(addr)?.read(&mut header, proc.memory_mut()) }?;
+            Ring::header(addr)?.write(&header, proc.memory_mut())?;
+        }
+        proc.deref_mut_data().ring = Some(Ring { sq, cq });
+        Ok(0)
+    }
+
+    /// Do up to n submissions of the rings of the process, the first
+    /// argument, in order, queueing a completion for each. Stops early if the
+    /// submission ring is empty or the completion ring is full.
+    /// Returns Ok(number of submissions done) on success, Err(()) on error.
+    pub fn sys_ring_enter(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let n = proc.argint(0)?;
+        if n < 0 {
+            return Err(());
+        }
+        let ring = proc.deref_data().ring.ok_or(())?;
+        let mut sqh = RingHeader::default();
+        let mut cqh = RingHeader::default();
+        let mut done = 0;
+        while done < n as usize && !proc.killed() {
+            // SAFETY: RingHeader contains only integers.
+            unsafe { Ring::header(ring.sq)?.read(&mut sqh, proc.memory_mut()) }?;
+            unsafe { Ring::header(ring.cq)?.read(&mut cqh, proc.memory_mut()) }?;
+            if sqh.head == sqh.tail || cqh.tail.wrapping_sub(cqh.head) >= RING_ENTRIES {
+                break;
+            }
+            let mut sqe = RingSqe::default();
+            // SAFETY: RingSqe contains only integers.
+            unsafe { Ring::entry(ring.sq, sqh.head)?.read(&mut sqe, proc.memory_mut()) }?;
+            sqh.head = sqh.head.wrapping_add(1);
+            Ring::header(ring.sq)?.write(&sqh, proc.memory_mut())?;
+
+            let res = self.ring_op(&sqe, proc).map_or(-1, |n| n as i32);
+            let cqe = RingCqe {
+                data: sqe.data,
+                res,
+                _pad: 0,
+            };
+            Ring::entry(ring.cq, cqh.tail)?.write(&cqe, proc.memory_mut())?;
+            cqh.tail = cqh.tail.wrapping_add(1);
+            Ring::header(ring.cq)?.write(&cqh, proc.memory_mut())?;
+            done += 1;
+        }
+        Ok(done)
+    }
+
+    /// Do the operation of a submission.
+    /// Returns Ok(what the system call it is like returns) on success,
+    /// Err(()) on error.
+    fn ring_op(&self, sqe: &RingSqe, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let f = proc.files().get(sqe.fd)?;
+        match sqe.op {
+            RING_READ => f.read(UserSlice::new(sqe.addr as usize, sqe.len as usize)?, proc),
+            RING_WRITE => {
+                f.write(
+                    UserSlice::new(sqe.addr as usize, sqe.len as usize)?,
+                    proc,
+                    &self.file_system,
+                )
+            }
+            RING_FSYNC => {
+                self.sync()?;
+                Ok(0)
+            }
+            _ => Err(()),
+        }
+    }
+}
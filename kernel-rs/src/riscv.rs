@@ -56,6 +56,11 @@ pub unsafe fn w_mepc(x: usize) {
 bitflags! {
     /// Supervisor Status Register, sstatus.
     pub struct Sstatus: usize {
+        /// Floating-point unit status: Off (0), Initial, Clean or Dirty.
+        const FS_INITIAL = (1) << 13;
+        const FS_CLEAN = (2) << 13;
+        const FS_DIRTY = (3) << 13;
+
         /// Previous mode, 1=Supervisor, 0=User
         const SPP = (1) << 8;
 
@@ -257,8 +262,34 @@ pub unsafe fn w_mtvec(x: usize) {
 /// Use riscv's sv39 page table scheme.
 pub const SATP_SV39: usize = (8) << 60;
 
-pub const fn make_satp(pagetable: usize) -> usize {
-    SATP_SV39 | pagetable >> 12
+/// Use riscv's sv48 page table scheme.
+pub const SATP_SV48: usize = (9) << 60;
+
+/// riscv's page table schemes.
+#[derive(Copy, Clone)]
+pub enum PagingMode {
+    /// Three levels of page-table pages.
+    Sv39,
+    /// Four levels of page-table pages.
+    Sv48,
+}
+
+impl PagingMode {
+    /// Number of levels of page-table pages.
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+}
+
+pub const fn make_satp(mode: PagingMode, pagetable: usize) -> usize {
+    let mode = match mode {
+        PagingMode::Sv39 => SATP_SV39,
+        PagingMode::Sv48 => SATP_SV48,
+    };
+    mode | pagetable >> 12
 }
 
 /// Supervisor address translation and protection;
@@ -341,6 +372,14 @@ pub fn r_time() -> u64 {
     x
 }
 
+/// Wait for an interrupt.
+#[inline]
+pub fn wfi() {
+    unsafe {
+        asm!("wfi");
+    }
+}
+
 /// Enable device interrupts.
 #[inline]
 pub unsafe fn intr_on() {
@@ -383,6 +422,16 @@ pub fn r_sp() -> usize {
     x
 }
 
+/// Read s0, the frame pointer.
+#[inline]
+pub fn r_fp() -> usize {
+    let mut x;
+    unsafe {
+        asm!("mv {}, s0", out(reg) x);
+    }
+    x
+}
+
 #[inline]
 pub unsafe fn w_tp(x: usize) {
     unsafe {
@@ -436,6 +485,11 @@ bitflags! {
         const X = 1 << 3;
         /// user-accessible
         const U = 1 << 4;
+        /// copy-on-write, for software: writable once it refers to a page of its own.
+        /// Without U, it is inaccessible only until mprotect() allows it.
+        const COW = 1 << 8;
+        /// shared, for software: refers to a page of shared text
+        const SHARED = 1 << 9;
     }
 }
 
@@ -450,7 +504,7 @@ pub fn pte2pa(pte: usize) -> PAddr {
     ((pte >> 10) << 12).into()
 }
 
-/// Extract the three 9-bit page table indices from a virtual address.
+/// Extract the 9-bit page table indices from a virtual address.
 
 /// 9 bits
 pub const PXMASK: usize = 0x1ff;
@@ -464,4 +518,5 @@ pub fn pxshift(level: usize) -> usize {
 /// MAXVA is actually one bit less than the max allowed by
 /// Sv39, to avoid having to sign-extend virtual addresses
 /// that have the high bit set.
+/// Sv48 uses the same virtual addresses, which are all below its own limit.
 pub const MAXVA: usize = (1) << (9 + 9 + 9 + 12 - 1);
@@ -0,0 +1,25 @@
+//! Driver for the Goldfish real-time clock of qemu -machine virt.
+
+use core::ptr;
+
+use crate::platform::platform;
+
+/// Low 32 bits of the time. Reading it latches the high 32 bits.
+const TIME_LOW: usize = 0x00;
+
+/// High 32 bits of the time.
+const TIME_HIGH: usize = 0x04;
+
+/// Returns nanoseconds since the epoch.
+pub fn read_ns() -> u64 {
+    // SAFETY:
+    // * The kernel can access [rtc..rtc+PGSIZE).
+    // * The registers are properly aligned.
+    // * Reading the registers does not have side effects other than latching TIME_HIGH.
+    let rtc = platform().rtc;
+    unsafe {
+        let low = ptr::read_volatile((rtc + TIME_LOW) as *const u32);
+        let high = ptr::read_volatile((rtc + TIME_HIGH) as *const u32);
+        (high as u64) << 32 | low as u64
+    }
+}
@@ -0,0 +1,103 @@
+//! Supervisor Binary Interface (SBI).
+//!
+//! With the `sbi` feature, the kernel runs in supervisor mode under firmware
+//! such as OpenSBI, and asks it through ecalls for what only machine mode can
+//! do: timer interrupts, starting harts, and resetting the machine.
+//! The console stays on the uart, which supervisor mode drives directly.
+//! See the RISC-V SBI specification (https://github.com/riscv/riscv-sbi-doc).
+
+/// Legacy shutdown, for firmware without the system reset extension.
+const EID_LEGACY_SHUTDOWN: usize = 0x08;
+
+/// Timer extension.
+const EID_TIME: usize = 0x54494d45;
+
+/// IPI extension.
+const EID_IPI: usize = 0x735049;
+
+/// Hart state management extension.
+const EID_HSM: usize = 0x48534d;
+
+/// System reset extension.
+const EID_SRST: usize = 0x53525354;
+
+// Reset types of the system reset extension.
+const RESET_SHUTDOWN: usize = 0;
+const RESET_COLD_REBOOT: usize = 1;
+
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// Calls function `fid` of extension `eid`.
+/// Returns Ok(value) on success, Err(error) on error.
+///
+/// # Safety
+///
+/// The function must not break the kernel's assumptions, e.g. on memory.
+unsafe fn ecall(eid: usize, fid: usize, args: [usize; 3]) -> Result<usize, isize> {
+    let error: usize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error as isize)
+    }
+}
+
+/// Ask for a timer interrupt when the time counter reaches `stime_value`,
+/// and clear the pending one.
+pub fn set_timer(stime_value: u64) {
+    // SAFETY: it only programs the timer.
+    let _ = unsafe { ecall(EID_TIME, 0, [stime_value as usize, 0, 0]) };
+}
+
+/// Start hart `hartid` in supervisor mode at physical address `start_addr`,
+/// with its hartid in a0 and `opaque` in a1.
+/// Returns Ok(()) on success, Err(()) on error.
+///
+/// # Safety
+///
+/// `start_addr` must be the kernel's entry point.
+pub unsafe fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), ()> {
+    unsafe { ecall(EID_HSM, 0, [hartid, start_addr, opaque]) }
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Send a supervisor software interrupt to the harts in `hart_mask`.
+pub fn send_ipi(hart_mask: usize) {
+    // SAFETY: the interrupt is handled as any other.
+    let _ = unsafe { ecall(EID_IPI, 0, [hart_mask, 0, 0]) };
+}
+
+/// Stop this hart. Returns only if the firmware cannot do it.
+pub fn hart_stop() {
+    // SAFETY: this hart stops, or nothing happens.
+    let _ = unsafe { ecall(EID_HSM, 1, [0; 3]) };
+}
+
+/// Shut down the machine. Returns only if the firmware cannot do it.
+pub fn shutdown(reason: ResetReason) {
+    // SAFETY: the machine is shut down, or nothing happens.
+    let _ = unsafe { ecall(EID_SRST, 0, [RESET_SHUTDOWN, reason as usize, 0]) };
+    // SAFETY: the same as above.
+    let _ = unsafe { ecall(EID_LEGACY_SHUTDOWN, 0, [0; 3]) };
+}
+
+/// Restart the machine. Returns only if the firmware cannot do it.
+pub fn reboot() {
+    // SAFETY: the machine is restarted, or nothing happens.
+    let _ = unsafe { ecall(EID_SRST, 0, [RESET_COLD_REBOOT, 0, 0]) };
+}
@@ -0,0 +1,94 @@
+//! Per-process filters of system calls, shared with user programs through
+//! kernel/seccomp.h.
+//!
+//! A filter is a bitmap of the system call numbers that a process may make.
+//! Forked children inherit the filter of their parent, and exec keeps it.
+//! Once locked, the filter cannot change for the rest of the process's life.
+
+use crate::some_or;
+
+/// seccomp operation: allow only the system calls in a struct seccomp_filter.
+pub const SECCOMP_SET_FILTER: i32 = 1;
+
+/// seccomp operation: forbid further changes to the filter.
+pub const SECCOMP_LOCK: i32 = 2;
+
+/// Number of 64-bit words in `SeccompFilter::allow`.
+pub const SECCOMP_NWORDS: usize = 2;
+
+/// Action for denied system calls: fail with -1.
+pub const SECCOMP_RET_ERRNO: i32 = 0;
+
+/// Action for denied system calls: kill the process.
+pub const SECCOMP_RET_KILL: i32 = 1;
+
+/// exit, which is always allowed, so that a filtered process can still end.
+const SYS_EXIT: i32 = 2;
+
+#[derive(Copy, Clone)]
+// It needs repr(C) because it is passed by user programs.
+#[repr(C)]
+pub struct SeccompFilter {
+    /// Bit `n % 64` of `allow[n / 64]` is set if system call `n` is allowed.
+    pub allow: [u64; SECCOMP_NWORDS],
+
+    /// SECCOMP_RET_ERRNO or SECCOMP_RET_KILL.
+    pub action: i32,
+}
+
+/// The system call filter of a process.
+#[derive(Copy, Clone)]
+pub struct Seccomp {
+    /// None if every system call is allowed.
+    filter: Option<SeccompFilter>,
+
+    locked: bool,
+}
+
+impl Seccomp {
+    /// Allows every system call, and is not locked.
+    pub const fn new() -> Self {
+        Self {
+            filter: None,
+            locked: false,
+        }
+    }
+
+    /// Is system call `num` allowed?
+    pub fn allows(&self, num: i32) -> bool {
+        let filter = some_or!(&self.filter, return true);
+        num == SYS_EXIT
+            || (num >= 0
+                && filter
+                    .allow
+                    .get(num as usize / 64)
+                    .map_or(false, |word| word & 1 << (num % 64) != 0))
+    }
+
+    /// Should a denied system call kill the process?
+    pub fn kills(&self) -> bool {
+        self.filter
+            .map_or(false, |filter| filter.action == SECCOMP_RET_KILL)
+    }
+
+    /// Replace the filter.
+    /// Returns Ok(()) on success, Err(()) if locked, or if the filter is invalid.
+    pub fn set(&mut self, filter: SeccompFilter) -> Result<(), ()> {
+        if self.locked || (filter.action != SECCOMP_RET_ERRNO && filter.action != SECCOMP_RET_KILL)
+        {
+            return Err(());
+        }
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    /// Forbid further changes.
+    /// Returns Ok(()) on success, Err(()) if already locked.
+    pub fn lock(&mut self) -> Result<(), ()> {
+        if self.locked {
+            return Err(());
+        }
+        self.locked = true;
+        Ok(())
+    }
+}
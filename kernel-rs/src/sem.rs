@@ -0,0 +1,157 @@
+//! Named semaphores.
+//!
+//! sem_open() returns a file descriptor of the semaphore with a name, which
+//! processes share by opening the same name, without sharing memory. A
+//! semaphore lives while a file descriptor refers to it, and goes away on
+//! the last close, with its name.
+
+use core::cell::Cell;
+use core::convert::TryFrom;
+
+use crate::{
+    arena::{Arena, ArenaObject, ArrayArena, Rc},
+    fcntl::FcntlFlags,
+    file::FileType,
+    kernel::Kernel,
+    lock::{Sleepablelock, Spinlock},
+    param::{NSEM, SEMNAME},
+    proc::CurrentProc,
+};
+
+pub type Semaphores = Spinlock<ArrayArena<Semaphore, NSEM>>;
+
+pub type RcSemaphore = Rc<Semaphores>;
+
+pub struct Semaphore {
+    name: [u8; SEMNAME],
+    len: usize,
+    value: Sleepablelock<u32>,
+}
+
+impl Semaphore {
+    pub const fn zero() -> Self {
+        Self {
+            name: [0; SEMNAME],
+            len: 0,
+            value: Sleepablelock::new("sem", 0),
+        }
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+
+    /// Decrements the value, waiting while it is 0.
+    /// Returns Ok(()) on success, Err(()) if the process is killed meanwhile.
+    pub fn wait(&self, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let mut value = self.value.lock();
+        while *value == 0 {
+            if proc.killed() {
+                return Err(());
+            }
+            value.sleep();
+        }
+        *value -= 1;
+        Ok(())
+    }
+
+    /// Increments the value, and wakes up the processes waiting for it.
+    pub fn post(&self) -> Result<(), ()> {
+        let mut value = self.value.lock();
+        *value = value.checked_add(1).ok_or(())?;
+        value.wakeup_all();
+        Ok(())
+    }
+}
+
+#[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
+impl const Default for Semaphore {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl ArenaObject for Semaphore {
+    fn finalize<'s, A: Arena>(&'s mut self, _guard: &'s mut A::Guard<'_>) {
+        self.len = 0;
+    }
+}
+
+impl Semaphores {
+    pub const fn zero() -> Self {
+        Spinlock::new("SEMAPHORES", ArrayArena::<Semaphore, NSEM>::new())
+    }
+
+    /// Returns the semaphore named name. If there is none and create is
+    /// true, makes one whose value is value.
+    fn open(&self, name: &[u8], create: bool, value: u32) -> Result<RcSemaphore, ()> {
+        if name.is_empty() || name.len() > SEMNAME {
+            return Err(());
+        }
+        let created = Cell::new(false);
+        let sem = self
+            .find_or_alloc(
+                |sem| sem.name() == name,
+                |sem| {
+                    sem.name[..name.len()].copy_from_slice(name);
+                    sem.len = name.len();
+                    *sem.value.get_mut() = value;
+                    created.set(true);
+                },
+            )
+            .ok_or(())?;
+        // Dropping sem frees the semaphore that was just made.
+        if created.get() && !create {
+            return Err(());
+        }
+        Ok(sem)
+    }
+}
+
+impl Kernel {
+    /// Open the semaphore named name. With O_CREATE in flags, make it with
+    /// value as its value if there is none. flags may also contain O_CLOEXEC.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    pub fn sys_sem_open(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut name = [0; SEMNAME + 1];
+        let name = proc.argstr(0, &mut name)?;
+        let flags = FcntlFlags::from_bits(proc.argint(1)?).ok_or(())?;
+        if !(FcntlFlags::O_CREATE | FcntlFlags::O_CLOEXEC).contains(flags) {
+            return Err(());
+        }
+        let value = u32::try_from(proc.argint(2)?).map_err(|_| ())?;
+        let sem =
+            self.semaphores
+                .open(name.to_bytes(), flags.contains(FcntlFlags::O_CREATE), value)?;
+        let f = self
+            .ftable
+            .alloc_file(FileType::Semaphore { sem }, false, false)?;
+        let fd = proc
+            .files()
+            .alloc(f, flags.contains(FcntlFlags::O_CLOEXEC))
+            .map_err(|_| ())?;
+        Ok(fd as usize)
+    }
+
+    /// Wait for the semaphore of file descriptor fd, and decrement it.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_sem_wait(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let f = proc.files().get(proc.argint(0)?)?;
+        match &f.typ {
+            FileType::Semaphore { sem } => sem.wait(proc)?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Increment the semaphore of file descriptor fd.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_sem_post(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let f = proc.files().get(proc.argint(0)?)?;
+        match &f.typ {
+            FileType::Semaphore { sem } => sem.post()?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+}
@@ -1,15 +1,36 @@
+#[cfg(feature = "sbi")]
+use core::{
+    cmp,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 use crate::{
     kernel::kernel_main,
-    memlayout::{clint_mtimecmp, CLINT_MTIME},
     param::NCPU,
+    platform::platforminit,
+    riscv::{w_tp, SIE},
+};
+#[cfg(not(feature = "sbi"))]
+use crate::{
+    memlayout::{clint_mtime, clint_mtimecmp},
     riscv::{
-        r_mhartid, w_medeleg, w_mepc, w_mideleg, w_mscratch, w_mtvec, w_satp, w_tp, Mstatus, MIE,
-        SIE,
+        r_mcounteren, r_mhartid, w_mcounteren, w_medeleg, w_mepc, w_mideleg, w_mscratch, w_mtvec,
+        w_satp, Mstatus, MIE,
     },
 };
+#[cfg(feature = "sbi")]
+use crate::{platform::platform, riscv::r_time, sbi};
+
+/// Cycles between timer interrupts; about 1/10th second in qemu.
+pub const TIMER_INTERVAL: usize = 1_000_000;
 
 extern "C" {
+    // assembly code in entry.S, where every hart starts.
+    #[cfg(feature = "sbi")]
+    fn _entry();
+
     // assembly code in kernelvec.S for machine-mode timer interrupt.
+    #[cfg(not(feature = "sbi"))]
     fn timervec();
 }
 
@@ -27,11 +48,17 @@ impl Stack {
 pub static mut stack0: Stack = Stack::new();
 
 /// A scratch area per CPU for machine-mode timer interrupts.
+#[cfg(not(feature = "sbi"))]
 static mut TIMER_SCRATCH: [[usize; NCPU]; 5] = [[0; NCPU]; 5];
 
-/// entry.S jumps here in machine mode on stack0.
+/// entry.S jumps here in machine mode on stack0,
+/// with the hartid and the address of the device tree blob.
+#[cfg(not(feature = "sbi"))]
 #[no_mangle]
-pub unsafe fn start() {
+pub unsafe extern "C" fn start(hartid: usize, dtb: usize) {
+    // find memory and devices before anything else uses them.
+    unsafe { platforminit(dtb) };
+
     // set M Previous Privilege mode to Supervisor, for mret.
     let mut x = Mstatus::read();
     x.remove(Mstatus::MPP_MASK);
@@ -56,8 +83,11 @@ pub unsafe fn start() {
     // ask for clock interrupts.
     unsafe { timerinit() };
 
+    // allow supervisor mode to read the time counter.
+    unsafe { w_mcounteren(r_mcounteren() | 2) };
+
     // keep each CPU's hartid in its tp register, for cpuid().
-    unsafe { w_tp(r_mhartid()) };
+    unsafe { w_tp(hartid) };
 
     unsafe {
         // switch to supervisor mode and jump to main().
@@ -65,16 +95,52 @@ pub unsafe fn start() {
     }
 }
 
+/// entry.S jumps here in supervisor mode on stack0,
+/// with the hartid and the address of the device tree blob.
+/// The SBI firmware jumps to entry.S on one hart, which starts the others.
+#[cfg(feature = "sbi")]
+#[no_mangle]
+pub unsafe extern "C" fn start(hartid: usize, dtb: usize) -> ! {
+    static BOOTED: AtomicBool = AtomicBool::new(false);
+
+    // find memory and devices before anything else uses them.
+    unsafe { platforminit(dtb) };
+
+    // keep each CPU's hartid in its tp register, for cpuid().
+    unsafe { w_tp(hartid) };
+
+    if !BOOTED.swap(true, Ordering::AcqRel) {
+        for id in 0..cmp::min(platform().ncpu, NCPU) {
+            if id != hartid {
+                // SAFETY: _entry is the kernel's entry point.
+                let _ = unsafe { sbi::hart_start(id, _entry as usize, dtb) };
+            }
+        }
+    }
+
+    let mut x = SIE::read();
+    x.insert(SIE::SEIE);
+    x.insert(SIE::STIE);
+    x.insert(SIE::SSIE);
+    unsafe { x.write() };
+
+    // ask for clock interrupts.
+    sbi::set_timer(r_time() + TIMER_INTERVAL as u64);
+
+    unsafe { kernel_main() }
+}
+
 /// set up to receive timer interrupts in machine mode,
 /// which arrive at timervec in kernelvec.S,
 /// which turns them into software interrupts for devintr() in trap.c.
+#[cfg(not(feature = "sbi"))]
 unsafe fn timerinit() {
     // each CPU has a separate source of timer interrupts.
     let id = r_mhartid();
 
     // ask the CLINT for a timer interrupt.
-    let interval: usize = 1_000_000; // cycles; about 1/10th second in qemu.
-    unsafe { *(clint_mtimecmp(id) as *mut usize) = (*(CLINT_MTIME as *mut usize)) + interval };
+    let interval = TIMER_INTERVAL;
+    unsafe { *(clint_mtimecmp(id) as *mut usize) = (*(clint_mtime() as *mut usize)) + interval };
 
     // prepare information in scratch[] for timervec.
     // scratch[0..2] : space for timervec to save registers.
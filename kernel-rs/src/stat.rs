@@ -16,3 +16,26 @@ pub struct Stat {
     /// Size of file in bytes
     pub size: usize,
 }
+
+/// Usage of a file system.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Statfs {
+    /// Block size in bytes
+    pub bsize: u32,
+
+    /// Size of file system image (blocks)
+    pub blocks: u32,
+
+    /// Number of free blocks
+    pub bfree: u32,
+
+    /// Number of inodes
+    pub files: u32,
+
+    /// Number of free inodes
+    pub ffree: u32,
+}
+
+/// The major number that makes mknod() create a FIFO.
+pub const FIFO: i32 = -1;
@@ -0,0 +1,127 @@
+//! Taking harts offline, and suspending the system to idle.
+//!
+//! cpu_offline() asks a hart to go offline, and the hart parks the next time
+//! it is in the scheduler, between processes. There is one run queue, the
+//! process table, so the processes of the hart need not move: the other
+//! harts simply run them. A parked hart takes no device interrupts, and
+//! waits for interrupts with wfi, but for the timer interrupts, at which it
+//! passes an RCU quiescent point and checks whether cpu_online() brought it
+//! back. The boot hart, hart 0, never goes offline, so there is always one
+//! hart to run processes.
+//!
+//! suspend() writes out the file system, takes every hart but the boot hart
+//! offline, idles for the given ticks, and brings them back.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    capability::Capabilities,
+    kernel::Kernel,
+    klog,
+    platform::platform,
+    plic,
+    proc::{cpuid, CurrentProc},
+    rcu,
+    riscv::wfi,
+};
+
+/// The harts asked to go offline, as a mask.
+static OFFLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// The harts that are parked, as a mask.
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Parks this hart while it is asked to be offline. The scheduler calls it
+/// between processes, with interrupts on.
+pub fn park_if_offline() {
+    let hart = cpuid();
+    if OFFLINE.load(Ordering::Acquire) & 1 << hart == 0 {
+        return;
+    }
+    plic::set_hart_enabled(hart, false);
+    let _ = PARKED.fetch_or(1 << hart, Ordering::AcqRel);
+    klog!(Info, "hart {} offline", hart);
+    while OFFLINE.load(Ordering::Acquire) & 1 << hart != 0 {
+        rcu::quiescent();
+        wfi();
+    }
+    let _ = PARKED.fetch_and(!(1 << hart), Ordering::AcqRel);
+    plic::set_hart_enabled(hart, true);
+    klog!(Info, "hart {} online", hart);
+}
+
+impl Kernel {
+    /// Asks the harts in mask to go offline, and waits until they park.
+    /// Returns Err(()) if the process is killed meanwhile.
+    fn offline(&self, mask: usize, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let _ = OFFLINE.fetch_or(mask, Ordering::AcqRel);
+        let mut ticks = self.ticks.lock();
+        while PARKED.load(Ordering::Acquire) & mask != mask {
+            if proc.killed() {
+                return Err(());
+            }
+            ticks.sleep();
+        }
+        Ok(())
+    }
+
+    /// Returns the mask of the hart that the nth system call argument
+    /// names, which must be an existing hart but the boot hart, if the
+    /// process has CAP_SYS_ADMIN.
+    fn arghart(&self, n: usize, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        let hart = proc.argint(n)?;
+        if !proc.is_privileged(Capabilities::SYS_ADMIN)
+            || hart <= 0
+            || hart as usize >= platform().ncpu
+        {
+            return Err(());
+        }
+        Ok(1 << hart)
+    }
+
+    /// Take hart offline. It needs CAP_SYS_ADMIN.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_cpu_offline(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mask = self.arghart(0, proc)?;
+        self.offline(mask, proc)?;
+        Ok(0)
+    }
+
+    /// Bring hart back online. It needs CAP_SYS_ADMIN.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_cpu_online(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mask = self.arghart(0, proc)?;
+        let _ = OFFLINE.fetch_and(!mask, Ordering::AcqRel);
+        Ok(0)
+    }
+
+    /// Write out the file system, and idle for n ticks with only the boot
+    /// hart online. It needs CAP_SYS_ADMIN.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_suspend(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let n = proc.argint(0)?;
+        if !proc.is_privileged(Capabilities::SYS_ADMIN) || n < 0 {
+            return Err(());
+        }
+        self.sync()?;
+
+        // Harts that are offline already stay so.
+        let all = (1 << platform().ncpu) - 1;
+        let mask = all & !1 & !OFFLINE.load(Ordering::Acquire);
+        let result = self.offline(mask, proc).and_then(|_| {
+            klog!(Info, "suspended for {} ticks", n);
+            let mut ticks = self.ticks.lock();
+            let ticks0 = *ticks;
+            while ticks.wrapping_sub(ticks0) < n as u32 {
+                if proc.killed() {
+                    return Err(());
+                }
+                ticks.sleep();
+            }
+            Ok(())
+        });
+        let _ = OFFLINE.fetch_and(!mask, Ordering::AcqRel);
+        klog!(Info, "resumed");
+        result.map(|_| 0)
+    }
+}
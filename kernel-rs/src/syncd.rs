@@ -0,0 +1,30 @@
+//! The sync daemon, a kernel thread that syncs the file systems every
+//! SYNC_INTERVAL clock ticks, so that a crash loses at most what was written
+//! since, even by programs that never call sync().
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{kernel::Kernel, kthread, param::SYNC_INTERVAL, proc::CurrentProc};
+
+/// Has the daemon started?
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts the daemon unless it has started. Must be called after the file
+/// system is ready.
+pub fn start(kernel: &Kernel) {
+    if !STARTED.swap(true, Ordering::AcqRel) {
+        let _ = kthread::spawn(kernel, run, 0, "syncd").expect("syncd: spawn");
+    }
+}
+
+fn run(kernel: &Kernel, _proc: &CurrentProc<'_>, _arg: usize) {
+    loop {
+        let mut ticks = kernel.ticks.lock();
+        let ticks0 = *ticks;
+        while ticks.wrapping_sub(ticks0) < SYNC_INTERVAL {
+            ticks.sleep();
+        }
+        drop(ticks);
+        let _ = kernel.sync();
+    }
+}
@@ -1,16 +1,154 @@
-use core::{mem, str};
+use core::fmt::Write;
+use core::str;
 
+use arrayvec::ArrayString;
 use cstr_core::CStr;
 
 use crate::{
     kernel::Kernel,
-    println,
+    klog,
     proc::CurrentProc,
-    vm::{Addr, UVAddr},
+    vm::{UserPtr, UserSlice},
 };
 
+/// How the tracer prints a system call argument.
+#[derive(Copy, Clone)]
+enum Arg {
+    Int,
+    Str,
+    Ptr,
+}
+
+/// Names and arguments of the system calls, indexed by their numbers.
+const SYSCALLS: [(&str, &[Arg]); 74] = {
+    use Arg::*;
+    [
+        ("", &[]),
+        ("fork", &[]),
+        ("exit", &[Int]),
+        ("wait", &[Ptr]),
+        ("pipe", &[Ptr]),
+        ("read", &[Int, Ptr, Int]),
+        ("kill", &[Int]),
+        ("exec", &[Str, Ptr]),
+        ("fstat", &[Int, Ptr]),
+        ("chdir", &[Str]),
+        ("dup", &[Int]),
+        ("getpid", &[]),
+        ("sbrk", &[Int]),
+        ("sleep", &[Int]),
+        ("uptime", &[]),
+        ("open", &[Str, Int]),
+        ("write", &[Int, Ptr, Int]),
+        ("mknod", &[Str, Int, Int]),
+        ("unlink", &[Str]),
+        ("link", &[Str, Str]),
+        ("mkdir", &[Str]),
+        ("close", &[Int]),
+        ("poweroff", &[Int]),
+        ("ioctl", &[Int, Int, Ptr]),
+        ("gettimeofday", &[Ptr]),
+        ("clock_gettime", &[Int, Ptr]),
+        ("reboot", &[Int, Int]),
+        ("execve", &[Str, Ptr, Ptr]),
+        ("prctl", &[Int, Int]),
+        ("mprotect", &[Ptr, Int, Int]),
+        ("seccomp", &[Int, Ptr]),
+        ("chroot", &[Str]),
+        ("dmesg", &[Ptr, Int]),
+        ("trace", &[Ptr, Ptr]),
+        ("ptrace", &[Int, Int, Ptr, Ptr]),
+        ("faultinject", &[Int, Int]),
+        ("fcntl", &[Int, Int, Int]),
+        ("sendfile", &[Int, Int, Ptr, Int]),
+        ("pipe2", &[Ptr, Int]),
+        ("access", &[Str, Int]),
+        ("statfs", &[Str, Ptr]),
+        ("fstatfs", &[Int, Ptr]),
+        ("lseek", &[Int, Int, Int]),
+        ("fallocate", &[Int, Int, Int, Int]),
+        ("flock", &[Int, Int]),
+        ("mount", &[Int, Str, Int]),
+        ("umount", &[Str]),
+        ("sync", &[]),
+        ("getcwd", &[Ptr, Int]),
+        ("openat", &[Int, Str, Int]),
+        ("mkdirat", &[Int, Str]),
+        ("unlinkat", &[Int, Str, Int]),
+        ("madvise", &[Ptr, Int, Int]),
+        ("ring_setup", &[Ptr, Ptr]),
+        ("ring_enter", &[Int]),
+        ("eventfd", &[Int, Int]),
+        ("timerfd", &[Int, Int, Int]),
+        ("sem_open", &[Str, Int, Int]),
+        ("sem_wait", &[Int]),
+        ("sem_post", &[Int]),
+        ("mq_open", &[Str, Int]),
+        ("mq_send", &[Int, Ptr, Int, Int]),
+        ("mq_receive", &[Int, Ptr, Int, Ptr]),
+        ("getppid", &[]),
+        ("gettid", &[]),
+        ("uname", &[Ptr]),
+        ("sysinfo", &[Ptr]),
+        ("rescan", &[]),
+        ("cpu_offline", &[Int]),
+        ("cpu_online", &[Int]),
+        ("suspend", &[Int]),
+        ("getuid", &[]),
+        ("setuid", &[Int]),
+        ("quotactl", &[Int, Int, Ptr]),
+    ]
+};
+
+/// Strings longer than this are printed as pointers.
+const TRACE_STRLEN: usize = 32;
+
 impl Kernel {
     pub fn syscall(&'static self, num: i32, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let seccomp = &proc.deref_data().seccomp;
+        if !seccomp.allows(num) {
+            if seccomp.kills() {
+                proc.kill();
+            }
+            return Err(());
+        }
+
+        let traced = (0..128).contains(&num) && proc.deref_data().trace_mask & (1 << num) != 0;
+        let (name, args) = match SYSCALLS.get(num as usize) {
+            Some(syscall) if traced => *syscall,
+            _ => return self.dispatch(num, proc),
+        };
+
+        // Format the arguments now, as exec replaces the memory they are in.
+        let mut buf = ArrayString::<[u8; 128]>::new();
+        for (i, arg) in args.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            let mut s = [0; TRACE_STRLEN];
+            let _ = match arg {
+                Arg::Int => write!(buf, "{}{}", sep, proc.argint(i)?),
+                Arg::Str => {
+                    match proc.argstr(i, &mut s) {
+                        Ok(s) => write!(buf, "{}{:?}", sep, s),
+                        Err(()) => write!(buf, "{}{:#x}", sep, proc.argaddr(i)?),
+                    }
+                }
+                Arg::Ptr => write!(buf, "{}{:#x}", sep, proc.argaddr(i)?),
+            };
+        }
+        let pid = proc.pid();
+        let result = self.dispatch(num, proc);
+        klog!(
+            Info,
+            "{}: {}({}) = {}",
+            pid,
+            name,
+            buf,
+            result.map_or(-1, |ret| ret as isize)
+        );
+        result
+    }
+
+    fn dispatch(&'static self, num: i32, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         match num {
             1 => self.sys_fork(proc),
             2 => self.sys_exit(proc),
@@ -34,8 +172,60 @@ impl Kernel {
             20 => self.sys_mkdir(proc),
             21 => self.sys_close(proc),
             22 => self.sys_poweroff(proc),
+            23 => self.sys_ioctl(proc),
+            24 => self.sys_gettimeofday(proc),
+            25 => self.sys_clock_gettime(proc),
+            26 => self.sys_reboot(proc),
+            27 => self.sys_execve(proc),
+            28 => self.sys_prctl(proc),
+            29 => self.sys_mprotect(proc),
+            30 => self.sys_seccomp(proc),
+            31 => self.sys_chroot(proc),
+            32 => self.sys_dmesg(proc),
+            33 => self.sys_trace(proc),
+            34 => self.sys_ptrace(proc),
+            35 => self.sys_faultinject(proc),
+            36 => self.sys_fcntl(proc),
+            37 => self.sys_sendfile(proc),
+            38 => self.sys_pipe2(proc),
+            39 => self.sys_access(proc),
+            40 => self.sys_statfs(proc),
+            41 => self.sys_fstatfs(proc),
+            42 => self.sys_lseek(proc),
+            43 => self.sys_fallocate(proc),
+            44 => self.sys_flock(proc),
+            45 => self.sys_mount(proc),
+            46 => self.sys_umount(proc),
+            47 => self.sys_sync(proc),
+            48 => self.sys_getcwd(proc),
+            49 => self.sys_openat(proc),
+            50 => self.sys_mkdirat(proc),
+            51 => self.sys_unlinkat(proc),
+            52 => self.sys_madvise(proc),
+            53 => self.sys_ring_setup(proc),
+            54 => self.sys_ring_enter(proc),
+            55 => self.sys_eventfd(proc),
+            56 => self.sys_timerfd(proc),
+            57 => self.sys_sem_open(proc),
+            58 => self.sys_sem_wait(proc),
+            59 => self.sys_sem_post(proc),
+            60 => self.sys_mq_open(proc),
+            61 => self.sys_mq_send(proc),
+            62 => self.sys_mq_receive(proc),
+            63 => self.sys_getppid(proc),
+            64 => self.sys_gettid(proc),
+            65 => self.sys_uname(proc),
+            66 => self.sys_sysinfo(proc),
+            67 => self.sys_rescan(proc),
+            68 => self.sys_cpu_offline(proc),
+            69 => self.sys_cpu_online(proc),
+            70 => self.sys_suspend(proc),
+            71 => self.sys_getuid(proc),
+            72 => self.sys_setuid(proc),
+            73 => self.sys_quotactl(proc),
             _ => {
-                println!(
+                klog!(
+                    Warn,
                     "{} {}: unknown sys call {}",
                     proc.pid(),
                     str::from_utf8(&proc.deref_data().name).unwrap_or("???"),
@@ -50,23 +240,17 @@ impl Kernel {
 impl CurrentProc<'_> {
     /// Fetch the usize at addr from the current process.
     /// Returns Ok(fetched integer) on success, Err(()) on error.
-    pub fn fetchaddr(&mut self, addr: UVAddr) -> Result<usize, ()> {
+    pub fn fetchaddr(&mut self, addr: UserPtr<usize>) -> Result<usize, ()> {
         let mut ip = 0;
-        let sz = mem::size_of::<usize>();
-        if addr.into_usize() >= self.memory().size()
-            || addr.into_usize() + sz > self.memory().size()
-        {
-            return Err(());
-        }
         // SAFETY: usize does not have any internal structure.
-        unsafe { self.memory_mut().copy_in(&mut ip, addr) }?;
+        unsafe { addr.read(&mut ip, self.memory_mut()) }?;
         Ok(ip)
     }
 
     /// Fetch the nul-terminated string at addr from the current process.
     /// Returns reference to the string in the buffer.
-    pub fn fetchstr<'a>(&mut self, addr: UVAddr, buf: &'a mut [u8]) -> Result<&'a CStr, ()> {
-        self.memory_mut().copy_in_str(buf, addr)?;
+    pub fn fetchstr<'a>(&mut self, addr: UserPtr<u8>, buf: &'a mut [u8]) -> Result<&'a CStr, ()> {
+        addr.read_str(buf, self.memory_mut())?;
 
         // SAFETY: buf contains '\0' as copy_in_str has succeeded.
         Ok(unsafe { CStr::from_ptr(buf.as_ptr()) })
@@ -89,18 +273,34 @@ impl CurrentProc<'_> {
         Ok(self.argraw(n) as i32)
     }
 
-    /// Retrieve an argument as a pointer.
+    /// Retrieve an argument as an address.
     /// Doesn't check for legality, since
-    /// copyin/copyout will do that.
+    /// it is not used to access user memory.
     pub fn argaddr(&self, n: usize) -> Result<usize, ()> {
         Ok(self.argraw(n))
     }
 
+    /// Retrieve an argument as a pointer to a T in user memory.
+    /// Accesses through the pointer check the rest.
+    pub fn argptr<T>(&self, n: usize) -> Result<UserPtr<T>, ()> {
+        UserPtr::new(self.argraw(n))
+    }
+
+    /// Retrieve the nth argument as the address, and the mth argument as the
+    /// length, of a range of bytes in user memory.
+    pub fn argslice(&self, n: usize, m: usize) -> Result<UserSlice, ()> {
+        let len = self.argint(m)?;
+        if len < 0 {
+            return Err(());
+        }
+        UserSlice::new(self.argraw(n), len as usize)
+    }
+
     /// Fetch the nth word-sized system call argument as a null-terminated string.
     /// Copies into buf, at most max.
     /// Returns reference to the string in the buffer.
     pub fn argstr<'a>(&mut self, n: usize, buf: &'a mut [u8]) -> Result<&'a CStr, ()> {
-        let addr = self.argaddr(n)?;
-        self.fetchstr(addr.into(), buf)
+        let addr = self.argptr(n)?;
+        self.fetchstr(addr, buf)
     }
 }
@@ -4,46 +4,42 @@
 
 #![allow(clippy::unit_arg)]
 
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::{cell::UnsafeCell, mem};
 
-use arrayvec::ArrayVec;
 use cstr_core::CStr;
 
 use crate::{
-    fcntl::FcntlFlags,
+    capability::Capabilities,
+    coredump::SIGTRAP,
+    eventfd::{EventFile, TimerFile},
+    exec::ExecArgs,
+    fcntl::{
+        FcntlFlags, AT_FDCWD, AT_REMOVEDIR, FD_CLOEXEC, F_GETFD, F_GETPIPE_SZ, F_SETFD,
+        F_SETPIPE_SZ, R_OK, W_OK, X_OK,
+    },
     file::{FileType, InodeFileType, RcFile},
-    fs::{Dirent, FileName, FsTransaction, InodeGuard, InodeType, Path, RcInode},
+    fs::{
+        Dirent, Dqblk, FileName, FsTransaction, InodeGuard, InodeType, Lookup, Path, RcInode,
+        DIRSIZ, Q_GETQUOTA, Q_SETQUOTA, ROOTINO,
+    },
     kernel::Kernel,
-    ok_or,
-    page::Page,
-    param::{MAXARG, MAXPATH, NOFILE},
+    mount::{MS_DISCARD, MS_REMOUNT, MS_SYNC},
+    param::{MAXPATH, ROOTDEV},
     proc::CurrentProc,
-    some_or,
-    vm::UVAddr,
+    stat::{Statfs, FIFO},
+    vm::{UserPtr, UserSlice},
 };
 
-impl RcFile {
-    /// Allocate a file descriptor for the given file.
-    /// Takes over file reference from caller on success.
-    fn fdalloc(self, proc: &mut CurrentProc<'_>) -> Result<i32, Self> {
-        let proc_data = proc.deref_mut_data();
-        for fd in 0..NOFILE {
-            // user pointer to struct stat
-            if proc_data.open_files[fd].is_none() {
-                proc_data.open_files[fd] = Some(self);
-                return Ok(fd as i32);
-            }
-        }
-        Err(self)
-    }
-}
-
 impl Kernel {
-    /// Create an inode with given type.
+    /// Create an inode with given type. A relative path starts at dir, or at
+    /// the current directory if dir is None.
     /// Returns Ok(created inode, result of given function f) on success, Err(()) on error.
-    fn create<F, T>(
+    pub fn create<F, T>(
         &self,
         path: &Path,
+        dir: Option<&RcInode>,
         typ: InodeType,
         tx: &FsTransaction<'_>,
         proc: &CurrentProc<'_>,
@@ -52,14 +48,18 @@ impl Kernel {
     where
         F: FnOnce(&mut InodeGuard<'_>) -> T,
     {
-        let (ptr, name) = self.itable.nameiparent(path, proc)?;
-        let mut dp = ptr.lock();
-        if let Ok((ptr2, _)) = dp.dirlookup(&name, &self.itable) {
+        // Only a directory may be named with a trailing slash.
+        if path.has_trailing_slash() && typ != InodeType::Dir {
+            return Err(());
+        }
+        let (ptr, name) = self.itable.nameiparent(path, dir, proc)?;
+        let mut dp = ptr.lock()?;
+        if let Some((ptr2, _)) = dp.dirlookup(&name, &self.itable)? {
             drop(dp);
             if typ != InodeType::File {
                 return Err(());
             }
-            let mut ip = ptr2.lock();
+            let mut ip = ptr2.lock()?;
             if let InodeType::None | InodeType::Dir = ip.deref_inner().typ {
                 return Err(());
             }
@@ -67,17 +67,17 @@ impl Kernel {
             drop(ip);
             return Ok((ptr2, ret));
         }
-        let ptr2 = self.itable.alloc_inode(dp.dev, typ, tx);
-        let mut ip = ptr2.lock();
+        let ptr2 = self
+            .itable
+            .alloc_inode(dp.dev, typ, proc.deref_data().uid, tx)?;
+        let mut ip = ptr2.lock()?;
         ip.deref_inner_mut().nlink = 1;
-        ip.update(tx);
 
         // Create . and .. entries.
-        if typ == InodeType::Dir {
-            // for ".."
-            dp.deref_inner_mut().nlink += 1;
-            dp.update(tx);
-
+        let linked = ip.update(tx).and_then(|_| {
+            if typ != InodeType::Dir {
+                return Ok(());
+            }
             // No ip->nlink++ for ".": avoid cyclic ref count.
             // SAFETY: b"." does not contain any NUL characters.
             ip.dirlink(
@@ -95,10 +95,23 @@ impl Kernel {
                     &self.itable,
                 )
             })
-            .expect("create dots");
+        });
+        if linked
+            .and_then(|_| dp.dirlink(&name, ip.inum, tx, &self.itable))
+            .is_err()
+        {
+            // The disk is full, or no buffer is free. Dropping the last
+            // reference to the inode, which has no links, frees it and any
+            // blocks it got.
+            ip.deref_inner_mut().nlink = 0;
+            let _ = ip.update(tx);
+            return Err(());
+        }
+        if typ == InodeType::Dir {
+            // for ".."
+            dp.deref_inner_mut().nlink += 1;
+            dp.update(tx)?;
         }
-        dp.dirlink(&name, ip.inum, tx, &self.itable)
-            .expect("create: dirlink");
         let ret = f(&mut ip);
         drop(ip);
         Ok((ptr2, ret))
@@ -107,54 +120,78 @@ impl Kernel {
     /// Create another name(newname) for the file oldname.
     /// Returns Ok(()) on success, Err(()) on error.
     fn link(&self, oldname: &CStr, newname: &CStr, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        if Path::new(newname).has_trailing_slash() {
+            return Err(());
+        }
         let tx = self.file_system.begin_transaction();
-        let ptr = self.itable.namei(Path::new(oldname), proc)?;
-        let mut ip = ptr.lock();
+        let ptr = self.itable.namei(Path::new(oldname), None, proc)?;
+        let mut ip = ptr.lock()?;
         if ip.deref_inner().typ == InodeType::Dir {
             return Err(());
         }
         ip.deref_inner_mut().nlink += 1;
-        ip.update(&tx);
+        if let Err(()) = ip.update(&tx) {
+            ip.deref_inner_mut().nlink -= 1;
+            return Err(());
+        }
         drop(ip);
 
-        if let Ok((ptr2, name)) = self.itable.nameiparent(Path::new(newname), proc) {
-            let mut dp = ptr2.lock();
-            if dp.dev != ptr.dev || dp.dirlink(name, ptr.inum, &tx, &self.itable).is_err() {
-            } else {
-                return Ok(());
+        if let Ok((ptr2, name)) = self.itable.nameiparent(Path::new(newname), None, proc) {
+            if let Ok(mut dp) = ptr2.lock() {
+                if dp.dev != ptr.dev || dp.dirlink(name, ptr.inum, &tx, &self.itable).is_err() {
+                } else {
+                    return Ok(());
+                }
             }
         }
 
-        let mut ip = ptr.lock();
+        let mut ip = ptr.lock()?;
         ip.deref_inner_mut().nlink -= 1;
-        ip.update(&tx);
+        ip.update(&tx)?;
         Err(())
     }
 
-    /// Remove a file(filename).
+    /// Remove a file(filename), relative to dir if it is not None. With
+    /// only_dir, the file must be a directory.
     /// Returns Ok(()) on success, Err(()) on error.
-    fn unlink(&self, filename: &CStr, proc: &CurrentProc<'_>) -> Result<(), ()> {
+    fn unlink(
+        &self,
+        filename: &CStr,
+        dir: Option<&RcInode>,
+        only_dir: bool,
+        proc: &CurrentProc<'_>,
+    ) -> Result<(), ()> {
         let de: Dirent = Default::default();
+        let only_dir = only_dir || Path::new(filename).has_trailing_slash();
         let tx = self.file_system.begin_transaction();
-        let (ptr, name) = self.itable.nameiparent(Path::new(filename), proc)?;
-        let mut dp = ptr.lock();
+        let (ptr, name) = self.itable.nameiparent(Path::new(filename), dir, proc)?;
+        let mut dp = ptr.lock()?;
 
         // Cannot unlink "." or "..".
         if !(name.as_bytes() == b"." || name.as_bytes() == b"..") {
-            if let Ok((ptr2, off)) = dp.dirlookup(&name, &self.itable) {
-                let mut ip = ptr2.lock();
+            if let Some((ptr2, off)) = dp.dirlookup(&name, &self.itable)? {
+                let mut ip = ptr2.lock()?;
                 assert!(ip.deref_inner().nlink >= 1, "unlink: nlink < 1");
+                if only_dir && ip.deref_inner().typ != InodeType::Dir {
+                    return Err(());
+                }
 
-                if ip.deref_inner().typ != InodeType::Dir || ip.is_dir_empty() {
-                    dp.write_kernel(&de, off, &tx).expect("unlink: writei");
+                // Nor a directory that a volume is mounted on.
+                if ip.deref_inner().typ != InodeType::Dir
+                    || (ip.is_dir_empty() && !ptr2.mounted.load(Ordering::Acquire))
+                {
+                    dp.write_kernel(&de, off, &tx)?;
                     if ip.deref_inner().typ == InodeType::Dir {
                         dp.deref_inner_mut().nlink -= 1;
-                        dp.update(&tx);
+                        dp.update(&tx)?;
                     }
                     drop(dp);
                     drop(ptr);
                     ip.deref_inner_mut().nlink -= 1;
-                    ip.update(&tx);
+                    ip.update(&tx)?;
+                    if ip.deref_inner().nlink == 0 {
+                        tx.add_orphan(ip.dev, ip.inum);
+                    }
                     return Ok(());
                 }
             }
@@ -163,21 +200,31 @@ impl Kernel {
         Err(())
     }
 
-    /// Open a file; omode indicate read/write.
+    /// Open a file, relative to dir if it is not None; omode indicate read/write.
     /// Returns Ok(file descriptor) on success, Err(()) on error.
     fn open(
         &'static self,
         name: &Path,
+        dir: Option<&RcInode>,
         omode: FcntlFlags,
         proc: &mut CurrentProc<'_>,
     ) -> Result<usize, ()> {
         let tx = self.file_system.begin_transaction();
 
+        if self.fat32.is_mounted() {
+            if let Ok(Lookup::Fat32(path)) = self.itable.lookup(name, dir, proc) {
+                drop(tx);
+                return self.open_fat32(path, omode, proc);
+            }
+        }
+
         let (ip, typ) = if omode.contains(FcntlFlags::O_CREATE) {
-            self.create(name, InodeType::File, &tx, proc, |ip| ip.deref_inner().typ)?
+            self.create(name, dir, InodeType::File, &tx, proc, |ip| {
+                ip.deref_inner().typ
+            })?
         } else {
-            let ptr = self.itable.namei(name, proc)?;
-            let ip = ptr.lock();
+            let ptr = self.itable.namei(name, dir, proc)?;
+            let ip = ptr.lock()?;
             let typ = ip.deref_inner().typ;
 
             if typ == InodeType::Dir && omode != FcntlFlags::O_RDONLY {
@@ -187,26 +234,43 @@ impl Kernel {
             (ptr, typ)
         };
 
+        let cloexec = omode.contains(FcntlFlags::O_CLOEXEC);
+        let readable = !omode.intersects(FcntlFlags::O_WRONLY);
+        let writable = omode.intersects(FcntlFlags::O_WRONLY | FcntlFlags::O_RDWR);
+        if typ == InodeType::Fifo {
+            let f = self.open_fifo(ip, readable, writable)?;
+            // Waiting for the other end must not hold up the file system.
+            drop(tx);
+            if let FileType::Fifo { pipe, .. } = &f.typ {
+                let nonblock = omode.contains(FcntlFlags::O_NONBLOCK);
+                pipe.wait_peer(readable, writable, nonblock, proc)?;
+            }
+            let fd = proc.files().alloc(f, cloexec).map_err(|_| ())?;
+            return Ok(fd as usize);
+        }
+
         let filetype = match typ {
-            InodeType::Device { major, .. } => {
-                let major = self.devsw.get(major as usize).ok_or(())?;
-                FileType::Device { ip, major }
+            InodeType::Device { major, minor } => {
+                let _ = self.devsw.get(major).ok_or(())?;
+                FileType::Device {
+                    ip,
+                    major,
+                    minor,
+                    off: AtomicU32::new(0),
+                }
             }
             _ => {
                 FileType::Inode {
                     inner: InodeFileType {
                         ip,
                         off: UnsafeCell::new(0),
+                        sync: omode.contains(FcntlFlags::O_SYNC),
                     },
                 }
             }
         };
 
-        let f = self.ftable.alloc_file(
-            filetype,
-            !omode.intersects(FcntlFlags::O_WRONLY),
-            omode.intersects(FcntlFlags::O_WRONLY | FcntlFlags::O_RDWR),
-        )?;
+        let f = self.ftable.alloc_file(filetype, readable, writable)?;
 
         if omode.contains(FcntlFlags::O_TRUNC) && typ == InodeType::File {
             match &f.typ {
@@ -214,23 +278,127 @@ impl Kernel {
                 FileType::Device { ip, .. }
                 | FileType::Inode {
                     inner: InodeFileType { ip, .. },
-                } => ip.lock().itrunc(&tx),
+                } => ip.lock()?.itrunc(&tx)?,
                 _ => panic!("sys_open : Not reach"),
             };
         }
-        let fd = f.fdalloc(proc).map_err(|_| ())?;
+        let fd = proc.files().alloc(f, cloexec).map_err(|_| ())?;
+        Ok(fd as usize)
+    }
+
+    /// Open path in the mounted FAT32 volume, relative to its root.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    fn open_fat32(
+        &self,
+        path: &Path,
+        omode: FcntlFlags,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<usize, ()> {
+        let readable = !omode.intersects(FcntlFlags::O_WRONLY);
+        let writable = omode.intersects(FcntlFlags::O_WRONLY | FcntlFlags::O_RDWR);
+        let file = self.fat32.open(
+            path,
+            omode.contains(FcntlFlags::O_CREATE),
+            writable,
+            omode.contains(FcntlFlags::O_TRUNC),
+        )?;
+        let f = self
+            .ftable
+            .alloc_file(FileType::Fat32 { file }, readable, writable)?;
+        let fd = proc
+            .files()
+            .alloc(f, omode.contains(FcntlFlags::O_CLOEXEC))
+            .map_err(|_| ())?;
         Ok(fd as usize)
     }
 
+    /// Mount the FAT32 volume of the block device numbered dev on the
+    /// directory path, or with MS_REMOUNT, set the options of the root file
+    /// system on ROOTDEV, which path must name. It needs CAP_SYS_ADMIN.
+    /// FAT32 volumes take no options.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn mount(&self, dev: u32, path: &CStr, flags: i32, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        if !proc.is_privileged(Capabilities::SYS_ADMIN)
+            || flags & !(MS_REMOUNT | MS_DISCARD | MS_SYNC) != 0
+        {
+            return Err(());
+        }
+        // The directory is dropped if mounting fails, or on unmounting.
+        let _tx = self.file_system.begin_transaction();
+        let ip = self.itable.namei(Path::new(path), None, proc)?;
+        if flags & MS_REMOUNT != 0 {
+            if dev != ROOTDEV || ip.inum != ROOTINO {
+                return Err(());
+            }
+            self.file_system.set_discard(flags & MS_DISCARD != 0);
+            self.file_system.set_sync(flags & MS_SYNC != 0);
+            return Ok(());
+        }
+        if flags != 0 {
+            return Err(());
+        }
+        self.fat32.mount(dev, ip)
+    }
+
+    /// Get (Q_GETQUOTA) the limits and usage of user id uid into the dqblk at
+    /// addr, or set (Q_SETQUOTA) its limits to those in it. Setting them,
+    /// or getting those of another user id, needs CAP_SYS_ADMIN.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_quotactl(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let cmd = proc.argint(0)?;
+        let uid = proc.argint(1)?;
+        let buf: UserPtr<Dqblk> = proc.argptr(2)?;
+        if uid < 0
+            || ((cmd != Q_GETQUOTA || uid as u32 != proc.deref_data().uid)
+                && !proc.is_privileged(Capabilities::SYS_ADMIN))
+        {
+            return Err(());
+        }
+        match cmd {
+            Q_GETQUOTA => {
+                let dq = self.file_system.quota(uid as u32).ok_or(())?;
+                buf.write(&dq, proc.memory_mut())?;
+            }
+            Q_SETQUOTA => {
+                let mut dq = Dqblk::zero();
+                // SAFETY: Dqblk contains only u32's.
+                unsafe { buf.read(&mut dq, proc.memory_mut()) }?;
+                let tx = self.file_system.begin_transaction();
+                tx.set_quota(uid as u32, &dq, &self.itable)?;
+            }
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Unmount the FAT32 volume mounted on the directory path. It needs
+    /// CAP_SYS_ADMIN.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn umount(&self, path: &CStr, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        if !proc.is_privileged(Capabilities::SYS_ADMIN) {
+            return Err(());
+        }
+        let _tx = self.file_system.begin_transaction();
+        match self.itable.lookup(Path::new(path), None, proc)? {
+            Lookup::Fat32(rest) if rest.is_empty_string() => self.fat32.umount(),
+            _ => Err(()),
+        }
+    }
+
     /// Create a new directory.
     /// Returns Ok(()) on success, Err(()) on error.
-    fn mkdir(&self, dirname: &CStr, proc: &CurrentProc<'_>) -> Result<(), ()> {
+    fn mkdir(
+        &self,
+        dirname: &CStr,
+        dir: Option<&RcInode>,
+        proc: &CurrentProc<'_>,
+    ) -> Result<(), ()> {
         let tx = self.file_system.begin_transaction();
-        self.create(Path::new(dirname), InodeType::Dir, &tx, proc, |_| ())?;
+        self.create(Path::new(dirname), dir, InodeType::Dir, &tx, proc, |_| ())?;
         Ok(())
     }
 
-    /// Create a device file.
+    /// Create a device file. It needs CAP_MKNOD.
     /// Returns Ok(()) on success, Err(()) on error.
     fn mknod(
         &self,
@@ -239,9 +407,13 @@ impl Kernel {
         minor: u16,
         proc: &CurrentProc<'_>,
     ) -> Result<(), ()> {
+        if !proc.is_privileged(Capabilities::MKNOD) {
+            return Err(());
+        }
         let tx = self.file_system.begin_transaction();
         self.create(
             Path::new(filename),
+            None,
             InodeType::Device { major, minor },
             &tx,
             proc,
@@ -250,6 +422,21 @@ impl Kernel {
         Ok(())
     }
 
+    /// Create a FIFO. Unlike a device file, it needs no capability.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn mkfifo(&self, filename: &CStr, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        let tx = self.file_system.begin_transaction();
+        self.create(
+            Path::new(filename),
+            None,
+            InodeType::Fifo,
+            &tx,
+            proc,
+            |_| (),
+        )?;
+        Ok(())
+    }
+
     /// Change the current directory.
     /// Returns Ok(()) on success, Err(()) on error.
     fn chdir(&self, dirname: &CStr, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
@@ -259,8 +446,8 @@ impl Kernel {
         // of an inode may cause disk write operations, so we must begin a
         // transaction here.
         let _tx = self.file_system.begin_transaction();
-        let ptr = self.itable.namei(Path::new(dirname), proc)?;
-        let ip = ptr.lock();
+        let ptr = self.itable.namei(Path::new(dirname), None, proc)?;
+        let ip = ptr.lock()?;
         if ip.deref_inner().typ != InodeType::Dir {
             return Err(());
         }
@@ -269,25 +456,114 @@ impl Kernel {
         Ok(())
     }
 
+    /// Make the path of the current directory, from the root directory of
+    /// the process, at the end of buf, followed by a NUL.
+    /// Returns Ok(where it starts) on success, Err(()) if the current
+    /// directory has been removed, or the path is longer than buf.
+    fn getcwd(&self, buf: &mut [u8; MAXPATH], proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        // TODO(https://github.com/kaist-cp/rv6/issues/290)
+        // Dropping the inodes on the way may write to the disk, so we must
+        // begin a transaction here.
+        let _tx = self.file_system.begin_transaction();
+        let root = proc.root().inum;
+        let mut ptr = proc.cwd().clone();
+        let mut start = MAXPATH - 1;
+        buf[start] = 0;
+        // Go up through ".." entries, looking up each directory by its inode
+        // number in its parent.
+        while ptr.inum != root && ptr.inum != ROOTINO {
+            let (parent, _) = ptr
+                .lock()?
+                .dirlookup(unsafe { FileName::from_bytes(b"..") }, &self.itable)?
+                .ok_or(())?;
+            let mut name = [0; DIRSIZ];
+            let len = parent.lock()?.dirname(ptr.inum, &mut name)?;
+            if start < len + 1 {
+                return Err(());
+            }
+            start -= len;
+            buf[start..start + len].copy_from_slice(&name[..len]);
+            start -= 1;
+            buf[start] = b'/';
+            ptr = parent;
+        }
+        if start == MAXPATH - 1 {
+            start -= 1;
+            buf[start] = b'/';
+        }
+        Ok(start)
+    }
+
+    /// Check whether the file at path can be accessed as mode says.
+    /// There are no owners or permission bits, so anyone may do what the type
+    /// of the file allows: a directory cannot be written, and only files and
+    /// directories can be executed or searched.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn access(&self, path: &CStr, mode: i32, proc: &CurrentProc<'_>) -> Result<(), ()> {
+        // TODO(https://github.com/kaist-cp/rv6/issues/290)
+        // The method namei can drop inodes. If namei succeeds, its return
+        // value, ptr, will be dropped when this method returns. Deallocation
+        // of an inode may cause disk write operations, so we must begin a
+        // transaction here.
+        let _tx = self.file_system.begin_transaction();
+        let ptr = self.itable.namei(Path::new(path), None, proc)?;
+        let typ = ptr.lock()?.deref_inner().typ;
+        let ok = match typ {
+            InodeType::Dir => mode & W_OK == 0,
+            InodeType::File => true,
+            InodeType::Device { .. } | InodeType::Fifo => mode & X_OK == 0,
+            InodeType::None => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Change the root directory, and the current directory, to dirname.
+    /// Moving the current directory too keeps the process from reaching
+    /// outside the new root through relative paths. It needs CAP_SYS_CHROOT.
+    /// Returns Ok(()) on success, Err(()) on error.
+    fn chroot(&self, dirname: &CStr, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
+        if !proc.is_privileged(Capabilities::SYS_CHROOT) {
+            return Err(());
+        }
+        // TODO(https://github.com/kaist-cp/rv6/issues/290)
+        // The method namei can drop inodes, and so can replacing the root and
+        // the current directory. Deallocation of an inode may cause disk write
+        // operations, so we must begin a transaction here.
+        let _tx = self.file_system.begin_transaction();
+        let ptr = self.itable.namei(Path::new(dirname), None, proc)?;
+        let ip = ptr.lock()?;
+        if ip.deref_inner().typ != InodeType::Dir {
+            return Err(());
+        }
+        drop(ip);
+        let _ = mem::replace(proc.cwd_mut(), ptr.clone());
+        let _ = mem::replace(proc.root_mut(), ptr);
+        Ok(())
+    }
+
     /// Create a pipe, put read/write file descriptors in fd0 and fd1.
+    /// Exec closes them if cloexec is true.
     /// Returns Ok(()) on success, Err(()) on error.
-    fn pipe(&self, fdarray: UVAddr, proc: &mut CurrentProc<'_>) -> Result<(), ()> {
+    fn pipe(
+        &self,
+        fdarray: UserPtr<[i32; 2]>,
+        cloexec: bool,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<(), ()> {
         let (pipereader, pipewriter) = self.allocate_pipe()?;
 
-        let fd0 = pipereader.fdalloc(proc).map_err(|_| ())?;
-        let fd1 = pipewriter
-            .fdalloc(proc)
-            .map_err(|_| proc.deref_mut_data().open_files[fd0 as usize] = None)?;
+        let fd0 = proc.files().alloc(pipereader, cloexec).map_err(|_| ())?;
+        let fd1 = proc.files().alloc(pipewriter, cloexec).map_err(|_| {
+            let _ = proc.files().close(fd0);
+        })?;
 
-        if proc.memory_mut().copy_out(fdarray, &fd0).is_err()
-            || proc
-                .memory_mut()
-                .copy_out(fdarray + mem::size_of::<i32>(), &fd1)
-                .is_err()
-        {
-            let proc_data = proc.deref_mut_data();
-            proc_data.open_files[fd0 as usize] = None;
-            proc_data.open_files[fd1 as usize] = None;
+        if fdarray.write(&[fd0, fd1], proc.memory_mut()).is_err() {
+            let _ = proc.files().close(fd0);
+            let _ = proc.files().close(fd1);
             return Err(());
         }
         Ok(())
@@ -299,8 +575,7 @@ impl Kernel {
     /// Returns Ok(new file descriptor) on success, Err(()) on error.
     pub fn sys_dup(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let (_, f) = proc.argfd(0)?;
-        let newfile = f.clone();
-        let fd = newfile.fdalloc(proc).map_err(|_| ())?;
+        let fd = proc.files().alloc(f, false).map_err(|_| ())?;
         Ok(fd as usize)
     }
 
@@ -308,27 +583,120 @@ impl Kernel {
     /// Returns Ok(number read) on success, Err(()) on error.
     pub fn sys_read(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let (_, f) = proc.argfd(0)?;
-        let n = proc.argint(2)?;
-        let p = proc.argaddr(1)?;
-        // SAFETY: read will not access proc's open_files.
-        unsafe { (*(f as *const RcFile)).read(p.into(), n, proc) }
+        let dst = proc.argslice(1, 2)?;
+        f.read(dst, proc)
     }
 
     /// Write n bytes from buf to given file descriptor fd.
     /// Returns Ok(n) on success, Err(()) on error.
     pub fn sys_write(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let (_, f) = proc.argfd(0)?;
-        let n = proc.argint(2)?;
-        let p = proc.argaddr(1)?;
-        // SAFETY: write will not access proc's open_files.
-        unsafe { (*(f as *const RcFile)).write(p.into(), n, proc, &self.file_system) }
+        let src = proc.argslice(1, 2)?;
+        f.write(src, proc, &self.file_system)
+    }
+
+    /// Copy count bytes from in_fd to out_fd without going through user
+    /// memory. in_fd must be a regular file. If offset is not null, read
+    /// from *offset and update it, leaving the offset of in_fd alone.
+    /// Returns Ok(number of bytes copied) on success, Err(()) on error.
+    pub fn sys_sendfile(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, out) = proc.argfd(0)?;
+        let (_, src) = proc.argfd(1)?;
+        let offset = proc.argaddr(2)?;
+        let count = proc.argint(3)?;
+        if count < 0 {
+            return Err(());
+        }
+        let mut off = if offset == 0 {
+            None
+        } else {
+            let ptr = UserPtr::<u32>::new(offset)?;
+            let mut off = 0;
+            // SAFETY: u32 can be safely transmuted to [u8; _].
+            unsafe { ptr.read(&mut off, proc.memory_mut()) }?;
+            Some((ptr, off))
+        };
+        let n = out.sendfile(
+            &src,
+            off.as_mut().map(|(_, off)| off),
+            count as usize,
+            proc,
+            &self.file_system,
+        )?;
+        if let Some((ptr, off)) = off {
+            ptr.write(&off, proc.memory_mut())?;
+        }
+        Ok(n)
+    }
+
+    /// Get the usage of the file system that holds file fd into *buf.
+    /// Returns Ok(0) on success, Err(()) on error, or if fd is a pipe.
+    pub fn sys_fstatfs(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, f) = proc.argfd(0)?;
+        if let FileType::Pipe { .. } = f.typ {
+            return Err(());
+        }
+        let buf: UserPtr<Statfs> = proc.argptr(1)?;
+        buf.write(&self.file_system.statfs(), proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Move the offset of file fd by offset from where whence says.
+    /// Returns Ok(the new offset) on success, Err(()) on error.
+    pub fn sys_lseek(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, f) = proc.argfd(0)?;
+        let off = proc.argint(1)?;
+        let whence = proc.argint(2)?;
+        f.seek(off, whence)
+    }
+
+    /// Allocate blocks for len bytes of file fd at off, or free them as mode
+    /// says.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_fallocate(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, f) = proc.argfd(0)?;
+        let mode = proc.argint(1)?;
+        let off = u32::try_from(proc.argint(2)?).map_err(|_| ())?;
+        let len = u32::try_from(proc.argint(3)?).map_err(|_| ())?;
+        f.fallocate(mode, off, len, &self.file_system)?;
+        Ok(0)
+    }
+
+    /// Apply or remove an advisory lock on file fd, as op says.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_flock(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, f) = proc.argfd(0)?;
+        let op = proc.argint(1)?;
+        f.flock(op, proc)?;
+        Ok(0)
+    }
+
+    /// Mount the FAT32 volume of the block device numbered dev on the
+    /// directory path, or set the options of the root file system.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_mount(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let dev = u32::try_from(proc.argint(0)?).map_err(|_| ())?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(1, &mut path)?;
+        let flags = proc.argint(2)?;
+        self.mount(dev, path, flags, proc)?;
+        Ok(0)
+    }
+
+    /// Unmount the FAT32 volume mounted on the directory path.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_umount(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(0, &mut path)?;
+        self.umount(path, proc)?;
+        Ok(0)
     }
 
     /// Release open file fd.
     /// Returns Ok(0) on success, Err(()) on error.
     pub fn sys_close(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
-        let (fd, _) = proc.argfd(0)?;
-        proc.deref_mut_data().open_files[fd as usize] = None;
+        let fd = proc.argint(0)?;
+        proc.files().close(fd)?;
         Ok(0)
     }
 
@@ -337,9 +705,8 @@ impl Kernel {
     pub fn sys_fstat(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let (_, f) = proc.argfd(0)?;
         // user pointer to struct stat
-        let st = proc.argaddr(1)?;
-        // SAFETY: stat will not access proc's open_files.
-        unsafe { (*(f as *const RcFile)).stat(st.into(), proc) }?;
+        let st = proc.argptr(1)?;
+        f.stat(st, proc)?;
         Ok(0)
     }
 
@@ -359,7 +726,23 @@ impl Kernel {
     pub fn sys_unlink(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let mut path: [u8; MAXPATH] = [0; MAXPATH];
         let path = proc.argstr(0, &mut path)?;
-        self.unlink(path, proc)?;
+        self.unlink(path, None, false, proc)?;
+        Ok(0)
+    }
+
+    /// Remove a file, relative to the directory of the file descriptor in
+    /// the first argument, or AT_FDCWD. With AT_REMOVEDIR, it must be a
+    /// directory.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_unlinkat(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let dir = proc.argdirfd(0)?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(1, &mut path)?;
+        let flags = proc.argint(2)?;
+        if flags & !AT_REMOVEDIR != 0 {
+            return Err(());
+        }
+        self.unlink(path, dir.as_ref(), flags & AT_REMOVEDIR != 0, proc)?;
         Ok(0)
     }
 
@@ -371,7 +754,20 @@ impl Kernel {
         let path = Path::new(path);
         let omode = proc.argint(1)?;
         let omode = FcntlFlags::from_bits_truncate(omode);
-        self.open(path, omode, proc)
+        self.open(path, None, omode, proc)
+    }
+
+    /// Open a file, relative to the directory of the file descriptor in the
+    /// first argument, or AT_FDCWD.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    pub fn sys_openat(&'static self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let dir = proc.argdirfd(0)?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(1, &mut path)?;
+        let path = Path::new(path);
+        let omode = proc.argint(2)?;
+        let omode = FcntlFlags::from_bits_truncate(omode);
+        self.open(path, dir.as_ref(), omode, proc)
     }
 
     /// Create a new directory.
@@ -379,18 +775,33 @@ impl Kernel {
     pub fn sys_mkdir(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let mut path: [u8; MAXPATH] = [0; MAXPATH];
         let path = proc.argstr(0, &mut path)?;
-        self.mkdir(path, proc)?;
+        self.mkdir(path, None, proc)?;
         Ok(0)
     }
 
-    /// Create a new directory.
+    /// Create a new directory, relative to the directory of the file
+    /// descriptor in the first argument, or AT_FDCWD.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_mkdirat(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let dir = proc.argdirfd(0)?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(1, &mut path)?;
+        self.mkdir(path, dir.as_ref(), proc)?;
+        Ok(0)
+    }
+
+    /// Create a device file, or a FIFO if the major number is FIFO.
     /// Returns Ok(0) on success, Err(()) on error.
     pub fn sys_mknod(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let mut path: [u8; MAXPATH] = [0; MAXPATH];
         let path = proc.argstr(0, &mut path)?;
-        let major = proc.argint(1)? as u16;
+        let major = proc.argint(1)?;
         let minor = proc.argint(2)? as u16;
-        self.mknod(path, major, minor, proc)?;
+        if major == FIFO {
+            self.mkfifo(path, proc)?;
+        } else {
+            self.mknod(path, major as u16, minor, proc)?;
+        }
         Ok(0)
     }
 
@@ -403,68 +814,260 @@ impl Kernel {
         Ok(0)
     }
 
+    /// Check whether the calling process can access path in the way mode
+    /// says, a combination of R_OK, W_OK, and X_OK, or F_OK for existence.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_access(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(0, &mut path)?;
+        let mode = proc.argint(1)?;
+        if mode & !(R_OK | W_OK | X_OK) != 0 {
+            return Err(());
+        }
+        self.access(path, mode, proc)?;
+        Ok(0)
+    }
+
+    /// Copy the path of the current directory, with a NUL, into the buffer of
+    /// the size in the second argument.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_getcwd(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let addr = proc.argaddr(0)?;
+        let size = proc.argint(1)?;
+        let mut buf = [0; MAXPATH];
+        let start = self.getcwd(&mut buf, proc)?;
+        if size < 0 || (size as usize) < MAXPATH - start {
+            return Err(());
+        }
+        UserSlice::new(addr, MAXPATH - start)?.write(&buf[start..], proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Commits what FS system calls have written, and flushes the write
+    /// caches of the disks, so that it survives a crash.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    pub fn sync(&self) -> Result<(), ()> {
+        let result = self.file_system.log.sync();
+        let result = self.file_system.log.disk.flush().and(result);
+        self.fat32.sync().and(result)
+    }
+
+    /// Sync the file systems.
+    /// Returns Ok(0) on success, Err(()) on an I/O error.
+    pub fn sys_sync(&self, _proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        self.sync()?;
+        Ok(0)
+    }
+
+    /// Get the usage of the file system that holds path into *buf.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_statfs(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(0, &mut path)?;
+        let buf: UserPtr<Statfs> = proc.argptr(1)?;
+        {
+            // TODO(https://github.com/kaist-cp/rv6/issues/290)
+            // The inode that namei returns is dropped right away, which may
+            // write to the disk, so we must begin a transaction here.
+            let _tx = self.file_system.begin_transaction();
+            let _ = self.itable.namei(Path::new(path), None, proc)?;
+        }
+        // There is one file system.
+        buf.write(&self.file_system.statfs(), proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Change the root directory.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_chroot(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(0, &mut path)?;
+        self.chroot(path, proc)?;
+        Ok(0)
+    }
+
     /// Load a file and execute it with arguments.
     /// Returns Ok(argc argument to user main) on success, Err(()) on error.
     pub fn sys_exec(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
-        let mut path: [u8; MAXPATH] = [0; MAXPATH];
-        let mut args = ArrayVec::<[Page; MAXARG]>::new();
-        let path = proc.argstr(0, &mut path)?;
-        let uargv = proc.argaddr(1)?;
-
-        let mut success = false;
-        for i in 0..MAXARG {
-            let uarg = ok_or!(
-                proc.fetchaddr((uargv + mem::size_of::<usize>() * i).into()),
-                break
-            );
-
-            if uarg == 0 {
-                success = true;
-                break;
-            }
+        self.exec_user(None, proc)
+    }
 
-            let mut page = some_or!(self.kmem.alloc(), break);
-            if proc.fetchstr(uarg.into(), &mut page[..]).is_err() {
-                self.kmem.free(page);
-                break;
-            }
-            args.push(page);
+    /// Load a file and execute it with arguments and environment variables.
+    /// Returns Ok(argc argument to user main) on success, Err(()) on error.
+    pub fn sys_execve(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let uenvp = proc.argaddr(2)?;
+        if uenvp == 0 {
+            return self.exec_user(None, proc);
         }
+        self.exec_user(Some(UserPtr::new(uenvp)?), proc)
+    }
 
-        let ret = if success {
-            self.exec(Path::new(path), &args, proc)
-        } else {
-            Err(())
-        };
+    /// Execute the path in the first argument with the argv array in the
+    /// second argument, and the envp array at `uenvp` if any.
+    /// Returns Ok(argc argument to user main) on success, Err(()) on error.
+    fn exec_user(
+        &self,
+        uenvp: Option<UserPtr<usize>>,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<usize, ()> {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = proc.argstr(0, &mut path)?;
+        let uargv = proc.argptr(1)?;
+        let mut args = ExecArgs::new(&self.kmem).map_err(|_| self.out_of_memory(proc))?;
 
-        for page in args.drain(..) {
-            self.kmem.free(page);
+        let mut ret = args.fetch(uargv, false, proc);
+        if let Some(uenvp) = uenvp {
+            ret = ret.and_then(|_| args.fetch(uenvp, true, proc));
+        }
+        let ret = ret.and_then(|_| self.exec(Path::new(path), &mut args, proc));
+        args.free(&self.kmem);
+        if ret.is_err() {
+            self.out_of_memory(proc);
         }
 
+        // A traced process stops before the new program runs.
+        if ret.is_ok() {
+            let _ = self.procs().ptrace_stop(SIGTRAP, proc);
+        }
         ret
     }
 
+    /// Manipulate the device underlying given file descriptor fd.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_ioctl(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (_, f) = proc.argfd(0)?;
+        let req = proc.argint(1)?;
+        let arg = proc.argaddr(2)?;
+        f.ioctl(req, arg)?;
+        Ok(0)
+    }
+
+    /// Control open file fd. F_GETFD returns the flags of fd, and F_SETFD
+    /// sets them to arg; FD_CLOEXEC is the only one. Pipes and FIFOs have
+    /// more to control: F_GETPIPE_SZ returns the size of the buffer of a
+    /// pipe, and F_SETPIPE_SZ resizes it to at least arg bytes and returns
+    /// the new size.
+    /// Returns Err(()) on error.
+    pub fn sys_fcntl(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let (fd, f) = proc.argfd(0)?;
+        let cmd = proc.argint(1)?;
+        let arg = proc.argint(2)?;
+        match cmd {
+            F_GETFD => {
+                let cloexec = proc.files().cloexec(fd)?;
+                return Ok(if cloexec { FD_CLOEXEC as usize } else { 0 });
+            }
+            F_SETFD => {
+                proc.files().set_cloexec(fd, arg & FD_CLOEXEC != 0)?;
+                return Ok(0);
+            }
+            _ => (),
+        }
+        let pipe = match &f.typ {
+            FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipe,
+            _ => return Err(()),
+        };
+        match cmd {
+            F_GETPIPE_SZ => Ok(pipe.size()),
+            F_SETPIPE_SZ if arg > 0 => pipe.set_size(arg as usize, &self.kmem),
+            _ => Err(()),
+        }
+    }
+
     /// Create a pipe.
     /// Returns Ok(0) on success, Err(()) on error.
     pub fn sys_pipe(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         // user pointer to array of two integers
-        let fdarray = proc.argaddr(0)?.into();
-        self.pipe(fdarray, proc)?;
+        let fdarray = proc.argptr(0)?;
+        self.pipe(fdarray, false, proc)?;
+        Ok(0)
+    }
+
+    /// Create a pipe, like pipe(), with flags. O_CLOEXEC is the only one.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_pipe2(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let fdarray = proc.argptr(0)?;
+        let flags = FcntlFlags::from_bits(proc.argint(1)?).ok_or(())?;
+        if !FcntlFlags::O_CLOEXEC.contains(flags) {
+            return Err(());
+        }
+        self.pipe(fdarray, flags.contains(FcntlFlags::O_CLOEXEC), proc)?;
         Ok(0)
     }
+
+    /// Allocate a file descriptor for the event or timer file `typ`.
+    /// flags may contain O_CLOEXEC and O_NONBLOCK.
+    fn alloc_event(
+        &self,
+        typ: FileType,
+        flags: FcntlFlags,
+        proc: &mut CurrentProc<'_>,
+    ) -> Result<usize, ()> {
+        let f = self.ftable.alloc_file(typ, true, true)?;
+        let fd = proc
+            .files()
+            .alloc(f, flags.contains(FcntlFlags::O_CLOEXEC))
+            .map_err(|_| ())?;
+        Ok(fd as usize)
+    }
+
+    /// Create an event file whose counter starts at initval.
+    /// flags may contain O_CLOEXEC and O_NONBLOCK.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    pub fn sys_eventfd(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let initval = u32::try_from(proc.argint(0)?).map_err(|_| ())?;
+        let flags = FcntlFlags::from_bits(proc.argint(1)?).ok_or(())?;
+        if !(FcntlFlags::O_CLOEXEC | FcntlFlags::O_NONBLOCK).contains(flags) {
+            return Err(());
+        }
+        let event = EventFile::new(initval as u64, flags.contains(FcntlFlags::O_NONBLOCK));
+        self.alloc_event(FileType::Event { event }, flags, proc)
+    }
+
+    /// Create a timer file that expires value ticks from now, and then every
+    /// interval ticks unless interval is 0.
+    /// flags may contain O_CLOEXEC and O_NONBLOCK.
+    /// Returns Ok(file descriptor) on success, Err(()) on error.
+    pub fn sys_timerfd(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let value = u32::try_from(proc.argint(0)?).map_err(|_| ())?;
+        let interval = u32::try_from(proc.argint(1)?).map_err(|_| ())?;
+        let flags = FcntlFlags::from_bits(proc.argint(2)?).ok_or(())?;
+        if !(FcntlFlags::O_CLOEXEC | FcntlFlags::O_NONBLOCK).contains(flags) {
+            return Err(());
+        }
+        let start = *self.ticks.lock();
+        let timer = TimerFile::new(
+            start,
+            value,
+            interval,
+            flags.contains(FcntlFlags::O_NONBLOCK),
+        );
+        self.alloc_event(FileType::Timer { timer }, flags, proc)
+    }
 }
 
 impl CurrentProc<'_> {
     /// Fetch the nth word-sized system call argument as a file descriptor
     /// and return both the descriptor and the corresponding struct file.
-    fn argfd(&self, n: usize) -> Result<(i32, &'_ RcFile), ()> {
+    /// The file stays open while it is in use, even if another process
+    /// sharing the file descriptor table closes the descriptor.
+    fn argfd(&self, n: usize) -> Result<(i32, RcFile), ()> {
         let fd = self.argint(n)?;
-        if fd < 0 || fd >= NOFILE as i32 {
-            return Err(());
-        }
-
-        let f = some_or!(&self.deref_data().open_files[fd as usize], return Err(()));
-
+        let f = self.files().get(fd)?;
         Ok((fd, f))
     }
+
+    /// Fetch the nth word-sized system call argument as a file descriptor
+    /// of a directory that paths start at, or AT_FDCWD for the current
+    /// directory.
+    /// Returns Ok(the inode, or None for AT_FDCWD) on success, Err(()) on error.
+    fn argdirfd(&self, n: usize) -> Result<Option<RcInode>, ()> {
+        if self.argint(n)? == AT_FDCWD {
+            return Ok(None);
+        }
+        match &self.argfd(n)?.1.typ {
+            FileType::Inode { inner } => Ok(Some(inner.ip.clone())),
+            _ => Err(()),
+        }
+    }
 }
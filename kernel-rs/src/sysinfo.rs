@@ -0,0 +1,81 @@
+//! uname() and sysinfo().
+
+use crate::{kernel::Kernel, proc::CurrentProc, vm::UserPtr};
+
+/// Length of each field of `Utsname`, with the NUL.
+const UTSLEN: usize = 32;
+
+/// The identity of the kernel.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Utsname {
+    /// Name of the kernel
+    pub sysname: [u8; UTSLEN],
+
+    /// Version of the kernel
+    pub release: [u8; UTSLEN],
+
+    /// Git commit that the kernel was built from
+    pub version: [u8; UTSLEN],
+
+    /// Hardware
+    pub machine: [u8; UTSLEN],
+}
+
+/// Usage of the system.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Sysinfo {
+    /// Clock ticks since boot
+    pub uptime: u32,
+
+    /// Number of pages of memory
+    pub totalpages: u32,
+
+    /// Number of free pages
+    pub freepages: u32,
+
+    /// Number of processes
+    pub procs: u32,
+}
+
+/// Returns s as a field of `Utsname`, cut so that it ends with NUL.
+fn field(s: &str) -> [u8; UTSLEN] {
+    let mut field = [0; UTSLEN];
+    let n = s.len().min(UTSLEN - 1);
+    field[..n].copy_from_slice(&s.as_bytes()[..n]);
+    field
+}
+
+impl Kernel {
+    /// Store the name, version, git commit and hardware of the kernel at
+    /// addr, a struct utsname.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_uname(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let addr: UserPtr<Utsname> = proc.argptr(0)?;
+        let uts = Utsname {
+            sysname: field("rv6"),
+            release: field(env!("CARGO_PKG_VERSION")),
+            // The Makefile sets RV6_COMMIT when it builds the kernel.
+            version: field(option_env!("RV6_COMMIT").unwrap_or("unknown")),
+            machine: field("riscv64"),
+        };
+        addr.write(&uts, proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Store the uptime in ticks, the total and free pages of memory, and
+    /// the number of processes at addr, a struct sysinfo.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_sysinfo(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let addr: UserPtr<Sysinfo> = proc.argptr(0)?;
+        let info = Sysinfo {
+            uptime: *self.ticks.lock(),
+            totalpages: self.kmem.total_pages() as u32,
+            freepages: self.kmem.free_pages() as u32,
+            procs: self.procs().count() as u32,
+        };
+        addr.write(&info, proc.memory_mut())?;
+        Ok(0)
+    }
+}
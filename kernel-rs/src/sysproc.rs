@@ -1,4 +1,21 @@
-use crate::{kernel::Kernel, poweroff, proc::CurrentProc};
+use core::mem;
+
+use crate::{
+    capability::Capabilities,
+    kernel::Kernel,
+    mman::{MADV_DONTNEED, MADV_WILLNEED, PROT_EXEC, PROT_READ, PROT_WRITE},
+    poweroff::{self, RB_POWEROFF, RB_RESTART},
+    prctl::{PR_CAPBSET_DROP, PR_CAPBSET_READ, PR_GET_DUMPABLE, PR_SET_DUMPABLE},
+    proc::CurrentProc,
+    ptrace::{
+        PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH, PTRACE_GETREGS, PTRACE_PEEKDATA,
+        PTRACE_POKEDATA, PTRACE_SETREGS, PTRACE_SYSCALL, PTRACE_TRACEME,
+    },
+    riscv::{pgroundup, sfence_vma, PteFlags},
+    seccomp::{SeccompFilter, SECCOMP_LOCK, SECCOMP_NWORDS, SECCOMP_RET_ERRNO, SECCOMP_SET_FILTER},
+    time::{Timespec, Timeval},
+    vm::{UserPtr, UserSlice},
+};
 
 impl Kernel {
     /// Terminate the current process; status reported to wait(). No return.
@@ -10,14 +27,19 @@ impl Kernel {
     /// Create a process.
     /// Returns Ok(child’s PID) on success, Err(()) on error.
     pub fn sys_fork(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
-        Ok(self.procs().fork(proc, &self.kmem)? as _)
+        let pid = self
+            .procs()
+            .fork(proc, &self.kmem)
+            .map_err(|_| self.out_of_memory(proc))?;
+        Ok(pid as _)
     }
 
     /// Wait for a child to exit.
     /// Returns Ok(child’s PID) on success, Err(()) on error.
     pub fn sys_wait(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let p = proc.argaddr(0)?;
-        Ok(self.procs().wait(p.into(), proc)? as _)
+        let addr = if p == 0 { None } else { Some(UserPtr::new(p)?) };
+        Ok(self.procs().wait(addr, proc)? as _)
     }
 
     /// Return the current process’s PID.
@@ -25,11 +47,44 @@ impl Kernel {
         Ok(proc.pid() as _)
     }
 
+    /// Return the PID of the current process’s parent, or 0 if it has none.
+    pub fn sys_getppid(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        Ok(self.procs().parent_pid(proc) as _)
+    }
+
+    /// Return the current thread’s ID. A process has one thread, whose ID
+    /// is the PID.
+    pub fn sys_gettid(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        Ok(proc.pid() as _)
+    }
+
+    /// Return the current process’s user ID.
+    pub fn sys_getuid(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        Ok(proc.deref_data().uid as _)
+    }
+
+    /// Set the current process’s user ID, which owns the files it creates
+    /// from then on. It needs CAP_SETUID.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_setuid(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let uid = proc.argint(0)?;
+        if uid < 0 || !proc.is_privileged(Capabilities::SETUID) {
+            return Err(());
+        }
+        proc.deref_mut_data().uid = uid as u32;
+        Ok(0)
+    }
+
     /// Grow process’s memory by n bytes.
     /// Returns Ok(start of new memory) on success, Err(()) on error.
     pub fn sys_sbrk(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
         let n = proc.argint(0)?;
-        proc.memory_mut().resize(n, &self.kmem)
+        let ret = proc.memory_mut().resize(n, &self.kmem);
+        match ret {
+            Ok(_) => proc.update_oom_score(),
+            Err(_) => self.out_of_memory(proc),
+        }
+        ret
     }
 
     /// Pause for n clock ticks.
@@ -61,9 +116,244 @@ impl Kernel {
         Ok(*self.ticks.lock() as usize)
     }
 
-    /// Shutdowns this machine, discarding all unsaved data. No return.
+    /// Copy the most recent bytes of the kernel log, at most n, into buf.
+    /// Returns Ok(the number of bytes copied) on success, Err(()) on error.
+    pub fn sys_dmesg(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let dst = proc.argslice(0, 1)?;
+        self.klog.lock().read(dst, proc.memory_mut())
+    }
+
+    /// Get the wall-clock time into struct timeval.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_gettimeofday(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let tv = proc.argptr(0)?;
+        let now = Timeval::from_ns(self.clock.realtime());
+        tv.write(&now, proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Get the time of the given clock into struct timespec.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_clock_gettime(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let clockid = proc.argint(0)?;
+        let tp = proc.argptr(1)?;
+        let now = Timespec::from_ns(self.clock.get(clockid)?);
+        tp.write(&now, proc.memory_mut())?;
+        Ok(0)
+    }
+
+    /// Shutdowns this machine, discarding all unsaved data.
+    /// It needs CAP_SYS_BOOT. No return on success, Err(()) on error.
     pub fn sys_poweroff(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        if !proc.is_privileged(Capabilities::SYS_BOOT) {
+            return Err(());
+        }
         let exitcode = proc.argint(0)?;
         poweroff::machine_poweroff(exitcode as _);
     }
+
+    /// Power off (RB_POWEROFF) with the given exit code, or restart (RB_RESTART) this machine,
+    /// after committing the file system log and stopping the other harts.
+    /// Only init with CAP_SYS_BOOT may call it. No return on success, Err(()) on error.
+    pub fn sys_reboot(&self, proc: &CurrentProc<'_>) -> Result<usize, ()> {
+        let cmd = proc.argint(0)?;
+        let status = proc.argint(1)?;
+        if proc.pid() != 1
+            || !proc.is_privileged(Capabilities::SYS_BOOT)
+            || (cmd != RB_POWEROFF && cmd != RB_RESTART)
+        {
+            return Err(());
+        }
+
+        self.file_system.log.freeze();
+        self.halt_others();
+        if cmd == RB_RESTART {
+            poweroff::machine_reboot();
+        }
+        poweroff::machine_poweroff(status as _);
+    }
+
+    /// Get (PR_GET_DUMPABLE) or set (PR_SET_DUMPABLE) whether the process
+    /// dumps core when it dies from a fault, or check (PR_CAPBSET_READ) or
+    /// drop (PR_CAPBSET_DROP) a capability.
+    /// Returns Ok(whether it dumps core or has the capability, or 0) on
+    /// success, Err(()) on error.
+    pub fn sys_prctl(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let option = proc.argint(0)?;
+        let arg = proc.argint(1)?;
+        let data = proc.deref_mut_data();
+        match option {
+            PR_GET_DUMPABLE => Ok(data.dumpable as usize),
+            PR_SET_DUMPABLE if arg == 0 || arg == 1 => {
+                data.dumpable = arg == 1;
+                Ok(0)
+            }
+            PR_CAPBSET_READ => {
+                let cap = Capabilities::from_number(arg).ok_or(())?;
+                Ok(data.caps.contains(cap) as usize)
+            }
+            PR_CAPBSET_DROP => {
+                let cap = Capabilities::from_number(arg).ok_or(())?;
+                data.caps.remove(cap);
+                proc.update_oom_score();
+                Ok(0)
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Change the protection of the pages from addr to addr + len to prot, a
+    /// combination of PROT_READ, PROT_WRITE, and PROT_EXEC. Pages may not be
+    /// both writable and executable, and the guard page stays inaccessible.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_mprotect(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let addr = proc.argaddr(0)?;
+        let len = proc.argint(1)?;
+        let prot = proc.argint(2)?;
+        if len < 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(());
+        }
+        let mut perm = PteFlags::empty();
+        perm.set(PteFlags::R, prot & PROT_READ != 0);
+        perm.set(PteFlags::W, prot & PROT_WRITE != 0);
+        perm.set(PteFlags::X, prot & PROT_EXEC != 0);
+        proc.memory_mut()
+            .protect(addr.into(), pgroundup(len as usize), perm)?;
+        // The page table of this process is in use. Other harts that ran this
+        // process flush their TLBs when they switch to it again, in userret,
+        // so flushing this hart's is enough.
+        // SAFETY: flushing the TLB does not change the memory.
+        unsafe { sfence_vma() };
+        Ok(0)
+    }
+
+    /// Advise how the pages from addr to addr + len will be used.
+    /// MADV_DONTNEED frees them, so that they read as zeros again, and
+    /// MADV_WILLNEED gives them physical memory now, rather than when first
+    /// written.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_madvise(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let addr = proc.argaddr(0)?;
+        let len = proc.argint(1)?;
+        let advice = proc.argint(2)?;
+        if len < 0 {
+            return Err(());
+        }
+        let len = pgroundup(len as usize);
+        match advice {
+            MADV_DONTNEED => {
+                proc.memory_mut().discard(addr.into(), len, &self.kmem)?;
+                // The freed pages must not be reached through the TLB.
+                // SAFETY: flushing the TLB does not change the memory.
+                unsafe { sfence_vma() };
+            }
+            MADV_WILLNEED => proc.memory_mut().populate(addr.into(), len, &self.kmem)?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Log the system calls in the mask that this process and its future
+    /// children make. The first argument has the bits of system calls 0 to
+    /// 63, and the second those of 64 to 127.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_trace(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let low = proc.argaddr(0)? as u128;
+        let high = proc.argaddr(1)? as u128;
+        proc.deref_mut_data().trace_mask = high << 64 | low;
+        Ok(0)
+    }
+
+    /// Trace another process with the request in the first argument, on the
+    /// process whose pid is the second argument. The third and fourth
+    /// arguments are an address in the tracee and data, as the request needs.
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_ptrace(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let request = proc.argint(0)?;
+        let pid = proc.argint(1)?;
+        let addr = proc.argaddr(2)?;
+        let data = proc.argaddr(3)?;
+        match request {
+            PTRACE_TRACEME => self.procs().trace_me(proc)?,
+            PTRACE_ATTACH => self.procs().attach(pid, proc)?,
+            PTRACE_PEEKDATA => {
+                let src = UserPtr::<usize>::new(addr)?;
+                let dst = UserPtr::<usize>::new(data)?;
+                self.procs().with_tracee(pid, proc, |_, memory, proc| {
+                    let mut word = 0;
+                    // SAFETY: usize does not have any internal structure.
+                    unsafe { src.read(&mut word, memory) }?;
+                    dst.write(&word, proc.memory_mut())
+                })??
+            }
+            PTRACE_POKEDATA => {
+                let dst = UserSlice::new(addr, mem::size_of::<usize>())?;
+                self.procs().with_tracee(pid, proc, |_, memory, _| {
+                    dst.poke(&data.to_ne_bytes(), memory)
+                })??
+            }
+            PTRACE_GETREGS => {
+                let dst = UserPtr::<[usize; 32]>::new(data)?;
+                self.procs()
+                    .with_tracee(pid, proc, |trap_frame, _, proc| {
+                        dst.write(&trap_frame.user_regs(), proc.memory_mut())
+                    })??
+            }
+            PTRACE_SETREGS => {
+                let src = UserPtr::<[usize; 32]>::new(data)?;
+                let mut regs = [0; 32];
+                // SAFETY: [usize; 32] does not have any internal structure.
+                unsafe { src.read(&mut regs, proc.memory_mut()) }?;
+                self.procs().with_tracee(pid, proc, |trap_frame, _, _| {
+                    trap_frame.set_user_regs(&regs)
+                })?
+            }
+            PTRACE_CONT => self.procs().resume_tracee(pid, false, proc)?,
+            PTRACE_SYSCALL => self.procs().resume_tracee(pid, true, proc)?,
+            PTRACE_DETACH => self.procs().detach_tracee(pid, proc)?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Allow only the system calls in the struct seccomp_filter in the second
+    /// argument (SECCOMP_SET_FILTER), or forbid further changes to the filter
+    /// (SECCOMP_LOCK).
+    /// Returns Ok(0) on success, Err(()) on error.
+    pub fn sys_seccomp(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        match proc.argint(0)? {
+            SECCOMP_SET_FILTER => {
+                let ptr: UserPtr<SeccompFilter> = proc.argptr(1)?;
+                let mut filter = SeccompFilter {
+                    allow: [0; SECCOMP_NWORDS],
+                    action: SECCOMP_RET_ERRNO,
+                };
+                // SAFETY: SeccompFilter only contains integers.
+                unsafe { ptr.read(&mut filter, proc.memory_mut()) }?;
+                proc.deref_mut_data().seccomp.set(filter)?;
+            }
+            SECCOMP_LOCK => proc.deref_mut_data().seccomp.lock()?,
+            _ => return Err(()),
+        }
+        Ok(0)
+    }
+
+    /// Make the requests at the sites in the mask, the first argument, that
+    /// this process makes fail once in the second argument times, at random.
+    /// Returns Ok(0) on success, Err(()) on error, or if the kernel was built
+    /// without the `fault-inject` feature.
+    pub fn sys_faultinject(&self, proc: &mut CurrentProc<'_>) -> Result<usize, ()> {
+        let sites = proc.argint(0)?;
+        let rate = proc.argint(1)?;
+        if sites < 0 || rate < 0 {
+            return Err(());
+        }
+        #[cfg(feature = "fault-inject")]
+        {
+            crate::fault::set(sites as u32, rate as u32, proc.pid());
+            Ok(0)
+        }
+        #[cfg(not(feature = "fault-inject"))]
+        Err(())
+    }
 }
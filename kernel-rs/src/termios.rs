@@ -0,0 +1,90 @@
+//! Terminal attributes, shared with user programs through kernel/termios.h.
+
+use bitflags::bitflags;
+
+/// ioctl request: get the terminal attributes.
+pub const TCGETS: i32 = 0x5401;
+
+/// ioctl request: set the terminal attributes.
+pub const TCSETS: i32 = 0x5402;
+
+/// Index of the end-of-file character in `Termios::cc`.
+pub const VEOF: usize = 0;
+
+/// Index of the erase character in `Termios::cc`.
+pub const VERASE: usize = 1;
+
+/// Index of the kill-line character in `Termios::cc`.
+pub const VKILL: usize = 2;
+
+/// Index of the minimum number of bytes for a non-canonical read in `Termios::cc`.
+pub const VMIN: usize = 3;
+
+/// Size of `Termios::cc`.
+pub const NCCS: usize = 4;
+
+bitflags! {
+    /// Input modes.
+    pub struct InputFlags: u32 {
+        /// Translate carriage return to newline on input.
+        const ICRNL = 0x1;
+    }
+}
+
+bitflags! {
+    /// Local modes.
+    pub struct LocalFlags: u32 {
+        /// Enable control-p (print process list).
+        const ISIG = 0x1;
+        /// Canonical input: line editing, and reads return a line at a time.
+        const ICANON = 0x2;
+        /// Echo input characters.
+        const ECHO = 0x8;
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Termios {
+    /// Input modes
+    pub iflag: InputFlags,
+
+    /// Local modes
+    pub lflag: LocalFlags,
+
+    /// Special characters
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    /// Cooked mode: line editing with echo, as the console has always behaved.
+    pub const fn new() -> Self {
+        Self {
+            iflag: InputFlags::ICRNL,
+            lflag: LocalFlags::from_bits_truncate(
+                LocalFlags::ISIG.bits() | LocalFlags::ICANON.bits() | LocalFlags::ECHO.bits(),
+            ),
+            cc: [ctrl('D'), ctrl('H'), ctrl('U'), 1],
+        }
+    }
+
+    /// Drops unknown bits that a user program may have passed in.
+    pub fn sanitize(&mut self) {
+        self.iflag = InputFlags::from_bits_truncate(self.iflag.bits());
+        self.lflag = LocalFlags::from_bits_truncate(self.lflag.bits());
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        self.lflag.contains(LocalFlags::ICANON)
+    }
+
+    /// Is `c` the special character `cc[index]`? A zero entry disables it.
+    pub fn is_special(&self, c: i32, index: usize) -> bool {
+        self.cc[index] != 0 && c == self.cc[index] as i32
+    }
+}
+
+/// Control-x
+const fn ctrl(x: char) -> u8 {
+    x as u8 - b'@'
+}
@@ -0,0 +1,137 @@
+//! Shared text.
+//!
+//! The pages of the read-only segments of a program are shared by the
+//! processes that run it, instead of each loading its own copy. A `Text`
+//! holds them while a process maps them. Writing to the program makes its
+//! `Text` stale: processes keep what they loaded, but the next exec reads the
+//! program again.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arena::{Arena, ArenaObject, ArrayArena, Rc},
+    fs::InodeGuard,
+    kalloc::Kmem,
+    kernel::kernel_builder,
+    lock::Spinlock,
+    page::Page,
+    param::{MAXTEXT, NTEXT},
+    vm::PAddr,
+};
+
+pub type Texts = Spinlock<ArrayArena<Text, NTEXT>>;
+
+pub type RcText = Rc<Texts>;
+
+pub struct Text {
+    dev: u32,
+    inum: u32,
+
+    /// Was the program written after its pages were read?
+    stale: AtomicBool,
+
+    /// The pages, each with the offset and the length of its bytes in the
+    /// program. The rest of a page is zeros.
+    pages: Spinlock<[Option<(u32, u32, Page)>; MAXTEXT]>,
+}
+
+impl Text {
+    pub const fn zero() -> Self {
+        Self {
+            dev: 0,
+            inum: 0,
+            stale: AtomicBool::new(false),
+            pages: Spinlock::new("TEXT", [None; MAXTEXT]),
+        }
+    }
+
+    /// Returns the address of the page with the len bytes at off in the
+    /// program, which the caller has locked as ip. Reads the page if no
+    /// process did before.
+    /// Returns Ok(address) on success, Err(()) on error, or if the text has
+    /// no room for another page.
+    pub fn page(
+        &self,
+        ip: &mut InodeGuard<'_>,
+        off: u32,
+        len: u32,
+        allocator: &Spinlock<Kmem>,
+    ) -> Result<PAddr, ()> {
+        let found = self
+            .pages
+            .lock()
+            .iter()
+            .flatten()
+            .find(|(o, l, _)| *o == off && *l == len)
+            .map(|(_, _, page)| page.addr());
+        if let Some(pa) = found {
+            return Ok(pa);
+        }
+
+        // Reading the program sleeps, so do it without holding the lock.
+        // Nobody else reads pages meanwhile, since it needs ip.
+        let mut page = allocator.alloc_zeroed().ok_or(())?;
+        if ip.read_bytes_kernel(&mut page[..len as usize], off) != Ok(len as usize) {
+            allocator.free(page);
+            return Err(());
+        }
+        let pa = page.addr();
+        let mut pages = self.pages.lock();
+        match pages.iter_mut().find(|p| p.is_none()) {
+            Some(slot) => {
+                *slot = Some((off, len, page));
+                Ok(pa)
+            }
+            None => {
+                drop(pages);
+                allocator.free(page);
+                Err(())
+            }
+        }
+    }
+}
+
+#[rustfmt::skip] // Need this if lower than rustfmt 1.4.34
+impl const Default for Text {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl ArenaObject for Text {
+    fn finalize<'s, A: Arena>(&'s mut self, _guard: &'s mut A::Guard<'_>) {
+        // TODO: remove kernel_builder()
+        let allocator = &kernel_builder().kmem;
+        for (_, _, page) in self.pages.get_mut().iter_mut().filter_map(Option::take) {
+            allocator.free(page);
+        }
+    }
+}
+
+impl Texts {
+    pub const fn zero() -> Self {
+        Spinlock::new("TEXTS", ArrayArena::<Text, NTEXT>::new())
+    }
+
+    /// Returns the text of the program that the caller has locked as ip.
+    pub fn get(&self, ip: &InodeGuard<'_>) -> Option<RcText> {
+        let (dev, inum) = (ip.dev, ip.inum);
+        self.find_or_alloc(
+            |text| text.dev == dev && text.inum == inum && !text.stale.load(Ordering::Relaxed),
+            |text| {
+                text.dev = dev;
+                text.inum = inum;
+                *text.stale.get_mut() = false;
+            },
+        )
+    }
+
+    /// Makes the text of the program (dev, inum) stale, as it is written.
+    pub fn invalidate(&self, dev: u32, inum: u32) {
+        self.for_each_used(|text| {
+            if text.dev == dev && text.inum == inum {
+                text.stale.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+}
@@ -0,0 +1,88 @@
+//! Kernel time of day, from the RTC at boot and the time counter since then.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{riscv::r_time, rtc};
+
+/// Frequency of the time counter (qemu -machine virt).
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// clock_gettime() clock: wall-clock time.
+pub const CLOCK_REALTIME: i32 = 0;
+
+/// clock_gettime() clock: time since boot, which never jumps.
+pub const CLOCK_MONOTONIC: i32 = 1;
+
+/// Time in seconds and nanoseconds, as in struct timespec.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+/// Time in seconds and microseconds, as in struct timeval.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Timeval {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+impl Timespec {
+    pub const fn from_ns(ns: u64) -> Self {
+        Self {
+            sec: (ns / NSEC_PER_SEC) as i64,
+            nsec: (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+}
+
+impl Timeval {
+    pub const fn from_ns(ns: u64) -> Self {
+        Self {
+            sec: (ns / NSEC_PER_SEC) as i64,
+            usec: (ns % NSEC_PER_SEC / 1000) as i64,
+        }
+    }
+}
+
+pub struct Clock {
+    /// Nanoseconds since the epoch when the time counter was zero.
+    offset: AtomicU64,
+}
+
+impl Clock {
+    pub const fn new() -> Self {
+        Self {
+            offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Read the RTC once. Afterwards, the time is kept by the time counter.
+    pub fn init(&self) {
+        let offset = rtc::read_ns().saturating_sub(self.monotonic());
+        self.offset.store(offset, Ordering::Release);
+    }
+
+    /// Returns nanoseconds since boot.
+    pub fn monotonic(&self) -> u64 {
+        r_time() * (NSEC_PER_SEC / TIMEBASE_FREQ)
+    }
+
+    /// Returns nanoseconds since the epoch.
+    pub fn realtime(&self) -> u64 {
+        self.offset.load(Ordering::Acquire) + self.monotonic()
+    }
+
+    /// Returns nanoseconds of the given clock, or Err(()) if there is no such clock.
+    pub fn get(&self, clockid: i32) -> Result<u64, ()> {
+        match clockid {
+            CLOCK_REALTIME => Ok(self.realtime()),
+            CLOCK_MONOTONIC => Ok(self.monotonic()),
+            _ => Err(()),
+        }
+    }
+}
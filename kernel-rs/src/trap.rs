@@ -1,17 +1,32 @@
 use core::mem;
 
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub::{self, KernelFrame};
+#[cfg(feature = "stack-check")]
+use crate::riscv::r_sp;
 use crate::{
+    backtrace::dump_hart,
+    coredump::signal,
     kernel::{kernel, Kernel},
-    memlayout::{TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ},
+    klog,
+    kstat::Counter,
+    memlayout::{TRAMPOLINE, TRAPFRAME},
     ok_or,
-    plic::{plic_claim, plic_complete},
+    plic::{self, plic_claim, plic_complete},
+    poweroff::halt_hart,
     println,
     proc::{cpuid, CurrentProc, Procstate},
+    ptrace::{SIGSTOP, SYSCALL_STOP},
     riscv::{
-        intr_get, intr_off, intr_on, r_satp, r_scause, r_sepc, r_sip, r_stval, r_tp, w_sepc, w_sip,
-        w_stvec, Sstatus, PGSIZE,
+        intr_get, intr_off, intr_on, pgrounddown, r_satp, r_scause, r_sepc, r_sip, r_stval, r_tp,
+        w_sepc, w_sip, w_stvec, Sstatus, PGSIZE,
     },
+    virtio::Disk,
+    watchdog,
+    workqueue::{self, Work},
 };
+#[cfg(feature = "sbi")]
+use crate::{riscv::r_time, sbi, start::TIMER_INTERVAL};
 
 extern "C" {
     // trampoline.S
@@ -25,7 +40,74 @@ extern "C" {
     fn kernelvec();
 }
 
-pub fn trapinit() {}
+/// Set in scause if the trap is an interrupt.
+const SCAUSE_INTR: usize = 1 << 63;
+
+/// The cause of a trap, from scause.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ScauseKind {
+    /// A machine-mode timer interrupt, forwarded by timervec in kernelvec.S.
+    SoftwareIntr,
+
+    /// A timer interrupt from SBI.
+    TimerIntr,
+
+    /// A device interrupt, via the PLIC.
+    ExternalIntr,
+
+    InstrMisaligned,
+    InstrAccessFault,
+    IllegalInstr,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+
+    /// A system call from user space.
+    UserEcall,
+
+    InstrPageFault,
+    LoadPageFault,
+    StorePageFault,
+
+    /// Any other scause.
+    Unknown(usize),
+}
+
+impl ScauseKind {
+    /// The cause of the current trap.
+    pub fn read() -> Self {
+        Self::from_raw(r_scause())
+    }
+
+    pub fn from_raw(scause: usize) -> Self {
+        if scause & SCAUSE_INTR != 0 {
+            match scause & !SCAUSE_INTR {
+                1 => Self::SoftwareIntr,
+                5 => Self::TimerIntr,
+                9 => Self::ExternalIntr,
+                _ => Self::Unknown(scause),
+            }
+        } else {
+            match scause {
+                0 => Self::InstrMisaligned,
+                1 => Self::InstrAccessFault,
+                2 => Self::IllegalInstr,
+                3 => Self::Breakpoint,
+                4 => Self::LoadMisaligned,
+                5 => Self::LoadAccessFault,
+                6 => Self::StoreMisaligned,
+                7 => Self::StoreAccessFault,
+                8 => Self::UserEcall,
+                12 => Self::InstrPageFault,
+                13 => Self::LoadPageFault,
+                15 => Self::StorePageFault,
+                _ => Self::Unknown(scause),
+            }
+        }
+    }
+}
 
 /// Set up to take exceptions and traps while in the kernel.
 pub unsafe fn trapinithart() {
@@ -50,44 +132,92 @@ pub unsafe extern "C" fn usertrap() {
     // SAFETY: usertrap can be reached only after the initialization of the kernel
     let kernel = unsafe { kernel() };
     let mut proc = kernel.current_proc().expect("No current proc");
+    #[cfg(feature = "stack-check")]
+    proc.deref_data().check_sp(r_sp());
 
-    // Save user program counter.
+    // Save user program counter, and FP registers if changed.
     proc.trap_frame_mut().epc = r_sepc();
-    if r_scause() == 8 {
-        // system call
+    proc.trap_frame_mut().fp.save_if_dirty();
+    let cause = ScauseKind::read();
+    match cause {
+        ScauseKind::UserEcall => {
+            if proc.ptrace().syscalls() {
+                let _ = kernel.procs().ptrace_stop(SYSCALL_STOP, &mut proc);
+            }
 
-        if proc.killed() {
-            kernel.procs().exit_current(-1, &mut proc);
-        }
+            if proc.killed() {
+                kernel.procs().exit_current(-1, &mut proc);
+            }
 
-        // sepc points to the ecall instruction,
-        // but we want to return to the next instruction.
-        proc.trap_frame_mut().epc = (proc.trap_frame().epc).wrapping_add(4);
+            // sepc points to the ecall instruction,
+            // but we want to return to the next instruction.
+            proc.trap_frame_mut().epc = (proc.trap_frame().epc).wrapping_add(4);
 
-        // An interrupt will change sstatus &c registers,
-        // so don't enable until done with those registers.
-        unsafe { intr_on() };
-        proc.trap_frame_mut().a0 = ok_or!(
-            kernel.syscall(proc.trap_frame_mut().a7 as i32, &mut proc),
-            usize::MAX
-        );
-    } else {
-        which_dev = unsafe { devintr(&kernel) };
-        if which_dev == 0 {
-            println!(
-                "usertrap(): unexpected scause {:018p} pid={}",
-                r_scause() as *const u8,
-                proc.pid()
-            );
-            println!(
-                "            sepc={:018p} stval={:018p}",
-                r_sepc() as *const u8,
-                r_stval() as *const u8
+            // An interrupt will change sstatus &c registers,
+            // so don't enable until done with those registers.
+            unsafe { intr_on() };
+            proc.trap_frame_mut().a0 = ok_or!(
+                kernel.syscall(proc.trap_frame_mut().a7 as i32, &mut proc),
+                usize::MAX
             );
-            proc.kill();
+            proc.deref_data().check_kstack();
+
+            if proc.ptrace().syscalls() {
+                let _ = kernel.procs().ptrace_stop(SYSCALL_STOP, &mut proc);
+            }
+        }
+        ScauseKind::StorePageFault if proc.memory_mut().is_cow(r_stval().into()) => {
+            // A write to the zero page. Give the page a page of its own, and
+            // retry the instruction.
+            kernel.kstat.inc(Counter::StorePageFault);
+            let va = pgrounddown(r_stval()).into();
+            if proc.memory_mut().unshare(va, &kernel.kmem).is_err() {
+                kernel.out_of_memory(&proc);
+                klog!(Warn, "usertrap(): out of memory pid={}", proc.pid());
+                proc.kill();
+            }
+        }
+        ScauseKind::IllegalInstr if proc.trap_frame().fp.restore_if_off() => {
+            // An illegal instruction while FP is off, probably an FP instruction.
+            // FP is now on with the process's registers; retry the instruction.
+        }
+        _ => {
+            which_dev = unsafe { devintr(&kernel, cause) };
+            // SAFETY: the trap came from user space, where no spinlock is held.
+            unsafe { workqueue::run(kernel) };
+            if let Some(counter) = Counter::page_fault(cause) {
+                kernel.kstat.inc(counter);
+            }
+            // A traced process stops for its tracer instead of dying.
+            if which_dev == 0 && !kernel.procs().ptrace_stop(signal(cause), &mut proc) {
+                klog!(
+                    Warn,
+                    "usertrap(): unexpected scause {:018p} pid={}",
+                    r_scause() as *const u8,
+                    proc.pid()
+                );
+                klog!(
+                    Warn,
+                    "            sepc={:018p} stval={:018p}",
+                    r_sepc() as *const u8,
+                    r_stval() as *const u8
+                );
+                if proc.deref_data().dumpable {
+                    // Writing the core file sleeps on the disk.
+                    unsafe { intr_on() };
+                    if kernel.dump_core(cause, &mut proc).is_err() {
+                        klog!(Warn, "usertrap(): cannot dump core pid={}", proc.pid());
+                    }
+                }
+                proc.kill();
+            }
         }
     }
 
+    if proc.ptrace().is_pending() {
+        let _ = kernel.procs().ptrace_stop(SIGSTOP, &mut proc);
+    }
+
     if proc.killed() {
         kernel.procs().exit_current(-1, &mut proc);
     }
@@ -159,7 +289,7 @@ pub unsafe fn usertrapret(mut proc: CurrentProc<'_>) {
 /// Interrupts and exceptions from kernel code go here via kernelvec,
 /// on whatever the current kernel stack is.
 #[no_mangle]
-pub unsafe fn kerneltrap() {
+pub unsafe extern "C" fn kerneltrap(#[cfg(feature = "gdbstub")] frame: &mut KernelFrame) {
     let sepc = r_sepc();
     let sstatus = Sstatus::read();
     let scause = r_scause();
@@ -173,7 +303,22 @@ pub unsafe fn kerneltrap() {
     // SAFETY: kerneltrap can be reached only after the initialization of the kernel
     let kernel = unsafe { kernel() };
 
-    let which_dev = unsafe { devintr(&kernel) };
+    #[cfg(feature = "stack-check")]
+    if let Some(proc) = kernel.current_proc() {
+        proc.deref_data().check_kstack();
+        proc.deref_data().check_sp(r_sp());
+    }
+
+    // A breakpoint, for gdb.
+    #[cfg(feature = "gdbstub")]
+    if ScauseKind::from_raw(scause) == ScauseKind::Breakpoint {
+        if let Some(pc) = gdbstub::trap(frame, sepc) {
+            unsafe { w_sepc(pc) };
+            return;
+        }
+    }
+
+    let which_dev = unsafe { devintr(&kernel, ScauseKind::from_raw(scause)) };
     if which_dev == 0 {
         println!("scause {:018p}", scause as *const u8);
         println!(
@@ -184,13 +329,17 @@ pub unsafe fn kerneltrap() {
         panic!("kerneltrap");
     }
 
+    // SAFETY: an interrupt came while interrupts were on, and hence no
+    // spinlock was held.
+    unsafe { workqueue::run(kernel) };
+
     // Give up the CPU if this is a timer interrupt.
     if which_dev == 2 {
         if let Some(proc) = kernel.current_proc() {
             // SAFETY:
             // Reading state without lock is safe because `proc_yield` and `sched`
-            // is called after we check if current process is `RUNNING`.
-            if unsafe { (*proc.info.get_mut_raw()).state } == Procstate::RUNNING {
+            // is called after we check if current process is `Running`.
+            if unsafe { (*proc.info.get_mut_raw()).state } == Procstate::Running {
                 unsafe { proc.proc_yield() };
             }
         }
@@ -198,6 +347,10 @@ pub unsafe fn kerneltrap() {
 
     // The yield() may have caused some traps to occur,
     // so restore trap registers for use by kernelvec.S's sepc instruction.
+    // Keep FS, as scheduler() may have turned FP off meanwhile.
+    let mut sstatus = sstatus;
+    sstatus.remove(Sstatus::FS_DIRTY);
+    sstatus.insert(Sstatus::read() & Sstatus::FS_DIRTY);
     unsafe { w_sepc(sepc) };
     unsafe { sstatus.write() };
 }
@@ -205,55 +358,109 @@ pub unsafe fn kerneltrap() {
 fn clockintr(kernel: &Kernel) {
     let mut ticks = kernel.ticks.lock();
     *ticks = ticks.wrapping_add(1);
-    ticks.wakeup();
+    kernel.vdso.update(*ticks, &kernel.clock);
+    ticks.wakeup_all();
+
+    workqueue::queue(&DISK_TIMEOUT_WORK);
+}
+
+/// Wakes the waiters of the disk requests that are past their deadline.
+static DISK_TIMEOUT_WORK: Work = Work::new(|kernel| {
+    Disk::expire(&mut kernel.file_system.log.disk.lock());
+    Disk::expire(&mut kernel.fat32.disk.lock());
+    for disk in &kernel.md.disks {
+        Disk::expire(&mut disk.lock());
+    }
+});
+
+/// Samples the interrupted pc for the profiler, at a timer interrupt.
+fn profileintr(kernel: &Kernel) {
+    let in_kernel = Sstatus::read().contains(Sstatus::SPP);
+    let pid = kernel.current_proc().map(|proc| proc.pid());
+    kernel.profile.sample(r_sepc(), pid, in_kernel);
 }
 
-/// Check if it's an external interrupt or software interrupt,
+/// Check if `cause` is an external interrupt or software interrupt,
 /// and handle it.
 /// Returns 2 if timer interrupt,
 /// 1 if other device,
 /// 0 if not recognized.
-unsafe fn devintr(kernel: &Kernel) -> i32 {
-    let scause: usize = r_scause();
-
-    if scause & 0x8000000000000000 != 0 && scause & 0xff == 9 {
-        // This is a supervisor external interrupt, via PLIC.
-
-        // irq indicates which device interrupted.
-        let irq = unsafe { plic_claim() };
-
-        if irq as usize == UART0_IRQ {
-            kernel.uart.intr();
-        } else if irq as usize == VIRTIO0_IRQ {
-            kernel.file_system.log.disk.lock().intr();
-        } else if irq != 0 {
-            // Use `panic!` instead of `println` to prevent stack overflow.
-            // https://github.com/kaist-cp/rv6/issues/311
-            panic!("unexpected interrupt irq={}\n", irq);
+unsafe fn devintr(kernel: &Kernel, cause: ScauseKind) -> i32 {
+    // Interrupts are off, so we stay on this cpu.
+    let cpu = kernel.current_cpu();
+    unsafe { (*cpu).nintr += 1 };
+    let which_dev = unsafe { handle_devintr(kernel, cause) };
+    unsafe { (*cpu).nintr -= 1 };
+    which_dev
+}
+
+unsafe fn handle_devintr(kernel: &Kernel, cause: ScauseKind) -> i32 {
+    if kernel.is_halted() {
+        if kernel.is_panicked() {
+            dump_hart(
+                cpuid(),
+                r_sepc(),
+                kernel.current_proc().map(|proc| proc.pid()),
+            );
         }
+        halt_hart();
+    }
+
+    #[cfg(feature = "gdbstub")]
+    gdbstub::wait();
+
+    match cause {
+        #[cfg(feature = "sbi")]
+        ScauseKind::TimerIntr => {
+            // Supervisor timer interrupt.
+            // Asking for the next one acknowledges it.
+            sbi::set_timer(r_time() + TIMER_INTERVAL as u64);
+
+            watchdog::tick(kernel);
+            profileintr(kernel);
+            if cpuid() == 0 {
+                clockintr(kernel);
+            }
 
-        // The PLIC allows each device to raise at most one
-        // interrupt at a time; tell the PLIC the device is
-        // now allowed to interrupt again.
-        if irq != 0 {
-            unsafe { plic_complete(irq) };
+            2
         }
+        ScauseKind::ExternalIntr => {
+            // This is a supervisor external interrupt, via PLIC.
+
+            // irq indicates which device interrupted.
+            let irq = unsafe { plic_claim() };
 
-        1
-    } else if scause == 0x8000000000000001 {
-        // Software interrupt from a machine-mode timer interrupt,
-        // forwarded by timervec in kernelvec.S.
+            if irq != 0 && !plic::handle(kernel, irq as usize) {
+                // Use `panic!` instead of `println` to prevent stack overflow.
+                // https://github.com/kaist-cp/rv6/issues/311
+                panic!("unexpected interrupt irq={}\n", irq);
+            }
 
-        if cpuid() == 0 {
-            clockintr(kernel);
+            // The PLIC allows each device to raise at most one
+            // interrupt at a time; tell the PLIC the device is
+            // now allowed to interrupt again.
+            if irq != 0 {
+                unsafe { plic_complete(irq) };
+            }
+
+            1
         }
+        ScauseKind::SoftwareIntr => {
+            // Software interrupt from a machine-mode timer interrupt,
+            // forwarded by timervec in kernelvec.S.
+
+            watchdog::tick(kernel);
+            profileintr(kernel);
+            if cpuid() == 0 {
+                clockintr(kernel);
+            }
 
-        // Acknowledge the software interrupt by clearing
-        // the SSIP bit in sip.
-        unsafe { w_sip(r_sip() & !2) };
+            // Acknowledge the software interrupt by clearing
+            // the SSIP bit in sip.
+            unsafe { w_sip(r_sip() & !2) };
 
-        2
-    } else {
-        0
+            2
+        }
+        _ => 0,
     }
 }
@@ -2,16 +2,23 @@
 use core::ptr;
 
 use self::UartCtrlRegs::{FCR, IER, ISR, LCR, LSR, RBR, THR};
-use crate::memlayout::UART0;
 use crate::{
-    console::consoleintr,
+    console::{consoleintr, consoleintr_end},
     kernel::kernel_builder,
-    lock::{pop_off, push_off, Sleepablelock, SleepablelockGuard},
+    lock::{pop_off, push_off, Sleepablelock, SleepablelockGuard, Spinlock},
+    platform::platform,
     utils::spin_loop,
+    workqueue::{self, Work},
 };
 
 const UART_TX_BUF_SIZE: usize = 32;
 
+/// Size of the buffer of input that the bottom half has yet to hand over.
+const UART_RX_BUF_SIZE: usize = 64;
+
+/// The bottom half of a uart interrupt.
+static RX_WORK: Work = Work::new(|kernel| kernel.uart.rx());
+
 enum UartRegBits {
     IERTxEnable,
     IERRxEnable,
@@ -65,15 +72,16 @@ enum UartCtrlRegs {
 
 impl UartCtrlRegs {
     /// The UART control registers are memory-mapped
-    /// at address UART0. This macro returns the
+    /// at the uart's base address. This macro returns the
     /// address of one of the registers.
     fn reg(self) -> *mut u8 {
+        let base = platform().uart.base;
         match self {
-            THR | RBR => UART0 as *mut u8,
-            IER => (UART0 + 1) as *mut u8,
-            FCR | ISR => (UART0 + 2) as *mut u8,
-            LCR => (UART0 + 3) as *mut u8,
-            LSR => (UART0 + 5) as *mut u8,
+            THR | RBR => base as *mut u8,
+            IER => (base + 1) as *mut u8,
+            FCR | ISR => (base + 2) as *mut u8,
+            LCR => (base + 3) as *mut u8,
+            LSR => (base + 5) as *mut u8,
         }
     }
 
@@ -96,8 +104,18 @@ pub struct UartTX {
     pub r: u64,
 }
 
+/// Input read by the top half of an interrupt.
+struct UartRX {
+    buf: [u8; UART_RX_BUF_SIZE],
+
+    /// The oldest character is at `buf[r % UART_RX_BUF_SIZE]`.
+    r: usize,
+    w: usize,
+}
+
 pub struct Uart {
     pub tx_lock: Sleepablelock<UartTX>,
+    rx: Spinlock<UartRX>,
 }
 
 impl Uart {
@@ -111,6 +129,14 @@ impl Uart {
                     r: 0,
                 },
             ),
+            rx: Spinlock::new(
+                "uart_rx",
+                UartRX {
+                    buf: [0; UART_RX_BUF_SIZE],
+                    r: 0,
+                    w: 0,
+                },
+            ),
         }
     }
 
@@ -210,7 +236,7 @@ impl Uart {
             guard.r += 1;
 
             // Maybe uartputc() is waiting for space in the buffer.
-            guard.wakeup();
+            guard.wakeup_all();
 
             THR.write(c);
         }
@@ -233,18 +259,48 @@ impl Uart {
     /// arrived, or the uart is ready for more output, or
     /// both. Called from trap.c.
     pub fn intr(&self) {
-        // Read and process incoming characters.
-        loop {
-            let c = Uart::getc();
-            if c == -1 {
-                break;
+        // Read incoming characters, and leave the processing of them to the
+        // bottom half. Input that does not fit is dropped.
+        {
+            let mut rx = self.rx.lock();
+            loop {
+                let c = Uart::getc();
+                if c == -1 {
+                    break;
+                }
+                if rx.w - rx.r < UART_RX_BUF_SIZE {
+                    let w = rx.w;
+                    rx.buf[w % UART_RX_BUF_SIZE] = c as u8;
+                    rx.w += 1;
+                }
             }
-            unsafe {
-                consoleintr(c);
+            if rx.w != rx.r {
+                workqueue::queue(&RX_WORK);
             }
         }
 
         // Send buffered characters.
         self.start(self.tx_lock.lock());
     }
+
+    /// Hands the input read by `Uart::intr` over to the console.
+    fn rx(&self) {
+        loop {
+            let c = {
+                let mut rx = self.rx.lock();
+                if rx.r == rx.w {
+                    break;
+                }
+                let r = rx.r;
+                rx.r += 1;
+                rx.buf[r % UART_RX_BUF_SIZE]
+            };
+            unsafe {
+                consoleintr(c as i32);
+            }
+        }
+        unsafe {
+            consoleintr_end();
+        }
+    }
 }
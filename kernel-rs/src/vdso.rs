@@ -0,0 +1,65 @@
+//! Read-only pages that every process can read without a system call.
+//!
+//! VDSO_TIME is a single page that every process shares. The kernel updates
+//! it at each timer interrupt. VDSO_PROC is one page per process, and holds
+//! constants such as the pid. kernel/vdso.h describes the same layout to user
+//! programs.
+
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::time::Clock;
+
+/// The page at VDSO_TIME.
+// It must be page-aligned, and fill its page, because it is mapped into user
+// memory as it is.
+// It needs repr(C) because it is read by user programs.
+#[repr(C, align(4096))]
+pub struct VdsoTime {
+    /// Odd while the kernel updates the page. Readers retry if it is odd, or
+    /// has changed while they read.
+    seq: AtomicU32,
+
+    /// Timer interrupts since boot, as uptime() returns.
+    ticks: AtomicU32,
+
+    /// Nanoseconds since boot, at the last timer interrupt.
+    monotonic: AtomicU64,
+
+    /// Nanoseconds since the epoch, at the last timer interrupt.
+    realtime: AtomicU64,
+}
+
+/// The page at VDSO_PROC.
+// It needs repr(C) because it is read by user programs.
+#[repr(C)]
+pub struct VdsoProc {
+    pub pid: i32,
+}
+
+impl VdsoTime {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            ticks: AtomicU32::new(0),
+            monotonic: AtomicU64::new(0),
+            realtime: AtomicU64::new(0),
+        }
+    }
+
+    /// Physical address of the page.
+    pub fn addr(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Record the time of a timer interrupt.
+    /// Only one hart may call it at a time.
+    pub fn update(&self, ticks: u32, clock: &Clock) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.ticks.store(ticks, Ordering::Relaxed);
+        self.monotonic.store(clock.monotonic(), Ordering::Relaxed);
+        self.realtime.store(clock.realtime(), Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
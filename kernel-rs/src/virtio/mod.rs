@@ -13,11 +13,15 @@ use core::ptr;
 
 use bitflags::bitflags;
 
-use crate::memlayout::VIRTIO0;
-
 mod virtio_disk;
+mod virtio_input;
+#[cfg(feature = "gdbstub")]
+mod virtio_serial;
 
 pub use virtio_disk::Disk;
+pub use virtio_input::Keyboard;
+#[cfg(feature = "gdbstub")]
+pub use virtio_serial::Serial;
 
 /// Memory mapped IO registers.
 /// The kernel and virtio driver communicates to each other using these registers.
@@ -32,7 +36,7 @@ enum MmioRegs {
     MagicValue = 0x000,
     /// version; 1 is legacy
     Version = 0x004,
-    /// device type; 1 is net, 2 is disk
+    /// device type; 1 is net, 2 is disk, 3 is console, 18 is input
     DeviceId = 0x008,
     /// 0x554d4551
     VendorId = 0x00c,
@@ -58,66 +62,76 @@ enum MmioRegs {
     InterruptAck = 0x064,
     /// read/write
     Status = 0x070,
+    /// in the configuration of a disk: most sectors that a discard may
+    /// cover, read-only
+    BlkMaxDiscardSectors = 0x124,
 }
 
 impl MmioRegs {
-    fn read(self) -> u32 {
+    /// Reads the register of the mmio interface at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` is the address of a virtio mmio interface mapped by `KernelMemory::new`.
+    unsafe fn read_at(self, base: usize) -> u32 {
         // SAFETY:
-        // * `src` is valid, as the kernel can access [VIRTIO0..VIRTIO0+PGSIZE).
+        // * `src` is valid, as the kernel can access [base..base+PGSIZE).
         // * `src` is properly aligned, as self % 4 == 0.
         // * `src` points to a properly initialized value, as u32 does not have
         //   any internal structure to be initialized.
         // * volatile concurrent accesses are safe.
         //   (https://github.com/kaist-cp/rv6/issues/188#issuecomment-683548362)
-        unsafe { ptr::read_volatile((VIRTIO0 as *mut u8).add(self as _) as _) }
+        unsafe { ptr::read_volatile((base as *mut u8).add(self as _) as _) }
     }
 
+    /// Writes the register of the mmio interface at `base`.
+    ///
     /// # Safety
     ///
+    /// `base` is the address of a virtio mmio interface mapped by `KernelMemory::new`.
+    ///
     /// Writing at memory mapped registers may cause hardware side effects.
     /// For example, after writing at `QueueNotify`, the virtio driver reads/writes the address given by the kernel.
     /// If a wrong address was given, this could lead to undefined behavior.
-    unsafe fn write(self, dst: u32) {
+    unsafe fn write_at(self, base: usize, dst: u32) {
         // SAFETY:
-        // * `dst` is valid, as the kernel can access [VIRTIO0..VIRTIO0+PGSIZE).
+        // * `dst` is valid, as the kernel can access [base..base+PGSIZE).
         // * `dst` is properly aligned, as self % 4 == 0.
         // * volatile concurrent accesses are safe.
         //   (https://github.com/kaist-cp/rv6/issues/188#issuecomment-683548362)
-        unsafe { ptr::write_volatile((VIRTIO0 as *mut u8).add(self as _) as _, dst) }
+        unsafe { ptr::write_volatile((base as *mut u8).add(self as _) as _, dst) }
     }
 
-    /// Checks the virtio disk's properties.
-    fn check_virtio_disk() {
-        assert!(
-            MmioRegs::MagicValue.read() == 0x74726976,
-            "could not find virtio disk"
-        );
-        assert!(MmioRegs::Version.read() == 1, "could not find virtio disk");
-        assert!(MmioRegs::DeviceId.read() == 2, "could not find virtio disk");
-        assert!(
-            MmioRegs::VendorId.read() == 0x554d4551,
-            "could not find virtio disk"
-        );
+    /// Is there a virtio disk at the interface at `base`, one of platform()?
+    fn is_virtio_disk(base: usize) -> bool {
+        // SAFETY: the kernel maps every virtio mmio interface of platform().
+        unsafe {
+            MmioRegs::MagicValue.read_at(base) == 0x74726976
+                && MmioRegs::Version.read_at(base) == 1
+                && MmioRegs::DeviceId.read_at(base) == 2
+                && MmioRegs::VendorId.read_at(base) == 0x554d4551
+        }
     }
 
     /// Sets the virtio status.
-    fn set_status(status: &VirtIOStatus) {
+    fn set_status(base: usize, status: &VirtIOStatus) {
         // SAFETY: simply setting status bits does not cause side effects.
         unsafe {
-            MmioRegs::Status.write(status.bits());
+            MmioRegs::Status.write_at(base, status.bits());
         }
     }
 
     /// Returns the device's virtio features.
-    fn get_features() -> VirtIOFeatures {
-        VirtIOFeatures::from_bits_truncate(MmioRegs::DeviceFeatures.read())
+    fn get_features(base: usize) -> VirtIOFeatures {
+        // SAFETY: the kernel maps every virtio mmio interface of platform().
+        VirtIOFeatures::from_bits_truncate(unsafe { MmioRegs::DeviceFeatures.read_at(base) })
     }
 
     /// Sets the device's virtio features.
-    fn set_features(features: &VirtIOFeatures) {
+    fn set_features(base: usize, features: &VirtIOFeatures) {
         // SAFETY: simply setting features bits does not cause side effects.
         unsafe {
-            MmioRegs::DriverFeatures.write(features.bits());
+            MmioRegs::DriverFeatures.write_at(base, features.bits());
         }
     }
 
@@ -127,10 +141,10 @@ impl MmioRegs {
     ///
     /// The virtio driver will uses this info to calculate addresses.
     /// Hence, the caller must give the correct page size. Otherwise, the driver may read/write at wrong addresses.
-    unsafe fn set_pg_size(size: u32) {
+    unsafe fn set_pg_size(base: usize, size: u32) {
         // SAFETY: simply telling the page size does not cause side effects.
         unsafe {
-            MmioRegs::GuestPageSize.write(size);
+            MmioRegs::GuestPageSize.write_at(base, size);
         }
     }
 
@@ -140,18 +154,24 @@ impl MmioRegs {
     ///
     /// The virtio driver will later use this info to read/write descriptors.
     /// Hence, the caller must give correct info.
-    unsafe fn select_and_init_queue(queue_num: u32, queue_size: u32, queue_pg_num: u32) {
+    unsafe fn select_and_init_queue(
+        base: usize,
+        queue_num: u32,
+        queue_size: u32,
+        queue_pg_num: u32,
+    ) {
         // SAFETY: simply selecting and initializing the queue does not cause side effects.
         unsafe {
-            MmioRegs::QueueSel.write(queue_num);
+            MmioRegs::QueueSel.write_at(base, queue_num);
         }
-        let max = MmioRegs::QueueNumMax.read();
+        // SAFETY: the kernel maps every virtio mmio interface of platform().
+        let max = unsafe { MmioRegs::QueueNumMax.read_at(base) };
         assert!(max != 0, "virtio disk has no queue {}", queue_num);
         assert!(max >= NUM as u32, "virtio disk max queue too short");
 
         unsafe {
-            MmioRegs::QueueNum.write(queue_size);
-            MmioRegs::QueuePfn.write(queue_pg_num);
+            MmioRegs::QueueNum.write_at(base, queue_size);
+            MmioRegs::QueuePfn.write_at(base, queue_pg_num);
         }
     }
 
@@ -161,18 +181,18 @@ impl MmioRegs {
     ///
     /// After notifying the queue, the driver will try to access the queue and read/write at the addresses given through descriptors.
     /// This may cause undefined behavior if the descriptors were not well set or contains wrong addresses.
-    unsafe fn notify_queue(num: u32) {
+    unsafe fn notify_queue(base: usize, num: u32) {
         unsafe {
-            MmioRegs::QueueNotify.write(num);
+            MmioRegs::QueueNotify.write_at(base, num);
         }
     }
 
     /// Acknowledges all interrupts.
-    fn intr_ack_all() {
-        let intr_status = MmioRegs::InterruptStatus.read() & 0x3;
+    fn intr_ack_all(base: usize) {
         // SAFETY: simply acknowledging interrupts does not cause undefined behavior.
         unsafe {
-            MmioRegs::InterruptAck.write(intr_status);
+            let intr_status = MmioRegs::InterruptStatus.read_at(base) & 0x3;
+            MmioRegs::InterruptAck.write_at(base, intr_status);
         }
     }
 }
@@ -196,6 +216,12 @@ bitflags! {
         /// Supports scsi command passthru
         const BLK_F_SCSI = 1 << 7;
 
+        /// Cache flush command support
+        const BLK_F_FLUSH = 1 << 9;
+
+        /// Discard command support
+        const BLK_F_DISCARD = 1 << 13;
+
         /// Writeback mode available in config
         const BLK_F_CONFIG_WCE = 1 << 11;
 
@@ -209,6 +235,8 @@ bitflags! {
         const ETC =
             !Self::BLK_F_RO.bits &
             !Self::BLK_F_SCSI.bits &
+            !Self::BLK_F_FLUSH.bits &
+            !Self::BLK_F_DISCARD.bits &
             !Self::BLK_F_CONFIG_WCE.bits &
             !Self::BLK_F_MQ.bits &
             !Self::F_ANY_LAYOUT.bits &
@@ -290,6 +318,13 @@ struct VirtqUsedElem {
     len: u32,
 }
 
+/// The status of a finished request, on success.
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Not a status that the device writes, so the status of a request that has
+/// not finished.
+const VIRTIO_BLK_S_PENDING: u8 = 0xff;
+
 /// for disk ops
 /// read the disk
 const VIRTIO_BLK_T_IN: u32 = 0;
@@ -297,6 +332,12 @@ const VIRTIO_BLK_T_IN: u32 = 0;
 /// write the disk
 const VIRTIO_BLK_T_OUT: u32 = 1;
 
+/// flush the write cache of the disk
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// discard sectors of the disk
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
 impl VirtqDesc {
     const fn zero() -> Self {
         Self {
@@ -3,8 +3,20 @@
 /// qemu presents a "legacy" virtio interface.
 ///
 /// qemu ... -drive file=fs.img,if=none,format=raw,id=x0 -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+///
+/// Each `Disk` drives the disk at one mmio interface, so there may be more.
+/// The first may be the initial RAM disk instead.
+///
+/// A request that the disk does not finish within DISK_TIMEOUT timer
+/// intervals, as a lost interrupt or a bug of the emulator may cause, is
+/// retried once by notifying the disk again. If it still does not finish,
+/// the disk is reset, and the requests in flight fail with an I/O error.
+/// Once a request failed, the disk is marked failed, and the file system on
+/// it stops writing.
 use core::array::IntoIter;
+use core::cmp;
 use core::mem;
+use core::ops::Range;
 use core::ptr;
 use core::sync::atomic::{fence, Ordering};
 
@@ -12,14 +24,20 @@ use arrayvec::ArrayVec;
 
 use super::{
     MmioRegs, VirtIOFeatures, VirtIOStatus, VirtqAvail, VirtqDesc, VirtqDescFlags, VirtqUsed, NUM,
+    VIRTIO_BLK_S_OK, VIRTIO_BLK_S_PENDING, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
     VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
 };
 use crate::{
     bio::Buf,
+    blockdev::{self, BlockDevice},
     kernel::kernel_builder,
+    klog,
     lock::{Sleepablelock, SleepablelockGuard},
-    param::BSIZE,
-    riscv::{PGSHIFT, PGSIZE},
+    param::{BSIZE, DISK_TIMEOUT},
+    proc::might_sleep,
+    ramdisk,
+    riscv::{r_time, PGSHIFT, PGSIZE},
+    start::TIMER_INTERVAL,
 };
 
 // It must be page-aligned.
@@ -50,6 +68,23 @@ pub struct Disk {
 // two or more physically-contiguous pages.
 #[repr(align(4096))]
 struct DiskInfo {
+    /// Address of the mmio interface of the disk.
+    base: usize,
+
+    /// Physical memory of the RAM disk, if this is one instead.
+    ram: Option<Range<usize>>,
+
+    /// Does the disk take flushes? Otherwise, it has no write cache.
+    flush: bool,
+
+    /// Most sectors that a discard may cover, or 0 if the disk does not take
+    /// discards.
+    max_discard: u32,
+
+    /// Has a read, a write, or a flush failed? A failed discard leaves the
+    /// blocks as they were, which is harmless.
+    failed: bool,
+
     /// is a descriptor free?
     /// TODO(https://github.com/kaist-cp/rv6/issues/368): can be implemented with bitmap
     free: [bool; NUM],
@@ -63,6 +98,9 @@ struct DiskInfo {
 
     /// Disk command headers. One-for-one with descriptors, for convenience.
     ops: [VirtIOBlockOutHeader; NUM],
+
+    /// The sectors of discards, likewise.
+    discards: [VirtIOBlockDiscard; NUM],
 }
 
 /// # Safety
@@ -71,7 +109,17 @@ struct DiskInfo {
 #[derive(Copy, Clone)]
 struct InflightInfo {
     b: *mut Buf,
-    status: bool,
+
+    /// The status that the device writes, VIRTIO_BLK_S_OK (0) on success.
+    status: u8,
+
+    /// Is this a request without a `Buf`, a flush or a discard, that has not
+    /// finished?
+    pending: bool,
+
+    /// The time by which the request should finish, or 0 if none is waited
+    /// for.
+    deadline: u64,
 }
 
 /// The format of the first descriptor in a disk request. To be followed by two
@@ -100,10 +148,16 @@ impl Disk {
 impl DiskInfo {
     const fn zero() -> Self {
         Self {
+            base: 0,
+            ram: None,
+            flush: false,
+            max_discard: 0,
+            failed: false,
             free: [true; NUM],
             used_idx: 0,
             inflight: [InflightInfo::zero(); NUM],
             ops: [VirtIOBlockOutHeader::zero(); NUM],
+            discards: [VirtIOBlockDiscard::zero(); NUM],
         }
     }
 }
@@ -112,7 +166,9 @@ impl InflightInfo {
     const fn zero() -> Self {
         Self {
             b: ptr::null_mut(),
-            status: false,
+            status: 0,
+            pending: false,
+            deadline: 0,
         }
     }
 }
@@ -141,6 +197,27 @@ impl VirtIOBlockOutHeader {
     }
 }
 
+/// The sectors that a discard covers, the data of the request.
+// It needs repr(C) because it is read by device.
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtIOBlockDiscard {
+    sector: usize,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl VirtIOBlockDiscard {
+    const fn zero() -> Self {
+        Self {
+            sector: 0,
+            num_sectors: 0,
+            flags: 0,
+        }
+    }
+}
+
 /// A descriptor allocated by driver.
 #[derive(Debug)]
 struct Descriptor {
@@ -164,37 +241,140 @@ impl Drop for Descriptor {
 impl Sleepablelock<Disk> {
     /// Return a locked Buf with the `latest` contents of the indicated block.
     /// If buf.valid is true, we don't need to access Disk.
-    pub fn read(&self, dev: u32, blockno: u32) -> Buf {
+    /// On an I/O error, the data of the Buf are zeros, and it stays invalid,
+    /// so that the next read tries the disk again. The disk is failed then,
+    /// so the file system on it writes nothing that it made of the zeros.
+    /// Returns Ok(the Buf) on success, Err(()) if no buffer is free.
+    pub fn read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        let mut buf = self.get_buf(dev, blockno)?;
+        if self.fill(&mut buf).is_err() {
+            buf.deref_inner_mut().data.fill(0);
+        }
+        Ok(buf)
+    }
+
+    /// Like `read`, but fails on an I/O error, or if a fault is injected.
+    /// Returns Ok(the Buf) on success, Err(()) on failure or if no buffer is
+    /// free.
+    pub fn try_read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        #[cfg(feature = "fault-inject")]
+        if crate::fault::should_fail(crate::fault::Site::Disk) {
+            return Err(());
+        }
+        let mut buf = self.get_buf(dev, blockno)?;
+        self.fill(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn get_buf(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        might_sleep(0);
         // TODO: remove kernel_builder()
-        let mut buf = unsafe { kernel_builder().get_bcache() }
-            .get_buf(dev, blockno)
-            .lock();
+        unsafe { kernel_builder().get_bcache() }
+            .get_buf(dev, blockno)?
+            .lock()
+    }
+
+    /// Reads the block of buf from the disk, unless buf is valid.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn fill(&self, buf: &mut Buf) -> Result<(), ()> {
         if !buf.deref_inner().valid {
-            Disk::rw(&mut self.lock(), &mut buf, false);
+            let blockno = buf.blockno;
+            blockdev::account(buf.dev(), false, || {
+                Disk::rw(&mut self.lock(), buf, blockno, false)
+            })?;
             buf.deref_inner_mut().valid = true;
         }
-        buf
+        Ok(())
+    }
+
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    pub fn write(&self, b: &mut Buf) -> Result<(), ()> {
+        might_sleep(0);
+        let blockno = b.blockno;
+        blockdev::account(b.dev(), true, || {
+            Disk::rw(&mut self.lock(), b, blockno, true)
+        })
+    }
+
+    /// Waits until what was written reaches stable storage, out of the write
+    /// cache of the disk.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    pub fn flush(&self) -> Result<(), ()> {
+        might_sleep(0);
+        Disk::flush(&mut self.lock())
+    }
+
+    /// Has a request to the disk failed?
+    pub fn has_failed(&self) -> bool {
+        self.lock().info.failed
+    }
+
+    /// Tells the disk that the n blocks from blockno are no longer used, so
+    /// that it may free their storage. They may read as anything afterwards.
+    pub fn discard(&self, blockno: u32, n: u32) {
+        might_sleep(0);
+        // A discard that fails leaves the blocks as they were, which is one
+        // of the things they may read as.
+        let _ = Disk::discard(&mut self.lock(), blockno, n);
     }
 
-    pub fn write(&self, b: &mut Buf) {
-        Disk::rw(&mut self.lock(), b, true)
+    /// Reads or writes b from or to block blockno of the disk, which need not
+    /// be the block of b, for a device made of disks.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    pub fn rw_at(&self, b: &mut Buf, blockno: u32, write: bool) -> Result<(), ()> {
+        might_sleep(0);
+        Disk::rw(&mut self.lock(), b, blockno, write)
+    }
+}
+
+impl BlockDevice for Sleepablelock<Disk> {
+    fn read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        Sleepablelock::<Disk>::read(self, dev, blockno)
+    }
+
+    fn try_read(&self, dev: u32, blockno: u32) -> Result<Buf, ()> {
+        Sleepablelock::<Disk>::try_read(self, dev, blockno)
+    }
+
+    fn write(&self, b: &mut Buf) -> Result<(), ()> {
+        Sleepablelock::<Disk>::write(self, b)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        Sleepablelock::<Disk>::flush(self)
+    }
+
+    fn discard(&self, blockno: u32, n: u32) {
+        Sleepablelock::<Disk>::discard(self, blockno, n)
     }
 }
 
 impl Disk {
-    pub fn init(&self) {
+    /// Is a disk attached at the mmio interface at `base`, one of platform()?
+    pub fn is_attached(base: usize) -> bool {
+        MmioRegs::is_virtio_disk(base)
+    }
+
+    /// Makes this the RAM disk in the physical memory `ram`.
+    pub fn init_ram(&mut self, ram: Range<usize>) {
+        self.info.ram = Some(ram);
+    }
+
+    /// Initializes the disk at the mmio interface at `base`, one of platform().
+    pub fn init(&mut self, base: usize) {
         let mut status: VirtIOStatus = VirtIOStatus::empty();
 
         // MMIO registers are located below KERNBASE, while kernel text and data
         // are located above KERNBASE, so we can safely read/write MMIO registers.
-        MmioRegs::check_virtio_disk();
+        assert!(MmioRegs::is_virtio_disk(base), "could not find virtio disk");
+        self.info.base = base;
         status.insert(VirtIOStatus::ACKNOWLEDGE);
-        MmioRegs::set_status(&status);
+        MmioRegs::set_status(base, &status);
         status.insert(VirtIOStatus::DRIVER);
-        MmioRegs::set_status(&status);
+        MmioRegs::set_status(base, &status);
 
         // Negotiate features
-        let features = MmioRegs::get_features()
+        let features = MmioRegs::get_features(base)
             - (VirtIOFeatures::BLK_F_RO
                 | VirtIOFeatures::BLK_F_SCSI
                 | VirtIOFeatures::BLK_F_CONFIG_WCE
@@ -203,38 +383,55 @@ impl Disk {
                 | VirtIOFeatures::RING_F_EVENT_IDX
                 | VirtIOFeatures::RING_F_INDIRECT_DESC);
 
-        MmioRegs::set_features(&features);
+        MmioRegs::set_features(base, &features);
+        self.info.flush = features.contains(VirtIOFeatures::BLK_F_FLUSH);
+        if features.contains(VirtIOFeatures::BLK_F_DISCARD) {
+            // SAFETY: the kernel maps every virtio mmio interface of platform().
+            self.info.max_discard = unsafe { MmioRegs::BlkMaxDiscardSectors.read_at(base) };
+        }
 
         // Tell device that feature negotiation is complete.
         status.insert(VirtIOStatus::FEATURES_OK);
-        MmioRegs::set_status(&status);
+        MmioRegs::set_status(base, &status);
 
         // Tell device we're completely ready.
         status.insert(VirtIOStatus::DRIVER_OK);
-        MmioRegs::set_status(&status);
+        MmioRegs::set_status(base, &status);
         // SAFETY: page size is `PGSIZE`.
         unsafe {
-            MmioRegs::set_pg_size(PGSIZE as _);
+            MmioRegs::set_pg_size(base, PGSIZE as _);
         }
 
         // Initialize queue 0.
         unsafe {
             MmioRegs::select_and_init_queue(
+                base,
                 0,
                 NUM as _,
                 (self.desc.as_ptr() as usize >> PGSHIFT) as _,
             );
         }
 
-        // plic.rs and trap.rs arrange for interrupts from VIRTIO0_IRQ.
+        // kernel_main() registers the handler of interrupts from the interface.
     }
 
     // This method reads and writes disk by reading and writing MMIO registers.
     // By the construction of the kernel page table in KernelMemory::new, the
     // virtual addresses of the MMIO registers are mapped to the proper physical
     // addresses. Therefore, this method is safe.
-    fn rw(this: &mut SleepablelockGuard<'_, Self>, b: &mut Buf, write: bool) {
-        let sector: usize = (*b).blockno as usize * (BSIZE / 512);
+    // Returns Ok(()) on success, Err(()) on an I/O error.
+    fn rw(
+        this: &mut SleepablelockGuard<'_, Self>,
+        b: &mut Buf,
+        blockno: u32,
+        write: bool,
+    ) -> Result<(), ()> {
+        if let Some(ram) = &this.info.ram {
+            ramdisk::rw(ram, b, blockno, write);
+            return Ok(());
+        }
+
+        let sector: usize = blockno as usize * (BSIZE / 512);
 
         // The spec's Section 5.2 says that legacy block operations use
         // three descriptors: one for type/reserved/sector, one for the
@@ -286,7 +483,7 @@ impl Disk {
 
         // 3. Set the third descriptor.
         // device writes 0 on success
-        this.info.inflight[desc[0].idx].status = true;
+        this.info.inflight[desc[0].idx].status = VIRTIO_BLK_S_PENDING;
 
         // Device writes the status
         this.desc[desc[2].idx] = VirtqDesc {
@@ -302,67 +499,307 @@ impl Disk {
         // to a valid Buf.
         this.info.inflight[desc[0].idx].b = b;
 
+        // SAFETY: the all three descriptors' fields are well set.
+        unsafe { this.submit(desc[0].idx) };
+
+        // Wait for virtio_disk_intr() to say request has finished.
+        let result = Disk::wait(
+            this,
+            desc[0].idx,
+            |_| !b.deref_inner().disk,
+            |this| {
+                b.vdisk_request_waitchannel.sleep(
+                    this,
+                    // TODO: remove kernel_builder()
+                    &kernel_builder().current_proc().expect("No current proc"),
+                )
+            },
+        );
+        // As it assigns null, the invariant of inflight is maintained even if
+        // b: &mut Buf becomes invalid after this method returns.
+        this.info.inflight[desc[0].idx].b = ptr::null_mut();
+        IntoIter::new(desc).for_each(|desc| this.free(desc));
+        this.wakeup_all();
+        if result.is_err() {
+            this.info.failed = true;
+        }
+        result
+    }
+
+    /// Waits until the writes that the disk has finished reach stable
+    /// storage. It does nothing if the disk has no write cache.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn flush(this: &mut SleepablelockGuard<'_, Self>) -> Result<(), ()> {
+        if this.info.ram.is_none() && this.info.flush {
+            let result = Disk::request(this, VIRTIO_BLK_T_FLUSH, None);
+            if result.is_err() {
+                this.info.failed = true;
+            }
+            return result;
+        }
+        Ok(())
+    }
+
+    /// Discards the n blocks from blockno. It does nothing if the disk does
+    /// not take discards.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn discard(this: &mut SleepablelockGuard<'_, Self>, blockno: u32, n: u32) -> Result<(), ()> {
+        if this.info.ram.is_some() || this.info.max_discard == 0 {
+            return Ok(());
+        }
+        let per_block = (BSIZE / 512) as u32;
+        let mut sector = blockno * per_block;
+        let end = (blockno + n) * per_block;
+        while sector < end {
+            let num_sectors = cmp::min(end - sector, this.info.max_discard);
+            Disk::request(
+                this,
+                VIRTIO_BLK_T_DISCARD,
+                Some(VirtIOBlockDiscard {
+                    sector: sector as usize,
+                    num_sectors,
+                    flags: 0,
+                }),
+            )?;
+            sector += num_sectors;
+        }
+        Ok(())
+    }
+
+    /// Sends a request of type typ without a `Buf`, with the sectors to
+    /// discard if any, and waits until it finishes.
+    /// Returns Ok(()) on success, Err(()) on an I/O error.
+    fn request(
+        this: &mut SleepablelockGuard<'_, Self>,
+        typ: u32,
+        discard: Option<VirtIOBlockDiscard>,
+    ) -> Result<(), ()> {
+        let desc = loop {
+            match this.alloc_three_descriptors() {
+                Some(idx) => break idx,
+                // See rw().
+                None => this.sleep(),
+            }
+        };
+
+        let buf0 = &mut this.info.ops[desc[0].idx];
+        *buf0 = VirtIOBlockOutHeader {
+            typ,
+            reserved: 0,
+            sector: 0,
+        };
+        this.desc[desc[0].idx] = VirtqDesc {
+            addr: buf0 as *const _ as _,
+            len: mem::size_of::<VirtIOBlockOutHeader>() as _,
+            flags: VirtqDescFlags::NEXT,
+            next: desc[2].idx as _,
+        };
+
+        // A discard has the sectors as data. A flush has none, so the header
+        // is followed by the status right away, and the second descriptor is
+        // not used.
+        if let Some(discard) = discard {
+            let data = &mut this.info.discards[desc[0].idx];
+            *data = discard;
+            this.desc[desc[1].idx] = VirtqDesc {
+                addr: data as *const _ as _,
+                len: mem::size_of::<VirtIOBlockDiscard>() as _,
+                flags: VirtqDescFlags::NEXT,
+                next: desc[2].idx as _,
+            };
+            this.desc[desc[0].idx].next = desc[1].idx as _;
+        }
+
+        this.info.inflight[desc[0].idx].status = VIRTIO_BLK_S_PENDING;
+        this.desc[desc[2].idx] = VirtqDesc {
+            addr: &this.info.inflight[desc[0].idx].status as *const _ as _,
+            len: 1,
+            flags: VirtqDescFlags::WRITE,
+            next: 0,
+        };
+        this.info.inflight[desc[0].idx].pending = true;
+
+        // SAFETY: the descriptors of the chain are well set.
+        unsafe { this.submit(desc[0].idx) };
+
+        // Wait for complete() to say the request has finished.
+        let head = desc[0].idx;
+        let result = Disk::wait(
+            this,
+            head,
+            |this| !this.info.inflight[head].pending,
+            |this| this.sleep(),
+        );
+        IntoIter::new(desc).for_each(|desc| this.free(desc));
+        this.wakeup_all();
+        result
+    }
+
+    /// Waits with sleep until done says that the request whose chain begins
+    /// at head has finished. If it has not by its deadline, it is retried
+    /// once, and then the disk is reset.
+    /// Returns Ok(()) if the disk did the request, Err(()) on an I/O error.
+    fn wait<D, S>(
+        this: &mut SleepablelockGuard<'_, Self>,
+        head: usize,
+        done: D,
+        mut sleep: S,
+    ) -> Result<(), ()>
+    where
+        D: Fn(&Self) -> bool,
+        S: FnMut(&mut SleepablelockGuard<'_, Self>),
+    {
+        let timeout = DISK_TIMEOUT * TIMER_INTERVAL as u64;
+        this.info.inflight[head].deadline = r_time() + timeout;
+        let mut retried = false;
+        while !done(this) {
+            if r_time() < this.info.inflight[head].deadline {
+                sleep(this);
+                continue;
+            }
+
+            // The interrupt may have been lost.
+            Disk::complete(this);
+            if done(this) {
+                break;
+            }
+
+            let base = this.info.base;
+            if retried {
+                klog!(Error, "disk {:#x}: request timed out, resetting", base);
+                Disk::reset(this);
+            } else {
+                klog!(Warn, "disk {:#x}: request timed out, retrying", base);
+                retried = true;
+                this.info.inflight[head].deadline = r_time() + timeout;
+                // SAFETY: the descriptors of the chain are still well set.
+                unsafe { MmioRegs::notify_queue(base, 0) };
+            }
+        }
+        this.info.inflight[head].deadline = 0;
+
+        // reset() leaves the status as it was.
+        if this.info.inflight[head].status == VIRTIO_BLK_S_OK {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Resets the disk, failing every request in flight, as the disk may
+    /// have lost any of them. The waiters free their descriptors.
+    fn reset(this: &mut SleepablelockGuard<'_, Self>) {
+        let base = this.info.base;
+        // Writing 0 to the status stops the device.
+        MmioRegs::set_status(base, &VirtIOStatus::empty());
+
+        for info in this.info.inflight.iter_mut() {
+            if info.pending {
+                info.pending = false;
+            } else {
+                // SAFETY: from the invariant, b refers to a valid buffer
+                // unless it is null.
+                if let Some(buf) = unsafe { info.b.as_mut() } {
+                    if buf.deref_inner().disk {
+                        buf.deref_inner_mut().disk = false;
+                        buf.vdisk_request_waitchannel.wakeup_all();
+                    }
+                }
+            }
+        }
+        this.wakeup_all();
+
+        this.avail.idx = 0;
+        this.used.id = 0;
+        this.info.used_idx = 0;
+        this.init(base);
+    }
+
+    /// Wakes the waiters of the requests whose deadline has passed, so that
+    /// they retry them. The timer interrupt queues it.
+    pub fn expire(this: &mut SleepablelockGuard<'_, Self>) {
+        let now = r_time();
+        for info in this.info.inflight.iter() {
+            if info.deadline == 0 || now < info.deadline {
+                continue;
+            }
+            if info.pending {
+                this.wakeup_all();
+            } else {
+                // SAFETY: from the invariant, b refers to a valid buffer
+                // unless it is null.
+                if let Some(buf) = unsafe { info.b.as_ref() } {
+                    buf.vdisk_request_waitchannel.wakeup_all();
+                }
+            }
+        }
+    }
+
+    /// Tells the device about the chain of descriptors whose first one is
+    /// `head`.
+    ///
+    /// # Safety
+    ///
+    /// The descriptors of the chain must be well set.
+    unsafe fn submit(&mut self, head: usize) {
         // Tell the device the first index in our chain of descriptors.
-        let ring_idx = this.avail.idx as usize % NUM;
-        this.avail.ring[ring_idx] = desc[0].idx as _;
+        let ring_idx = self.avail.idx as usize % NUM;
+        self.avail.ring[ring_idx] = head as _;
 
         fence(Ordering::SeqCst);
 
         // Tell the device another avail ring entry is available.
-        this.avail.idx += 1;
+        self.avail.idx += 1;
 
         fence(Ordering::SeqCst);
 
-        // SAFETY: the all three descriptors' fields are well set.
         // Value is queue number.
         unsafe {
-            MmioRegs::notify_queue(0);
-        }
-
-        // Wait for virtio_disk_intr() to say request has finished.
-        while b.deref_inner().disk {
-            (*b).vdisk_request_waitchannel.sleep(
-                this,
-                // TODO: remove kernel_builder()
-                &kernel_builder().current_proc().expect("No current proc"),
-            );
+            MmioRegs::notify_queue(self.info.base, 0);
         }
-        // As it assigns null, the invariant of inflight is maintained even if
-        // b: &mut Buf becomes invalid after this method returns.
-        this.info.inflight[desc[0].idx].b = ptr::null_mut();
-        IntoIter::new(desc).for_each(|desc| this.free(desc));
-        this.wakeup();
     }
 
-    pub fn intr(&mut self) {
+    /// Acknowledges an interrupt from the disk at the mmio interface at
+    /// `base`. The top half of one.
+    pub fn ack(base: usize) {
         // The device won't raise another interrupt until we tell it
         // we've seen this interrupt, which the following line does.
         // This may race with the device writing new entries to
         // the "used" ring, in which case we may process the new
         // completion entries in this interrupt, and have nothing to do
         // in the next interrupt, which is harmless.
-        MmioRegs::intr_ack_all();
+        MmioRegs::intr_ack_all(base);
+    }
 
+    /// Hands finished requests back to their processes. The bottom half of
+    /// a disk interrupt, after `Disk::ack`.
+    pub fn complete(this: &mut SleepablelockGuard<'_, Self>) {
         fence(Ordering::SeqCst);
 
         // The device increments disk.used->idx when it
         // adds an entry to the used ring.
 
-        while self.info.used_idx != self.used.id {
+        while this.info.used_idx != this.used.id {
             fence(Ordering::SeqCst);
-            let id = self.used.ring[(self.info.used_idx as usize) % NUM].id as usize;
-
-            assert!(!self.info.inflight[id].status, "Disk::intr status");
-
-            // SAFETY: from the invariant, b refers to a valid
-            // buffer unless it is null.
-            let buf = unsafe { self.info.inflight[id].b.as_mut() }.expect("Disk::intr");
+            let id = this.used.ring[(this.info.used_idx as usize) % NUM].id as usize;
+
+            // The waiter reads the status, which is not VIRTIO_BLK_S_OK on
+            // an error.
+            if this.info.inflight[id].pending {
+                // request() waits on the disk.
+                this.info.inflight[id].pending = false;
+                this.wakeup_all();
+            } else {
+                // SAFETY: from the invariant, b refers to a valid
+                // buffer unless it is null.
+                let buf = unsafe { this.info.inflight[id].b.as_mut() }.expect("Disk::complete");
 
-            // disk is done with buf
-            buf.deref_inner_mut().disk = false;
-            buf.vdisk_request_waitchannel.wakeup();
+                // disk is done with buf
+                buf.deref_inner_mut().disk = false;
+                buf.vdisk_request_waitchannel.wakeup_all();
+            }
 
-            self.info.used_idx += 1;
+            this.info.used_idx += 1;
         }
     }
 
@@ -0,0 +1,209 @@
+/// Driver for qemu's virtio keyboard device.
+/// Uses qemu's mmio interface to virtio.
+/// qemu presents a "legacy" virtio interface.
+///
+/// qemu ... -device virtio-keyboard-device,bus=virtio-mmio-bus.1
+///
+/// The device is optional: without it, the console only takes input from the uart.
+use core::mem;
+use core::sync::atomic::{fence, Ordering};
+
+use super::{MmioRegs, VirtIOStatus, VirtqAvail, VirtqDesc, VirtqDescFlags, VirtqUsed, NUM};
+use crate::{
+    console::{consoleintr, consoleintr_end},
+    kernel::kernel_builder,
+    keymap::{Key, Keymap},
+    platform::platform,
+    riscv::{PGSHIFT, PGSIZE},
+};
+
+/// virtio device type of input devices.
+const VIRTIO_ID_INPUT: u32 = 18;
+
+/// Event type of key presses and releases.
+const EV_KEY: u16 = 1;
+
+// It must be page-aligned.
+// It needs repr(C) because it is read by device.
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C, align(4096))]
+pub struct Keyboard {
+    /// Descriptors of the event buffers, which the device fills in.
+    desc: [VirtqDesc; NUM],
+
+    /// Ring in which the driver gives event buffers to the device.
+    avail: VirtqAvail,
+
+    /// Ring in which the device returns filled event buffers.
+    used: VirtqUsed,
+
+    info: KeyboardInfo,
+}
+
+// It must be page-aligned because a virtqueue (desc + avail + used) occupies
+// two or more physically-contiguous pages.
+#[repr(align(4096))]
+struct KeyboardInfo {
+    /// Is the device attached?
+    present: bool,
+
+    /// we've looked this far in used.
+    used_idx: u16,
+
+    /// Event buffers. One-for-one with descriptors.
+    events: [VirtIOInputEvent; NUM],
+
+    keymap: Keymap,
+}
+
+/// An input event, from the spec.
+// It needs repr(C) because it is written by device.
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtIOInputEvent {
+    typ: u16,
+    code: u16,
+    value: u32,
+}
+
+impl Keyboard {
+    pub const fn zero() -> Self {
+        Self {
+            desc: [VirtqDesc::zero(); NUM],
+            avail: VirtqAvail::zero(),
+            used: VirtqUsed::zero(),
+            info: KeyboardInfo {
+                present: false,
+                used_idx: 0,
+                events: [VirtIOInputEvent {
+                    typ: 0,
+                    code: 0,
+                    value: 0,
+                }; NUM],
+                keymap: Keymap::new(),
+            },
+        }
+    }
+
+    pub fn init(&mut self) {
+        let base = platform().virtio[1].base;
+        // SAFETY: the second virtio mmio interface is mapped by KernelMemory::new.
+        let found = unsafe {
+            MmioRegs::MagicValue.read_at(base) == 0x74726976
+                && MmioRegs::Version.read_at(base) == 1
+                && MmioRegs::DeviceId.read_at(base) == VIRTIO_ID_INPUT
+        };
+        if !found {
+            return;
+        }
+
+        let mut status = VirtIOStatus::ACKNOWLEDGE | VirtIOStatus::DRIVER;
+        // SAFETY: setting status and features bits does not cause side effects.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            // No features are needed.
+            MmioRegs::DriverFeatures.write_at(base, 0);
+        }
+        status.insert(VirtIOStatus::FEATURES_OK);
+        // SAFETY: page size is `PGSIZE`.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            MmioRegs::GuestPageSize.write_at(base, PGSIZE as _);
+        }
+
+        // Initialize the event queue, queue 0.
+        // SAFETY: simply selecting the queue does not cause side effects.
+        unsafe { MmioRegs::QueueSel.write_at(base, 0) };
+        // SAFETY: the second virtio mmio interface is mapped by KernelMemory::new.
+        let max = unsafe { MmioRegs::QueueNumMax.read_at(base) };
+        if max < NUM as u32 {
+            // Reset the device, to leave it as it was found.
+            // SAFETY: the queue is not set yet.
+            unsafe { MmioRegs::Status.write_at(base, 0) };
+            return;
+        }
+
+        // Give every event buffer to the device.
+        for i in 0..NUM {
+            self.desc[i] = VirtqDesc {
+                addr: &self.info.events[i] as *const _ as _,
+                len: mem::size_of::<VirtIOInputEvent>() as _,
+                flags: VirtqDescFlags::WRITE,
+                next: 0,
+            };
+            self.avail.ring[i] = i as _;
+        }
+        fence(Ordering::SeqCst);
+        self.avail.idx = NUM as _;
+
+        // SAFETY: the queue and its descriptors are well set.
+        unsafe {
+            MmioRegs::QueueNum.write_at(base, NUM as _);
+            MmioRegs::QueuePfn.write_at(base, (self.desc.as_ptr() as usize >> PGSHIFT) as _);
+        }
+
+        // Tell device we're completely ready.
+        status.insert(VirtIOStatus::DRIVER_OK);
+        // SAFETY: the queue is well set.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            MmioRegs::QueueNotify.write_at(base, 0);
+        }
+        self.info.present = true;
+
+        // kernel_main() registers the handler of interrupts from the interface.
+    }
+
+    pub fn intr(&mut self) {
+        if !self.info.present {
+            return;
+        }
+
+        let base = platform().virtio[1].base;
+        // SAFETY: simply acknowledging interrupts does not cause undefined behavior.
+        unsafe {
+            let intr_status = MmioRegs::InterruptStatus.read_at(base) & 0x3;
+            MmioRegs::InterruptAck.write_at(base, intr_status);
+        }
+
+        fence(Ordering::SeqCst);
+
+        while self.info.used_idx != self.used.id {
+            fence(Ordering::SeqCst);
+            let id = self.used.ring[self.info.used_idx as usize % NUM].id as usize;
+            let event = self.info.events[id];
+            self.info.used_idx = self.info.used_idx.wrapping_add(1);
+
+            // Give the buffer back to the device.
+            let ring_idx = self.avail.idx as usize % NUM;
+            self.avail.ring[ring_idx] = id as _;
+            fence(Ordering::SeqCst);
+            self.avail.idx = self.avail.idx.wrapping_add(1);
+
+            if event.typ == EV_KEY {
+                self.deliver(event.code, event.value);
+            }
+        }
+
+        fence(Ordering::SeqCst);
+        // SAFETY: the descriptors of the returned buffers are unchanged.
+        unsafe { MmioRegs::QueueNotify.write_at(base, 0) };
+        unsafe { consoleintr_end() };
+    }
+
+    /// Hand a key event to the console.
+    fn deliver(&mut self, code: u16, value: u32) {
+        match self.info.keymap.key(code, value) {
+            Key::None => (),
+            Key::Char(c) => unsafe { consoleintr(c as i32) },
+            Key::Seq(seq) => {
+                for &c in seq {
+                    unsafe { consoleintr(c as i32) };
+                }
+            }
+            // TODO: remove kernel_builder()
+            Key::Switch(n) => kernel_builder().console.switch(n),
+        }
+    }
+}
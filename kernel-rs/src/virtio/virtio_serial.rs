@@ -0,0 +1,259 @@
+/// Driver for qemu's virtio serial device, with the `gdbstub` feature.
+/// Uses qemu's mmio interface to virtio.
+/// qemu presents a "legacy" virtio interface.
+///
+/// qemu ... -device virtio-serial-device,bus=virtio-mmio-bus.5
+///          -device virtconsole,chardev=...
+///
+/// Only the first port, the console one, is used, as the driver does not
+/// take VIRTIO_CONSOLE_F_MULTIPORT. gdb talks to the gdbstub over it, and the
+/// stub polls it, as it runs with interrupts off.
+use core::array::IntoIter;
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, Ordering};
+
+use super::{MmioRegs, VirtIOStatus, VirtqAvail, VirtqDesc, VirtqDescFlags, VirtqUsed, NUM};
+use crate::riscv::{PGSHIFT, PGSIZE};
+
+/// virtio device type of console devices.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// The queues of the first port.
+const RECEIVEQ: u32 = 0;
+const TRANSMITQ: u32 = 1;
+
+/// Size of a receive buffer, and of the transmit buffer.
+const BUFSIZE: usize = 64;
+
+// It must be page-aligned.
+// It needs repr(C) because it is read by device.
+// https://github.com/kaist-cp/rv6/issues/52
+#[repr(C, align(4096))]
+pub struct Serial {
+    receiveq: Virtq,
+    transmitq: Virtq,
+    info: SerialInfo,
+}
+
+/// A virtqueue, laid out as the legacy interface expects.
+// It must be page-aligned.
+// It needs repr(C) because it is read by device.
+#[repr(C, align(4096))]
+struct Virtq {
+    desc: [VirtqDesc; NUM],
+    avail: VirtqAvail,
+    used: VirtqUsed,
+
+    /// we've looked this far in used.
+    used_idx: u16,
+}
+
+struct SerialInfo {
+    /// The mmio interface of the device, or 0 if it is not attached.
+    base: usize,
+
+    /// Receive buffers. One-for-one with the descriptors of the receiveq.
+    rx: [[u8; BUFSIZE]; NUM],
+
+    /// The receive buffer being read, if any: its descriptor, its length,
+    /// and how far it has been read.
+    reading: Option<(usize, usize, usize)>,
+
+    /// Bytes to transmit.
+    tx: [u8; BUFSIZE],
+    tx_len: usize,
+}
+
+impl Virtq {
+    const fn zero() -> Self {
+        Self {
+            desc: [VirtqDesc::zero(); NUM],
+            avail: VirtqAvail::zero(),
+            used: VirtqUsed::zero(),
+            used_idx: 0,
+        }
+    }
+
+    /// Gives the chain at descriptor `id` to the device.
+    fn push(&mut self, id: usize) {
+        let ring_idx = self.avail.idx as usize % NUM;
+        self.avail.ring[ring_idx] = id as _;
+        fence(Ordering::SeqCst);
+        self.avail.idx = self.avail.idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Takes the next chain that the device returned, if any.
+    /// Returns Some((its descriptor, the length the device wrote)).
+    fn pop(&mut self) -> Option<(usize, usize)> {
+        fence(Ordering::SeqCst);
+        if self.used_idx == self.used.id {
+            return None;
+        }
+        let elem = self.used.ring[self.used_idx as usize % NUM];
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some((elem.id as usize, elem.len as usize))
+    }
+}
+
+impl Serial {
+    pub const fn zero() -> Self {
+        Self {
+            receiveq: Virtq::zero(),
+            transmitq: Virtq::zero(),
+            info: SerialInfo {
+                base: 0,
+                rx: [[0; BUFSIZE]; NUM],
+                reading: None,
+                tx: [0; BUFSIZE],
+                tx_len: 0,
+            },
+        }
+    }
+
+    /// Attaches the device at the mmio interface at `base`, one of platform().
+    /// Returns whether a serial device is there.
+    pub fn init(&mut self, base: usize) -> bool {
+        // SAFETY: the kernel maps every virtio mmio interface of platform().
+        let found = unsafe {
+            MmioRegs::MagicValue.read_at(base) == 0x74726976
+                && MmioRegs::Version.read_at(base) == 1
+                && MmioRegs::DeviceId.read_at(base) == VIRTIO_ID_CONSOLE
+        };
+        if !found {
+            return false;
+        }
+
+        let mut status = VirtIOStatus::ACKNOWLEDGE | VirtIOStatus::DRIVER;
+        // SAFETY: setting status and features bits does not cause side effects.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            // No features are needed, not even a second port.
+            MmioRegs::DriverFeatures.write_at(base, 0);
+        }
+        status.insert(VirtIOStatus::FEATURES_OK);
+        // SAFETY: page size is `PGSIZE`.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            MmioRegs::GuestPageSize.write_at(base, PGSIZE as _);
+        }
+
+        for (num, queue) in
+            IntoIter::new([(RECEIVEQ, &self.receiveq), (TRANSMITQ, &self.transmitq)])
+        {
+            // SAFETY: simply selecting the queue does not cause side effects.
+            unsafe { MmioRegs::QueueSel.write_at(base, num) };
+            // SAFETY: the kernel maps every virtio mmio interface of platform().
+            let max = unsafe { MmioRegs::QueueNumMax.read_at(base) };
+            if max < NUM as u32 {
+                // Reset the device, which forgets the queues set so far.
+                // SAFETY: the device has not used them, without DRIVER_OK.
+                unsafe { MmioRegs::Status.write_at(base, 0) };
+                return false;
+            }
+            // SAFETY: the device uses the queue only after DRIVER_OK.
+            unsafe {
+                MmioRegs::QueueNum.write_at(base, NUM as _);
+                MmioRegs::QueuePfn.write_at(base, (queue as *const _ as usize >> PGSHIFT) as _);
+            }
+        }
+
+        // Give every receive buffer to the device.
+        for i in 0..NUM {
+            self.receiveq.desc[i] = VirtqDesc {
+                addr: self.info.rx[i].as_ptr() as _,
+                len: BUFSIZE as _,
+                flags: VirtqDescFlags::WRITE,
+                next: 0,
+            };
+            self.receiveq.push(i);
+        }
+        self.transmitq.desc[0] = VirtqDesc {
+            addr: self.info.tx.as_ptr() as _,
+            len: 0,
+            flags: VirtqDescFlags::FREED,
+            next: 0,
+        };
+
+        // Tell device we're completely ready.
+        status.insert(VirtIOStatus::DRIVER_OK);
+        // SAFETY: the queues are well set.
+        unsafe {
+            MmioRegs::Status.write_at(base, status.bits());
+            MmioRegs::QueueNotify.write_at(base, RECEIVEQ);
+        }
+        self.info.base = base;
+        true
+    }
+
+    /// Is the device attached?
+    pub fn is_attached(&self) -> bool {
+        self.info.base != 0
+    }
+
+    /// Acknowledges the interrupts of the device. What it received is left
+    /// for `poll`.
+    pub fn ack(&self) {
+        // SAFETY: simply acknowledging interrupts does not cause undefined behavior.
+        unsafe {
+            let intr_status = MmioRegs::InterruptStatus.read_at(self.info.base) & 0x3;
+            MmioRegs::InterruptAck.write_at(self.info.base, intr_status);
+        }
+    }
+
+    /// Returns the next received byte, if any.
+    pub fn poll(&mut self) -> Option<u8> {
+        loop {
+            if let Some((id, len, pos)) = self.info.reading {
+                if pos < len {
+                    self.info.reading = Some((id, len, pos + 1));
+                    return Some(self.info.rx[id][pos]);
+                }
+
+                // Give the buffer back to the device.
+                self.info.reading = None;
+                self.receiveq.push(id);
+                // SAFETY: the descriptor of the buffer is unchanged.
+                unsafe { MmioRegs::QueueNotify.write_at(self.info.base, RECEIVEQ) };
+            }
+            let (id, len) = self.receiveq.pop()?;
+            self.info.reading = Some((id, len, 0));
+        }
+    }
+
+    /// Waits for the next received byte.
+    pub fn getc(&mut self) -> u8 {
+        self.flush();
+        loop {
+            if let Some(c) = self.poll() {
+                return c;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Transmits c, once the transmit buffer fills up or is flushed.
+    pub fn putc(&mut self, c: u8) {
+        if self.info.tx_len == BUFSIZE {
+            self.flush();
+        }
+        self.info.tx[self.info.tx_len] = c;
+        self.info.tx_len += 1;
+    }
+
+    /// Transmits what is in the transmit buffer, and waits until the device
+    /// takes it.
+    pub fn flush(&mut self) {
+        if self.info.tx_len == 0 {
+            return;
+        }
+        self.transmitq.desc[0].len = self.info.tx_len as _;
+        self.transmitq.push(0);
+        // SAFETY: the descriptor points to the transmit buffer.
+        unsafe { MmioRegs::QueueNotify.write_at(self.info.base, TRANSMITQ) };
+        while self.transmitq.pop().is_none() {
+            spin_loop();
+        }
+        self.info.tx_len = 0;
+    }
+}
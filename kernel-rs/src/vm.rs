@@ -2,15 +2,19 @@ use core::{cmp, marker::PhantomData, mem, ops::Add, slice};
 
 use crate::{
     fs::InodeGuard,
-    kalloc::Kmem,
+    kalloc::{self, Kmem},
+    kernel::kernel_builder,
     lock::Spinlock,
-    memlayout::{kstack, FINISHER, KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, TRAPFRAME, UART0, VIRTIO0},
+    memlayout::{kstack, phystop, KERNBASE, TRAMPOLINE, TRAPFRAME, VDSO_PROC, VDSO_TIME},
     page::Page,
     param::NPROC,
+    platform::platform,
     riscv::{
         make_satp, pa2pte, pgrounddown, pgroundup, pte2pa, pxshift, sfence_vma, w_satp, PteFlags,
         MAXVA, PGSIZE, PXMASK,
     },
+    text::RcText,
+    vdso::VdsoProc,
 };
 
 extern "C" {
@@ -105,7 +109,7 @@ impl PageTableEntry {
     }
 
     fn is_user(&self) -> bool {
-        self.flag_intersects(PteFlags::V | PteFlags::U)
+        self.get_flags().contains(PteFlags::V | PteFlags::U)
     }
 
     fn is_table(&self) -> bool {
@@ -116,6 +120,14 @@ impl PageTableEntry {
         self.is_valid() && self.flag_intersects(PteFlags::R | PteFlags::W | PteFlags::X)
     }
 
+    fn is_zero_page(&self) -> bool {
+        self.is_data() && self.get_pa().into_usize() == zero_page()
+    }
+
+    fn is_shared(&self) -> bool {
+        self.is_data() && self.flag_intersects(PteFlags::SHARED)
+    }
+
     /// Make the entry refer to a given page-table page.
     fn set_table(&mut self, page: *mut RawPageTable) {
         self.inner = pa2pte((page as usize).into()) | PteFlags::V.bits();
@@ -129,9 +141,15 @@ impl PageTableEntry {
         self.inner = pa2pte(pa) | (perm | PteFlags::V).bits();
     }
 
-    /// Make the entry inaccessible by user processes by clearing PteFlags::U.
+    /// Keep the address of the entry, but change its permission.
+    fn set_perm(&mut self, perm: PteFlags) {
+        self.set_entry(self.get_pa(), perm);
+    }
+
+    /// Make the entry inaccessible by user processes for good by clearing
+    /// PteFlags::U and PteFlags::COW.
     fn clear_user(&mut self) {
-        self.inner &= !(PteFlags::U.bits());
+        self.inner &= !((PteFlags::U | PteFlags::COW).bits());
     }
 
     /// Invalidate the entry by making every bit 0.
@@ -154,6 +172,9 @@ impl PageTableEntry {
 
 const PTE_PER_PT: usize = PGSIZE / mem::size_of::<PageTableEntry>();
 
+/// Size of a megapage, which a PTE in a level-1 page-table page maps.
+const MEGAPAGE: usize = PGSIZE * PTE_PER_PT;
+
 /// # Safety
 ///
 /// It should be converted to a Page by Page::from_usize(self.inner.as_ptr() as _)
@@ -167,8 +188,7 @@ impl RawPageTable {
     /// Return `Ok(..)` if the allocation has succeeded.
     /// Return `None` if the allocation has failed.
     fn new(allocator: &Spinlock<Kmem>) -> Option<*mut RawPageTable> {
-        let mut page = allocator.alloc()?;
-        page.write_bytes(0);
+        let page = allocator.alloc_zeroed()?;
         // This line guarantees the invariant.
         Some(page.into_usize() as *mut RawPageTable)
     }
@@ -201,6 +221,28 @@ impl RawPageTable {
         pte
     }
 
+    /// Call `f` with the virtual address, the level, and the PTE of every
+    /// valid leaf under this page-table page, which is at `level` and maps
+    /// virtual addresses from `base`.
+    fn for_each_leaf<F: FnMut(usize, usize, &PageTableEntry)>(
+        &self,
+        level: usize,
+        base: usize,
+        f: &mut F,
+    ) {
+        for (i, pte) in self.inner.iter().enumerate() {
+            let va = base + (i << pxshift(level));
+            if pte.is_table() {
+                // SAFETY: pte refers to a valid page-table page by the invariant
+                // of PageTableEntry.
+                let table = unsafe { &*(pte.get_pa().into_usize() as *const RawPageTable) };
+                table.for_each_leaf(level - 1, va, f);
+            } else if pte.is_data() {
+                f(va, level, pte);
+            }
+        }
+    }
+
     /// Recursively free page-table pages.
     /// All leaf mappings must already have been removed.
     ///
@@ -258,19 +300,34 @@ impl<A: VAddr> PageTable<A> {
     ///   21..29 -- 9 bits of level-1 index.
     ///   12..20 -- 9 bits of level-0 index.
     ///    0..11 -- 12 bits of byte offset within the page.
+    /// The Sv48 scheme adds a level-3 index in bits 39..47 on top,
+    /// if platform() finds the harts support it.
+    ///
+    /// Returns `None` if `va` is in a megapage.
     fn get_mut(
         &mut self,
         va: A,
         allocator: Option<&Spinlock<Kmem>>,
+    ) -> Option<&mut PageTableEntry> {
+        self.get_mut_at(va, 0, allocator)
+    }
+
+    /// Like `get_mut`, but returns the PTE in the level-`level` page-table page,
+    /// which maps a megapage if `level` is 1.
+    fn get_mut_at(
+        &mut self,
+        va: A,
+        level: usize,
+        allocator: Option<&Spinlock<Kmem>>,
     ) -> Option<&mut PageTableEntry> {
         assert!(va.into_usize() < MAXVA, "PageTable::get_mut");
         // SAFETY: self.ptr uniquely refers to a valid RawPageTable
         // according to the invariant.
         let mut page_table = unsafe { &mut *self.ptr };
-        for level in (1..3).rev() {
+        for level in (level + 1..platform().paging.levels()).rev() {
             page_table = page_table.get_table_mut(va.px(level), allocator)?;
         }
-        Some(page_table.get_entry_mut(va.px(0)))
+        Some(page_table.get_entry_mut(va.px(level)))
     }
 
     fn insert(
@@ -307,6 +364,45 @@ impl<A: VAddr> PageTable<A> {
         Ok(())
     }
 
+    /// Like `insert_range`, but uses megapages where both the virtual and
+    /// physical addresses are aligned to them, so that large ranges take
+    /// fewer page-table pages and TLB entries. `va` and `pa` must be
+    /// page-aligned. Mappings made by it must never be removed.
+    fn insert_range_huge(
+        &mut self,
+        va: A,
+        size: usize,
+        pa: PAddr,
+        perm: PteFlags,
+        allocator: &Spinlock<Kmem>,
+    ) -> Result<(), ()> {
+        assert!(va.is_page_aligned() && pa.is_page_aligned());
+        let size = pgroundup(size);
+        let mut i = 0;
+        while i < size {
+            let (va, pa) = (va + i, pa + i);
+            if (va.into_usize() | pa.into_usize()) % MEGAPAGE == 0 && size - i >= MEGAPAGE {
+                let pte = self.get_mut_at(va, 1, Some(allocator)).ok_or(())?;
+                assert!(!pte.is_valid(), "PageTable::insert_range_huge");
+                pte.set_entry(pa, perm);
+                i += MEGAPAGE;
+            } else {
+                self.insert(va, pa, perm, allocator)?;
+                i += PGSIZE;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `f` with the virtual address, the level, and the PTE of every
+    /// valid leaf.
+    fn for_each_leaf<F: FnMut(usize, usize, &PageTableEntry)>(&self, mut f: F) {
+        // SAFETY: self.ptr uniquely refers to a valid RawPageTable
+        // according to the invariant.
+        let page_table = unsafe { &*self.ptr };
+        page_table.for_each_leaf(platform().paging.levels() - 1, 0, &mut f);
+    }
+
     fn remove(&mut self, va: A) -> Option<PAddr> {
         let pte = self.get_mut(va, None)?;
         assert!(pte.is_data(), "PageTable::remove");
@@ -334,9 +430,25 @@ impl<A: VAddr> Drop for PageTable<A> {
     }
 }
 
+/// The address of the page of zeros that user memory maps until written.
+fn zero_page() -> usize {
+    // TODO: remove kernel_builder()
+    &kernel_builder().zero_page as *const _ as usize
+}
+
+/// The permission of the zero page mapped with perm. It is copy-on-write
+/// instead of writable.
+fn zero_page_perm(perm: PteFlags) -> PteFlags {
+    if perm.contains(PteFlags::W) {
+        (perm - PteFlags::W) | PteFlags::COW
+    } else {
+        perm
+    }
+}
+
 /// UserMemory manages the page table and allocated pages of a process. Its
-/// invariant guarantees that every PAddr mapped to VAddr except TRAMPOLINE and
-/// TRAPFRAME is from Page. This property is crucial for safety of methods that
+/// invariant guarantees that every PAddr mapped to VAddr except TRAMPOLINE,
+/// TRAPFRAME, VDSO_TIME, the zero page and shared text is from Page. This property is crucial for safety of methods that
 /// read or write on memory, such as copy_in. Also, it is essential for safety
 /// of freeing a page created from each PAddr as well.
 ///
@@ -346,9 +458,16 @@ impl<A: VAddr> Drop for PageTable<A> {
 /// - If va ∈ dom(pt), va mod PGSIZE = 0 ∧ pt(va) mod PGSIZE = 0.
 /// - pt(TRAMPOLINE) = trampoline.
 /// - TRAPFRAME ∈ dom(pt).
-/// - If va ∈ dom(pt) ∧ va ∉ { TRAMPOLINE, TRAPFRAME },
-///   then Page::from_usize(pt(va)) succeeds without breaking the invariant of Page.
-/// - If va ∈ dom(pt) where va ∉ { 0, TRAMPOLINE, TRAPFRAME },
+/// - pt(VDSO_TIME) = the address of kernel_builder().vdso.
+/// - VDSO_PROC ∈ dom(pt).
+/// - If va ∈ dom(pt) ∧ va ∉ { TRAMPOLINE, TRAPFRAME, VDSO_TIME } ∧ pt(va) ≠ zero_page()
+///   ∧ va is not SHARED, then Page::from_usize(pt(va)) succeeds without breaking the
+///   invariant of Page.
+/// - If pt(va) = zero_page(), then pt does not let user code write to va.
+/// - nzero = |{ va | pt(va) = zero_page() }|, and as many pages are reserved in the allocator.
+/// - If va is SHARED, then pt(va) is a page of text, and pt does not let user code write to va.
+/// - nshared = |{ va | va is SHARED }|.
+/// - If va ∈ dom(pt) where va ∉ { 0, TRAMPOLINE, TRAPFRAME, VDSO_TIME, VDSO_PROC },
 ///   then va - PGSIZE ∈ dom(pt).
 /// - pgroundup(size) ∉ dom(pt).
 /// - If size > 0, then pgroundup(size) - PGSIZE ∈ dom(pt).
@@ -357,11 +476,19 @@ pub struct UserMemory {
     page_table: PageTable<UVAddr>,
     /// Size of process memory (bytes).
     size: usize,
+    /// Number of pages that map the zero page. Growing the memory maps the
+    /// zero page, and a page of its own replaces it when written.
+    nzero: usize,
+    /// Number of pages that map pages of `text`, which a page of its own
+    /// replaces when written.
+    nshared: usize,
+    /// The text of the program, if its pages are shared.
+    text: Option<RcText>,
 }
 
 impl UserMemory {
-    /// Create a user page table with no user memory, but with the trampoline
-    /// and a given trap frame. If `src_opt` is `Some(src)`, then load `src`
+    /// Create a user page table with no user memory, but with the trampoline,
+    /// a given trap frame, and the vDSO pages. If `src_opt` is `Some(src)`, then load `src`
     /// into address 0 of the pagetable. In this case, src.len() must be less
     /// than a page.
     /// Return Some(..) if every allocation has succeeded.
@@ -401,22 +528,44 @@ impl UserMemory {
             )
             .ok()?;
 
+        // Map the time, which every process shares, and a page for the
+        // constants of this process below TRAPFRAME, for user code to read.
+        page_table
+            .insert(
+                VDSO_TIME.into(),
+                // TODO: remove kernel_builder()
+                kernel_builder().vdso.addr().into(),
+                PteFlags::R | PteFlags::U,
+                allocator,
+            )
+            .ok()?;
+        let pa = allocator.alloc_zeroed()?.into_usize();
+        page_table
+            .insert(
+                VDSO_PROC.into(),
+                pa.into(),
+                PteFlags::R | PteFlags::U,
+                allocator,
+            )
+            // SAFETY: pa is the address of the page allocated above.
+            .map_err(|_| allocator.free(unsafe { Page::from_usize(pa) }))
+            .ok()?;
+
         let mut memory = Self {
             page_table: scopeguard::ScopeGuard::into_inner(page_table),
             size: 0,
+            nzero: 0,
+            nshared: 0,
+            text: None,
         };
 
         if let Some(src) = src_opt {
             assert!(src.len() < PGSIZE, "new: more than a page");
-            let mut page = allocator.alloc()?;
-            page.write_bytes(0);
+            let mut page = allocator.alloc_zeroed()?;
             (&mut page[..src.len()]).copy_from_slice(src);
             memory
-                .push_page(
-                    page,
-                    PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U,
-                    allocator,
-                )
+                // initcode does not write to memory, so W^X allows it to run.
+                .push_page(page, PteFlags::R | PteFlags::X | PteFlags::U, allocator)
                 .map_err(|page| allocator.free(page))
                 .ok()?;
         }
@@ -432,6 +581,7 @@ impl UserMemory {
         let mut new = scopeguard::guard(new, |mut new| {
             let _ = new.dealloc(0, allocator);
         });
+        new.text = self.text.clone();
         for i in num_iter::range_step(0, self.size, PGSIZE) {
             let pte = self
                 .page_table
@@ -439,8 +589,16 @@ impl UserMemory {
                 .expect("clone_into: pte not found");
             assert!(pte.is_valid(), "clone_into: invalid page");
 
-            let pa = pte.get_pa();
             let flags = pte.get_flags();
+            if pte.is_zero_page() {
+                new.push_zero_page(flags, allocator).ok()?;
+                continue;
+            }
+            let pa = pte.get_pa();
+            if pte.is_shared() {
+                new.push_shared_page(pa, flags, allocator).ok()?;
+                continue;
+            }
             let mut page = allocator.alloc()?;
             // SAFETY: pa is an address in page_table,
             // and thus it is the address of a page by the invariant.
@@ -455,6 +613,18 @@ impl UserMemory {
         Some(new)
     }
 
+    /// Set the pid that user code reads at VDSO_PROC.
+    pub fn set_pid(&mut self, pid: i32) {
+        let pte = self
+            .page_table
+            .get_mut(VDSO_PROC.into(), None)
+            .expect("set_pid: VDSO_PROC not mapped");
+        let info = pte.get_pa().into_usize() as *mut VdsoProc;
+        // SAFETY: pt(VDSO_PROC) is the address of a page by the invariant,
+        // and only this memory refers to it.
+        unsafe { (*info).pid = pid };
+    }
+
     /// Get the size of this memory.
     pub fn size(&self) -> usize {
         self.size
@@ -473,11 +643,9 @@ impl UserMemory {
     ) -> Result<(), ()> {
         assert!(va.is_page_aligned(), "load_file: va must be page aligned");
         for i in num_iter::range_step(0, sz, PGSIZE as _) {
-            let dst = self
-                .get_slice(va + i as usize)
-                .expect("load_file: address should exist");
+            let dst = self.get_slice_mut(va + i as usize, PteFlags::W).ok_or(())?;
             let n = cmp::min((sz - i) as usize, PGSIZE);
-            let bytes_read = ip.read_bytes_kernel(&mut dst[..n], offset + i);
+            let bytes_read = ip.read_bytes_kernel(&mut dst[..n], offset + i)?;
             if bytes_read != n {
                 return Err(());
             }
@@ -485,8 +653,10 @@ impl UserMemory {
         Ok(())
     }
 
-    /// Allocate PTEs and physical memory to grow process to newsz, which need
-    /// not be page aligned. Returns Ok(new size) or Err(()) on error.
+    /// Allocate PTEs to grow process to newsz, which need not be page aligned.
+    /// The new pages map the zero page, and physical memory is reserved for
+    /// them. They are readable and writable, but not executable.
+    /// Returns Ok(new size) or Err(()) on error.
     pub fn alloc(&mut self, newsz: usize, allocator: &Spinlock<Kmem>) -> Result<usize, ()> {
         if newsz <= self.size {
             return Ok(self.size);
@@ -497,14 +667,7 @@ impl UserMemory {
             let _ = this.dealloc(oldsz, allocator);
         });
         while pgroundup(this.size) < pgroundup(newsz) {
-            let mut page = allocator.alloc().ok_or(())?;
-            page.write_bytes(0);
-            this.push_page(
-                page,
-                PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U,
-                allocator,
-            )
-            .map_err(|page| allocator.free(page))?;
+            this.push_zero_page(PteFlags::R | PteFlags::W | PteFlags::U, allocator)?;
         }
         let this = scopeguard::ScopeGuard::into_inner(this);
         this.size = newsz;
@@ -519,9 +682,7 @@ impl UserMemory {
         }
 
         while pgroundup(newsz) < pgroundup(self.size) {
-            if let Some(page) = self.pop_page() {
-                allocator.free(page);
-            }
+            self.pop_page(allocator);
         }
         self.size = newsz;
         newsz
@@ -543,6 +704,189 @@ impl UserMemory {
         Ok(size)
     }
 
+    /// Change the permission of the pages from va to va + len, which must be
+    /// page-aligned and within the memory, to perm, a combination of R, W and
+    /// X. The pages are inaccessible if perm is empty. Pages may not be both
+    /// writable and executable (W^X). Pages that user code may never access,
+    /// such as the guard page, stay so.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn protect(&mut self, va: UVAddr, len: usize, perm: PteFlags) -> Result<(), ()> {
+        let start = va.into_usize();
+        let end = start.checked_add(len).ok_or(())?;
+        if !va.is_page_aligned()
+            || end > pgroundup(self.size)
+            || !(PteFlags::R | PteFlags::W | PteFlags::X).contains(perm)
+            || perm.contains(PteFlags::W | PteFlags::X)
+        {
+            return Err(());
+        }
+        for va in num_iter::range_step(start, end, PGSIZE) {
+            let flags = self
+                .page_table
+                .get_mut(va.into(), None)
+                .expect("protect")
+                .get_flags();
+            if !flags.intersects(PteFlags::U | PteFlags::COW) {
+                return Err(());
+            }
+        }
+        let perm = if perm.is_empty() {
+            // COW tells the pages from those that user code may never access.
+            PteFlags::R | PteFlags::COW
+        } else {
+            perm | PteFlags::U
+        };
+        for va in num_iter::range_step(start, end, PGSIZE) {
+            let pte = self.page_table.get_mut(va.into(), None).expect("protect");
+            if pte.is_zero_page() {
+                pte.set_perm(zero_page_perm(perm));
+            } else if pte.is_shared() {
+                pte.set_perm(zero_page_perm(perm) | PteFlags::SHARED);
+            } else {
+                pte.set_perm(perm);
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the pages from va to va + len, which must be page-aligned and
+    /// within the memory, back to the allocator, so that they map the zero
+    /// page again. The pages must be writable, since others, such as code,
+    /// could not be read again. Pages of shared text are left alone, since
+    /// they already hold what the program does.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn discard(
+        &mut self,
+        va: UVAddr,
+        len: usize,
+        allocator: &Spinlock<Kmem>,
+    ) -> Result<(), ()> {
+        let start = va.into_usize();
+        let end = start.checked_add(len).ok_or(())?;
+        if !va.is_page_aligned() || end > pgroundup(self.size) {
+            return Err(());
+        }
+        for va in num_iter::range_step(start, end, PGSIZE) {
+            let flags = self
+                .page_table
+                .get_mut(va.into(), None)
+                .expect("discard")
+                .get_flags();
+            if !flags.contains(PteFlags::U) || !flags.intersects(PteFlags::W | PteFlags::COW) {
+                return Err(());
+            }
+        }
+        for va in num_iter::range_step(start, end, PGSIZE) {
+            let pte = self.page_table.get_mut(va.into(), None).expect("discard");
+            if pte.is_zero_page() || pte.is_shared() {
+                continue;
+            }
+            // SAFETY: pte.get_pa() is the address of a page by the invariant.
+            let page = unsafe { Page::from_usize(pte.get_pa().into_usize()) };
+            pte.set_entry(
+                zero_page().into(),
+                zero_page_perm(pte.get_flags() - PteFlags::V),
+            );
+            allocator.free_reserved(page);
+            self.nzero += 1;
+        }
+        Ok(())
+    }
+
+    /// Give each page from va to va + len, which must be page-aligned and
+    /// within the memory, a page of its own now if it is the zero page,
+    /// copy-on-write, rather than when first written.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn populate(
+        &mut self,
+        va: UVAddr,
+        len: usize,
+        allocator: &Spinlock<Kmem>,
+    ) -> Result<(), ()> {
+        let start = va.into_usize();
+        let end = start.checked_add(len).ok_or(())?;
+        if !va.is_page_aligned() || end > pgroundup(self.size) {
+            return Err(());
+        }
+        for va in num_iter::range_step(start, end, PGSIZE) {
+            let pte = self.page_table.get_mut(va.into(), None).expect("populate");
+            if pte.is_zero_page() && pte.get_flags().contains(PteFlags::U | PteFlags::COW) {
+                self.unshare(va.into(), allocator)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of pages of this memory that have physical memory of their
+    /// own, rather than map the zero page or shared text.
+    pub fn rss(&self) -> usize {
+        pgroundup(self.size) / PGSIZE - self.nzero - self.nshared
+    }
+
+    /// Is the page at va the zero page or shared text, copy-on-write for user
+    /// code?
+    pub fn is_cow(&mut self, va: UVAddr) -> bool {
+        va.into_usize() < self.size
+            && self
+                .page_table
+                .get_mut(pgrounddown(va.into_usize()).into(), None)
+                .map_or(false, |pte| {
+                    (pte.is_zero_page() || pte.is_shared())
+                        && pte.get_flags().contains(PteFlags::U | PteFlags::COW)
+                })
+    }
+
+    /// If the page at va, which must be page-aligned and mapped, is the zero
+    /// page or shared text, give it a copy of its own. A copy of the zero page
+    /// takes a reserved page. The page is writable if it was copy-on-write.
+    /// Returns Ok(()) on success, Err(()) if out of memory.
+    pub fn unshare(&mut self, va: UVAddr, allocator: &Spinlock<Kmem>) -> Result<(), ()> {
+        let pte = self.page_table.get_mut(va, None).expect("unshare");
+        let page = if pte.is_zero_page() {
+            allocator.alloc_reserved().ok_or(())?
+        } else if pte.is_shared() {
+            let mut page = allocator.alloc().ok_or(())?;
+            // SAFETY: pte.get_pa() is the address of a page of text by the
+            // invariant, which text keeps alive.
+            let src =
+                unsafe { slice::from_raw_parts(pte.get_pa().into_usize() as *const u8, PGSIZE) };
+            page.copy_from_slice(src);
+            page
+        } else {
+            return Ok(());
+        };
+        let mut perm = pte.get_flags() - PteFlags::V - PteFlags::SHARED;
+        if perm.contains(PteFlags::COW) {
+            perm = (perm - PteFlags::COW) | PteFlags::W;
+        }
+        if pte.is_zero_page() {
+            self.nzero -= 1;
+        } else {
+            self.nshared -= 1;
+        }
+        pte.set_entry(page.into_usize().into(), perm);
+        Ok(())
+    }
+
+    /// Keep text alive while this memory maps its pages.
+    pub fn set_text(&mut self, text: RcText) {
+        self.text = Some(text);
+    }
+
+    /// Map the page of text at pa at va, which must be page-aligned and map
+    /// the zero page. User code cannot write to the page, but writing to it
+    /// gives it a copy of its own if it is writable.
+    pub fn share(&mut self, va: UVAddr, pa: PAddr, allocator: &Spinlock<Kmem>) {
+        assert!(self.text.is_some(), "share: no text");
+        let pte = self.page_table.get_mut(va, None).expect("share");
+        assert!(pte.is_zero_page(), "share: not the zero page");
+        let perm = pte.get_flags() - PteFlags::V;
+        pte.set_entry(pa, perm | PteFlags::SHARED);
+        self.nzero -= 1;
+        self.nshared += 1;
+        allocator.unreserve(1);
+    }
+
     /// Mark a PTE invalid for user access.
     /// Used by exec for the user stack guard page.
     pub fn clear(&mut self, va: UVAddr) {
@@ -554,15 +898,16 @@ impl UserMemory {
 
     /// Copy from kernel to user.
     /// Copy len bytes from src to virtual address dstva in a given page table.
+    /// User code must be able to access the pages with perm.
     /// Return Ok(()) on success, Err(()) on error.
-    pub fn copy_out_bytes(&mut self, dstva: UVAddr, src: &[u8]) -> Result<(), ()> {
+    fn copy_out_bytes(&mut self, dstva: UVAddr, src: &[u8], perm: PteFlags) -> Result<(), ()> {
         let mut dst = dstva.into_usize();
         let mut len = src.len();
         let mut offset = 0;
         while len > 0 {
             let va = pgrounddown(dst);
             let poffset = dst - va;
-            let page = self.get_slice(va.into()).ok_or(())?;
+            let page = self.get_slice_mut(va.into(), perm).ok_or(())?;
             let n = cmp::min(PGSIZE - poffset, len);
             page[poffset..poffset + n].copy_from_slice(&src[offset..offset + n]);
             len -= n;
@@ -575,26 +920,27 @@ impl UserMemory {
     /// Copy from kernel to user.
     /// Copy from src to virtual address dstva in a given page table.
     /// Return Ok(()) on success, Err(()) on error.
-    pub fn copy_out<T>(&mut self, dstva: UVAddr, src: &T) -> Result<(), ()> {
+    fn copy_out<T>(&mut self, dstva: UVAddr, src: &T) -> Result<(), ()> {
         self.copy_out_bytes(
             dstva,
             // SAFETY: src is a valid reference to T and
             // u8 does not have any internal structure.
             unsafe { core::slice::from_raw_parts_mut(src as *const _ as _, mem::size_of::<T>()) },
+            PteFlags::W,
         )
     }
 
     /// Copy from user to kernel.
     /// Copy len bytes to dst from virtual address srcva in a given page table.
     /// Return Ok(()) on success, Err(()) on error.
-    pub fn copy_in_bytes(&mut self, dst: &mut [u8], srcva: UVAddr) -> Result<(), ()> {
+    fn copy_in_bytes(&mut self, dst: &mut [u8], srcva: UVAddr) -> Result<(), ()> {
         let mut src = srcva.into_usize();
         let mut len = dst.len();
         let mut offset = 0;
         while len > 0 {
             let va = pgrounddown(src);
             let poffset = src - va;
-            let page = self.get_slice(va.into()).ok_or(())?;
+            let page = self.get_slice(va.into(), PteFlags::R).ok_or(())?;
             let n = cmp::min(PGSIZE - poffset, len);
             dst[offset..offset + n].copy_from_slice(&page[poffset..poffset + n]);
             len -= n;
@@ -611,7 +957,7 @@ impl UserMemory {
     /// # Safety
     ///
     /// `T` can be safely `transmute`d to `[u8; size_of::<T>()]`.
-    pub unsafe fn copy_in<T>(&mut self, dst: &mut T, srcva: UVAddr) -> Result<(), ()> {
+    unsafe fn copy_in<T>(&mut self, dst: &mut T, srcva: UVAddr) -> Result<(), ()> {
         self.copy_in_bytes(
             unsafe { core::slice::from_raw_parts_mut(dst as *mut _ as _, mem::size_of::<T>()) },
             srcva,
@@ -622,14 +968,14 @@ impl UserMemory {
     /// Copy bytes to dst from virtual address srcva in a given page table,
     /// until a '\0', or max.
     /// Return OK(()) on success, Err(()) on error.
-    pub fn copy_in_str(&mut self, dst: &mut [u8], srcva: UVAddr) -> Result<(), ()> {
+    fn copy_in_str(&mut self, dst: &mut [u8], srcva: UVAddr) -> Result<(), ()> {
         let mut src = srcva.into_usize();
         let mut offset = 0;
         let mut max = dst.len();
         while max > 0 {
             let va = pgrounddown(src);
             let poffset = src - va;
-            let page = self.get_slice(va.into()).ok_or(())?;
+            let page = self.get_slice(va.into(), PteFlags::R).ok_or(())?;
             let n = cmp::min(PGSIZE - poffset, max);
 
             let from = &page[poffset..poffset + n];
@@ -649,25 +995,76 @@ impl UserMemory {
         Err(())
     }
 
-    /// Return the address of the page table for this memory in the riscv's sv39
-    /// page table scheme.
+    /// Does the page table map nothing of the kernel but the trampoline, the
+    /// trap frame at trap_frame, and the vDSO pages? User code must not
+    /// see the rest even through supervisor-only pages, which is why kernel
+    /// code and data live in a separate page table.
+    pub fn is_isolated(&self, trap_frame: PAddr) -> bool {
+        // SAFETY: we assume that reading the address of trampoline is safe.
+        let trampoline_pa = unsafe { trampoline.as_mut_ptr() as usize };
+        // SAFETY: we assume that reading the address of end is safe.
+        let kernel_end = unsafe { kalloc::end.as_mut_ptr() as usize };
+        // TODO: remove kernel_builder()
+        let vdso_time = kernel_builder().vdso.addr();
+        let size = pgroundup(self.size);
+        let mut isolated = true;
+        self.page_table.for_each_leaf(|va, level, pte| {
+            let pa = pte.get_pa().into_usize();
+            let flags = pte.get_flags();
+            isolated &= level == 0
+                && match va {
+                    TRAMPOLINE => pa == trampoline_pa && !flags.contains(PteFlags::U),
+                    TRAPFRAME => pa == trap_frame.into_usize() && !flags.contains(PteFlags::U),
+                    VDSO_TIME => pa == vdso_time && !flags.contains(PteFlags::W),
+                    VDSO_PROC => pa >= kernel_end && !flags.contains(PteFlags::W),
+                    // Pages of this memory, including shared text, come from
+                    // the allocator, which hands out only pages after the
+                    // kernel, or are the zero page, which user code cannot
+                    // write.
+                    _ => {
+                        va < size
+                            && (pa >= kernel_end
+                                || (pa == zero_page() && !flags.contains(PteFlags::W)))
+                    }
+                };
+        });
+        isolated
+    }
+
+    /// Return the address of the page table for this memory in the riscv's page
+    /// table scheme in use.
     pub fn satp(&self) -> usize {
-        make_satp(self.page_table.as_usize())
+        make_satp(platform().paging, self.page_table.as_usize())
     }
 
-    /// Return a page at va as a slice. Some(page) on success, None on failure.
-    fn get_slice(&mut self, va: UVAddr) -> Option<&mut [u8]> {
-        if va.into_usize() >= TRAPFRAME {
+    /// Return a page at va as a slice, if user code may access it with perm.
+    /// Some(page) on success, None on failure.
+    fn get_slice(&mut self, va: UVAddr, perm: PteFlags) -> Option<&mut [u8]> {
+        // The vDSO pages are read-only for user code, so the kernel does not
+        // write to them on its behalf either.
+        if va.into_usize() >= VDSO_PROC {
             return None;
         }
         let pte = self.page_table.get_mut(va, None)?;
-        if !pte.is_user() {
+        let mut flags = pte.get_flags();
+        if flags.contains(PteFlags::COW) {
+            flags |= PteFlags::W;
+        }
+        if !pte.is_user() || !flags.contains(perm) {
             return None;
         }
-        // SAFETY: va < TRAPFRAME, so pte.get_pa() is the address of a page.
+        // SAFETY: va < VDSO_PROC, so pte.get_pa() is the address of a page.
         Some(unsafe { slice::from_raw_parts_mut(pte.get_pa().into_usize() as _, PGSIZE) })
     }
 
+    /// Like `get_slice`, but for writing: the page is not the zero page.
+    fn get_slice_mut(&mut self, va: UVAddr, perm: PteFlags) -> Option<&mut [u8]> {
+        let _ = self.get_slice(va, perm)?;
+        // TODO: remove kernel_builder()
+        self.unshare(va, &kernel_builder().kmem).ok()?;
+        self.get_slice(va, perm)
+    }
+
     /// Increase the size by appending a given page with given flags.
     /// Ok(()) on success, Err(given page) on failure.
     fn push_page(
@@ -687,25 +1084,77 @@ impl UserMemory {
         Ok(())
     }
 
-    /// Decrease the size by removing the most recently appended page.
-    /// Some(page) if size > 0, None if size = 0.
-    fn pop_page(&mut self) -> Option<Page> {
-        if self.size == 0 {
-            return None;
+    /// Increase the size by appending the zero page with given flags,
+    /// reserving a page for it. Ok(()) on success, Err(()) on failure.
+    fn push_zero_page(&mut self, perm: PteFlags, allocator: &Spinlock<Kmem>) -> Result<(), ()> {
+        allocator.reserve(1)?;
+        let size = pgroundup(self.size);
+        if let Err(()) = self.page_table.insert(
+            size.into(),
+            zero_page().into(),
+            zero_page_perm(perm),
+            allocator,
+        ) {
+            allocator.unreserve(1);
+            return Err(());
         }
+        self.size = size + PGSIZE;
+        self.nzero += 1;
+        Ok(())
+    }
+
+    /// Increase the size by appending the page of text at pa with given
+    /// flags, which include SHARED. Ok(()) on success, Err(()) on failure.
+    fn push_shared_page(
+        &mut self,
+        pa: PAddr,
+        perm: PteFlags,
+        allocator: &Spinlock<Kmem>,
+    ) -> Result<(), ()> {
+        let size = pgroundup(self.size);
+        self.page_table.insert(size.into(), pa, perm, allocator)?;
+        self.size = size + PGSIZE;
+        self.nshared += 1;
+        Ok(())
+    }
+
+    /// Decrease the size by removing the most recently appended page, which
+    /// must exist. Frees the page if it has one of its own, or releases the
+    /// reserved page if it is the zero page.
+    fn pop_page(&mut self, allocator: &Spinlock<Kmem>) {
+        assert!(self.size > 0, "pop_page");
         self.size = pgroundup(self.size) - PGSIZE;
+        let pte = self
+            .page_table
+            .get_mut(self.size.into(), None)
+            .expect("pop_page");
+        let (zero, shared) = (pte.is_zero_page(), pte.is_shared());
         let pa = self
             .page_table
             .remove(self.size.into())
             .expect("pop_page")
             .into_usize();
-        // SAFETY: pa is an address in page_table,
-        // and, thus, it is the address of a page by the invariant.
-        Some(unsafe { Page::from_usize(pa) })
+        if zero {
+            self.nzero -= 1;
+            allocator.unreserve(1);
+        } else if shared {
+            self.nshared -= 1;
+        } else {
+            // SAFETY: pa is an address in page_table,
+            // and, thus, it is the address of a page by the invariant.
+            allocator.free(unsafe { Page::from_usize(pa) });
+        }
     }
 
     pub fn free(mut self, allocator: &Spinlock<Kmem>) {
         let _ = self.dealloc(0, allocator);
+        drop(self.text.take());
+        let pa = self
+            .page_table
+            .remove(VDSO_PROC.into())
+            .expect("free: VDSO_PROC not mapped");
+        // SAFETY: pt(VDSO_PROC) is the address of a page by the invariant.
+        allocator.free(unsafe { Page::from_usize(pa.into_usize()) });
         // SAFETY: self will be dropped.
         unsafe { self.page_table.free(allocator) };
         mem::forget(self);
@@ -720,6 +1169,168 @@ impl Drop for UserMemory {
     }
 }
 
+/// A range of bytes in user memory, given by user code.
+///
+/// Data moves between the kernel and user memory only through UserSlice and
+/// UserPtr. A range that wraps around, or reaches the pages that the kernel
+/// maps at the top of every process, is rejected when it is made. A range
+/// outside the memory of the process, or in pages that user code may not
+/// access in the same way, is rejected when it is accessed.
+#[derive(Clone, Copy)]
+pub struct UserSlice {
+    addr: UVAddr,
+    len: usize,
+}
+
+impl UserSlice {
+    /// Returns Ok(the len bytes from addr) on success, Err(()) if the range is
+    /// not in user address space.
+    pub fn new(addr: usize, len: usize) -> Result<Self, ()> {
+        match addr.checked_add(len) {
+            Some(end) if end <= VDSO_PROC => {
+                Ok(Self {
+                    addr: addr.into(),
+                    len,
+                })
+            }
+            _ => Err(()),
+        }
+    }
+
+    pub fn len(self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    /// The len bytes from the off'th byte of self.
+    /// Panics if they are not in self.
+    pub fn sub(self, off: usize, len: usize) -> Self {
+        assert!(
+            off.checked_add(len).map_or(false, |end| end <= self.len),
+            "UserSlice::sub"
+        );
+        Self {
+            addr: self.addr + off,
+            len,
+        }
+    }
+
+    /// Copy the bytes into dst, which is as long as self.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn read(self, dst: &mut [u8], mem: &mut UserMemory) -> Result<(), ()> {
+        assert_eq!(dst.len(), self.len, "UserSlice::read");
+        self.check(mem)?;
+        mem.copy_in_bytes(dst, self.addr)
+    }
+
+    /// Copy src, which is as long as self, into the bytes.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn write(self, src: &[u8], mem: &mut UserMemory) -> Result<(), ()> {
+        assert_eq!(src.len(), self.len, "UserSlice::write");
+        self.check(mem)?;
+        mem.copy_out_bytes(self.addr, src, PteFlags::W)
+    }
+
+    /// Like write(), but also into pages that user code may not write, such
+    /// as its code, so that a debugger can set breakpoints.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn poke(self, src: &[u8], mem: &mut UserMemory) -> Result<(), ()> {
+        assert_eq!(src.len(), self.len, "UserSlice::poke");
+        self.check(mem)?;
+        mem.copy_out_bytes(self.addr, src, PteFlags::empty())
+    }
+
+    /// Check that the bytes are in the memory of the process.
+    fn check(self, mem: &UserMemory) -> Result<(), ()> {
+        if self.addr.into_usize() + self.len > mem.size() {
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+/// A pointer to a T in user memory, given by user code. See UserSlice.
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    /// Returns Ok(the pointer to addr) on success, Err(()) if the T at addr is
+    /// not in user address space.
+    pub fn new(addr: usize) -> Result<Self, ()> {
+        let _ = UserSlice::new(addr, mem::size_of::<T>())?;
+        Ok(Self {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    /// The pointer to the i'th T of the array that starts at self.
+    /// Returns Ok(pointer) on success, Err(()) if it is not in user address
+    /// space.
+    pub fn add(self, i: usize) -> Result<Self, ()> {
+        Self::new(
+            i.checked_mul(mem::size_of::<T>())
+                .and_then(|off| self.addr.checked_add(off))
+                .ok_or(())?,
+        )
+    }
+
+    fn as_slice(self) -> UserSlice {
+        UserSlice {
+            addr: self.addr.into(),
+            len: mem::size_of::<T>(),
+        }
+    }
+
+    /// Copy the T into dst.
+    /// Returns Ok(()) on success, Err(()) on error.
+    ///
+    /// # Safety
+    ///
+    /// `T` can be safely `transmute`d to `[u8; size_of::<T>()]`.
+    pub unsafe fn read(self, dst: &mut T, mem: &mut UserMemory) -> Result<(), ()> {
+        self.as_slice().check(mem)?;
+        // SAFETY: the safety condition of this method.
+        unsafe { mem.copy_in(dst, self.addr.into()) }
+    }
+
+    /// Copy src into the T.
+    /// Returns Ok(()) on success, Err(()) on error.
+    pub fn write(self, src: &T, mem: &mut UserMemory) -> Result<(), ()> {
+        self.as_slice().check(mem)?;
+        mem.copy_out(self.addr.into(), src)
+    }
+}
+
+impl UserPtr<u8> {
+    /// Copy the null-terminated string that starts at self into dst,
+    /// including the '\0'.
+    /// Returns Ok(()) on success, Err(()) on error, or if dst is too short.
+    pub fn read_str(self, dst: &mut [u8], mem: &mut UserMemory) -> Result<(), ()> {
+        // The string may not go beyond the memory of the process.
+        let max = mem.size().checked_sub(self.addr).ok_or(())?;
+        let len = cmp::min(dst.len(), max);
+        mem.copy_in_str(&mut dst[..len], self.addr.into())
+    }
+}
+
 /// KernelMemory manages the page table and allocated pages of the kernel.
 /// Every PAddr in KernelMemory is not originated from a page. KernelMemory
 /// neither provides memory read/write methods nor decreases memory. Therefore,
@@ -741,13 +1352,25 @@ impl KernelMemory {
             unsafe { page_table.free(allocator) };
             mem::forget(page_table);
         });
+        let platform = platform();
 
         // SiFive Test Finisher MMIO
         page_table
             .insert_range(
-                FINISHER.into(),
+                platform.finisher.into(),
+                PGSIZE,
+                platform.finisher.into(),
+                PteFlags::R | PteFlags::W,
+                allocator,
+            )
+            .ok()?;
+
+        // Goldfish RTC
+        page_table
+            .insert_range(
+                platform.rtc.into(),
                 PGSIZE,
-                FINISHER.into(),
+                platform.rtc.into(),
                 PteFlags::R | PteFlags::W,
                 allocator,
             )
@@ -756,31 +1379,45 @@ impl KernelMemory {
         // Uart registers
         page_table
             .insert_range(
-                UART0.into(),
+                platform.uart.base.into(),
                 PGSIZE,
-                UART0.into(),
+                platform.uart.base.into(),
                 PteFlags::R | PteFlags::W,
                 allocator,
             )
             .ok()?;
 
-        // Virtio mmio disk interface
+        // gdb's virtio mmio interface
+        #[cfg(feature = "gdbstub")]
         page_table
             .insert_range(
-                VIRTIO0.into(),
+                platform.gdb_virtio.base.into(),
                 PGSIZE,
-                VIRTIO0.into(),
+                platform.gdb_virtio.base.into(),
                 PteFlags::R | PteFlags::W,
                 allocator,
             )
             .ok()?;
 
+        // Virtio mmio disk, keyboard, second disk, and md disk interfaces
+        for virtio in &platform.virtio {
+            page_table
+                .insert_range(
+                    virtio.base.into(),
+                    PGSIZE,
+                    virtio.base.into(),
+                    PteFlags::R | PteFlags::W,
+                    allocator,
+                )
+                .ok()?;
+        }
+
         // PLIC
         page_table
-            .insert_range(
-                PLIC.into(),
+            .insert_range_huge(
+                platform.plic.into(),
                 0x400000,
-                PLIC.into(),
+                platform.plic.into(),
                 PteFlags::R | PteFlags::W,
                 allocator,
             )
@@ -790,7 +1427,7 @@ impl KernelMemory {
         // SAFETY: we assume that reading the address of etext is safe.
         let et = unsafe { etext.as_mut_ptr() as usize };
         page_table
-            .insert_range(
+            .insert_range_huge(
                 KERNBASE.into(),
                 et - KERNBASE,
                 KERNBASE.into(),
@@ -800,10 +1437,11 @@ impl KernelMemory {
             .ok()?;
 
         // Map kernel data and the physical RAM we'll make use of.
+        // Most of it is in megapages.
         page_table
-            .insert_range(
+            .insert_range_huge(
                 et.into(),
-                PHYSTOP - et,
+                phystop() - et,
                 et.into(),
                 PteFlags::R | PteFlags::W,
                 allocator,
@@ -848,7 +1486,7 @@ impl KernelMemory {
     /// Switch h/w page table register to the kernel's page table, and enable paging.
     pub unsafe fn init_hart(&self) {
         unsafe {
-            w_satp(make_satp(self.page_table.as_usize()));
+            w_satp(make_satp(platform().paging, self.page_table.as_usize()));
             sfence_vma();
         }
     }
@@ -0,0 +1,261 @@
+//! A model of a VT100-like terminal screen.
+//!
+//! Output to a virtual console is sent to the uart as it is, and the terminal
+//! on the other side renders it. At the same time, the output is fed to the
+//! console's `Screen`, which interprets the same subset of ANSI escape
+//! sequences that rv6 programs use, so that the screen can be redrawn when
+//! switching virtual consoles, and lines scrolled off the top can be viewed
+//! later. Colors (SGR) are passed through but not remembered.
+
+use core::{cmp, fmt, ops::Range};
+
+/// Number of rows of the screen.
+pub const ROWS: usize = 24;
+
+/// Number of columns of the screen.
+pub const COLS: usize = 80;
+
+/// Number of lines kept after they scroll off the top of the screen.
+pub const SCROLLBACK: usize = 64;
+
+/// Number of lines kept in total.
+const NLINES: usize = ROWS + SCROLLBACK;
+
+/// Maximum number of parameters of a control sequence.
+const NPARAMS: usize = 4;
+
+/// Escape sequence parser state.
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Normal,
+    /// After ESC.
+    Escape,
+    /// After ESC [.
+    Csi,
+}
+
+pub struct Screen {
+    /// Lines of the screen, preceded by the scrollback, in a ring buffer.
+    lines: [[u8; COLS]; NLINES],
+
+    /// Index in `lines` of the top row of the screen.
+    top: usize,
+
+    /// Number of lines in the scrollback.
+    history: usize,
+
+    /// Cursor row.
+    row: usize,
+
+    /// Cursor column. It is `COLS` after writing to the last column,
+    /// so that the next character wraps to the next line.
+    col: usize,
+
+    state: State,
+
+    params: [u16; NPARAMS],
+
+    nparams: usize,
+}
+
+impl Screen {
+    pub const fn new() -> Self {
+        Self {
+            lines: [[b' '; COLS]; NLINES],
+            top: 0,
+            history: 0,
+            row: 0,
+            col: 0,
+            state: State::Normal,
+            params: [0; NPARAMS],
+            nparams: 0,
+        }
+    }
+
+    /// Clear the screen and the scrollback.
+    // This does not assign `Self::new()`, which is too large for a kernel stack.
+    fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            *line = [b' '; COLS];
+        }
+        self.top = 0;
+        self.history = 0;
+        self.row = 0;
+        self.col = 0;
+    }
+
+    /// Number of lines that can be viewed above the screen.
+    pub fn history(&self) -> usize {
+        self.history
+    }
+
+    /// The `row`th row of the screen scrolled back by `back` lines.
+    fn line(&self, back: usize, row: usize) -> &[u8; COLS] {
+        &self.lines[(self.top + NLINES - back + row) % NLINES]
+    }
+
+    fn line_mut(&mut self, row: usize) -> &mut [u8; COLS] {
+        &mut self.lines[(self.top + row) % NLINES]
+    }
+
+    /// Move the cursor down, scrolling the screen up at the bottom.
+    fn linefeed(&mut self) {
+        if self.row + 1 < ROWS {
+            self.row += 1;
+            return;
+        }
+        self.top = (self.top + 1) % NLINES;
+        self.history = cmp::min(self.history + 1, SCROLLBACK);
+        *self.line_mut(ROWS - 1) = [b' '; COLS];
+    }
+
+    /// The `i`th parameter of the control sequence, or `default` if omitted.
+    fn param(&self, i: usize, default: usize) -> usize {
+        if i < self.nparams && self.params[i] != 0 {
+            self.params[i] as usize
+        } else {
+            default
+        }
+    }
+
+    /// Erase columns `cols` of the `row`th row.
+    fn erase(&mut self, row: usize, cols: Range<usize>) {
+        for c in &mut self.line_mut(row)[cols] {
+            *c = b' ';
+        }
+    }
+
+    /// Feed one byte of output.
+    pub fn putc(&mut self, c: u8) {
+        match self.state {
+            State::Normal => self.put_normal(c),
+            State::Escape => {
+                self.state = State::Normal;
+                match c {
+                    b'[' => {
+                        self.params = [0; NPARAMS];
+                        self.nparams = 0;
+                        self.state = State::Csi;
+                    }
+                    b'c' => self.reset(),
+                    _ => (),
+                }
+            }
+            State::Csi => {
+                match c {
+                    b'0'..=b'9' => {
+                        if self.nparams == 0 {
+                            self.nparams = 1;
+                        }
+                        if let Some(p) = self.params.get_mut(self.nparams - 1) {
+                            *p = p.saturating_mul(10).saturating_add((c - b'0') as u16);
+                        }
+                    }
+                    b';' => self.nparams = cmp::max(self.nparams, 1) + 1,
+                    0x40..=0x7e => {
+                        self.state = State::Normal;
+                        self.control(c);
+                    }
+                    // Private markers and intermediate bytes are ignored.
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn put_normal(&mut self, c: u8) {
+        match c {
+            0x1b => self.state = State::Escape,
+            // The uart sends newlines as carriage return and line feed.
+            b'\n' => {
+                self.col = 0;
+                self.linefeed();
+            }
+            b'\r' => self.col = 0,
+            0x08 => self.col = cmp::min(self.col, COLS - 1).saturating_sub(1),
+            b'\t' => self.col = cmp::min((self.col / 8 + 1) * 8, COLS - 1),
+            0x20..=0x7e => {
+                if self.col == COLS {
+                    self.col = 0;
+                    self.linefeed();
+                }
+                let col = self.col;
+                self.line_mut(self.row)[col] = c;
+                self.col += 1;
+            }
+            // Other control characters do not change the screen.
+            _ => (),
+        }
+    }
+
+    /// Execute the control sequence ending with `c`.
+    fn control(&mut self, c: u8) {
+        let n = self.param(0, 1);
+        let col = cmp::min(self.col, COLS - 1);
+        match c {
+            // Cursor up, down, forward and back.
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = cmp::min(self.row + n, ROWS - 1),
+            b'C' => self.col = cmp::min(col + n, COLS - 1),
+            b'D' => self.col = col.saturating_sub(n),
+            // Cursor position.
+            b'H' | b'f' => {
+                self.row = cmp::min(self.param(0, 1), ROWS) - 1;
+                self.col = cmp::min(self.param(1, 1), COLS) - 1;
+            }
+            // Erase in display.
+            b'J' => {
+                let (from, to) = match self.param(0, 0) {
+                    0 => {
+                        self.erase(self.row, col..COLS);
+                        (self.row + 1, ROWS)
+                    }
+                    1 => {
+                        self.erase(self.row, 0..col + 1);
+                        (0, self.row)
+                    }
+                    _ => (0, ROWS),
+                };
+                for row in from..to {
+                    self.erase(row, 0..COLS);
+                }
+            }
+            // Erase in line.
+            b'K' => {
+                match self.param(0, 0) {
+                    0 => self.erase(self.row, col..COLS),
+                    1 => self.erase(self.row, 0..col + 1),
+                    _ => self.erase(self.row, 0..COLS),
+                }
+            }
+            // Colors and other sequences are left to the terminal.
+            _ => (),
+        }
+    }
+
+    /// Redraw the screen scrolled back by `back` lines on `out`.
+    pub fn redraw<W: fmt::Write>(&self, back: usize, out: &mut W) -> fmt::Result {
+        let back = cmp::min(back, self.history);
+        out.write_str("\x1b[0m\x1b[2J\x1b[H")?;
+        for row in 0..ROWS {
+            let line = self.line(back, row);
+            let len = line.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+            if row > 0 {
+                out.write_str("\r\n")?;
+            }
+            for c in &line[..len] {
+                out.write_char(*c as char)?;
+            }
+        }
+        if back == 0 {
+            write!(
+                out,
+                "\x1b[{};{}H",
+                self.row + 1,
+                cmp::min(self.col, COLS - 1) + 1
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
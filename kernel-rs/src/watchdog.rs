@@ -0,0 +1,91 @@
+//! A watchdog for harts that lock up.
+//!
+//! At each timer interrupt, a hart stamps its heartbeat with the time and
+//! records the pc the interrupt came at, and then looks at the heartbeats of
+//! the other harts. A hart whose heartbeat has not moved for WATCHDOG_TICKS
+//! timer intervals has run with interrupts off all that while, spinning on a
+//! lock that is never released or looping in the scheduler, and the kernel
+//! panics with the last pc the hart was seen at, instead of hanging
+//! silently. Harts watch one another, so a lockup of the only running hart
+//! goes unnoticed.
+//!
+//! The watchdog is armed at the end of the boot. `watchdog=off` on the
+//! command line keeps it off, and so does the gdbstub feature, as gdb stops
+//! the harts for as long as it wants.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use array_macro::array;
+
+use crate::{
+    cmdline::cmdline,
+    kernel::Kernel,
+    param::{NCPU, WATCHDOG_TICKS},
+    percpu::PerCpu,
+    proc::cpuid,
+    riscv::{r_sepc, r_time},
+    start::TIMER_INTERVAL,
+    time::{NSEC_PER_SEC, TIMEBASE_FREQ},
+};
+
+struct Heartbeat {
+    /// The time of the last timer interrupt of the hart. 0 if the hart has
+    /// not taken one yet.
+    time: AtomicU64,
+
+    /// The pc the last timer interrupt came at.
+    pc: AtomicUsize,
+}
+
+static HEARTBEATS: PerCpu<Heartbeat> = PerCpu::new(array![_ => Heartbeat {
+    time: AtomicU64::new(0),
+    pc: AtomicUsize::new(0),
+}; NCPU]);
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the hart that found a lockup, so that only it panics.
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Arms the watchdog, unless the command line turns it off.
+pub fn init() {
+    if cfg!(feature = "gdbstub") || cmdline().is_off("watchdog") {
+        return;
+    }
+    ARMED.store(true, Ordering::Release);
+}
+
+/// Stamps the heartbeat of this hart, and checks those of the others.
+/// Called at each timer interrupt, with interrupts off.
+pub fn tick(kernel: &Kernel) {
+    let now = r_time();
+    let me = cpuid();
+    // SAFETY: the heartbeat is atomic.
+    let heartbeat = unsafe { &*HEARTBEATS.get_raw(me) };
+    heartbeat.pc.store(r_sepc(), Ordering::Relaxed);
+    heartbeat.time.store(now, Ordering::Release);
+
+    // The other harts stop on purpose when the kernel halts.
+    if !ARMED.load(Ordering::Acquire) || kernel.is_halted() || kernel.is_panicked() {
+        return;
+    }
+
+    let timeout = WATCHDOG_TICKS * TIMER_INTERVAL as u64;
+    for id in (0..NCPU).filter(|id| *id != me) {
+        // SAFETY: the heartbeat is atomic.
+        let heartbeat = unsafe { &*HEARTBEATS.get_raw(id) };
+        let time = heartbeat.time.load(Ordering::Acquire);
+        if time == 0 || now.saturating_sub(time) < timeout {
+            continue;
+        }
+        if FIRED.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        panic!(
+            "watchdog: hart {} stuck for {} ms, last pc={:018p}",
+            id,
+            (now - time) * (NSEC_PER_SEC / TIMEBASE_FREQ) / 1_000_000,
+            heartbeat.pc.load(Ordering::Relaxed) as *const u8
+        );
+    }
+}
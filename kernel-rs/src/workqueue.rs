@@ -0,0 +1,116 @@
+//! Deferred work of interrupt handlers, their bottom halves.
+//!
+//! An interrupt handler does only what cannot wait with interrupts off, such
+//! as acknowledging its device, and queues the rest as a `Work` on its hart.
+//! The hart runs the queued work as it leaves the trap, with interrupts on,
+//! so that other devices are not held up meanwhile. Work runs on behalf of
+//! whatever the trap interrupted, so it must not sleep.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use array_macro::array;
+
+use crate::{
+    kernel::Kernel,
+    param::NCPU,
+    percpu::PerCpu,
+    riscv::{intr_off, intr_on},
+};
+
+/// Works that a hart can have queued at once.
+const NWORK: usize = 8;
+
+pub type WorkFn = fn(&Kernel);
+
+/// A piece of deferred work, queued at most once at a time.
+pub struct Work {
+    f: WorkFn,
+    queued: AtomicBool,
+}
+
+struct Queue {
+    works: [Option<&'static Work>; NWORK],
+
+    /// The oldest work is at `works[head]`.
+    head: usize,
+    len: usize,
+
+    /// Is this hart running the queue?
+    running: bool,
+}
+
+static QUEUES: PerCpu<Queue> = PerCpu::new(array![_ => Queue::new(); NCPU]);
+
+impl Work {
+    pub const fn new(f: WorkFn) -> Self {
+        Self {
+            f,
+            queued: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            works: [None; NWORK],
+            head: 0,
+            len: 0,
+            running: false,
+        }
+    }
+
+    fn push(&mut self, work: &'static Work) {
+        assert!(self.len < NWORK, "workqueue: full");
+        self.works[(self.head + self.len) % NWORK] = Some(work);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.works[self.head].take();
+        self.head = (self.head + 1) % NWORK;
+        self.len -= 1;
+        work
+    }
+}
+
+/// Queues `work` on this hart, unless it is queued already. Interrupts must
+/// be off, as in an interrupt handler.
+pub fn queue(work: &'static Work) {
+    if work.queued.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    // SAFETY: interrupts are off, and the reference does not outlive this.
+    unsafe { QUEUES.current_mut() }.push(work);
+}
+
+/// Runs the work queued on this hart, with interrupts on. Called with
+/// interrupts off at the end of a trap, and returns with them off.
+///
+/// # Safety
+///
+/// Turning interrupts on must be safe, that is, no spinlock may be held.
+pub unsafe fn run(kernel: &Kernel) {
+    {
+        // SAFETY: interrupts are off, and the reference does not outlive this.
+        let queue = unsafe { QUEUES.current_mut() };
+        // A trap in the middle of running leaves its work to the outer one.
+        if queue.running || queue.len == 0 {
+            return;
+        }
+        queue.running = true;
+    }
+    // SAFETY: interrupts are off, and the reference does not outlive this.
+    while let Some(work) = unsafe { QUEUES.current_mut() }.pop() {
+        // Queued again from now on, it runs again.
+        work.queued.store(false, Ordering::Release);
+        unsafe { intr_on() };
+        (work.f)(kernel);
+        unsafe { intr_off() };
+    }
+    // SAFETY: interrupts are off, and the reference does not outlive this.
+    unsafe { QUEUES.current_mut() }.running = false;
+}
@@ -0,0 +1,6 @@
+#define CAP_SETUID       7  // Change the user id with setuid()
+#define CAP_SYS_CHROOT  18  // Change the root directory with chroot()
+#define CAP_SYS_PTRACE  19  // Trace processes other than children with ptrace()
+#define CAP_SYS_ADMIN   21  // Mount and unmount file systems
+#define CAP_SYS_BOOT  22  // Shut down or restart the machine
+#define CAP_MKNOD     27  // Create device files with mknod()
@@ -0,0 +1,4 @@
+// Sites for faultinject().
+#define FAULT_KALLOC 1 // Page allocations
+#define FAULT_ARENA  2 // Allocations of open files
+#define FAULT_DISK   4 // Disk reads of file data
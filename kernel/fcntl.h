@@ -3,3 +3,45 @@
 #define O_RDWR    0x002
 #define O_CREATE  0x200
 #define O_TRUNC   0x400
+#define O_NONBLOCK 0x800
+#define O_SYNC    0x101000
+#define O_CLOEXEC 0x80000
+
+// The directory file descriptor of *at() calls that means the current
+// directory.
+#define AT_FDCWD -100
+
+// unlinkat() flags.
+#define AT_REMOVEDIR 0x200
+
+// fcntl() commands.
+#define F_GETFD 1
+#define F_SETFD 2
+#define F_SETPIPE_SZ 1031
+#define F_GETPIPE_SZ 1032
+
+// Descriptor flags of F_GETFD and F_SETFD.
+#define FD_CLOEXEC 1
+
+// access() modes.
+#define F_OK 0
+#define X_OK 1
+#define W_OK 2
+#define R_OK 4
+
+// lseek() whences.
+#define SEEK_SET  0
+#define SEEK_CUR  1
+#define SEEK_END  2
+#define SEEK_DATA 3
+#define SEEK_HOLE 4
+
+// fallocate() modes.
+#define FALLOC_FL_KEEP_SIZE  1
+#define FALLOC_FL_PUNCH_HOLE 2
+
+// flock() operations.
+#define LOCK_SH 1
+#define LOCK_EX 2
+#define LOCK_NB 4
+#define LOCK_UN 8
@@ -3,14 +3,17 @@
 
 
 #define ROOTINO  1   // root i-number
-#define BSIZE 1024  // block size
+#define QUOTAINO 2   // i-number of the quota file, which no directory links
+#define BSIZE 4096  // block size, at most a page
 
 // Disk layout:
 // [ boot block | super block | log | inode blocks |
 //                                          free bit map | data blocks]
 //
 // mkfs computes the super block and builds an initial file system. The
-// super block describes the disk layout:
+// super block describes the disk layout. The rest of its block is the
+// orphan list: the inumbers of inodes that have no links but may still be
+// open, or 0. mkfs leaves it empty.
 struct superblock {
   uint magic;        // Must be FSMAGIC
   uint size;         // Size of file system image (blocks)
@@ -20,11 +23,12 @@ struct superblock {
   uint logstart;     // Block number of first log block
   uint inodestart;   // Block number of first inode block
   uint bmapstart;    // Block number of first free map block
+  uint bsize;        // Block size (bytes)
 };
 
-#define FSMAGIC 0x10203040
+#define FSMAGIC 0x10203041
 
-#define NDIRECT 12
+#define NDIRECT 11
 #define NINDIRECT (BSIZE / sizeof(uint))
 #define MAXFILE (NDIRECT + NINDIRECT)
 
@@ -35,9 +39,16 @@ struct dinode {
   ushort minor;         // Minor device number (T_DEVICE only)
   short nlink;          // Number of links to inode in file system
   uint size;            // Size of file (bytes)
+  uint uid;             // User id of the owner
   uint addrs[NDIRECT+1];   // Data block addresses
 };
 
+// Entry of the quota file, indexed by user id.
+struct dquot {
+  uint bhardlimit;      // Most blocks that files of the user id may take, or 0
+  uint ihardlimit;      // Most inodes that files of the user id may take, or 0
+};
+
 // Inodes per block.
 #define IPB           (BSIZE / sizeof(struct dinode))
 
@@ -0,0 +1,21 @@
+#!/usr/bin/perl -w
+
+# Generate ksymtab.S, the kernel's symbol table, from the output of nm -n.
+# Only function symbols are kept; backtrace.rs reads the table.
+
+print "# generated by ksyms.pl - do not edit\n";
+
+my @names;
+while (<STDIN>) {
+    next unless /^([0-9a-f]+) [tT] (.+)$/;
+    my ($addr, $name) = ($1, $2);
+    $name =~ s/(["\\])/\\$1/g;
+    print " .section .ksymtab, \"a\"\n" if !@names;
+    print " .quad 0x$addr, .Lksym" . scalar(@names) . "\n";
+    push @names, $name;
+}
+
+print " .section .ksymstr, \"a\"\n";
+for my $i (0 .. $#names) {
+    print ".Lksym$i: .string \"$names[$i]\"\n";
+}
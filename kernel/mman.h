@@ -0,0 +1,7 @@
+#define PROT_NONE   0x0  // Pages may not be accessed
+#define PROT_READ   0x1  // Pages may be read
+#define PROT_WRITE  0x2  // Pages may be written
+#define PROT_EXEC   0x4  // Pages may be executed, but not together with PROT_WRITE
+
+#define MADV_WILLNEED 3  // Pages will be written soon
+#define MADV_DONTNEED 4  // Pages will not be needed, and may read as zeros
@@ -0,0 +1,3 @@
+#define MS_REMOUNT  1  // Change the options of the root file system at /
+#define MS_DISCARD  2  // Discard freed blocks
+#define MS_SYNC     4  // Commit each write() before it returns
@@ -3,11 +3,15 @@
 #define NOFILE       16  // open files per process
 #define NFILE       100  // open files per system
 #define NINODE       50  // maximum number of active i-nodes
+#define NQUOTA       32  // user ids below this may have disk quotas
+#define NCONSOLE      4  // number of virtual consoles
 #define NDEV         10  // maximum major device number
 #define ROOTDEV       1  // device number of file system root disk
-#define MAXARG       32  // max exec arguments
+#define FATDEV        2  // device number of the second disk
+#define MDDEV         3  // device number of the md device
+#define MAXARG       32  // max exec arguments, and environment strings
 #define MAXOPBLOCKS  10  // max # of blocks any FS op writes
-#define LOGSIZE      (MAXOPBLOCKS*3)  // max data blocks in on-disk log
-#define NBUF         (MAXOPBLOCKS*3)  // size of disk block cache
+#define LOGSIZE      (MAXOPBLOCKS*5)  // data blocks in on-disk log made by mkfs
+#define NBUF         (MAXOPBLOCKS*11) // size of disk block cache
 #define FSSIZE       2000  // size of file system in blocks
 #define MAXPATH      128   // maximum file path name
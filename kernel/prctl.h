@@ -0,0 +1,4 @@
+#define PR_GET_DUMPABLE  3  // Whether the process dumps core on a fault
+#define PR_SET_DUMPABLE  4  // Set it to 0 or 1
+#define PR_CAPBSET_READ  23  // Whether the process has a capability
+#define PR_CAPBSET_DROP  24  // Drop a capability for good
@@ -0,0 +1,7 @@
+// A sample of the profiler, as read from /proc/profile.
+struct profsample {
+  uint64 pc;    // The interrupted pc
+  int pid;      // The current process, or -1
+  uchar kernel; // 1 if pc is in kernel code
+  uchar pad[3];
+};
@@ -0,0 +1,25 @@
+#define PTRACE_TRACEME    0  // Be traced by the parent
+#define PTRACE_PEEKDATA   2  // Copy the word at addr in the tracee to *data
+#define PTRACE_POKEDATA   5  // Write the word data at addr in the tracee
+#define PTRACE_CONT       7  // Resume the tracee
+#define PTRACE_GETREGS   12  // Copy the registers of the tracee to *data
+#define PTRACE_SETREGS   13  // Set the registers of the tracee from *data
+#define PTRACE_ATTACH    16  // Trace the process pid
+#define PTRACE_DETACH    17  // Stop tracing the tracee, and resume it
+#define PTRACE_SYSCALL   24  // Resume the tracee until it enters or exits a system call
+
+// wait() status of a stopped tracee.
+#define WIFSTOPPED(status)  (((status) & 0xff) == 0x7f)
+#define WSTOPSIG(status)    (((status) >> 8) & 0xff)
+
+// Why a tracee stopped.
+#define SIGTRAP        5              // A breakpoint, or exec
+#define SIGSTOP       19              // PTRACE_ATTACH
+#define SYSCALL_STOP  (SIGTRAP|0x80)  // A system call entry or exit
+// Faults stop with the signals that gdb shows for them.
+
+// Registers for PTRACE_GETREGS and PTRACE_SETREGS.
+struct user_regs {
+  uint64 pc;
+  uint64 x[31];  // x1 to x31
+};
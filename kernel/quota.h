@@ -0,0 +1,11 @@
+// Commands and structure of quotactl().
+
+#define Q_GETQUOTA  1  // Get the limits and usage of a user id
+#define Q_SETQUOTA  2  // Set the limits of a user id
+
+struct dqblk {
+  uint bhardlimit; // Most blocks that its files may take, or 0
+  uint ihardlimit; // Most inodes that its files may take, or 0
+  uint curblocks;  // Blocks that its files take
+  uint curinodes;  // Inodes that its files take
+};
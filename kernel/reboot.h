@@ -0,0 +1,2 @@
+#define RB_POWEROFF  0  // Power off, with the status as the exit code
+#define RB_RESTART   1  // Restart
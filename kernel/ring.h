@@ -0,0 +1,49 @@
+// Submission and completion rings of ring_setup(), a page each.
+// A ring is a header followed by RING_ENTRIES entries. The head and the
+// tail count the entries ever consumed and produced: user code advances the
+// tail of the submission ring and the head of the completion ring, and
+// ring_enter() the others.
+
+#define RING_ENTRIES 64
+
+#define RING_READ  1  // read len bytes from fd into addr, like read()
+#define RING_WRITE 2  // write len bytes at addr to fd, like write()
+#define RING_FSYNC 3  // sync the file systems, like sync(); fd must be open
+
+struct ring_header {
+  uint head;
+  uint tail;
+  uint pad[2];
+};
+
+struct ring_sqe {
+  uint op;
+  int fd;
+  uint64 addr;
+  uint len;
+  uint pad;
+  uint64 data;  // copied into the completion
+};
+
+struct ring_cqe {
+  uint64 data;
+  int res;      // what the system call the operation is like returns
+  uint pad;
+};
+
+struct ring_sq {
+  struct ring_header h;
+  struct ring_sqe sqes[RING_ENTRIES];
+};
+
+struct ring_cq {
+  struct ring_header h;
+  struct ring_cqe cqes[RING_ENTRIES];
+};
+
+// The rings of a process, set up by ring_init() of user/ring.c.
+struct ring {
+  volatile struct ring_sq *sq;
+  volatile struct ring_cq *cq;
+  uint pending;  // submissions queued but not yet entered
+};
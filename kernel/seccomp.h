@@ -0,0 +1,13 @@
+#define SECCOMP_SET_FILTER  1  // Allow only the system calls in a struct seccomp_filter
+#define SECCOMP_LOCK        2  // Forbid further changes to the filter
+
+#define SECCOMP_RET_ERRNO   0  // Denied system calls fail with -1
+#define SECCOMP_RET_KILL    1  // Denied system calls kill the process
+
+#define SECCOMP_NWORDS      2
+
+// exit is always allowed.
+struct seccomp_filter {
+  uint64 allow[SECCOMP_NWORDS];  // Bit n%64 of allow[n/64] allows system call n
+  int action;                    // SECCOMP_RET_ERRNO or SECCOMP_RET_KILL
+};
@@ -1,6 +1,9 @@
 #define T_DIR     1   // Directory
 #define T_FILE    2   // File
 #define T_DEVICE  3   // Device
+#define T_FIFO    4   // Named pipe
+
+#define FIFO      (-1) // mknod() major number of a FIFO
 
 struct stat {
   int dev;     // File system's disk device
@@ -9,3 +12,11 @@ struct stat {
   short nlink; // Number of links to file
   uint64 size; // Size of file in bytes
 };
+
+struct statfs {
+  uint bsize;  // Block size in bytes
+  uint blocks; // Size of file system image (blocks)
+  uint bfree;  // Number of free blocks
+  uint files;  // Number of inodes
+  uint ffree;  // Number of free inodes
+};
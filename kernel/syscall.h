@@ -21,3 +21,54 @@
 #define SYS_mkdir  20
 #define SYS_close  21
 #define SYS_poweroff    22
+#define SYS_ioctl  23
+#define SYS_gettimeofday 24
+#define SYS_clock_gettime 25
+#define SYS_reboot 26
+#define SYS_execve 27
+#define SYS_prctl 28
+#define SYS_mprotect 29
+#define SYS_seccomp 30
+#define SYS_chroot 31
+#define SYS_dmesg 32
+#define SYS_trace 33
+#define SYS_ptrace 34
+#define SYS_faultinject 35
+#define SYS_fcntl 36
+#define SYS_sendfile 37
+#define SYS_pipe2 38
+#define SYS_access 39
+#define SYS_statfs 40
+#define SYS_fstatfs 41
+#define SYS_lseek 42
+#define SYS_fallocate 43
+#define SYS_flock 44
+#define SYS_mount 45
+#define SYS_umount 46
+#define SYS_sync   47
+#define SYS_getcwd 48
+#define SYS_openat 49
+#define SYS_mkdirat 50
+#define SYS_unlinkat 51
+#define SYS_madvise 52
+#define SYS_ring_setup 53
+#define SYS_ring_enter 54
+#define SYS_eventfd 55
+#define SYS_timerfd 56
+#define SYS_sem_open 57
+#define SYS_sem_wait 58
+#define SYS_sem_post 59
+#define SYS_mq_open 60
+#define SYS_mq_send 61
+#define SYS_mq_receive 62
+#define SYS_getppid 63
+#define SYS_gettid 64
+#define SYS_uname 65
+#define SYS_sysinfo 66
+#define SYS_rescan 67
+#define SYS_cpu_offline 68
+#define SYS_cpu_online 69
+#define SYS_suspend 70
+#define SYS_getuid 71
+#define SYS_setuid 72
+#define SYS_quotactl 73
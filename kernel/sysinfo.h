@@ -0,0 +1,17 @@
+// Structures of uname() and sysinfo().
+
+#define UTSLEN 32  // Length of each field of struct utsname, with the NUL
+
+struct utsname {
+  char sysname[UTSLEN]; // Name of the kernel
+  char release[UTSLEN]; // Version of the kernel
+  char version[UTSLEN]; // Git commit that the kernel was built from
+  char machine[UTSLEN]; // Hardware
+};
+
+struct sysinfo {
+  uint uptime;     // Clock ticks since boot
+  uint totalpages; // Number of pages of memory
+  uint freepages;  // Number of free pages
+  uint procs;      // Number of processes
+};
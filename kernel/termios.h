@@ -0,0 +1,20 @@
+#define TCGETS  0x5401
+#define TCSETS  0x5402
+
+#define ICRNL   0x1   // Translate carriage return to newline on input
+
+#define ISIG    0x1   // Enable control-p (print process list)
+#define ICANON  0x2   // Canonical input (line editing)
+#define ECHO    0x8   // Echo input characters
+
+#define VEOF    0     // End-of-file character
+#define VERASE  1     // Erase character
+#define VKILL   2     // Kill-line character
+#define VMIN    3     // Minimum number of bytes for non-canonical read
+#define NCCS    4
+
+struct termios {
+  uint iflag;         // Input modes
+  uint lflag;         // Local modes
+  uchar cc[NCCS];     // Special characters
+};
@@ -0,0 +1,12 @@
+#define CLOCK_REALTIME   0  // Wall-clock time
+#define CLOCK_MONOTONIC  1  // Time since boot
+
+struct timespec {
+  long sec;   // Seconds
+  long nsec;  // Nanoseconds
+};
+
+struct timeval {
+  long sec;   // Seconds
+  long usec;  // Microseconds
+};
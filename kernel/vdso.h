@@ -0,0 +1,23 @@
+// Read-only pages that the kernel maps into every process,
+// so that user code can read them without a system call.
+// See kernel-rs/src/vdso.rs.
+//
+// Top of user memory:
+//   VDSO_PROC  (MAXVA - 4*PGSIZE), constants of this process
+//   VDSO_TIME  (MAXVA - 3*PGSIZE), shared by every process
+//   TRAPFRAME  (MAXVA - 2*PGSIZE)
+//   TRAMPOLINE (MAXVA - PGSIZE)
+#define VDSO_TIME 0x3fffffd000L
+#define VDSO_PROC 0x3fffffc000L
+
+// Updated at every timer interrupt.
+struct vdso_time {
+  uint seq;          // Odd while the kernel updates the page
+  uint ticks;        // Timer interrupts since boot, as uptime() returns
+  uint64 monotonic;  // Nanoseconds since boot
+  uint64 realtime;   // Nanoseconds since the epoch
+};
+
+struct vdso_proc {
+  int pid;
+};
@@ -0,0 +1,67 @@
+#include "kernel/types.h"
+#include "kernel/date.h"
+#include "kernel/time.h"
+#include "user/user.h"
+
+static int
+isleap(uint y)
+{
+  return (y % 4 == 0 && y % 100 != 0) || y % 400 == 0;
+}
+
+// Convert seconds since the epoch to a UTC date.
+static void
+todate(long t, struct rtcdate *d)
+{
+  static const uint mdays[] = { 31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31 };
+  long days = t / 86400;
+  long secs = t % 86400;
+
+  d->hour = secs / 3600;
+  d->minute = secs % 3600 / 60;
+  d->second = secs % 60;
+
+  d->year = 1970;
+  while(days >= 365 + isleap(d->year)){
+    days -= 365 + isleap(d->year);
+    d->year++;
+  }
+  d->month = 0;
+  while(days >= mdays[d->month] + (d->month == 1 && isleap(d->year))){
+    days -= mdays[d->month] + (d->month == 1 && isleap(d->year));
+    d->month++;
+  }
+  d->month++;
+  d->day = days + 1;
+}
+
+static void
+print2(uint n)
+{
+  printf("%d%d", n / 10, n % 10);
+}
+
+int
+main(int argc, char *argv[])
+{
+  struct timeval tv;
+  struct rtcdate d;
+
+  if(gettimeofday(&tv) < 0){
+    fprintf(2, "date: gettimeofday failed\n");
+    exit(1);
+  }
+  todate(tv.sec, &d);
+  printf("%d-", d.year);
+  print2(d.month);
+  printf("-");
+  print2(d.day);
+  printf(" ");
+  print2(d.hour);
+  printf(":");
+  print2(d.minute);
+  printf(":");
+  print2(d.second);
+  printf(" UTC\n");
+  exit(0);
+}
@@ -0,0 +1,21 @@
+#include "kernel/types.h"
+#include "kernel/stat.h"
+#include "user/user.h"
+
+int
+main(int argc, char *argv[])
+{
+  struct statfs sf;
+  char *path;
+
+  path = argc > 1 ? argv[1] : "/";
+  if(statfs(path, &sf) < 0){
+    fprintf(2, "df: cannot statfs %s\n", path);
+    exit(1);
+  }
+  printf("blocks of %d bytes: %d, used %d, free %d\n",
+         sf.bsize, sf.blocks, sf.blocks - sf.bfree, sf.bfree);
+  printf("inodes: %d, used %d, free %d\n",
+         sf.files, sf.files - sf.ffree, sf.ffree);
+  exit(0);
+}
@@ -0,0 +1,21 @@
+#include "kernel/types.h"
+#include "user/user.h"
+
+// As large as the kernel log.
+char buf[4096];
+
+int
+main(int argc, char *argv[])
+{
+  int n;
+
+  if((n = dmesg(buf, sizeof(buf))) < 0){
+    fprintf(2, "dmesg: cannot read kernel log\n");
+    exit(1);
+  }
+  if(write(1, buf, n) != n){
+    fprintf(2, "dmesg: write error\n");
+    exit(1);
+  }
+  exit(0);
+}
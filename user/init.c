@@ -1,6 +1,7 @@
 // init: The initial user-level program
 
 #include "kernel/types.h"
+#include "kernel/param.h"
 #include "kernel/stat.h"
 #include "kernel/spinlock.h"
 #include "kernel/sleeplock.h"
@@ -8,6 +9,7 @@
 #include "kernel/file.h"
 #include "user/user.h"
 #include "kernel/fcntl.h"
+#include "kernel/reboot.h"
 
 #ifdef USERTEST
 char *argv[] = { "usertests", 0 };
@@ -15,19 +17,27 @@ char *argv[] = { "usertests", 0 };
 char *argv[] = { "sh", 0 };
 #endif
 
-int
-main(void)
-{
-  // https://github.com/kaist-cp/rv6/commit/d12c1db8d9d7a7e5632e51ae712123d868087fe4
-  // Add xstate to immediately run usertests and poweroff.
-  int pid, wpid, xstate;
+// The environment of every program.
+char *envp[] = { "PATH=/", 0 };
 
-  if(open("console", O_RDWR) < 0){
-    mknod("console", CONSOLE, 0);
-    open("console", O_RDWR);
+// Open the console device with the given minor number as fds 0, 1 and 2.
+void
+openconsole(char *name, int minor)
+{
+  if(open(name, O_RDWR) < 0){
+    mknod(name, CONSOLE, minor);
+    open(name, O_RDWR);
   }
   dup(0);  // stdout
   dup(0);  // stderr
+}
+
+void
+respawn(void)
+{
+  // https://github.com/kaist-cp/rv6/commit/d12c1db8d9d7a7e5632e51ae712123d868087fe4
+  // Add xstate to immediately run usertests and poweroff.
+  int pid, wpid, xstate;
 
   for(;;){
     printf("init: starting %s\n", argv[0]);
@@ -37,7 +47,7 @@ main(void)
       exit(1);
     }
     if(pid == 0){
-      exec(argv[0], argv);
+      execve(argv[0], argv, envp);
       printf("init: exec %s failed\n", argv[0]);
       exit(1);
     }
@@ -57,7 +67,47 @@ main(void)
       }
     }
 #ifdef USERTEST
-    poweroff(xstate);
+    reboot(RB_POWEROFF, xstate);
 #endif
   }
 }
+
+// Make the device files in /proc, unless they exist.
+void
+makeproc(void)
+{
+  mkdir("/proc");
+  mknod("/proc/profile", PROFILE, 0);
+  mknod("/proc/stat", KSTAT, 0);
+  mknod("/proc/diskstats", KSTAT, 1);
+  mknod("/proc/self", KSTAT, 2);
+  mknod("/proc/cmdline", KSTAT, 3);
+  mknod("/proc/kmsg", KSTAT, 4);
+}
+
+int
+main(void)
+{
+  openconsole("console", 0);
+  makeproc();
+
+#ifndef USERTEST
+  // Run a shell on each of the other virtual consoles,
+  // reachable with control-a 2, control-a 3, ...
+  int i;
+  char name[] = "tty0";
+
+  for(i = 1; i < NCONSOLE; i++){
+    if(fork() == 0){
+      close(0);
+      close(1);
+      close(2);
+      name[3] = '0' + i;
+      openconsole(name, i);
+      respawn();
+    }
+  }
+#endif
+
+  respawn();
+}
@@ -0,0 +1,46 @@
+#include "kernel/types.h"
+#include "kernel/param.h"
+#include "kernel/mount.h"
+#include "user/user.h"
+
+// mount [-o opts] [dev] dir: dev is a device number, FATDEV by default.
+// opts is a comma-separated list of remount, discard, and sync.
+int
+main(int argc, char *argv[])
+{
+  int dev = FATDEV, flags = 0;
+  char *opt, *next;
+
+  if(argc > 2 && strcmp(argv[1], "-o") == 0){
+    for(opt = argv[2]; opt; opt = next){
+      next = strchr(opt, ',');
+      if(next)
+        *next++ = 0;
+      if(strcmp(opt, "remount") == 0)
+        flags |= MS_REMOUNT;
+      else if(strcmp(opt, "discard") == 0)
+        flags |= MS_DISCARD;
+      else if(strcmp(opt, "sync") == 0)
+        flags |= MS_SYNC;
+      else {
+        fprintf(2, "mount: unknown option %s\n", opt);
+        exit(1);
+      }
+    }
+    argc -= 2;
+    argv += 2;
+  }
+  if(argc != 2 && argc != 3){
+    fprintf(2, "Usage: mount [-o opts] [dev] dir\n");
+    exit(1);
+  }
+  if(argc == 3)
+    dev = atoi(argv[1]);
+  else if(flags & MS_REMOUNT)
+    dev = ROOTDEV;
+  if(mount(dev, argv[argc-1], flags) < 0){
+    fprintf(2, "mount: cannot mount device %d on %s\n", dev, argv[argc-1]);
+    exit(1);
+  }
+  exit(0);
+}
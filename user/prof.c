@@ -0,0 +1,112 @@
+#include "kernel/types.h"
+#include "kernel/fcntl.h"
+#include "kernel/profile.h"
+#include "user/user.h"
+
+// Run a command with the profiler on, and print a flat profile: the pcs
+// sampled most often, in user or kernel code, by process.
+// prof command [args...]
+// Kernel pcs can be looked up in kernel/kernel.sym, and user pcs in
+// user/_command.sym, on the host.
+
+#define NSAMPLE 4096  // Samples read at most
+#define NENTRY  512   // Distinct pcs counted at most
+#define NTOP    20    // Lines printed
+
+struct entry {
+  uint64 pc;
+  int pid;
+  int kernel;
+  int count;
+};
+
+struct entry entries[NENTRY];
+int nentry;
+
+void
+count(struct profsample *s)
+{
+  int i;
+
+  for(i = 0; i < nentry; i++){
+    if(entries[i].pc == s->pc && entries[i].pid == s->pid && entries[i].kernel == s->kernel){
+      entries[i].count++;
+      return;
+    }
+  }
+  if(nentry < NENTRY){
+    entries[nentry].pc = s->pc;
+    entries[nentry].pid = s->pid;
+    entries[nentry].kernel = s->kernel;
+    entries[nentry].count = 1;
+    nentry++;
+  }
+}
+
+int
+main(int argc, char *argv[])
+{
+  struct profsample *samples;
+  struct entry e;
+  int fd, pid, n, total, i, j;
+
+  if(argc < 2){
+    fprintf(2, "usage: prof command [args...]\n");
+    exit(1);
+  }
+  if((fd = open("/proc/profile", O_RDWR)) < 0){
+    fprintf(2, "prof: cannot open /proc/profile\n");
+    exit(1);
+  }
+  samples = malloc(NSAMPLE * sizeof(*samples));
+
+  if(write(fd, "1", 1) != 1){
+    fprintf(2, "prof: cannot start profiling\n");
+    exit(1);
+  }
+  pid = fork();
+  if(pid < 0){
+    fprintf(2, "prof: fork failed\n");
+    exit(1);
+  }
+  if(pid == 0){
+    close(fd);
+    exec(argv[1], argv + 1);
+    fprintf(2, "prof: exec %s failed\n", argv[1]);
+    exit(1);
+  }
+  wait(0);
+  write(fd, "0", 1);
+
+  total = 0;
+  while((n = read(fd, samples, NSAMPLE * sizeof(*samples))) > 0){
+    n /= sizeof(*samples);
+    for(i = 0; i < n; i++)
+      count(&samples[i]);
+    total += n;
+  }
+  close(fd);
+  if(total == 0){
+    printf("prof: no samples\n");
+    exit(0);
+  }
+
+  // Move the largest counts to the front.
+  for(i = 0; i < nentry && i < NTOP; i++){
+    for(j = i + 1; j < nentry; j++){
+      if(entries[j].count > entries[i].count){
+        e = entries[i];
+        entries[i] = entries[j];
+        entries[j] = e;
+      }
+    }
+  }
+
+  printf("%d samples\n", total);
+  printf("count\t%%\tpid\tpc\n");
+  for(i = 0; i < nentry && i < NTOP; i++){
+    printf("%d\t%d\t%d\t%s %p\n", entries[i].count, entries[i].count * 100 / total,
+           entries[i].pid, entries[i].kernel ? "kernel" : "user", entries[i].pc);
+  }
+  exit(0);
+}
@@ -0,0 +1,16 @@
+#include "kernel/types.h"
+#include "kernel/param.h"
+#include "user/user.h"
+
+int
+main(int argc, char *argv[])
+{
+  char path[MAXPATH];
+
+  if(getcwd(path, sizeof(path)) < 0){
+    fprintf(2, "pwd: cannot get the current directory\n");
+    exit(1);
+  }
+  printf("%s\n", path);
+  exit(0);
+}
@@ -0,0 +1,78 @@
+#include "kernel/types.h"
+#include "kernel/stat.h"
+#include "kernel/ring.h"
+#include "kernel/riscv.h"
+#include "user/user.h"
+
+// Library shim for the rings of ring_setup(): queue operations with
+// ring_prep(), do them all with one ring_submit(), and collect their
+// completions with ring_reap().
+
+// Set up a pair of rings in new pages.
+// Returns 0 on success, -1 on error.
+int
+ring_init(struct ring *r)
+{
+  char *p;
+
+  p = sbrk(3*PGSIZE);
+  if(p == (char*)-1)
+    return -1;
+  p = (char*)PGROUNDUP((uint64)p);
+  memset(p, 0, 2*PGSIZE);
+  if(ring_setup(p, p + PGSIZE) < 0)
+    return -1;
+  r->sq = (struct ring_sq*)p;
+  r->cq = (struct ring_cq*)(p + PGSIZE);
+  r->pending = 0;
+  return 0;
+}
+
+// Queue an operation, whose completion carries data.
+// Returns 0 on success, -1 if the submission ring is full.
+int
+ring_prep(struct ring *r, int op, int fd, void *addr, int len, uint64 data)
+{
+  volatile struct ring_sqe *sqe;
+  uint tail = r->sq->h.tail;
+
+  if(tail - r->sq->h.head >= RING_ENTRIES)
+    return -1;
+  sqe = &r->sq->sqes[tail % RING_ENTRIES];
+  sqe->op = op;
+  sqe->fd = fd;
+  sqe->addr = (uint64)addr;
+  sqe->len = len;
+  sqe->data = data;
+  r->sq->h.tail = tail + 1;
+  r->pending++;
+  return 0;
+}
+
+// Do the queued operations, in one system call.
+// Returns the number done, which is less than queued if the completion
+// ring filled up, or -1 on error.
+int
+ring_submit(struct ring *r)
+{
+  int n;
+
+  n = ring_enter(r->pending);
+  if(n > 0)
+    r->pending -= n;
+  return n;
+}
+
+// Take the oldest completion into *cqe.
+// Returns 1 if there was one, 0 otherwise.
+int
+ring_reap(struct ring *r, struct ring_cqe *cqe)
+{
+  uint head = r->cq->h.head;
+
+  if(head == r->cq->h.tail)
+    return 0;
+  *cqe = *(struct ring_cqe*)&r->cq->cqes[head % RING_ENTRIES];
+  r->cq->h.head = head + 1;
+  return 1;
+}
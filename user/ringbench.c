@@ -0,0 +1,92 @@
+#include "kernel/types.h"
+#include "kernel/stat.h"
+#include "kernel/fcntl.h"
+#include "kernel/ring.h"
+#include "user/user.h"
+
+// Compare small writes and reads of a file, one system call each, with the
+// same batched through the rings of ring_setup().
+
+#define N    4096  // operations of each kind
+#define SIZE 16    // bytes each
+
+char buf[SIZE];
+
+int
+openfile(int flags)
+{
+  int fd;
+
+  fd = open("ringbench.tmp", flags);
+  if(fd < 0){
+    fprintf(2, "ringbench: cannot open ringbench.tmp\n");
+    exit(1);
+  }
+  return fd;
+}
+
+// Do N operations of op with a system call each.
+// Returns the ticks taken.
+int
+bysyscall(int op)
+{
+  int fd, i, start;
+
+  fd = openfile(op == RING_WRITE ? O_CREATE|O_WRONLY|O_TRUNC : O_RDONLY);
+  start = uptime();
+  for(i = 0; i < N; i++){
+    if((op == RING_WRITE ? write(fd, buf, SIZE) : read(fd, buf, SIZE)) != SIZE){
+      fprintf(2, "ringbench: system call failed\n");
+      exit(1);
+    }
+  }
+  close(fd);
+  return uptime() - start;
+}
+
+// Do N operations of op through the rings, a ring at a time.
+// Returns the ticks taken.
+int
+byring(struct ring *r, int op)
+{
+  struct ring_cqe cqe;
+  int fd, i, start, done;
+
+  fd = openfile(op == RING_WRITE ? O_CREATE|O_WRONLY|O_TRUNC : O_RDONLY);
+  start = uptime();
+  for(i = 0; i < N; i += done){
+    done = 0;
+    while(i + done < N && ring_prep(r, op, fd, buf, SIZE, i + done) == 0)
+      done++;
+    if(ring_submit(r) != done){
+      fprintf(2, "ringbench: ring_submit failed\n");
+      exit(1);
+    }
+    while(ring_reap(r, &cqe)){
+      if(cqe.res != SIZE){
+        fprintf(2, "ringbench: operation %d failed\n", (int)cqe.data);
+        exit(1);
+      }
+    }
+  }
+  close(fd);
+  return uptime() - start;
+}
+
+int
+main(int argc, char *argv[])
+{
+  struct ring r;
+
+  if(ring_init(&r) < 0){
+    fprintf(2, "ringbench: ring_init failed\n");
+    exit(1);
+  }
+  memset(buf, 'r', SIZE);
+  printf("%d writes of %d bytes: %d ticks by system call, ", N, SIZE, bysyscall(RING_WRITE));
+  printf("%d by ring\n", byring(&r, RING_WRITE));
+  printf("%d reads of %d bytes: %d ticks by system call, ", N, SIZE, bysyscall(RING_READ));
+  printf("%d by ring\n", byring(&r, RING_READ));
+  unlink("ringbench.tmp");
+  exit(0);
+}
@@ -75,7 +75,7 @@ runcmd(struct cmd *cmd)
     ecmd = (struct execcmd*)cmd;
     if(ecmd->argv[0] == 0)
       exit(1);
-    exec(ecmd->argv[0], ecmd->argv);
+    execvp(ecmd->argv[0], ecmd->argv);
     fprintf(2, "exec %s failed\n", ecmd->argv[0]);
     break;
 
@@ -130,10 +130,14 @@ runcmd(struct cmd *cmd)
   exit(0);
 }
 
+// Whether to print a prompt, i.e. the commands are not from a script.
+int prompt = 1;
+
 int
 getcmd(char *buf, int nbuf)
 {
-  fprintf(2, "$ ");
+  if(prompt)
+    fprintf(2, "$ ");
   memset(buf, 0, nbuf);
   gets(buf, nbuf);
   if(buf[0] == 0) // EOF
@@ -142,7 +146,7 @@ getcmd(char *buf, int nbuf)
 }
 
 int
-main(void)
+main(int argc, char *argv[])
 {
   static char buf[100];
   int fd;
@@ -155,8 +159,20 @@ main(void)
     }
   }
 
+  // Read commands from a script, e.g. one that starts with "#!/sh".
+  if(argc > 1){
+    close(0);
+    if(open(argv[1], O_RDONLY) < 0){
+      fprintf(2, "sh: cannot open %s\n", argv[1]);
+      exit(1);
+    }
+    prompt = 0;
+  }
+
   // Read and run input commands.
   while(getcmd(buf, sizeof(buf)) >= 0){
+    if(buf[0] == '#')
+      continue;  // comment
     if(buf[0] == 'c' && buf[1] == 'd' && buf[2] == ' '){
       // Chdir must be called by the parent, not the child.
       buf[strlen(buf)-1] = 0;  // chop \n
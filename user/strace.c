@@ -0,0 +1,34 @@
+#include "kernel/types.h"
+#include "user/user.h"
+
+// Run a command, logging the system calls it makes to the kernel log.
+// strace [-m mask] [-M mask] command [args...]
+// where bit n of the -m mask selects system call n, and bit n of the -M mask
+// system call 64 + n. By default, all are logged; with only -m, none from 64.
+int
+main(int argc, char *argv[])
+{
+  uint64 mask = ~0ULL, mask_high = ~0ULL;
+  int i = 1;
+
+  if(argc > i + 1 && strcmp(argv[i], "-m") == 0){
+    mask = atoi(argv[i + 1]);
+    mask_high = 0;
+    i += 2;
+  }
+  if(argc > i + 1 && strcmp(argv[i], "-M") == 0){
+    mask_high = atoi(argv[i + 1]);
+    i += 2;
+  }
+  if(i >= argc){
+    fprintf(2, "usage: strace [-m mask] [-M mask] command [args...]\n");
+    exit(1);
+  }
+  if(trace(mask, mask_high) < 0){
+    fprintf(2, "strace: trace failed\n");
+    exit(1);
+  }
+  exec(argv[i], argv + i);
+  fprintf(2, "strace: exec %s failed\n", argv[i]);
+  exit(1);
+}
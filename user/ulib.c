@@ -1,8 +1,24 @@
 #include "kernel/types.h"
 #include "kernel/stat.h"
 #include "kernel/fcntl.h"
+#include "kernel/param.h"
+#include "kernel/time.h"
+#include "kernel/vdso.h"
 #include "user/user.h"
 
+// The environment, as "name=value" strings.
+char **environ;
+
+// Entry point of every program.
+void
+_start(int argc, char **argv, char **envp)
+{
+  extern int main(int, char**, char**);
+
+  environ = envp;
+  exit(main(argc, argv, envp));
+}
+
 char*
 strcpy(char *s, const char *t)
 {
@@ -134,3 +150,97 @@ memcpy(void *dst, const void *src, uint n)
 {
   return memmove(dst, src, n);
 }
+
+// getpid() without a system call.
+int
+vgetpid(void)
+{
+  return ((volatile struct vdso_proc*)VDSO_PROC)->pid;
+}
+
+// Read the time page, retrying while the kernel updates it.
+static void
+vdso_read(struct vdso_time *t)
+{
+  volatile struct vdso_time *v = (volatile struct vdso_time*)VDSO_TIME;
+  uint seq;
+
+  do {
+    while((seq = v->seq) & 1)
+      ;
+    __sync_synchronize();
+    t->ticks = v->ticks;
+    t->monotonic = v->monotonic;
+    t->realtime = v->realtime;
+    __sync_synchronize();
+  } while(v->seq != seq);
+}
+
+// uptime() without a system call.
+int
+vuptime(void)
+{
+  struct vdso_time t;
+
+  vdso_read(&t);
+  return t.ticks;
+}
+
+// gettimeofday() without a system call, as of the last timer interrupt.
+int
+vgettimeofday(struct timeval *tv)
+{
+  struct vdso_time t;
+
+  vdso_read(&t);
+  tv->sec = t.realtime / 1000000000;
+  tv->usec = t.realtime % 1000000000 / 1000;
+  return 0;
+}
+
+// exec() with the environment of this program.
+int
+exec(char *path, char **argv)
+{
+  return execve(path, argv, environ);
+}
+
+// exec() that looks for a file name without a slash in the directories
+// of PATH, separated by colons.
+int
+execvp(char *file, char **argv)
+{
+  char buf[MAXPATH], *dir, *end;
+  int n;
+
+  if(strchr(file, '/') || (dir = getenv("PATH")) == 0)
+    return exec(file, argv);
+  for(;;){
+    end = strchr(dir, ':');
+    n = end ? end - dir : strlen(dir);
+    if(n + 1 + strlen(file) < sizeof(buf)){
+      memmove(buf, dir, n);
+      buf[n] = '/';
+      strcpy(buf + n + 1, file);
+      exec(buf, argv);
+    }
+    if(end == 0)
+      return -1;
+    dir = end + 1;
+  }
+}
+
+// The value of the environment variable name, or 0 if it is not set.
+char*
+getenv(const char *name)
+{
+  uint n = strlen(name);
+  char **e;
+
+  if(environ == 0)
+    return 0;
+  for(e = environ; *e; e++)
+    if(memcmp(*e, name, n) == 0 && (*e)[n] == '=')
+      return *e + n + 1;
+  return 0;
+}
@@ -0,0 +1,25 @@
+#include "kernel/types.h"
+#include "kernel/sysinfo.h"
+#include "user/user.h"
+
+int
+main(int argc, char *argv[])
+{
+  struct utsname uts;
+  struct sysinfo info;
+
+  if(uname(&uts) < 0){
+    fprintf(2, "uname: uname failed\n");
+    exit(1);
+  }
+  printf("%s %s %s %s\n", uts.sysname, uts.release, uts.version, uts.machine);
+  if(argc > 1 && strcmp(argv[1], "-i") == 0){
+    if(sysinfo(&info) < 0){
+      fprintf(2, "uname: sysinfo failed\n");
+      exit(1);
+    }
+    printf("uptime %d ticks, %d of %d pages free, %d processes\n",
+           info.uptime, info.freepages, info.totalpages, info.procs);
+  }
+  exit(0);
+}
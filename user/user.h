@@ -1,5 +1,14 @@
 struct stat;
 struct rtcdate;
+struct timeval;
+struct timespec;
+struct seccomp_filter;
+struct statfs;
+struct ring;
+struct ring_cqe;
+struct utsname;
+struct sysinfo;
+struct dqblk;
 
 // system calls
 int fork(void);
@@ -10,7 +19,6 @@ int write(int, const void*, int);
 int read(int, void*, int);
 int close(int);
 int kill(int);
-int exec(char*, char**);
 int open(const char*, int);
 int mknod(const char*, short, short);
 int unlink(const char*);
@@ -24,8 +32,63 @@ char* sbrk(int);
 int sleep(int);
 int uptime(void);
 int poweroff(int) __attribute__((noreturn));
+int ioctl(int, int, void*);
+int gettimeofday(struct timeval*);
+int clock_gettime(int, struct timespec*);
+int reboot(int, int);
+int execve(char*, char**, char**);
+int prctl(int, int);
+int mprotect(void*, int, int);
+int seccomp(int, struct seccomp_filter*);
+int chroot(const char*);
+int dmesg(char*, int);
+int trace(uint64, uint64);
+int ptrace(int, int, uint64, uint64);
+int faultinject(int, int);
+int fcntl(int, int, int);
+int sendfile(int, int, uint*, int);
+int pipe2(int*, int);
+int access(const char*, int);
+int statfs(const char*, struct statfs*);
+int fstatfs(int, struct statfs*);
+int lseek(int, int, int);
+int fallocate(int, int, int, int);
+int flock(int, int);
+int mount(int, const char*, int);
+int umount(const char*);
+int sync(void);
+int getcwd(char*, int);
+int openat(int, const char*, int);
+int mkdirat(int, const char*);
+int unlinkat(int, const char*, int);
+int madvise(void*, int, int);
+int ring_setup(void*, void*);
+int ring_enter(int);
+int eventfd(int, int);
+int timerfd(int, int, int);
+int sem_open(const char*, int, int);
+int sem_wait(int);
+int sem_post(int);
+int mq_open(const char*, int);
+int mq_send(int, const void*, int, int);
+int mq_receive(int, void*, int, int*);
+int getppid(void);
+int gettid(void);
+int uname(struct utsname*);
+int sysinfo(struct sysinfo*);
+int rescan(void);
+int cpu_offline(int);
+int cpu_online(int);
+int suspend(int);
+int getuid(void);
+int setuid(int);
+int quotactl(int, int, struct dqblk*);
 
 // ulib.c
+extern char **environ;
+int exec(char*, char**);
+int execvp(char*, char**);
+char* getenv(const char*);
 int stat(const char*, struct stat*);
 char* strcpy(char*, const char*);
 void *memmove(void*, const void*, int);
@@ -41,3 +104,12 @@ void free(void*);
 int atoi(const char*);
 int memcmp(const void *, const void *, uint);
 void *memcpy(void *, const void *, uint);
+int vgetpid(void);
+int vuptime(void);
+int vgettimeofday(struct timeval*);
+
+// ring.c
+int ring_init(struct ring*);
+int ring_prep(struct ring*, int, int, void*, int, uint64);
+int ring_submit(struct ring*);
+int ring_reap(struct ring*, struct ring_cqe*);
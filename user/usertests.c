@@ -4,9 +4,20 @@
 #include "user/user.h"
 #include "kernel/fs.h"
 #include "kernel/fcntl.h"
+#include "kernel/mman.h"
+#include "kernel/seccomp.h"
+#include "kernel/prctl.h"
+#include "kernel/capability.h"
+#include "kernel/mount.h"
+#include "kernel/ptrace.h"
+#include "kernel/profile.h"
+#include "kernel/fault.h"
 #include "kernel/syscall.h"
 #include "kernel/memlayout.h"
 #include "kernel/riscv.h"
+#include "kernel/ring.h"
+#include "kernel/sysinfo.h"
+#include "kernel/quota.h"
 
 //
 // Tests xv6 system calls.  usertests without arguments runs them all
@@ -2394,7 +2405,7 @@ bigargtest(char *s)
 }
 
 // what happens when the file system runs out of blocks?
-// answer: balloc panics, so this test is not useful.
+// answer: writes fail; see diskfull.
 void
 fsfull()
 {
@@ -2492,6 +2503,2501 @@ stacktest(char *s)
     exit(xstatus);
 }
 
+// pages are never both writable and executable (W^X), and
+// mprotect() switches a page from one to the other.
+void
+wxtest(char *s)
+{
+  uint32 *code;
+  int (*f)(void);
+  int pid;
+  int xstatus;
+
+  code = (uint32*) sbrk(PGSIZE);
+  if(code == (uint32*)0xffffffffffffffffL || (uint64)code % PGSIZE != 0){
+    printf("%s: sbrk failed\n", s);
+    exit(1);
+  }
+  code[0] = 0x02a00513;  // li a0, 42
+  code[1] = 0x00008067;  // ret
+  f = (int (*)(void)) code;
+
+  // the heap is not executable.
+  pid = fork();
+  if(pid == 0){
+    f();
+    printf("%s: executed the heap\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != -1){
+    printf("%s: child not killed\n", s);
+    exit(1);
+  }
+
+  if(mprotect(code, PGSIZE, PROT_READ|PROT_WRITE|PROT_EXEC) == 0){
+    printf("%s: mprotect allowed W+X\n", s);
+    exit(1);
+  }
+  if(mprotect(code, PGSIZE, PROT_READ|PROT_EXEC) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+  if(f() != 42){
+    printf("%s: wrong result\n", s);
+    exit(1);
+  }
+
+  // the code is no longer writable.
+  pid = fork();
+  if(pid == 0){
+    code[0] = 0;
+    printf("%s: wrote to code\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != -1){
+    printf("%s: child not killed\n", s);
+    exit(1);
+  }
+
+  // nor is the text of this program.
+  pid = fork();
+  if(pid == 0){
+    *(volatile uint32*)wxtest = 0;
+    printf("%s: wrote to text\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != -1){
+    printf("%s: child not killed\n", s);
+    exit(1);
+  }
+}
+
+// a seccomp filter denies system calls, is inherited by children,
+// and cannot change once locked.
+void
+seccomptest(char *s)
+{
+  struct seccomp_filter f;
+  int pid;
+  int xstatus;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    memset(&f, 0, sizeof(f));
+    f.allow[SYS_fork/64] |= 1L << (SYS_fork%64);
+    f.allow[SYS_wait/64] |= 1L << (SYS_wait%64);
+    f.allow[SYS_write/64] |= 1L << (SYS_write%64);
+    f.allow[SYS_seccomp/64] |= 1L << (SYS_seccomp%64);
+    f.action = SECCOMP_RET_ERRNO;
+    if(seccomp(SECCOMP_SET_FILTER, &f) != 0){
+      printf("%s: seccomp failed\n", s);
+      exit(1);
+    }
+    if(uptime() != -1){
+      printf("%s: uptime allowed\n", s);
+      exit(1);
+    }
+    if(seccomp(SECCOMP_LOCK, 0) != 0){
+      printf("%s: lock failed\n", s);
+      exit(1);
+    }
+    if(seccomp(SECCOMP_SET_FILTER, &f) != -1 || seccomp(SECCOMP_LOCK, 0) != -1){
+      printf("%s: changed a locked filter\n", s);
+      exit(1);
+    }
+    pid = fork();
+    if(pid == 0){
+      if(uptime() != -1){
+        printf("%s: uptime allowed in child\n", s);
+        exit(1);
+      }
+      exit(0);
+    }
+    wait(&xstatus);
+    exit(xstatus);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+
+  pid = fork();
+  if(pid == 0){
+    memset(&f, 0, sizeof(f));
+    f.action = SECCOMP_RET_KILL;
+    seccomp(SECCOMP_SET_FILTER, &f);
+    uptime();
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != -1){
+    printf("%s: denied child not killed\n", s);
+    exit(1);
+  }
+}
+
+// a dropped capability is gone for good, also in children.
+void
+captest(char *s)
+{
+  int pid;
+  int xstatus;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(prctl(PR_CAPBSET_READ, CAP_MKNOD) != 1){
+      printf("%s: no CAP_MKNOD to begin with\n", s);
+      exit(1);
+    }
+    if(prctl(PR_CAPBSET_DROP, CAP_MKNOD) != 0){
+      printf("%s: drop failed\n", s);
+      exit(1);
+    }
+    if(prctl(PR_CAPBSET_READ, CAP_MKNOD) != 0){
+      printf("%s: CAP_MKNOD not dropped\n", s);
+      exit(1);
+    }
+    if(mknod("capdev", 1, 0) == 0){
+      unlink("capdev");
+      printf("%s: mknod without CAP_MKNOD\n", s);
+      exit(1);
+    }
+    pid = fork();
+    if(pid == 0)
+      exit(prctl(PR_CAPBSET_READ, CAP_MKNOD));
+    wait(&xstatus);
+    exit(xstatus);
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: capability came back\n", s);
+    exit(1);
+  }
+}
+
+// after chroot(), paths cannot reach outside the new root.
+void
+chroottest(char *s)
+{
+  int pid;
+  int fd;
+  int xstatus;
+
+  if(mkdir("chrootdir") != 0){
+    printf("%s: mkdir failed\n", s);
+    exit(1);
+  }
+  fd = open("chrootdir/inside", O_CREATE|O_WRONLY);
+  if(fd < 0){
+    printf("%s: create failed\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(chroot("chrootdir") != 0){
+      printf("%s: chroot failed\n", s);
+      exit(1);
+    }
+    if((fd = open("/inside", 0)) < 0){
+      printf("%s: cannot open /inside\n", s);
+      exit(1);
+    }
+    close(fd);
+    if((fd = open("/../../inside", 0)) < 0){
+      printf("%s: .. went above the root\n", s);
+      exit(1);
+    }
+    close(fd);
+    if(open("../chrootdir/inside", 0) >= 0 || open("/chrootdir", 0) >= 0){
+      printf("%s: reached outside the root\n", s);
+      exit(1);
+    }
+    exit(0);
+  }
+  wait(&xstatus);
+  unlink("chrootdir/inside");
+  unlink("chrootdir");
+  if(xstatus != 0)
+    exit(1);
+}
+
+// the kernel log holds the message of an unknown system call,
+// and dmesg() copies no more than asked. /proc/kmsg reads the
+// same log.
+void
+dmesgtest(char *s)
+{
+  static char buf[4096];
+  int n, i, fd;
+
+  if(dmesg(buf, 0) != 0){
+    printf("%s: dmesg of 0 bytes failed\n", s);
+    exit(1);
+  }
+  if(dmesg((char*)0xffffffffffffffffULL, 10) != -1){
+    printf("%s: dmesg to a bad address succeeded\n", s);
+    exit(1);
+  }
+
+  // make an unknown system call, which the kernel logs.
+  asm volatile("li a7, 1000; ecall" : : : "a0", "a7");
+
+  n = dmesg(buf, sizeof(buf));
+  if(n <= 0 || n > sizeof(buf)){
+    printf("%s: dmesg returned %d\n", s, n);
+    exit(1);
+  }
+  for(i = 0; i + 7 <= n; i++){
+    if(memcmp(buf + i, "unknown", 7) == 0)
+      break;
+  }
+  if(i + 7 > n){
+    printf("%s: unknown system call not in the kernel log\n", s);
+    exit(1);
+  }
+  if(dmesg(buf, 5) != 5){
+    printf("%s: dmesg of 5 bytes failed\n", s);
+    exit(1);
+  }
+
+  fd = open("/proc/kmsg", O_RDONLY);
+  if(fd < 0){
+    printf("%s: open /proc/kmsg failed\n", s);
+    exit(1);
+  }
+  n = 0;
+  while((i = read(fd, buf + n, sizeof(buf) - n)) > 0)
+    n += i;
+  close(fd);
+  for(i = 0; i + 7 <= n; i++){
+    if(memcmp(buf + i, "unknown", 7) == 0)
+      break;
+  }
+  if(i + 7 > n){
+    printf("%s: unknown system call not in /proc/kmsg\n", s);
+    exit(1);
+  }
+}
+
+// a traced system call shows up in the kernel log, with its
+// arguments and return value.
+void
+tracetest(char *s)
+{
+  static char buf[4096];
+  int n, i, pid, xstatus;
+  char *want = "close(-5) = -1";
+  int len = strlen(want);
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(trace(1 << SYS_close, 0) < 0){
+      printf("%s: trace failed\n", s);
+      exit(1);
+    }
+    close(-5);
+    exit(0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+
+  n = dmesg(buf, sizeof(buf));
+  for(i = 0; i + len <= n; i++){
+    if(memcmp(buf + i, want, len) == 0)
+      break;
+  }
+  if(i + len > n){
+    printf("%s: traced close not in the kernel log\n", s);
+    exit(1);
+  }
+}
+
+// a parent traces its child: it sees the child stop at a breakpoint,
+// reads and writes its memory and registers, and resumes it.
+int ptracevar = 1;
+
+void
+ptracetest(char *s)
+{
+  struct user_regs regs;
+  uint64 word;
+  int pid, xstatus;
+
+  if(ptrace(PTRACE_ATTACH, getpid(), 0, 0) != -1){
+    printf("%s: attached to itself\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(ptrace(PTRACE_TRACEME, 0, 0, 0) < 0)
+      exit(1);
+    // ebreak, in 4 bytes even if the assembler would compress it.
+    asm volatile(".4byte 0x00100073");
+    exit(ptracevar == 42 ? 0 : 2);
+  }
+
+  if(wait(&xstatus) != pid || !WIFSTOPPED(xstatus) || WSTOPSIG(xstatus) != SIGTRAP){
+    printf("%s: child did not stop at the breakpoint, status %x\n", s, xstatus);
+    exit(1);
+  }
+  if(ptrace(PTRACE_GETREGS, pid, 0, (uint64)&regs) < 0){
+    printf("%s: PTRACE_GETREGS failed\n", s);
+    exit(1);
+  }
+  if(ptrace(PTRACE_PEEKDATA, pid, regs.pc, (uint64)&word) < 0 || (uint32)word != 0x00100073){
+    printf("%s: no ebreak at pc %p\n", s, regs.pc);
+    exit(1);
+  }
+  if(ptrace(PTRACE_POKEDATA, pid, (uint64)&ptracevar, 42) < 0){
+    printf("%s: PTRACE_POKEDATA failed\n", s);
+    exit(1);
+  }
+  regs.pc += 4;
+  if(ptrace(PTRACE_SETREGS, pid, 0, (uint64)&regs) < 0){
+    printf("%s: PTRACE_SETREGS failed\n", s);
+    exit(1);
+  }
+  if(ptrace(PTRACE_CONT, pid, 0, 0) < 0){
+    printf("%s: PTRACE_CONT failed\n", s);
+    exit(1);
+  }
+  if(ptrace(PTRACE_CONT, pid, 0, 0) != -1){
+    printf("%s: resumed a running tracee\n", s);
+    exit(1);
+  }
+  if(wait(&xstatus) != pid || xstatus != 0){
+    printf("%s: child exited with %d\n", s, xstatus);
+    exit(1);
+  }
+}
+
+// the profiler samples the pc of a process that spins in user space.
+void
+profiletest(char *s)
+{
+  struct profsample samples[64];
+  int fd, n, i, found;
+  uint start;
+  volatile int spin = 0;
+
+  fd = open("/proc/profile", O_RDWR);
+  if(fd < 0){
+    printf("%s: cannot open /proc/profile\n", s);
+    exit(1);
+  }
+  if(write(fd, "1", 1) != 1){
+    printf("%s: cannot start profiling\n", s);
+    exit(1);
+  }
+  start = uptime();
+  while(uptime() < start + 5)
+    spin++;
+  write(fd, "0", 1);
+
+  found = 0;
+  while((n = read(fd, samples, sizeof(samples))) > 0){
+    for(i = 0; i < n / sizeof(samples[0]); i++){
+      if(samples[i].pid == getpid() && !samples[i].kernel)
+        found = 1;
+    }
+  }
+  close(fd);
+  if(!found){
+    printf("%s: no sample of this process\n", s);
+    exit(1);
+  }
+}
+
+// returns the count named name in /proc/stat, or -1.
+int
+kstatcount(char *name)
+{
+  char buf[512], *p;
+  int fd, n, len;
+
+  fd = open("/proc/stat", O_RDONLY);
+  if(fd < 0)
+    return -1;
+  // read in small pieces, to check that reads continue where they stopped.
+  len = 0;
+  while(len < sizeof(buf) - 8 && (n = read(fd, buf + len, 7)) > 0)
+    len += n;
+  close(fd);
+  buf[len] = 0;
+
+  n = strlen(name);
+  for(p = buf; *p; p = strchr(p, '\n') + 1){
+    if(memcmp(p, name, n) == 0 && p[n] == ' ')
+      return atoi(p + n + 1);
+    if(strchr(p, '\n') == 0)
+      break;
+  }
+  return -1;
+}
+
+// the kernel counts context switches and buffer cache lookups in /proc/stat.
+void
+kstattest(char *s)
+{
+  int switches, hits, fd;
+  char c;
+
+  switches = kstatcount("context_switch");
+  hits = kstatcount("bcache_hit");
+  if(switches < 0 || hits < 0){
+    printf("%s: cannot read /proc/stat\n", s);
+    exit(1);
+  }
+  sleep(2);
+  fd = open("README", O_RDONLY);
+  read(fd, &c, 1);
+  close(fd);
+  if(kstatcount("context_switch") <= switches){
+    printf("%s: context_switch did not grow\n", s);
+    exit(1);
+  }
+  if(kstatcount("bcache_hit") <= hits){
+    printf("%s: bcache_hit did not grow\n", s);
+    exit(1);
+  }
+}
+
+// returns the writes of the root disk in /proc/diskstats, or -1.
+int
+diskwrites(void)
+{
+  char buf[512], *p;
+  int fd, n, i;
+
+  fd = open("/proc/diskstats", O_RDONLY);
+  if(fd < 0)
+    return -1;
+  n = read(fd, buf, sizeof(buf) - 1);
+  close(fd);
+  if(n < 0)
+    return -1;
+  buf[n] = 0;
+
+  // the line of ROOTDEV: dev reads rbytes writes ...
+  for(p = buf; *p; p = strchr(p, '\n') + 1){
+    if(atoi(p) == ROOTDEV && *p != 'd'){
+      for(i = 0; i < 3; i++)
+        p = strchr(p, ' ') + 1;
+      return atoi(p);
+    }
+    if(strchr(p, '\n') == 0)
+      break;
+  }
+  return -1;
+}
+
+// the kernel counts the requests to each block device in /proc/diskstats.
+void
+diskstatstest(char *s)
+{
+  int writes, fd;
+
+  writes = diskwrites();
+  if(writes < 0){
+    printf("%s: cannot read /proc/diskstats\n", s);
+    exit(1);
+  }
+  fd = open("diskstats0", O_CREATE|O_RDWR);
+  if(fd < 0 || write(fd, "x", 1) != 1){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("diskstats0");
+  if(diskwrites() <= writes){
+    printf("%s: writes did not grow\n", s);
+    exit(1);
+  }
+}
+
+// with injected faults, opens, pipes, execs, reads, and writes fail
+// now and then, but cleanly, and leave nothing behind.
+// passes trivially if the kernel was built without FAULT_INJECT=yes.
+void
+faulttest(char *s)
+{
+  char *args[] = { "echo", "fault", 0 };
+  int i, fd, fds[2], pid, xstatus;
+
+  if(faultinject(0, 0) < 0)
+    return;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    for(i = 0; i < 50; i++){
+      // faults hit only the process that asked last.
+      if(faultinject(FAULT_KALLOC | FAULT_ARENA | FAULT_DISK, 4) < 0){
+        printf("%s: faultinject failed\n", s);
+        exit(1);
+      }
+      if((fd = open("faultfile", O_CREATE | O_RDWR)) >= 0){
+        write(fd, buf, BSIZE);
+        close(fd);
+      }
+      if((fd = open("README", O_RDONLY)) >= 0){
+        read(fd, buf, BSIZE);
+        close(fd);
+      }
+      if(pipe(fds) == 0){
+        write(fds[1], "x", 1);
+        read(fds[0], buf, 1);
+        close(fds[0]);
+        close(fds[1]);
+      }
+      if(sbrk(4096) != (char*)-1)
+        sbrk(-4096);
+      pid = fork();
+      if(pid == 0){
+        faultinject(FAULT_KALLOC | FAULT_ARENA | FAULT_DISK, 4);
+        close(1);
+        exec("echo", args);
+        exit(0);
+      }
+      if(pid > 0)
+        wait(0);
+    }
+    faultinject(0, 0);
+    exit(0);
+  }
+  wait(&xstatus);
+  unlink("faultfile");
+  if(xstatus != 0)
+    exit(xstatus);
+
+  // the kernel still works.
+  if((fd = open("README", O_RDONLY)) < 0 || read(fd, buf, BSIZE) <= 0){
+    printf("%s: cannot read README after faults\n", s);
+    exit(1);
+  }
+  close(fd);
+  if(pipe(fds) != 0){
+    printf("%s: cannot make a pipe after faults\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+}
+
+// a pipe's buffer can grow, keeping what is in it, and then holds
+// that much without blocking the writer.
+void
+pipesize(char *s)
+{
+  int fds[2], fd, i, n, size;
+
+  if(pipe(fds) != 0){
+    printf("%s: pipe() failed\n", s);
+    exit(1);
+  }
+  if(fcntl(fds[0], F_GETPIPE_SZ, 0) != 4096){
+    printf("%s: default pipe size is not 4096\n", s);
+    exit(1);
+  }
+  if(write(fds[1], "ab", 2) != 2){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  // sizes round up to a power of two number of pages.
+  size = fcntl(fds[1], F_SETPIPE_SZ, 10000);
+  if(size != 16384 || fcntl(fds[0], F_GETPIPE_SZ, 0) != size){
+    printf("%s: F_SETPIPE_SZ returned %d\n", s, size);
+    exit(1);
+  }
+  for(i = 0; i < size - 2; i += n){
+    n = size - 2 - i < 4096 ? size - 2 - i : 4096;
+    memset(buf, 'a' + (i / 4096) % 26, n);
+    if(write(fds[1], buf, n) != n){
+      printf("%s: write failed\n", s);
+      exit(1);
+    }
+  }
+  // the buffer is full, so it cannot shrink.
+  if(fcntl(fds[1], F_SETPIPE_SZ, 4096) >= 0){
+    printf("%s: shrank a full pipe\n", s);
+    exit(1);
+  }
+  if(read(fds[0], buf, 2) != 2 || buf[0] != 'a' || buf[1] != 'b'){
+    printf("%s: lost what was in the pipe before growing\n", s);
+    exit(1);
+  }
+  for(i = 0; i < size - 2; i += n){
+    n = read(fds[0], buf, 4096);
+    if(n <= 0 || buf[0] != 'a' + (i / 4096) % 26){
+      printf("%s: read back wrong data\n", s);
+      exit(1);
+    }
+  }
+  if(fcntl(fds[1], F_SETPIPE_SZ, 1 << 30) >= 0){
+    printf("%s: grew a pipe past the cap\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+
+  if((fd = open("README", O_RDONLY)) < 0){
+    printf("%s: open README failed\n", s);
+    exit(1);
+  }
+  if(fcntl(fd, F_GETPIPE_SZ, 0) >= 0){
+    printf("%s: a file has a pipe size\n", s);
+    exit(1);
+  }
+  close(fd);
+}
+
+// a named pipe connects processes that open it by name, and
+// opening one end waits for the other.
+void
+fifotest(char *s)
+{
+  int fd, pid, xstatus, n, total;
+  struct stat st;
+
+  unlink("fifo0");
+  if(mknod("fifo0", FIFO, 0) < 0){
+    printf("%s: mknod FIFO failed\n", s);
+    exit(1);
+  }
+  if(stat("fifo0", &st) < 0 || st.type != T_FIFO){
+    printf("%s: stat of a FIFO is not T_FIFO\n", s);
+    exit(1);
+  }
+  if(open("fifo0", O_WRONLY|O_NONBLOCK) >= 0){
+    printf("%s: opened a FIFO without a reader\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    fd = open("fifo0", O_WRONLY);
+    if(fd < 0){
+      printf("%s: open FIFO for writing failed\n", s);
+      exit(1);
+    }
+    memset(buf, 'x', 5000);
+    if(write(fd, buf, 5000) != 5000){
+      printf("%s: write to FIFO failed\n", s);
+      exit(1);
+    }
+    close(fd);
+    exit(0);
+  }
+
+  fd = open("fifo0", O_RDONLY);
+  if(fd < 0){
+    printf("%s: open FIFO for reading failed\n", s);
+    exit(1);
+  }
+  total = 0;
+  while((n = read(fd, buf, sizeof(buf))) > 0){
+    if(buf[0] != 'x' || buf[n-1] != 'x'){
+      printf("%s: read wrong data from FIFO\n", s);
+      exit(1);
+    }
+    total += n;
+  }
+  close(fd);
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+  if(total != 5000){
+    printf("%s: read %d bytes from FIFO, not 5000\n", s, total);
+    exit(1);
+  }
+
+  // without a writer, a nonblocking reader sees end of file.
+  fd = open("fifo0", O_RDONLY|O_NONBLOCK);
+  if(fd < 0 || read(fd, buf, 1) != 0){
+    printf("%s: nonblocking read of an idle FIFO failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("fifo0");
+}
+
+// sendfile() copies from a file to a pipe or another file
+// without a buffer in user memory.
+void
+sendfiletest(char *s)
+{
+  int fd, fd2, fds[2], i, n;
+  uint off;
+
+  unlink("sendfile0");
+  unlink("sendfile1");
+  fd = open("sendfile0", O_CREATE|O_RDWR);
+  if(fd < 0){
+    printf("%s: create sendfile0 failed\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 5000; i++)
+    buf[i] = 'a' + i % 26;
+  if(write(fd, buf, 5000) != 5000){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  // from the file offset to a pipe.
+  fd = open("sendfile0", O_RDONLY);
+  if(pipe(fds) != 0){
+    printf("%s: pipe() failed\n", s);
+    exit(1);
+  }
+  if(read(fd, buf, 10) != 10){
+    printf("%s: read failed\n", s);
+    exit(1);
+  }
+  if(sendfile(fds[1], fd, 0, 3000) != 3000){
+    printf("%s: sendfile to a pipe failed\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 3000; i += n){
+    n = read(fds[0], buf, 3000 - i);
+    if(n <= 0 || buf[0] != 'a' + (10 + i) % 26){
+      printf("%s: read wrong data from the pipe\n", s);
+      exit(1);
+    }
+  }
+  if(read(fd, buf, 1) != 1 || buf[0] != 'a' + 3010 % 26){
+    printf("%s: sendfile did not advance the file offset\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+
+  // from an explicit offset to another file, up to the end.
+  fd2 = open("sendfile1", O_CREATE|O_RDWR);
+  off = 4000;
+  if(sendfile(fd2, fd, &off, 5000) != 1000 || off != 5000){
+    printf("%s: sendfile to a file returned a wrong count\n", s);
+    exit(1);
+  }
+  if(read(fd, buf, 1) != 1 || buf[0] != 'a' + 3011 % 26){
+    printf("%s: sendfile with an offset moved the file offset\n", s);
+    exit(1);
+  }
+  close(fd2);
+  fd2 = open("sendfile1", O_RDONLY);
+  if(read(fd2, buf, sizeof(buf)) != 1000 || buf[0] != 'a' + 4000 % 26 || buf[999] != 'a' + 4999 % 26){
+    printf("%s: read wrong data from the copy\n", s);
+    exit(1);
+  }
+  if(sendfile(fd, fd2, 0, 10) >= 0){
+    printf("%s: sendfile to a read-only file succeeded\n", s);
+    exit(1);
+  }
+  close(fd);
+  close(fd2);
+  unlink("sendfile0");
+  unlink("sendfile1");
+}
+
+// O_CLOEXEC and pipe2() mark descriptors that exec closes.
+void
+cloexectest(char *s)
+{
+  int fd, fd2, fds[2], pid, xstatus;
+  char *echoargv[] = { "echo", "cloexec", 0 };
+
+  fd = open("README", O_RDONLY|O_CLOEXEC);
+  if(fd < 0 || fcntl(fd, F_GETFD, 0) != FD_CLOEXEC){
+    printf("%s: open with O_CLOEXEC did not set FD_CLOEXEC\n", s);
+    exit(1);
+  }
+  fd2 = dup(fd);
+  if(fd2 < 0 || fcntl(fd2, F_GETFD, 0) != 0){
+    printf("%s: dup kept FD_CLOEXEC\n", s);
+    exit(1);
+  }
+  if(fcntl(fd2, F_SETFD, FD_CLOEXEC) != 0 || fcntl(fd2, F_GETFD, 0) != FD_CLOEXEC){
+    printf("%s: F_SETFD failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  close(fd2);
+
+  if(pipe2(fds, O_CLOEXEC) != 0){
+    printf("%s: pipe2 failed\n", s);
+    exit(1);
+  }
+  if(fcntl(fds[0], F_GETFD, 0) != FD_CLOEXEC || fcntl(fds[1], F_GETFD, 0) != FD_CLOEXEC){
+    printf("%s: pipe2 did not set FD_CLOEXEC\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+  if(pipe2(fds, O_TRUNC) >= 0){
+    printf("%s: pipe2 took an unknown flag\n", s);
+    exit(1);
+  }
+
+  // echo finds its standard output closed, so writes nothing.
+  unlink("cloexec0");
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    close(1);
+    if(open("cloexec0", O_CREATE|O_WRONLY|O_CLOEXEC) != 1)
+      exit(1);
+    exec("echo", echoargv);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+  fd = open("cloexec0", O_RDONLY);
+  if(fd < 0 || read(fd, buf, sizeof(buf)) != 0){
+    printf("%s: exec kept a close-on-exec descriptor\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("cloexec0");
+}
+
+// access() checks a path against what its type allows.
+void
+accesstest(char *s)
+{
+  if(access("README", F_OK) != 0 || access("README", R_OK|W_OK|X_OK) != 0){
+    printf("%s: access to README failed\n", s);
+    exit(1);
+  }
+  if(access(".", R_OK|X_OK) != 0){
+    printf("%s: access to . failed\n", s);
+    exit(1);
+  }
+  if(access(".", W_OK) == 0){
+    printf("%s: a directory is writable\n", s);
+    exit(1);
+  }
+  if(access("console", X_OK) == 0){
+    printf("%s: a device is executable\n", s);
+    exit(1);
+  }
+  if(access("nosuchfile", F_OK) == 0){
+    printf("%s: access to a missing file succeeded\n", s);
+    exit(1);
+  }
+  if(access("README", 8) == 0){
+    printf("%s: access took an unknown mode\n", s);
+    exit(1);
+  }
+}
+
+// statfs() reports blocks and inodes as files come and go.
+void
+statfstest(char *s)
+{
+  struct statfs before, during, after;
+  int fd, fds[2], i;
+
+  if(statfs(".", &before) < 0){
+    printf("%s: statfs failed\n", s);
+    exit(1);
+  }
+  if(before.bsize != BSIZE || before.bfree > before.blocks || before.ffree > before.files){
+    printf("%s: statfs reported nonsense\n", s);
+    exit(1);
+  }
+
+  unlink("statfs0");
+  fd = open("statfs0", O_CREATE|O_WRONLY);
+  if(fd < 0){
+    printf("%s: create statfs0 failed\n", s);
+    exit(1);
+  }
+  memset(buf, 'x', BSIZE);
+  for(i = 0; i < 10; i++){
+    if(write(fd, buf, BSIZE) != BSIZE){
+      printf("%s: write failed\n", s);
+      exit(1);
+    }
+  }
+  if(fstatfs(fd, &during) < 0){
+    printf("%s: fstatfs failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  if(during.bfree > before.bfree - 10 || during.ffree != before.ffree - 1){
+    printf("%s: statfs did not count a new file\n", s);
+    exit(1);
+  }
+
+  unlink("statfs0");
+  statfs(".", &after);
+  if(after.bfree != before.bfree || after.ffree != before.ffree){
+    printf("%s: statfs did not count a removed file\n", s);
+    exit(1);
+  }
+
+  if(pipe(fds) != 0){
+    printf("%s: pipe() failed\n", s);
+    exit(1);
+  }
+  if(fstatfs(fds[0], &during) >= 0){
+    printf("%s: fstatfs of a pipe succeeded\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+}
+
+// writing past the end leaves a hole, which takes no blocks,
+// reads as zeros, and is found by SEEK_HOLE and SEEK_DATA.
+void
+sparsetest(char *s)
+{
+  struct statfs before, after;
+  int fd, fd2, i;
+  uint off;
+
+  unlink("sparse0");
+  unlink("sparse1");
+  statfs(".", &before);
+  fd = open("sparse0", O_CREATE|O_RDWR);
+  if(fd < 0){
+    printf("%s: create sparse0 failed\n", s);
+    exit(1);
+  }
+  if(write(fd, "a", 1) != 1 || lseek(fd, 5*BSIZE, SEEK_SET) != 5*BSIZE || write(fd, "b", 1) != 1){
+    printf("%s: write past the end failed\n", s);
+    exit(1);
+  }
+  statfs(".", &after);
+  if(before.bfree - after.bfree != 2){
+    printf("%s: a hole took blocks\n", s);
+    exit(1);
+  }
+  if(lseek(fd, 0, SEEK_SET) != 0 || read(fd, buf, sizeof(buf)) != 5*BSIZE+1){
+    printf("%s: read of a sparse file failed\n", s);
+    exit(1);
+  }
+  for(i = 1; i < 5*BSIZE; i++){
+    if(buf[i] != 0){
+      printf("%s: a hole did not read as zeros\n", s);
+      exit(1);
+    }
+  }
+  if(buf[0] != 'a' || buf[5*BSIZE] != 'b'){
+    printf("%s: read wrong data around a hole\n", s);
+    exit(1);
+  }
+  if(lseek(fd, 1, SEEK_DATA) != 1 || lseek(fd, 0, SEEK_HOLE) != BSIZE ||
+     lseek(fd, BSIZE, SEEK_DATA) != 5*BSIZE || lseek(fd, 5*BSIZE, SEEK_HOLE) != 5*BSIZE+1 ||
+     lseek(fd, 5*BSIZE+1, SEEK_DATA) >= 0){
+    printf("%s: SEEK_HOLE or SEEK_DATA went wrong\n", s);
+    exit(1);
+  }
+  if(lseek(fd, -1, SEEK_END) != 5*BSIZE || lseek(fd, -1, SEEK_SET) >= 0){
+    printf("%s: lseek went wrong\n", s);
+    exit(1);
+  }
+
+  // sendfile keeps the hole.
+  fd2 = open("sparse1", O_CREATE|O_RDWR);
+  off = 0;
+  statfs(".", &before);
+  if(sendfile(fd2, fd, &off, 5*BSIZE+1) != 5*BSIZE+1){
+    printf("%s: sendfile of a sparse file failed\n", s);
+    exit(1);
+  }
+  statfs(".", &after);
+  if(before.bfree - after.bfree != 2 || lseek(fd2, 0, SEEK_HOLE) != BSIZE){
+    printf("%s: sendfile filled a hole\n", s);
+    exit(1);
+  }
+  if(lseek(fd2, 0, SEEK_SET) != 0 || read(fd2, buf, sizeof(buf)) != 5*BSIZE+1 || buf[5*BSIZE] != 'b'){
+    printf("%s: read wrong data from the copy\n", s);
+    exit(1);
+  }
+  close(fd);
+  close(fd2);
+  unlink("sparse0");
+  unlink("sparse1");
+}
+
+// fallocate() allocates blocks ahead of writes, and punches holes.
+void
+fallocatetest(char *s)
+{
+  struct statfs before, after;
+  struct stat st;
+  int fd, i;
+
+  unlink("falloc0");
+  fd = open("falloc0", O_CREATE|O_RDWR);
+  if(fd < 0){
+    printf("%s: create falloc0 failed\n", s);
+    exit(1);
+  }
+  statfs(".", &before);
+  if(fallocate(fd, 0, 0, 4*BSIZE) != 0 || fallocate(fd, FALLOC_FL_KEEP_SIZE, 4*BSIZE, BSIZE) != 0){
+    printf("%s: fallocate failed\n", s);
+    exit(1);
+  }
+  statfs(".", &after);
+  if(before.bfree - after.bfree != 5){
+    printf("%s: fallocate allocated %d blocks, not 5\n", s, before.bfree - after.bfree);
+    exit(1);
+  }
+  if(fstat(fd, &st) < 0 || st.size != 4*BSIZE){
+    printf("%s: fallocate set a wrong size\n", s);
+    exit(1);
+  }
+
+  memset(buf, 'x', 4*BSIZE);
+  if(write(fd, buf, 4*BSIZE) != 4*BSIZE){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  statfs(".", &before);
+  if(fallocate(fd, FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE, 100, 2*BSIZE) != 0){
+    printf("%s: punching a hole failed\n", s);
+    exit(1);
+  }
+  statfs(".", &after);
+  if(after.bfree - before.bfree != 1 || lseek(fd, 0, SEEK_HOLE) != BSIZE){
+    printf("%s: punching a hole did not free a block\n", s);
+    exit(1);
+  }
+  if(lseek(fd, 0, SEEK_SET) != 0 || read(fd, buf, sizeof(buf)) != 4*BSIZE){
+    printf("%s: read failed\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 4*BSIZE; i++){
+    if(buf[i] != (i >= 100 && i < 100+2*BSIZE ? 0 : 'x')){
+      printf("%s: wrong data at %d after punching a hole\n", s, i);
+      exit(1);
+    }
+  }
+
+  if(fallocate(fd, FALLOC_FL_PUNCH_HOLE, 0, BSIZE) >= 0 || fallocate(fd, 0, 0, 0) >= 0){
+    printf("%s: fallocate took a bad mode or length\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("falloc0");
+}
+
+// flock() locks belong to open files, and closing the last
+// descriptor of one releases its lock.
+void
+flocktest(char *s)
+{
+  int fd1, fd2, pid, xstatus;
+
+  unlink("flock0");
+  fd1 = open("flock0", O_CREATE|O_RDWR);
+  fd2 = open("flock0", O_RDWR);
+  if(fd1 < 0 || fd2 < 0){
+    printf("%s: open flock0 failed\n", s);
+    exit(1);
+  }
+  if(flock(fd1, LOCK_SH) != 0 || flock(fd2, LOCK_SH|LOCK_NB) != 0){
+    printf("%s: shared locks conflict\n", s);
+    exit(1);
+  }
+  if(flock(fd1, LOCK_EX|LOCK_NB) >= 0){
+    printf("%s: exclusive lock over a shared one\n", s);
+    exit(1);
+  }
+  if(flock(fd2, LOCK_UN) != 0 || flock(fd1, LOCK_EX|LOCK_NB) != 0){
+    printf("%s: exclusive lock failed\n", s);
+    exit(1);
+  }
+  if(flock(fd2, LOCK_SH|LOCK_NB) >= 0){
+    printf("%s: shared lock over an exclusive one\n", s);
+    exit(1);
+  }
+
+  // the child shares the lock of fd1, and waits for fd2's.
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(flock(fd1, LOCK_EX|LOCK_NB) != 0)
+      exit(1);
+    close(fd1);
+    if(flock(fd2, LOCK_EX) != 0)
+      exit(1);
+    exit(0);
+  }
+  sleep(1);
+  close(fd1);
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: closing did not release the lock\n", s);
+    exit(1);
+  }
+  if(flock(fd2, LOCK_EX|LOCK_NB) != 0){
+    printf("%s: exit did not release the lock\n", s);
+    exit(1);
+  }
+  if(flock(fd2, 0) >= 0){
+    printf("%s: flock took a bad operation\n", s);
+    exit(1);
+  }
+  close(fd2);
+  unlink("flock0");
+}
+
+// mount() takes only a directory, and only when the second disk or
+// the md device holds a FAT32 volume; run with FAT=image or MD=...
+// to test the volume.
+void
+mounttest(char *s)
+{
+  struct stat st;
+  char *data = "hello, fat32\n";
+  int fd, n;
+
+  if(mount(FATDEV, "README", 0) >= 0 || umount("README") >= 0 || umount("/") >= 0){
+    printf("%s: mount took a bad path\n", s);
+    exit(1);
+  }
+  unlink("mnt0");
+  if(mkdir("mnt0") != 0){
+    printf("%s: mkdir mnt0 failed\n", s);
+    exit(1);
+  }
+  if(mount(ROOTDEV, "mnt0", 0) >= 0 || mount(NDEV, "mnt0", 0) >= 0){
+    printf("%s: mount took a bad device\n", s);
+    exit(1);
+  }
+  if(mount(FATDEV, "mnt0", 0) < 0 && mount(MDDEV, "mnt0", 0) < 0){
+    // no volume to test.
+    unlink("mnt0");
+    return;
+  }
+  if(mount(FATDEV, "mnt0", 0) >= 0 || mount(MDDEV, "mnt0", 0) >= 0){
+    printf("%s: mounted twice\n", s);
+    exit(1);
+  }
+
+  fd = open("mnt0/mnttest.txt", O_CREATE|O_RDWR|O_TRUNC);
+  if(fd < 0){
+    printf("%s: create in the volume failed\n", s);
+    exit(1);
+  }
+  if(write(fd, data, strlen(data)) != strlen(data)){
+    printf("%s: write to the volume failed\n", s);
+    exit(1);
+  }
+  if(umount("mnt0") >= 0 || unlink("mnt0") >= 0){
+    printf("%s: unmounted or removed with a file open\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  fd = open("mnt0/MNTTEST.TXT", O_RDONLY);
+  if(fd < 0 || fstat(fd, &st) < 0 || st.type != T_FILE || st.size != strlen(data)){
+    printf("%s: written file is missing\n", s);
+    exit(1);
+  }
+  n = read(fd, buf, sizeof(buf));
+  if(n != strlen(data) || memcmp(buf, data, n) != 0){
+    printf("%s: read back wrong data\n", s);
+    exit(1);
+  }
+  close(fd);
+  if(open("mnt0/longname.text", O_CREATE|O_RDWR) >= 0){
+    printf("%s: created a file without a short name\n", s);
+    exit(1);
+  }
+
+  fd = open("mnt0", O_RDONLY);
+  if(fd < 0 || fstat(fd, &st) < 0 || st.type != T_DIR){
+    printf("%s: open the root of the volume failed\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  if(umount("mnt0") != 0){
+    printf("%s: umount failed\n", s);
+    exit(1);
+  }
+  if(open("mnt0/mnttest.txt", O_RDONLY) >= 0){
+    printf("%s: the volume is still there after umount\n", s);
+    exit(1);
+  }
+  unlink("mnt0");
+}
+
+// with the discard option, freed blocks are discarded after commit,
+// but not the ones that are allocated again.
+void
+discardtest(char *s)
+{
+  int fd, i, j, pid, xstatus;
+
+  if(mount(FATDEV, "/", MS_REMOUNT|MS_DISCARD) >= 0 || mount(ROOTDEV, "README", MS_REMOUNT) >= 0 ||
+     mount(FATDEV, "/", MS_DISCARD) >= 0){
+    printf("%s: remount took bad arguments\n", s);
+    exit(1);
+  }
+  if(mount(ROOTDEV, "/", MS_REMOUNT|MS_DISCARD) != 0){
+    printf("%s: remount with discard failed\n", s);
+    exit(1);
+  }
+
+  // two processes free and allocate blocks at once, so that
+  // transactions have both.
+  for(i = 0; i < 2; i++){
+    pid = fork();
+    if(pid < 0){
+      printf("%s: fork failed\n", s);
+      exit(1);
+    }
+    if(pid == 0){
+      char name[] = { 'd', 'i', 's', '0' + i, 0 };
+      for(j = 0; j < 10; j++){
+        fd = open(name, O_CREATE|O_RDWR|O_TRUNC);
+        if(fd < 0){
+          printf("%s: create %s failed\n", s, name);
+          exit(1);
+        }
+        memset(buf, 'a' + i + j, BSIZE);
+        if(write(fd, buf, BSIZE) != BSIZE || write(fd, buf, BSIZE) != BSIZE){
+          printf("%s: write %s failed\n", s, name);
+          exit(1);
+        }
+        close(fd);
+        fd = open(name, O_RDONLY);
+        if(read(fd, buf, BSIZE) != BSIZE || buf[0] != 'a' + i + j || buf[BSIZE-1] != 'a' + i + j){
+          printf("%s: %s read back wrong data\n", s, name);
+          exit(1);
+        }
+        close(fd);
+        unlink(name);
+      }
+      exit(0);
+    }
+  }
+  for(i = 0; i < 2; i++){
+    wait(&xstatus);
+    if(xstatus != 0)
+      exit(xstatus);
+  }
+
+  if(mount(ROOTDEV, "/", MS_REMOUNT) != 0){
+    printf("%s: remount without discard failed\n", s);
+    exit(1);
+  }
+}
+
+// a write() to a file opened with O_SYNC, or on the file system
+// remounted with sync, commits before it returns.
+void
+synctest(char *s)
+{
+  int fd, i, round, commits;
+
+  for(round = 0; round < 2; round++){
+    if(round == 1 && mount(ROOTDEV, "/", MS_REMOUNT|MS_SYNC) != 0){
+      printf("%s: remount with sync failed\n", s);
+      exit(1);
+    }
+    fd = open("synctest", O_CREATE|O_RDWR|O_TRUNC|(round == 0 ? O_SYNC : 0));
+    if(fd < 0){
+      printf("%s: create synctest failed\n", s);
+      exit(1);
+    }
+    commits = kstatcount("log_commit");
+    for(i = 0; i < 4; i++){
+      memset(buf, 'a' + i, BSIZE);
+      if(write(fd, buf, BSIZE) != BSIZE){
+        printf("%s: write failed\n", s);
+        exit(1);
+      }
+    }
+    if(kstatcount("log_commit") < commits + 4){
+      printf("%s: writes did not commit\n", s);
+      exit(1);
+    }
+    close(fd);
+  }
+  if(mount(ROOTDEV, "/", MS_REMOUNT) != 0){
+    printf("%s: remount without sync failed\n", s);
+    exit(1);
+  }
+
+  fd = open("synctest", O_RDONLY);
+  for(i = 0; i < 4; i++){
+    if(read(fd, buf, BSIZE) != BSIZE || buf[0] != 'a' + i || buf[BSIZE-1] != 'a' + i){
+      printf("%s: read back wrong data\n", s);
+      exit(1);
+    }
+  }
+  close(fd);
+  unlink("synctest");
+}
+
+// sync() commits what has been written, while another process
+// keeps writing.
+void
+synccall(char *s)
+{
+  int fd, i, pid, xstatus;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    fd = open("synccall", O_CREATE|O_RDWR|O_TRUNC);
+    if(fd < 0){
+      printf("%s: create synccall failed\n", s);
+      exit(1);
+    }
+    for(i = 0; i < 20; i++){
+      if(write(fd, "synccall", 8) != 8){
+        printf("%s: write failed\n", s);
+        exit(1);
+      }
+    }
+    close(fd);
+    exit(0);
+  }
+  for(i = 0; i < 20; i++){
+    if(sync() != 0){
+      printf("%s: sync failed\n", s);
+      exit(1);
+    }
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+  if(sync() != 0){
+    printf("%s: sync failed\n", s);
+    exit(1);
+  }
+  unlink("synccall");
+}
+
+// getcwd() makes the path of the current directory.
+void
+getcwdtest(char *s)
+{
+  char path[MAXPATH];
+  int pid, xstatus;
+
+  if(getcwd(path, sizeof(path)) != 0 || strcmp(path, "/") != 0){
+    printf("%s: getcwd at / failed\n", s);
+    exit(1);
+  }
+  if(mkdir("cwda") != 0 || mkdir("cwda/cwdb") != 0){
+    printf("%s: mkdir failed\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(chdir("cwda/cwdb") != 0){
+      printf("%s: chdir failed\n", s);
+      exit(1);
+    }
+    if(getcwd(path, sizeof(path)) != 0 || strcmp(path, "/cwda/cwdb") != 0){
+      printf("%s: getcwd returned %s\n", s, path);
+      exit(1);
+    }
+    if(getcwd(path, 5) >= 0){
+      printf("%s: getcwd into a short buffer succeeded\n", s);
+      exit(1);
+    }
+    chdir("/");
+    if(unlink("cwda/cwdb") != 0){
+      printf("%s: unlink failed\n", s);
+      exit(1);
+    }
+    exit(0);
+  }
+  wait(&xstatus);
+  unlink("cwda/cwdb");
+  unlink("cwda");
+  exit(xstatus);
+}
+
+// openat(), mkdirat(), and unlinkat() take relative paths from a
+// directory file descriptor.
+void
+atcalls(char *s)
+{
+  int dirfd, fd;
+  char c;
+
+  if(mkdir("atdir") != 0){
+    printf("%s: mkdir failed\n", s);
+    exit(1);
+  }
+  dirfd = open("atdir", O_RDONLY);
+  if(dirfd < 0){
+    printf("%s: open atdir failed\n", s);
+    exit(1);
+  }
+  if(mkdirat(dirfd, "sub") != 0){
+    printf("%s: mkdirat failed\n", s);
+    exit(1);
+  }
+  fd = openat(dirfd, "sub/f", O_CREATE|O_RDWR);
+  if(fd < 0 || write(fd, "x", 1) != 1){
+    printf("%s: openat to create failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  fd = open("atdir/sub/f", O_RDONLY);
+  if(fd < 0 || read(fd, &c, 1) != 1 || c != 'x'){
+    printf("%s: the file is not where openat made it\n", s);
+    exit(1);
+  }
+  close(fd);
+  fd = openat(AT_FDCWD, "atdir/sub/f", O_RDONLY);
+  if(fd < 0){
+    printf("%s: openat with AT_FDCWD failed\n", s);
+    exit(1);
+  }
+  if(openat(fd, "f", O_RDONLY) >= 0){
+    printf("%s: openat relative to a file succeeded\n", s);
+    exit(1);
+  }
+  close(fd);
+  if(unlinkat(dirfd, "sub/f", AT_REMOVEDIR) >= 0){
+    printf("%s: unlinkat removed a file with AT_REMOVEDIR\n", s);
+    exit(1);
+  }
+  if(unlinkat(dirfd, "sub/f", 0) != 0 || unlinkat(dirfd, "sub", AT_REMOVEDIR) != 0){
+    printf("%s: unlinkat failed\n", s);
+    exit(1);
+  }
+  close(dirfd);
+  if(unlink("atdir") != 0){
+    printf("%s: atdir was not empty\n", s);
+    exit(1);
+  }
+}
+
+// exec with MAXARG arguments must work, and with one more must fail.
+void
+maxargtest(char *s)
+{
+  static char *args[MAXARG+2];
+  char out[2*MAXARG];
+  int fd, i, n, pid, xstatus;
+
+  args[0] = "echo";
+  for(i = 1; i <= MAXARG; i++)
+    args[i] = "a";
+  args[MAXARG+1] = 0;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(exec("echo", args) >= 0){
+      printf("%s: exec with %d arguments succeeded\n", s, MAXARG+1);
+      exit(1);
+    }
+    close(1);
+    if(open("maxarg-out", O_CREATE|O_TRUNC|O_WRONLY) != 1){
+      printf("%s: create failed\n", s);
+      exit(1);
+    }
+    args[MAXARG] = 0;
+    exec("echo", args);
+    printf("%s: exec with %d arguments failed\n", s, MAXARG);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+
+  fd = open("maxarg-out", O_RDONLY);
+  if(fd < 0){
+    printf("%s: open failed\n", s);
+    exit(1);
+  }
+  n = read(fd, out, sizeof(out));
+  close(fd);
+  unlink("maxarg-out");
+  if(n != 2*(MAXARG-1)){
+    printf("%s: read %d bytes of output\n", s, n);
+    exit(1);
+  }
+  for(i = 0; i < n; i++){
+    if(out[i] != (i == n-1 ? '\n' : i % 2 ? ' ' : 'a')){
+      printf("%s: wrong output\n", s);
+      exit(1);
+    }
+  }
+}
+
+// fill the disk, and check that writes and mkdir fail instead of
+// crashing the kernel, and that the space comes back afterwards.
+void
+diskfull(char *s)
+{
+  char name[8];
+  int fd, i, n, nfiles, full;
+
+  full = 0;
+  for(nfiles = 0; nfiles < 100 && !full; nfiles++){
+    name[0] = 'd';
+    name[1] = 'f';
+    name[2] = '0' + nfiles / 10;
+    name[3] = '0' + nfiles % 10;
+    name[4] = '\0';
+    unlink(name);
+    fd = open(name, O_CREATE|O_RDWR);
+    if(fd < 0)
+      break;
+    for(n = 0; n < MAXFILE; n++){
+      if(write(fd, buf, BSIZE) != BSIZE){
+        full = 1;
+        break;
+      }
+    }
+    close(fd);
+  }
+  if(!full){
+    printf("%s: the disk did not fill up\n", s);
+    exit(1);
+  }
+
+  if(mkdir("diskfulldir") == 0){
+    printf("%s: mkdir succeeded on a full disk\n", s);
+    exit(1);
+  }
+
+  for(i = 0; i < nfiles; i++){
+    name[2] = '0' + i / 10;
+    name[3] = '0' + i % 10;
+    unlink(name);
+  }
+
+  fd = open("diskfull", O_CREATE|O_RDWR);
+  if(fd < 0){
+    printf("%s: create failed after freeing the disk\n", s);
+    exit(1);
+  }
+  for(n = 0; n < 10; n++){
+    if(write(fd, buf, BSIZE) != BSIZE){
+      printf("%s: write failed after freeing the disk\n", s);
+      exit(1);
+    }
+  }
+  close(fd);
+  unlink("diskfull");
+}
+
+// exhaust memory, and check that fork and exec fail instead of
+// crashing the kernel.
+void
+oomcalls(char *s)
+{
+  int pid, xstatus;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    char *args[] = { "echo", "oom", 0 };
+    while(1){
+      uint64 a = (uint64) sbrk(4096);
+      if(a == 0xffffffffffffffffLL)
+        break;
+      *(char*)(a + 4096 - 1) = 1;
+    }
+    if(fork() >= 0){
+      printf("%s: fork succeeded out of memory\n", s);
+      exit(1);
+    }
+    if(exec("echo", args) >= 0){
+      printf("%s: exec succeeded out of memory\n", s);
+      exit(1);
+    }
+    exit(0);
+  }
+  wait(&xstatus);
+  exit(xstatus);
+}
+
+// a process without CAP_SYS_ADMIN that takes all of memory is killed
+// when another process runs out of memory.
+void
+oomkill(char *s)
+{
+  int fds[2], pid, xstatus;
+  char c;
+
+  if(pipe(fds) != 0){
+    printf("%s: pipe failed\n", s);
+    exit(1);
+  }
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(prctl(PR_CAPBSET_DROP, CAP_SYS_ADMIN) != 0){
+      printf("%s: prctl failed\n", s);
+      exit(1);
+    }
+    while(1){
+      uint64 a = (uint64) sbrk(4096);
+      if(a == 0xffffffffffffffffLL)
+        break;
+      *(char*)(a + 4096 - 1) = 1;
+    }
+    write(fds[1], "x", 1);
+    for(;;) sleep(1000);
+  }
+  close(fds[1]);
+  if(read(fds[0], &c, 1) != 1){
+    printf("%s: the child did not allocate memory\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+
+  // there is no memory for this fork, so the child gets killed.
+  int pid2 = fork();
+  if(pid2 == 0)
+    exit(0);
+  if(pid2 > 0){
+    printf("%s: fork succeeded out of memory\n", s);
+    exit(1);
+  }
+  if(wait(&xstatus) != pid || xstatus != -1){
+    printf("%s: the child was not killed\n", s);
+    exit(1);
+  }
+}
+
+// a PROT_NONE page, such as a guard page, cannot be accessed even by
+// system calls, and a read-only one cannot be written by them, until
+// mprotect() allows it. fork() keeps the protection, and mprotect() the data.
+void
+mprotecttest(char *s)
+{
+  char *p;
+  int fds[2], pid, xstatus;
+
+  p = sbrk(2*PGSIZE);
+  if(p == (char*)0xffffffffffffffffL){
+    printf("%s: sbrk failed\n", s);
+    exit(1);
+  }
+  p = (char*)(((uint64)p + PGSIZE-1) & ~(PGSIZE-1));
+  p[0] = 'x';
+  if(pipe(fds) != 0){
+    printf("%s: pipe failed\n", s);
+    exit(1);
+  }
+
+  if(mprotect(p, PGSIZE, PROT_NONE) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    printf("%s: read a PROT_NONE page %x\n", s, *(volatile char*)p);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != -1){
+    printf("%s: child not killed\n", s);
+    exit(1);
+  }
+  if(write(fds[1], p, 1) != -1){
+    printf("%s: write() read a PROT_NONE page\n", s);
+    exit(1);
+  }
+
+  if(mprotect(p, PGSIZE, PROT_READ) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+  if(p[0] != 'x'){
+    printf("%s: mprotect lost the data\n", s);
+    exit(1);
+  }
+  if(write(fds[1], "y", 1) != 1){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  if(read(fds[0], p, 1) != -1){
+    printf("%s: read() wrote a read-only page\n", s);
+    exit(1);
+  }
+
+  if(mprotect(p, PGSIZE, PROT_READ|PROT_WRITE) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+  if(read(fds[0], p, 1) != 1 || p[0] != 'y'){
+    printf("%s: read failed\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+  sbrk(-2*PGSIZE);
+}
+
+// madvise(MADV_DONTNEED) gives written pages back, so that they read as
+// zeros, and madvise(MADV_WILLNEED) keeps their contents. Pages that could
+// not be read again, such as code, are not given back.
+void
+madvisetest(char *s)
+{
+  enum { N=4 };
+  char *p;
+  int i;
+
+  p = sbrk((N+1)*PGSIZE);
+  if(p == (char*)0xffffffffffffffffL){
+    printf("%s: sbrk failed\n", s);
+    exit(1);
+  }
+  p = (char*)(((uint64)p + PGSIZE-1) & ~(PGSIZE-1));
+  for(i = 0; i < N; i++)
+    p[i*PGSIZE] = 'a' + i;
+
+  if(madvise(p + PGSIZE, 2*PGSIZE, MADV_DONTNEED) != 0){
+    printf("%s: madvise(MADV_DONTNEED) failed\n", s);
+    exit(1);
+  }
+  if(p[0] != 'a' || p[PGSIZE] != 0 || p[2*PGSIZE] != 0 || p[3*PGSIZE] != 'd'){
+    printf("%s: wrong contents after MADV_DONTNEED\n", s);
+    exit(1);
+  }
+  p[PGSIZE] = 'B';
+
+  if(madvise(p, N*PGSIZE, MADV_WILLNEED) != 0){
+    printf("%s: madvise(MADV_WILLNEED) failed\n", s);
+    exit(1);
+  }
+  if(p[0] != 'a' || p[PGSIZE] != 'B' || p[2*PGSIZE] != 0 || p[3*PGSIZE] != 'd'){
+    printf("%s: wrong contents after MADV_WILLNEED\n", s);
+    exit(1);
+  }
+
+  if(madvise((char*)((uint64)madvisetest & ~(PGSIZE-1)), PGSIZE, MADV_DONTNEED) != -1){
+    printf("%s: gave back code\n", s);
+    exit(1);
+  }
+  if(madvise(p, PGSIZE, 0) != -1 || madvise(p + 1, PGSIZE, MADV_DONTNEED) != -1){
+    printf("%s: madvise accepted bad arguments\n", s);
+    exit(1);
+  }
+  sbrk(-(N+1)*PGSIZE);
+}
+
+// copy the program src to dst.
+void
+copyprog(char *s, char *src, char *dst)
+{
+  char buf[512];
+  int fd0, fd1, n;
+
+  fd0 = open(src, O_RDONLY);
+  fd1 = open(dst, O_CREATE|O_WRONLY|O_TRUNC);
+  if(fd0 < 0 || fd1 < 0){
+    printf("%s: open %s or %s failed\n", s, src, dst);
+    exit(1);
+  }
+  while((n = read(fd0, buf, sizeof(buf))) > 0){
+    if(write(fd1, buf, n) != n){
+      printf("%s: write %s failed\n", s, dst);
+      exit(1);
+    }
+  }
+  close(fd0);
+  close(fd1);
+}
+
+// processes running the same program share its text, but rewriting the
+// program must not leave a later exec running the old text.
+void
+sharedtext(char *s)
+{
+  char *catargv[] = { "stcopy", 0 };
+  char *echoargv[] = { "stcopy", "OK", 0 };
+  int fds[2], pid1, pid2, fd, xstatus;
+  char buf[3];
+
+  copyprog(s, "cat", "stcopy");
+  if(pipe(fds) < 0){
+    printf("%s: pipe failed\n", s);
+    exit(1);
+  }
+
+  // a cat that waits for input keeps the text of stcopy alive.
+  pid1 = fork();
+  if(pid1 < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid1 == 0){
+    close(0);
+    dup(fds[0]);
+    close(fds[0]);
+    close(fds[1]);
+    exec("stcopy", catargv);
+    printf("%s: exec stcopy failed\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  sleep(1);
+
+  // a second cat shares the text.
+  pid2 = fork();
+  if(pid2 < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid2 == 0){
+    close(0);
+    open("stcopy", O_RDONLY);
+    close(1);
+    open("st-out", O_CREATE|O_WRONLY|O_TRUNC);
+    exec("stcopy", catargv);
+    printf("%s: exec stcopy failed\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+
+  // now stcopy is echo, though the first cat still runs.
+  copyprog(s, "echo", "stcopy");
+  pid2 = fork();
+  if(pid2 < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid2 == 0){
+    close(1);
+    open("st-out", O_CREATE|O_WRONLY|O_TRUNC);
+    exec("stcopy", echoargv);
+    printf("%s: exec stcopy failed\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(xstatus);
+
+  close(fds[1]);
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: cat failed\n", s);
+    exit(1);
+  }
+
+  fd = open("st-out", O_RDONLY);
+  if(fd < 0 || read(fd, buf, 2) != 2){
+    printf("%s: read st-out failed\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("st-out");
+  unlink("stcopy");
+  if(buf[0] != 'O' || buf[1] != 'K'){
+    printf("%s: ran the old text\n", s);
+    exit(1);
+  }
+}
+
+// operations batched through the rings of ring_setup() complete in order,
+// each as the system call it is like would.
+void
+ringtest(char *s)
+{
+  struct ring r;
+  struct ring_cqe cqe;
+  char data[8];
+  int fd, i;
+  int expected[] = { 5, 5, 0, -1, -1 };
+
+  if(ring_enter(1) >= 0){
+    printf("%s: ring_enter without rings succeeded\n", s);
+    exit(1);
+  }
+  if(ring_setup((char*)sbrk(0) + 1, (char*)sbrk(0) + PGSIZE) >= 0){
+    printf("%s: ring_setup of bad pages succeeded\n", s);
+    exit(1);
+  }
+  if(ring_init(&r) < 0){
+    printf("%s: ring_init failed\n", s);
+    exit(1);
+  }
+
+  fd = open("ringtest.tmp", O_CREATE|O_RDWR);
+  if(fd < 0){
+    printf("%s: open failed\n", s);
+    exit(1);
+  }
+  ring_prep(&r, RING_WRITE, fd, "hello", 5, 0);
+  ring_prep(&r, RING_WRITE, fd, "world", 5, 1);
+  ring_prep(&r, RING_FSYNC, fd, 0, 0, 2);
+  ring_prep(&r, RING_WRITE, 99, "x", 1, 3);
+  ring_prep(&r, 42, fd, data, 1, 4);
+  if(ring_submit(&r) != 5){
+    printf("%s: ring_submit did not do all\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 5; i++){
+    if(!ring_reap(&r, &cqe) || cqe.data != i || cqe.res != expected[i]){
+      printf("%s: completion %d is wrong\n", s, i);
+      exit(1);
+    }
+  }
+  if(ring_reap(&r, &cqe)){
+    printf("%s: too many completions\n", s);
+    exit(1);
+  }
+
+  close(fd);
+  fd = open("ringtest.tmp", O_RDONLY);
+  ring_prep(&r, RING_READ, fd, data, 8, 5);
+  if(ring_submit(&r) != 1 || !ring_reap(&r, &cqe) || cqe.res != 8 ||
+     memcmp(data, "hellowor", 8) != 0){
+    printf("%s: read back wrong data\n", s);
+    exit(1);
+  }
+  close(fd);
+  unlink("ringtest.tmp");
+}
+
+void
+eventfdtest(char *s)
+{
+  uint64 n;
+  int fd, pid, xstatus;
+
+  fd = eventfd(3, O_NONBLOCK);
+  if(fd < 0){
+    printf("%s: eventfd failed\n", s);
+    exit(1);
+  }
+  n = 4;
+  if(write(fd, &n, sizeof(n)) != sizeof(n)){
+    printf("%s: eventfd write failed\n", s);
+    exit(1);
+  }
+  if(read(fd, &n, sizeof(n)) != sizeof(n) || n != 7){
+    printf("%s: eventfd read wrong count\n", s);
+    exit(1);
+  }
+  if(read(fd, &n, sizeof(n)) >= 0){
+    printf("%s: nonblocking read of zero count succeeded\n", s);
+    exit(1);
+  }
+  if(read(fd, &n, 4) >= 0){
+    printf("%s: short read succeeded\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  // a blocking read waits for a write from another process.
+  fd = eventfd(0, 0);
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    sleep(2);
+    n = 1;
+    write(fd, &n, sizeof(n));
+    exit(0);
+  }
+  if(read(fd, &n, sizeof(n)) != sizeof(n) || n != 1){
+    printf("%s: blocking eventfd read failed\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  close(fd);
+
+  if(eventfd(0, O_RDWR) >= 0){
+    printf("%s: eventfd with bad flags succeeded\n", s);
+    exit(1);
+  }
+
+  fd = timerfd(2, 1, O_NONBLOCK);
+  if(fd < 0){
+    printf("%s: timerfd failed\n", s);
+    exit(1);
+  }
+  if(read(fd, &n, sizeof(n)) >= 0){
+    printf("%s: timer expired early\n", s);
+    exit(1);
+  }
+  if(write(fd, &n, sizeof(n)) >= 0){
+    printf("%s: timerfd write succeeded\n", s);
+    exit(1);
+  }
+  sleep(5);
+  if(read(fd, &n, sizeof(n)) != sizeof(n) || n < 3){
+    printf("%s: timerfd read wrong expirations\n", s);
+    exit(1);
+  }
+  close(fd);
+
+  // a one-shot timer expires once, and a blocking read waits for it.
+  fd = timerfd(2, 0, 0);
+  if(read(fd, &n, sizeof(n)) != sizeof(n) || n != 1){
+    printf("%s: one-shot timerfd read failed\n", s);
+    exit(1);
+  }
+  close(fd);
+}
+
+void
+semtest(char *s)
+{
+  int sem, done, pid, i, xstatus;
+
+  if(sem_open("semtest", 0, 0) >= 0){
+    printf("%s: sem_open of no semaphore succeeded\n", s);
+    exit(1);
+  }
+  sem = sem_open("semtest", O_CREATE, 0);
+  done = sem_open("semtest.done", O_CREATE, 0);
+  if(sem < 0 || done < 0){
+    printf("%s: sem_open failed\n", s);
+    exit(1);
+  }
+
+  // the child opens the semaphores by name, not through the inherited
+  // file descriptors.
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    close(sem);
+    close(done);
+    sem = sem_open("semtest", 0, 0);
+    done = sem_open("semtest.done", O_CREATE, 5);
+    if(sem < 0 || done < 0)
+      exit(1);
+    for(i = 0; i < 3; i++){
+      if(sem_wait(sem) < 0)
+        exit(1);
+      sem_post(done);
+    }
+    exit(0);
+  }
+  for(i = 0; i < 3; i++){
+    sem_post(sem);
+    if(sem_wait(done) < 0){
+      printf("%s: sem_wait failed\n", s);
+      exit(1);
+    }
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: child failed\n", s);
+    exit(1);
+  }
+  if(read(sem, &i, 1) >= 0 || sem_post(0) >= 0){
+    printf("%s: read of semaphore or sem_post of non-semaphore succeeded\n", s);
+    exit(1);
+  }
+  close(sem);
+  close(done);
+
+  // the last close frees the semaphores.
+  if(sem_open("semtest", 0, 0) >= 0){
+    printf("%s: semaphore outlived its last close\n", s);
+    exit(1);
+  }
+}
+
+void
+mqtest(char *s)
+{
+  char msg[8];
+  int mq, pid, prio, i, xstatus;
+  char *sent[] = { "low", "high", "mid", "high2" };
+  int prios[] = { 1, 5, 3, 5 };
+  char *expected[] = { "high", "high2", "mid", "low" };
+
+  if(mq_open("mqtest", 0) >= 0){
+    printf("%s: mq_open of no queue succeeded\n", s);
+    exit(1);
+  }
+  mq = mq_open("mqtest", O_CREATE|O_NONBLOCK);
+  if(mq < 0){
+    printf("%s: mq_open failed\n", s);
+    exit(1);
+  }
+  if(mq_receive(mq, msg, sizeof(msg), 0) >= 0){
+    printf("%s: nonblocking receive from empty queue succeeded\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 4; i++){
+    if(mq_send(mq, sent[i], strlen(sent[i]) + 1, prios[i]) < 0){
+      printf("%s: mq_send failed\n", s);
+      exit(1);
+    }
+  }
+  if(mq_receive(mq, msg, 2, 0) >= 0){
+    printf("%s: receive into short buffer succeeded\n", s);
+    exit(1);
+  }
+  for(i = 0; i < 4; i++){
+    if(mq_receive(mq, msg, sizeof(msg), &prio) != strlen(expected[i]) + 1 ||
+       strcmp(msg, expected[i]) != 0){
+      printf("%s: received %s out of order\n", s, msg);
+      exit(1);
+    }
+  }
+  if(prio != 1){
+    printf("%s: wrong priority\n", s);
+    exit(1);
+  }
+
+  // the queue holds 16 messages, and is full after them.
+  for(i = 0; i < 16; i++){
+    if(mq_send(mq, "x", 1, 0) < 0){
+      printf("%s: mq_send %d failed\n", s, i);
+      exit(1);
+    }
+  }
+  if(mq_send(mq, "x", 1, 0) >= 0){
+    printf("%s: nonblocking send to full queue succeeded\n", s);
+    exit(1);
+  }
+  close(mq);
+  if(mq_open("mqtest", 0) >= 0){
+    printf("%s: queue outlived its last close\n", s);
+    exit(1);
+  }
+
+  // a blocking receive waits for a sender in another process.
+  mq = mq_open("mqtest", O_CREATE);
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    sleep(2);
+    mq_send(mq, "ping", 5, 0);
+    exit(0);
+  }
+  if(mq_receive(mq, msg, sizeof(msg), 0) != 5 || strcmp(msg, "ping") != 0){
+    printf("%s: blocking receive failed\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  close(mq);
+}
+
+void
+procselftest(char *s)
+{
+  char text[64], *ppid;
+  int parent, pid, fd, n, xstatus;
+
+  if(gettid() != getpid()){
+    printf("%s: gettid is not getpid\n", s);
+    exit(1);
+  }
+  parent = getpid();
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(getppid() != parent){
+      printf("%s: getppid %d, not %d\n", s, getppid(), parent);
+      exit(1);
+    }
+    fd = open("/proc/self", O_RDONLY);
+    if(fd < 0){
+      printf("%s: open /proc/self failed\n", s);
+      exit(1);
+    }
+    n = read(fd, text, sizeof(text) - 1);
+    close(fd);
+    if(n <= 0){
+      printf("%s: read /proc/self failed\n", s);
+      exit(1);
+    }
+    text[n] = 0;
+    ppid = strchr(text, '\n');
+    if(memcmp(text, "pid ", 4) != 0 || atoi(text + 4) != getpid() ||
+       ppid == 0 || memcmp(ppid + 1, "ppid ", 5) != 0 || atoi(ppid + 6) != parent){
+      printf("%s: /proc/self is wrong: %s\n", s, text);
+      exit(1);
+    }
+    exit(0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+}
+
+void
+sysinfotest(char *s)
+{
+  struct utsname uts;
+  struct sysinfo before, after;
+  int pid, xstatus;
+
+  if(uname(&uts) < 0 || strcmp(uts.sysname, "rv6") != 0 ||
+     strcmp(uts.machine, "riscv64") != 0 || uts.release[0] == 0){
+    printf("%s: uname failed\n", s);
+    exit(1);
+  }
+  if(uname((struct utsname*)0xffffffffffffffffULL) >= 0){
+    printf("%s: uname to bad address succeeded\n", s);
+    exit(1);
+  }
+  if(sysinfo(&before) < 0){
+    printf("%s: sysinfo failed\n", s);
+    exit(1);
+  }
+  if(before.freepages > before.totalpages || before.totalpages == 0 ||
+     before.procs < 2 || before.uptime > uptime()){
+    printf("%s: sysinfo is wrong\n", s);
+    exit(1);
+  }
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    if(sysinfo(&after) < 0 || after.procs != before.procs + 1)
+      exit(1);
+    exit(0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: sysinfo does not count the child\n", s);
+    exit(1);
+  }
+}
+
+void
+quotatest(char *s)
+{
+  static char buf[BSIZE];
+  struct dqblk dq;
+  int fd, n, pid, xstatus;
+
+  memset(&dq, 0, sizeof(dq));
+  dq.bhardlimit = 4;
+  dq.ihardlimit = 2;
+  if(quotactl(Q_SETQUOTA, 1, &dq) < 0){
+    printf("%s: quotactl(Q_SETQUOTA) failed\n", s);
+    exit(1);
+  }
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    prctl(PR_CAPBSET_DROP, CAP_SYS_ADMIN);
+    if(setuid(1) < 0 || getuid() != 1){
+      printf("%s: setuid failed\n", s);
+      exit(1);
+    }
+    fd = open("quota0", O_CREATE|O_WRONLY);
+    if(fd < 0){
+      printf("%s: create failed\n", s);
+      exit(1);
+    }
+    for(n = 0; n < 8; n++){
+      if(write(fd, buf, BSIZE) != BSIZE)
+        break;
+    }
+    close(fd);
+    if(n != 4){
+      printf("%s: wrote %d blocks over a quota of 4\n", s, n);
+      exit(1);
+    }
+    fd = open("quota1", O_CREATE|O_WRONLY);
+    if(fd < 0){
+      printf("%s: second create failed\n", s);
+      exit(1);
+    }
+    close(fd);
+    if(open("quota2", O_CREATE|O_WRONLY) >= 0){
+      printf("%s: create over a quota of 2 inodes succeeded\n", s);
+      exit(1);
+    }
+    if(quotactl(Q_GETQUOTA, 1, &dq) < 0 || dq.curblocks != 4 || dq.curinodes != 2){
+      printf("%s: quotactl(Q_GETQUOTA) is wrong\n", s);
+      exit(1);
+    }
+    if(quotactl(Q_GETQUOTA, 0, &dq) >= 0 || quotactl(Q_SETQUOTA, 1, &dq) >= 0){
+      printf("%s: quotactl without CAP_SYS_ADMIN succeeded\n", s);
+      exit(1);
+    }
+    exit(0);
+  }
+  wait(&xstatus);
+  unlink("quota0");
+  unlink("quota1");
+  unlink("quota2");
+  if(quotactl(Q_GETQUOTA, 1, &dq) < 0 || dq.curblocks != 0 || dq.curinodes != 0){
+    printf("%s: unlink did not give the blocks and inodes back\n", s);
+    exit(1);
+  }
+  memset(&dq, 0, sizeof(dq));
+  quotactl(Q_SETQUOTA, 1, &dq);
+  if(xstatus != 0)
+    exit(1);
+}
+
+void
+rescantest(char *s)
+{
+  int pid, xstatus;
+
+  // whatever the first scan finds, a second one right after finds nothing.
+  if(rescan() < 0){
+    printf("%s: rescan failed\n", s);
+    exit(1);
+  }
+  if(rescan() != 0){
+    printf("%s: second rescan attached a device\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    prctl(PR_CAPBSET_DROP, CAP_SYS_ADMIN);
+    exit(rescan() >= 0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: rescan without CAP_SYS_ADMIN succeeded\n", s);
+    exit(1);
+  }
+}
+
+void
+cpuofflinetest(char *s)
+{
+  int i, pid, xstatus;
+
+  if(cpu_offline(0) >= 0){
+    printf("%s: boot hart went offline\n", s);
+    exit(1);
+  }
+  if(cpu_offline(64) >= 0 || cpu_online(-1) >= 0){
+    printf("%s: bad hart accepted\n", s);
+    exit(1);
+  }
+
+  // with one hart fewer, processes still run, on the other harts.
+  if(cpu_offline(1) == 0){
+    for(i = 0; i < 4; i++){
+      pid = fork();
+      if(pid < 0){
+        printf("%s: fork failed\n", s);
+        exit(1);
+      }
+      if(pid == 0)
+        exit(0);
+      wait(&xstatus);
+    }
+    if(cpu_online(1) < 0){
+      printf("%s: cpu_online failed\n", s);
+      exit(1);
+    }
+  }
+
+  if(suspend(2) < 0){
+    printf("%s: suspend failed\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    prctl(PR_CAPBSET_DROP, CAP_SYS_ADMIN);
+    exit(suspend(0) >= 0 || cpu_offline(1) >= 0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: suspend without CAP_SYS_ADMIN succeeded\n", s);
+    exit(1);
+  }
+}
+
 // regression test. copyin(), copyout(), and copyinstr() used to cast
 // the virtual page address to uint, which (with certain wild system
 // call arguments) resulted in a kernel page faults.
@@ -2807,6 +5313,48 @@ main(int argc, char *argv[])
     {sbrkarg, "sbrkarg"},
     {validatetest, "validatetest"},
     {stacktest, "stacktest"},
+    {wxtest, "wxtest"},
+    {seccomptest, "seccomptest"},
+    {captest, "captest"},
+    {chroottest, "chroottest"},
+    {dmesgtest, "dmesgtest"},
+    {tracetest, "tracetest"},
+    {ptracetest, "ptracetest"},
+    {profiletest, "profiletest"},
+    {kstattest, "kstattest"},
+    {diskstatstest, "diskstatstest"},
+    {faulttest, "faulttest"},
+    {pipesize, "pipesize"},
+    {fifotest, "fifotest"},
+    {sendfiletest, "sendfiletest"},
+    {cloexectest, "cloexectest"},
+    {accesstest, "accesstest"},
+    {statfstest, "statfstest"},
+    {sparsetest, "sparsetest"},
+    {fallocatetest, "fallocatetest"},
+    {flocktest, "flocktest"},
+    {mounttest, "mounttest"},
+    {discardtest, "discardtest"},
+    {synctest, "synctest"},
+    {synccall, "synccall"},
+    {getcwdtest, "getcwd"},
+    {atcalls, "atcalls"},
+    {maxargtest, "maxargtest"},
+    {diskfull, "diskfull"},
+    {oomcalls, "oomcalls"},
+    {oomkill, "oomkill"},
+    {mprotecttest, "mprotecttest"},
+    {madvisetest, "madvisetest"},
+    {sharedtext, "sharedtext"},
+    {ringtest, "ringtest"},
+    {eventfdtest, "eventfdtest"},
+    {semtest, "semtest"},
+    {mqtest, "mqtest"},
+    {procselftest, "procselftest"},
+    {sysinfotest, "sysinfotest"},
+    {quotatest, "quotatest"},
+    {rescantest, "rescantest"},
+    {cpuofflinetest, "cpuofflinetest"},
     {opentest, "opentest"},
     {writetest, "writetest"},
     {writebig, "writebig"},
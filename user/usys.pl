@@ -23,7 +23,6 @@ entry("read");
 entry("write");
 entry("close");
 entry("kill");
-entry("exec");
 entry("open");
 entry("mknod");
 entry("unlink");
@@ -37,3 +36,54 @@ entry("sbrk");
 entry("sleep");
 entry("uptime");
 entry("poweroff");
+entry("ioctl");
+entry("gettimeofday");
+entry("clock_gettime");
+entry("reboot");
+entry("execve");
+entry("prctl");
+entry("mprotect");
+entry("seccomp");
+entry("chroot");
+entry("dmesg");
+entry("trace");
+entry("ptrace");
+entry("faultinject");
+entry("fcntl");
+entry("sendfile");
+entry("pipe2");
+entry("access");
+entry("statfs");
+entry("fstatfs");
+entry("lseek");
+entry("fallocate");
+entry("flock");
+entry("mount");
+entry("umount");
+entry("sync");
+entry("getcwd");
+entry("openat");
+entry("mkdirat");
+entry("unlinkat");
+entry("madvise");
+entry("ring_setup");
+entry("ring_enter");
+entry("eventfd");
+entry("timerfd");
+entry("sem_open");
+entry("sem_wait");
+entry("sem_post");
+entry("mq_open");
+entry("mq_send");
+entry("mq_receive");
+entry("getppid");
+entry("gettid");
+entry("uname");
+entry("sysinfo");
+entry("rescan");
+entry("cpu_offline");
+entry("cpu_online");
+entry("suspend");
+entry("getuid");
+entry("setuid");
+entry("quotactl");